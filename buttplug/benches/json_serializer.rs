@@ -0,0 +1,145 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Benchmarks for JSON serializer throughput, mostly around VibrateCmd, as
+//! that's what clients send the most of. Run with `cargo bench --bench
+//! json_serializer`.
+
+use buttplug::core::messages::{
+  serializer::{
+    ButtplugClientJSONSerializer,
+    ButtplugMessageSerializer,
+    ButtplugSerializedMessage,
+    ButtplugServerJSONSerializer,
+    ButtplugServerJSONSerializerBuilder,
+  },
+  ButtplugCurrentSpecClientMessage,
+  ButtplugMessage,
+  ButtplugServerMessage,
+  Ok,
+  VibrateCmd,
+  VibrateSubcommand,
+};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+const HANDSHAKE: &str =
+  r#"[{"RequestServerInfo":{"Id":1,"ClientName":"Bench Client","MessageVersion":2}}]"#;
+
+/// Number of messages in each batch, as clients running patterns tend to send
+/// commands in bursts.
+const BATCH_SIZE: u32 = 100;
+
+fn handshaken_serializer(validate_schema: bool) -> ButtplugServerJSONSerializer {
+  let serializer = ButtplugServerJSONSerializerBuilder::default()
+    .validate_schema(validate_schema)
+    .finish();
+  serializer
+    .deserialize_str(HANDSHAKE)
+    .expect("Bench, assuming infallible.");
+  serializer
+}
+
+fn vibrate_cmd_batch() -> Vec<ButtplugCurrentSpecClientMessage> {
+  (0..BATCH_SIZE)
+    .map(|i| {
+      let mut msg = VibrateCmd::new(
+        0,
+        vec![
+          VibrateSubcommand::new(0, 0.5),
+          VibrateSubcommand::new(1, 0.25),
+        ],
+      );
+      msg.set_id(i + 2);
+      msg.into()
+    })
+    .collect()
+}
+
+fn vibrate_cmd_batch_json() -> String {
+  match ButtplugClientJSONSerializer::default().serialize(vibrate_cmd_batch()) {
+    ButtplugSerializedMessage::Text(json) => json,
+    ButtplugSerializedMessage::Binary(_) => unreachable!("JSON serializer only outputs text."),
+  }
+}
+
+fn deserialize_benchmark(c: &mut Criterion) {
+  let json = vibrate_cmd_batch_json();
+  let mut group = c.benchmark_group("server_json_deserialize_vibrate_cmd");
+  group.throughput(Throughput::Elements(BATCH_SIZE as u64));
+  for (name, validate_schema) in [("validated", true), ("unvalidated", false)] {
+    let serializer = handshaken_serializer(validate_schema);
+    group.bench_function(format!("{}_str", name), |b| {
+      b.iter(|| {
+        serializer
+          .deserialize_str(&json)
+          .expect("Bench, assuming infallible.")
+      })
+    });
+    group.bench_function(format!("{}_bytes", name), |b| {
+      b.iter(|| {
+        serializer
+          .deserialize_bytes(json.as_bytes())
+          .expect("Bench, assuming infallible.")
+      })
+    });
+    // Going through the serializer trait, which takes ownership of a String.
+    group.bench_function(format!("{}_serialized_message", name), |b| {
+      b.iter_batched(
+        || ButtplugSerializedMessage::Text(json.clone()),
+        |msg| {
+          serializer
+            .deserialize(msg)
+            .expect("Bench, assuming infallible.")
+        },
+        BatchSize::SmallInput,
+      )
+    });
+  }
+  group.finish();
+}
+
+fn serialize_benchmark(c: &mut Criterion) {
+  let mut group = c.benchmark_group("json_serialize");
+  group.throughput(Throughput::Elements(BATCH_SIZE as u64));
+  let client_serializer = ButtplugClientJSONSerializer::default();
+  group.bench_function("client_vibrate_cmd", |b| {
+    b.iter_batched(
+      vibrate_cmd_batch,
+      |msgs| client_serializer.serialize(msgs),
+      BatchSize::SmallInput,
+    )
+  });
+  let server_serializer = handshaken_serializer(true);
+  group.bench_function("server_ok", |b| {
+    b.iter_batched(
+      || {
+        (0..BATCH_SIZE)
+          .map(|i| ButtplugServerMessage::from(Ok::new(i + 2)))
+          .collect::<Vec<_>>()
+      },
+      |msgs| server_serializer.serialize(msgs),
+      BatchSize::SmallInput,
+    )
+  });
+  group.finish();
+}
+
+fn new_connection_benchmark(c: &mut Criterion) {
+  // Each serializer compiles its own validator, so this is mostly the cost of
+  // compiling the schema.
+  c.bench_function("server_json_new_connection", |b| {
+    b.iter(|| handshaken_serializer(true))
+  });
+}
+
+criterion_group!(
+  benches,
+  deserialize_benchmark,
+  serialize_benchmark,
+  new_connection_benchmark
+);
+criterion_main!(benches);
@@ -0,0 +1,330 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Vibrates devices along with audio.
+//!
+//! [AudioAnalyzer] turns blocks of audio samples into vibration levels, one
+//! per [AudioBand] the caller is interested in, and [drive_device] feeds a
+//! stream of sample blocks through an analyzer and sends the levels to a
+//! device. Capturing audio is left to the application. For instance, a cpal
+//! input stream callback can push each buffer it receives into a channel, and
+//! the receiving end of that channel can be handed to [drive_device].
+
+use super::{ButtplugClientDevice, ButtplugClientError, VibrateCommand};
+use futures::{FutureExt, Stream, StreamExt};
+use std::{f64::consts::PI, sync::Arc};
+
+/// Bass/mid crossover frequency, in Hz.
+const BASS_CUTOFF_HZ: f64 = 250.0;
+/// Mid/treble crossover frequency, in Hz.
+const TREBLE_CUTOFF_HZ: f64 = 2000.0;
+
+/// Part of the audio spectrum a vibration level follows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioBand {
+  /// The whole signal.
+  Full,
+  /// Below 250Hz, i.e. kick drums and bass lines.
+  Bass,
+  /// 250Hz to 2kHz, where most vocals sit.
+  Mid,
+  /// Above 2kHz, i.e. cymbals and hi-hats.
+  Treble,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioReactiveOptions {
+  pub sample_rate: u32,
+  /// Samples are expected to be interleaved, like most audio APIs hand them
+  /// out. Channels are mixed down before analysis.
+  pub channels: u16,
+  /// Band each vibrator follows, starting with vibrator 0. With a single
+  /// band, all vibrators follow it.
+  pub bands: Vec<AudioBand>,
+  /// Multiplier applied to the RMS level of the audio before it's turned
+  /// into a vibration level. Typical music sits well below full scale.
+  pub gain: f64,
+  /// Levels below this, after gain, are treated as silence.
+  pub noise_floor: f64,
+  /// Time, in milliseconds, for vibration to rise towards a louder level.
+  pub attack_ms: f64,
+  /// Time, in milliseconds, for vibration to fall towards a quieter level.
+  pub release_ms: f64,
+  /// Milliseconds of audio per vibration level. Bluetooth devices generally
+  /// can't take commands much faster than every 50ms.
+  pub update_interval_ms: u32,
+  /// Smallest change in any vibration level worth sending to the device.
+  pub min_change: f64,
+}
+
+impl Default for AudioReactiveOptions {
+  fn default() -> Self {
+    Self {
+      sample_rate: 44100,
+      channels: 1,
+      bands: vec![AudioBand::Full],
+      gain: 2.0,
+      noise_floor: 0.02,
+      attack_ms: 10.0,
+      release_ms: 200.0,
+      update_interval_ms: 50,
+      min_change: 0.02,
+    }
+  }
+}
+
+/// One pole low pass filter.
+struct LowPass {
+  coefficient: f64,
+  state: f64,
+}
+
+impl LowPass {
+  fn new(cutoff_hz: f64, sample_rate: f64) -> Self {
+    Self {
+      coefficient: 1.0 - (-2.0 * PI * cutoff_hz / sample_rate).exp(),
+      state: 0.0,
+    }
+  }
+
+  fn process(&mut self, sample: f64) -> f64 {
+    self.state += self.coefficient * (sample - self.state);
+    self.state
+  }
+}
+
+/// Smoothing coefficient for a time constant, applied once per update.
+fn smoothing_coefficient(time_ms: f64, update_interval_ms: u32) -> f64 {
+  if time_ms <= 0.0 {
+    1.0
+  } else {
+    1.0 - (-f64::from(update_interval_ms) / time_ms).exp()
+  }
+}
+
+/// Turns audio samples into vibration levels between 0.0 and 1.0.
+pub struct AudioAnalyzer {
+  options: AudioReactiveOptions,
+  bass_filter: LowPass,
+  treble_filter: LowPass,
+  attack: f64,
+  release: f64,
+  /// Frames making up one update.
+  frames_per_update: usize,
+  /// Samples of the current frame seen so far, for mixing down.
+  frame: Vec<f32>,
+  frames_seen: usize,
+  /// Sum of squares per band over the current update.
+  energy: Vec<f64>,
+  levels: Vec<f64>,
+}
+
+impl AudioAnalyzer {
+  pub fn new(options: AudioReactiveOptions) -> Self {
+    let sample_rate = f64::from(options.sample_rate.max(1));
+    let frames_per_update =
+      ((sample_rate * f64::from(options.update_interval_ms) / 1000.0) as usize).max(1);
+    Self {
+      bass_filter: LowPass::new(BASS_CUTOFF_HZ, sample_rate),
+      treble_filter: LowPass::new(TREBLE_CUTOFF_HZ, sample_rate),
+      attack: smoothing_coefficient(options.attack_ms, options.update_interval_ms),
+      release: smoothing_coefficient(options.release_ms, options.update_interval_ms),
+      frames_per_update,
+      frame: Vec::with_capacity(options.channels.max(1).into()),
+      frames_seen: 0,
+      energy: vec![0.0; options.bands.len()],
+      levels: vec![0.0; options.bands.len()],
+      options,
+    }
+  }
+
+  /// Current vibration levels, one per band in the options.
+  pub fn levels(&self) -> &[f64] {
+    &self.levels
+  }
+
+  /// Analyzes a block of samples, returning the vibration levels for every
+  /// update interval finished by the block, oldest first. Blocks don't need
+  /// to line up with update intervals or frames.
+  pub fn process(&mut self, samples: &[f32]) -> Vec<Vec<f64>> {
+    let channels = usize::from(self.options.channels.max(1));
+    let mut updates = vec![];
+    for sample in samples {
+      self.frame.push(*sample);
+      if self.frame.len() < channels {
+        continue;
+      }
+      let mixed = self.frame.iter().map(|s| f64::from(*s)).sum::<f64>() / channels as f64;
+      self.frame.clear();
+      self.process_frame(mixed);
+      if self.frames_seen == self.frames_per_update {
+        updates.push(self.finish_update());
+      }
+    }
+    updates
+  }
+
+  fn process_frame(&mut self, sample: f64) {
+    let bass = self.bass_filter.process(sample);
+    let below_treble = self.treble_filter.process(sample);
+    for (band, energy) in self.options.bands.iter().zip(self.energy.iter_mut()) {
+      let value = match band {
+        AudioBand::Full => sample,
+        AudioBand::Bass => bass,
+        AudioBand::Mid => below_treble - bass,
+        AudioBand::Treble => sample - below_treble,
+      };
+      *energy += value * value;
+    }
+    self.frames_seen += 1;
+  }
+
+  fn finish_update(&mut self) -> Vec<f64> {
+    let noise_floor = self.options.noise_floor.clamp(0.0, 0.99);
+    for (energy, level) in self.energy.iter_mut().zip(self.levels.iter_mut()) {
+      let rms = (*energy / self.frames_seen as f64).sqrt();
+      let target = ((rms * self.options.gain - noise_floor) / (1.0 - noise_floor)).clamp(0.0, 1.0);
+      let coefficient = if target > *level {
+        self.attack
+      } else {
+        self.release
+      };
+      *level += coefficient * (target - *level);
+      *energy = 0.0;
+    }
+    self.frames_seen = 0;
+    self.levels.clone()
+  }
+}
+
+/// Vibrates `device` along with the audio in `samples` until the stream ends,
+/// then stops the device.
+///
+/// Only the newest levels are sent, so if the device falls behind, sample
+/// blocks that arrived while it was busy are analyzed but not sent
+/// individually. Levels that changed less than
+/// [AudioReactiveOptions::min_change] since the last command aren't sent.
+pub async fn drive_device<S>(
+  device: Arc<ButtplugClientDevice>,
+  mut samples: S,
+  options: AudioReactiveOptions,
+) -> Result<(), ButtplugClientError>
+where
+  S: Stream<Item = Vec<f32>> + Unpin,
+{
+  let min_change = options.min_change;
+  let mut analyzer = AudioAnalyzer::new(options);
+  let mut last_sent: Option<Vec<f64>> = None;
+  let mut finished = false;
+  while !finished {
+    let mut latest = match samples.next().await {
+      Some(block) => analyzer.process(&block).pop(),
+      None => break,
+    };
+    // Catch up on whatever arrived while the last command was being sent.
+    loop {
+      match samples.next().now_or_never() {
+        Some(Some(block)) => latest = analyzer.process(&block).pop().or(latest),
+        Some(None) => {
+          finished = true;
+          break;
+        }
+        None => break,
+      }
+    }
+    let levels = match latest {
+      Some(levels) => levels,
+      None => continue,
+    };
+    if let Some(last) = &last_sent {
+      if last
+        .iter()
+        .zip(levels.iter())
+        .all(|(last, level)| (last - level).abs() < min_change)
+      {
+        continue;
+      }
+    }
+    let command = if levels.len() == 1 {
+      VibrateCommand::Speed(levels[0])
+    } else {
+      VibrateCommand::SpeedVec(levels.clone())
+    };
+    device.vibrate(command).await?;
+    last_sent = Some(levels);
+  }
+  device.stop().await
+}
+
+#[cfg(test)]
+mod test {
+  use super::{AudioAnalyzer, AudioBand, AudioReactiveOptions};
+  use std::f64::consts::PI;
+
+  fn sine(frequency: f64, seconds: f64) -> Vec<f32> {
+    (0..(44100.0 * seconds) as usize)
+      .map(|i| (2.0 * PI * frequency * i as f64 / 44100.0).sin() as f32)
+      .collect()
+  }
+
+  fn analyzer(bands: Vec<AudioBand>) -> AudioAnalyzer {
+    AudioAnalyzer::new(AudioReactiveOptions {
+      bands,
+      ..Default::default()
+    })
+  }
+
+  #[test]
+  fn test_silence() {
+    let mut analyzer = analyzer(vec![AudioBand::Full]);
+    let updates = analyzer.process(&[0.0; 44100]);
+    // One update per 50ms of audio.
+    assert_eq!(updates.len(), 20);
+    assert!(updates.iter().all(|levels| levels == &[0.0]));
+  }
+
+  #[test]
+  fn test_bands() {
+    let bands = vec![AudioBand::Bass, AudioBand::Treble];
+    let mut analyzer = analyzer(bands.clone());
+    analyzer.process(&sine(60.0, 0.5));
+    assert!(analyzer.levels()[0] > 0.9);
+    assert!(analyzer.levels()[1] < 0.2);
+
+    let mut analyzer = self::analyzer(bands);
+    analyzer.process(&sine(5000.0, 0.5));
+    assert!(analyzer.levels()[0] < 0.2);
+    assert!(analyzer.levels()[1] > 0.9);
+  }
+
+  #[test]
+  fn test_release_smoothing() {
+    let mut analyzer = analyzer(vec![AudioBand::Full]);
+    analyzer.process(&sine(440.0, 0.5));
+    assert!(analyzer.levels()[0] > 0.9);
+    // Vibration falls off gradually once the audio stops.
+    let updates = analyzer.process(&[0.0; 4410]);
+    assert_eq!(updates.len(), 2);
+    assert!(updates[0][0] > updates[1][0]);
+    assert!(updates[1][0] > 0.5);
+  }
+
+  #[test]
+  fn test_interleaved_channels() {
+    let mut analyzer = AudioAnalyzer::new(AudioReactiveOptions {
+      channels: 2,
+      ..Default::default()
+    });
+    // Opposite phase channels cancel out when mixed down.
+    let samples: Vec<f32> = sine(440.0, 0.5)
+      .into_iter()
+      .flat_map(|sample| [sample, -sample])
+      .collect();
+    analyzer.process(&samples);
+    assert_eq!(analyzer.levels(), &[0.0]);
+  }
+}
@@ -0,0 +1,218 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Blocking facade over [ButtplugClient], for applications without an async
+//! runtime.
+//!
+//! GUI toolkits and game engines usually own their main loop and have no
+//! executor to hand futures to. [ButtplugBlockingClient] starts a dedicated
+//! thread running the library's runtime (see
+//! [async_manager][crate::util::async_manager]), runs the client on it, and
+//! exposes blocking equivalents of the common client calls. Anything not
+//! covered here can be run through [ButtplugBlockingClient::block_on] on the
+//! wrapped [ButtplugClient], which is available from
+//! [ButtplugBlockingClient::client].
+//!
+//! Blocking calls must not be made from inside an async task, as they park the
+//! calling thread until the runtime thread answers.
+
+use super::{
+  ButtplugClient,
+  ButtplugClientDevice,
+  ButtplugClientEvent,
+  ButtplugClientEventCallbackHandle,
+  ButtplugClientResult,
+  VibrateCommand,
+};
+use crate::{
+  connector::ButtplugConnector,
+  core::messages::{ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage},
+  util::async_manager,
+};
+use futures::{
+  channel::oneshot,
+  future::{BoxFuture, Future, FutureExt},
+};
+use std::{
+  sync::Arc,
+  thread::{self, JoinHandle},
+};
+use tokio::sync::mpsc;
+
+/// Name of the thread the client's runtime runs on.
+const RUNTIME_THREAD_NAME: &str = "buttplug-client-runtime";
+
+/// [ButtplugClient] wrapper with blocking methods, driven by a runtime on a
+/// dedicated thread.
+///
+/// The runtime thread lives as long as this struct does. Dropping it stops the
+/// runtime, which ends the client's connection along with it.
+pub struct ButtplugBlockingClient {
+  client: Arc<ButtplugClient>,
+  job_sender: Option<mpsc::UnboundedSender<BoxFuture<'static, ()>>>,
+  runtime_thread: Option<JoinHandle<()>>,
+}
+
+impl ButtplugBlockingClient {
+  /// Creates a client with the given name, along with its runtime thread.
+  pub fn new(name: &str) -> Self {
+    Self::from_client(ButtplugClient::new(name))
+  }
+
+  /// Wraps an existing client, e.g. one with a custom
+  /// [request_timeout][ButtplugClient::request_timeout], and starts its
+  /// runtime thread. The client should not be connected yet, as its event
+  /// loop would be left running on whatever runtime it was connected from.
+  pub fn from_client(client: ButtplugClient) -> Self {
+    let (job_sender, mut job_receiver) = mpsc::unbounded_channel::<BoxFuture<'static, ()>>();
+    let runtime_thread = thread::Builder::new()
+      .name(RUNTIME_THREAD_NAME.to_owned())
+      .spawn(move || {
+        async_manager::block_on(async move {
+          while let Some(job) = job_receiver.recv().await {
+            async_manager::spawn(job);
+          }
+          debug!("Blocking client dropped, stopping client runtime.");
+        })
+      })
+      .expect("Should always be able to spawn the client runtime thread.");
+    Self {
+      client: Arc::new(client),
+      job_sender: Some(job_sender),
+      runtime_thread: Some(runtime_thread),
+    }
+  }
+
+  /// The wrapped async client. Futures it returns can be waited on with
+  /// [ButtplugBlockingClient::block_on].
+  pub fn client(&self) -> &Arc<ButtplugClient> {
+    &self.client
+  }
+
+  /// Runs `future` on the client's runtime thread, blocking the calling thread
+  /// until it finishes.
+  pub fn block_on<F>(&self, future: F) -> F::Output
+  where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+  {
+    let (output_sender, output_receiver) = oneshot::channel();
+    let job = async move {
+      // If the caller is gone there's nobody to hand the output to.
+      let _ = output_sender.send(future.await);
+    }
+    .boxed();
+    self
+      .job_sender
+      .as_ref()
+      .expect("Only taken on drop.")
+      .send(job)
+      .expect("Runtime thread lives as long as the blocking client.");
+    futures::executor::block_on(output_receiver)
+      .expect("Runtime thread dropped the future, most likely due to a panic while running it.")
+  }
+
+  /// Blocking version of [ButtplugClient::connect].
+  ///
+  /// The connector is moved to the runtime thread before connecting. For
+  /// connectors that need a runtime while being built, like in-process
+  /// connectors, use [ButtplugBlockingClient::connect_with].
+  pub fn connect<ConnectorType>(&self, connector: ConnectorType) -> ButtplugClientResult
+  where
+    ConnectorType: ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage>
+      + 'static,
+  {
+    let client = self.client.clone();
+    self.block_on(async move { client.connect(connector).await })
+  }
+
+  /// Builds a connector on the runtime thread with `connector_builder`, then
+  /// connects to it, blocking until the handshake finishes.
+  pub fn connect_with<ConnectorType, F>(&self, connector_builder: F) -> ButtplugClientResult
+  where
+    ConnectorType: ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage>
+      + 'static,
+    F: FnOnce() -> ConnectorType + Send + 'static,
+  {
+    let client = self.client.clone();
+    self.block_on(async move { client.connect(connector_builder()).await })
+  }
+
+  /// Blocking version of [ButtplugClient::connect_in_process], with a server
+  /// using all device managers available on the current platform.
+  #[cfg(feature = "server")]
+  pub fn connect_in_process(&self) -> ButtplugClientResult {
+    let client = self.client.clone();
+    self.block_on(async move { client.connect_in_process(None).await })
+  }
+
+  /// Returns true if the client is currently connected.
+  pub fn connected(&self) -> bool {
+    self.client.connected()
+  }
+
+  /// Blocking version of [ButtplugClient::disconnect].
+  pub fn disconnect(&self) -> ButtplugClientResult {
+    self.block_on(self.client.disconnect())
+  }
+
+  /// Blocking version of [ButtplugClient::start_scanning].
+  pub fn start_scanning(&self) -> ButtplugClientResult {
+    self.block_on(self.client.start_scanning())
+  }
+
+  /// Blocking version of [ButtplugClient::stop_scanning].
+  pub fn stop_scanning(&self) -> ButtplugClientResult {
+    self.block_on(self.client.stop_scanning())
+  }
+
+  /// Blocking version of [ButtplugClient::stop_all_devices].
+  pub fn stop_all_devices(&self) -> ButtplugClientResult {
+    self.block_on(self.client.stop_all_devices())
+  }
+
+  /// Registers a callback for client events, called on the runtime thread.
+  /// See [ButtplugClient::add_event_callback].
+  pub fn add_event_callback<F>(&self, callback: F) -> ButtplugClientEventCallbackHandle
+  where
+    F: Fn(ButtplugClientEvent) + Send + 'static,
+  {
+    let client = self.client.clone();
+    self.block_on(async move { client.add_event_callback(callback) })
+  }
+
+  /// Currently connected devices. See [ButtplugClient::devices].
+  pub fn devices(&self) -> Vec<Arc<ButtplugClientDevice>> {
+    self.client.devices()
+  }
+
+  /// Blocking version of [ButtplugClientDevice::vibrate].
+  pub fn vibrate(
+    &self,
+    device: &ButtplugClientDevice,
+    speed_cmd: VibrateCommand,
+  ) -> ButtplugClientResult {
+    self.block_on(device.vibrate(speed_cmd))
+  }
+
+  /// Blocking version of [ButtplugClientDevice::stop].
+  pub fn stop_device(&self, device: &ButtplugClientDevice) -> ButtplugClientResult {
+    self.block_on(device.stop())
+  }
+}
+
+impl Drop for ButtplugBlockingClient {
+  fn drop(&mut self) {
+    // Closing the job channel ends the runtime thread's loop.
+    self.job_sender.take();
+    if let Some(runtime_thread) = self.runtime_thread.take() {
+      if runtime_thread.join().is_err() {
+        error!("Client runtime thread panicked.");
+      }
+    }
+  }
+}
@@ -0,0 +1,125 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Delivers [ButtplugClientEvent]s to event streams and registered callbacks.
+//!
+//! Streams from [ButtplugClient::event_stream][super::ButtplugClient::event_stream]
+//! share a broadcast channel, and may skip events if they fall too far behind.
+//! Callbacks registered with
+//! [ButtplugClient::add_event_callback][super::ButtplugClient::add_event_callback]
+//! each get their own unbounded queue and a task that drains it, so a callback
+//! sees every event emitted while it's registered, in the order the client
+//! emitted them, and is never called for two events at once. No ordering is
+//! kept between different callbacks.
+
+use super::ButtplugClientEvent;
+use crate::util::async_manager;
+use dashmap::DashMap;
+use std::sync::{
+  atomic::{AtomicBool, AtomicU32, Ordering},
+  Arc,
+};
+use tokio::sync::{broadcast, mpsc};
+
+/// Sending side of client events, shared between a client and its event loop.
+#[derive(Clone)]
+pub(super) struct ClientEventDispatcher {
+  stream_sender: broadcast::Sender<ButtplugClientEvent>,
+  next_callback_id: Arc<AtomicU32>,
+  callback_senders: Arc<DashMap<u32, mpsc::UnboundedSender<ButtplugClientEvent>>>,
+}
+
+impl Default for ClientEventDispatcher {
+  fn default() -> Self {
+    let (stream_sender, _) = broadcast::channel(256);
+    Self {
+      stream_sender,
+      next_callback_id: Arc::new(AtomicU32::new(0)),
+      callback_senders: Arc::new(DashMap::new()),
+    }
+  }
+}
+
+impl ClientEventDispatcher {
+  pub fn subscribe(&self) -> broadcast::Receiver<ButtplugClientEvent> {
+    self.stream_sender.subscribe()
+  }
+
+  /// Registers `callback`, starting the task that calls it. Must be called
+  /// from within the library's runtime.
+  pub fn add_callback<F>(&self, callback: F) -> ButtplugClientEventCallbackHandle
+  where
+    F: Fn(ButtplugClientEvent) + Send + 'static,
+  {
+    let id = self.next_callback_id.fetch_add(1, Ordering::SeqCst);
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let active = Arc::new(AtomicBool::new(true));
+    let task_active = active.clone();
+    async_manager::spawn(async move {
+      while let Some(event) = receiver.recv().await {
+        // Events may still be queued when the callback is deregistered.
+        if !task_active.load(Ordering::SeqCst) {
+          break;
+        }
+        callback(event);
+      }
+    });
+    self.callback_senders.insert(id, sender);
+    ButtplugClientEventCallbackHandle {
+      id,
+      active,
+      dispatcher: self.clone(),
+    }
+  }
+
+  /// Sends `event` to all event streams and queues it for all callbacks.
+  pub fn send(&self, event: ButtplugClientEvent) {
+    trace!("Forwarding event {:?} to client", event);
+    let mut delivered = false;
+    for sender in self.callback_senders.iter() {
+      // Only fails if the callback task is gone, in which case its handle is
+      // on the way to removing it.
+      delivered |= sender.send(event.clone()).is_ok();
+    }
+    // Only errors if there are no stream receivers.
+    delivered |= self.stream_sender.send(event.clone()).is_ok();
+    if !delivered {
+      error!(
+        "Client event {:?} dropped, no client event listener available.",
+        event
+      );
+    }
+  }
+}
+
+/// Keeps an event callback registered. Dropping the handle, or calling
+/// [ButtplugClientEventCallbackHandle::deregister], deregisters it.
+///
+/// Once deregistered, the callback isn't called for any further events,
+/// including ones already queued for it. A call that's already running still
+/// finishes, so if the callback is deregistered from another thread, it may
+/// be running while or just after deregistration returns.
+#[must_use = "The callback is deregistered as soon as the handle is dropped."]
+pub struct ButtplugClientEventCallbackHandle {
+  id: u32,
+  active: Arc<AtomicBool>,
+  dispatcher: ClientEventDispatcher,
+}
+
+impl ButtplugClientEventCallbackHandle {
+  /// Deregisters the callback. Same as dropping the handle, for call sites
+  /// that want to be explicit about it.
+  pub fn deregister(self) {
+  }
+}
+
+impl Drop for ButtplugClientEventCallbackHandle {
+  fn drop(&mut self) {
+    self.active.store(false, Ordering::SeqCst);
+    self.dispatcher.callback_senders.remove(&self.id);
+  }
+}
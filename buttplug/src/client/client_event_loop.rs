@@ -8,8 +8,9 @@
 //! Implementation of internal Buttplug Client event loop.
 
 use super::{
+  client_event_dispatcher::ClientEventDispatcher,
   client_message_sorter::ClientMessageSorter,
-  device::{ButtplugClientDevice, ButtplugClientDeviceEvent},
+  device::{ButtplugClientDevice, ButtplugClientDeviceEvent, DeviceDisconnectReason},
   ButtplugClientEvent,
   ButtplugClientMessageFuturePair,
 };
@@ -32,6 +33,7 @@ use futures::FutureExt;
 use std::sync::{
   atomic::{AtomicBool, Ordering},
   Arc,
+  RwLock,
 };
 use tokio::sync::{broadcast, mpsc};
 
@@ -91,11 +93,14 @@ where
   from_connector_receiver: mpsc::Receiver<ButtplugCurrentSpecServerMessage>,
   /// Map of devices shared between the client and the event loop
   device_map: Arc<DashMap<u32, Arc<ButtplugClientDevice>>>,
-  /// Sends events to the [ButtplugClient] instance.
-  to_client_sender: broadcast::Sender<ButtplugClientEvent>,
+  /// Sends events to the [ButtplugClient] instance's streams and callbacks.
+  event_dispatcher: ClientEventDispatcher,
   /// Sends events to the client receiver. Stored here so it can be handed to
   /// new ButtplugClientDevice instances.
   from_client_sender: broadcast::Sender<ButtplugClientRequest>,
+  /// Clock offset found by [ButtplugClient::sync_server_time], handed to new
+  /// ButtplugClientDevice instances so they can timestamp commands.
+  server_time_offset: Arc<RwLock<Option<i64>>>,
   /// Receives incoming messages from client instances.
   from_client_receiver: broadcast::Receiver<ButtplugClientRequest>,
   sorter: ClientMessageSorter,
@@ -108,27 +113,31 @@ where
 {
   /// Creates a new [ButtplugClientEventLoop].
   ///
-  /// Given the [ButtplugClientConnector] object, as well as the channels used
-  /// for communicating with the client, creates an event loop structure and
-  /// returns it.
+  /// Given the [ButtplugClientConnector] object, the channels used for
+  /// communicating with the client, and the sorter used to match responses to
+  /// requests, creates an event loop structure and returns it.
+  #[allow(clippy::too_many_arguments)]
   pub fn new(
     connected_status: Arc<AtomicBool>,
     connector: ConnectorType,
     from_connector_receiver: mpsc::Receiver<ButtplugCurrentSpecServerMessage>,
-    to_client_sender: broadcast::Sender<ButtplugClientEvent>,
+    event_dispatcher: ClientEventDispatcher,
     from_client_sender: broadcast::Sender<ButtplugClientRequest>,
     device_map: Arc<DashMap<u32, Arc<ButtplugClientDevice>>>,
+    server_time_offset: Arc<RwLock<Option<i64>>>,
+    sorter: ClientMessageSorter,
   ) -> Self {
     trace!("Creating ButtplugClientEventLoop instance.");
     Self {
       connected_status,
       device_map,
+      server_time_offset,
       from_client_receiver: from_client_sender.subscribe(),
       from_client_sender,
-      to_client_sender,
+      event_dispatcher,
       from_connector_receiver,
       connector,
-      sorter: ClientMessageSorter::default(),
+      sorter,
     }
   }
 
@@ -154,6 +163,7 @@ where
         let device = Arc::new(ButtplugClientDevice::new_from_device_info(
           info,
           self.from_client_sender.clone(),
+          self.server_time_offset.clone(),
         ));
         self.device_map.insert(info.device_index, device.clone());
         device
@@ -162,23 +172,10 @@ where
   }
 
   fn send_client_event(&mut self, event: ButtplugClientEvent) {
-    trace!("Forwarding event {:?} to client", event);
-
-    if self.to_client_sender.receiver_count() == 0 {
-      error!(
-        "Client event {:?} dropped, no client event listener available.",
-        event
-      );
-      return;
-    }
-
-    self
-      .to_client_sender
-      .send(event)
-      .expect("Already checked for receivers.");
+    self.event_dispatcher.send(event);
   }
 
-  fn disconnect_device(&mut self, device_index: u32) {
+  fn disconnect_device(&mut self, device_index: u32, reason: DeviceDisconnectReason) {
     if !self.device_map.contains_key(&device_index) {
       return;
     }
@@ -189,7 +186,7 @@ where
       .expect("Checked for device index already."))
     .clone();
     device.set_device_connected(false);
-    device.queue_event(ButtplugClientDeviceEvent::DeviceRemoved);
+    device.queue_event(ButtplugClientDeviceEvent::DeviceRemoved(reason));
     // Then remove it from our storage map
     self.device_map.remove(&device_index);
     self.send_client_event(ButtplugClientEvent::DeviceRemoved(device));
@@ -234,7 +231,11 @@ where
       ButtplugCurrentSpecServerMessage::DeviceRemoved(dev) => {
         if self.device_map.contains_key(&dev.device_index()) {
           trace!("Device removed, updating map and sending to client");
-          self.disconnect_device(dev.device_index());
+          let reason = dev.reason().map_or(
+            DeviceDisconnectReason::Unknown,
+            DeviceDisconnectReason::from,
+          );
+          self.disconnect_device(dev.device_index(), reason);
         } else {
           error!("Received DeviceRemoved for non-existent device index");
           self.send_client_event(ButtplugClientEvent::Error(ButtplugDeviceError::DeviceConnectionError("Device removal requested for a device the client does not know about. Server may be in a weird state.".to_owned()).into()));
@@ -254,6 +255,55 @@ where
             ));
         }
       }
+      ButtplugCurrentSpecServerMessage::SensorReading(msg) => {
+        let device_idx = msg.device_index();
+        if let Some(device) = self.device_map.get(&device_idx) {
+          device
+            .value()
+            .queue_event(ButtplugClientDeviceEvent::Message(
+              ButtplugCurrentSpecServerMessage::from(msg),
+            ));
+        }
+      }
+      ButtplugCurrentSpecServerMessage::DeviceClaimed(msg) => {
+        let device = self
+          .device_map
+          .get(&msg.device_index())
+          .map(|device| device.value().clone());
+        if let Some(device) = device {
+          self.send_client_event(ButtplugClientEvent::DeviceClaimed(
+            device,
+            msg.client_name().to_owned(),
+          ));
+        }
+      }
+      ButtplugCurrentSpecServerMessage::DeviceReleased(msg) => {
+        let device = self
+          .device_map
+          .get(&msg.device_index())
+          .map(|device| device.value().clone());
+        if let Some(device) = device {
+          self.send_client_event(ButtplugClientEvent::DeviceReleased(
+            device,
+            msg.client_name().to_owned(),
+          ));
+        }
+      }
+      ButtplugCurrentSpecServerMessage::ScanningPartialFailure(msg) => {
+        self.send_client_event(ButtplugClientEvent::ScanningPartialFailure(
+          msg.failures().clone(),
+        ));
+      }
+      ButtplugCurrentSpecServerMessage::DeviceInitializationFailed(msg) => {
+        self.send_client_event(ButtplugClientEvent::DeviceInitializationFailed(msg));
+      }
+      ButtplugCurrentSpecServerMessage::PingTimeout(msg) => {
+        trace!(
+          "Ping timeout event received, server applied {:?}",
+          msg.policy()
+        );
+        self.send_client_event(ButtplugClientEvent::PingTimeout);
+      }
       ButtplugCurrentSpecServerMessage::Error(e) => {
         self.send_client_event(ButtplugClientEvent::Error(e.into()));
       }
@@ -347,7 +397,7 @@ where
     let device_indexes: Vec<u32> = self.device_map.iter().map(|k| *k.key()).collect();
     device_indexes
       .iter()
-      .for_each(|k| self.disconnect_device(*k));
+      .for_each(|k| self.disconnect_device(*k, DeviceDisconnectReason::ClientDisconnected));
 
     self.send_client_event(ButtplugClientEvent::ServerDisconnect);
 
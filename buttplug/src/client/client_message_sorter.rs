@@ -13,13 +13,33 @@ use crate::{
     ButtplugClientMessageFuturePair,
     ButtplugServerMessageStateShared,
   },
-  core::messages::{ButtplugCurrentSpecServerMessage, ButtplugMessage, ButtplugMessageValidator},
+  core::messages::{
+    ButtplugClientMessage,
+    ButtplugCurrentSpecServerMessage,
+    ButtplugDeviceCommandMessageUnion,
+    ButtplugDeviceMessage,
+    ButtplugMessage,
+    ButtplugMessageValidator,
+  },
+  util::{async_manager, message_tracing::message_span},
 };
 use dashmap::DashMap;
-use std::sync::{
-  atomic::{AtomicU32, Ordering},
-  Arc,
+use futures_timer::Delay;
+use std::{
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+  },
+  time::Duration,
 };
+use tracing::{info_span, Span};
+
+/// Default amount of time to wait for a server response before failing the
+/// request.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Default maximum number of requests that can be waiting on a server response
+/// at once.
+pub const DEFAULT_MAX_PENDING_REQUESTS: usize = 1024;
 
 /// Message sorting and pairing for remote client connectors.
 ///
@@ -59,34 +79,119 @@ use std::sync::{
 /// - If the message `id` is not zero but there is no future waiting, the
 ///   message is dropped and an error is emitted.
 ///
+/// Since a server may never respond to a request, each registered future is
+/// also failed with [ButtplugClientError::RequestTimeout] if no response
+/// arrives within the request timeout, or if the map of waiting futures is
+/// full and it is the oldest entry.
 pub struct ClientMessageSorter {
   /// Map of message `id`s to their related future.
   ///
   /// This is where we store message `id`s that are waiting for a return from
   /// the server. Once we get back a response with a matching `id`, we remove
   /// the entry from this map, and use the waker to complete the future with the
-  /// received response message.
-  future_map: DashMap<u32, ButtplugServerMessageStateShared>,
+  /// received response message. The message's span is held alongside the
+  /// future, so that it closes once the request is resolved.
+  future_map: Arc<DashMap<u32, (ButtplugServerMessageStateShared, Span)>>,
 
   /// Message `id` counter
   ///
   /// Every time we add a message to the future_map, we need it to have a unique
-  /// `id`. We assume that unsigned 2^32 will be enough (Buttplug isn't THAT
-  /// chatty), and use it as a monotonically increasing counter for setting `id`s.
+  /// `id`. We use it as a monotonically increasing counter for setting `id`s,
+  /// wrapping around to 1 (skipping the reserved event `id` of 0) after
+  /// [u32::MAX], and skipping any `id` that is still waiting on a response.
   current_id: Arc<AtomicU32>,
+
+  /// Amount of time to wait for a response before failing the future. If None,
+  /// futures will wait until a response comes in or the client disconnects.
+  request_timeout: Option<Duration>,
+
+  /// Maximum number of futures that can be waiting on responses at once.
+  max_pending_requests: usize,
 }
 
 impl ClientMessageSorter {
+  /// Creates a sorter that fails requests after `request_timeout` (or never, if
+  /// None), and keeps at most `max_pending_requests` futures waiting at once.
+  /// Message `id`s start at `first_message_id`, or 1 if that is 0.
+  pub fn new(
+    request_timeout: Option<Duration>,
+    max_pending_requests: usize,
+    first_message_id: u32,
+  ) -> Self {
+    Self {
+      future_map: Arc::new(DashMap::new()),
+      current_id: Arc::new(AtomicU32::new(first_message_id.max(1))),
+      request_timeout,
+      max_pending_requests,
+    }
+  }
+
+  /// Returns the next free message `id`, and moves the counter past it.
+  fn next_id(&self) -> u32 {
+    loop {
+      let id = self.current_id.load(Ordering::SeqCst);
+      let next_id = if id == u32::MAX { 1 } else { id + 1 };
+      self.current_id.store(next_id, Ordering::SeqCst);
+      // After wrapping around, a request from the last pass over the id space
+      // could still be waiting on its response.
+      if !self.future_map.contains_key(&id) {
+        return id;
+      }
+    }
+  }
+
   /// Registers a future to be resolved when we receive a response.
   ///
   /// Given a message and its related future, set the message's `id`, and match
   /// that id with the future to be resolved when we get a response back.
   pub fn register_future(&self, msg_fut: &mut ButtplugClientMessageFuturePair) {
-    let id = self.current_id.load(Ordering::SeqCst);
+    // Make room before picking an id, in case the map is full of every other
+    // id.
+    while self.future_map.len() >= self.max_pending_requests.max(1) {
+      // Ids are handed out in increasing order, wrapping around, so the request
+      // that has been waiting the longest is the one furthest behind the
+      // counter.
+      let current_id = self.current_id.load(Ordering::SeqCst);
+      let oldest_id = match self
+        .future_map
+        .iter()
+        .map(|entry| *entry.key())
+        .min_by_key(|id| id.wrapping_sub(current_id))
+      {
+        Some(oldest_id) => oldest_id,
+        None => break,
+      };
+      if let Some((_, (state, _))) = self.future_map.remove(&oldest_id) {
+        warn!(
+          "Too many requests waiting on server responses, evicting message id {}.",
+          oldest_id
+        );
+        state.set_reply(Err(ButtplugClientError::RequestTimeout));
+      }
+    }
+    let id = self.next_id();
     trace!("Setting message id to {}", id);
     msg_fut.msg.set_id(id);
-    self.future_map.insert(id, msg_fut.waker.clone());
-    self.current_id.store(id + 1, Ordering::SeqCst);
+    let span = message_span(|| {
+      let device_index = ButtplugDeviceCommandMessageUnion::try_from(ButtplugClientMessage::from(
+        msg_fut.msg.clone(),
+      ))
+      .ok()
+      .map(|device_msg| device_msg.device_index());
+      info_span!("Buttplug Client Message", id, device_index)
+    });
+    self.future_map.insert(id, (msg_fut.waker.clone(), span));
+    if let Some(timeout) = self.request_timeout {
+      let future_map = self.future_map.clone();
+      async_manager::spawn(async move {
+        Delay::new(timeout).await;
+        // If the entry is still here, the server never answered.
+        if let Some((_, (state, _))) = future_map.remove(&id) {
+          warn!("No response received for message id {}, timing out.", id);
+          state.set_reply(Err(ButtplugClientError::RequestTimeout));
+        }
+      });
+    }
   }
 
   /// Given a response message from the server, resolve related future if we
@@ -99,7 +204,7 @@ impl ClientMessageSorter {
     let id = msg.id();
     trace!("Trying to resolve message future for id {}.", id);
     match self.future_map.remove(&id) {
-      Some((_, state)) => {
+      Some((_, (state, _span))) => {
         trace!("Resolved id {} to a future.", id);
         if let Err(e) = msg.is_valid() {
           error!("Message not valid: {:?} - Error: {}", msg, e);
@@ -125,9 +230,60 @@ impl Default for ClientMessageSorter {
   /// Sets the current_id to 1, since as a client we can't send message `id` of
   /// 0 (0 is reserved for system incoming messages).
   fn default() -> Self {
-    Self {
-      future_map: DashMap::new(),
-      current_id: Arc::new(AtomicU32::new(1)),
-    }
+    Self::new(
+      Some(DEFAULT_REQUEST_TIMEOUT),
+      DEFAULT_MAX_PENDING_REQUESTS,
+      1,
+    )
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::ClientMessageSorter;
+  use crate::{
+    client::{ButtplugClientMessageFuturePair, ButtplugServerMessageFuture},
+    core::messages::{ButtplugMessage, Ping},
+  };
+  use std::sync::atomic::Ordering;
+
+  fn register(sorter: &ClientMessageSorter) -> u32 {
+    let mut msg_fut = ButtplugClientMessageFuturePair::new(
+      Ping::default().into(),
+      ButtplugServerMessageFuture::default().get_state_clone(),
+    );
+    sorter.register_future(&mut msg_fut);
+    msg_fut.msg.id()
+  }
+
+  #[test]
+  fn test_message_id_wraparound() {
+    let sorter = ClientMessageSorter::new(None, 16, u32::MAX - 1);
+    assert_eq!(register(&sorter), u32::MAX - 1);
+    assert_eq!(register(&sorter), u32::MAX);
+    // 0 is reserved for server events.
+    assert_eq!(register(&sorter), 1);
+    assert_eq!(register(&sorter), 2);
+  }
+
+  #[test]
+  fn test_message_id_skips_waiting_requests() {
+    let sorter = ClientMessageSorter::new(None, 16, 1);
+    assert_eq!(register(&sorter), 1);
+    assert_eq!(register(&sorter), 2);
+    // Pretend we've gone all the way around the id space.
+    sorter.current_id.store(1, Ordering::SeqCst);
+    assert_eq!(register(&sorter), 3);
+  }
+
+  #[test]
+  fn test_oldest_request_evicted_after_wraparound() {
+    let sorter = ClientMessageSorter::new(None, 2, u32::MAX);
+    assert_eq!(register(&sorter), u32::MAX);
+    assert_eq!(register(&sorter), 1);
+    assert_eq!(register(&sorter), 2);
+    assert!(!sorter.future_map.contains_key(&u32::MAX));
+    assert!(sorter.future_map.contains_key(&1));
+    assert!(sorter.future_map.contains_key(&2));
   }
 }
@@ -7,7 +7,13 @@
 
 //! Representation and management of devices connected to the server.
 
-use super::{ButtplugClientError, ButtplugClientRequest, ButtplugClientResultFuture};
+use super::{
+  server_time_from_offset,
+  ButtplugClientError,
+  ButtplugClientRequest,
+  ButtplugClientResult,
+  ButtplugClientResultFuture,
+};
 use crate::{
   client::{ButtplugClientMessageFuturePair, ButtplugServerMessageFuture},
   connector::ButtplugConnectorError,
@@ -22,7 +28,10 @@ use crate::{
       DeviceMessageAttributes,
       DeviceMessageAttributesMap,
       DeviceMessageInfo,
+      DeviceRemovedReason,
       LinearCmd,
+      OscillateCmd,
+      OscillateSubcommand,
       RSSILevelCmd,
       RawReadCmd,
       RawSubscribeCmd,
@@ -30,7 +39,10 @@ use crate::{
       RawWriteCmd,
       RotateCmd,
       RotationSubcommand,
+      SensorSubscribeCmd,
+      SensorUnsubscribeCmd,
       StopDeviceCmd,
+      UploadPatternCmd,
       VectorSubcommand,
       VibrateCmd,
       VibrateSubcommand,
@@ -39,7 +51,9 @@ use crate::{
   device::Endpoint,
   util::stream::convert_broadcast_receiver_to_stream,
 };
+use async_stream::stream;
 use futures::{future, Stream};
+use futures_timer::Delay;
 use std::{
   collections::HashMap,
   convert::TryFrom,
@@ -47,16 +61,48 @@ use std::{
   sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
+    Mutex,
+    RwLock,
   },
+  time::{Duration, Instant},
 };
 use tokio::sync::broadcast;
 use tracing_futures::Instrument;
 
+/// Why a [ButtplugClientDevice] was disconnected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceDisconnectReason {
+  /// Disconnected on request, via the server's device manager.
+  UserRequested,
+  /// The device went away without being asked to.
+  ConnectionLost,
+  /// Disconnected by the server after a ping timeout, as it couldn't be
+  /// stopped.
+  PingTimeout,
+  /// The server's device communication manager for the device shut down.
+  CommManagerShutdown,
+  /// This client disconnected from the server.
+  ClientDisconnected,
+  /// The server didn't say why. Servers only give a reason as of spec v3.
+  Unknown,
+}
+
+impl From<DeviceRemovedReason> for DeviceDisconnectReason {
+  fn from(reason: DeviceRemovedReason) -> Self {
+    match reason {
+      DeviceRemovedReason::UserRequested => Self::UserRequested,
+      DeviceRemovedReason::ConnectionLost => Self::ConnectionLost,
+      DeviceRemovedReason::PingTimeout => Self::PingTimeout,
+      DeviceRemovedReason::CommManagerShutdown => Self::CommManagerShutdown,
+    }
+  }
+}
+
 /// Enum for messages going to a [ButtplugClientDevice] instance.
 #[derive(Clone, Debug)]
 pub enum ButtplugClientDeviceEvent {
   /// Device has disconnected from server.
-  DeviceRemoved,
+  DeviceRemoved(DeviceDisconnectReason),
   /// Client has disconnected from server.
   ClientDisconnect,
   /// Message was received from server for that specific device.
@@ -98,6 +144,23 @@ pub enum RotateCommand {
   RotateMap(HashMap<u32, (f64, bool)>),
 }
 
+/// Convenience enum for forming [OscillateCmd] commands.
+///
+/// Allows users to easily specify speeds across different oscillation features
+/// in a device. Units are in absolute speed values (0.0-1.0).
+pub enum OscillateCommand {
+  /// Sets all oscillation features of a device to the same speed.
+  Speed(f64),
+  /// Sets oscillation features to speed based on the index of the speed in the
+  /// vec (i.e. oscillator 0 is set to `SpeedVec[0]`, oscillator 1 is set to
+  /// `SpeedVec[1]`, etc...)
+  SpeedVec(Vec<f64>),
+  /// Sets oscillation features indicated by index to requested speed. For
+  /// instance, if the map has an entry of (1, 0.5), it will set oscillator 1
+  /// to a speed of 0.5.
+  SpeedMap(HashMap<u32, f64>),
+}
+
 /// Convenience enum for forming [LinearCmd] commands.
 ///
 /// Allows users to easily specify position/durations across different rotation
@@ -116,6 +179,67 @@ pub enum LinearCommand {
   LinearMap(HashMap<u32, (u32, f64)>),
 }
 
+/// Settings for skipping redundant vibration commands. See
+/// [ButtplugClientDevice::set_vibrate_deduplication].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VibrateDeduplication {
+  /// Unchanged speeds are still resent once this long has passed since they
+  /// were last sent, in case a command was lost or the device was stopped
+  /// from somewhere else. None never resends them.
+  pub refresh_interval: Option<Duration>,
+}
+
+/// Vibration speeds last sent to a device, while deduplication is on.
+struct SentVibrateSpeeds {
+  options: VibrateDeduplication,
+  speeds: HashMap<u32, (f64, Instant)>,
+}
+
+impl SentVibrateSpeeds {
+  fn new(options: VibrateDeduplication) -> Self {
+    Self {
+      options,
+      speeds: HashMap::new(),
+    }
+  }
+
+  /// Drops subcommands for vibrators already running at the requested speed,
+  /// and records the rest as sent.
+  fn filter(&mut self, subcommands: Vec<VibrateSubcommand>) -> Vec<VibrateSubcommand> {
+    let now = Instant::now();
+    let refresh_interval = self.options.refresh_interval;
+    subcommands
+      .into_iter()
+      .filter(|subcommand| {
+        let unchanged = match self.speeds.get(&subcommand.index()) {
+          Some((speed, sent)) => {
+            *speed == subcommand.speed()
+              && match refresh_interval {
+                Some(interval) => now.duration_since(*sent) < interval,
+                None => true,
+              }
+          }
+          None => false,
+        };
+        if !unchanged {
+          self
+            .speeds
+            .insert(subcommand.index(), (subcommand.speed(), now));
+        }
+        !unchanged
+      })
+      .collect()
+  }
+
+  /// Forgets what was sent to `indexes`, so they're sent next time no matter
+  /// what.
+  fn forget(&mut self, indexes: &[u32]) {
+    for index in indexes {
+      self.speeds.remove(index);
+    }
+  }
+}
+
 // Using a macro here so we can encabe the return statement. Otherwise we'd have
 // to do validity checks on every call since we return futures, not results.
 macro_rules! check_message_support {
@@ -157,6 +281,8 @@ pub struct ButtplugClientDevice {
   /// [ButtplugServer][crate::server::ButtplugServer]'s
   /// [DeviceManager][crate::server::device_manager::DeviceManager].
   index: u32,
+  /// Hardware address of the device, if the server sent one.
+  address: Option<String>,
   /// Map of messages the device can take, along with the attributes of those
   /// messages.
   pub allowed_messages: ClientDeviceMessageAttributesMap,
@@ -173,6 +299,11 @@ pub struct ButtplugClientDevice {
   /// [ButtplugClientDevice] instance is still connected to the
   /// [ButtplugServer][crate::server::ButtplugServer].
   client_connected: Arc<AtomicBool>,
+  /// Set while vibration commands are deduplicated.
+  sent_vibrate_speeds: Arc<Mutex<Option<SentVibrateSpeeds>>>,
+  /// Clock offset shared with the [ButtplugClient][super::ButtplugClient],
+  /// set once it has synced with the server's clock.
+  server_time_offset: Arc<RwLock<Option<i64>>>,
 }
 
 unsafe impl Send for ButtplugClientDevice {
@@ -197,8 +328,10 @@ impl ButtplugClientDevice {
   pub(super) fn new(
     name: &str,
     index: u32,
+    address: Option<String>,
     allowed_messages: ClientDeviceMessageAttributesMap,
     message_sender: broadcast::Sender<ButtplugClientRequest>,
+    server_time_offset: Arc<RwLock<Option<i64>>>,
   ) -> Self {
     info!(
       "Creating client device {} with index {} and messages {:?}.",
@@ -211,23 +344,29 @@ impl ButtplugClientDevice {
     Self {
       name: name.to_owned(),
       index,
+      address,
       allowed_messages,
       event_loop_sender: message_sender,
       internal_event_sender: event_sender,
       device_connected,
       client_connected,
+      sent_vibrate_speeds: Arc::new(Mutex::new(None)),
+      server_time_offset,
     }
   }
 
   pub(super) fn new_from_device_info(
     info: &DeviceMessageInfo,
     sender: broadcast::Sender<ButtplugClientRequest>,
+    server_time_offset: Arc<RwLock<Option<i64>>>,
   ) -> Self {
     ButtplugClientDevice::new(
       &*info.device_name,
       info.device_index,
+      info.device_address.clone(),
       convert_to_client_device_map(&info.device_messages),
       sender,
+      server_time_offset,
     )
   }
 
@@ -343,7 +482,7 @@ impl ButtplugClientDevice {
               .into(),
           );
         }
-        speed_vec = Vec::with_capacity(map.len() as usize);
+        speed_vec = Vec::with_capacity(map.len());
         for (idx, speed) in map {
           if idx > vibrator_count - 1 {
             return self.create_boxed_future_client_error(
@@ -360,13 +499,130 @@ impl ButtplugClientDevice {
               .into(),
           );
         }
-        speed_vec = Vec::with_capacity(vec.len() as usize);
+        speed_vec = Vec::with_capacity(vec.len());
         for (i, v) in vec.iter().enumerate() {
           speed_vec.push(VibrateSubcommand::new(i as u32, *v));
         }
       }
     }
-    let msg = VibrateCmd::new(self.index, speed_vec).into();
+    let speed_vec = match self
+      .sent_vibrate_speeds
+      .lock()
+      .expect("We never panic while holding this lock.")
+      .as_mut()
+    {
+      Some(sent_speeds) => {
+        let speed_vec = sent_speeds.filter(speed_vec);
+        if speed_vec.is_empty() {
+          trace!(
+            "Vibration speeds for device {} unchanged, skipping.",
+            self.index
+          );
+          return Box::pin(future::ready(Ok(())));
+        }
+        speed_vec
+      }
+      None => speed_vec,
+    };
+    let indexes: Vec<u32> = speed_vec.iter().map(|speed| speed.index()).collect();
+    let send_fut = self.send_message_expect_ok(VibrateCmd::new(self.index, speed_vec).into());
+    let sent_vibrate_speeds = self.sent_vibrate_speeds.clone();
+    Box::pin(async move {
+      let result = send_fut.await;
+      // If the speeds didn't make it, make sure they're sent next time.
+      if result.is_err() {
+        if let Some(sent_speeds) = sent_vibrate_speeds
+          .lock()
+          .expect("We never panic while holding this lock.")
+          .as_mut()
+        {
+          sent_speeds.forget(&indexes);
+        }
+      }
+      result
+    })
+  }
+
+  /// Turns on skipping of redundant vibration commands, or turns it off if
+  /// `deduplication` is None.
+  ///
+  /// While on, the device remembers the last speed sent to each vibrator, and
+  /// [vibrate][ButtplugClientDevice::vibrate] only sends speeds that changed
+  /// since. If nothing changed, no command is sent at all. Meant for apps like
+  /// games that send the current speed every frame, to cut down on traffic to
+  /// the server and device.
+  pub fn set_vibrate_deduplication(&self, deduplication: Option<VibrateDeduplication>) {
+    *self
+      .sent_vibrate_speeds
+      .lock()
+      .expect("We never panic while holding this lock.") =
+      deduplication.map(SentVibrateSpeeds::new);
+  }
+
+  /// Forgets deduplicated vibration speeds, e.g. after the device was
+  /// stopped, so the next speeds are sent even if they match.
+  pub(super) fn forget_sent_commands(&self) {
+    if let Some(sent_speeds) = self
+      .sent_vibrate_speeds
+      .lock()
+      .expect("We never panic while holding this lock.")
+      .as_mut()
+    {
+      sent_speeds.speeds.clear();
+    }
+  }
+
+  /// Commands device to oscillate, assuming it has the features to do so.
+  pub fn oscillate(&self, speed_cmd: OscillateCommand) -> ButtplugClientResultFuture {
+    check_message_support!(self, ButtplugCurrentSpecDeviceMessageType::OscillateCmd);
+    let mut oscillator_count: u32 = 0;
+    if let Some(features) = self
+      .allowed_messages
+      .get(&ButtplugCurrentSpecDeviceMessageType::OscillateCmd)
+    {
+      if let Some(v) = features.feature_count {
+        oscillator_count = v;
+      }
+    }
+    let mut speed_vec: Vec<OscillateSubcommand>;
+    match speed_cmd {
+      OscillateCommand::Speed(speed) => {
+        speed_vec = Vec::with_capacity(oscillator_count as usize);
+        for i in 0..oscillator_count {
+          speed_vec.push(OscillateSubcommand::new(i, speed));
+        }
+      }
+      OscillateCommand::SpeedMap(map) => {
+        if map.len() as u32 > oscillator_count {
+          return self.create_boxed_future_client_error(
+            ButtplugDeviceError::DeviceFeatureCountMismatch(oscillator_count, map.len() as u32)
+              .into(),
+          );
+        }
+        speed_vec = Vec::with_capacity(map.len());
+        for (idx, speed) in map {
+          if idx > oscillator_count - 1 {
+            return self.create_boxed_future_client_error(
+              ButtplugDeviceError::DeviceFeatureIndexError(oscillator_count, idx).into(),
+            );
+          }
+          speed_vec.push(OscillateSubcommand::new(idx, speed));
+        }
+      }
+      OscillateCommand::SpeedVec(vec) => {
+        if vec.len() as u32 > oscillator_count {
+          return self.create_boxed_future_client_error(
+            ButtplugDeviceError::DeviceFeatureCountMismatch(oscillator_count, vec.len() as u32)
+              .into(),
+          );
+        }
+        speed_vec = Vec::with_capacity(vec.len());
+        for (i, v) in vec.iter().enumerate() {
+          speed_vec.push(OscillateSubcommand::new(i as u32, *v));
+        }
+      }
+    }
+    let msg = OscillateCmd::new(self.index, speed_vec).into();
     self.send_message_expect_ok(msg)
   }
 
@@ -396,7 +652,7 @@ impl ButtplugClientDevice {
             ButtplugDeviceError::DeviceFeatureCountMismatch(linear_count, map.len() as u32).into(),
           );
         }
-        linear_vec = Vec::with_capacity(map.len() as usize);
+        linear_vec = Vec::with_capacity(map.len());
         for (idx, (dur, pos)) in map {
           if idx > linear_count - 1 {
             return self.create_boxed_future_client_error(
@@ -412,14 +668,23 @@ impl ButtplugClientDevice {
             ButtplugDeviceError::DeviceFeatureCountMismatch(linear_count, vec.len() as u32).into(),
           );
         }
-        linear_vec = Vec::with_capacity(vec.len() as usize);
+        linear_vec = Vec::with_capacity(vec.len());
         for (i, v) in vec.iter().enumerate() {
           linear_vec.push(VectorSubcommand::new(i as u32, v.0, v.1));
         }
       }
     }
-    let msg = LinearCmd::new(self.index, linear_vec).into();
-    self.send_message_expect_ok(msg)
+    let mut msg = LinearCmd::new(self.index, linear_vec);
+    // Once the client knows the server's clock, timestamp the command so the
+    // server can make up for the time it takes to get there.
+    msg.set_sent_at(
+      self
+        .server_time_offset
+        .read()
+        .expect("We never panic while holding this lock.")
+        .map(server_time_from_offset),
+    );
+    self.send_message_expect_ok(msg.into())
   }
 
   /// Commands device to rotate, assuming it has the features to do so.
@@ -448,7 +713,7 @@ impl ButtplugClientDevice {
             ButtplugDeviceError::DeviceFeatureCountMismatch(rotate_count, map.len() as u32).into(),
           );
         }
-        rotate_vec = Vec::with_capacity(map.len() as usize);
+        rotate_vec = Vec::with_capacity(map.len());
         for (idx, (speed, clockwise)) in map {
           if idx > rotate_count - 1 {
             return self.create_boxed_future_client_error(
@@ -464,7 +729,7 @@ impl ButtplugClientDevice {
             ButtplugDeviceError::DeviceFeatureCountMismatch(rotate_count, vec.len() as u32).into(),
           );
         }
-        rotate_vec = Vec::with_capacity(vec.len() as usize);
+        rotate_vec = Vec::with_capacity(vec.len());
         for (i, v) in vec.iter().enumerate() {
           rotate_vec.push(RotationSubcommand::new(i as u32, v.0, v.1));
         }
@@ -514,6 +779,66 @@ impl ButtplugClientDevice {
     })
   }
 
+  /// Polls the battery level of the device every `interval`, yielding each
+  /// reading (0.0-1.0) as it arrives.
+  ///
+  /// The stream ends when the device or client disconnects, or after yielding
+  /// the first error returned for a reading.
+  pub fn battery_stream(
+    &self,
+    interval: Duration,
+  ) -> Box<dyn Stream<Item = ButtplugClientResult<f64>> + Send + Unpin> {
+    self.reading_stream(interval, ButtplugClientDevice::battery_level)
+  }
+
+  /// Polls the RSSI level of the device every `interval`, yielding each
+  /// reading as it arrives.
+  ///
+  /// The stream ends when the device or client disconnects, or after yielding
+  /// the first error returned for a reading.
+  pub fn rssi_stream(
+    &self,
+    interval: Duration,
+  ) -> Box<dyn Stream<Item = ButtplugClientResult<i32>> + Send + Unpin> {
+    self.reading_stream(interval, ButtplugClientDevice::rssi_level)
+  }
+
+  fn reading_stream<T>(
+    &self,
+    interval: Duration,
+    read: fn(&ButtplugClientDevice) -> ButtplugClientResultFuture<T>,
+  ) -> Box<dyn Stream<Item = ButtplugClientResult<T>> + Send + Unpin>
+  where
+    T: 'static + Send,
+  {
+    // The stream outlives our borrow of self, so it gets its own handle that
+    // shares our event loop sender and connection status.
+    let device = ButtplugClientDevice {
+      name: self.name.clone(),
+      index: self.index,
+      address: self.address.clone(),
+      allowed_messages: self.allowed_messages.clone(),
+      event_loop_sender: self.event_loop_sender.clone(),
+      internal_event_sender: self.internal_event_sender.clone(),
+      device_connected: self.device_connected.clone(),
+      client_connected: self.client_connected.clone(),
+      sent_vibrate_speeds: self.sent_vibrate_speeds.clone(),
+      server_time_offset: self.server_time_offset.clone(),
+    };
+    Box::new(Box::pin(stream! {
+      while device.connected() && device.client_connected.load(Ordering::SeqCst) {
+        match read(&device).await {
+          Ok(reading) => yield Ok(reading),
+          Err(err) => {
+            yield Err(err);
+            break;
+          }
+        }
+        Delay::new(interval).await;
+      }
+    }))
+  }
+
   pub fn raw_write(
     &self,
     endpoint: Endpoint,
@@ -577,11 +902,48 @@ impl ButtplugClientDevice {
     self.send_message_expect_ok(msg)
   }
 
+  /// Starts readings from one of the device's sensors, which show up as
+  /// [ButtplugClientDeviceEvent::Message] events on the device [event
+  /// stream][ButtplugClientDevice::event_stream].
+  pub fn subscribe_sensor(&self, sensor_index: u32) -> ButtplugClientResultFuture {
+    check_message_support!(
+      self,
+      ButtplugCurrentSpecDeviceMessageType::SensorSubscribeCmd
+    );
+    let msg = ButtplugCurrentSpecClientMessage::SensorSubscribeCmd(SensorSubscribeCmd::new(
+      self.index,
+      sensor_index,
+    ));
+    self.send_message_expect_ok(msg)
+  }
+
+  pub fn unsubscribe_sensor(&self, sensor_index: u32) -> ButtplugClientResultFuture {
+    check_message_support!(
+      self,
+      ButtplugCurrentSpecDeviceMessageType::SensorUnsubscribeCmd
+    );
+    let msg = ButtplugCurrentSpecClientMessage::SensorUnsubscribeCmd(SensorUnsubscribeCmd::new(
+      self.index,
+      sensor_index,
+    ));
+    self.send_message_expect_ok(msg)
+  }
+
+  /// Uploads a pattern to a device that can store and play back patterns
+  /// from its own firmware. The format of `data` depends on the device.
+  pub fn upload_pattern(&self, data: Vec<u8>) -> ButtplugClientResultFuture {
+    check_message_support!(self, ButtplugCurrentSpecDeviceMessageType::UploadPatternCmd);
+    let msg =
+      ButtplugCurrentSpecClientMessage::UploadPatternCmd(UploadPatternCmd::new(self.index, data));
+    self.send_message_expect_ok(msg)
+  }
+
   /// Commands device to stop all movement.
   pub fn stop(&self) -> ButtplugClientResultFuture {
     // Everything *should* support StopDeviceCmd but let's just make sure.
     check_message_support!(self, ButtplugCurrentSpecDeviceMessageType::StopDeviceCmd);
     // All devices accept StopDeviceCmd
+    self.forget_sent_commands();
     self.send_message_expect_ok(StopDeviceCmd::new(self.index).into())
   }
 
@@ -589,6 +951,15 @@ impl ButtplugClientDevice {
     self.index
   }
 
+  /// Hardware address of the device. Unlike the index, this stays the same
+  /// across connections, so it can be used for keeping per-device settings.
+  ///
+  /// Servers only send addresses to in-process clients and clients using spec
+  /// v3, so this will be None for remote connections for now.
+  pub fn address(&self) -> Option<&str> {
+    self.address.as_deref()
+  }
+
   pub(super) fn set_device_connected(&self, connected: bool) {
     self.device_connected.store(connected, Ordering::SeqCst);
   }
@@ -626,3 +997,47 @@ impl fmt::Debug for ButtplugClientDevice {
       .finish()
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::{SentVibrateSpeeds, VibrateDeduplication};
+  use crate::core::messages::VibrateSubcommand;
+  use std::{thread, time::Duration};
+
+  fn speeds(speeds: &[(u32, f64)]) -> Vec<VibrateSubcommand> {
+    speeds
+      .iter()
+      .map(|(index, speed)| VibrateSubcommand::new(*index, *speed))
+      .collect()
+  }
+
+  #[test]
+  fn test_vibrate_deduplication_sends_changes() {
+    let mut sent = SentVibrateSpeeds::new(VibrateDeduplication::default());
+    assert_eq!(
+      sent.filter(speeds(&[(0, 0.5), (1, 0.5)])),
+      speeds(&[(0, 0.5), (1, 0.5)])
+    );
+    assert!(sent.filter(speeds(&[(0, 0.5), (1, 0.5)])).is_empty());
+    assert_eq!(
+      sent.filter(speeds(&[(0, 0.5), (1, 1.0)])),
+      speeds(&[(1, 1.0)])
+    );
+    sent.forget(&[0]);
+    assert_eq!(
+      sent.filter(speeds(&[(0, 0.5), (1, 1.0)])),
+      speeds(&[(0, 0.5)])
+    );
+  }
+
+  #[test]
+  fn test_vibrate_deduplication_refresh() {
+    let mut sent = SentVibrateSpeeds::new(VibrateDeduplication {
+      refresh_interval: Some(Duration::from_millis(50)),
+    });
+    assert_eq!(sent.filter(speeds(&[(0, 0.5)])), speeds(&[(0, 0.5)]));
+    assert!(sent.filter(speeds(&[(0, 0.5)])).is_empty());
+    thread::sleep(Duration::from_millis(60));
+    assert_eq!(sent.filter(speeds(&[(0, 0.5)])), speeds(&[(0, 0.5)]));
+  }
+}
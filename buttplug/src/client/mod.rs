@@ -6,51 +6,76 @@
 // for full license information.
 
 //! Communications API for accessing Buttplug Servers
+#[cfg(feature = "audio-reactive")]
+pub mod audio_reactive;
+#[cfg(not(feature = "wasm-bindgen-runtime"))]
+pub mod blocking;
+mod client_event_dispatcher;
+pub use client_event_dispatcher::ButtplugClientEventCallbackHandle;
 pub mod client_event_loop;
 mod client_message_sorter;
+pub use client_message_sorter::{DEFAULT_MAX_PENDING_REQUESTS, DEFAULT_REQUEST_TIMEOUT};
 pub mod device;
+#[cfg(feature = "osc-bridge")]
+pub mod osc_bridge;
 
 #[cfg(feature = "server")]
 use crate::server::ButtplugServer;
 use crate::{
   connector::{ButtplugConnector, ButtplugConnectorError, ButtplugConnectorFuture},
   core::{
-    errors::{ButtplugError, ButtplugHandshakeError},
+    errors::{ButtplugError, ButtplugHandshakeError, ButtplugMessageError},
     messages::{
       ButtplugCurrentSpecClientMessage,
       ButtplugCurrentSpecServerMessage,
       ButtplugMessageSpecVersion,
+      ClaimDevice,
+      CommManagerScanningFailure,
+      DeviceInitializationFailed,
       Ping,
+      ReleaseDevice,
       RequestDeviceList,
       RequestServerInfo,
+      RequestServerTime,
       StartScanning,
       StopAllDevices,
       StopScanning,
+      BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
     },
   },
   util::{
     async_manager,
     future::{ButtplugFuture, ButtplugFutureStateShared},
     stream::convert_broadcast_receiver_to_stream,
+    time::unix_time_millis,
   },
 };
+use client_event_dispatcher::ClientEventDispatcher;
 use client_event_loop::{ButtplugClientEventLoop, ButtplugClientRequest};
+use client_message_sorter::ClientMessageSorter;
 use dashmap::DashMap;
 pub use device::{
   ButtplugClientDevice,
   ButtplugClientDeviceEvent,
   ButtplugClientDeviceMessageType,
+  DeviceDisconnectReason,
   LinearCommand,
+  OscillateCommand,
   RotateCommand,
   VibrateCommand,
+  VibrateDeduplication,
 };
 use futures::{
   future::{self, BoxFuture},
   Stream,
 };
-use std::sync::{
-  atomic::{AtomicBool, Ordering},
-  Arc,
+use std::{
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+    RwLock,
+  },
+  time::Duration,
 };
 use thiserror::Error;
 use tokio::sync::{broadcast, mpsc, Mutex};
@@ -65,6 +90,16 @@ use tracing_futures::Instrument;
 type ButtplugClientResult<T = ()> = Result<T, ButtplugClientError>;
 type ButtplugClientResultFuture<T = ()> = BoxFuture<'static, ButtplugClientResult<T>>;
 
+/// Number of timestamp exchanges [ButtplugClient::sync_server_time] runs. The
+/// exchange with the shortest round trip is used for the estimate.
+pub const TIME_SYNC_SAMPLES: u32 = 5;
+
+/// Current server time, given the offset found by
+/// [ButtplugClient::sync_server_time].
+fn server_time_from_offset(offset: i64) -> u64 {
+  (unix_time_millis() as i64 + offset).max(0) as u64
+}
+
 /// Result type used for passing server responses.
 pub type ButtplugServerMessageResult = ButtplugClientResult<ButtplugCurrentSpecServerMessage>;
 pub type ButtplugServerMessageResultFuture =
@@ -108,12 +143,14 @@ impl ButtplugClientMessageFuturePair {
 
 /// Represents all of the different types of errors a ButtplugClient can return.
 ///
-/// Clients can return two types of errors:
+/// Clients can return three types of errors:
 ///
 /// - [ButtplugConnectorError], which means there was a problem with the
-/// connection between the client and the server, like a network connection
-/// issue.
+///   connection between the client and the server, like a network connection
+///   issue.
 /// - [ButtplugError], which is an error specific to the Buttplug Protocol.
+/// - [ButtplugClientError::RequestTimeout], which means the server did not
+///   answer a request in time.
 #[derive(Debug, Error)]
 pub enum ButtplugClientError {
   /// Connector error
@@ -122,6 +159,10 @@ pub enum ButtplugClientError {
   /// Protocol error
   #[error(transparent)]
   ButtplugError(#[from] ButtplugError),
+  /// The server did not respond to a request in time. The request may be
+  /// retried.
+  #[error("Server did not respond to the request in time.")]
+  RequestTimeout,
 }
 
 /// Enum representing different events that can be emitted by a client.
@@ -140,8 +181,21 @@ pub enum ButtplugClientEvent {
   /// Emitted when a device has been removed from the server. Includes a
   /// [ButtplugClientDevice] object representing the device.
   DeviceRemoved(Arc<ButtplugClientDevice>),
+  /// Emitted when a client, possibly this one, claims a device. Includes the
+  /// device and the name of the claiming client.
+  DeviceClaimed(Arc<ButtplugClientDevice>, String),
+  /// Emitted when a device claim is released. Includes the device and the name
+  /// of the client that held the claim.
+  DeviceReleased(Arc<ButtplugClientDevice>, String),
+  /// Emitted when some of the server's device communication managers failed to
+  /// start or stop scanning. Scanning continues on the rest.
+  ScanningPartialFailure(Vec<CommManagerScanningFailure>),
+  /// Emitted when the server found a device that matched a protocol, but
+  /// couldn't connect to or initialize it, so it won't be added.
+  DeviceInitializationFailed(DeviceInitializationFailed),
   /// Emitted when a client has not pinged the server in a sufficient amount of
-  /// time.
+  /// time. Depending on the server's ping timeout policy, devices may have been
+  /// stopped, the client may have been disconnected, or both.
   PingTimeout,
   /// Emitted when the client successfully connects to a server.
   ServerConnect,
@@ -177,12 +231,23 @@ pub struct ButtplugClient {
   client_name: String,
   /// The server name that we're current connected to.
   server_name: Arc<Mutex<Option<String>>>,
-  event_stream: broadcast::Sender<ButtplugClientEvent>,
+  event_dispatcher: ClientEventDispatcher,
   // Sender to relay messages to the internal client loop
   message_sender: broadcast::Sender<ButtplugClientRequest>,
   connected: Arc<AtomicBool>,
   _client_span: Arc<Mutex<Option<Span>>>,
   device_map: Arc<DashMap<u32, Arc<ButtplugClientDevice>>>,
+  /// Milliseconds to add to our clock to get the server's, if
+  /// [ButtplugClient::sync_server_time] has been run on this connection.
+  server_time_offset: Arc<RwLock<Option<i64>>>,
+  /// Spec version the server we're connected to speaks, from its ServerInfo.
+  server_spec_version: Arc<RwLock<Option<ButtplugMessageSpecVersion>>>,
+  /// Amount of time to wait for a server response before failing a request.
+  request_timeout: Option<Duration>,
+  /// Maximum number of requests that can be waiting on server responses.
+  max_pending_requests: usize,
+  /// Id of the first message sent on a connection.
+  first_message_id: u32,
 }
 
 unsafe impl Send for ButtplugClient {
@@ -195,18 +260,56 @@ unsafe impl Sync for ButtplugClient {
 impl ButtplugClient {
   pub fn new(name: &str) -> Self {
     let (message_sender, _) = broadcast::channel(256);
-    let (event_stream, _) = broadcast::channel(256);
     Self {
       client_name: name.to_owned(),
       server_name: Arc::new(Mutex::new(None)),
-      event_stream,
+      event_dispatcher: ClientEventDispatcher::default(),
       message_sender,
       _client_span: Arc::new(Mutex::new(None)),
       connected: Arc::new(AtomicBool::new(false)),
       device_map: Arc::new(DashMap::new()),
+      server_time_offset: Arc::new(RwLock::new(None)),
+      server_spec_version: Arc::new(RwLock::new(None)),
+      request_timeout: Some(DEFAULT_REQUEST_TIMEOUT),
+      max_pending_requests: DEFAULT_MAX_PENDING_REQUESTS,
+      first_message_id: 1,
     }
   }
 
+  /// Sets how long to wait for the server to respond to a request.
+  ///
+  /// If the server doesn't respond in time, the request fails with
+  /// [ButtplugClientError::RequestTimeout]. None waits until the server
+  /// responds or disconnects. Defaults to [DEFAULT_REQUEST_TIMEOUT]. Takes
+  /// effect on the next connect.
+  pub fn request_timeout(mut self, timeout: Option<Duration>) -> Self {
+    self.request_timeout = timeout;
+    self
+  }
+
+  /// Sets the maximum number of requests that can be waiting on server
+  /// responses at once.
+  ///
+  /// If a new request would go over this limit, the oldest waiting request
+  /// fails with [ButtplugClientError::RequestTimeout]. Defaults to
+  /// [DEFAULT_MAX_PENDING_REQUESTS]. Takes effect on the next connect.
+  pub fn max_pending_requests(mut self, max: usize) -> Self {
+    self.max_pending_requests = max;
+    self
+  }
+
+  /// Sets the id of the first message sent on a connection.
+  ///
+  /// Message ids increase by one with each request, wrapping around to 1
+  /// after [u32::MAX], as 0 is reserved for server events. Ids of requests
+  /// still waiting on a response are skipped, so the server never sees an id
+  /// reused while it's in use. Defaults to 1. Takes effect on the next
+  /// connect.
+  pub fn first_message_id(mut self, id: u32) -> Self {
+    self.first_message_id = id;
+    self
+  }
+
   pub async fn connect<ConnectorType>(
     &self,
     mut connector: ConnectorType,
@@ -228,6 +331,15 @@ impl ButtplugClient {
       Some(span)
     };
     info!("Connecting to server.");
+    // Any offset we had belongs to the last server we were connected to.
+    *self
+      .server_time_offset
+      .write()
+      .expect("We never panic while holding this lock.") = None;
+    *self
+      .server_spec_version
+      .write()
+      .expect("We never panic while holding this lock.") = None;
     let (connector_sender, connector_receiver) = mpsc::channel(256);
     connector.connect(connector_sender).await.map_err(|e| {
       error!("Connection to server failed: {:?}", e);
@@ -238,9 +350,15 @@ impl ButtplugClient {
       self.connected.clone(),
       connector,
       connector_receiver,
-      self.event_stream.clone(),
+      self.event_dispatcher.clone(),
       self.message_sender.clone(),
       self.device_map.clone(),
+      self.server_time_offset.clone(),
+      ClientMessageSorter::new(
+        self.request_timeout,
+        self.max_pending_requests,
+        self.first_message_id,
+      ),
     );
 
     // Start the event loop before we run the handshake.
@@ -325,6 +443,15 @@ impl ButtplugClient {
         .add_comm_manager(SerialPortCommunicationManagerBuilder::default())
         .expect("Expected that all additions will work in connect_in_process.");
     }
+    #[cfg(feature = "usb-manager")]
+    {
+      use crate::server::comm_managers::usb::USBCommunicationManagerBuilder;
+      connector
+        .server_ref()
+        .device_manager()
+        .add_comm_manager(USBCommunicationManagerBuilder::default())
+        .expect("Expected that all additions will work in connect_in_process.");
+    }
     #[cfg(feature = "lovense-connect-service-manager")]
     {
       use crate::server::comm_managers::lovense_connect_service::LovenseConnectServiceCommunicationManagerBuilder;
@@ -372,16 +499,39 @@ impl ButtplugClient {
   async fn run_handshake(&self) -> ButtplugClientResult {
     // Run our handshake
     info!("Running handshake with server.");
-    let msg = self
+    let msg = match self
       .send_message_ignore_connect_status(
-        RequestServerInfo::new(&self.client_name, ButtplugMessageSpecVersion::Version2).into(),
+        RequestServerInfo::new(&self.client_name, BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into(),
       )
-      .await?;
+      .await
+    {
+      // Servers that only know spec v2 reject newer clients during the
+      // handshake, so try again as a v2 client. The server then only sends us
+      // v2 messages, and won't list v3 only messages for its devices.
+      Err(ButtplugClientError::ButtplugError(ButtplugError::ButtplugHandshakeError(err))) => {
+        info!(
+          "Server rejected spec version {}, retrying handshake with version {}: {}",
+          BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+          ButtplugMessageSpecVersion::Version2,
+          err
+        );
+        self
+          .send_message_ignore_connect_status(
+            RequestServerInfo::new(&self.client_name, ButtplugMessageSpecVersion::Version2).into(),
+          )
+          .await?
+      }
+      result => result?,
+    };
 
     debug!("Got ServerInfo return.");
     if let ButtplugCurrentSpecServerMessage::ServerInfo(server_info) = msg {
       info!("Connected to {}", server_info.server_name());
       *self.server_name.lock().await = Some(server_info.server_name().clone());
+      *self
+        .server_spec_version
+        .write()
+        .expect("We never panic while holding this lock.") = Some(server_info.message_version());
       // Don't set ourselves as connected until after ServerInfo has been
       // received. This means we avoid possible races with the RequestServerInfo
       // handshake.
@@ -457,11 +607,31 @@ impl ButtplugClient {
   /// Returns Err([ButtplugClientError]) if request fails due to issues with
   /// DeviceManagers on the server, disconnection, etc.
   pub fn stop_all_devices(&self) -> ButtplugClientResultFuture {
+    for device in self.device_map.iter() {
+      device.value().forget_sent_commands();
+    }
     self.send_message_expect_ok(StopAllDevices::default().into())
   }
 
+  /// Requests exclusive control of a device. While claimed, commands to the
+  /// device from other clients are rejected.
+  ///
+  /// Returns Err([ButtplugClientError]) if the device is already claimed by
+  /// another client, or has been removed.
+  pub fn claim_device(&self, device: &ButtplugClientDevice) -> ButtplugClientResultFuture {
+    self.send_message_expect_ok(ClaimDevice::new(device.index()).into())
+  }
+
+  /// Releases a claim on a device made with [ButtplugClient::claim_device].
+  ///
+  /// Returns Err([ButtplugClientError]) if the device isn't claimed by this
+  /// client.
+  pub fn release_device(&self, device: &ButtplugClientDevice) -> ButtplugClientResultFuture {
+    self.send_message_expect_ok(ReleaseDevice::new(device.index()).into())
+  }
+
   pub fn event_stream(&self) -> impl Stream<Item = ButtplugClientEvent> {
-    let stream = convert_broadcast_receiver_to_stream(self.event_stream.subscribe());
+    let stream = convert_broadcast_receiver_to_stream(self.event_dispatcher.subscribe());
     // We can either Box::pin here or force the user to pin_mut!() on their
     // end. While this does end up with a dynamic dispatch on our end, it
     // still makes the API nicer for the user, so we'll just eat the perf hit.
@@ -470,6 +640,21 @@ impl ButtplugClient {
     Box::pin(stream)
   }
 
+  /// Registers a callback to be called with each event the client emits,
+  /// as an alternative to polling [ButtplugClient::event_stream].
+  ///
+  /// Callbacks are called on a task of their own, one event at a time, in
+  /// the order events were emitted, and unlike streams never skip events if
+  /// they fall behind. The callback stays registered until the returned
+  /// handle is dropped or deregistered. Must be called from within the
+  /// library's runtime.
+  pub fn add_event_callback<F>(&self, callback: F) -> ButtplugClientEventCallbackHandle
+  where
+    F: Fn(ButtplugClientEvent) + Send + 'static,
+  {
+    self.event_dispatcher.add_callback(callback)
+  }
+
   /// Send message to the internal event loop.
   ///
   /// Mostly for handling boilerplate around possible send errors.
@@ -545,11 +730,112 @@ impl ButtplugClient {
       .collect()
   }
 
+  /// Retreives the connected device with the given hardware address. See
+  /// [ButtplugClientDevice::address] for when servers send addresses.
+  pub fn device_by_address(&self, address: &str) -> Option<Arc<ButtplugClientDevice>> {
+    self
+      .device_map
+      .iter()
+      .find(|map_pair| map_pair.value().address() == Some(address))
+      .map(|map_pair| map_pair.value().clone())
+  }
+
+  /// Retreives a connected device with the given name. Names aren't unique,
+  /// so if more than one device matches, the one with the lowest index is
+  /// returned.
+  pub fn device_by_name(&self, name: &str) -> Option<Arc<ButtplugClientDevice>> {
+    self
+      .device_map
+      .iter()
+      .filter(|map_pair| map_pair.value().name == name)
+      .min_by_key(|map_pair| *map_pair.key())
+      .map(|map_pair| map_pair.value().clone())
+  }
+
   pub fn ping(&self) -> ButtplugClientResultFuture {
     let ping_fut = self.send_message_expect_ok(Ping::default().into());
     Box::pin(async move { ping_fut.await })
   }
 
+  /// Estimates the offset between the server's clock and ours, by exchanging
+  /// timestamps with the server [TIME_SYNC_SAMPLES] times, assuming each
+  /// exchange spends the same time in both directions.
+  ///
+  /// Returns the offset in milliseconds, which is also stored and available
+  /// through [ButtplugClient::estimated_server_offset] until the client
+  /// reconnects. Clocks drift, so long running sessions should resync
+  /// occasionally.
+  ///
+  /// Servers older than spec v3 can't tell the time, so this fails without
+  /// asking them. Devices only timestamp commands once this has succeeded, so
+  /// older servers are never sent fields they don't know about.
+  pub async fn sync_server_time(&self) -> ButtplugClientResult<i64> {
+    let server_spec_version = *self
+      .server_spec_version
+      .read()
+      .expect("We never panic while holding this lock.");
+    if server_spec_version.is_none_or(|version| version < ButtplugMessageSpecVersion::Version3) {
+      return Err(
+        ButtplugError::from(ButtplugMessageError::UnhandledMessage(format!(
+          "RequestServerTime needs a spec v3 server, server speaks {:?}",
+          server_spec_version
+        )))
+        .into(),
+      );
+    }
+    // (round trip, offset) for the best exchange seen so far.
+    let mut best_sample: Option<(u64, i64)> = None;
+    for _ in 0..TIME_SYNC_SAMPLES {
+      let sent = unix_time_millis();
+      let reply = self
+        .send_message(RequestServerTime::default().into())
+        .await?;
+      let received = unix_time_millis();
+      let server_time = match reply {
+        ButtplugCurrentSpecServerMessage::ServerTime(time) => time.timestamp(),
+        ButtplugCurrentSpecServerMessage::Error(err) => {
+          return Err(ButtplugError::from(err).into())
+        }
+        msg => {
+          return Err(
+            ButtplugError::from(ButtplugMessageError::UnexpectedMessageType(format!(
+              "{:?}",
+              msg
+            )))
+            .into(),
+          )
+        }
+      };
+      let round_trip = received.saturating_sub(sent);
+      let offset = server_time as i64 - (sent + round_trip / 2) as i64;
+      if best_sample.is_none_or(|(best_round_trip, _)| round_trip < best_round_trip) {
+        best_sample = Some((round_trip, offset));
+      }
+    }
+    let (_, offset) = best_sample.expect("TIME_SYNC_SAMPLES is never 0.");
+    *self
+      .server_time_offset
+      .write()
+      .expect("We never panic while holding this lock.") = Some(offset);
+    Ok(offset)
+  }
+
+  /// Milliseconds to add to the local unix time to get the server's, as found
+  /// by the last [ButtplugClient::sync_server_time] call on this connection.
+  pub fn estimated_server_offset(&self) -> Option<i64> {
+    *self
+      .server_time_offset
+      .read()
+      .expect("We never panic while holding this lock.")
+  }
+
+  /// Current time on the server's clock, in milliseconds since the unix epoch,
+  /// for scheduling things in the server's timebase. None if
+  /// [ButtplugClient::sync_server_time] hasn't been run on this connection.
+  pub fn estimated_server_time(&self) -> Option<u64> {
+    self.estimated_server_offset().map(server_time_from_offset)
+  }
+
   pub fn server_name(&self) -> Option<String> {
     // We'd have to be calling server_name in an extremely tight, asynchronous
     // loop for this to return None, so we'll treat this as lockless.
@@ -0,0 +1,473 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Drives devices from OSC messages, e.g. avatar parameters sent by VRChat.
+//!
+//! [run_osc_bridge] listens on a UDP socket and looks up the address of every
+//! OSC message it receives in a table of [OscMapping]s supplied by the
+//! application. The first argument of a matching message sets the mapped
+//! actuator. OSC senders often update parameters every frame, much faster than
+//! Bluetooth devices can take commands, so values are collected and only the
+//! latest ones are sent, at most once per
+//! [OscBridgeOptions::min_command_interval_ms] for each device.
+
+use super::{
+  ButtplugClient,
+  ButtplugClientDevice,
+  LinearCommand,
+  OscillateCommand,
+  RotateCommand,
+  VibrateCommand,
+};
+use crate::util::async_manager;
+use futures::FutureExt;
+use futures_timer::Delay;
+use std::{
+  collections::HashMap,
+  convert::TryInto,
+  io,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+use thiserror::Error;
+use tokio::net::UdpSocket;
+
+/// Largest OSC packet the bridge reads.
+const MAX_PACKET_SIZE: usize = 65536;
+
+/// Device an OSC address is mapped to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum OscDevice {
+  Index(u32),
+  /// See [ButtplugClient::device_by_name] for how duplicate names are
+  /// handled.
+  Name(String),
+  Address(String),
+}
+
+/// Device feature an OSC address is mapped to, along with the feature index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OscActuator {
+  /// Speed from 0.0 to 1.0.
+  Vibrate(u32),
+  /// Speed from 0.0 to 1.0.
+  Oscillate(u32),
+  /// Speed from -1.0 to 1.0, with negative values rotating counterclockwise.
+  Rotate(u32),
+  /// Position from 0.0 to 1.0, reached over the command interval.
+  Linear(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OscMapping {
+  /// OSC address to match exactly, e.g. `/avatar/parameters/Vibe`.
+  pub address: String,
+  pub device: OscDevice,
+  pub actuator: OscActuator,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OscBridgeOptions {
+  pub mappings: Vec<OscMapping>,
+  /// Minimum milliseconds between commands to the same device.
+  pub min_command_interval_ms: u32,
+}
+
+impl Default for OscBridgeOptions {
+  fn default() -> Self {
+    Self {
+      mappings: vec![],
+      min_command_interval_ms: 50,
+    }
+  }
+}
+
+/// OSC message argument.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscArgument {
+  Int(i32),
+  Long(i64),
+  Float(f32),
+  Double(f64),
+  String(String),
+  Blob(Vec<u8>),
+  Bool(bool),
+  Nil,
+  Infinitum,
+}
+
+impl OscArgument {
+  /// Numeric value of the argument, with booleans as 0.0 or 1.0.
+  pub fn as_f64(&self) -> Option<f64> {
+    match self {
+      OscArgument::Int(value) => Some(f64::from(*value)),
+      OscArgument::Long(value) => Some(*value as f64),
+      OscArgument::Float(value) => Some(f64::from(*value)),
+      OscArgument::Double(value) => Some(*value),
+      OscArgument::Bool(value) => Some(if *value { 1.0 } else { 0.0 }),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage {
+  pub address: String,
+  pub arguments: Vec<OscArgument>,
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum OscParseError {
+  #[error("OSC packet ended unexpectedly.")]
+  UnexpectedEnd,
+  #[error("OSC packet contains a string that isn't null terminated UTF-8.")]
+  InvalidString,
+  #[error("OSC message has no type tag string.")]
+  MissingTypeTags,
+  #[error("OSC argument type {0} is not supported.")]
+  UnsupportedType(char),
+}
+
+/// Reads OSC packets, which are padded to 4 byte boundaries throughout.
+struct OscReader<'a> {
+  data: &'a [u8],
+}
+
+impl<'a> OscReader<'a> {
+  fn take(&mut self, len: usize) -> Result<&'a [u8], OscParseError> {
+    if self.data.len() < len {
+      return Err(OscParseError::UnexpectedEnd);
+    }
+    let (taken, rest) = self.data.split_at(len);
+    self.data = rest;
+    Ok(taken)
+  }
+
+  fn take_array<const N: usize>(&mut self) -> Result<[u8; N], OscParseError> {
+    Ok(self.take(N)?.try_into().expect("Took exactly N bytes."))
+  }
+
+  fn skip_padding(&mut self, len: usize) -> Result<(), OscParseError> {
+    self.take((4 - len % 4) % 4).map(|_| ())
+  }
+
+  fn read_string(&mut self) -> Result<String, OscParseError> {
+    let len = self
+      .data
+      .iter()
+      .position(|byte| *byte == 0)
+      .ok_or(OscParseError::InvalidString)?;
+    let string = std::str::from_utf8(self.take(len)?)
+      .map_err(|_| OscParseError::InvalidString)?
+      .to_owned();
+    // Skip the terminating null and the padding after it.
+    self.take(((len + 4) & !3) - len)?;
+    Ok(string)
+  }
+
+  fn read_blob(&mut self) -> Result<Vec<u8>, OscParseError> {
+    let len = i32::from_be_bytes(self.take_array()?);
+    let len = usize::try_from(len).map_err(|_| OscParseError::UnexpectedEnd)?;
+    let blob = self.take(len)?.to_vec();
+    self.skip_padding(len)?;
+    Ok(blob)
+  }
+}
+
+/// Parses an OSC packet, flattening bundles into the messages they contain.
+pub fn parse_osc_packet(packet: &[u8]) -> Result<Vec<OscMessage>, OscParseError> {
+  let mut messages = vec![];
+  parse_osc_packet_into(packet, &mut messages)?;
+  Ok(messages)
+}
+
+fn parse_osc_packet_into(
+  packet: &[u8],
+  messages: &mut Vec<OscMessage>,
+) -> Result<(), OscParseError> {
+  let mut reader = OscReader { data: packet };
+  let address = reader.read_string()?;
+  if address == "#bundle" {
+    // Bundle time tags are ignored, everything is applied as it arrives.
+    reader.take(8)?;
+    while !reader.data.is_empty() {
+      let element = reader.read_blob()?;
+      parse_osc_packet_into(&element, messages)?;
+    }
+    return Ok(());
+  }
+  // Some older senders leave out the type tags of messages without
+  // arguments.
+  if reader.data.is_empty() {
+    messages.push(OscMessage {
+      address,
+      arguments: vec![],
+    });
+    return Ok(());
+  }
+  let type_tags = reader.read_string()?;
+  let type_tags = type_tags
+    .strip_prefix(',')
+    .ok_or(OscParseError::MissingTypeTags)?;
+  let mut arguments = vec![];
+  for tag in type_tags.chars() {
+    arguments.push(match tag {
+      'i' => OscArgument::Int(i32::from_be_bytes(reader.take_array()?)),
+      'h' => OscArgument::Long(i64::from_be_bytes(reader.take_array()?)),
+      'f' => OscArgument::Float(f32::from_be_bytes(reader.take_array()?)),
+      'd' => OscArgument::Double(f64::from_be_bytes(reader.take_array()?)),
+      's' => OscArgument::String(reader.read_string()?),
+      'b' => OscArgument::Blob(reader.read_blob()?),
+      'T' => OscArgument::Bool(true),
+      'F' => OscArgument::Bool(false),
+      'N' => OscArgument::Nil,
+      'I' => OscArgument::Infinitum,
+      tag => return Err(OscParseError::UnsupportedType(tag)),
+    });
+  }
+  messages.push(OscMessage { address, arguments });
+  Ok(())
+}
+
+/// Values waiting to be sent to a device.
+#[derive(Default)]
+struct PendingCommands {
+  vibrate: HashMap<u32, f64>,
+  oscillate: HashMap<u32, f64>,
+  rotate: HashMap<u32, (f64, bool)>,
+  linear: HashMap<u32, f64>,
+  last_sent: Option<Instant>,
+}
+
+impl PendingCommands {
+  fn set(&mut self, actuator: OscActuator, value: f64) {
+    match actuator {
+      OscActuator::Vibrate(index) => {
+        self.vibrate.insert(index, value.clamp(0.0, 1.0));
+      }
+      OscActuator::Oscillate(index) => {
+        self.oscillate.insert(index, value.clamp(0.0, 1.0));
+      }
+      OscActuator::Rotate(index) => {
+        self
+          .rotate
+          .insert(index, (value.abs().min(1.0), value >= 0.0));
+      }
+      OscActuator::Linear(index) => {
+        self.linear.insert(index, value.clamp(0.0, 1.0));
+      }
+    }
+  }
+
+  fn is_empty(&self) -> bool {
+    self.vibrate.is_empty()
+      && self.oscillate.is_empty()
+      && self.rotate.is_empty()
+      && self.linear.is_empty()
+  }
+
+  /// Sends everything pending to the device, without waiting for the
+  /// commands to finish.
+  fn send(&mut self, device: &ButtplugClientDevice, interval_ms: u32) {
+    let mut futures = vec![];
+    if !self.vibrate.is_empty() {
+      futures.push(device.vibrate(VibrateCommand::SpeedMap(std::mem::take(&mut self.vibrate))));
+    }
+    if !self.oscillate.is_empty() {
+      futures.push(device.oscillate(OscillateCommand::SpeedMap(std::mem::take(
+        &mut self.oscillate,
+      ))));
+    }
+    if !self.rotate.is_empty() {
+      futures.push(device.rotate(RotateCommand::RotateMap(std::mem::take(&mut self.rotate))));
+    }
+    if !self.linear.is_empty() {
+      let linear = std::mem::take(&mut self.linear)
+        .into_iter()
+        .map(|(index, position)| (index, (interval_ms, position)))
+        .collect();
+      futures.push(device.linear(LinearCommand::LinearMap(linear)));
+    }
+    self.last_sent = Some(Instant::now());
+    async_manager::spawn(async move {
+      for fut in futures {
+        if let Err(err) = fut.await {
+          error!("OSC bridge cannot send command to device: {:?}", err);
+        }
+      }
+    });
+  }
+}
+
+fn find_device(client: &ButtplugClient, device: &OscDevice) -> Option<Arc<ButtplugClientDevice>> {
+  match device {
+    OscDevice::Index(index) => client
+      .devices()
+      .into_iter()
+      .find(|device| device.index() == *index),
+    OscDevice::Name(name) => client.device_by_name(name),
+    OscDevice::Address(address) => client.device_by_address(address),
+  }
+}
+
+/// Reads OSC messages from `socket` and drives the mapped devices through
+/// `client`, until receiving from the socket fails. Malformed packets and
+/// messages for devices that aren't connected are skipped.
+pub async fn run_osc_bridge(
+  socket: UdpSocket,
+  client: &ButtplugClient,
+  options: OscBridgeOptions,
+) -> io::Result<()> {
+  let interval = Duration::from_millis(options.min_command_interval_ms.into());
+  let mut pending: HashMap<OscDevice, PendingCommands> = HashMap::new();
+  let mut buffer = vec![0; MAX_PACKET_SIZE];
+  loop {
+    select! {
+      received = socket.recv_from(&mut buffer).fuse() => {
+        let (len, _) = received?;
+        let messages = match parse_osc_packet(&buffer[..len]) {
+          Ok(messages) => messages,
+          Err(err) => {
+            warn!("OSC bridge received invalid packet: {}", err);
+            continue;
+          }
+        };
+        for message in messages {
+          let value = match message.arguments.first().and_then(OscArgument::as_f64) {
+            Some(value) => value,
+            None => continue,
+          };
+          for mapping in options.mappings.iter().filter(|m| m.address == message.address) {
+            pending
+              .entry(mapping.device.clone())
+              .or_default()
+              .set(mapping.actuator, value);
+          }
+        }
+      }
+      _ = Delay::new(interval).fuse() => {}
+    }
+    for (osc_device, commands) in pending.iter_mut() {
+      if commands.is_empty()
+        || commands
+          .last_sent
+          .map_or(false, |last_sent| last_sent.elapsed() < interval)
+      {
+        continue;
+      }
+      match find_device(client, osc_device) {
+        Some(device) => commands.send(&device, options.min_command_interval_ms),
+        None => {
+          debug!(
+            "OSC bridge dropping values for missing device {:?}",
+            osc_device
+          );
+          *commands = PendingCommands {
+            last_sent: commands.last_sent,
+            ..Default::default()
+          };
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{parse_osc_packet, OscActuator, OscArgument, OscParseError, PendingCommands};
+
+  fn osc_string(string: &str) -> Vec<u8> {
+    let mut bytes = string.as_bytes().to_vec();
+    bytes.push(0);
+    while bytes.len() % 4 != 0 {
+      bytes.push(0);
+    }
+    bytes
+  }
+
+  fn float_message(address: &str, value: f32) -> Vec<u8> {
+    let mut packet = osc_string(address);
+    packet.extend(osc_string(",f"));
+    packet.extend(value.to_be_bytes());
+    packet
+  }
+
+  #[test]
+  fn test_parse_message() {
+    let messages = parse_osc_packet(&float_message("/avatar/parameters/Vibe", 0.5))
+      .expect("Test, assuming infallible.");
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].address, "/avatar/parameters/Vibe");
+    assert_eq!(messages[0].arguments, vec![OscArgument::Float(0.5)]);
+
+    let mut packet = osc_string("/test");
+    packet.extend(osc_string(",isTN"));
+    packet.extend(7i32.to_be_bytes());
+    packet.extend(osc_string("four"));
+    let messages = parse_osc_packet(&packet).expect("Test, assuming infallible.");
+    assert_eq!(
+      messages[0].arguments,
+      vec![
+        OscArgument::Int(7),
+        OscArgument::String("four".to_owned()),
+        OscArgument::Bool(true),
+        OscArgument::Nil,
+      ]
+    );
+  }
+
+  #[test]
+  fn test_parse_bundle() {
+    let mut packet = osc_string("#bundle");
+    packet.extend([0, 0, 0, 0, 0, 0, 0, 1]);
+    for (address, value) in [("/a", 0.25f32), ("/b", 0.75)] {
+      let message = float_message(address, value);
+      packet.extend((message.len() as i32).to_be_bytes());
+      packet.extend(message);
+    }
+    let messages = parse_osc_packet(&packet).expect("Test, assuming infallible.");
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[1].address, "/b");
+    assert_eq!(messages[1].arguments, vec![OscArgument::Float(0.75)]);
+  }
+
+  #[test]
+  fn test_parse_invalid_packets() {
+    let packet = float_message("/a", 0.5);
+    assert_eq!(
+      parse_osc_packet(&packet[..packet.len() - 1]),
+      Err(OscParseError::UnexpectedEnd)
+    );
+    let mut packet = osc_string("/a");
+    packet.extend(osc_string(",r"));
+    assert_eq!(
+      parse_osc_packet(&packet),
+      Err(OscParseError::UnsupportedType('r'))
+    );
+    let mut packet = osc_string("/a");
+    packet.extend(osc_string("f"));
+    assert_eq!(
+      parse_osc_packet(&packet),
+      Err(OscParseError::MissingTypeTags)
+    );
+    // Type tags can be left out if there are no arguments.
+    let messages = parse_osc_packet(&osc_string("/a")).expect("Test, assuming infallible.");
+    assert!(messages[0].arguments.is_empty());
+  }
+
+  #[test]
+  fn test_pending_values_keep_latest() {
+    let mut pending = PendingCommands::default();
+    assert!(pending.is_empty());
+    pending.set(OscActuator::Vibrate(0), 0.2);
+    pending.set(OscActuator::Vibrate(0), 1.5);
+    pending.set(OscActuator::Rotate(1), -0.5);
+    assert_eq!(pending.vibrate[&0], 1.0);
+    assert_eq!(pending.rotate[&1], (0.5, false));
+    assert!(!pending.is_empty());
+  }
+}
@@ -62,9 +62,8 @@ impl<'a> Default for ButtplugInProcessClientConnector {
 impl<'a> ButtplugInProcessClientConnector {
   /// Creates a new in-process connector, with a server instance.
   ///
-  /// Sets up a server, using the basic [ButtplugServer] construction arguments.
-  /// Takes the server's name and the ping time it should use, with a ping time
-  /// of 0 meaning infinite ping.
+  /// Takes a server built with [ButtplugServerBuilder]. If none is given, a
+  /// server is built using the builder defaults.
   pub fn new(server: Option<ButtplugServer>) -> Self {
     // Create a dummy channel, will just be overwritten on connect.
     let (server_outbound_sender, _) = channel(256);
@@ -108,12 +107,19 @@ impl ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServ
           info!("Starting In Process Client Connector Event Sender Loop");
           pin_mut!(server_recv);
           while let Some(event) = server_recv.next().await {
+            // The server should only send us events in the latest message
+            // spec, but an event that slips through shouldn't take the
+            // connection down with it.
+            let event = match event.try_into() {
+              Ok(event) => event,
+              Err(err) => {
+                error!("Cannot convert server event to current message spec, dropping it: {:?}", err);
+                continue;
+              }
+            };
             // If we get an error back, it means the client dropped our event
-            // handler, so just stop trying. Otherwise, since this is an
-            // in-process conversion, we can unwrap because we know our
-            // try_into() will always succeed (which may not be the case with
-            // remote connections that have different spec versions).
-            if send.send(event.try_into().expect("This is in-process so we're always on the latest message spec, this will always work.")).await.is_err() {
+            // handler, so just stop trying.
+            if send.send(event).await.is_err() {
               break;
             }
           }
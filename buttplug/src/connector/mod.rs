@@ -83,6 +83,8 @@ pub use remote_connector::{
 };
 use thiserror::Error;
 use tokio::sync::mpsc::Sender;
+#[cfg(all(target_arch = "wasm32", feature = "browser-websockets"))]
+pub use transport::ButtplugBrowserWebsocketClientTransport;
 #[cfg(feature = "websockets")]
 pub use transport::ButtplugWebsocketClientTransport;
 #[cfg(not(target_arch = "wasm32"))]
@@ -93,7 +95,12 @@ pub use transport::{
   ButtplugPipeServerTransportBuilder,
 };
 #[cfg(feature = "websockets")]
-pub use transport::{ButtplugWebsocketServerTransport, ButtplugWebsocketServerTransportBuilder};
+pub use transport::{
+  ButtplugWebsocketAuth,
+  ButtplugWebsocketAuthRejection,
+  ButtplugWebsocketServerTransport,
+  ButtplugWebsocketServerTransportBuilder,
+};
 
 pub type ButtplugConnectorResult = Result<(), ButtplugConnectorError>;
 pub type ButtplugConnectorStateShared =
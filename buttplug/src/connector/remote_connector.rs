@@ -25,7 +25,6 @@ use crate::{
   util::async_manager,
 };
 use futures::{future::BoxFuture, FutureExt};
-use std::marker::PhantomData;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 enum ButtplugRemoteConnectorMessage<T>
@@ -56,6 +55,7 @@ async fn remote_connector_event_loop<
   // Sends messages not matched in the sorter to the client.
   connector_incoming_sender: Sender<InboundMessageType>,
   transport: TransportType,
+  serializer: SerializerType,
   // Sends sorter processed messages to the transport.
   transport_outgoing_sender: Sender<ButtplugSerializedMessage>,
   // Takes data coming in from the transport.
@@ -67,8 +67,6 @@ async fn remote_connector_event_loop<
   OutboundMessageType: ButtplugMessage + 'static,
   InboundMessageType: ButtplugMessage + 'static,
 {
-  // Message sorter that receives messages that come in from the client.
-  let serializer = SerializerType::default();
   loop {
     // We use two Options instead of an enum because we may never get anything.
     //
@@ -201,7 +199,9 @@ pub struct ButtplugRemoteConnector<
   transport: Option<TransportType>,
   /// Sender for forwarding outgoing messages to the connector event loop.
   event_loop_sender: Option<Sender<ButtplugRemoteConnectorMessage<OutboundMessageType>>>,
-  dummy_serializer: PhantomData<SerializerType>,
+  /// Serializer for messages going over the transport. Taken along with the
+  /// transport on connect.
+  serializer: Option<SerializerType>,
 }
 
 impl<TransportType, SerializerType, OutboundMessageType, InboundMessageType>
@@ -214,10 +214,16 @@ where
   InboundMessageType: ButtplugMessage + 'static,
 {
   pub fn new(transport: TransportType) -> Self {
+    Self::new_with_serializer(transport, SerializerType::default())
+  }
+
+  /// Creates a connector that uses an already configured serializer, i.e. a
+  /// JSON serializer with schema validation turned off.
+  pub fn new_with_serializer(transport: TransportType, serializer: SerializerType) -> Self {
     Self {
       transport: Some(transport),
       event_loop_sender: None,
-      dummy_serializer: PhantomData::default(),
+      serializer: Some(serializer),
     }
   }
 }
@@ -246,6 +252,10 @@ where
         .transport
         .take()
         .expect("Already checked that this would be a valid take().");
+      let serializer = self
+        .serializer
+        .take()
+        .expect("Serializer is always taken along with the transport.");
       let (connector_outgoing_sender, connector_outgoing_receiver) = channel(256);
       self.event_loop_sender = Some(connector_outgoing_sender);
       Box::pin(async move {
@@ -268,6 +278,7 @@ where
                 connector_outgoing_receiver,
                 connector_incoming_sender,
                 transport,
+                serializer,
                 transport_outgoing_sender,
                 transport_incoming_receiver,
               )
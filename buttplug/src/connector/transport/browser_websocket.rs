@@ -0,0 +1,249 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Websocket client transport for browsers, using the browser's WebSocket API
+//! via web-sys. Only builds for wasm32 with the `browser-websockets` feature.
+
+use crate::{
+  connector::{
+    transport::{
+      ButtplugConnectorTransport,
+      ButtplugConnectorTransportSpecificError,
+      ButtplugTransportIncomingMessage,
+    },
+    ButtplugConnectorError,
+    ButtplugConnectorResultFuture,
+  },
+  core::messages::serializer::ButtplugSerializedMessage,
+  util::async_manager,
+};
+use futures::{
+  channel::{
+    mpsc::{unbounded, UnboundedReceiver},
+    oneshot,
+  },
+  future::BoxFuture,
+  FutureExt,
+  StreamExt,
+};
+use js_sys::{ArrayBuffer, Uint8Array};
+use std::sync::Arc;
+use tokio::sync::{
+  mpsc::{Receiver, Sender},
+  Notify,
+};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use web_sys::{BinaryType, CloseEvent, Event, MessageEvent, WebSocket};
+
+/// Things the browser tells us about the socket. WebSocket callbacks are
+/// synchronous, so they're funneled through a channel into the connection
+/// loop.
+enum BrowserSocketEvent {
+  Open,
+  Message(ButtplugSerializedMessage),
+  Error,
+  Close(String),
+}
+
+fn js_error_string(err: JsValue) -> String {
+  err.as_string().unwrap_or_else(|| format!("{:?}", err))
+}
+
+/// Websocket transport for clients running in a browser.
+pub struct ButtplugBrowserWebsocketClientTransport {
+  /// Address of the server we'll connect to.
+  address: String,
+  /// Internally held notifier, used for when disconnect is called.
+  disconnect_notifier: Arc<Notify>,
+}
+
+impl ButtplugBrowserWebsocketClientTransport {
+  /// Creates a new transport for the given address, which should be the full
+  /// URL of the server, i.e. "ws://127.0.0.1:12345". Browsers only allow
+  /// "ws://" connections from pages that weren't served over https.
+  pub fn new(address: &str) -> Self {
+    Self {
+      address: address.to_owned(),
+      disconnect_notifier: Arc::new(Notify::new()),
+    }
+  }
+}
+
+async fn run_connection_loop(
+  socket: &WebSocket,
+  mut socket_events: UnboundedReceiver<BrowserSocketEvent>,
+  mut outgoing_receiver: Receiver<ButtplugSerializedMessage>,
+  incoming_sender: Sender<ButtplugTransportIncomingMessage>,
+  disconnect_notifier: Arc<Notify>,
+) {
+  loop {
+    select! {
+      msg = outgoing_receiver.recv().fuse() => {
+        let result = match msg {
+          Some(ButtplugSerializedMessage::Text(text)) => socket.send_with_str(&text),
+          Some(ButtplugSerializedMessage::Binary(bin)) => socket.send_with_u8_array(&bin),
+          None => {
+            info!("Connector holding websocket dropped, returning");
+            break;
+          }
+        };
+        if let Err(err) = result {
+          error!(
+            "Cannot send websocket message, assuming disconnect: {}",
+            js_error_string(err)
+          );
+          break;
+        }
+      },
+      event = socket_events.next() => {
+        let incoming = match event {
+          Some(BrowserSocketEvent::Message(msg)) => {
+            ButtplugTransportIncomingMessage::Message(msg)
+          }
+          Some(BrowserSocketEvent::Close(reason)) => {
+            info!("Websocket has closed: {}", reason);
+            let _ = incoming_sender
+              .send(ButtplugTransportIncomingMessage::Close(reason))
+              .await;
+            break;
+          }
+          Some(BrowserSocketEvent::Error) => {
+            // The browser fires close right after error, so keep going until
+            // that shows up.
+            error!("Error in browser websocket.");
+            continue;
+          }
+          Some(BrowserSocketEvent::Open) => continue,
+          None => break,
+        };
+        if incoming_sender.send(incoming).await.is_err() {
+          error!("Websocket holder has closed, exiting websocket loop.");
+          break;
+        }
+      },
+      _ = disconnect_notifier.notified().fuse() => {
+        info!("Websocket requested to disconnect.");
+        break;
+      }
+    }
+  }
+  if let Err(err) = socket.close() {
+    error!("{}", js_error_string(err));
+  }
+}
+
+impl ButtplugConnectorTransport for ButtplugBrowserWebsocketClientTransport {
+  fn connect(
+    &self,
+    outgoing_receiver: Receiver<ButtplugSerializedMessage>,
+    incoming_sender: Sender<ButtplugTransportIncomingMessage>,
+  ) -> BoxFuture<'static, Result<(), ButtplugConnectorError>> {
+    let address = self.address.clone();
+    let disconnect_notifier = self.disconnect_notifier.clone();
+    let (connect_sender, connect_receiver) = oneshot::channel();
+
+    // WebSocket handles can't leave the thread they were made on, so
+    // everything touching the socket lives in a local task, and we just wait
+    // to hear how connecting went.
+    async_manager::spawn(async move {
+      let socket = match WebSocket::new(&address) {
+        Ok(socket) => socket,
+        Err(err) => {
+          let _ = connect_sender.send(Err(js_error_string(err)));
+          return;
+        }
+      };
+      socket.set_binary_type(BinaryType::Arraybuffer);
+
+      let (event_sender, mut socket_events) = unbounded();
+      let open_sender = event_sender.clone();
+      let on_open = Closure::wrap(Box::new(move |_: Event| {
+        let _ = open_sender.unbounded_send(BrowserSocketEvent::Open);
+      }) as Box<dyn FnMut(Event)>);
+      let message_sender = event_sender.clone();
+      let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+        let data = event.data();
+        let msg = if let Some(text) = data.as_string() {
+          ButtplugSerializedMessage::Text(text)
+        } else if let Ok(buffer) = data.dyn_into::<ArrayBuffer>() {
+          ButtplugSerializedMessage::Binary(Uint8Array::new(&buffer).to_vec())
+        } else {
+          error!("Unknown websocket message data type, ignoring.");
+          return;
+        };
+        let _ = message_sender.unbounded_send(BrowserSocketEvent::Message(msg));
+      }) as Box<dyn FnMut(MessageEvent)>);
+      let error_sender = event_sender.clone();
+      let on_error = Closure::wrap(Box::new(move |_: Event| {
+        let _ = error_sender.unbounded_send(BrowserSocketEvent::Error);
+      }) as Box<dyn FnMut(Event)>);
+      let on_close = Closure::wrap(Box::new(move |event: CloseEvent| {
+        let _ = event_sender.unbounded_send(BrowserSocketEvent::Close(format!(
+          "{} {}",
+          event.code(),
+          event.reason()
+        )));
+      }) as Box<dyn FnMut(CloseEvent)>);
+      socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+      socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+      socket.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+      socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+      // Browsers don't say why a connection failed, just that it closed.
+      let connected = loop {
+        match socket_events.next().await {
+          Some(BrowserSocketEvent::Open) => break Ok(()),
+          Some(BrowserSocketEvent::Error) => continue,
+          Some(BrowserSocketEvent::Close(reason)) => break Err(reason),
+          Some(BrowserSocketEvent::Message(_)) | None => {
+            break Err("Websocket closed before opening.".to_owned())
+          }
+        }
+      };
+      let is_connected = connected.is_ok();
+      if connect_sender.send(connected).is_ok() && is_connected {
+        run_connection_loop(
+          &socket,
+          socket_events,
+          outgoing_receiver,
+          incoming_sender,
+          disconnect_notifier,
+        )
+        .await;
+      }
+
+      // The closures have to outlive any callback the browser might still
+      // make, so unhook them before they're dropped.
+      socket.set_onopen(None);
+      socket.set_onmessage(None);
+      socket.set_onerror(None);
+      socket.set_onclose(None);
+    });
+
+    Box::pin(async move {
+      match connect_receiver.await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(reason)) => Err(ButtplugConnectorError::TransportSpecificError(
+          ButtplugConnectorTransportSpecificError::GenericNetworkError(reason),
+        )),
+        Err(_) => Err(ButtplugConnectorError::TransportSpecificError(
+          ButtplugConnectorTransportSpecificError::GenericNetworkError(
+            "Websocket task exited before connecting.".to_owned(),
+          ),
+        )),
+      }
+    })
+  }
+
+  fn disconnect(self) -> ButtplugConnectorResultFuture {
+    let disconnect_notifier = self.disconnect_notifier;
+    Box::pin(async move {
+      disconnect_notifier.notify_waiters();
+      Ok(())
+    })
+  }
+}
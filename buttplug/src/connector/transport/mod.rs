@@ -1,3 +1,5 @@
+#[cfg(all(target_arch = "wasm32", feature = "browser-websockets"))]
+mod browser_websocket;
 #[cfg(not(target_arch = "wasm32"))]
 mod pipe;
 #[cfg(feature = "websockets")]
@@ -7,6 +9,8 @@ use crate::connector::{
   ButtplugConnectorResultFuture,
   ButtplugSerializedMessage,
 };
+#[cfg(all(target_arch = "wasm32", feature = "browser-websockets"))]
+pub use browser_websocket::ButtplugBrowserWebsocketClientTransport;
 use futures::future::BoxFuture;
 #[cfg(not(target_arch = "wasm32"))]
 pub use pipe::{
@@ -17,6 +21,8 @@ use thiserror::Error;
 use tokio::sync::mpsc::{Receiver, Sender};
 #[cfg(feature = "websockets")]
 pub use websocket::{
+  ButtplugWebsocketAuth,
+  ButtplugWebsocketAuthRejection,
   ButtplugWebsocketClientTransport,
   ButtplugWebsocketServerTransport,
   ButtplugWebsocketServerTransportBuilder,
@@ -51,6 +57,9 @@ pub enum ButtplugConnectorTransportSpecificError {
   #[cfg(feature = "websockets")]
   #[error("Tungstenite specific error: {0}")]
   TungsteniteError(#[from] TungsteniteError),
+  #[cfg(feature = "websockets")]
+  #[error("Server rejected authentication: {0}")]
+  AuthenticationRejected(ButtplugWebsocketAuthRejection),
   #[error("Network error: {0}")]
   GenericNetworkError(String),
 }
@@ -1,2 +1,63 @@
 pub mod pipe_client;
 pub mod pipe_server;
+
+use serde::de::IgnoredAny;
+
+/// Pipes are byte streams, so a single read can hold several messages, or only
+/// part of one. Collects incoming bytes and splits them back up into whole
+/// JSON values.
+#[derive(Default)]
+struct PipeMessageBuffer {
+  buffer: Vec<u8>,
+}
+
+impl PipeMessageBuffer {
+  fn extend(&mut self, data: &[u8]) {
+    self.buffer.extend_from_slice(data);
+  }
+
+  /// Removes and returns the next complete message, if one has arrived.
+  fn next_message(&mut self) -> Option<Vec<u8>> {
+    let mut stream = serde_json::Deserializer::from_slice(&self.buffer).into_iter::<IgnoredAny>();
+    match stream.next() {
+      Some(Ok(_)) => {
+        let end = stream.byte_offset();
+        Some(self.buffer.drain(..end).collect())
+      }
+      // Rest of the message hasn't arrived yet.
+      Some(Err(err)) if err.is_eof() => None,
+      Some(Err(err)) => {
+        error!("Dropping invalid data from pipe: {:?}", err);
+        self.buffer.clear();
+        None
+      }
+      // Only whitespace left.
+      None => {
+        self.buffer.clear();
+        None
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::PipeMessageBuffer;
+
+  #[test]
+  fn test_pipe_message_buffer_splits_messages() {
+    let mut buffer = PipeMessageBuffer::default();
+    buffer.extend(br#"[{"Ok":{"Id":1}}][{"Ok":"#);
+    assert_eq!(
+      buffer.next_message(),
+      Some(br#"[{"Ok":{"Id":1}}]"#.to_vec())
+    );
+    assert_eq!(buffer.next_message(), None);
+    buffer.extend(br#"{"Id":2}}]"#);
+    assert_eq!(
+      buffer.next_message(),
+      Some(br#"[{"Ok":{"Id":2}}]"#.to_vec())
+    );
+    assert_eq!(buffer.next_message(), None);
+  }
+}
@@ -7,6 +7,7 @@
 
 //! Handling of named pipes and unix domain sockets, via tokio.
 
+use super::PipeMessageBuffer;
 use crate::{
   connector::{
     transport::{
@@ -67,7 +68,8 @@ async fn run_connection_loop(
 ) {
   info!("Starting pipe server connection event loop.");
 
-  loop {
+  let mut message_buffer = PipeMessageBuffer::default();
+  'connection: loop {
     tokio::select! {
       _ = disconnect_notifier.notified()=> {
         info!("Pipe server connector requested disconnect, exiting loop.");
@@ -108,20 +110,25 @@ async fn run_connection_loop(
               match client.try_read(&mut data) {
                 Ok(n) => {
                   if n == 0 {
-                    continue;
-                  }
-                  data.truncate(n);
-                  let json_str = if let Ok(json) = String::from_utf8(data) {
-                    json
-                  } else {
-                    error!("Could not parse incoming values as valid utf8.");
-                    continue;
-                  };
-                  if response_sender.send(ButtplugTransportIncomingMessage::Message(ButtplugSerializedMessage::Text(json_str))).await.is_err() {
-                    error!("Connector that owns transport no longer available, exiting.");
+                    info!("Pipe server closed the connection.");
                     break;
                   }
+                  message_buffer.extend(&data[..n]);
+                  while let Some(message) = message_buffer.next_message() {
+                    let json_str = if let Ok(json) = String::from_utf8(message) {
+                      json
+                    } else {
+                      error!("Could not parse incoming values as valid utf8.");
+                      continue;
+                    };
+                    if response_sender.send(ButtplugTransportIncomingMessage::Message(ButtplugSerializedMessage::Text(json_str))).await.is_err() {
+                      error!("Connector that owns transport no longer available, exiting.");
+                      break 'connection;
+                    }
+                  }
                 },
+                // Readiness can be a false positive, in which case just wait again.
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
                 Err(err) => {
                   error!("Error from pipe server, assuming disconnection: {:?}", err);
                   break;
@@ -1,3 +1,4 @@
+use super::PipeMessageBuffer;
 use crate::{
   connector::{
     transport::{
@@ -58,13 +59,14 @@ async fn run_connection_loop(
 ) {
   info!("Starting pipe server connection event loop.");
 
-  loop {
+  let mut message_buffer = PipeMessageBuffer::default();
+  'connection: loop {
     tokio::select! {
       _ = disconnect_notifier.notified()=> {
         info!("Pipe server connector requested disconnect.");
-        #[cfg(target = "windows")]
+        #[cfg(target_os = "windows")]
         let response = server.disconnect();
-        #[cfg(not(target = "windows"))]
+        #[cfg(not(target_os = "windows"))]
         let response = server.shutdown().await;
 
         if response.is_err(){
@@ -96,9 +98,9 @@ async fn run_connection_loop(
           }
         } else {
           info!("Pipe server connector owner dropped, disconnecting websocket connection.");
-          #[cfg(target = "windows")]
+          #[cfg(target_os = "windows")]
           let response = server.disconnect();
-          #[cfg(not(target = "windows"))]
+          #[cfg(not(target_os = "windows"))]
           let response = server.shutdown().await;
 
           if response.is_err(){
@@ -116,21 +118,25 @@ async fn run_connection_loop(
               match server.try_read(&mut data) {
                 Ok(n) => {
                   if n == 0 {
-                    continue;
-                  }
-                  data.truncate(n);
-                  let json_str = if let Ok(json) = String::from_utf8(data) {
-                    json
-                  } else {
-                    error!("Could not parse incoming values as valid utf8.");
-                    continue;
-                  };
-                  if response_sender.send(ButtplugTransportIncomingMessage::Message(ButtplugSerializedMessage::Text(json_str))).await.is_err() {
-                    error!("Connector that owns transport no longer available, exiting.");
+                    info!("Pipe client closed the connection.");
                     break;
                   }
-
+                  message_buffer.extend(&data[..n]);
+                  while let Some(message) = message_buffer.next_message() {
+                    let json_str = if let Ok(json) = String::from_utf8(message) {
+                      json
+                    } else {
+                      error!("Could not parse incoming values as valid utf8.");
+                      continue;
+                    };
+                    if response_sender.send(ButtplugTransportIncomingMessage::Message(ButtplugSerializedMessage::Text(json_str))).await.is_err() {
+                      error!("Connector that owns transport no longer available, exiting.");
+                      break 'connection;
+                    }
+                  }
                 },
+                // Readiness can be a false positive, in which case just wait again.
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
                 Err(err) => {
                   error!("Error from pipe server, assuming disconnection: {:?}", err);
                   break;
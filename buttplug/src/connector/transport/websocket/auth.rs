@@ -0,0 +1,209 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Token authentication for websocket transports.
+//!
+//! Tokens are checked during the websocket handshake, so a client without a
+//! valid token never gets as far as sending RequestServerInfo. Clients send
+//! their token as a bearer token in the Authorization header. Browsers can't
+//! set headers on websocket connections, so a `token` query parameter on the
+//! server URL is accepted too.
+//!
+//! Rejected handshakes get an HTTP error status, which the client transport
+//! turns back into a [ButtplugWebsocketAuthRejection]: 401 if no token was
+//! sent, 403 if the token wasn't one the server knows.
+
+use async_tungstenite::tungstenite::{
+  handshake::server::{ErrorResponse, Request},
+  http::{
+    header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    HeaderValue,
+    Response,
+    StatusCode,
+  },
+};
+use displaydoc::Display;
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// Why a websocket server refused a connection during authentication.
+#[derive(Debug, Error, Display, Clone, Copy, PartialEq, Eq)]
+pub enum ButtplugWebsocketAuthRejection {
+  /// Server requires a token, and none was sent.
+  MissingToken,
+  /// Token sent was not accepted by the server.
+  InvalidToken,
+}
+
+impl ButtplugWebsocketAuthRejection {
+  fn status(&self) -> StatusCode {
+    match self {
+      Self::MissingToken => StatusCode::UNAUTHORIZED,
+      Self::InvalidToken => StatusCode::FORBIDDEN,
+    }
+  }
+
+  /// Rejection the server meant by a handshake response status, if any.
+  pub(super) fn from_status(status: StatusCode) -> Option<Self> {
+    match status {
+      StatusCode::UNAUTHORIZED => Some(Self::MissingToken),
+      StatusCode::FORBIDDEN => Some(Self::InvalidToken),
+      _ => None,
+    }
+  }
+
+  pub(super) fn into_response(self) -> ErrorResponse {
+    let mut response = Response::new(Some(self.to_string()));
+    *response.status_mut() = self.status();
+    response
+      .headers_mut()
+      .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    response
+  }
+}
+
+/// Tokens a websocket server accepts. Clients are only let in if they send
+/// one of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ButtplugWebsocketAuth {
+  /// Token to the name of the client it belongs to, used in logs.
+  tokens: HashMap<String, String>,
+}
+
+impl ButtplugWebsocketAuth {
+  /// Accepts `secret` from any client.
+  pub fn shared_secret(&mut self, secret: &str) -> &mut Self {
+    self
+      .tokens
+      .insert(secret.to_owned(), "shared secret".to_owned());
+    self
+  }
+
+  /// Accepts `token` from the client called `client_name`. Giving each client
+  /// its own token means one can be revoked without changing the others.
+  pub fn client_token(&mut self, client_name: &str, token: &str) -> &mut Self {
+    self.tokens.insert(token.to_owned(), client_name.to_owned());
+    self
+  }
+
+  /// True if no tokens have been added, in which case the server doesn't
+  /// check for them.
+  pub fn is_empty(&self) -> bool {
+    self.tokens.is_empty()
+  }
+
+  /// Checks the token on a handshake request, returning the name of the
+  /// client it belongs to.
+  pub(super) fn check_request(
+    &self,
+    request: &Request,
+  ) -> Result<&str, ButtplugWebsocketAuthRejection> {
+    let token = request_token(request).ok_or(ButtplugWebsocketAuthRejection::MissingToken)?;
+    self
+      .tokens
+      .iter()
+      // Check every token, so how long this takes doesn't say how close a
+      // guess was.
+      .fold(None, |found, (known, client_name)| {
+        if tokens_match(known, &token) {
+          Some(client_name.as_str())
+        } else {
+          found
+        }
+      })
+      .ok_or(ButtplugWebsocketAuthRejection::InvalidToken)
+  }
+}
+
+/// Header value a client sends its token in.
+pub(super) fn authorization_header(token: &str) -> Option<HeaderValue> {
+  HeaderValue::from_str(&format!("Bearer {}", token)).ok()
+}
+
+fn request_token(request: &Request) -> Option<String> {
+  if let Some(header) = request.headers().get(AUTHORIZATION) {
+    return header
+      .to_str()
+      .ok()
+      .and_then(|value| value.strip_prefix("Bearer "))
+      .map(|token| token.trim().to_owned());
+  }
+  request.uri().query().and_then(|query| {
+    query
+      .split('&')
+      .find_map(|pair| pair.strip_prefix("token="))
+      .map(|token| token.to_owned())
+  })
+}
+
+/// Compares tokens in time that only depends on their lengths.
+fn tokens_match(known: &str, sent: &str) -> bool {
+  known.len() == sent.len()
+    && known
+      .bytes()
+      .zip(sent.bytes())
+      .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+      == 0
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn request(uri: &str, authorization: Option<&str>) -> Request {
+    let mut builder = Request::builder().uri(uri);
+    if let Some(authorization) = authorization {
+      builder = builder.header(AUTHORIZATION, authorization);
+    }
+    builder.body(()).expect("Test, assuming infallible.")
+  }
+
+  #[test]
+  fn test_check_request() {
+    let mut auth = ButtplugWebsocketAuth::default();
+    auth
+      .shared_secret("secret")
+      .client_token("Phone", "phone-token");
+    assert_eq!(
+      auth.check_request(&request("/", Some("Bearer secret"))),
+      Ok("shared secret")
+    );
+    assert_eq!(
+      auth.check_request(&request("/", Some("Bearer phone-token"))),
+      Ok("Phone")
+    );
+    assert_eq!(
+      auth.check_request(&request("/?token=phone-token", None)),
+      Ok("Phone")
+    );
+    assert_eq!(
+      auth.check_request(&request("/", None)),
+      Err(ButtplugWebsocketAuthRejection::MissingToken)
+    );
+    assert_eq!(
+      auth.check_request(&request("/", Some("Bearer secreT"))),
+      Err(ButtplugWebsocketAuthRejection::InvalidToken)
+    );
+    assert_eq!(
+      auth.check_request(&request("/", Some("Basic secret"))),
+      Err(ButtplugWebsocketAuthRejection::MissingToken)
+    );
+  }
+
+  #[test]
+  fn test_rejection_status_round_trip() {
+    for rejection in [
+      ButtplugWebsocketAuthRejection::MissingToken,
+      ButtplugWebsocketAuthRejection::InvalidToken,
+    ] {
+      assert_eq!(
+        ButtplugWebsocketAuthRejection::from_status(rejection.into_response().status()),
+        Some(rejection)
+      );
+    }
+  }
+}
@@ -0,0 +1,83 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Message compression for websocket transports.
+//!
+//! async-tungstenite doesn't support the permessage-deflate extension, so
+//! compression is negotiated as a websocket subprotocol instead. A client that
+//! wants compression asks for [DEFLATE_SUBPROTOCOL] in its handshake, and a
+//! server that allows it echoes the subprotocol back. Once negotiated, either
+//! side may send a message as a binary frame holding the raw deflate stream of
+//! the message's JSON. Text frames are still plain JSON, so short messages,
+//! which don't shrink much, are sent as is.
+
+use async_tungstenite::tungstenite::protocol::Message;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use std::io::{self, Read, Write};
+
+/// Subprotocol name used to negotiate compression.
+pub const DEFLATE_SUBPROTOCOL: &str = "buttplug-deflate";
+
+/// Messages shorter than this many bytes are sent uncompressed.
+const COMPRESSION_THRESHOLD: usize = 256;
+
+/// True if a Sec-WebSocket-Protocol header value lists the deflate
+/// subprotocol.
+pub(super) fn requests_deflate(protocols: &str) -> bool {
+  protocols
+    .split(',')
+    .any(|protocol| protocol.trim() == DEFLATE_SUBPROTOCOL)
+}
+
+/// Turns an outgoing message into a websocket frame, compressing it if it's
+/// long enough to be worth it.
+pub(super) fn compress_message(text: String) -> Message {
+  if text.len() < COMPRESSION_THRESHOLD {
+    return Message::Text(text);
+  }
+  let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+  encoder
+    .write_all(text.as_bytes())
+    .expect("Writing to a Vec can't fail.");
+  Message::Binary(encoder.finish().expect("Writing to a Vec can't fail."))
+}
+
+/// Inflates a binary frame received on a compressed connection.
+pub(super) fn decompress_message(data: &[u8]) -> io::Result<String> {
+  let mut text = String::new();
+  DeflateDecoder::new(data).read_to_string(&mut text)?;
+  Ok(text)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_compression_round_trip() {
+    let short = "[{\"Ok\":{\"Id\":1}}]".to_owned();
+    assert_eq!(compress_message(short.clone()), Message::Text(short));
+    let long = format!("[{}]", vec!["{\"Ok\":{\"Id\":1}}"; 100].join(","));
+    match compress_message(long.clone()) {
+      Message::Binary(data) => {
+        assert!(data.len() < long.len());
+        assert_eq!(
+          decompress_message(&data).expect("Test, assuming infallible."),
+          long
+        );
+      }
+      msg => panic!("Expected compressed message, got {:?}", msg),
+    }
+  }
+
+  #[test]
+  fn test_requests_deflate() {
+    assert!(requests_deflate("buttplug-deflate"));
+    assert!(requests_deflate("chat, buttplug-deflate"));
+    assert!(!requests_deflate("chat"));
+  }
+}
@@ -1,7 +1,11 @@
+mod auth;
+mod compression;
 pub mod websocket_client;
 pub mod websocket_server;
 
 pub use async_tungstenite::tungstenite::Error as TungsteniteError;
+pub use auth::{ButtplugWebsocketAuth, ButtplugWebsocketAuthRejection};
+pub use compression::DEFLATE_SUBPROTOCOL;
 pub use websocket_client::ButtplugWebsocketClientTransport;
 
 pub use websocket_server::{
@@ -7,6 +7,15 @@
 
 //! Handling of websockets using async-tungstenite
 
+use super::{
+  auth::{authorization_header, ButtplugWebsocketAuthRejection},
+  compression::{
+    compress_message,
+    decompress_message,
+    requests_deflate,
+    DEFLATE_SUBPROTOCOL,
+  },
+};
 use crate::{
   connector::{
     transport::{
@@ -20,15 +29,47 @@ use crate::{
   core::messages::serializer::ButtplugSerializedMessage,
   util::async_manager,
 };
-use async_tungstenite::{tokio::connect_async_with_tls_connector, tungstenite::protocol::Message};
-use futures::{future::BoxFuture, FutureExt, SinkExt, StreamExt};
-use std::sync::Arc;
+use async_tungstenite::{
+  tokio::connect_async_with_tls_connector,
+  tungstenite::{
+    client::IntoClientRequest,
+    http::{
+      header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL},
+      HeaderValue,
+    },
+    protocol::Message,
+    Error as TungsteniteError,
+  },
+};
+use futures::{
+  future::{self, BoxFuture},
+  Future,
+  FutureExt,
+  SinkExt,
+  StreamExt,
+};
+use futures_timer::Delay;
+use std::{
+  sync::Arc,
+  time::{Duration, Instant},
+};
 use tokio::sync::{
   mpsc::{Receiver, Sender},
   Notify,
 };
 use tracing::Instrument;
 
+/// Default interval between websocket ping frames sent to the server.
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Resolves when the next heartbeat is due, or never if heartbeats are disabled.
+fn heartbeat_wait(deadline: Option<Instant>) -> impl Future<Output = ()> {
+  match deadline {
+    Some(deadline) => Delay::new(deadline.saturating_duration_since(Instant::now())).left_future(),
+    None => future::pending::<()>().right_future(),
+  }
+}
+
 /// Websocket connector for ButtplugClients, using [async_tungstenite]
 pub struct ButtplugWebsocketClientTransport {
   /// Address of the server we'll connect to.
@@ -40,6 +81,14 @@ pub struct ButtplugWebsocketClientTransport {
   bypass_cert_verify: bool,
   /// Internally held sender, used for when disconnect is called.
   disconnect_notifier: Arc<Notify>,
+  /// Interval between websocket ping frames. If the server hasn't sent us
+  /// anything by the time the next ping is due, the connection is considered
+  /// dead. None disables liveness checks.
+  heartbeat_interval: Option<Duration>,
+  /// If true, ask the server to compress messages. See [compression][Self::compression].
+  compression: bool,
+  /// Token sent to servers that require authentication.
+  auth_token: Option<String>,
 }
 
 impl ButtplugWebsocketClientTransport {
@@ -49,9 +98,47 @@ impl ButtplugWebsocketClientTransport {
       address: address.to_owned(),
       bypass_cert_verify,
       disconnect_notifier: Arc::new(Notify::new()),
+      heartbeat_interval: Some(DEFAULT_HEARTBEAT_INTERVAL),
+      compression: false,
+      auth_token: None,
     }
   }
 
+  /// Sets the interval for websocket level ping frames.
+  ///
+  /// These are separate from the Buttplug Ping message, and are answered by the
+  /// websocket layer of the server, so they'll catch a hung server process or a
+  /// dropped network connection even if the server has no ping timeout set. If
+  /// no frame is received from the server within one interval after a ping is
+  /// sent, the transport closes and the client will see a server disconnect.
+  /// Passing None disables the heartbeat. Defaults to 5 seconds.
+  pub fn heartbeat_interval(mut self, interval: Option<Duration>) -> Self {
+    self.heartbeat_interval = interval;
+    self
+  }
+
+  /// Asks the server to deflate compress messages, to save bandwidth on slow
+  /// or metered links.
+  ///
+  /// Compression is negotiated during the websocket handshake, using the
+  /// [DEFLATE_SUBPROTOCOL] subprotocol. If the server doesn't support it or
+  /// doesn't allow it, the connection goes ahead uncompressed. Defaults to
+  /// false.
+  pub fn compression(mut self, enabled: bool) -> Self {
+    self.compression = enabled;
+    self
+  }
+
+  /// Sets the token to send to servers that require authentication.
+  ///
+  /// The token is sent as a bearer token during the websocket handshake. If
+  /// the server refuses it, connecting fails with
+  /// [ButtplugConnectorTransportSpecificError::AuthenticationRejected].
+  pub fn auth_token(mut self, token: &str) -> Self {
+    self.auth_token = Some(token.to_owned());
+    self
+  }
+
   /// Creates a new connector for "ws://" addresses
   ///
   /// Returns a websocket connector for connecting over insecure websockets to a
@@ -107,19 +194,52 @@ impl ButtplugConnectorTransport for ButtplugWebsocketClientTransport {
       None
     };
     let address = self.address.clone();
+    let heartbeat_interval = self.heartbeat_interval;
+    let compression = self.compression;
+    let auth_token = self.auth_token.clone();
 
     Box::pin(async move {
-      match connect_async_with_tls_connector(&address, tls_connector).await {
-        Ok((stream, _)) => {
+      let to_connector_error = |err| {
+        ButtplugConnectorError::TransportSpecificError(
+          ButtplugConnectorTransportSpecificError::TungsteniteError(err),
+        )
+      };
+      let mut request = address.into_client_request().map_err(to_connector_error)?;
+      if compression {
+        request.headers_mut().insert(
+          SEC_WEBSOCKET_PROTOCOL,
+          HeaderValue::from_static(DEFLATE_SUBPROTOCOL),
+        );
+      }
+      if let Some(token) = auth_token {
+        let header = authorization_header(&token).ok_or_else(|| {
+          ButtplugConnectorError::ConnectorGenericError(
+            "Authentication token contains characters that can't be sent in a header.".to_owned(),
+          )
+        })?;
+        request.headers_mut().insert(AUTHORIZATION, header);
+      }
+      match connect_async_with_tls_connector(request, tls_connector).await {
+        Ok((stream, response)) => {
+          let compressed = match response.headers().get(SEC_WEBSOCKET_PROTOCOL) {
+            Some(protocols) => compression && requests_deflate(protocols.to_str().unwrap_or("")),
+            None => false,
+          };
+          if compression && !compressed {
+            info!("Websocket server did not accept compression, sending messages uncompressed.");
+          }
           let (mut writer, mut reader) = stream.split();
 
           async_manager::spawn(
             async move {
+              let mut heartbeat_deadline = heartbeat_interval.map(|interval| Instant::now() + interval);
+              let mut awaiting_pong = false;
               loop {
                 select! {
                   msg = outgoing_receiver.recv().fuse() => {
                     if let Some(msg) = msg {
                       let out_msg = match msg {
+                        ButtplugSerializedMessage::Text(text) if compressed => compress_message(text),
                         ButtplugSerializedMessage::Text(text) => Message::Text(text),
                         ButtplugSerializedMessage::Binary(bin) => Message::Binary(bin),
                       };
@@ -138,6 +258,9 @@ impl ButtplugConnectorTransport for ButtplugWebsocketClientTransport {
                       writer.close().await.unwrap_or_else(|err| error!("{}", err));
                       return;
                     }
+                    // Any frame from the server proves it's still alive, not
+                    // just a pong.
+                    awaiting_pong = false;
                     match response.expect("Already checked for none.") {
                       Ok(msg) => match msg {
                         Message::Text(t) => {
@@ -152,6 +275,26 @@ impl ButtplugConnectorTransport for ButtplugWebsocketClientTransport {
                             return;
                           }
                         }
+                        Message::Binary(v) if compressed => {
+                          let text = match decompress_message(&v) {
+                            Ok(text) => text,
+                            Err(err) => {
+                              error!("Cannot decompress websocket message, assuming disconnect: {}", err);
+                              writer.close().await.unwrap_or_else(|err| error!("{}", err));
+                              return;
+                            }
+                          };
+                          if incoming_sender
+                            .send(ButtplugTransportIncomingMessage::Message(
+                              ButtplugSerializedMessage::Text(text),
+                            ))
+                            .await
+                            .is_err()
+                          {
+                            error!("Websocket holder has closed, exiting websocket loop.");
+                            return;
+                          }
+                        }
                         Message::Binary(v) => {
                           if incoming_sender
                             .send(ButtplugTransportIncomingMessage::Message(
@@ -182,6 +325,19 @@ impl ButtplugConnectorTransport for ButtplugWebsocketClientTransport {
                       }
                     }
                   }
+                  _ = heartbeat_wait(heartbeat_deadline).fuse() => {
+                    if awaiting_pong {
+                      error!("Websocket server did not respond to ping, assuming disconnect.");
+                      writer.close().await.unwrap_or_else(|err| error!("{}", err));
+                      return;
+                    }
+                    if let Err(err) = writer.send(Message::Ping(vec![])).await {
+                      error!("Cannot send websocket ping, assuming disconnect: {}", err);
+                      return;
+                    }
+                    awaiting_pong = true;
+                    heartbeat_deadline = heartbeat_interval.map(|interval| Instant::now() + interval);
+                  }
                   _ = disconnect_notifier.notified().fuse() => {
                     // If we can't close, just print the error to the logs but
                     // still break out of the loop.
@@ -198,9 +354,15 @@ impl ButtplugConnectorTransport for ButtplugWebsocketClientTransport {
           );
           Ok(())
         }
-        Err(websocket_error) => Err(ButtplugConnectorError::TransportSpecificError(
-          ButtplugConnectorTransportSpecificError::TungsteniteError(websocket_error),
-        )),
+        Err(TungsteniteError::Http(response)) => {
+          match ButtplugWebsocketAuthRejection::from_status(response.status()) {
+            Some(rejection) => Err(ButtplugConnectorError::TransportSpecificError(
+              ButtplugConnectorTransportSpecificError::AuthenticationRejected(rejection),
+            )),
+            None => Err(to_connector_error(TungsteniteError::Http(response))),
+          }
+        }
+        Err(websocket_error) => Err(to_connector_error(websocket_error)),
       }
     })
   }
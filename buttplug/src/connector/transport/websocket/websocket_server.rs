@@ -1,3 +1,12 @@
+use super::{
+  auth::ButtplugWebsocketAuth,
+  compression::{
+    compress_message,
+    decompress_message,
+    requests_deflate,
+    DEFLATE_SUBPROTOCOL,
+  },
+};
 use crate::{
   connector::{
     transport::{
@@ -11,9 +20,20 @@ use crate::{
   core::messages::serializer::ButtplugSerializedMessage,
   util::async_manager,
 };
+use async_tungstenite::tungstenite::{
+  handshake::server::{ErrorResponse, Request, Response},
+  http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderValue},
+  Error as TungsteniteError,
+};
 use futures::{future::BoxFuture, AsyncRead, AsyncWrite, FutureExt, SinkExt, StreamExt};
 use futures_timer::Delay;
-use std::{sync::Arc, time::Duration};
+use std::{
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
 use tokio::net::TcpListener;
 use tokio::sync::{
   mpsc::{Receiver, Sender},
@@ -26,6 +46,11 @@ pub struct ButtplugWebsocketServerTransportBuilder {
   listen_on_all_interfaces: bool,
   /// Insecure port for listening for websocket connections.
   port: u16,
+  /// If true, compress messages for clients that ask for it.
+  compression: bool,
+  /// Tokens clients must send to connect. Empty if no authentication is
+  /// required.
+  auth: ButtplugWebsocketAuth,
 }
 
 impl Default for ButtplugWebsocketServerTransportBuilder {
@@ -33,6 +58,8 @@ impl Default for ButtplugWebsocketServerTransportBuilder {
     Self {
       listen_on_all_interfaces: false,
       port: 12345,
+      compression: true,
+      auth: ButtplugWebsocketAuth::default(),
     }
   }
 }
@@ -48,10 +75,30 @@ impl ButtplugWebsocketServerTransportBuilder {
     self
   }
 
+  /// Sets whether clients may ask for deflate compressed messages, using the
+  /// [DEFLATE_SUBPROTOCOL] subprotocol. Clients that don't ask are always sent
+  /// uncompressed messages. Defaults to true.
+  pub fn compression(&mut self, compression: bool) -> &mut Self {
+    self.compression = compression;
+    self
+  }
+
+  /// Requires clients to send one of the tokens in `auth` when connecting.
+  /// Clients without a valid token are refused during the websocket
+  /// handshake, and the server goes on waiting for one that has one. Servers
+  /// listening on all interfaces should always set this, otherwise anyone on
+  /// the network can connect. Defaults to no authentication.
+  pub fn auth(&mut self, auth: ButtplugWebsocketAuth) -> &mut Self {
+    self.auth = auth;
+    self
+  }
+
   pub fn finish(&self) -> ButtplugWebsocketServerTransport {
     ButtplugWebsocketServerTransport {
       port: self.port,
       listen_on_all_interfaces: self.listen_on_all_interfaces,
+      compression: self.compression,
+      auth: Arc::new(self.auth.clone()),
       disconnect_notifier: Arc::new(Notify::new()),
     }
   }
@@ -62,6 +109,7 @@ async fn run_connection_loop<S>(
   mut request_receiver: Receiver<ButtplugSerializedMessage>,
   response_sender: Sender<ButtplugTransportIncomingMessage>,
   disconnect_notifier: Arc<Notify>,
+  compressed: bool,
 ) where
   S: AsyncRead + AsyncWrite + Unpin,
 {
@@ -101,8 +149,13 @@ async fn run_connection_loop<S>(
         if let Some(serialized_msg) = serialized_msg {
           match serialized_msg {
             ButtplugSerializedMessage::Text(text_msg) => {
+              let ws_msg = if compressed {
+                compress_message(text_msg)
+              } else {
+                async_tungstenite::tungstenite::Message::Text(text_msg)
+              };
               if websocket_server_sender
-                .send(async_tungstenite::tungstenite::Message::Text(text_msg))
+                .send(ws_msg)
                 .await
                 .is_err() {
                 error!("Cannot send text value to server, considering connection closed.");
@@ -153,6 +206,21 @@ async fn run_connection_loop<S>(
                   pong_count += 1;
                   continue;
                 }
+                async_tungstenite::tungstenite::Message::Binary(binary_msg) if compressed => {
+                  let text_msg = match decompress_message(&binary_msg) {
+                    Ok(text_msg) => text_msg,
+                    Err(err) => {
+                      error!("Cannot decompress message from client, considering connection closed: {}", err);
+                      let _ = response_sender.send(ButtplugTransportIncomingMessage::Close("Websocket server closed".to_owned())).await;
+                      break;
+                    }
+                  };
+                  trace!("Got compressed text: {}", text_msg);
+                  if response_sender.send(ButtplugTransportIncomingMessage::Message(ButtplugSerializedMessage::Text(text_msg))).await.is_err() {
+                    error!("Connector that owns transport no longer available, exiting.");
+                    break;
+                  }
+                }
                 async_tungstenite::tungstenite::Message::Binary(_) => {
                   error!("Don't know how to handle binary message types!");
                 }
@@ -178,6 +246,8 @@ async fn run_connection_loop<S>(
 pub struct ButtplugWebsocketServerTransport {
   port: u16,
   listen_on_all_interfaces: bool,
+  compression: bool,
+  auth: Arc<ButtplugWebsocketAuth>,
   disconnect_notifier: Arc<Notify>,
 }
 
@@ -199,6 +269,8 @@ impl ButtplugConnectorTransport for ButtplugWebsocketServerTransport {
     debug!("Websocket Insecure: Trying to listen on {}", addr);
     let response_sender_clone = incoming_sender;
     let disconnect_notifier_clone = disconnect_notifier;
+    let compression = self.compression;
+    let auth = self.auth.clone();
     let fut = async move {
       // Create the event loop and TCP listener we'll accept connections on.
       let try_socket = TcpListener::bind(&addr).await;
@@ -209,29 +281,70 @@ impl ButtplugConnectorTransport for ButtplugWebsocketServerTransport {
         )
       })?;
       debug!("Websocket Insecure: Listening on: {}", addr);
-      if let Ok((stream, _)) = listener.accept().await {
-        info!("Websocket Insecure: Got connection");
-        let ws_fut = async_tungstenite::tokio::accept_async(stream);
-        let ws_stream = ws_fut.await.map_err(|err| {
-          error!("Websocket server accept error: {:?}", err);
-          ButtplugConnectorError::TransportSpecificError(
-            ButtplugConnectorTransportSpecificError::TungsteniteError(err),
+      // Clients that fail authentication don't count, so keep accepting until
+      // one gets through the handshake.
+      loop {
+        let (stream, _) = listener.accept().await.map_err(|_| {
+          ButtplugConnectorError::ConnectorGenericError(
+            "Could not run accept for insecure port".to_owned(),
           )
         })?;
+        info!("Websocket Insecure: Got connection");
+        // Compression is on if the client asks for it in the handshake and we
+        // allow it.
+        let compressed = Arc::new(AtomicBool::new(false));
+        let compressed_clone = compressed.clone();
+        let auth_clone = auth.clone();
+        let check_handshake =
+          move |request: &Request, mut response: Response| -> Result<Response, ErrorResponse> {
+            if !auth_clone.is_empty() {
+              match auth_clone.check_request(request) {
+                Ok(client_name) => info!("Websocket client authenticated as {}.", client_name),
+                Err(rejection) => return Err(rejection.into_response()),
+              }
+            }
+            let requested = match request.headers().get(SEC_WEBSOCKET_PROTOCOL) {
+              Some(protocols) => requests_deflate(protocols.to_str().unwrap_or("")),
+              None => false,
+            };
+            if compression && requested {
+              response.headers_mut().insert(
+                SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_static(DEFLATE_SUBPROTOCOL),
+              );
+              compressed_clone.store(true, Ordering::SeqCst);
+            }
+            Ok(response)
+          };
+        let ws_fut = async_tungstenite::tokio::accept_hdr_async(stream, check_handshake);
+        let ws_stream = match ws_fut.await {
+          Ok(ws_stream) => ws_stream,
+          Err(TungsteniteError::Http(response)) if !auth.is_empty() => {
+            warn!(
+              "Websocket client refused: {}",
+              response.body().as_deref().unwrap_or("unknown reason")
+            );
+            continue;
+          }
+          Err(err) => {
+            error!("Websocket server accept error: {:?}", err);
+            return Err(ButtplugConnectorError::TransportSpecificError(
+              ButtplugConnectorTransportSpecificError::TungsteniteError(err),
+            ));
+          }
+        };
+        let compressed = compressed.load(Ordering::SeqCst);
         async_manager::spawn(async move {
           run_connection_loop(
             ws_stream,
             outgoing_receiver,
             response_sender_clone,
             disconnect_notifier_clone,
+            compressed,
           )
           .await;
         });
-        Ok(())
-      } else {
-        Err(ButtplugConnectorError::ConnectorGenericError(
-          "Could not run accept for insecure port".to_owned(),
-        ))
+        return Ok(());
       }
     };
 
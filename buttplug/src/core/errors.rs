@@ -73,6 +73,14 @@ pub enum ButtplugMessageError {
   UnhandledMessage(String),
   /// Message validation error(s): {0}
   ValidationError(String),
+  /// Message id 0 is reserved for server events, and cannot be used by client messages.
+  ReservedMessageId,
+  /// Message id {0} is already in use by a message that has not been replied to yet.
+  MessageIdInUse(u32),
+  /// Client {0} is not permitted to send {1}
+  MessageNotPermitted(String, String),
+  /// Server is overloaded, {0} messages from this client are already waiting on replies.
+  ServerOverloaded(u32),
   /// Message serialization error
   #[error(transparent)]
   MessageSerializationError(#[from] ButtplugSerializerError),
@@ -129,10 +137,22 @@ pub enum ButtplugDeviceError {
   DeviceFeatureIndexError(u32, u32),
   /// Device connection error: {0}
   DeviceConnectionError(String),
+  /// Device connection failed: {0}
+  DeviceConnectionFailed(#[from] ButtplugDeviceConnectionError),
+  /// Device did not finish connecting and initializing within {0}ms
+  DeviceInitializationTimeout(u32),
   /// Device communication error: {0}
   DeviceCommunicationError(String),
   /// Device does not have endpoint {0}
   InvalidEndpoint(Endpoint),
+  /// Device did not confirm write to endpoint {0} after {1} attempts
+  DeviceWriteNotConfirmed(Endpoint, u32),
+  /// Write of {1} bytes to endpoint {0} is longer than the {2} bytes the device can take at once
+  DeviceWriteTooLong(Endpoint, u32, u32),
+  /// Device {0} is claimed by client {1}
+  DeviceClaimedByOtherClient(u32, String),
+  /// Device {0} is not claimed
+  DeviceNotClaimed(u32),
   /// Device does not handle command type: {0}
   UnhandledCommand(String),
   #[cfg(feature = "server")]
@@ -148,8 +168,20 @@ pub enum ButtplugDeviceError {
   DeviceScanningAlreadyStarted,
   /// Device scanning already stopped.
   DeviceScanningAlreadyStopped,
+  /// Device scanning failed on all device communication managers: {0}
+  DeviceScanningFailed(String),
+  /// Device command cancelled before it was sent to the device.
+  DeviceCommandCancelled,
+  /// Device command queue is full, {0} commands already waiting.
+  DeviceCommandQueueFull(u32),
+  /// Devices are emergency stopped, device commands are refused until they're resumed.
+  DevicesEmergencyStopped,
+  /// Emergency stop could not confirm every device stopped: {0}
+  EmergencyStopFailed(String),
   /// Device permission error: {0}
   DevicePermissionError(String),
+  /// No Bluetooth adapter available. Make sure Bluetooth is plugged in and turned on.
+  NoBluetoothAdapter,
   /// {0}
   ProtocolAttributesNotFound(String),
   /// Protocol {0} not implemented in library
@@ -164,6 +196,36 @@ pub enum ButtplugDeviceError {
   DeviceConfigurationFileError(String),
 }
 
+/// Reasons a comm manager can fail to connect to a device, so callers can
+/// tell failures worth retrying from ones that aren't.
+#[derive(Debug, Error, Display, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub enum ButtplugDeviceConnectionError {
+  /// Device not found: {0}
+  NotFound(String),
+  /// Device authentication or pairing failed: {0}
+  AuthFailed(String),
+  /// Device connection timed out: {0}
+  Timeout(String),
+  /// Device or adapter busy: {0}
+  Busy(String),
+  /// Device connection not supported: {0}
+  Unsupported(String),
+}
+
+impl ButtplugDeviceConnectionError {
+  /// True if trying the same connection again later may succeed.
+  pub fn is_retryable(&self) -> bool {
+    matches!(self, Self::Timeout(_) | Self::Busy(_))
+  }
+}
+
+impl From<ButtplugDeviceConnectionError> for ButtplugError {
+  fn from(err: ButtplugDeviceConnectionError) -> Self {
+    ButtplugDeviceError::from(err).into()
+  }
+}
+
 /// Unknown errors occur in exceptional circumstances where no other error type
 /// will suffice. These are rare and usually fatal (disconnecting) errors.
 impl<T> From<ButtplugUnknownError> for BoxFuture<'static, Result<T, ButtplugError>>
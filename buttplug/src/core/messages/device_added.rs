@@ -5,7 +5,12 @@
 // Licensed under the BSD 3-Clause license. See LICENSE file in the project root
 // for full license information.
 
-use super::device_message_info::{DeviceMessageInfoV0, DeviceMessageInfoV1};
+use super::device_message_info::{
+  ordered_map,
+  DeviceMessageInfoV0,
+  DeviceMessageInfoV1,
+  SharedDeviceMessageAttributesMap,
+};
 use super::*;
 
 #[cfg(feature = "serialize-json")]
@@ -20,21 +25,32 @@ pub struct DeviceAdded {
   device_index: u32,
   #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceName"))]
   device_name: String,
+  /// Only sent in spec v3.
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(
+      rename = "DeviceAddress",
+      default,
+      skip_serializing_if = "Option::is_none"
+    )
+  )]
+  device_address: Option<String>,
   #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceMessages"))]
-  device_messages: DeviceMessageAttributesMap,
+  device_messages: SharedDeviceMessageAttributesMap,
 }
 
 impl DeviceAdded {
   pub fn new(
     device_index: u32,
     device_name: &str,
-    device_messages: &DeviceMessageAttributesMap,
+    device_messages: impl Into<SharedDeviceMessageAttributesMap>,
   ) -> Self {
     Self {
       id: 0,
       device_index,
       device_name: device_name.to_string(),
-      device_messages: device_messages.clone(),
+      device_address: None,
+      device_messages: device_messages.into(),
     }
   }
 
@@ -46,9 +62,21 @@ impl DeviceAdded {
     &self.device_name
   }
 
+  pub fn device_address(&self) -> &Option<String> {
+    &self.device_address
+  }
+
+  pub fn set_device_address(&mut self, address: Option<String>) {
+    self.device_address = address;
+  }
+
   pub fn device_messages(&self) -> &DeviceMessageAttributesMap {
     &self.device_messages
   }
+
+  pub fn shared_device_messages(&self) -> &SharedDeviceMessageAttributesMap {
+    &self.device_messages
+  }
 }
 
 impl ButtplugMessageValidator for DeviceAdded {
@@ -66,7 +94,10 @@ pub struct DeviceAddedV1 {
   device_index: u32,
   #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceName"))]
   device_name: String,
-  #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceMessages"))]
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(rename = "DeviceMessages", serialize_with = "ordered_map")
+  )]
   device_messages: DeviceMessageAttributesMap,
 }
 
@@ -0,0 +1,63 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::*;
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+
+/// Server event sent when a device matched at least one protocol, but failed
+/// to connect or initialize (or took too long doing so), so it was never
+/// added. Lets GUIs tell users their device was found but couldn't be set up.
+#[derive(Debug, ButtplugMessage, PartialEq, Clone)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct DeviceInitializationFailed {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
+  id: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceName"))]
+  device_name: String,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceAddress"))]
+  device_address: String,
+  /// Every protocol that matched the device, in the order they were tried.
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Protocols"))]
+  protocols: Vec<String>,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Error"))]
+  error: String,
+}
+
+impl DeviceInitializationFailed {
+  pub fn new(device_name: &str, device_address: &str, protocols: &[String], error: &str) -> Self {
+    Self {
+      id: 0,
+      device_name: device_name.to_owned(),
+      device_address: device_address.to_owned(),
+      protocols: protocols.to_vec(),
+      error: error.to_owned(),
+    }
+  }
+
+  pub fn device_name(&self) -> &str {
+    &self.device_name
+  }
+
+  pub fn device_address(&self) -> &str {
+    &self.device_address
+  }
+
+  pub fn protocols(&self) -> &Vec<String> {
+    &self.protocols
+  }
+
+  pub fn error(&self) -> &str {
+    &self.error
+  }
+}
+
+impl ButtplugMessageValidator for DeviceInitializationFailed {
+  fn is_valid(&self) -> Result<(), ButtplugMessageError> {
+    self.is_system_id(self.id)
+  }
+}
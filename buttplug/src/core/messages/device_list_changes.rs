@@ -0,0 +1,74 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::*;
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+
+/// Reply to [RequestDeviceListChanges], holding what changed in the device
+/// list since the requested revision.
+///
+/// `devices` holds devices that connected since then, with their current
+/// info, and `removed_devices` the indexes of devices that went away. A device
+/// that reconnected is in `devices`, so clients should replace any device they
+/// already have at its index. If the server no longer has changes going back
+/// to the requested revision, `full` is set and `devices` is the whole list,
+/// which replaces the client's. Clients send `revision` with their next
+/// request.
+#[derive(Default, Clone, Debug, PartialEq, ButtplugMessage)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct DeviceListChanges {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
+  id: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Revision"))]
+  revision: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Full"))]
+  full: bool,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Devices"))]
+  devices: Vec<DeviceMessageInfo>,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "RemovedDevices"))]
+  removed_devices: Vec<u32>,
+}
+
+impl DeviceListChanges {
+  pub fn new(
+    revision: u32,
+    full: bool,
+    devices: Vec<DeviceMessageInfo>,
+    removed_devices: Vec<u32>,
+  ) -> Self {
+    Self {
+      id: 1,
+      revision,
+      full,
+      devices,
+      removed_devices,
+    }
+  }
+
+  pub fn revision(&self) -> u32 {
+    self.revision
+  }
+
+  pub fn full(&self) -> bool {
+    self.full
+  }
+
+  pub fn devices(&self) -> &Vec<DeviceMessageInfo> {
+    &self.devices
+  }
+
+  pub fn removed_devices(&self) -> &Vec<u32> {
+    &self.removed_devices
+  }
+}
+
+impl ButtplugMessageValidator for DeviceListChanges {
+  fn is_valid(&self) -> Result<(), ButtplugMessageError> {
+    self.is_not_system_id(self.id)
+  }
+}
@@ -6,13 +6,111 @@
 // for full license information.
 
 use super::*;
+use once_cell::sync::OnceCell;
 #[cfg(feature = "serialize-json")]
-use serde::{Deserialize, Serialize, Serializer};
-use std::collections::{BTreeMap, HashMap};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+  collections::{BTreeMap, HashMap},
+  ops::Deref,
+  sync::{Arc, Mutex, Weak},
+};
 
 pub type DeviceMessageAttributesMap = HashMap<ButtplugDeviceMessageType, DeviceMessageAttributes>;
 
-fn ordered_map<S>(value: &DeviceMessageAttributesMap, serializer: S) -> Result<S::Ok, S::Error>
+/// Attribute maps handed out by [SharedDeviceMessageAttributesMap::interned],
+/// by key. Only weak references are kept, so maps go away with the last device
+/// or message using them.
+static INTERNED_ATTRIBUTE_MAPS: OnceCell<
+  Mutex<HashMap<String, Vec<Weak<DeviceMessageAttributesMap>>>>,
+> = OnceCell::new();
+
+/// Attribute map that can be shared between devices and messages without
+/// copying it. Cloning one only bumps a reference count, so DeviceAdded and
+/// DeviceList messages don't copy every attribute of every device they list.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SharedDeviceMessageAttributesMap(Arc<DeviceMessageAttributesMap>);
+
+impl SharedDeviceMessageAttributesMap {
+  /// Shares `attributes` with every other map interned under the same key with
+  /// the same attributes, i.e. all connected devices of one model. `key`
+  /// should identify the model, such as its protocol and name.
+  pub fn interned(key: &str, attributes: DeviceMessageAttributesMap) -> Self {
+    let mut interned = INTERNED_ATTRIBUTE_MAPS
+      .get_or_init(Default::default)
+      .lock()
+      .expect("We never panic while holding this lock.");
+    let maps = interned.entry(key.to_owned()).or_default();
+    maps.retain(|map| map.strong_count() > 0);
+    if let Some(map) = maps
+      .iter()
+      .filter_map(Weak::upgrade)
+      .find(|map| **map == attributes)
+    {
+      return Self(map);
+    }
+    let map = Arc::new(attributes);
+    maps.push(Arc::downgrade(&map));
+    Self(map)
+  }
+
+  /// True if both share the same map, rather than just having the same
+  /// attributes.
+  pub fn ptr_eq(&self, other: &Self) -> bool {
+    Arc::ptr_eq(&self.0, &other.0)
+  }
+
+  /// The map, copied only if something else is still sharing it.
+  pub fn into_map(self) -> DeviceMessageAttributesMap {
+    Arc::try_unwrap(self.0).unwrap_or_else(|map| (*map).clone())
+  }
+}
+
+impl Deref for SharedDeviceMessageAttributesMap {
+  type Target = DeviceMessageAttributesMap;
+
+  fn deref(&self) -> &Self::Target {
+    &self.0
+  }
+}
+
+impl From<DeviceMessageAttributesMap> for SharedDeviceMessageAttributesMap {
+  fn from(attributes: DeviceMessageAttributesMap) -> Self {
+    Self(Arc::new(attributes))
+  }
+}
+
+impl From<&DeviceMessageAttributesMap> for SharedDeviceMessageAttributesMap {
+  fn from(attributes: &DeviceMessageAttributesMap) -> Self {
+    Self(Arc::new(attributes.clone()))
+  }
+}
+
+#[cfg(feature = "serialize-json")]
+impl Serialize for SharedDeviceMessageAttributesMap {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: Serializer,
+  {
+    ordered_map(&self.0, serializer)
+  }
+}
+
+#[cfg(feature = "serialize-json")]
+impl<'de> Deserialize<'de> for SharedDeviceMessageAttributesMap {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    DeviceMessageAttributesMap::deserialize(deserializer).map(Self::from)
+  }
+}
+
+/// Serializes attribute maps sorted by message type, so output doesn't depend
+/// on hash order.
+pub(super) fn ordered_map<S>(
+  value: &DeviceMessageAttributesMap,
+  serializer: S,
+) -> Result<S::Ok, S::Error>
 where
   S: Serializer,
 {
@@ -27,28 +125,38 @@ pub struct DeviceMessageInfo {
   pub device_index: u32,
   #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceName"))]
   pub device_name: String,
+  /// Hardware address of the device, which stays the same across
+  /// connections. Only sent in spec v3.
   #[cfg_attr(
     feature = "serialize-json",
-    serde(rename = "DeviceMessages", serialize_with = "ordered_map")
+    serde(
+      rename = "DeviceAddress",
+      default,
+      skip_serializing_if = "Option::is_none"
+    )
   )]
-  pub device_messages: DeviceMessageAttributesMap,
+  pub device_address: Option<String>,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceMessages"))]
+  pub device_messages: SharedDeviceMessageAttributesMap,
   // We need to store off the original device messages we had passed in, as we
   // may need to include message attributes in earlier versions that are
   // deprecated in later versions.
   #[cfg_attr(feature = "serialize-json", serde(skip))]
-  pub original_device_messages: DeviceMessageAttributesMap,
+  pub original_device_messages: SharedDeviceMessageAttributesMap,
 }
 
 impl DeviceMessageInfo {
   pub fn new(
     device_index: u32,
     device_name: &str,
-    device_messages: DeviceMessageAttributesMap,
+    device_messages: impl Into<SharedDeviceMessageAttributesMap>,
   ) -> Self {
+    let device_messages = device_messages.into();
     Self {
       device_index,
       device_name: device_name.to_owned(),
-      device_messages: device_messages.to_owned(),
+      device_address: None,
+      device_messages: device_messages.clone(),
       original_device_messages: device_messages,
     }
   }
@@ -59,8 +167,9 @@ impl From<DeviceAdded> for DeviceMessageInfo {
     Self {
       device_index: device_added.device_index(),
       device_name: device_added.device_name().clone(),
-      device_messages: device_added.device_messages().clone(),
-      original_device_messages: device_added.device_messages().clone(),
+      device_address: device_added.device_address().clone(),
+      device_messages: device_added.shared_device_messages().clone(),
+      original_device_messages: device_added.shared_device_messages().clone(),
     }
   }
 }
@@ -92,7 +201,7 @@ impl From<DeviceMessageInfo> for DeviceMessageInfoV1 {
     let mut dmi_v1 = Self {
       device_index: device_message_info.device_index,
       device_name: device_message_info.device_name,
-      device_messages: device_message_info.original_device_messages,
+      device_messages: device_message_info.original_device_messages.into_map(),
     };
     // Remove entries that weren't in V1.
     let v2_message_types = [
@@ -102,6 +211,10 @@ impl From<DeviceMessageInfo> for DeviceMessageInfoV1 {
       ButtplugDeviceMessageType::RawUnsubscribeCmd,
       ButtplugDeviceMessageType::BatteryLevelCmd,
       ButtplugDeviceMessageType::RSSILevelCmd,
+      ButtplugDeviceMessageType::UploadPatternCmd,
+      ButtplugDeviceMessageType::OscillateCmd,
+      ButtplugDeviceMessageType::SensorSubscribeCmd,
+      ButtplugDeviceMessageType::SensorUnsubscribeCmd,
     ];
     for t in &v2_message_types {
       dmi_v1.device_messages.remove(t);
@@ -0,0 +1,43 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::*;
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+
+/// Server event sent when a device claim is released, either through
+/// [ReleaseDevice] or because the claiming client disconnected.
+#[derive(Debug, ButtplugDeviceMessage, PartialEq, Clone)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct DeviceReleased {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
+  id: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceIndex"))]
+  device_index: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "ClientName"))]
+  client_name: String,
+}
+
+impl DeviceReleased {
+  pub fn new(device_index: u32, client_name: &str) -> Self {
+    Self {
+      id: 0,
+      device_index,
+      client_name: client_name.to_owned(),
+    }
+  }
+
+  pub fn client_name(&self) -> &str {
+    &self.client_name
+  }
+}
+
+impl ButtplugMessageValidator for DeviceReleased {
+  fn is_valid(&self) -> Result<(), ButtplugMessageError> {
+    self.is_system_id(self.id)
+  }
+}
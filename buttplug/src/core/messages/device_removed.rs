@@ -9,6 +9,22 @@ use super::*;
 #[cfg(feature = "serialize-json")]
 use serde::{Deserialize, Serialize};
 
+/// Why a device was removed from the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub enum DeviceRemovedReason {
+  /// Disconnected on request, via the server's device manager.
+  UserRequested,
+  /// The device went away without being asked to, i.e. went out of range or
+  /// ran out of battery.
+  ConnectionLost,
+  /// Disconnected after a ping timeout, as it couldn't be stopped.
+  PingTimeout,
+  /// The device communication manager the device was connected through shut
+  /// down.
+  CommManagerShutdown,
+}
+
 #[derive(Debug, Default, ButtplugMessage, Clone, PartialEq)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
 pub struct DeviceRemoved {
@@ -16,6 +32,11 @@ pub struct DeviceRemoved {
   id: u32,
   #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceIndex"))]
   device_index: u32,
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(rename = "Reason", skip_serializing_if = "Option::is_none", default)
+  )]
+  reason: Option<DeviceRemovedReason>,
 }
 
 impl DeviceRemoved {
@@ -23,12 +44,25 @@ impl DeviceRemoved {
     Self {
       id: 0,
       device_index,
+      reason: None,
+    }
+  }
+
+  pub fn new_with_reason(device_index: u32, reason: DeviceRemovedReason) -> Self {
+    Self {
+      id: 0,
+      device_index,
+      reason: Some(reason),
     }
   }
 
   pub fn device_index(&self) -> u32 {
     self.device_index
   }
+
+  pub fn reason(&self) -> Option<DeviceRemovedReason> {
+    self.reason
+  }
 }
 
 impl ButtplugMessageValidator for DeviceRemoved {
@@ -36,3 +70,41 @@ impl ButtplugMessageValidator for DeviceRemoved {
     self.is_system_id(self.id)
   }
 }
+
+/// DeviceRemoved as of spec v2, before removal reasons were added.
+#[derive(Debug, Default, ButtplugMessage, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct DeviceRemovedV2 {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
+  id: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceIndex"))]
+  device_index: u32,
+}
+
+impl DeviceRemovedV2 {
+  pub fn new(device_index: u32) -> Self {
+    Self {
+      id: 0,
+      device_index,
+    }
+  }
+
+  pub fn device_index(&self) -> u32 {
+    self.device_index
+  }
+}
+
+impl From<DeviceRemoved> for DeviceRemovedV2 {
+  fn from(msg: DeviceRemoved) -> Self {
+    Self {
+      id: msg.id,
+      device_index: msg.device_index,
+    }
+  }
+}
+
+impl ButtplugMessageValidator for DeviceRemovedV2 {
+  fn is_valid(&self) -> Result<(), ButtplugMessageError> {
+    self.is_system_id(self.id)
+  }
+}
@@ -6,7 +6,7 @@
 // for full license information.
 
 use super::*;
-use crate::core::errors::*;
+use crate::{core::errors::*, device::Endpoint};
 #[cfg(feature = "serialize-json")]
 use serde::{Deserialize, Serialize};
 #[cfg(feature = "serialize-json")]
@@ -25,6 +25,174 @@ pub enum ErrorCode {
   ErrorDevice,
 }
 
+/// Machine readable classification of an error, carried in [ErrorDetails] so
+/// clients can react to specific failures without parsing the error message.
+#[derive(Debug, Clone, PartialEq, Eq, Copy)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub enum ErrorClass {
+  Unknown,
+  HandshakeRequired,
+  HandshakeAlreadyHappened,
+  SpecVersionMismatch,
+  PingedOut,
+  PingTimer,
+  UnexpectedMessage,
+  InvalidMessage,
+  MessageConversion,
+  DeviceNotAvailable,
+  DeviceNotConnected,
+  DeviceMessageNotSupported,
+  DeviceFeatureMismatch,
+  DeviceInvalidEndpoint,
+  DeviceCommunication,
+  DeviceWriteNotConfirmed,
+  DeviceClaimed,
+  DeviceNotClaimed,
+  DeviceScanning,
+  DeviceCommandQueue,
+  DeviceEmergencyStop,
+  DevicePermission,
+  NoBluetoothAdapter,
+  DeviceProtocol,
+  DeviceConfiguration,
+  ReservedMessageId,
+  MessageIdInUse,
+  MessageNotPermitted,
+  ServerOverloaded,
+}
+
+/// Structured information about an error, sent alongside the error message
+/// text. Detail fields are only filled in when they apply to the error.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct ErrorDetails {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "ErrorClass"))]
+  pub error_class: ErrorClass,
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(
+      rename = "DeviceIndex",
+      skip_serializing_if = "Option::is_none",
+      default
+    )
+  )]
+  pub device_index: Option<u32>,
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(
+      rename = "MessageType",
+      skip_serializing_if = "Option::is_none",
+      default
+    )
+  )]
+  pub message_type: Option<ButtplugDeviceMessageType>,
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(rename = "Endpoint", skip_serializing_if = "Option::is_none", default)
+  )]
+  pub endpoint: Option<Endpoint>,
+}
+
+impl ErrorDetails {
+  pub fn new(error_class: ErrorClass) -> Self {
+    Self {
+      error_class,
+      device_index: None,
+      message_type: None,
+      endpoint: None,
+    }
+  }
+}
+
+impl From<&ButtplugError> for ErrorDetails {
+  fn from(error: &ButtplugError) -> Self {
+    let error_class = match error {
+      ButtplugError::ButtplugHandshakeError(err) => match err {
+        ButtplugHandshakeError::UnexpectedHandshakeMessageReceived(_) => {
+          ErrorClass::UnexpectedMessage
+        }
+        ButtplugHandshakeError::RequestServerInfoExpected => ErrorClass::HandshakeRequired,
+        ButtplugHandshakeError::HandshakeAlreadyHappened => ErrorClass::HandshakeAlreadyHappened,
+        ButtplugHandshakeError::MessageSpecVersionMismatch(..) => ErrorClass::SpecVersionMismatch,
+        ButtplugHandshakeError::UntypedDeserializedError(_) => ErrorClass::Unknown,
+      },
+      ButtplugError::ButtplugMessageError(err) => match err {
+        ButtplugMessageError::UnexpectedMessageType(_)
+        | ButtplugMessageError::UnhandledMessage(_) => ErrorClass::UnexpectedMessage,
+        ButtplugMessageError::VersionError(..)
+        | ButtplugMessageError::MessageConversionError(_) => ErrorClass::MessageConversion,
+        ButtplugMessageError::InvalidMessageContents(_)
+        | ButtplugMessageError::ValidationError(_)
+        | ButtplugMessageError::MessageSerializationError(_) => ErrorClass::InvalidMessage,
+        ButtplugMessageError::ReservedMessageId => ErrorClass::ReservedMessageId,
+        ButtplugMessageError::MessageIdInUse(_) => ErrorClass::MessageIdInUse,
+        ButtplugMessageError::MessageNotPermitted(..) => ErrorClass::MessageNotPermitted,
+        ButtplugMessageError::ServerOverloaded(_) => ErrorClass::ServerOverloaded,
+        ButtplugMessageError::UntypedDeserializedError(_) => ErrorClass::Unknown,
+      },
+      ButtplugError::ButtplugPingError(err) => match err {
+        ButtplugPingError::PingedOut => ErrorClass::PingedOut,
+        ButtplugPingError::PingTimerNotRunning | ButtplugPingError::InvalidPingTimeout => {
+          ErrorClass::PingTimer
+        }
+        ButtplugPingError::UntypedDeserializedError(_) => ErrorClass::Unknown,
+      },
+      ButtplugError::ButtplugDeviceError(err) => {
+        let mut details = ErrorDetails::new(match err {
+          ButtplugDeviceError::DeviceNotAvailable(_) => ErrorClass::DeviceNotAvailable,
+          ButtplugDeviceError::DeviceNotConnected(_) => ErrorClass::DeviceNotConnected,
+          ButtplugDeviceError::MessageNotSupported(_)
+          | ButtplugDeviceError::UnhandledCommand(_) => ErrorClass::DeviceMessageNotSupported,
+          ButtplugDeviceError::DeviceFeatureCountMismatch(..)
+          | ButtplugDeviceError::DeviceFeatureIndexError(..) => ErrorClass::DeviceFeatureMismatch,
+          ButtplugDeviceError::InvalidEndpoint(_) => ErrorClass::DeviceInvalidEndpoint,
+          ButtplugDeviceError::DeviceConnectionError(_)
+          | ButtplugDeviceError::DeviceConnectionFailed(_)
+          | ButtplugDeviceError::DeviceInitializationTimeout(_)
+          | ButtplugDeviceError::DeviceCommunicationError(_)
+          | ButtplugDeviceError::DeviceWriteTooLong(..) => ErrorClass::DeviceCommunication,
+          ButtplugDeviceError::DeviceWriteNotConfirmed(..) => ErrorClass::DeviceWriteNotConfirmed,
+          ButtplugDeviceError::DeviceClaimedByOtherClient(..) => ErrorClass::DeviceClaimed,
+          ButtplugDeviceError::DeviceNotClaimed(_) => ErrorClass::DeviceNotClaimed,
+          ButtplugDeviceError::DeviceScanningAlreadyStarted
+          | ButtplugDeviceError::DeviceScanningAlreadyStopped
+          | ButtplugDeviceError::DeviceScanningFailed(_) => ErrorClass::DeviceScanning,
+          ButtplugDeviceError::DeviceCommandCancelled
+          | ButtplugDeviceError::DeviceCommandQueueFull(_) => ErrorClass::DeviceCommandQueue,
+          ButtplugDeviceError::DevicesEmergencyStopped
+          | ButtplugDeviceError::EmergencyStopFailed(_) => ErrorClass::DeviceEmergencyStop,
+          ButtplugDeviceError::DevicePermissionError(_) => ErrorClass::DevicePermission,
+          ButtplugDeviceError::NoBluetoothAdapter => ErrorClass::NoBluetoothAdapter,
+          ButtplugDeviceError::DeviceSpecificError(_)
+          | ButtplugDeviceError::ProtocolAttributesNotFound(_)
+          | ButtplugDeviceError::ProtocolNotImplemented(_)
+          | ButtplugDeviceError::ProtocolSpecificError(..)
+          | ButtplugDeviceError::ProtocolRequirementError(_) => ErrorClass::DeviceProtocol,
+          ButtplugDeviceError::DeviceConfigurationFileError(_) => ErrorClass::DeviceConfiguration,
+          ButtplugDeviceError::UntypedDeserializedError(_) => ErrorClass::Unknown,
+        });
+        match err {
+          ButtplugDeviceError::DeviceNotAvailable(index)
+          | ButtplugDeviceError::DeviceClaimedByOtherClient(index, _)
+          | ButtplugDeviceError::DeviceNotClaimed(index) => details.device_index = Some(*index),
+          ButtplugDeviceError::MessageNotSupported(message_type) => {
+            details.message_type = Some(*message_type)
+          }
+          ButtplugDeviceError::InvalidEndpoint(endpoint)
+          | ButtplugDeviceError::DeviceWriteNotConfirmed(endpoint, _)
+          | ButtplugDeviceError::DeviceWriteTooLong(endpoint, ..) => {
+            details.endpoint = Some(*endpoint)
+          }
+          _ => {}
+        }
+        return details;
+      }
+      ButtplugError::ButtplugUnknownError(_) => ErrorClass::Unknown,
+    };
+    ErrorDetails::new(error_class)
+  }
+}
+
 /// Represents the Buttplug Protocol Error message, as documented in the [Buttplug
 /// Protocol Spec](https://buttplug-spec.docs.buttplug.io/status.html#error).
 // Error is one of the few things that can have either a System ID or message
@@ -42,6 +210,17 @@ pub struct Error {
   /// Description of the error.
   #[cfg_attr(feature = "serialize-json", serde(rename = "ErrorMessage"))]
   pub error_message: String,
+  /// Structured error information. Optional, so clients and servers that
+  /// don't know about it can keep using the error code and message.
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(
+      rename = "ErrorDetails",
+      skip_serializing_if = "Option::is_none",
+      default
+    )
+  )]
+  pub error_details: Option<ErrorDetails>,
   #[cfg_attr(feature = "serialize-json", serde(skip))]
   original_error: Option<ButtplugError>,
 }
@@ -51,6 +230,7 @@ impl PartialEq for Error {
     self.id == other.id
       && self.error_code == other.error_code
       && self.error_message == other.error_message
+      && self.error_details == other.error_details
   }
 }
 
@@ -65,10 +245,19 @@ impl Error {
       id: 0,
       error_code,
       error_message: error_message.to_string(),
+      error_details: original_error.as_ref().map(ErrorDetails::from),
       original_error,
     }
   }
 
+  /// Sets the index of the device the error is about, if the error doesn't
+  /// already name one.
+  pub fn set_device_index(&mut self, device_index: u32) {
+    if let Some(details) = &mut self.error_details {
+      details.device_index.get_or_insert(device_index);
+    }
+  }
+
   pub fn original_error(&self) -> ButtplugError {
     if self.original_error.is_some() {
       self
@@ -143,7 +332,16 @@ impl From<Error> for ErrorV0 {
 #[cfg(feature = "serialize-json")]
 #[cfg(test)]
 mod test {
-  use crate::core::messages::{ButtplugCurrentSpecServerMessage, Error, ErrorCode};
+  use crate::core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::{
+      ButtplugCurrentSpecServerMessage,
+      ButtplugDeviceMessageType,
+      Error,
+      ErrorClass,
+      ErrorCode,
+    },
+  };
 
   const ERROR_STR: &str = "{\"Error\":{\"Id\":0,\"ErrorCode\":1,\"ErrorMessage\":\"Test Error\"}}";
 
@@ -171,4 +369,33 @@ mod test {
       union
     );
   }
+
+  #[test]
+  fn test_error_details_serialize() {
+    let mut error = Error::from(ButtplugError::from(
+      ButtplugDeviceError::MessageNotSupported(ButtplugDeviceMessageType::RotateCmd),
+    ));
+    error.set_device_index(3);
+    let js = serde_json::to_value(&ButtplugCurrentSpecServerMessage::Error(error))
+      .expect("Infallible serialization.");
+    assert_eq!(
+      js["Error"]["ErrorDetails"],
+      serde_json::json!({
+        "ErrorClass": "DeviceMessageNotSupported",
+        "DeviceIndex": 3,
+        "MessageType": "RotateCmd"
+      })
+    );
+  }
+
+  #[test]
+  fn test_error_details_keep_existing_device_index() {
+    let mut error = Error::from(ButtplugError::from(
+      ButtplugDeviceError::DeviceNotAvailable(5),
+    ));
+    error.set_device_index(1);
+    let details = error.error_details.expect("Test, assuming infallible");
+    assert_eq!(details.error_class, ErrorClass::DeviceNotAvailable);
+    assert_eq!(details.device_index, Some(5));
+  }
 }
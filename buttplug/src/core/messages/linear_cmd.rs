@@ -51,6 +51,13 @@ pub struct LinearCmd {
   device_index: u32,
   #[cfg_attr(feature = "serialize-json", serde(rename = "Vectors"))]
   vectors: Vec<VectorSubcommand>,
+  /// When the client sent the command, in milliseconds since the unix epoch
+  /// on the server's clock, see [RequestServerTime].
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(rename = "SentAt", skip_serializing_if = "Option::is_none", default)
+  )]
+  sent_at: Option<u64>,
 }
 
 impl LinearCmd {
@@ -59,12 +66,50 @@ impl LinearCmd {
       id: 1,
       device_index,
       vectors,
+      sent_at: None,
     }
   }
 
   pub fn vectors(&self) -> &Vec<VectorSubcommand> {
     &self.vectors
   }
+
+  pub fn sent_at(&self) -> Option<u64> {
+    self.sent_at
+  }
+
+  /// Timestamps the command, so the server can make up for however long it
+  /// takes to reach the device.
+  pub fn set_sent_at(&mut self, sent_at: Option<u64>) {
+    self.sent_at = sent_at;
+  }
+
+  /// Server time the slowest movement should finish by, if the command was
+  /// timestamped.
+  pub fn finish_time(&self) -> Option<u64> {
+    let longest = self
+      .vectors
+      .iter()
+      .map(|vector| vector.duration)
+      .max()
+      .unwrap_or(0);
+    self.sent_at.map(|sent_at| sent_at + longest as u64)
+  }
+
+  /// Shortens every movement by the time since the command was sent, so they
+  /// still finish when the client meant them to. Movements whose time is
+  /// already up are sent with no duration. Does nothing if the command wasn't
+  /// timestamped.
+  pub fn compensate_latency(&mut self, now: u64) {
+    if let Some(sent_at) = self.sent_at {
+      let latency = now.saturating_sub(sent_at).min(u32::MAX as u64) as u32;
+      for vector in &mut self.vectors {
+        vector.duration = vector.duration.saturating_sub(latency);
+      }
+      // The finish time stays the same, and compensating again does nothing.
+      self.sent_at = Some(sent_at.max(now));
+    }
+  }
 }
 
 impl ButtplugMessageValidator for LinearCmd {
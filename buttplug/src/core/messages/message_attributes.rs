@@ -30,6 +30,9 @@ pub struct DeviceMessageAttributes {
   #[serde(rename = "MaxDuration")]
   #[serde(skip_serializing_if = "Option::is_none")]
   pub max_duration: Option<Vec<u32>>,
+  #[serde(rename = "FeatureDescriptors")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub feature_descriptors: Option<Vec<FeatureDescriptor>>,
   /*
   // Unimplemented attributes
   #[serde(rename = "Patterns")]
@@ -39,8 +42,192 @@ pub struct DeviceMessageAttributes {
   #[serde(skip_serializing_if = "Option::is_none")]
   actuator_type: Option<Vec<String>>,
   */
+  // Device configuration only, never serialized. Per feature [min, max] steps,
+  // for actuators that don't do anything in the lower part of their range.
+  #[serde(rename = "StepRange")]
+  #[serde(skip_serializing)]
+  pub step_range: Option<Vec<(u32, u32)>>,
+  // Device configuration only, never serialized. Per feature lookup table of
+  // steps that the 0.0-1.0 range is spread across, for nonlinear actuators.
+  #[serde(rename = "StepMap")]
+  #[serde(skip_serializing)]
+  pub step_map: Option<Vec<Vec<u32>>>,
+  // Device configuration only, never serialized. If true, every feature's
+  // value is sent whenever any of them changes, for protocols that pack all
+  // features into one command.
+  #[serde(rename = "MatchAll")]
+  #[serde(skip_serializing)]
+  pub match_all: Option<bool>,
+  // Device configuration only, never serialized. If true, commands are sent
+  // even if nothing changed, for devices that need repeats as a keepalive.
+  #[serde(rename = "AlwaysSend")]
+  #[serde(skip_serializing)]
+  pub always_send: Option<bool>,
+  // Device configuration only, never serialized. If set on StopDeviceCmd,
+  // stop writes are sent with response and the device has to echo them back,
+  // for devices where a lost stop command is dangerous.
+  #[serde(rename = "ConfirmWrites")]
+  #[serde(skip_serializing)]
+  pub confirm_writes: Option<WriteConfirmation>,
+  // Device configuration only, never serialized. What StopDeviceCmd does
+  // besides cutting power, for devices that should be left in a known state.
+  #[serde(rename = "StopBehavior")]
+  #[serde(skip_serializing)]
+  pub stop_behavior: Option<StopBehavior>,
   // Never serialize this, its for internal use only
   #[serde(rename = "FeatureOrder")]
   #[serde(skip)]
   pub feature_order: Option<Vec<u32>>,
 }
+
+impl DeviceMessageAttributes {
+  /// Attributes for a command addressing one feature per entry in
+  /// `step_count`, each with that many steps. For protocols that build their
+  /// attributes in code, rather than reading them from device configuration.
+  pub fn with_step_count(step_count: Vec<u32>) -> Self {
+    Self {
+      feature_count: Some(step_count.len() as u32),
+      step_count: Some(step_count),
+      ..Default::default()
+    }
+  }
+
+  /// Copies per feature step counts from StepCount into any feature
+  /// descriptors that don't specify their own, so clients can get everything
+  /// about a feature from its descriptor.
+  pub(crate) fn fill_feature_descriptor_step_counts(&mut self) {
+    if let (Some(descriptors), Some(step_count)) = (&mut self.feature_descriptors, &self.step_count)
+    {
+      for (descriptor, steps) in descriptors.iter_mut().zip(step_count.iter()) {
+        if descriptor.step_count.is_none() {
+          descriptor.step_count = Some(*steps);
+        }
+      }
+    }
+  }
+}
+
+/// Kind of actuator a device feature drives.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Display, Serialize, Deserialize)]
+pub enum ActuatorType {
+  Vibrate,
+  Rotate,
+  Oscillate,
+  Constrict,
+  Inflate,
+  Position,
+}
+
+/// Kind of reading a device sensor feature produces.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Display, Serialize, Deserialize)]
+pub enum SensorType {
+  /// Squeeze or touch pressure, i.e. the Kiiroo Pearl's touch sensors.
+  Pressure,
+  /// Digital buttons, as a bitmask of which are held down, i.e. a gamepad's
+  /// face buttons.
+  Button,
+  /// Analog controls, one value per axis, i.e. a gamepad's triggers and
+  /// thumbsticks.
+  Axis,
+}
+
+/// Describes a single feature of a device message, in the same order as the
+/// features are addressed in the message, so clients can tell features apart
+/// in UI (e.g. "Clitoral Vibrator" vs "Insertable Vibrator").
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct FeatureDescriptor {
+  #[serde(rename = "ActuatorType")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub actuator_type: Option<ActuatorType>,
+  /// Only set on sensor features, i.e. for SensorSubscribeCmd.
+  #[serde(rename = "SensorType")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub sensor_type: Option<SensorType>,
+  /// Free form description of the feature, usually its location on the body
+  /// or the device.
+  #[serde(rename = "Descriptor")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub descriptor: Option<String>,
+  #[serde(rename = "StepCount")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub step_count: Option<u32>,
+}
+
+/// Extra commands sent on StopDeviceCmd. Set per protocol or device in the
+/// device configuration, under StopDeviceCmd, or per device in user config.
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct StopBehavior {
+  /// Position (0.0-1.0) linear features move to on stop, i.e. 0.0 to park a
+  /// stroker at the bottom of its travel.
+  #[serde(rename = "ParkPosition")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub park_position: Option<f64>,
+  /// Time in milliseconds to take moving to ParkPosition.
+  #[serde(rename = "ParkDuration")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub park_duration: Option<u32>,
+}
+
+/// Where a device echoes back what was written to it, so writes can be
+/// confirmed. Set under StopDeviceCmd in the device configuration.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WriteConfirmation {
+  /// Endpoint the echo shows up on. Usually not the endpoint that was written
+  /// to, as most devices can't read back their command endpoint.
+  #[serde(rename = "Endpoint")]
+  pub endpoint: Endpoint,
+  /// If true, wait for the echo to be sent as a notification, instead of
+  /// reading the endpoint.
+  #[serde(rename = "Notify")]
+  #[serde(default)]
+  pub notify: bool,
+}
+
+#[cfg(test)]
+mod test {
+  use super::{ActuatorType, DeviceMessageAttributes, FeatureDescriptor};
+
+  #[test]
+  fn test_feature_descriptor_serialize() {
+    let attributes = DeviceMessageAttributes {
+      feature_count: Some(1),
+      feature_descriptors: Some(vec![FeatureDescriptor {
+        actuator_type: Some(ActuatorType::Vibrate),
+        descriptor: Some("Clitoral Vibrator".to_owned()),
+        step_count: Some(20),
+        ..Default::default()
+      }]),
+      ..Default::default()
+    };
+    let js = serde_json::to_string(&attributes).expect("Infallible serialization.");
+    assert_eq!(
+      js,
+      "{\"FeatureCount\":1,\"FeatureDescriptors\":[{\"ActuatorType\":\"Vibrate\",\"Descriptor\":\"Clitoral Vibrator\",\"StepCount\":20}]}"
+    );
+  }
+
+  #[test]
+  fn test_fill_feature_descriptor_step_counts() {
+    let mut attributes = DeviceMessageAttributes {
+      feature_count: Some(2),
+      step_count: Some(vec![20, 10]),
+      feature_descriptors: Some(vec![
+        FeatureDescriptor {
+          descriptor: Some("Insertable Vibrator".to_owned()),
+          ..Default::default()
+        },
+        FeatureDescriptor {
+          step_count: Some(5),
+          ..Default::default()
+        },
+      ]),
+      ..Default::default()
+    };
+    attributes.fill_feature_descriptor_step_counts();
+    let descriptors = attributes
+      .feature_descriptors
+      .expect("Test, assuming infallible");
+    assert_eq!(descriptors[0].step_count, Some(20));
+    assert_eq!(descriptors[1].step_count, Some(5));
+  }
+}
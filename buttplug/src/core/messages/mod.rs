@@ -16,9 +16,14 @@
 
 mod battery_level_cmd;
 mod battery_level_reading;
+mod claim_device;
 mod device_added;
+mod device_claimed;
+mod device_initialization_failed;
 mod device_list;
+mod device_list_changes;
 mod device_message_info;
+mod device_released;
 mod device_removed;
 mod error;
 mod fleshlight_launch_fw12_cmd;
@@ -29,65 +34,105 @@ mod log_level;
 mod lovense_cmd;
 mod message_attributes;
 mod ok;
+mod oscillate_cmd;
 mod ping;
+mod ping_timeout;
 mod raw_read_cmd;
 mod raw_reading;
 mod raw_subscribe_cmd;
 mod raw_unsubscribe_cmd;
 mod raw_write_cmd;
+mod release_device;
 mod request_device_list;
+mod request_device_list_changes;
 mod request_log;
 mod request_server_info;
+mod request_server_time;
 mod rotate_cmd;
 mod rssi_level_cmd;
 mod rssi_level_reading;
 mod scanning_finished;
+mod scanning_partial_failure;
+mod scanning_started;
+mod sensor_reading;
+mod sensor_subscribe_cmd;
+mod sensor_unsubscribe_cmd;
 pub mod serializer;
 mod server_info;
+mod server_time;
 mod single_motor_vibrate_cmd;
 mod start_scanning;
 mod stop_all_devices;
 mod stop_device_cmd;
 mod stop_scanning;
 mod test;
+mod upload_pattern_cmd;
 mod vibrate_cmd;
 mod vorze_a10_cyclone_cmd;
 
 pub use self::log::Log;
 pub use battery_level_cmd::BatteryLevelCmd;
 pub use battery_level_reading::BatteryLevelReading;
+pub use claim_device::ClaimDevice;
 pub use device_added::{DeviceAdded, DeviceAddedV0, DeviceAddedV1};
+pub use device_claimed::DeviceClaimed;
+pub use device_initialization_failed::DeviceInitializationFailed;
 pub use device_list::{DeviceList, DeviceListV0, DeviceListV1};
-pub use device_message_info::{DeviceMessageAttributesMap, DeviceMessageInfo};
-pub use device_removed::DeviceRemoved;
-pub use error::{Error, ErrorCode, ErrorV0};
+pub use device_list_changes::DeviceListChanges;
+pub use device_message_info::{
+  DeviceMessageAttributesMap,
+  DeviceMessageInfo,
+  SharedDeviceMessageAttributesMap,
+};
+pub use device_released::DeviceReleased;
+pub use device_removed::{DeviceRemoved, DeviceRemovedReason, DeviceRemovedV2};
+pub use error::{Error, ErrorClass, ErrorCode, ErrorDetails, ErrorV0};
 pub use fleshlight_launch_fw12_cmd::FleshlightLaunchFW12Cmd;
 pub use kiiroo_cmd::KiirooCmd;
 pub use linear_cmd::{LinearCmd, VectorSubcommand};
 pub use log_level::LogLevel;
 pub use lovense_cmd::LovenseCmd;
-pub use message_attributes::DeviceMessageAttributes;
+pub use message_attributes::{
+  ActuatorType,
+  DeviceMessageAttributes,
+  FeatureDescriptor,
+  SensorType,
+  StopBehavior,
+  WriteConfirmation,
+};
 pub use ok::Ok;
+pub use oscillate_cmd::{OscillateCmd, OscillateSubcommand};
 pub use ping::Ping;
+pub use ping_timeout::{PingTimeout, PingTimeoutPolicy};
 pub use raw_read_cmd::RawReadCmd;
 pub use raw_reading::RawReading;
 pub use raw_subscribe_cmd::RawSubscribeCmd;
 pub use raw_unsubscribe_cmd::RawUnsubscribeCmd;
 pub use raw_write_cmd::RawWriteCmd;
+pub use release_device::ReleaseDevice;
 pub use request_device_list::RequestDeviceList;
+pub use request_device_list_changes::RequestDeviceListChanges;
 pub use request_log::RequestLog;
 pub use request_server_info::RequestServerInfo;
+pub use request_server_time::RequestServerTime;
 pub use rotate_cmd::{RotateCmd, RotationSubcommand};
 pub use rssi_level_cmd::RSSILevelCmd;
 pub use rssi_level_reading::RSSILevelReading;
 pub use scanning_finished::ScanningFinished;
+pub use scanning_partial_failure::{CommManagerScanningFailure, ScanningPartialFailure};
+pub use scanning_started::ScanningStarted;
+pub use sensor_reading::SensorReading;
+pub use sensor_subscribe_cmd::SensorSubscribeCmd;
+pub use sensor_unsubscribe_cmd::SensorUnsubscribeCmd;
 pub use server_info::{ServerInfo, ServerInfoV0};
+pub use server_time::ServerTime;
 pub use single_motor_vibrate_cmd::SingleMotorVibrateCmd;
 pub use start_scanning::StartScanning;
 pub use stop_all_devices::StopAllDevices;
 pub use stop_device_cmd::StopDeviceCmd;
 pub use stop_scanning::StopScanning;
 pub use test::Test;
+pub use upload_pattern_cmd::UploadPatternCmd;
 pub use vibrate_cmd::{VibrateCmd, VibrateSubcommand};
 pub use vorze_a10_cyclone_cmd::VorzeA10CycloneCmd;
 
@@ -107,6 +152,7 @@ pub enum ButtplugMessageSpecVersion {
   Version0 = 0,
   Version1 = 1,
   Version2 = 2,
+  Version3 = 3,
 }
 
 /// Message Id for events sent from the server, which are not in response to a
@@ -115,7 +161,7 @@ pub const BUTTPLUG_SERVER_EVENT_ID: u32 = 0;
 
 /// The current latest version of the spec implemented by the library.
 pub const BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION: ButtplugMessageSpecVersion =
-  ButtplugMessageSpecVersion::Version2;
+  ButtplugMessageSpecVersion::Version3;
 
 /// Base trait for all Buttplug Protocol Message Structs. Handles management of
 /// message ids, as well as implementing conveinence functions for converting
@@ -187,6 +233,7 @@ pub enum ButtplugDeviceMessageType {
   VibrateCmd,
   LinearCmd,
   RotateCmd,
+  OscillateCmd,
   StopDeviceCmd,
   RawWriteCmd,
   RawReadCmd,
@@ -194,6 +241,9 @@ pub enum ButtplugDeviceMessageType {
   RawUnsubscribeCmd,
   BatteryLevelCmd,
   RSSILevelCmd,
+  SensorSubscribeCmd,
+  SensorUnsubscribeCmd,
+  UploadPatternCmd,
   // Deprecated generic commands
   SingleMotorVibrateCmd,
   // Deprecated device specific commands
@@ -229,6 +279,7 @@ pub enum ButtplugCurrentSpecDeviceMessageType {
   VibrateCmd,
   LinearCmd,
   RotateCmd,
+  OscillateCmd,
   StopDeviceCmd,
   RawWriteCmd,
   RawReadCmd,
@@ -236,6 +287,9 @@ pub enum ButtplugCurrentSpecDeviceMessageType {
   RawUnsubscribeCmd,
   BatteryLevelCmd,
   RSSILevelCmd,
+  SensorSubscribeCmd,
+  SensorUnsubscribeCmd,
+  UploadPatternCmd,
 }
 
 // Ordering for ButtplugCurrentDeviceMessageType should be lexicographic, for
@@ -259,6 +313,9 @@ impl TryFrom<ButtplugDeviceMessageType> for ButtplugCurrentSpecDeviceMessageType
       ButtplugDeviceMessageType::VibrateCmd => Ok(ButtplugCurrentSpecDeviceMessageType::VibrateCmd),
       ButtplugDeviceMessageType::LinearCmd => Ok(ButtplugCurrentSpecDeviceMessageType::LinearCmd),
       ButtplugDeviceMessageType::RotateCmd => Ok(ButtplugCurrentSpecDeviceMessageType::RotateCmd),
+      ButtplugDeviceMessageType::OscillateCmd => {
+        Ok(ButtplugCurrentSpecDeviceMessageType::OscillateCmd)
+      }
       ButtplugDeviceMessageType::StopDeviceCmd => {
         Ok(ButtplugCurrentSpecDeviceMessageType::StopDeviceCmd)
       }
@@ -278,6 +335,15 @@ impl TryFrom<ButtplugDeviceMessageType> for ButtplugCurrentSpecDeviceMessageType
       ButtplugDeviceMessageType::RSSILevelCmd => {
         Ok(ButtplugCurrentSpecDeviceMessageType::RSSILevelCmd)
       }
+      ButtplugDeviceMessageType::SensorSubscribeCmd => {
+        Ok(ButtplugCurrentSpecDeviceMessageType::SensorSubscribeCmd)
+      }
+      ButtplugDeviceMessageType::SensorUnsubscribeCmd => {
+        Ok(ButtplugCurrentSpecDeviceMessageType::SensorUnsubscribeCmd)
+      }
+      ButtplugDeviceMessageType::UploadPatternCmd => {
+        Ok(ButtplugCurrentSpecDeviceMessageType::UploadPatternCmd)
+      }
       _ => Err(ButtplugMessageError::MessageConversionError(
         "Device message deprecated, does not exist in current version of protocol.".to_owned(),
       )),
@@ -291,6 +357,7 @@ impl From<ButtplugCurrentSpecDeviceMessageType> for ButtplugDeviceMessageType {
       ButtplugCurrentSpecDeviceMessageType::VibrateCmd => ButtplugDeviceMessageType::VibrateCmd,
       ButtplugCurrentSpecDeviceMessageType::LinearCmd => ButtplugDeviceMessageType::LinearCmd,
       ButtplugCurrentSpecDeviceMessageType::RotateCmd => ButtplugDeviceMessageType::RotateCmd,
+      ButtplugCurrentSpecDeviceMessageType::OscillateCmd => ButtplugDeviceMessageType::OscillateCmd,
       ButtplugCurrentSpecDeviceMessageType::StopDeviceCmd => {
         ButtplugDeviceMessageType::StopDeviceCmd
       }
@@ -306,6 +373,15 @@ impl From<ButtplugCurrentSpecDeviceMessageType> for ButtplugDeviceMessageType {
         ButtplugDeviceMessageType::BatteryLevelCmd
       }
       ButtplugCurrentSpecDeviceMessageType::RSSILevelCmd => ButtplugDeviceMessageType::RSSILevelCmd,
+      ButtplugCurrentSpecDeviceMessageType::SensorSubscribeCmd => {
+        ButtplugDeviceMessageType::SensorSubscribeCmd
+      }
+      ButtplugCurrentSpecDeviceMessageType::SensorUnsubscribeCmd => {
+        ButtplugDeviceMessageType::SensorUnsubscribeCmd
+      }
+      ButtplugCurrentSpecDeviceMessageType::UploadPatternCmd => {
+        ButtplugDeviceMessageType::UploadPatternCmd
+      }
     }
   }
 }
@@ -322,6 +398,7 @@ impl From<ButtplugCurrentSpecDeviceMessageType> for ButtplugDeviceMessageType {
   ButtplugClientMessageType,
   FromSpecificButtplugMessage,
 )]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
 pub enum ButtplugClientMessage {
   Ping(Ping),
   RequestLog(RequestLog),
@@ -331,11 +408,13 @@ pub enum ButtplugClientMessage {
   StartScanning(StartScanning),
   StopScanning(StopScanning),
   RequestDeviceList(RequestDeviceList),
+  RequestDeviceListChanges(RequestDeviceListChanges),
   // Generic commands
   StopAllDevices(StopAllDevices),
   VibrateCmd(VibrateCmd),
   LinearCmd(LinearCmd),
   RotateCmd(RotateCmd),
+  OscillateCmd(OscillateCmd),
   RawWriteCmd(RawWriteCmd),
   RawReadCmd(RawReadCmd),
   StopDeviceCmd(StopDeviceCmd),
@@ -344,6 +423,15 @@ pub enum ButtplugClientMessage {
   // Sensor commands
   BatteryLevelCmd(BatteryLevelCmd),
   RSSILevelCmd(RSSILevelCmd),
+  SensorSubscribeCmd(SensorSubscribeCmd),
+  SensorUnsubscribeCmd(SensorUnsubscribeCmd),
+  // Firmware pattern commands
+  UploadPatternCmd(UploadPatternCmd),
+  // Device arbitration commands
+  ClaimDevice(ClaimDevice),
+  ReleaseDevice(ReleaseDevice),
+  // Clock synchronization commands
+  RequestServerTime(RequestServerTime),
   // Deprecated generic commands
   SingleMotorVibrateCmd(SingleMotorVibrateCmd),
   // Deprecated device specific commands
@@ -352,7 +440,6 @@ pub enum ButtplugClientMessage {
   KiirooCmd(KiirooCmd),
   VorzeA10CycloneCmd(VorzeA10CycloneCmd),
   // To Add:
-  // PatternCmd
   // ShockCmd?
   // ToneEmitterCmd?
 }
@@ -379,20 +466,32 @@ pub enum ButtplugServerMessage {
   ServerInfo(ServerInfo),
   // Device enumeration messages
   DeviceList(DeviceList),
+  DeviceListChanges(DeviceListChanges),
   DeviceAdded(DeviceAdded),
   DeviceRemoved(DeviceRemoved),
+  ScanningStarted(ScanningStarted),
   ScanningFinished(ScanningFinished),
+  ScanningPartialFailure(ScanningPartialFailure),
+  DeviceInitializationFailed(DeviceInitializationFailed),
   // Generic commands
   RawReading(RawReading),
   // Sensor Reading Messages
   BatteryLevelReading(BatteryLevelReading),
   RSSILevelReading(RSSILevelReading),
+  SensorReading(SensorReading),
+  // Device arbitration events
+  DeviceClaimed(DeviceClaimed),
+  DeviceReleased(DeviceReleased),
+  // Connection status events
+  PingTimeout(PingTimeout),
+  // Clock synchronization replies
+  ServerTime(ServerTime),
 }
 
 /// Type alias for the latest version of client-to-server messages.
-pub type ButtplugCurrentSpecClientMessage = ButtplugSpecV2ClientMessage;
+pub type ButtplugCurrentSpecClientMessage = ButtplugSpecV3ClientMessage;
 /// Type alias for the latest version of server-to-client messages.
-pub type ButtplugCurrentSpecServerMessage = ButtplugSpecV2ServerMessage;
+pub type ButtplugCurrentSpecServerMessage = ButtplugSpecV3ServerMessage;
 
 /// Represents all client-to-server messages in v2 of the Buttplug Spec
 #[derive(
@@ -450,13 +549,107 @@ pub enum ButtplugSpecV2ServerMessage {
   // Device enumeration messages
   DeviceList(DeviceList),
   DeviceAdded(DeviceAdded),
+  DeviceRemoved(DeviceRemovedV2),
+  ScanningFinished(ScanningFinished),
+  // Generic commands
+  RawReading(RawReading),
+  // Sensor commands
+  BatteryLevelReading(BatteryLevelReading),
+  RSSILevelReading(RSSILevelReading),
+}
+
+/// Represents all client-to-server messages in v3 of the Buttplug Spec. v2 is
+/// published and frozen, so messages added since go here only.
+#[derive(
+  Debug,
+  Clone,
+  PartialEq,
+  ButtplugMessage,
+  ButtplugMessageValidator,
+  ButtplugClientMessageType,
+  FromSpecificButtplugMessage,
+  TryFromButtplugClientMessage,
+)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub enum ButtplugSpecV3ClientMessage {
+  // Handshake messages
+  RequestServerInfo(RequestServerInfo),
+  Ping(Ping),
+  // Device enumeration messages
+  StartScanning(StartScanning),
+  StopScanning(StopScanning),
+  RequestDeviceList(RequestDeviceList),
+  RequestDeviceListChanges(RequestDeviceListChanges),
+  // Generic commands
+  StopAllDevices(StopAllDevices),
+  VibrateCmd(VibrateCmd),
+  LinearCmd(LinearCmd),
+  RotateCmd(RotateCmd),
+  OscillateCmd(OscillateCmd),
+  RawWriteCmd(RawWriteCmd),
+  RawReadCmd(RawReadCmd),
+  StopDeviceCmd(StopDeviceCmd),
+  RawSubscribeCmd(RawSubscribeCmd),
+  RawUnsubscribeCmd(RawUnsubscribeCmd),
+  // Sensor commands
+  BatteryLevelCmd(BatteryLevelCmd),
+  RSSILevelCmd(RSSILevelCmd),
+  SensorSubscribeCmd(SensorSubscribeCmd),
+  SensorUnsubscribeCmd(SensorUnsubscribeCmd),
+  // Firmware pattern commands
+  UploadPatternCmd(UploadPatternCmd),
+  // Device arbitration commands
+  ClaimDevice(ClaimDevice),
+  ReleaseDevice(ReleaseDevice),
+  // Clock synchronization commands
+  RequestServerTime(RequestServerTime),
+  // Logging commands
+  RequestLog(RequestLog),
+}
+
+/// Represents all server-to-client messages in v3 of the Buttplug Spec. See
+/// [ButtplugSpecV3ClientMessage] for status.
+#[derive(
+  Debug,
+  Clone,
+  PartialEq,
+  ButtplugMessage,
+  ButtplugMessageValidator,
+  ButtplugServerMessageType,
+  FromSpecificButtplugMessage,
+  TryFromButtplugServerMessage,
+)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub enum ButtplugSpecV3ServerMessage {
+  // Status messages
+  Ok(Ok),
+  Error(Error),
+  // Handshake messages
+  ServerInfo(ServerInfo),
+  // Device enumeration messages
+  DeviceList(DeviceList),
+  DeviceListChanges(DeviceListChanges),
+  DeviceAdded(DeviceAdded),
   DeviceRemoved(DeviceRemoved),
+  ScanningStarted(ScanningStarted),
   ScanningFinished(ScanningFinished),
+  ScanningPartialFailure(ScanningPartialFailure),
+  DeviceInitializationFailed(DeviceInitializationFailed),
   // Generic commands
   RawReading(RawReading),
   // Sensor commands
   BatteryLevelReading(BatteryLevelReading),
   RSSILevelReading(RSSILevelReading),
+  SensorReading(SensorReading),
+  // Device arbitration events
+  DeviceClaimed(DeviceClaimed),
+  DeviceReleased(DeviceReleased),
+  // Connection status events
+  PingTimeout(PingTimeout),
+  // Clock synchronization replies
+  ServerTime(ServerTime),
+  // Logging events
+  Log(Log),
 }
 
 /// Represents all client-to-server messages in v1 of the Buttplug Spec
@@ -508,7 +701,7 @@ pub(crate) enum ButtplugSpecV1ServerMessage {
   // Device enumeration messages
   DeviceList(DeviceListV1),
   DeviceAdded(DeviceAddedV1),
-  DeviceRemoved(DeviceRemoved),
+  DeviceRemoved(DeviceRemovedV2),
   ScanningFinished(ScanningFinished),
 }
 
@@ -532,7 +725,7 @@ impl TryFrom<ButtplugServerMessage> for ButtplugSpecV1ServerMessage {
         Ok(ButtplugSpecV1ServerMessage::DeviceAdded(msg.into()))
       }
       ButtplugServerMessage::DeviceRemoved(msg) => {
-        Ok(ButtplugSpecV1ServerMessage::DeviceRemoved(msg))
+        Ok(ButtplugSpecV1ServerMessage::DeviceRemoved(msg.into()))
       }
       ButtplugServerMessage::ScanningFinished(msg) => {
         Ok(ButtplugSpecV1ServerMessage::ScanningFinished(msg))
@@ -593,7 +786,7 @@ pub(crate) enum ButtplugSpecV0ServerMessage {
   // Device enumeration messages
   DeviceList(DeviceListV0),
   DeviceAdded(DeviceAddedV0),
-  DeviceRemoved(DeviceRemoved),
+  DeviceRemoved(DeviceRemovedV2),
   ScanningFinished(ScanningFinished),
 }
 
@@ -617,7 +810,7 @@ impl TryFrom<ButtplugServerMessage> for ButtplugSpecV0ServerMessage {
         Ok(ButtplugSpecV0ServerMessage::DeviceAdded(msg.into()))
       }
       ButtplugServerMessage::DeviceRemoved(msg) => {
-        Ok(ButtplugSpecV0ServerMessage::DeviceRemoved(msg))
+        Ok(ButtplugSpecV0ServerMessage::DeviceRemoved(msg.into()))
       }
       ButtplugServerMessage::ScanningFinished(msg) => {
         Ok(ButtplugSpecV0ServerMessage::ScanningFinished(msg))
@@ -645,6 +838,7 @@ impl TryFrom<ButtplugServerMessage> for ButtplugSpecV0ServerMessage {
 )]
 pub enum ButtplugDeviceManagerMessageUnion {
   RequestDeviceList(RequestDeviceList),
+  RequestDeviceListChanges(RequestDeviceListChanges),
   StopAllDevices(StopAllDevices),
   StartScanning(StartScanning),
   StopScanning(StopScanning),
@@ -670,6 +864,7 @@ pub enum ButtplugDeviceCommandMessageUnion {
   VibrateCmd(VibrateCmd),
   LinearCmd(LinearCmd),
   RotateCmd(RotateCmd),
+  OscillateCmd(OscillateCmd),
   RawWriteCmd(RawWriteCmd),
   RawReadCmd(RawReadCmd),
   StopDeviceCmd(StopDeviceCmd),
@@ -677,4 +872,165 @@ pub enum ButtplugDeviceCommandMessageUnion {
   RawUnsubscribeCmd(RawUnsubscribeCmd),
   BatteryLevelCmd(BatteryLevelCmd),
   RSSILevelCmd(RSSILevelCmd),
+  SensorSubscribeCmd(SensorSubscribeCmd),
+  SensorUnsubscribeCmd(SensorUnsubscribeCmd),
+  UploadPatternCmd(UploadPatternCmd),
+}
+
+#[cfg(test)]
+mod spec_version_test {
+  use super::*;
+
+  // Spec versions a message is expected to convert to, in order v0, v1, v2,
+  // v3. These matches are exhaustive on purpose, so adding a message without
+  // deciding which spec versions it belongs to fails to compile.
+  fn server_message_spec_support(msg: &ButtplugServerMessage) -> [bool; 4] {
+    match msg {
+      ButtplugServerMessage::Ok(_)
+      | ButtplugServerMessage::Error(_)
+      | ButtplugServerMessage::ServerInfo(_)
+      | ButtplugServerMessage::DeviceList(_)
+      | ButtplugServerMessage::DeviceAdded(_)
+      | ButtplugServerMessage::DeviceRemoved(_)
+      | ButtplugServerMessage::ScanningFinished(_) => [true, true, true, true],
+      ButtplugServerMessage::Log(_) => [true, true, false, true],
+      ButtplugServerMessage::Test(_) => [false, false, false, false],
+      ButtplugServerMessage::RawReading(_)
+      | ButtplugServerMessage::BatteryLevelReading(_)
+      | ButtplugServerMessage::RSSILevelReading(_) => [false, false, true, true],
+      ButtplugServerMessage::ScanningStarted(_)
+      | ButtplugServerMessage::DeviceClaimed(_)
+      | ButtplugServerMessage::DeviceReleased(_)
+      | ButtplugServerMessage::PingTimeout(_)
+      | ButtplugServerMessage::ServerTime(_)
+      | ButtplugServerMessage::ScanningPartialFailure(_)
+      | ButtplugServerMessage::DeviceInitializationFailed(_)
+      | ButtplugServerMessage::SensorReading(_)
+      | ButtplugServerMessage::DeviceListChanges(_) => [false, false, false, true],
+    }
+  }
+
+  fn client_message_spec_support(msg: &ButtplugClientMessage) -> [bool; 4] {
+    match msg {
+      ButtplugClientMessage::RequestServerInfo(_)
+      | ButtplugClientMessage::Ping(_)
+      | ButtplugClientMessage::StartScanning(_)
+      | ButtplugClientMessage::StopScanning(_)
+      | ButtplugClientMessage::RequestDeviceList(_)
+      | ButtplugClientMessage::StopAllDevices(_)
+      | ButtplugClientMessage::StopDeviceCmd(_) => [true, true, true, true],
+      ButtplugClientMessage::RequestLog(_) => [true, false, false, true],
+      ButtplugClientMessage::SingleMotorVibrateCmd(_)
+      | ButtplugClientMessage::FleshlightLaunchFW12Cmd(_)
+      | ButtplugClientMessage::LovenseCmd(_)
+      | ButtplugClientMessage::KiirooCmd(_)
+      | ButtplugClientMessage::VorzeA10CycloneCmd(_) => [true, true, false, false],
+      ButtplugClientMessage::VibrateCmd(_)
+      | ButtplugClientMessage::LinearCmd(_)
+      | ButtplugClientMessage::RotateCmd(_) => [false, true, true, true],
+      ButtplugClientMessage::RawWriteCmd(_)
+      | ButtplugClientMessage::RawReadCmd(_)
+      | ButtplugClientMessage::RawSubscribeCmd(_)
+      | ButtplugClientMessage::RawUnsubscribeCmd(_)
+      | ButtplugClientMessage::BatteryLevelCmd(_)
+      | ButtplugClientMessage::RSSILevelCmd(_) => [false, false, true, true],
+      ButtplugClientMessage::ClaimDevice(_)
+      | ButtplugClientMessage::ReleaseDevice(_)
+      | ButtplugClientMessage::RequestServerTime(_)
+      | ButtplugClientMessage::OscillateCmd(_)
+      | ButtplugClientMessage::SensorSubscribeCmd(_)
+      | ButtplugClientMessage::SensorUnsubscribeCmd(_)
+      | ButtplugClientMessage::UploadPatternCmd(_)
+      | ButtplugClientMessage::RequestDeviceListChanges(_) => [false, false, false, true],
+    }
+  }
+
+  fn all_server_messages() -> Vec<ButtplugServerMessage> {
+    vec![
+      Ok::new(1).into(),
+      Error::new(ErrorCode::ErrorUnknown, "Test", None).into(),
+      Test::new("Test").into(),
+      Log::new(LogLevel::Info, "Test").into(),
+      ServerInfo::new("Test Server", ButtplugMessageSpecVersion::Version2, 0).into(),
+      DeviceList::new(vec![]).into(),
+      DeviceListChanges::new(1, false, vec![], vec![0]).into(),
+      DeviceAdded::new(0, "Test Device", &DeviceMessageAttributesMap::new()).into(),
+      DeviceRemoved::new(0).into(),
+      ScanningStarted::default().into(),
+      ScanningFinished::default().into(),
+      RawReading::new(0, crate::device::Endpoint::Rx, vec![0]).into(),
+      BatteryLevelReading::new(0, 0.5).into(),
+      RSSILevelReading::new(0, -40).into(),
+      SensorReading::new(0, 0, SensorType::Pressure, vec![0]).into(),
+      DeviceClaimed::new(0, "Test Client").into(),
+      DeviceReleased::new(0, "Test Client").into(),
+      PingTimeout::new(PingTimeoutPolicy::StopDevices).into(),
+      ServerTime::new(0).into(),
+      ScanningPartialFailure::new(vec![CommManagerScanningFailure::new("Test", "Test")]).into(),
+      DeviceInitializationFailed::new("Test Device", "Test", &["test".to_owned()], "Test").into(),
+    ]
+  }
+
+  fn all_client_messages() -> Vec<ButtplugClientMessage> {
+    vec![
+      RequestServerInfo::new("Test Client", ButtplugMessageSpecVersion::Version2).into(),
+      Ping::default().into(),
+      RequestLog::new(LogLevel::Info).into(),
+      StartScanning::default().into(),
+      StopScanning::default().into(),
+      RequestDeviceList::default().into(),
+      RequestDeviceListChanges::new(1).into(),
+      StopAllDevices::default().into(),
+      StopDeviceCmd::new(0).into(),
+      VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]).into(),
+      LinearCmd::new(0, vec![VectorSubcommand::new(0, 100, 0.5)]).into(),
+      RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.5, true)]).into(),
+      OscillateCmd::new(0, vec![OscillateSubcommand::new(0, 0.5)]).into(),
+      RawWriteCmd::new(0, crate::device::Endpoint::Tx, vec![0], false).into(),
+      RawReadCmd::new(0, crate::device::Endpoint::Rx, 0, 0).into(),
+      RawSubscribeCmd::new(0, crate::device::Endpoint::Rx).into(),
+      RawUnsubscribeCmd::new(0, crate::device::Endpoint::Rx).into(),
+      BatteryLevelCmd::new(0).into(),
+      RSSILevelCmd::new(0).into(),
+      SensorSubscribeCmd::new(0, 0).into(),
+      SensorUnsubscribeCmd::new(0, 0).into(),
+      UploadPatternCmd::new(0, vec![0]).into(),
+      ClaimDevice::new(0).into(),
+      ReleaseDevice::new(0).into(),
+      RequestServerTime::default().into(),
+      SingleMotorVibrateCmd::new(0, 0.5).into(),
+      FleshlightLaunchFW12Cmd::new(0, 50, 50).into(),
+      LovenseCmd::new(0, "Vibrate:20;").into(),
+      KiirooCmd::new(0, "1").into(),
+      VorzeA10CycloneCmd::new(0, 50, true).into(),
+    ]
+  }
+
+  #[test]
+  fn test_server_message_spec_downgrades() {
+    for msg in all_server_messages() {
+      let expected = server_message_spec_support(&msg);
+      let converted = [
+        ButtplugSpecV0ServerMessage::try_from(msg.clone()).is_ok(),
+        ButtplugSpecV1ServerMessage::try_from(msg.clone()).is_ok(),
+        ButtplugSpecV2ServerMessage::try_from(msg.clone()).is_ok(),
+        ButtplugSpecV3ServerMessage::try_from(msg.clone()).is_ok(),
+      ];
+      assert_eq!(expected, converted, "Unexpected conversion for {:?}", msg);
+    }
+  }
+
+  #[test]
+  fn test_client_message_spec_versions() {
+    for msg in all_client_messages() {
+      let expected = client_message_spec_support(&msg);
+      let converted = [
+        ButtplugSpecV0ClientMessage::try_from(msg.clone()).is_ok(),
+        ButtplugSpecV1ClientMessage::try_from(msg.clone()).is_ok(),
+        ButtplugSpecV2ClientMessage::try_from(msg.clone()).is_ok(),
+        ButtplugSpecV3ClientMessage::try_from(msg.clone()).is_ok(),
+      ];
+      assert_eq!(expected, converted, "Unexpected conversion for {:?}", msg);
+    }
+  }
 }
@@ -0,0 +1,67 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::*;
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+
+/// What a server does when a client misses its ping deadline.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub enum PingTimeoutPolicy {
+  /// Stop all devices, but leave the client connected. The ping timer starts
+  /// again on the client's next ping.
+  StopDevices,
+  /// Drop the client, but leave devices running.
+  DisconnectClient,
+  /// Stop all devices and drop the client.
+  #[default]
+  StopDevicesAndDisconnect,
+}
+
+impl PingTimeoutPolicy {
+  pub fn stops_devices(&self) -> bool {
+    matches!(
+      self,
+      PingTimeoutPolicy::StopDevices | PingTimeoutPolicy::StopDevicesAndDisconnect
+    )
+  }
+
+  pub fn disconnects_client(&self) -> bool {
+    matches!(
+      self,
+      PingTimeoutPolicy::DisconnectClient | PingTimeoutPolicy::StopDevicesAndDisconnect
+    )
+  }
+}
+
+/// Server event sent when a client misses its ping deadline, saying which
+/// [PingTimeoutPolicy] the server applied.
+#[derive(Debug, ButtplugMessage, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct PingTimeout {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
+  id: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Policy"))]
+  policy: PingTimeoutPolicy,
+}
+
+impl PingTimeout {
+  pub fn new(policy: PingTimeoutPolicy) -> Self {
+    Self { id: 0, policy }
+  }
+
+  pub fn policy(&self) -> PingTimeoutPolicy {
+    self.policy
+  }
+}
+
+impl ButtplugMessageValidator for PingTimeout {
+  fn is_valid(&self) -> Result<(), ButtplugMessageError> {
+    self.is_system_id(self.id)
+  }
+}
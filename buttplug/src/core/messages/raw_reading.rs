@@ -23,6 +23,17 @@ pub struct RawReading {
   endpoint: Endpoint,
   #[cfg_attr(feature = "serialize-json", serde(rename = "Data"))]
   data: Vec<u8>,
+  // Only set on readings emitted from endpoint subscriptions, so clients can
+  // detect dropped notifications. Left off of RawReadCmd replies.
+  #[cfg_attr(
+    feature = "serialize-json",
+    serde(
+      rename = "Sequence",
+      skip_serializing_if = "Option::is_none",
+      default
+    )
+  )]
+  sequence: Option<u32>,
 }
 
 impl RawReading {
@@ -32,6 +43,7 @@ impl RawReading {
       device_index,
       endpoint,
       data,
+      sequence: None,
     }
   }
 
@@ -42,6 +54,14 @@ impl RawReading {
   pub fn data(&self) -> &Vec<u8> {
     &self.data
   }
+
+  pub fn sequence(&self) -> Option<u32> {
+    self.sequence
+  }
+
+  pub fn set_sequence(&mut self, sequence: Option<u32>) {
+    self.sequence = sequence;
+  }
 }
 
 #[cfg(feature = "serialize-json")]
@@ -71,4 +91,22 @@ mod test {
       "{\"RawReading\":{\"Id\":1,\"DeviceIndex\":0,\"Endpoint\":\"tx\",\"Data\":[0]}}";
     assert_eq!(js, endpoint_str);
   }
+
+  #[test]
+  fn test_sequence_serialize() {
+    let mut reading = RawReading::new(0, Endpoint::Rx, vec![0]);
+    reading.set_sequence(Some(3));
+    let union = ButtplugCurrentSpecServerMessage::RawReading(reading.clone());
+    let js = serde_json::to_string(&union).expect("Infallible serialization.");
+    assert_eq!(
+      js,
+      "{\"RawReading\":{\"Id\":1,\"DeviceIndex\":0,\"Endpoint\":\"rx\",\"Data\":[0],\"Sequence\":3}}"
+    );
+    let deserialized: ButtplugCurrentSpecServerMessage =
+      serde_json::from_str(&js).expect("Infallible deserialization.");
+    assert_eq!(
+      deserialized,
+      ButtplugCurrentSpecServerMessage::RawReading(reading)
+    );
+  }
 }
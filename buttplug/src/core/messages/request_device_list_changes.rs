@@ -0,0 +1,39 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::*;
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+
+/// Asks the server for the device list changes since `revision`, answered
+/// with [DeviceListChanges]. Revision 0 asks for the whole list. Lets clients
+/// with many devices keep their list up to date without being sent every
+/// device's message attributes each time.
+#[derive(Debug, ButtplugMessage, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct RequestDeviceListChanges {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
+  id: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Revision"))]
+  revision: u32,
+}
+
+impl RequestDeviceListChanges {
+  pub fn new(revision: u32) -> Self {
+    Self { id: 1, revision }
+  }
+
+  pub fn revision(&self) -> u32 {
+    self.revision
+  }
+}
+
+impl ButtplugMessageValidator for RequestDeviceListChanges {
+  fn is_valid(&self) -> Result<(), ButtplugMessageError> {
+    self.is_not_system_id(self.id)
+  }
+}
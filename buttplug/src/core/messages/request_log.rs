@@ -22,6 +22,10 @@ impl RequestLog {
   pub fn new(log_level: LogLevel) -> Self {
     Self { id: 1, log_level }
   }
+
+  pub fn log_level(&self) -> LogLevel {
+    self.log_level.clone()
+  }
 }
 
 impl ButtplugMessageValidator for RequestLog {
@@ -0,0 +1,57 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::*;
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+
+/// A device communication manager that failed to start or stop scanning.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct CommManagerScanningFailure {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "CommManager"))]
+  pub comm_manager: String,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Error"))]
+  pub error: String,
+}
+
+impl CommManagerScanningFailure {
+  pub fn new(comm_manager: &str, error: &str) -> Self {
+    Self {
+      comm_manager: comm_manager.to_owned(),
+      error: error.to_owned(),
+    }
+  }
+}
+
+/// Server event sent when some, but not all, device communication managers
+/// fail to start or stop scanning. If they all fail, the
+/// [StartScanning]/[StopScanning] request gets an error instead.
+#[derive(Debug, ButtplugMessage, PartialEq, Clone)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct ScanningPartialFailure {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
+  id: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Failures"))]
+  failures: Vec<CommManagerScanningFailure>,
+}
+
+impl ScanningPartialFailure {
+  pub fn new(failures: Vec<CommManagerScanningFailure>) -> Self {
+    Self { id: 0, failures }
+  }
+
+  pub fn failures(&self) -> &Vec<CommManagerScanningFailure> {
+    &self.failures
+  }
+}
+
+impl ButtplugMessageValidator for ScanningPartialFailure {
+  fn is_valid(&self) -> Result<(), ButtplugMessageError> {
+    self.is_system_id(self.id)
+  }
+}
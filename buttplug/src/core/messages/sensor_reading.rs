@@ -0,0 +1,87 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::*;
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+
+/// Server event carrying values from a sensor subscribed to via
+/// [SensorSubscribeCmd]. How many values there are, and what range they're in,
+/// depends on the sensor.
+#[derive(Debug, ButtplugDeviceMessage, PartialEq, Clone)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct SensorReading {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
+  id: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceIndex"))]
+  device_index: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "SensorIndex"))]
+  sensor_index: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "SensorType"))]
+  sensor_type: SensorType,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Data"))]
+  data: Vec<i32>,
+}
+
+impl SensorReading {
+  pub fn new(
+    device_index: u32,
+    sensor_index: u32,
+    sensor_type: SensorType,
+    data: Vec<i32>,
+  ) -> Self {
+    Self {
+      id: 0,
+      device_index,
+      sensor_index,
+      sensor_type,
+      data,
+    }
+  }
+
+  pub fn sensor_index(&self) -> u32 {
+    self.sensor_index
+  }
+
+  pub fn sensor_type(&self) -> SensorType {
+    self.sensor_type
+  }
+
+  pub fn data(&self) -> &Vec<i32> {
+    &self.data
+  }
+}
+
+impl ButtplugMessageValidator for SensorReading {
+  fn is_valid(&self) -> Result<(), ButtplugMessageError> {
+    self.is_system_id(self.id)
+  }
+}
+
+#[cfg(feature = "serialize-json")]
+#[cfg(test)]
+mod test {
+  use crate::core::messages::{ButtplugCurrentSpecServerMessage, SensorReading, SensorType};
+
+  #[test]
+  fn test_sensor_reading_serialize() {
+    let union = ButtplugCurrentSpecServerMessage::SensorReading(SensorReading::new(
+      0,
+      0,
+      SensorType::Pressure,
+      vec![10, 20],
+    ));
+    let js = serde_json::to_string(&union).expect("Infallible serialization.");
+    assert_eq!(
+      js,
+      "{\"SensorReading\":{\"Id\":0,\"DeviceIndex\":0,\"SensorIndex\":0,\"SensorType\":\"Pressure\",\"Data\":[10,20]}}"
+    );
+    let deserialized: ButtplugCurrentSpecServerMessage =
+      serde_json::from_str(&js).expect("Infallible deserialization.");
+    assert_eq!(deserialized, union);
+  }
+}
@@ -1,7 +1,7 @@
 use super::{ButtplugMessageSerializer, ButtplugSerializedMessage, ButtplugSerializerError};
 use crate::{
   core::{
-    errors::{ButtplugError, ButtplugHandshakeError},
+    errors::{ButtplugError, ButtplugHandshakeError, ButtplugMessageError},
     messages::{
       self,
       ButtplugClientMessage,
@@ -9,6 +9,7 @@ use crate::{
       ButtplugCurrentSpecServerMessage,
       ButtplugMessage,
       ButtplugMessageSpecVersion,
+      ButtplugMessageValidator,
       ButtplugServerMessage,
       ButtplugSpecV0ClientMessage,
       ButtplugSpecV0ServerMessage,
@@ -16,6 +17,8 @@ use crate::{
       ButtplugSpecV1ServerMessage,
       ButtplugSpecV2ClientMessage,
       ButtplugSpecV2ServerMessage,
+      ButtplugSpecV3ClientMessage,
+      ButtplugSpecV3ServerMessage,
     },
   },
   util::json::JSONValidator,
@@ -31,16 +34,49 @@ static MESSAGE_JSON_SCHEMA: &str =
 pub fn create_message_validator() -> JSONValidator {
   JSONValidator::new(MESSAGE_JSON_SCHEMA)
 }
+
 pub struct ButtplugServerJSONSerializer {
   pub(super) message_version: RefCell<Option<messages::ButtplugMessageSpecVersion>>,
-  validator: JSONValidator,
+  /// Only compiled if schema validation is on, as compiling the schema is far
+  /// slower than validating a message.
+  validator: Option<JSONValidator>,
 }
 
 impl Default for ButtplugServerJSONSerializer {
+  fn default() -> Self {
+    ButtplugServerJSONSerializerBuilder::default().finish()
+  }
+}
+
+/// Builds a [ButtplugServerJSONSerializer], for when the defaults won't do.
+#[derive(Clone, Debug)]
+pub struct ButtplugServerJSONSerializerBuilder {
+  validate_schema: bool,
+}
+
+impl Default for ButtplugServerJSONSerializerBuilder {
   fn default() -> Self {
     Self {
+      validate_schema: true,
+    }
+  }
+}
+
+impl ButtplugServerJSONSerializerBuilder {
+  /// Sets whether incoming messages are checked against the message schema
+  /// before being deserialized. On by default. Messages that don't match
+  /// what the library expects will still fail to deserialize with validation
+  /// off, but out of range values and other things only the schema knows
+  /// about will get through, so only turn this off for trusted clients.
+  pub fn validate_schema(mut self, validate_schema: bool) -> Self {
+    self.validate_schema = validate_schema;
+    self
+  }
+
+  pub fn finish(self) -> ButtplugServerJSONSerializer {
+    ButtplugServerJSONSerializer {
       message_version: RefCell::new(None),
-      validator: create_message_validator(),
+      validator: self.validate_schema.then(create_message_validator),
     }
   }
 }
@@ -60,20 +96,34 @@ where
   serde_json::to_string(&msg).expect("Infallible serialization")
 }
 
+/// Deserializes a message array, checking it against the schema first if
+/// `validator` is set. Takes the incoming bytes as is, so callers don't need to
+/// build a String out of them first. The messages still own copies of their
+/// strings, so this isn't zero-copy.
 fn deserialize_to_message<T>(
-  validator: &JSONValidator,
-  msg: String,
+  validator: Option<&JSONValidator>,
+  msg: &[u8],
 ) -> Result<Vec<T>, ButtplugSerializerError>
 where
-  T: serde::de::DeserializeOwned + Clone,
+  T: serde::de::DeserializeOwned,
 {
   // We have to pass back a string formatted error, as SerdeJson's error type
   // isn't clonable.
-  validator.validate(&msg).and_then(|_| {
-    serde_json::from_str::<Vec<T>>(&msg).map_err(|e| {
-      ButtplugSerializerError::JsonSerializerError(format!("Message: {} - Error: {:?}", msg, e))
-    })
-  })
+  let to_serializer_error = |e: serde_json::Error| {
+    ButtplugSerializerError::JsonSerializerError(format!(
+      "Message: {} - Error: {:?}",
+      String::from_utf8_lossy(msg),
+      e
+    ))
+  };
+  if let Some(validator) = validator {
+    let value = serde_json::from_slice::<serde_json::Value>(msg).map_err(to_serializer_error)?;
+    validator.validate_value(&value)?;
+  }
+  // With validation on, this parses the bytes a second time. Converting the
+  // already parsed value instead would have to copy every string and map key
+  // out of it anyways.
+  serde_json::from_slice::<Vec<T>>(msg).map_err(to_serializer_error)
 }
 
 fn serialize_to_version(
@@ -83,8 +133,7 @@ fn serialize_to_version(
   ButtplugSerializedMessage::Text(match version {
     ButtplugMessageSpecVersion::Version0 => {
       let msg_vec: Vec<ButtplugSpecV0ServerMessage> = msgs
-        .iter()
-        .cloned()
+        .into_iter()
         .map(|msg| match ButtplugSpecV0ServerMessage::try_from(msg) {
           Ok(msgv0) => msgv0,
           Err(err) => ButtplugSpecV0ServerMessage::Error(
@@ -96,8 +145,7 @@ fn serialize_to_version(
     }
     ButtplugMessageSpecVersion::Version1 => {
       let msg_vec: Vec<ButtplugSpecV1ServerMessage> = msgs
-        .iter()
-        .cloned()
+        .into_iter()
         .map(|msg| match ButtplugSpecV1ServerMessage::try_from(msg) {
           Ok(msgv0) => msgv0,
           Err(err) => ButtplugSpecV1ServerMessage::Error(
@@ -109,60 +157,167 @@ fn serialize_to_version(
     }
     ButtplugMessageSpecVersion::Version2 => {
       let msg_vec: Vec<ButtplugSpecV2ServerMessage> = msgs
-        .iter()
-        .cloned()
+        .into_iter()
         .map(|msg| match ButtplugSpecV2ServerMessage::try_from(msg) {
-          Ok(msgv0) => msgv0,
+          Ok(msgv0) => strip_spec_v3_fields(msgv0),
           Err(err) => ButtplugSpecV2ServerMessage::Error(ButtplugError::from(err).into()),
         })
         .collect();
       vec_to_protocol_json(msg_vec)
     }
+    ButtplugMessageSpecVersion::Version3 => {
+      let msg_vec: Vec<ButtplugSpecV3ServerMessage> = msgs
+        .into_iter()
+        .map(|msg| match ButtplugSpecV3ServerMessage::try_from(msg) {
+          Ok(msgv3) => msgv3,
+          Err(err) => ButtplugSpecV3ServerMessage::Error(ButtplugError::from(err).into()),
+        })
+        .collect();
+      vec_to_protocol_json(msg_vec)
+    }
   })
 }
 
+/// Device messages added in spec v3, which v2 clients don't know about.
+const SPEC_V3_DEVICE_MESSAGE_TYPES: [messages::ButtplugDeviceMessageType; 4] = [
+  messages::ButtplugDeviceMessageType::OscillateCmd,
+  messages::ButtplugDeviceMessageType::SensorSubscribeCmd,
+  messages::ButtplugDeviceMessageType::SensorUnsubscribeCmd,
+  messages::ButtplugDeviceMessageType::UploadPatternCmd,
+];
+
+/// Device addresses, feature descriptors, RawReading sequence numbers and some
+/// device messages were added in spec v3, and v2 clients check messages
+/// against a schema that doesn't allow them.
+fn strip_spec_v3_fields(msg: ButtplugSpecV2ServerMessage) -> ButtplugSpecV2ServerMessage {
+  match msg {
+    ButtplugSpecV2ServerMessage::DeviceAdded(msg) => {
+      let mut stripped = messages::DeviceAdded::new(
+        msg.device_index(),
+        msg.device_name(),
+        without_spec_v3_attributes(msg.shared_device_messages()),
+      );
+      stripped.set_id(msg.id());
+      ButtplugSpecV2ServerMessage::DeviceAdded(stripped)
+    }
+    ButtplugSpecV2ServerMessage::DeviceList(msg) => {
+      let mut stripped = messages::DeviceList::new(without_spec_v3_device_info(msg.devices()));
+      stripped.set_id(msg.id());
+      ButtplugSpecV2ServerMessage::DeviceList(stripped)
+    }
+    ButtplugSpecV2ServerMessage::RawReading(mut msg) => {
+      msg.set_sequence(None);
+      ButtplugSpecV2ServerMessage::RawReading(msg)
+    }
+    msg => msg,
+  }
+}
+
+fn without_spec_v3_device_info(
+  devices: &[messages::DeviceMessageInfo],
+) -> Vec<messages::DeviceMessageInfo> {
+  devices
+    .iter()
+    .cloned()
+    .map(|mut info| {
+      info.device_address = None;
+      info.device_messages = without_spec_v3_attributes(&info.device_messages);
+      info
+    })
+    .collect()
+}
+
+/// Only copies the attribute map if there's something to remove from it.
+fn without_spec_v3_attributes(
+  device_messages: &messages::SharedDeviceMessageAttributesMap,
+) -> messages::SharedDeviceMessageAttributesMap {
+  if !SPEC_V3_DEVICE_MESSAGE_TYPES
+    .iter()
+    .any(|message_type| device_messages.contains_key(message_type))
+    && device_messages
+      .values()
+      .all(|attrs| attrs.feature_descriptors.is_none())
+  {
+    return device_messages.clone();
+  }
+  let mut stripped = device_messages.clone().into_map();
+  for message_type in &SPEC_V3_DEVICE_MESSAGE_TYPES {
+    stripped.remove(message_type);
+  }
+  for attrs in stripped.values_mut() {
+    attrs.feature_descriptors = None;
+  }
+  stripped.into()
+}
+
+/// True if the message uses fields added in spec v3 to a message that older
+/// versions also have. Serde and the schema would let these through from older
+/// clients, as they're shared by every version.
+fn uses_spec_v3_fields(msg: &ButtplugClientMessage) -> bool {
+  matches!(msg, ButtplugClientMessage::LinearCmd(msg) if msg.sent_at().is_some())
+}
+
+fn reject_spec_v3_fields(
+  msgs: Vec<ButtplugClientMessage>,
+) -> Result<Vec<ButtplugClientMessage>, ButtplugSerializerError> {
+  match msgs.iter().find(|msg| uses_spec_v3_fields(msg)) {
+    Some(msg) => Err(ButtplugSerializerError::JsonSerializerError(format!(
+      "Message uses fields only available in spec v3: {:?}",
+      msg
+    ))),
+    None => Ok(msgs),
+  }
+}
+
 unsafe impl Sync for ButtplugServerJSONSerializer {
 }
 unsafe impl Send for ButtplugServerJSONSerializer {
 }
 
-impl ButtplugMessageSerializer for ButtplugServerJSONSerializer {
-  type Inbound = ButtplugClientMessage;
-  type Outbound = ButtplugServerMessage;
+impl ButtplugServerJSONSerializer {
+  /// Deserializes client messages from a string, without taking ownership of
+  /// it. Same as [ButtplugMessageSerializer::deserialize] for text messages.
+  pub fn deserialize_str(
+    &self,
+    msg: &str,
+  ) -> Result<Vec<ButtplugClientMessage>, ButtplugSerializerError> {
+    self.deserialize_bytes(msg.as_bytes())
+  }
 
-  fn deserialize(
+  /// Deserializes client messages from UTF-8 encoded JSON bytes, i.e. a
+  /// buffer read straight off of a socket, without building a String out of
+  /// it first.
+  pub fn deserialize_bytes(
     &self,
-    serialized_msg: ButtplugSerializedMessage,
+    msg: &[u8],
   ) -> Result<Vec<ButtplugClientMessage>, ButtplugSerializerError> {
-    let msg = if let ButtplugSerializedMessage::Text(text_msg) = serialized_msg {
-      text_msg
-    } else {
-      return Err(ButtplugSerializerError::BinaryDeserializationError);
-    };
     // If we don't have a message version yet, we need to parse this as a
     // RequestServerInfo message to get the version. RequestServerInfo can
     // always be parsed as the latest message version, as we keep it
     // compatible across versions via serde options.
     if let Some(version) = *self.message_version.borrow() {
       return Ok(match version {
-        ButtplugMessageSpecVersion::Version0 => {
-          deserialize_to_message::<ButtplugSpecV0ClientMessage>(&self.validator, msg)?
-            .iter()
-            .cloned()
+        ButtplugMessageSpecVersion::Version0 => reject_spec_v3_fields(
+          deserialize_to_message::<ButtplugSpecV0ClientMessage>(self.validator.as_ref(), msg)?
+            .into_iter()
             .map(|m| m.into())
-            .collect()
-        }
-        ButtplugMessageSpecVersion::Version1 => {
-          deserialize_to_message::<ButtplugSpecV1ClientMessage>(&self.validator, msg)?
-            .iter()
-            .cloned()
+            .collect(),
+        )?,
+        ButtplugMessageSpecVersion::Version1 => reject_spec_v3_fields(
+          deserialize_to_message::<ButtplugSpecV1ClientMessage>(self.validator.as_ref(), msg)?
+            .into_iter()
             .map(|m| m.into())
-            .collect()
-        }
-        ButtplugMessageSpecVersion::Version2 => {
-          deserialize_to_message::<ButtplugSpecV2ClientMessage>(&self.validator, msg)?
-            .iter()
-            .cloned()
+            .collect(),
+        )?,
+        ButtplugMessageSpecVersion::Version2 => reject_spec_v3_fields(
+          deserialize_to_message::<ButtplugSpecV2ClientMessage>(self.validator.as_ref(), msg)?
+            .into_iter()
+            .map(|m| m.into())
+            .collect(),
+        )?,
+        ButtplugMessageSpecVersion::Version3 => {
+          deserialize_to_message::<ButtplugSpecV3ClientMessage>(self.validator.as_ref(), msg)?
+            .into_iter()
             .map(|m| m.into())
             .collect()
         }
@@ -170,7 +325,8 @@ impl ButtplugMessageSerializer for ButtplugServerJSONSerializer {
     }
     // instead of using if/else here, return in the if, which drops the borrow.
     // so we can possibly mutate it now.
-    let msg_union = deserialize_to_message::<ButtplugSpecV2ClientMessage>(&self.validator, msg)?;
+    let msg_union =
+      deserialize_to_message::<ButtplugSpecV2ClientMessage>(self.validator.as_ref(), msg)?;
     // If the message is malformed, just return an spec version not received error.
     if msg_union.is_empty() {
       return Err(ButtplugSerializerError::MessageSpecVersionNotReceived);
@@ -184,7 +340,23 @@ impl ButtplugMessageSerializer for ButtplugServerJSONSerializer {
     } else {
       return Err(ButtplugSerializerError::MessageSpecVersionNotReceived);
     }
-    Ok(msg_union.iter().cloned().map(|m| m.into()).collect())
+    reject_spec_v3_fields(msg_union.into_iter().map(|m| m.into()).collect())
+  }
+}
+
+impl ButtplugMessageSerializer for ButtplugServerJSONSerializer {
+  type Inbound = ButtplugClientMessage;
+  type Outbound = ButtplugServerMessage;
+
+  fn deserialize(
+    &self,
+    serialized_msg: ButtplugSerializedMessage,
+  ) -> Result<Vec<ButtplugClientMessage>, ButtplugSerializerError> {
+    if let ButtplugSerializedMessage::Text(text_msg) = serialized_msg {
+      self.deserialize_str(&text_msg)
+    } else {
+      Err(ButtplugSerializerError::BinaryDeserializationError)
+    }
   }
 
   fn serialize(&self, msgs: Vec<ButtplugServerMessage>) -> ButtplugSerializedMessage {
@@ -209,14 +381,169 @@ impl ButtplugMessageSerializer for ButtplugServerJSONSerializer {
   }
 }
 
+/// Result of checking a single message in a payload passed to
+/// [validate_client_message_payload].
+#[derive(Debug, Clone)]
+pub struct MessageValidationResult {
+  /// The message, converted to the library's internal message format.
+  pub message: ButtplugClientMessage,
+  /// Set if the message parsed, but its contents are invalid (system ids on
+  /// client messages, out of range values, etc...).
+  pub validation_error: Option<ButtplugMessageError>,
+  /// Every message spec version that can represent this message.
+  pub compatible_versions: Vec<ButtplugMessageSpecVersion>,
+}
+
+/// Report returned by [validate_client_message_payload].
+#[derive(Debug, Clone)]
+pub struct MessageValidationReport {
+  /// Spec version the payload was checked against.
+  pub spec_version: ButtplugMessageSpecVersion,
+  /// Set if the payload failed schema validation, or couldn't be parsed as
+  /// messages of the spec version. If this is set, there will be no message
+  /// results.
+  pub parse_error: Option<ButtplugSerializerError>,
+  /// Results for each message in the payload, in order.
+  pub messages: Vec<MessageValidationResult>,
+}
+
+impl MessageValidationReport {
+  /// True if the payload parsed, and every message in it is valid.
+  pub fn is_valid(&self) -> bool {
+    self.parse_error.is_none()
+      && self
+        .messages
+        .iter()
+        .all(|result| result.validation_error.is_none())
+  }
+}
+
+fn deserialize_to_client_message<T>(
+  validator: Option<&JSONValidator>,
+  msg: &str,
+) -> Result<Vec<ButtplugClientMessage>, ButtplugSerializerError>
+where
+  T: serde::de::DeserializeOwned + Into<ButtplugClientMessage>,
+{
+  Ok(
+    deserialize_to_message::<T>(validator, msg.as_bytes())?
+      .into_iter()
+      .map(|m| m.into())
+      .collect(),
+  )
+}
+
+fn compatible_client_message_versions(
+  msg: &ButtplugClientMessage,
+) -> Vec<ButtplugMessageSpecVersion> {
+  [
+    (
+      ButtplugMessageSpecVersion::Version0,
+      ButtplugSpecV0ClientMessage::try_from(msg.clone()).is_ok() && !uses_spec_v3_fields(msg),
+    ),
+    (
+      ButtplugMessageSpecVersion::Version1,
+      ButtplugSpecV1ClientMessage::try_from(msg.clone()).is_ok() && !uses_spec_v3_fields(msg),
+    ),
+    (
+      ButtplugMessageSpecVersion::Version2,
+      ButtplugSpecV2ClientMessage::try_from(msg.clone()).is_ok() && !uses_spec_v3_fields(msg),
+    ),
+    (
+      ButtplugMessageSpecVersion::Version3,
+      ButtplugSpecV3ClientMessage::try_from(msg.clone()).is_ok(),
+    ),
+  ]
+  .iter()
+  .filter(|(_, compatible)| *compatible)
+  .map(|(version, _)| *version)
+  .collect()
+}
+
+/// Checks a serialized client message payload the same way a server using the
+/// given spec version would, without needing a server or connection. Meant for
+/// debugging tools, and for client developers testing their wire output
+/// against the reference implementation.
+pub fn validate_client_message_payload(
+  payload: &str,
+  spec_version: ButtplugMessageSpecVersion,
+) -> MessageValidationReport {
+  let validator = create_message_validator();
+  let parsed = match spec_version {
+    ButtplugMessageSpecVersion::Version0 => {
+      deserialize_to_client_message::<ButtplugSpecV0ClientMessage>(Some(&validator), payload)
+        .and_then(reject_spec_v3_fields)
+    }
+    ButtplugMessageSpecVersion::Version1 => {
+      deserialize_to_client_message::<ButtplugSpecV1ClientMessage>(Some(&validator), payload)
+        .and_then(reject_spec_v3_fields)
+    }
+    ButtplugMessageSpecVersion::Version2 => {
+      deserialize_to_client_message::<ButtplugSpecV2ClientMessage>(Some(&validator), payload)
+        .and_then(reject_spec_v3_fields)
+    }
+    ButtplugMessageSpecVersion::Version3 => {
+      deserialize_to_client_message::<ButtplugSpecV3ClientMessage>(Some(&validator), payload)
+    }
+  };
+  match parsed {
+    Ok(msgs) => MessageValidationReport {
+      spec_version,
+      parse_error: None,
+      messages: msgs
+        .into_iter()
+        .map(|message| MessageValidationResult {
+          validation_error: message.is_valid().err(),
+          compatible_versions: compatible_client_message_versions(&message),
+          message,
+        })
+        .collect(),
+    },
+    Err(err) => MessageValidationReport {
+      spec_version,
+      parse_error: Some(err),
+      messages: vec![],
+    },
+  }
+}
+
 pub struct ButtplugClientJSONSerializer {
-  validator: JSONValidator,
+  validator: Option<JSONValidator>,
 }
 
 impl Default for ButtplugClientJSONSerializer {
+  fn default() -> Self {
+    ButtplugClientJSONSerializerBuilder::default().finish()
+  }
+}
+
+/// Builds a [ButtplugClientJSONSerializer], for when the defaults won't do.
+#[derive(Clone, Debug)]
+pub struct ButtplugClientJSONSerializerBuilder {
+  validate_schema: bool,
+}
+
+impl Default for ButtplugClientJSONSerializerBuilder {
   fn default() -> Self {
     Self {
-      validator: create_message_validator(),
+      validate_schema: true,
+    }
+  }
+}
+
+impl ButtplugClientJSONSerializerBuilder {
+  /// Sets whether incoming messages are checked against the message schema
+  /// before being deserialized. On by default. See
+  /// [ButtplugServerJSONSerializerBuilder::validate_schema] for what turning
+  /// this off lets through.
+  pub fn validate_schema(mut self, validate_schema: bool) -> Self {
+    self.validate_schema = validate_schema;
+    self
+  }
+
+  pub fn finish(self) -> ButtplugClientJSONSerializer {
+    ButtplugClientJSONSerializer {
+      validator: self.validate_schema.then(create_message_validator),
     }
   }
 }
@@ -235,7 +562,7 @@ impl ButtplugMessageSerializer for ButtplugClientJSONSerializer {
     msg: ButtplugSerializedMessage,
   ) -> Result<Vec<ButtplugCurrentSpecServerMessage>, ButtplugSerializerError> {
     if let ButtplugSerializedMessage::Text(text_msg) = msg {
-      deserialize_to_message::<Self::Inbound>(&self.validator, text_msg)
+      deserialize_to_message::<Self::Inbound>(self.validator.as_ref(), text_msg.as_bytes())
     } else {
       Err(ButtplugSerializerError::BinaryDeserializationError)
     }
@@ -284,6 +611,55 @@ mod test {
     assert!(msg.is_err());
   }
 
+  #[test]
+  fn test_deserialize_bytes() {
+    let json = r#"[{
+            "RequestServerInfo": {
+                "Id": 1,
+                "ClientName": "Test Client",
+                "MessageVersion": 2
+            }
+        }]"#;
+    let serializer = ButtplugServerJSONSerializer::default();
+    let msgs = serializer
+      .deserialize_bytes(json.as_bytes())
+      .expect("Infallible deserialization");
+    assert_eq!(
+      msgs,
+      vec![RequestServerInfo::new("Test Client", ButtplugMessageSpecVersion::Version2).into()]
+    );
+    assert!(serializer.deserialize_bytes(b"[{\"Ping\"").is_err());
+  }
+
+  #[test]
+  fn test_server_schema_validation_toggle() {
+    let handshake = r#"[{
+            "RequestServerInfo": {
+                "Id": 1,
+                "ClientName": "Test Client",
+                "MessageVersion": 2
+            }
+        }]"#;
+    // Speed is out of range, which only the schema checks for.
+    let json = r#"[{
+            "VibrateCmd": {
+                "Id": 2,
+                "DeviceIndex": 0,
+                "Speeds": [{ "Index": 0, "Speed": 1.5 }]
+            }
+        }]"#;
+    for (validate_schema, expect_ok) in [(true, false), (false, true)] {
+      let serializer = ButtplugServerJSONSerializerBuilder::default()
+        .validate_schema(validate_schema)
+        .finish();
+      serializer
+        .deserialize(ButtplugSerializedMessage::Text(handshake.to_owned()))
+        .expect("Infallible deserialization");
+      let msg = serializer.deserialize(ButtplugSerializedMessage::Text(json.to_owned()));
+      assert_eq!(msg.is_ok(), expect_ok);
+    }
+  }
+
   #[test]
   fn test_client_incorrect_messages() {
     let incorrect_incoming_messages = vec![
@@ -321,4 +697,184 @@ mod test {
       }
     }
   }
+
+  #[test]
+  fn test_validate_client_message_payload() {
+    let json = r#"[{
+            "VibrateCmd": {
+                "Id": 1,
+                "DeviceIndex": 0,
+                "Speeds": [{ "Index": 0, "Speed": 0.5 }]
+            }
+        }]"#;
+    let report = validate_client_message_payload(json, ButtplugMessageSpecVersion::Version2);
+    assert!(report.is_valid());
+    assert_eq!(report.messages.len(), 1);
+    assert_eq!(
+      report.messages[0].compatible_versions,
+      vec![
+        ButtplugMessageSpecVersion::Version1,
+        ButtplugMessageSpecVersion::Version2,
+        ButtplugMessageSpecVersion::Version3
+      ]
+    );
+
+    // VibrateCmd doesn't exist in spec v0.
+    let report = validate_client_message_payload(json, ButtplugMessageSpecVersion::Version0);
+    assert!(!report.is_valid());
+    assert!(report.parse_error.is_some());
+    assert!(report.messages.is_empty());
+  }
+
+  #[test]
+  fn test_validate_client_message_payload_invalid_contents() {
+    // Parses fine, but client messages can't use the system id.
+    let json = r#"[{"StartScanning": { "Id": 0 }}, {"StopScanning": { "Id": 1 }}]"#;
+    let report = validate_client_message_payload(json, ButtplugMessageSpecVersion::Version2);
+    assert!(!report.is_valid());
+    assert!(report.parse_error.is_none());
+    assert!(report.messages[0].validation_error.is_some());
+    assert!(report.messages[1].validation_error.is_none());
+  }
+
+  #[test]
+  fn test_validate_client_message_payload_downgrade() {
+    let json = r#"[{
+            "SingleMotorVibrateCmd": {
+                "Id": 1,
+                "DeviceIndex": 0,
+                "Speed": 0.5
+            }
+        }]"#;
+    let report = validate_client_message_payload(json, ButtplugMessageSpecVersion::Version1);
+    assert!(report.is_valid());
+    assert_eq!(
+      report.messages[0].compatible_versions,
+      vec![
+        ButtplugMessageSpecVersion::Version0,
+        ButtplugMessageSpecVersion::Version1
+      ]
+    );
+  }
+
+  #[test]
+  fn test_device_address_only_in_v3() {
+    let mut device_added = messages::DeviceAdded::new(0, "Test Device", &Default::default());
+    device_added.set_device_address(Some("test-address".to_owned()));
+    for (version, expect_address) in [
+      (ButtplugMessageSpecVersion::Version2, false),
+      (ButtplugMessageSpecVersion::Version3, true),
+    ] {
+      let serializer = ButtplugServerJSONSerializer::default();
+      serializer.message_version.replace(Some(version));
+      let json = match serializer.serialize(vec![device_added.clone().into()]) {
+        ButtplugSerializedMessage::Text(json) => json,
+        ButtplugSerializedMessage::Binary(_) => unreachable!("JSON serializer only outputs text."),
+      };
+      assert_eq!(json.contains("test-address"), expect_address);
+      assert!(create_message_validator().validate(&json).is_ok());
+    }
+  }
+
+  #[test]
+  fn test_raw_reading_sequence_only_in_v3() {
+    let mut raw_reading = messages::RawReading::new(0, crate::device::Endpoint::Rx, vec![0]);
+    raw_reading.set_sequence(Some(5));
+    for (version, expect_sequence) in [
+      (ButtplugMessageSpecVersion::Version2, false),
+      (ButtplugMessageSpecVersion::Version3, true),
+    ] {
+      let serializer = ButtplugServerJSONSerializer::default();
+      serializer.message_version.replace(Some(version));
+      let json = match serializer.serialize(vec![raw_reading.clone().into()]) {
+        ButtplugSerializedMessage::Text(json) => json,
+        ButtplugSerializedMessage::Binary(_) => unreachable!("JSON serializer only outputs text."),
+      };
+      assert_eq!(json.contains("Sequence"), expect_sequence);
+    }
+  }
+
+  #[test]
+  fn test_feature_descriptors_only_in_v3() {
+    let mut device_messages = messages::DeviceMessageAttributesMap::new();
+    device_messages.insert(
+      messages::ButtplugDeviceMessageType::VibrateCmd,
+      messages::DeviceMessageAttributes {
+        feature_descriptors: Some(vec![messages::FeatureDescriptor {
+          descriptor: Some("Test Vibrator".to_owned()),
+          ..Default::default()
+        }]),
+        ..messages::DeviceMessageAttributes::with_step_count(vec![20])
+      },
+    );
+    let device_added = messages::DeviceAdded::new(0, "Test Device", device_messages);
+    let device_list = messages::DeviceList::new(vec![device_added.clone().into()]);
+    for (version, expect_descriptors) in [
+      (ButtplugMessageSpecVersion::Version2, false),
+      (ButtplugMessageSpecVersion::Version3, true),
+    ] {
+      let serializer = ButtplugServerJSONSerializer::default();
+      serializer.message_version.replace(Some(version));
+      for msg in [device_added.clone().into(), device_list.clone().into()] {
+        let json = match serializer.serialize(vec![msg]) {
+          ButtplugSerializedMessage::Text(json) => json,
+          ButtplugSerializedMessage::Binary(_) => {
+            unreachable!("JSON serializer only outputs text.")
+          }
+        };
+        assert!(json.contains("StepCount"));
+        assert_eq!(json.contains("FeatureDescriptors"), expect_descriptors);
+        assert_eq!(json.contains("Test Vibrator"), expect_descriptors);
+      }
+    }
+  }
+
+  #[test]
+  fn test_spec_v3_device_messages_only_in_v3() {
+    let mut device_messages = messages::DeviceMessageAttributesMap::new();
+    for message_type in [
+      messages::ButtplugDeviceMessageType::VibrateCmd,
+      messages::ButtplugDeviceMessageType::OscillateCmd,
+      messages::ButtplugDeviceMessageType::SensorSubscribeCmd,
+      messages::ButtplugDeviceMessageType::UploadPatternCmd,
+    ] {
+      device_messages.insert(
+        message_type,
+        messages::DeviceMessageAttributes::with_step_count(vec![20]),
+      );
+    }
+    let device_added = messages::DeviceAdded::new(0, "Test Device", device_messages);
+    for (version, expect_v3_messages) in [
+      (ButtplugMessageSpecVersion::Version2, false),
+      (ButtplugMessageSpecVersion::Version3, true),
+    ] {
+      let serializer = ButtplugServerJSONSerializer::default();
+      serializer.message_version.replace(Some(version));
+      let json = match serializer.serialize(vec![device_added.clone().into()]) {
+        ButtplugSerializedMessage::Text(json) => json,
+        ButtplugSerializedMessage::Binary(_) => unreachable!("JSON serializer only outputs text."),
+      };
+      assert!(json.contains("VibrateCmd"));
+      assert_eq!(json.contains("OscillateCmd"), expect_v3_messages);
+      assert_eq!(json.contains("SensorSubscribeCmd"), expect_v3_messages);
+      assert_eq!(json.contains("UploadPatternCmd"), expect_v3_messages);
+    }
+  }
+
+  #[test]
+  fn test_linear_cmd_sent_at_only_in_v3() {
+    let msg = r#"[{"LinearCmd":{"Id":1,"DeviceIndex":0,"Vectors":[{"Index":0,"Duration":500,"Position":0.5}],"SentAt":1000}}]"#;
+    for (version, expect_ok) in [
+      (ButtplugMessageSpecVersion::Version2, false),
+      (ButtplugMessageSpecVersion::Version3, true),
+    ] {
+      let serializer = ButtplugServerJSONSerializer::default();
+      serializer.message_version.replace(Some(version));
+      assert_eq!(serializer.deserialize_str(msg).is_ok(), expect_ok);
+      assert_eq!(
+        validate_client_message_payload(msg, version).is_valid(),
+        expect_ok
+      );
+    }
+  }
 }
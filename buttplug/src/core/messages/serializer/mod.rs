@@ -1,7 +1,15 @@
 #[cfg(feature = "serialize-json")]
 mod json_serializer;
 #[cfg(feature = "serialize-json")]
-pub use json_serializer::{ButtplugClientJSONSerializer, ButtplugServerJSONSerializer};
+pub use json_serializer::{
+  validate_client_message_payload,
+  ButtplugClientJSONSerializer,
+  ButtplugClientJSONSerializerBuilder,
+  ButtplugServerJSONSerializer,
+  ButtplugServerJSONSerializerBuilder,
+  MessageValidationReport,
+  MessageValidationResult,
+};
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
@@ -0,0 +1,37 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::*;
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+
+/// Reply to [RequestServerTime], carrying the server's clock in milliseconds
+/// since the unix epoch.
+#[derive(Debug, ButtplugMessage, Clone, PartialEq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct ServerTime {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
+  id: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Timestamp"))]
+  timestamp: u64,
+}
+
+impl ServerTime {
+  pub fn new(timestamp: u64) -> Self {
+    Self { id: 1, timestamp }
+  }
+
+  pub fn timestamp(&self) -> u64 {
+    self.timestamp
+  }
+}
+
+impl ButtplugMessageValidator for ServerTime {
+  fn is_valid(&self) -> Result<(), ButtplugMessageError> {
+    self.is_not_system_id(self.id)
+  }
+}
@@ -0,0 +1,79 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::*;
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+
+/// Uploads a pattern/script to a device that can store and play it back from
+/// its own firmware. The data format is protocol specific, and is chunked and
+/// encoded for the device by the protocol on the server side.
+#[derive(Debug, ButtplugDeviceMessage, PartialEq, Clone)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct UploadPatternCmd {
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
+  id: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceIndex"))]
+  device_index: u32,
+  #[cfg_attr(feature = "serialize-json", serde(rename = "Data"))]
+  data: Vec<u8>,
+}
+
+impl UploadPatternCmd {
+  pub fn new(device_index: u32, data: Vec<u8>) -> Self {
+    Self {
+      id: 1,
+      device_index,
+      data,
+    }
+  }
+
+  pub fn data(&self) -> &Vec<u8> {
+    &self.data
+  }
+}
+
+impl ButtplugMessageValidator for UploadPatternCmd {
+  fn is_valid(&self) -> Result<(), ButtplugMessageError> {
+    self.is_not_system_id(self.id)?;
+    if self.data.is_empty() {
+      Err(ButtplugMessageError::InvalidMessageContents(
+        "UploadPatternCmd requires non-empty pattern data.".to_string(),
+      ))
+    } else {
+      Ok(())
+    }
+  }
+}
+
+#[cfg(feature = "serialize-json")]
+#[cfg(test)]
+mod test {
+  use crate::core::messages::{
+    ButtplugCurrentSpecClientMessage,
+    ButtplugMessageValidator,
+    UploadPatternCmd,
+  };
+
+  #[test]
+  fn test_upload_pattern_cmd_serialize() {
+    let union = ButtplugCurrentSpecClientMessage::UploadPatternCmd(UploadPatternCmd::new(
+      0,
+      vec![1, 2, 3],
+    ));
+    let js = serde_json::to_string(&union).expect("Infallible serialization.");
+    assert_eq!(
+      js,
+      "{\"UploadPatternCmd\":{\"Id\":1,\"DeviceIndex\":0,\"Data\":[1,2,3]}}"
+    );
+  }
+
+  #[test]
+  fn test_upload_pattern_cmd_empty_data_invalid() {
+    assert!(UploadPatternCmd::new(0, vec![]).is_valid().is_err());
+  }
+}
@@ -0,0 +1,505 @@
+//! Per device command queue, so commands reach the hardware one at a time and
+//! in the order they were sent.
+//!
+//! Without this, every command sent to a device starts writing as soon as it
+//! arrives. On slow transports like BLE, a burst of commands can then finish
+//! out of order, leaving the device running whatever happened to land last.
+//!
+//! The command being sent can be cancelled too. Its future, which includes
+//! whatever writes the protocol handler still had to make, is dropped, so
+//! nothing else from it reaches the device. Queued commands whose caller has
+//! stopped waiting on them are skipped, rather than sent.
+//!
+//! Motion commands the client timestamped, see
+//! [LinearCmd::set_sent_at][crate::core::messages::LinearCmd::set_sent_at],
+//! get latency compensation. Commands aren't held back until a set time, they
+//! still run as soon as the device is free, but when one is sent its movement
+//! is shortened by the time since the client sent it. It then finishes when
+//! the client meant it to, however long the transport and queue took. One
+//! whose finish time has already passed is skipped if another timestamped
+//! command is waiting behind it, so a backlog doesn't make every later
+//! movement run late too.
+
+use super::ButtplugDeviceResultFuture;
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::{ButtplugDeviceCommandMessageUnion, ButtplugServerMessage},
+  },
+  util::{async_manager, time::unix_time_millis},
+};
+use futures::{channel::oneshot, future, FutureExt};
+use std::{
+  collections::VecDeque,
+  sync::{Arc, Mutex},
+};
+use tokio_util::sync::CancellationToken;
+
+/// What to do with a command sent to a device whose queue is already full.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum DeviceCommandQueueOverflowPolicy {
+  /// Reject the new command.
+  Reject,
+  /// Drop the oldest queued vibrate update to make room, since a later vibrate
+  /// command replaces its speeds anyways. If there are no vibrate updates
+  /// queued, the new command is rejected.
+  #[default]
+  DropOldest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceCommandQueueOptions {
+  /// Number of commands that can wait behind the one currently being sent
+  /// before the overflow policy is applied.
+  pub max_depth: usize,
+  pub overflow_policy: DeviceCommandQueueOverflowPolicy,
+}
+
+impl Default for DeviceCommandQueueOptions {
+  fn default() -> Self {
+    Self {
+      max_depth: 32,
+      overflow_policy: DeviceCommandQueueOverflowPolicy::default(),
+    }
+  }
+}
+
+type DeviceCommandResult = Result<ButtplugServerMessage, ButtplugError>;
+type DeviceCommandTask =
+  Box<dyn FnOnce(ButtplugDeviceCommandMessageUnion) -> ButtplugDeviceResultFuture + Send>;
+
+struct QueuedCommand {
+  message: ButtplugDeviceCommandMessageUnion,
+  /// Server time the command should finish by, if it was timestamped.
+  finish_time: Option<u64>,
+  task: DeviceCommandTask,
+  result_sender: oneshot::Sender<DeviceCommandResult>,
+}
+
+impl QueuedCommand {
+  fn cancel(self) {
+    // If the caller already stopped waiting on the command, there's no one to
+    // tell.
+    let _ = self
+      .result_sender
+      .send(Err(ButtplugDeviceError::DeviceCommandCancelled.into()));
+  }
+}
+
+/// The command currently being sent.
+struct InFlightCommand {
+  token: CancellationToken,
+  is_motion: bool,
+}
+
+#[derive(Default)]
+struct DeviceCommandQueueState {
+  commands: VecDeque<QueuedCommand>,
+  // True while a task is draining the queue.
+  running: bool,
+  in_flight: Option<InFlightCommand>,
+}
+
+impl DeviceCommandQueueState {
+  fn cancel_in_flight(&mut self) -> bool {
+    match self.in_flight.take() {
+      Some(command) => {
+        command.token.cancel();
+        true
+      }
+      None => false,
+    }
+  }
+}
+
+/// True for commands that set what the device is doing, so a later command of
+/// the same type replaces them.
+pub(crate) fn is_motion_command(message: &ButtplugDeviceCommandMessageUnion) -> bool {
+  matches!(
+    message,
+    ButtplugDeviceCommandMessageUnion::FleshlightLaunchFW12Cmd(_)
+      | ButtplugDeviceCommandMessageUnion::VorzeA10CycloneCmd(_)
+      | ButtplugDeviceCommandMessageUnion::KiirooCmd(_)
+      | ButtplugDeviceCommandMessageUnion::VibrateCmd(_)
+      | ButtplugDeviceCommandMessageUnion::LinearCmd(_)
+      | ButtplugDeviceCommandMessageUnion::RotateCmd(_)
+      | ButtplugDeviceCommandMessageUnion::OscillateCmd(_)
+  )
+}
+
+fn is_vibrate_update(message: &ButtplugDeviceCommandMessageUnion) -> bool {
+  matches!(message, ButtplugDeviceCommandMessageUnion::VibrateCmd(_))
+}
+
+fn motion_finish_time(message: &ButtplugDeviceCommandMessageUnion) -> Option<u64> {
+  match message {
+    ButtplugDeviceCommandMessageUnion::LinearCmd(msg) => msg.finish_time(),
+    _ => None,
+  }
+}
+
+/// True if `command` should have finished by `now`, and the next queued
+/// command is timestamped, so it can take over from here.
+fn is_overdue(command: &QueuedCommand, queued: &VecDeque<QueuedCommand>, now: u64) -> bool {
+  command
+    .finish_time
+    .is_some_and(|finish_time| finish_time <= now)
+    && queued
+      .front()
+      .is_some_and(|next| next.finish_time.is_some())
+}
+
+pub(crate) struct DeviceCommandQueue {
+  options: DeviceCommandQueueOptions,
+  state: Arc<Mutex<DeviceCommandQueueState>>,
+}
+
+impl DeviceCommandQueue {
+  pub fn new(options: DeviceCommandQueueOptions) -> Self {
+    Self {
+      options,
+      state: Arc::new(Mutex::new(DeviceCommandQueueState::default())),
+    }
+  }
+
+  pub fn set_options(&mut self, options: DeviceCommandQueueOptions) {
+    self.options = options;
+  }
+
+  /// Number of commands waiting to be sent, not counting the one currently
+  /// being sent.
+  pub fn depth(&self) -> usize {
+    self
+      .state
+      .lock()
+      .expect("Command queue lock should never be poisoned")
+      .commands
+      .len()
+  }
+
+  /// Cancels every queued command, along with the one being sent, returning
+  /// how many commands were cancelled.
+  pub fn cancel_all(&self) -> usize {
+    let (cancelled, in_flight_cancelled) = {
+      let mut state = self
+        .state
+        .lock()
+        .expect("Command queue lock should never be poisoned");
+      let cancelled: Vec<_> = state.commands.drain(..).collect();
+      (cancelled, state.cancel_in_flight())
+    };
+    let count = cancelled.len() + usize::from(in_flight_cancelled);
+    cancelled.into_iter().for_each(QueuedCommand::cancel);
+    count
+  }
+
+  /// Queues `task` to be run with `message` once every command queued before
+  /// it has finished. StopDeviceCmd skips ahead of everything else in the
+  /// queue, and cancels any queued or in flight motion commands, since they'd
+  /// just undo the stop.
+  pub fn enqueue<F>(
+    &self,
+    message: ButtplugDeviceCommandMessageUnion,
+    task: F,
+  ) -> ButtplugDeviceResultFuture
+  where
+    F: FnOnce(ButtplugDeviceCommandMessageUnion) -> ButtplugDeviceResultFuture + Send + 'static,
+  {
+    let (result_sender, result_receiver) = oneshot::channel();
+    let is_stop = matches!(message, ButtplugDeviceCommandMessageUnion::StopDeviceCmd(_));
+    let command = QueuedCommand {
+      finish_time: motion_finish_time(&message),
+      message,
+      task: Box::new(task),
+      result_sender,
+    };
+    let mut state = self
+      .state
+      .lock()
+      .expect("Command queue lock should never be poisoned");
+    if is_stop {
+      let (cancelled, kept): (VecDeque<_>, VecDeque<_>) = state
+        .commands
+        .drain(..)
+        .partition(|queued| is_motion_command(&queued.message));
+      if !cancelled.is_empty() {
+        debug!(
+          "StopDeviceCmd cancelled {} queued motion commands.",
+          cancelled.len()
+        );
+      }
+      cancelled.into_iter().for_each(QueuedCommand::cancel);
+      if matches!(&state.in_flight, Some(command) if command.is_motion) && state.cancel_in_flight()
+      {
+        debug!("StopDeviceCmd cancelled the motion command being sent.");
+      }
+      state.commands = kept;
+      state.commands.push_front(command);
+    } else {
+      if state.commands.len() >= self.options.max_depth {
+        let dropped = match self.options.overflow_policy {
+          DeviceCommandQueueOverflowPolicy::Reject => None,
+          DeviceCommandQueueOverflowPolicy::DropOldest => state
+            .commands
+            .iter()
+            .position(|queued| is_vibrate_update(&queued.message))
+            .and_then(|index| state.commands.remove(index)),
+        };
+        match dropped {
+          Some(dropped) => {
+            debug!("Device command queue full, dropping oldest vibrate update.");
+            dropped.cancel();
+          }
+          None => {
+            let depth = state.commands.len() as u32;
+            warn!("Device command queue full, rejecting command.");
+            return Box::pin(future::ready(Err(
+              ButtplugDeviceError::DeviceCommandQueueFull(depth).into(),
+            )));
+          }
+        }
+      }
+      state.commands.push_back(command);
+    }
+    trace!("Device command queue depth: {}", state.commands.len());
+    if !state.running {
+      state.running = true;
+      async_manager::spawn(Self::run_queue(self.state.clone()));
+    }
+    Box::pin(async move {
+      result_receiver
+        .await
+        .unwrap_or_else(|_| Err(ButtplugDeviceError::DeviceCommandCancelled.into()))
+    })
+  }
+
+  async fn run_queue(state: Arc<Mutex<DeviceCommandQueueState>>) {
+    loop {
+      let (command, token) = {
+        let mut state = state
+          .lock()
+          .expect("Command queue lock should never be poisoned");
+        match state.commands.pop_front() {
+          Some(command) if command.result_sender.is_canceled() => {
+            trace!("Nothing is waiting on queued command anymore, skipping it.");
+            continue;
+          }
+          Some(command) if is_overdue(&command, &state.commands, unix_time_millis()) => {
+            debug!("Queued motion command is past its finish time, skipping it.");
+            command.cancel();
+            continue;
+          }
+          Some(command) => {
+            let token = CancellationToken::new();
+            state.in_flight = Some(InFlightCommand {
+              token: token.clone(),
+              is_motion: is_motion_command(&command.message),
+            });
+            (command, token)
+          }
+          None => {
+            state.running = false;
+            return;
+          }
+        }
+      };
+      let mut message = command.message;
+      if let ButtplugDeviceCommandMessageUnion::LinearCmd(msg) = &mut message {
+        msg.compensate_latency(unix_time_millis());
+      }
+      let task = (command.task)(message);
+      let result = select! {
+        result = task.fuse() => result,
+        _ = token.cancelled().fuse() => Err(ButtplugDeviceError::DeviceCommandCancelled.into()),
+      };
+      state
+        .lock()
+        .expect("Command queue lock should never be poisoned")
+        .in_flight = None;
+      // The caller may have stopped waiting on the result, which is fine.
+      let _ = command.result_sender.send(result);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::core::messages::{self, VectorSubcommand, VibrateSubcommand};
+  use futures_timer::Delay;
+  use std::time::Duration;
+
+  fn vibrate(speed: f64) -> ButtplugDeviceCommandMessageUnion {
+    messages::VibrateCmd::new(0, vec![VibrateSubcommand::new(0, speed)]).into()
+  }
+
+  fn linear(duration: u32, sent_ago: u64) -> ButtplugDeviceCommandMessageUnion {
+    let mut msg = messages::LinearCmd::new(0, vec![VectorSubcommand::new(0, duration, 0.5)]);
+    msg.set_sent_at(Some(unix_time_millis() - sent_ago));
+    msg.into()
+  }
+
+  fn logged_linear_duration(message: &ButtplugDeviceCommandMessageUnion) -> u32 {
+    match message {
+      ButtplugDeviceCommandMessageUnion::LinearCmd(msg) => msg.vectors()[0].duration(),
+      _ => panic!("Expected a LinearCmd, got {:?}", message),
+    }
+  }
+
+  // Records the order commands are run in, taking a while to finish each one
+  // so later commands have to wait in the queue.
+  fn slow_task(
+    log: Arc<Mutex<Vec<ButtplugDeviceCommandMessageUnion>>>,
+  ) -> impl FnOnce(ButtplugDeviceCommandMessageUnion) -> ButtplugDeviceResultFuture + Send + 'static
+  {
+    move |message| {
+      Box::pin(async move {
+        Delay::new(Duration::from_millis(50)).await;
+        log.lock().expect("Test, assuming infallible").push(message);
+        Ok(messages::Ok::default().into())
+      })
+    }
+  }
+
+  #[test]
+  fn test_command_queue_runs_commands_in_order() {
+    async_manager::block_on(async {
+      let queue = DeviceCommandQueue::new(DeviceCommandQueueOptions::default());
+      let log = Arc::new(Mutex::new(vec![]));
+      let futures: Vec<_> = (1..=3)
+        .map(|i| queue.enqueue(vibrate(i as f64 / 10.0), slow_task(log.clone())))
+        .collect();
+      // Give the first command a chance to start.
+      Delay::new(Duration::from_millis(10)).await;
+      assert_eq!(queue.depth(), 2);
+      for result in future::join_all(futures).await {
+        assert!(result.is_ok());
+      }
+      assert_eq!(queue.depth(), 0);
+      assert_eq!(
+        *log.lock().expect("Test, assuming infallible"),
+        vec![vibrate(0.1), vibrate(0.2), vibrate(0.3)]
+      );
+    });
+  }
+
+  #[test]
+  fn test_command_queue_skips_abandoned_commands() {
+    async_manager::block_on(async {
+      let queue = DeviceCommandQueue::new(DeviceCommandQueueOptions::default());
+      let log = Arc::new(Mutex::new(vec![]));
+      let first = queue.enqueue(vibrate(0.1), slow_task(log.clone()));
+      Delay::new(Duration::from_millis(10)).await;
+      drop(queue.enqueue(vibrate(0.2), slow_task(log.clone())));
+      let last = queue.enqueue(vibrate(0.3), slow_task(log.clone()));
+      assert!(first.await.is_ok());
+      assert!(last.await.is_ok());
+      assert_eq!(
+        *log.lock().expect("Test, assuming infallible"),
+        vec![vibrate(0.1), vibrate(0.3)]
+      );
+    });
+  }
+
+  #[test]
+  fn test_command_queue_stop_preempts_motion_commands() {
+    async_manager::block_on(async {
+      let queue = DeviceCommandQueue::new(DeviceCommandQueueOptions::default());
+      let log = Arc::new(Mutex::new(vec![]));
+      let first = queue.enqueue(vibrate(0.1), slow_task(log.clone()));
+      Delay::new(Duration::from_millis(10)).await;
+      let queued = queue.enqueue(vibrate(0.2), slow_task(log.clone()));
+      let battery: ButtplugDeviceCommandMessageUnion = messages::BatteryLevelCmd::new(0).into();
+      let other = queue.enqueue(battery.clone(), slow_task(log.clone()));
+      let stop: ButtplugDeviceCommandMessageUnion = messages::StopDeviceCmd::new(0).into();
+      let stop_result = queue.enqueue(stop.clone(), slow_task(log.clone()));
+      for cancelled in [first, queued] {
+        assert!(matches!(
+          cancelled.await,
+          Err(ButtplugError::ButtplugDeviceError(
+            ButtplugDeviceError::DeviceCommandCancelled
+          ))
+        ));
+      }
+      assert!(stop_result.await.is_ok());
+      assert!(other.await.is_ok());
+      // The command being sent is cut off, then the stop runs before anything
+      // else that was waiting.
+      assert_eq!(
+        *log.lock().expect("Test, assuming infallible"),
+        vec![stop, battery]
+      );
+    });
+  }
+
+  #[test]
+  fn test_command_queue_overflow_policies() {
+    async_manager::block_on(async {
+      let log = Arc::new(Mutex::new(vec![]));
+      let queue = DeviceCommandQueue::new(DeviceCommandQueueOptions {
+        max_depth: 1,
+        overflow_policy: DeviceCommandQueueOverflowPolicy::DropOldest,
+      });
+      let running = queue.enqueue(vibrate(0.1), slow_task(log.clone()));
+      Delay::new(Duration::from_millis(10)).await;
+      let dropped = queue.enqueue(vibrate(0.2), slow_task(log.clone()));
+      let newest = queue.enqueue(vibrate(0.3), slow_task(log.clone()));
+      assert!(matches!(
+        dropped.await,
+        Err(ButtplugError::ButtplugDeviceError(
+          ButtplugDeviceError::DeviceCommandCancelled
+        ))
+      ));
+      assert!(running.await.is_ok());
+      assert!(newest.await.is_ok());
+      assert_eq!(
+        *log.lock().expect("Test, assuming infallible"),
+        vec![vibrate(0.1), vibrate(0.3)]
+      );
+
+      let queue = DeviceCommandQueue::new(DeviceCommandQueueOptions {
+        max_depth: 1,
+        overflow_policy: DeviceCommandQueueOverflowPolicy::Reject,
+      });
+      let running = queue.enqueue(vibrate(0.1), slow_task(log.clone()));
+      Delay::new(Duration::from_millis(10)).await;
+      let queued = queue.enqueue(vibrate(0.2), slow_task(log.clone()));
+      assert!(matches!(
+        queue.enqueue(vibrate(0.3), slow_task(log.clone())).await,
+        Err(ButtplugError::ButtplugDeviceError(
+          ButtplugDeviceError::DeviceCommandQueueFull(1)
+        ))
+      ));
+      assert!(running.await.is_ok());
+      assert!(queued.await.is_ok());
+    });
+  }
+
+  #[test]
+  fn test_command_queue_compensates_timestamped_linear_commands() {
+    async_manager::block_on(async {
+      let queue = DeviceCommandQueue::new(DeviceCommandQueueOptions::default());
+      let log = Arc::new(Mutex::new(vec![]));
+      let running = queue.enqueue(vibrate(0.1), slow_task(log.clone()));
+      Delay::new(Duration::from_millis(10)).await;
+      // Should have finished moving a second ago, and there's a newer command
+      // to take over.
+      let overdue = queue.enqueue(linear(500, 1500), slow_task(log.clone()));
+      let late = queue.enqueue(linear(1000, 200), slow_task(log.clone()));
+      assert!(running.await.is_ok());
+      assert!(matches!(
+        overdue.await,
+        Err(ButtplugError::ButtplugDeviceError(
+          ButtplugDeviceError::DeviceCommandCancelled
+        ))
+      ));
+      assert!(late.await.is_ok());
+      let log = log.lock().expect("Test, assuming infallible");
+      assert_eq!(log.len(), 2);
+      // Time spent getting to the device comes out of the move.
+      let duration = logged_linear_duration(&log[1]);
+      assert!(duration <= 800, "Duration {} not compensated", duration);
+      assert!(duration > 0);
+    });
+  }
+}
@@ -7,11 +7,14 @@
 
 //! Device specific identification and protocol implementations.
 
+#[cfg(feature = "scripted-protocols")]
+use super::protocol::scripted::ScriptedProtocolFactory;
 use super::protocol::{
   add_to_protocol_map,
   get_default_protocol_map,
   ButtplugProtocol,
-  TryCreateProtocolFunc,
+  ButtplugProtocolFactory,
+  ProtocolFactoryMap,
 };
 use crate::{
   core::{
@@ -19,11 +22,14 @@ use crate::{
     messages::{ButtplugDeviceMessageType, DeviceMessageAttributes, DeviceMessageAttributesMap},
   },
   device::Endpoint,
+  server::device_manager::DeviceUserConfig,
+  util::device_configuration::load_protocol_config_with_external_file,
 };
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
 use std::{
-  collections::{HashMap, HashSet},
+  collections::{BTreeSet, HashMap, HashSet},
+  path::Path,
   sync::Arc,
 };
 use uuid::Uuid;
@@ -34,11 +40,37 @@ use uuid::Uuid;
 // gonna hurt anything and making a ton of serde attributes is just going to get
 // confusing (see the messages impl).
 
+/// Manufacturer specific data from a BLE advertisement. In device config, the
+/// data is a prefix that the advertised data has to start with, so it can be
+/// left empty to match on the company ID alone.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BluetoothLEManufacturerData {
+  pub company: u16,
+  #[serde(default)]
+  pub data: Vec<u8>,
+}
+
+impl BluetoothLEManufacturerData {
+  pub fn new(company: u16, data: &[u8]) -> Self {
+    Self {
+      company,
+      data: data.to_vec(),
+    }
+  }
+
+  fn matches(&self, other: &BluetoothLEManufacturerData) -> bool {
+    self.company == other.company
+      && (self.data.starts_with(&other.data) || other.data.starts_with(&self.data))
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BluetoothLESpecifier {
   pub names: HashSet<String>,
   #[serde(default, rename = "advertised-services")]
   pub advertised_services: HashSet<Uuid>,
+  #[serde(default, rename = "manufacturer-data")]
+  pub manufacturer_data: Vec<BluetoothLEManufacturerData>,
   // Set of services that we may have gotten as part of the advertisement.
   pub services: HashMap<Uuid, HashMap<Endpoint, Uuid>>,
 }
@@ -57,6 +89,14 @@ impl PartialEq for BluetoothLESpecifier {
     {
       return true;
     }
+    if self.manufacturer_data.iter().any(|data| {
+      other
+        .manufacturer_data
+        .iter()
+        .any(|other_data| data.matches(other_data))
+    }) {
+      return true;
+    }
     // Otherwise, try wildcarded names.
     for name in &self.names {
       for other_name in &other.names {
@@ -84,12 +124,26 @@ impl PartialEq for BluetoothLESpecifier {
 
 impl BluetoothLESpecifier {
   pub fn new_from_device(name: &str, advertised_services: &[Uuid]) -> BluetoothLESpecifier {
+    Self::new_from_advertisement(name, advertised_services, &HashMap::new())
+  }
+
+  /// Builds a specifier from everything a device advertised, including
+  /// manufacturer data keyed by company ID.
+  pub fn new_from_advertisement(
+    name: &str,
+    advertised_services: &[Uuid],
+    manufacturer_data: &HashMap<u16, Vec<u8>>,
+  ) -> BluetoothLESpecifier {
     let mut name_set = HashSet::new();
     name_set.insert(name.to_string());
     let service_set = HashSet::from_iter(advertised_services.iter().copied());
     BluetoothLESpecifier {
       names: name_set,
       advertised_services: service_set,
+      manufacturer_data: manufacturer_data
+        .iter()
+        .map(|(company, data)| BluetoothLEManufacturerData::new(*company, data))
+        .collect(),
       services: HashMap::new(),
     }
   }
@@ -97,9 +151,92 @@ impl BluetoothLESpecifier {
   pub fn merge(&mut self, other: BluetoothLESpecifier) {
     // Add any new names.
     self.names = self.names.union(&other.names).cloned().collect();
+    // Add any new advertisement matches.
+    self.advertised_services = self
+      .advertised_services
+      .union(&other.advertised_services)
+      .cloned()
+      .collect();
+    for data in other.manufacturer_data {
+      if !self.manufacturer_data.contains(&data) {
+        self.manufacturer_data.push(data);
+      }
+    }
     // Add new services, overwrite matching services.
     self.services.extend(other.services);
   }
+
+  /// One scan filter per name, advertised service and manufacturer data
+  /// entry, since a WebBluetooth filter only matches if all of its fields do.
+  fn scan_filters(&self) -> Vec<BluetoothLEScanFilter> {
+    let names = self.names.iter().map(|name| match name.strip_suffix('*') {
+      Some(prefix) => BluetoothLEScanFilter {
+        name_prefix: Some(prefix.to_owned()),
+        ..Default::default()
+      },
+      None => BluetoothLEScanFilter {
+        name: Some(name.clone()),
+        ..Default::default()
+      },
+    });
+    let services = self
+      .advertised_services
+      .iter()
+      .map(|service| BluetoothLEScanFilter {
+        services: vec![*service],
+        ..Default::default()
+      });
+    let manufacturer_data = self
+      .manufacturer_data
+      .iter()
+      .map(|data| BluetoothLEScanFilter {
+        manufacturer_data: vec![BluetoothLEManufacturerDataFilter {
+          company_identifier: data.company,
+          data_prefix: data.data.clone(),
+        }],
+        ..Default::default()
+      });
+    names.chain(services).chain(manufacturer_data).collect()
+  }
+}
+
+/// Manufacturer data part of a [BluetoothLEScanFilter], matching on company
+/// ID and a prefix of the data.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BluetoothLEManufacturerDataFilter {
+  #[serde(rename = "companyIdentifier")]
+  pub company_identifier: u16,
+  #[serde(rename = "dataPrefix", default, skip_serializing_if = "Vec::is_empty")]
+  pub data_prefix: Vec<u8>,
+}
+
+/// A single scan filter, laid out like the filters WebBluetooth's
+/// `requestDevice()` takes. Fields that aren't set are left out when
+/// serialized.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BluetoothLEScanFilter {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub name: Option<String>,
+  #[serde(rename = "namePrefix")]
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub name_prefix: Option<String>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub services: Vec<Uuid>,
+  #[serde(rename = "manufacturerData")]
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub manufacturer_data: Vec<BluetoothLEManufacturerDataFilter>,
+}
+
+/// Everything needed to scan for and talk to every BLE device in the device
+/// config, laid out like the options WebBluetooth's `requestDevice()` takes,
+/// so web and mobile front ends don't need their own copy of the device list.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct BluetoothLEScanFilters {
+  pub filters: Vec<BluetoothLEScanFilter>,
+  /// Every service protocols use once connected. WebBluetooth only allows
+  /// access to services listed here or in the filters.
+  #[serde(rename = "optionalServices")]
+  pub optional_services: Vec<Uuid>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -175,12 +312,53 @@ impl PartialEq for SerialSpecifier {
   }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct USBSpecifier {
   #[serde(rename = "vendor-id")]
   vendor_id: u16,
   #[serde(rename = "product-id")]
   product_id: u16,
+  /// Interface to claim on the device.
+  #[serde(default)]
+  interface: u8,
+  /// USB endpoint addresses, keyed by the endpoint we expose them as. IN
+  /// endpoint addresses have the high bit set, i.e. 0x81.
+  #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+  endpoints: HashMap<Endpoint, u8>,
+}
+
+impl USBSpecifier {
+  pub fn new(vendor_id: u16, product_id: u16) -> Self {
+    Self {
+      vendor_id,
+      product_id,
+      ..Default::default()
+    }
+  }
+
+  pub fn vendor_id(&self) -> u16 {
+    self.vendor_id
+  }
+
+  pub fn product_id(&self) -> u16 {
+    self.product_id
+  }
+
+  pub fn interface(&self) -> u8 {
+    self.interface
+  }
+
+  pub fn endpoints(&self) -> &HashMap<Endpoint, u8> {
+    &self.endpoints
+  }
+}
+
+// Devices are only ever found by VID/PID, the rest of the specifier is how we
+// talk to them once they're connected.
+impl PartialEq for USBSpecifier {
+  fn eq(&self, other: &Self) -> bool {
+    self.vendor_id == other.vendor_id && self.product_id == other.product_id
+  }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -212,6 +390,24 @@ impl WebsocketSpecifier {
   }
 }
 
+/// Specifier for devices shared by another server through a
+/// [DeviceForwarder][crate::server::device_forwarder::DeviceForwarder]. The
+/// forwarding server has already matched the device, so this matches the
+/// protocol definition with the same name instead of anything the device
+/// advertises.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ForwardedSpecifier {
+  pub protocol: String,
+}
+
+impl ForwardedSpecifier {
+  pub fn new(protocol: &str) -> Self {
+    Self {
+      protocol: protocol.to_owned(),
+    }
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum DeviceSpecifier {
   BluetoothLE(BluetoothLESpecifier),
@@ -221,6 +417,7 @@ pub enum DeviceSpecifier {
   XInput(XInputSpecifier),
   LovenseConnectService(LovenseConnectServiceSpecifier),
   Websocket(WebsocketSpecifier),
+  Forwarded(ForwardedSpecifier),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -230,6 +427,45 @@ pub struct ProtocolAttributes {
   messages: Option<DeviceMessageAttributesMap>,
 }
 
+/// Periodic writes that keep a device's connection or motors alive, for
+/// devices that drop their connection or stop moving if they don't hear from
+/// us every so often.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeepaliveDefinition {
+  /// Milliseconds the endpoint can go without a write before a keepalive is
+  /// sent.
+  #[serde(rename = "interval-ms")]
+  pub interval_ms: u32,
+  pub endpoint: Endpoint,
+  /// If true, the last command written to the endpoint is sent again, and
+  /// `packet` is only sent until the first command is written. Otherwise,
+  /// `packet` is always sent.
+  #[serde(rename = "repeat-last-command", default)]
+  pub repeat_last_command: bool,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub packet: Vec<u8>,
+  #[serde(rename = "write-with-response", default)]
+  pub write_with_response: bool,
+}
+
+/// Script translating commands into bytes for a protocol, so new devices can
+/// be supported from the device configuration alone. Only used when the
+/// library is built with the `scripted-protocols` feature. See
+/// [ScriptedProtocolFactory][crate::device::protocol::scripted::ScriptedProtocolFactory]
+/// for the functions a script can define.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProtocolScript {
+  /// Rhai source for the script.
+  pub source: String,
+  /// Endpoint the bytes returned by the script are written to.
+  #[serde(default = "default_script_endpoint")]
+  pub endpoint: Endpoint,
+}
+
+fn default_script_endpoint() -> Endpoint {
+  Endpoint::Tx
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct ProtocolDefinition {
   // Can't get serde flatten specifiers into a String/DeviceSpecifier map, so
@@ -243,6 +479,8 @@ pub struct ProtocolDefinition {
   pub websocket: Option<WebsocketSpecifier>,
   #[serde(rename = "lovense-connect-service")]
   pub lovense_connect_service: Option<LovenseConnectServiceSpecifier>,
+  pub keepalive: Option<KeepaliveDefinition>,
+  pub script: Option<ProtocolScript>,
   pub defaults: Option<ProtocolAttributes>,
   #[serde(default)]
   pub configurations: Vec<ProtocolAttributes>,
@@ -277,6 +515,9 @@ impl PartialEq<DeviceSpecifier> for ProtocolDefinition {
       DeviceSpecifier::LovenseConnectService(other_lovense_service) => {
         option_some_eq(&self.lovense_connect_service, other_lovense_service)
       }
+      // Definitions don't know their own name, see
+      // DeviceConfigurationManager::find_all_protocol_definitions.
+      DeviceSpecifier::Forwarded(_) => false,
     }
   }
 }
@@ -334,6 +575,11 @@ impl ProtocolDefinition {
       error!("Lovense connect service specifier set for user configuration, ignoring.");
     }
 
+    // If a new keepalive is set, overwrite.
+    if other.keepalive.is_some() {
+      self.keepalive = other.keepalive;
+    }
+
     // If new defaults are set, overwrite.
     if other.defaults.is_some() {
       self.defaults = other.defaults;
@@ -357,6 +603,7 @@ pub struct DeviceProtocolConfiguration {
   allow_raw_messages: bool,
   defaults: Option<ProtocolAttributes>,
   configurations: Vec<ProtocolAttributes>,
+  user_config: DeviceUserConfig,
 }
 
 impl DeviceProtocolConfiguration {
@@ -369,9 +616,31 @@ impl DeviceProtocolConfiguration {
       allow_raw_messages,
       defaults,
       configurations,
+      user_config: DeviceUserConfig::default(),
     }
   }
 
+  /// User config for the device being created, resolved by address. Empty if
+  /// the user hasn't configured the device. Changes made after the device
+  /// connects are sent to [ButtplugProtocol::update_user_config].
+  pub fn user_config(&self) -> &DeviceUserConfig {
+    &self.user_config
+  }
+
+  pub fn set_user_config(&mut self, user_config: DeviceUserConfig) {
+    self.user_config = user_config;
+  }
+
+  /// True if there's a configuration specifically for `identifier`, as opposed
+  /// to [get_attributes][Self::get_attributes] falling back to the defaults.
+  pub fn has_identifier(&self, identifier: &str) -> bool {
+    self
+      .configurations
+      .iter()
+      .flat_map(|attrs| attrs.identifier.iter().flatten())
+      .any(|id| id == identifier)
+  }
+
   pub fn get_attributes(
     &self,
     identifier: &str,
@@ -386,8 +655,13 @@ impl DeviceProtocolConfiguration {
       }
     }
 
-    // If we're allowing raw messages, tack those on beforehand also.
-    if self.allow_raw_messages {
+    // If we're allowing raw messages, tack those on beforehand also. Device
+    // user config can override the server wide setting.
+    if self
+      .user_config
+      .allow_raw_messages()
+      .unwrap_or(self.allow_raw_messages)
+    {
       let endpoint_attributes = DeviceMessageAttributes {
         endpoints: Some(endpoints.to_owned()),
         ..Default::default()
@@ -436,10 +710,17 @@ impl DeviceProtocolConfiguration {
       attributes.extend(msg_attrs.clone());
     }
 
+    for attrs in attributes.values_mut() {
+      attrs.fill_feature_descriptor_step_counts();
+    }
+
     // Everything needs to be able to stop.
-    attributes
+    let stop_attributes = attributes
       .entry(ButtplugDeviceMessageType::StopDeviceCmd)
-      .or_insert_with(DeviceMessageAttributes::default);
+      .or_default();
+    if let Some(stop_behavior) = self.user_config.stop_behavior() {
+      stop_attributes.stop_behavior = Some(stop_behavior.clone());
+    }
 
     Ok((
       device_attrs
@@ -455,7 +736,7 @@ impl DeviceProtocolConfiguration {
 pub struct DeviceConfigurationManager {
   allow_raw_messages: bool,
   protocol_definitions: Arc<DashMap<String, ProtocolDefinition>>,
-  protocol_map: Arc<DashMap<String, TryCreateProtocolFunc>>,
+  protocol_map: Arc<ProtocolFactoryMap>,
 }
 
 impl Default for DeviceConfigurationManager {
@@ -480,13 +761,44 @@ impl DeviceConfigurationManager {
     protocol_name: &str,
     protocol_definition: ProtocolDefinition,
   ) {
+    #[cfg(feature = "scripted-protocols")]
+    if let Some(script) = &protocol_definition.script {
+      match ScriptedProtocolFactory::new(protocol_name, script) {
+        Ok(factory) => self.add_protocol_factory(protocol_name, Arc::new(factory)),
+        Err(err) => error!(
+          "Script for protocol {} failed to compile, its devices won't be created: {}",
+          protocol_name, err
+        ),
+      }
+    }
     self
       .protocol_definitions
       .insert(protocol_name.to_owned(), protocol_definition);
   }
 
+  /// Loads the device config built into the library, with the main device
+  /// config file at `path` merged over it, replacing any protocol definitions
+  /// with the same names. Lets apps pick up device config updates without
+  /// rebuilding. Fails if the file is older than the built in config, or was
+  /// written for a newer config schema than this library understands.
+  pub fn load_device_configuration_file(
+    &self,
+    path: impl AsRef<Path>,
+  ) -> Result<(), ButtplugError> {
+    let config = load_protocol_config_with_external_file(path)?;
+    for (name, definition) in config.protocols {
+      self.add_protocol_definition(&name, definition);
+    }
+    Ok(())
+  }
+
   pub fn remove_protocol_definition(&self, protocol_name: &str) {
-    self.protocol_definitions.remove(protocol_name);
+    if let Some((_, definition)) = self.protocol_definitions.remove(protocol_name) {
+      // Scripted protocols only exist as long as their definition does.
+      if cfg!(feature = "scripted-protocols") && definition.script.is_some() {
+        self.remove_protocol(protocol_name);
+      }
+    }
   }
 
   pub fn add_protocol<T>(&self, protocol_name: &str)
@@ -496,6 +808,14 @@ impl DeviceConfigurationManager {
     add_to_protocol_map::<T>(&self.protocol_map, protocol_name);
   }
 
+  pub fn add_protocol_factory(
+    &self,
+    protocol_name: &str,
+    factory: Arc<dyn ButtplugProtocolFactory>,
+  ) {
+    self.protocol_map.insert(protocol_name.to_owned(), factory);
+  }
+
   pub fn remove_protocol(&self, protocol_name: &str) {
     self.protocol_map.remove(protocol_name);
   }
@@ -508,11 +828,14 @@ impl DeviceConfigurationManager {
     self.protocol_map.contains_key(protocol_name)
   }
 
-  pub fn get_protocol_creator(&self, protocol_name: &str) -> Option<TryCreateProtocolFunc> {
+  pub fn get_protocol_creator(
+    &self,
+    protocol_name: &str,
+  ) -> Option<Arc<dyn ButtplugProtocolFactory>> {
     self
       .protocol_map
       .get(protocol_name)
-      .map(|pair| *pair.value())
+      .map(|pair| pair.value().clone())
   }
 
   /// Provides read-only access to the internal protocol/identifier map. Mainly
@@ -522,30 +845,70 @@ impl DeviceConfigurationManager {
     self.protocol_definitions.clone()
   }
 
+  /// Scan filters covering every protocol with a BLE definition, sorted and
+  /// without duplicates.
+  pub fn bluetoothle_scan_filters(&self) -> BluetoothLEScanFilters {
+    let mut filters = BTreeSet::new();
+    let mut optional_services = BTreeSet::new();
+    for definition in self.protocol_definitions.iter() {
+      if let Some(btle) = &definition.value().btle {
+        filters.extend(btle.scan_filters());
+        optional_services.extend(btle.services.keys().copied());
+      }
+    }
+    BluetoothLEScanFilters {
+      filters: filters.into_iter().collect(),
+      optional_services: optional_services.into_iter().collect(),
+    }
+  }
+
   pub fn find_protocol_definitions(
     &self,
     specifier: &DeviceSpecifier,
   ) -> Option<(bool, String, ProtocolDefinition)> {
+    self
+      .find_all_protocol_definitions(specifier)
+      .into_iter()
+      .next()
+  }
+
+  /// Finds every protocol definition that matches `specifier`, sorted by
+  /// protocol name. Some product lines share advertised names across
+  /// protocols, so device creation tries each of these in turn until one
+  /// initializes.
+  pub fn find_all_protocol_definitions(
+    &self,
+    specifier: &DeviceSpecifier,
+  ) -> Vec<(bool, String, ProtocolDefinition)> {
     debug!(
       "Looking for protocol that matches specifier: {:?}",
       specifier
     );
-    for config in self.protocol_definitions.iter() {
-      if config.value() == specifier {
+    let mut found: Vec<_> = self
+      .protocol_definitions
+      .iter()
+      .filter(|config| match specifier {
+        DeviceSpecifier::Forwarded(forwarded) => *config.key() == forwarded.protocol,
+        specifier => config.value() == specifier,
+      })
+      .map(|config| {
         info!(
           "Found protocol {:?} for specifier {:?}.",
           config.key(),
           specifier
         );
-        return Some((
+        (
           self.allow_raw_messages,
           config.key().clone(),
           config.value().clone(),
-        ));
-      }
+        )
+      })
+      .collect();
+    if found.is_empty() {
+      debug!("No protocol found for specifier {:?}.", specifier);
     }
-    debug!("No protocol found for specifier {:?}.", specifier);
-    None
+    found.sort_by(|a, b| a.1.cmp(&b.1));
+    found
   }
 
   pub fn get_protocol_config(&self, name: &str) -> Option<DeviceProtocolConfiguration> {
@@ -569,16 +932,32 @@ impl DeviceConfigurationManager {
 #[cfg(test)]
 mod test {
   use super::{
+    BluetoothLEManufacturerData,
+    BluetoothLEScanFilter,
     BluetoothLESpecifier,
+    DeviceConfigurationManager,
     DeviceProtocolConfiguration,
     DeviceSpecifier,
     SerialSpecifier,
+    USBSpecifier,
   };
   use crate::{
-    core::messages::ButtplugDeviceMessageType,
-    device::configuration_manager::ProtocolDefinition,
-    util::device_configuration::create_test_dcm,
+    core::{
+      errors::{ButtplugDeviceError, ButtplugError},
+      messages::ButtplugDeviceMessageType,
+    },
+    device::{configuration_manager::ProtocolDefinition, Endpoint},
+    server::device_manager::DeviceUserConfig,
+    util::device_configuration::{
+      create_test_dcm,
+      get_internal_config_schema_version,
+      get_internal_config_version,
+      UserDeviceConfigFile,
+      DEVICE_CONFIGURATION_JSON,
+    },
   };
+  use std::collections::HashMap;
+  use uuid::Uuid;
   /*
     #[test]
     fn test_load_config() {
@@ -601,6 +980,43 @@ mod test {
     assert!(config.find_protocol_definitions(&lovense).is_some());
   }
 
+  #[test]
+  fn test_manufacturer_data_equals() {
+    let mut config_specifier = BluetoothLESpecifier::new_from_device("Config Name", &[]);
+    config_specifier.manufacturer_data =
+      vec![BluetoothLEManufacturerData::new(0x1234, &[0x01, 0x02])];
+    let mut data = HashMap::new();
+    data.insert(0x1234, vec![0x01, 0x02, 0x03]);
+    let device = BluetoothLESpecifier::new_from_advertisement("Device Name", &[], &data);
+    assert_eq!(config_specifier, device);
+    // Data has to start with the prefix.
+    data.insert(0x1234, vec![0x01, 0x03]);
+    let device = BluetoothLESpecifier::new_from_advertisement("Device Name", &[], &data);
+    assert_ne!(config_specifier, device);
+    // Company has to match.
+    let mut data = HashMap::new();
+    data.insert(0x4321, vec![0x01, 0x02]);
+    let device = BluetoothLESpecifier::new_from_advertisement("Device Name", &[], &data);
+    assert_ne!(config_specifier, device);
+    // An empty prefix matches on the company alone.
+    config_specifier.manufacturer_data = vec![BluetoothLEManufacturerData::new(0x4321, &[])];
+    assert_eq!(config_specifier, device);
+  }
+
+  #[test]
+  fn test_usb_specifier_equals() {
+    let config_specifier: USBSpecifier = serde_json::from_str(
+      r#"{"vendor-id": 2889, "product-id": 1615, "interface": 1, "endpoints": {"tx": 2, "rx": 129}}"#,
+    )
+    .expect("Test, assuming infallible");
+    assert_eq!(config_specifier.interface(), 1);
+    assert_eq!(config_specifier.endpoints().get(&Endpoint::Tx), Some(&0x02));
+    assert_eq!(config_specifier.endpoints().get(&Endpoint::Rx), Some(&0x81));
+    // Found devices only know their VID/PID, which is all we match on.
+    assert_eq!(config_specifier, USBSpecifier::new(0x0b49, 0x064f));
+    assert_ne!(config_specifier, USBSpecifier::new(0x0b49, 0x0650));
+  }
+
   #[test]
   fn test_specific_device_config_creation() {
     let config = create_test_dcm(false);
@@ -655,6 +1071,38 @@ mod test {
     assert!(message_map.contains_key(&ButtplugDeviceMessageType::RawUnsubscribeCmd));
   }
 
+  #[test]
+  fn test_user_config_raw_message_override() {
+    let config = create_test_dcm(false);
+    let lovense =
+      DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device("LVS-Whatever", &[]));
+    let proto = config
+      .find_protocol_definitions(&lovense)
+      .expect("Test, assuming infallible");
+    let mut user_config = DeviceUserConfig::default();
+    user_config.set_allow_raw_messages(Some(true));
+    let mut proto_config = DeviceProtocolConfiguration::new(
+      false,
+      proto.2.defaults.clone(),
+      proto.2.configurations.clone(),
+    );
+    proto_config.set_user_config(user_config.clone());
+    let (_, message_map) = proto_config
+      .get_attributes("P", &vec![])
+      .expect("Test, assuming infallible");
+    assert!(message_map.contains_key(&ButtplugDeviceMessageType::RawWriteCmd));
+
+    // Works the other way too, denying raw for a device when the server allows it.
+    user_config.set_allow_raw_messages(Some(false));
+    let mut proto_config =
+      DeviceProtocolConfiguration::new(true, proto.2.defaults.clone(), proto.2.configurations);
+    proto_config.set_user_config(user_config);
+    let (_, message_map) = proto_config
+      .get_attributes("P", &vec![])
+      .expect("Test, assuming infallible");
+    assert!(!message_map.contains_key(&ButtplugDeviceMessageType::RawWriteCmd));
+  }
+
   #[test]
   fn test_non_raw_device_config_creation() {
     let config = create_test_dcm(false);
@@ -741,6 +1189,153 @@ mod test {
       .any(|x| x.port == "COM1"));
   }
 
+  fn write_test_config_file(name: &str, config: serde_json::Value) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("buttplug-{}-{}.json", name, std::process::id()));
+    std::fs::write(&path, config.to_string()).expect("Test, assuming infallible");
+    path
+  }
+
+  #[test]
+  fn test_load_device_configuration_file() {
+    let mut config_json: serde_json::Value =
+      serde_json::from_str(DEVICE_CONFIGURATION_JSON).expect("Test, assuming infallible");
+    let mut aneros = config_json["protocols"]["aneros"].clone();
+    aneros["btle"]["names"] = serde_json::json!(["External Aneros"]);
+    let path = write_test_config_file(
+      "external-device-config",
+      serde_json::json!({
+        "version": config_json["version"].take(),
+        "protocols": {
+          "aneros": aneros
+        }
+      }),
+    );
+    let config = DeviceConfigurationManager::default();
+    config
+      .load_device_configuration_file(&path)
+      .expect("Test, assuming infallible");
+    let _ = std::fs::remove_file(&path);
+    let external = DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device(
+      "External Aneros",
+      &[],
+    ));
+    let (_, protocol_name, _) = config
+      .find_protocol_definitions(&external)
+      .expect("Test, assuming infallible");
+    assert_eq!(protocol_name, "aneros");
+    // The external definition replaces the built in one.
+    let massage_demo =
+      DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device("Massage Demo", &[]));
+    assert!(config.find_protocol_definitions(&massage_demo).is_none());
+    // Protocols the external file doesn't mention come from the built in config.
+    let launch = DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device("Launch", &[]));
+    assert!(config.find_protocol_definitions(&launch).is_some());
+  }
+
+  #[test]
+  fn test_load_device_configuration_file_version_checks() {
+    let config = DeviceConfigurationManager::default();
+    let path = write_test_config_file(
+      "newer-schema-device-config",
+      serde_json::json!({
+        "version": get_internal_config_version(),
+        "schema-version": get_internal_config_schema_version() + 1,
+        "some-future-section": {}
+      }),
+    );
+    let result = config.load_device_configuration_file(&path);
+    let _ = std::fs::remove_file(&path);
+    assert!(matches!(
+      result,
+      Err(ButtplugError::ButtplugDeviceError(
+        ButtplugDeviceError::DeviceConfigurationFileError(msg)
+      )) if msg.contains("schema version")
+    ));
+
+    let path = write_test_config_file(
+      "older-device-config",
+      serde_json::json!({
+        "version": get_internal_config_version() - 1
+      }),
+    );
+    let result = config.load_device_configuration_file(&path);
+    let _ = std::fs::remove_file(&path);
+    assert!(result.is_err());
+    assert!(config
+      .load_device_configuration_file(std::env::temp_dir().join("buttplug-missing-config.json"))
+      .is_err());
+    // Nothing should have been loaded by any of the failed attempts.
+    assert!(config.protocol_definitions().is_empty());
+  }
+
+  #[test]
+  fn test_user_device_config_file_round_trip() {
+    let config_json = serde_json::json!({
+      "version": 1,
+      "some-future-section": { "a": 1 },
+      "user-config": {
+        "a1:b2": {
+          "display-name": "Old Name",
+          "some-future-setting": true
+        }
+      },
+      "protocols": {
+        "nobra": {
+          "serial": [{ "port": "COM1" }]
+        }
+      }
+    });
+    let mut file =
+      UserDeviceConfigFile::from_json(&config_json.to_string()).expect("Test, assuming infallible");
+    file
+      .device_config_mut("a1:b2")
+      .set_display_name(Some("New Name".to_owned()));
+    let new_device = file.device_config_mut("c3:d4");
+    new_device.set_deny(Some(true));
+    new_device.set_intensity_limit(Some(0.25));
+    let saved: serde_json::Value =
+      serde_json::from_str(&file.to_json()).expect("Test, assuming infallible");
+    assert_eq!(
+      saved["some-future-section"],
+      config_json["some-future-section"]
+    );
+    assert_eq!(saved["protocols"], config_json["protocols"]);
+    assert_eq!(saved["user-config"]["a1:b2"]["display-name"], "New Name");
+    assert_eq!(saved["user-config"]["a1:b2"]["some-future-setting"], true);
+    assert_eq!(saved["user-config"]["c3:d4"]["deny"], true);
+    assert_eq!(saved["user-config"]["c3:d4"]["intensity-limit"], 0.25);
+    assert!(saved["user-config"]["c3:d4"].get("allow").is_none());
+
+    let reloaded =
+      UserDeviceConfigFile::from_json(&file.to_json()).expect("Test, assuming infallible");
+    assert_eq!(reloaded, file);
+    assert!(UserDeviceConfigFile::from_json("not json").is_err());
+  }
+
+  #[test]
+  fn test_bluetoothle_scan_filters() {
+    let config = create_test_dcm(false);
+    let filters = config.bluetoothle_scan_filters();
+    let massage_demo = BluetoothLEScanFilter {
+      name: Some("Massage Demo".to_owned()),
+      ..Default::default()
+    };
+    let lovense = BluetoothLEScanFilter {
+      name_prefix: Some("LVS-".to_owned()),
+      ..Default::default()
+    };
+    assert!(filters.filters.contains(&massage_demo));
+    assert!(filters.filters.contains(&lovense));
+    assert!(filters.optional_services.contains(
+      &Uuid::parse_str("0000ff00-0000-1000-8000-00805f9b34fb").expect("Test, assuming infallible")
+    ));
+    let json = serde_json::to_value(&filters).expect("Test, assuming infallible");
+    assert!(json["filters"]
+      .as_array()
+      .expect("Test, assuming infallible")
+      .contains(&serde_json::json!({ "namePrefix": "LVS-" })));
+  }
+
   // TODO Test invalid config load (not json)
   // TODO Test invalid user config load (not json)
   // TODO Test device config with repeated ble service
@@ -0,0 +1,1276 @@
+use super::{
+  command_queue::{DeviceCommandQueue, DeviceCommandQueueOptions},
+  keepalive::DeviceKeepalive,
+  traffic_diagnostics::{DeviceTrafficDirection, DeviceTrafficRecord, DeviceTrafficTap},
+  DeviceInspectionReport,
+  Endpoint,
+};
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::{
+      self,
+      ButtplugDeviceCommandMessageUnion,
+      ButtplugDeviceMessage,
+      ButtplugMessage,
+      ButtplugDeviceMessageType,
+      ButtplugServerMessage,
+      DeviceMessageAttributesMap,
+      OscillateCmd,
+      OscillateSubcommand,
+      RawReadCmd,
+      RawReading,
+      RawSubscribeCmd,
+      RawUnsubscribeCmd,
+      RawWriteCmd,
+      RotateCmd,
+      RotationSubcommand,
+      SensorReading,
+      SharedDeviceMessageAttributesMap,
+      SingleMotorVibrateCmd,
+      StopDeviceCmd,
+      VibrateCmd,
+      VibrateSubcommand,
+      WriteConfirmation,
+    },
+    ButtplugResultFuture,
+  },
+  device::{
+    configuration_manager::{
+      DeviceConfigurationManager,
+      DeviceProtocolConfiguration,
+      DeviceSpecifier,
+      ProtocolDefinition,
+    },
+    protocol::{linear_motion::LaunchTranslator, ButtplugProtocol},
+  },
+  server::device_manager::DeviceUserConfig,
+  util::message_tracing::message_span,
+};
+use async_trait::async_trait;
+use core::hash::{Hash, Hasher};
+use futures::{
+  future::{self, BoxFuture},
+  FutureExt,
+};
+use futures_timer::Delay;
+use std::{
+  fmt::{self, Debug},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+    RwLock,
+  },
+  time::Duration,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{info_span, Instrument, Span};
+
+pub type ButtplugDeviceResultFuture =
+  BoxFuture<'static, Result<ButtplugServerMessage, ButtplugError>>;
+
+#[derive(PartialEq, Debug)]
+pub struct DeviceReadCmd {
+  pub endpoint: Endpoint,
+  pub length: u32,
+  pub timeout_ms: u32,
+}
+
+impl DeviceReadCmd {
+  pub fn new(endpoint: Endpoint, length: u32, timeout_ms: u32) -> Self {
+    Self {
+      endpoint,
+      length,
+      timeout_ms,
+    }
+  }
+}
+
+impl From<RawReadCmd> for DeviceReadCmd {
+  fn from(msg: RawReadCmd) -> Self {
+    Self {
+      endpoint: msg.endpoint(),
+      length: msg.expected_length(),
+      timeout_ms: msg.timeout(),
+    }
+  }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct DeviceWriteCmd {
+  pub endpoint: Endpoint,
+  pub data: Vec<u8>,
+  pub write_with_response: bool,
+  /// True for writes that came from a RawWriteCmd. Device impls that know
+  /// their maximum write size reject raw writes longer than it, unless
+  /// `chunked` is set.
+  pub raw: bool,
+  /// If true, device impls may split data that's too long for a single write
+  /// across multiple writes. Only ever set for raw writes, and only if the
+  /// device's user config turns on chunk-raw-writes, as protocols build
+  /// packets that need to go out as a single write.
+  pub chunked: bool,
+}
+
+impl DeviceWriteCmd {
+  pub fn new(endpoint: Endpoint, data: Vec<u8>, write_with_response: bool) -> Self {
+    Self {
+      endpoint,
+      data,
+      write_with_response,
+      raw: false,
+      chunked: false,
+    }
+  }
+}
+
+impl From<RawWriteCmd> for DeviceWriteCmd {
+  fn from(msg: RawWriteCmd) -> Self {
+    Self {
+      endpoint: msg.endpoint(),
+      data: msg.data().clone(),
+      write_with_response: msg.write_with_response(),
+      raw: true,
+      chunked: false,
+    }
+  }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct DeviceSubscribeCmd {
+  pub endpoint: Endpoint,
+}
+
+impl DeviceSubscribeCmd {
+  pub fn new(endpoint: Endpoint) -> Self {
+    Self { endpoint }
+  }
+}
+
+impl From<RawSubscribeCmd> for DeviceSubscribeCmd {
+  fn from(msg: RawSubscribeCmd) -> Self {
+    Self {
+      endpoint: msg.endpoint(),
+    }
+  }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct DeviceUnsubscribeCmd {
+  pub endpoint: Endpoint,
+}
+
+impl DeviceUnsubscribeCmd {
+  pub fn new(endpoint: Endpoint) -> Self {
+    Self { endpoint }
+  }
+}
+
+impl From<RawUnsubscribeCmd> for DeviceUnsubscribeCmd {
+  fn from(msg: RawUnsubscribeCmd) -> Self {
+    Self {
+      endpoint: msg.endpoint(),
+    }
+  }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum DeviceImplCommand {
+  // Endpoint, data, write with response
+  Write(DeviceWriteCmd),
+  // Endpoint, length, timeout in ms
+  Read(DeviceReadCmd),
+  Subscribe(DeviceSubscribeCmd),
+  Unsubscribe(DeviceUnsubscribeCmd),
+}
+
+impl From<RawWriteCmd> for DeviceImplCommand {
+  fn from(msg: RawWriteCmd) -> Self {
+    DeviceImplCommand::Write(msg.into())
+  }
+}
+
+impl From<RawSubscribeCmd> for DeviceImplCommand {
+  fn from(msg: RawSubscribeCmd) -> Self {
+    DeviceImplCommand::Subscribe(msg.into())
+  }
+}
+
+impl From<RawUnsubscribeCmd> for DeviceImplCommand {
+  fn from(msg: RawUnsubscribeCmd) -> Self {
+    DeviceImplCommand::Unsubscribe(msg.into())
+  }
+}
+
+impl From<DeviceReadCmd> for DeviceImplCommand {
+  fn from(msg: DeviceReadCmd) -> Self {
+    DeviceImplCommand::Read(msg)
+  }
+}
+
+impl From<DeviceWriteCmd> for DeviceImplCommand {
+  fn from(msg: DeviceWriteCmd) -> Self {
+    DeviceImplCommand::Write(msg)
+  }
+}
+
+impl From<DeviceSubscribeCmd> for DeviceImplCommand {
+  fn from(msg: DeviceSubscribeCmd) -> Self {
+    DeviceImplCommand::Subscribe(msg)
+  }
+}
+
+impl From<DeviceUnsubscribeCmd> for DeviceImplCommand {
+  fn from(msg: DeviceUnsubscribeCmd) -> Self {
+    DeviceImplCommand::Unsubscribe(msg)
+  }
+}
+
+#[derive(Debug)]
+pub struct ButtplugDeviceImplInfo {
+  pub endpoints: Vec<Endpoint>,
+  pub manufacturer_name: Option<String>,
+  pub product_name: Option<String>,
+  pub serial_number: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ButtplugDeviceCommand {
+  Connect,
+  Message(DeviceImplCommand),
+  Disconnect,
+}
+
+// TODO Split this down into connections and other returns.
+#[derive(Debug)]
+pub enum ButtplugDeviceReturn {
+  Connected(ButtplugDeviceImplInfo),
+  Ok(messages::Ok),
+  RawReading(messages::RawReading),
+  Error(ButtplugError),
+}
+
+#[derive(Debug, Clone)]
+pub enum ButtplugDeviceEvent {
+  Connected(Arc<ButtplugDevice>),
+  Notification(String, Endpoint, Vec<u8>),
+  Removed(String),
+}
+pub struct DeviceImpl {
+  name: String,
+  address: String,
+  endpoints: Vec<Endpoint>,
+  internal_impl: Box<dyn DeviceImplInternal>,
+  traffic_tap: DeviceTrafficTap,
+  // Wrappers share the wrapped device's tap, but leave recording to it so
+  // traffic isn't recorded twice.
+  records_traffic: bool,
+  // Shared with wrappers, so it applies however the device is reached.
+  chunk_raw_writes: Arc<AtomicBool>,
+}
+
+impl DeviceImpl {
+  pub fn new(
+    name: &str,
+    address: &str,
+    endpoints: &[Endpoint],
+    internal_impl: Box<dyn DeviceImplInternal>,
+  ) -> Self {
+    Self {
+      name: name.to_owned(),
+      address: address.to_owned(),
+      endpoints: endpoints.into(),
+      internal_impl,
+      traffic_tap: DeviceTrafficTap::default(),
+      records_traffic: true,
+      chunk_raw_writes: Arc::new(AtomicBool::new(false)),
+    }
+  }
+
+  /// Creates a device with the same identity as `device`, for wrappers whose
+  /// `internal_impl` forwards to it.
+  pub(super) fn new_wrapper(
+    device: &DeviceImpl,
+    internal_impl: Box<dyn DeviceImplInternal>,
+  ) -> Self {
+    Self {
+      name: device.name.clone(),
+      address: device.address.clone(),
+      endpoints: device.endpoints.clone(),
+      internal_impl,
+      traffic_tap: device.traffic_tap.clone(),
+      records_traffic: false,
+      chunk_raw_writes: device.chunk_raw_writes.clone(),
+    }
+  }
+
+  /// Creates a device that shares the connection of `device`, but sends all
+  /// writes with response and waits for the device to echo them back, as
+  /// configured by `confirmation`. See [ConfirmedWriteDeviceImpl].
+  pub fn new_with_confirmed_writes(
+    device: Arc<DeviceImpl>,
+    confirmation: WriteConfirmation,
+  ) -> Self {
+    Self::new_wrapper(
+      &device,
+      Box::new(ConfirmedWriteDeviceImpl::new(device.clone(), confirmation)),
+    )
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn address(&self) -> &str {
+    &self.address
+  }
+
+  pub fn connected(&self) -> bool {
+    self.internal_impl.connected()
+  }
+
+  pub fn event_stream(&self) -> broadcast::Receiver<ButtplugDeviceEvent> {
+    self.internal_impl.event_stream()
+  }
+
+  pub fn endpoints(&self) -> Vec<Endpoint> {
+    self.endpoints.clone()
+  }
+
+  pub fn disconnect(&self) -> ButtplugResultFuture {
+    self.internal_impl.disconnect()
+  }
+
+  pub fn read_value(
+    &self,
+    msg: DeviceReadCmd,
+  ) -> BoxFuture<'static, Result<RawReading, ButtplugError>> {
+    let span = message_span(|| info_span!("Device Read", endpoint = %msg.endpoint));
+    let read = self.internal_impl.read_value(msg).instrument(span);
+    if !self.records_traffic {
+      return Box::pin(read);
+    }
+    let traffic_tap = self.traffic_tap.clone();
+    let address = self.address.clone();
+    Box::pin(async move {
+      let reading = read.await?;
+      traffic_tap.record(
+        &address,
+        reading.endpoint(),
+        DeviceTrafficDirection::Read,
+        reading.data(),
+      );
+      Ok(reading)
+    })
+  }
+
+  pub fn write_value(&self, mut msg: DeviceWriteCmd) -> ButtplugResultFuture {
+    if msg.raw && self.chunk_raw_writes.load(Ordering::SeqCst) {
+      msg.chunked = true;
+    }
+    // Whichever wrapper sees the write first reports it, so e.g. confirmed
+    // writes don't wait on a read back that will never match.
+    if self.traffic_tap.record_dry_run_write(&self.address, &msg) {
+      return Box::pin(future::ready(Ok(())));
+    }
+    if self.records_traffic {
+      self.traffic_tap.record(
+        &self.address,
+        msg.endpoint,
+        DeviceTrafficDirection::Write,
+        &msg.data,
+      );
+    }
+    let span = message_span(|| {
+      info_span!(
+        "Device Write",
+        endpoint = %msg.endpoint,
+        length = msg.data.len()
+      )
+    });
+    Box::pin(self.internal_impl.write_value(msg).instrument(span))
+  }
+
+  pub fn subscribe(&self, msg: DeviceSubscribeCmd) -> ButtplugResultFuture {
+    let span = message_span(|| info_span!("Device Subscribe", endpoint = %msg.endpoint));
+    Box::pin(self.internal_impl.subscribe(msg).instrument(span))
+  }
+
+  pub fn unsubscribe(&self, msg: DeviceUnsubscribeCmd) -> ButtplugResultFuture {
+    let span = message_span(|| info_span!("Device Unsubscribe", endpoint = %msg.endpoint));
+    Box::pin(self.internal_impl.unsubscribe(msg).instrument(span))
+  }
+
+  /// Mirrors this device's traffic to `sender`, tagged with `device_index`,
+  /// or stops mirroring if `sink` is None. See
+  /// [traffic_diagnostics][super::traffic_diagnostics].
+  pub fn set_traffic_diagnostics(
+    &self,
+    sink: Option<(u32, broadcast::Sender<DeviceTrafficRecord>)>,
+  ) {
+    self.traffic_tap.set_sink(self, sink);
+  }
+
+  /// Puts this device in dry run mode, where writes are reported to `sender`,
+  /// tagged with `device_index`, instead of being sent to the device. Takes
+  /// the device out of dry run mode if `sink` is None.
+  pub fn set_dry_run(&self, sink: Option<(u32, broadcast::Sender<DeviceTrafficRecord>)>) {
+    self.traffic_tap.set_dry_run_sink(sink);
+  }
+
+  /// If true, raw writes too long to go out in one write are split across
+  /// several, instead of being rejected. Off by default, as splitting only
+  /// works for devices that put the pieces back together themselves.
+  pub fn set_chunk_raw_writes(&self, chunk: bool) {
+    self.chunk_raw_writes.store(chunk, Ordering::SeqCst);
+  }
+}
+
+pub trait DeviceImplInternal: Sync + Send {
+  fn connected(&self) -> bool;
+  fn disconnect(&self) -> ButtplugResultFuture;
+  // Ugh. Don't want to have to pass these around internally, but don't have a
+  // better solution yet.
+  fn event_stream(&self) -> broadcast::Receiver<ButtplugDeviceEvent>;
+  fn read_value(&self, msg: DeviceReadCmd)
+    -> BoxFuture<'static, Result<RawReading, ButtplugError>>;
+  fn write_value(&self, msg: DeviceWriteCmd) -> ButtplugResultFuture;
+  fn subscribe(&self, msg: DeviceSubscribeCmd) -> ButtplugResultFuture;
+  fn unsubscribe(&self, msg: DeviceUnsubscribeCmd) -> ButtplugResultFuture;
+}
+
+/// Number of times a confirmed write is attempted before giving up.
+pub const CONFIRMED_WRITE_ATTEMPTS: u32 = 3;
+const CONFIRMED_WRITE_READ_TIMEOUT_MS: u32 = 500;
+
+/// Device implementation wrapper that turns every write into a write with
+/// response, then waits for the device to echo the written data back on the
+/// endpoint given in its [WriteConfirmation], retrying up to
+/// [CONFIRMED_WRITE_ATTEMPTS] times. Used for stop commands on devices where
+/// losing a stop is dangerous, so only the device owning the endpoint needs
+/// to support confirmation, not every protocol.
+pub struct ConfirmedWriteDeviceImpl {
+  device: Arc<DeviceImpl>,
+  confirmation: WriteConfirmation,
+}
+
+impl ConfirmedWriteDeviceImpl {
+  pub fn new(device: Arc<DeviceImpl>, confirmation: WriteConfirmation) -> Self {
+    Self {
+      device,
+      confirmation,
+    }
+  }
+}
+
+/// Waits for the next notification from `endpoint`, up to
+/// [CONFIRMED_WRITE_READ_TIMEOUT_MS].
+async fn next_notification(
+  mut events: broadcast::Receiver<ButtplugDeviceEvent>,
+  endpoint: Endpoint,
+) -> Result<Vec<u8>, ButtplugError> {
+  let notification = async move {
+    loop {
+      match events.recv().await {
+        Ok(ButtplugDeviceEvent::Notification(_, notify_endpoint, data))
+          if notify_endpoint == endpoint =>
+        {
+          return Ok(data);
+        }
+        Ok(_) | Err(RecvError::Lagged(_)) => continue,
+        Err(RecvError::Closed) => {
+          return Err(
+            ButtplugDeviceError::DeviceCommunicationError(format!(
+              "Device event stream closed while waiting on {}",
+              endpoint
+            ))
+            .into(),
+          )
+        }
+      }
+    }
+  };
+  select! {
+    result = notification.fuse() => result,
+    _ = Delay::new(Duration::from_millis(CONFIRMED_WRITE_READ_TIMEOUT_MS.into())).fuse() => Err(
+      ButtplugDeviceError::DeviceCommunicationError(format!(
+        "No notification from {} within {}ms",
+        endpoint, CONFIRMED_WRITE_READ_TIMEOUT_MS
+      ))
+      .into(),
+    ),
+  }
+}
+
+impl DeviceImplInternal for ConfirmedWriteDeviceImpl {
+  fn connected(&self) -> bool {
+    self.device.connected()
+  }
+
+  fn disconnect(&self) -> ButtplugResultFuture {
+    self.device.disconnect()
+  }
+
+  fn event_stream(&self) -> broadcast::Receiver<ButtplugDeviceEvent> {
+    self.device.event_stream()
+  }
+
+  fn read_value(
+    &self,
+    msg: DeviceReadCmd,
+  ) -> BoxFuture<'static, Result<RawReading, ButtplugError>> {
+    self.device.read_value(msg)
+  }
+
+  fn write_value(&self, msg: DeviceWriteCmd) -> ButtplugResultFuture {
+    let device = self.device.clone();
+    let confirmation = self.confirmation.clone();
+    Box::pin(async move {
+      if confirmation.notify {
+        // Left subscribed afterwards, as the protocol may be using the
+        // endpoint too.
+        device
+          .subscribe(DeviceSubscribeCmd::new(confirmation.endpoint))
+          .await?;
+      }
+      for attempt in 1..=CONFIRMED_WRITE_ATTEMPTS {
+        // Listen before writing, so an echo that comes back right away isn't
+        // missed.
+        let events = device.event_stream();
+        let write_msg = DeviceWriteCmd::new(msg.endpoint, msg.data.clone(), true);
+        if let Err(err) = device.write_value(write_msg).await {
+          warn!(
+            "Confirmed write to {} failed on attempt {}: {:?}",
+            msg.endpoint, attempt, err
+          );
+          continue;
+        }
+        let echo = if confirmation.notify {
+          next_notification(events, confirmation.endpoint).await
+        } else {
+          device
+            .read_value(DeviceReadCmd::new(
+              confirmation.endpoint,
+              msg.data.len() as u32,
+              CONFIRMED_WRITE_READ_TIMEOUT_MS,
+            ))
+            .await
+            .map(|reading| reading.data().clone())
+        };
+        match echo {
+          Ok(data) if data == msg.data => return Ok(()),
+          Ok(data) => warn!(
+            "Confirmed write to {} got {:?} back from {} instead of {:?} on attempt {}",
+            msg.endpoint, data, confirmation.endpoint, msg.data, attempt
+          ),
+          Err(err) => warn!(
+            "Confirmed write to {} got nothing back from {} on attempt {}: {:?}",
+            msg.endpoint, confirmation.endpoint, attempt, err
+          ),
+        }
+      }
+      error!(
+        "Device {} did not confirm write to {}, giving up.",
+        device.name(),
+        msg.endpoint
+      );
+      Err(
+        ButtplugDeviceError::DeviceWriteNotConfirmed(msg.endpoint, CONFIRMED_WRITE_ATTEMPTS).into(),
+      )
+    })
+  }
+
+  fn subscribe(&self, msg: DeviceSubscribeCmd) -> ButtplugResultFuture {
+    self.device.subscribe(msg)
+  }
+
+  fn unsubscribe(&self, msg: DeviceUnsubscribeCmd) -> ButtplugResultFuture {
+    self.device.unsubscribe(msg)
+  }
+}
+
+#[async_trait]
+pub trait ButtplugDeviceImplCreator: Sync + Send + Debug {
+  fn get_specifier(&self) -> DeviceSpecifier;
+  /// Connects to the device and sets up its endpoints for `protocol`.
+  /// Connection failures should be returned as
+  /// [ButtplugDeviceConnectionError][crate::core::errors::ButtplugDeviceConnectionError]s,
+  /// so the device manager can tell which ones are worth retrying.
+  async fn try_create_device_impl(
+    &mut self,
+    protocol: ProtocolDefinition,
+  ) -> Result<DeviceImpl, ButtplugError>;
+  /// Reports what the comm manager knows about a device that didn't match any
+  /// protocol. If `connect` is true, the creator may connect to the device to
+  /// list its services, but must disconnect before returning. Returns None if
+  /// the comm manager has nothing worth reporting.
+  async fn inspect(&mut self, _connect: bool) -> Option<DeviceInspectionReport> {
+    None
+  }
+}
+
+/// Scales the speeds in vibrate, rotate and oscillate commands by `limit`.
+fn apply_intensity_limit(
+  message: ButtplugDeviceCommandMessageUnion,
+  limit: f64,
+) -> ButtplugDeviceCommandMessageUnion {
+  match message {
+    ButtplugDeviceCommandMessageUnion::VibrateCmd(msg) => {
+      let mut limited = VibrateCmd::new(
+        msg.device_index(),
+        msg
+          .speeds()
+          .iter()
+          .map(|cmd| VibrateSubcommand::new(cmd.index(), cmd.speed() * limit))
+          .collect(),
+      );
+      limited.set_id(msg.id());
+      limited.into()
+    }
+    ButtplugDeviceCommandMessageUnion::RotateCmd(msg) => {
+      let mut limited = RotateCmd::new(
+        msg.device_index(),
+        msg
+          .rotations
+          .iter()
+          .map(|cmd| RotationSubcommand::new(cmd.index(), cmd.speed() * limit, cmd.clockwise()))
+          .collect(),
+      );
+      limited.set_id(msg.id());
+      limited.into()
+    }
+    ButtplugDeviceCommandMessageUnion::OscillateCmd(msg) => {
+      let mut limited = OscillateCmd::new(
+        msg.device_index(),
+        msg
+          .speeds()
+          .iter()
+          .map(|cmd| OscillateSubcommand::new(cmd.index(), cmd.speed() * limit))
+          .collect(),
+      );
+      limited.set_id(msg.id());
+      limited.into()
+    }
+    message => message,
+  }
+}
+
+pub struct ButtplugDevice {
+  protocol: Arc<dyn ButtplugProtocol>,
+  // Name of the protocol config the device was matched with.
+  protocol_name: String,
+  device: Arc<DeviceImpl>,
+  display_name: Option<String>,
+  command_queue: DeviceCommandQueue,
+  // Set for devices that support LinearCmd but not the Launch's own commands,
+  // so FleshlightLaunchFW12Cmd can be sent to them as LinearCmd.
+  launch_translator: Option<Arc<LaunchTranslator>>,
+  // Intensity limit from the device's user config, if any.
+  intensity_limit: RwLock<Option<f64>>,
+  // Protocol attributes plus any translated messages, shared with other
+  // devices of the same model. Rebuilt when user config changes.
+  message_attributes: RwLock<SharedDeviceMessageAttributesMap>,
+}
+
+impl Debug for ButtplugDevice {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("ButtplugDevice")
+      .field("name", &self.name())
+      .field("address", &self.address())
+      .finish()
+  }
+}
+
+impl Hash for ButtplugDevice {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.device.address().hash(state);
+  }
+}
+
+impl Eq for ButtplugDevice {
+}
+
+impl PartialEq for ButtplugDevice {
+  fn eq(&self, other: &Self) -> bool {
+    self.device.address() == other.device.address()
+  }
+}
+
+/// Attributes for a device using `protocol`, shared between devices of the
+/// same model.
+fn interned_message_attributes(
+  protocol_name: &str,
+  protocol: &dyn ButtplugProtocol,
+  translates_launch_commands: bool,
+) -> SharedDeviceMessageAttributesMap {
+  let mut attributes = protocol.message_attributes();
+  if translates_launch_commands {
+    attributes
+      .entry(ButtplugDeviceMessageType::FleshlightLaunchFW12Cmd)
+      .or_default();
+  }
+  SharedDeviceMessageAttributesMap::interned(
+    &format!("{}/{}", protocol_name, protocol.name()),
+    attributes,
+  )
+}
+
+impl ButtplugDevice {
+  pub fn new(
+    protocol_name: &str,
+    protocol: Box<dyn ButtplugProtocol>,
+    device: Arc<DeviceImpl>,
+  ) -> Self {
+    let launch_translator = if protocol
+      .message_attributes()
+      .contains_key(&ButtplugDeviceMessageType::LinearCmd)
+    {
+      protocol
+        .fleshlight_launch_calibration()
+        .map(|calibration| Arc::new(LaunchTranslator::new(calibration)))
+    } else {
+      None
+    };
+    let message_attributes = RwLock::new(interned_message_attributes(
+      protocol_name,
+      protocol.as_ref(),
+      launch_translator.is_some(),
+    ));
+    Self {
+      protocol: Arc::from(protocol),
+      protocol_name: protocol_name.to_owned(),
+      device,
+      display_name: None,
+      command_queue: DeviceCommandQueue::new(DeviceCommandQueueOptions::default()),
+      launch_translator,
+      intensity_limit: RwLock::new(None),
+      message_attributes,
+    }
+  }
+
+  pub fn address(&self) -> &str {
+    self.device.address()
+  }
+
+  /// Name of the protocol the device was matched with, as it appears in the
+  /// device configuration file.
+  pub fn protocol_name(&self) -> &str {
+    &self.protocol_name
+  }
+
+  pub async fn try_create_device(
+    device_config_mgr: Arc<DeviceConfigurationManager>,
+    device_creator: &mut dyn ButtplugDeviceImplCreator,
+    user_config: Option<DeviceUserConfig>,
+  ) -> Result<Option<ButtplugDevice>, ButtplugError> {
+    // First off, we need to see if we even have a configuration available
+    // for the device we're trying to create. If we don't, return Ok(None),
+    // because this isn't actually an error. However, if we *do* have a
+    // configuration but something goes wrong after this, then it's an
+    // error.
+    let candidates: Vec<_> = device_config_mgr
+      .find_all_protocol_definitions(&device_creator.get_specifier())
+      .into_iter()
+      .filter(|(_, config_name, _)| {
+        // TODO Should we even return a config from the device_config_mgr if the
+        // protocol isn't there?
+        let has_protocol = device_config_mgr.has_protocol(config_name);
+        if !has_protocol {
+          info!("Protocol {} not available", config_name);
+        }
+        has_protocol
+      })
+      .collect();
+    let first_config = match candidates.first() {
+      Some((_, _, config)) => config.clone(),
+      None => return Ok(None),
+    };
+    // Now that we have both a possible device implementation and a
+    // configuration for that device, try to initialize the implementation.
+    // This usually means trying to connect to whatever the device is,
+    // finding endpoints, etc. Comm managers can only connect once, so this
+    // uses the first candidate's configuration, and all candidates share the
+    // resulting device implementation.
+    let device_impl = device_creator.try_create_device_impl(first_config).await?;
+    info!(
+      address = tracing::field::display(device_impl.address()),
+      "Found Buttplug Device {}",
+      device_impl.name()
+    );
+    let intensity_limit = user_config
+      .as_ref()
+      .and_then(|config| *config.intensity_limit());
+    device_impl.set_chunk_raw_writes(
+      user_config
+        .as_ref()
+        .and_then(|config| *config.chunk_raw_writes())
+        .unwrap_or(false),
+    );
+    // If we've made it this far, we now have a connected device
+    // implementation with endpoints set up. We now need to run whatever
+    // protocol initialization might need to happen. We'll fetch a protocol
+    // creator, pass the device implementation to it, then let it do
+    // whatever it needs. For most protocols, this is a no-op. However, for
+    // devices like Lovense, some Kiiroo, etc, this can get fairly
+    // complicated.
+    //
+    // Some product lines share advertised names across protocols, so if a
+    // protocol fails to initialize (i.e. it's the wrong variant), fall back
+    // to the next matching protocol.
+    let sharable_device_impl = Arc::new(device_impl);
+    let mut last_error = None;
+    for (allow_raw_messages, config_name, config) in candidates {
+      let mut device_protocol_config = DeviceProtocolConfiguration::new(
+        allow_raw_messages,
+        config.defaults.clone(),
+        config.configurations.clone(),
+      );
+      if let Some(user_config) = &user_config {
+        device_protocol_config.set_user_config(user_config.clone());
+      }
+      let protocol_factory = device_config_mgr
+        .get_protocol_creator(&config_name)
+        .expect("Already checked for protocol existence");
+      info!(
+        "Trying protocol {} for device {}",
+        config_name,
+        sharable_device_impl.name()
+      );
+      // Protocols with a keepalive get a device that tracks their writes, so
+      // keepalives are only sent when the protocol has gone quiet.
+      let keepalive = config.keepalive.clone().map(DeviceKeepalive::new);
+      let protocol_device_impl = match &keepalive {
+        Some(keepalive) => keepalive.wrap_device(sharable_device_impl.clone()),
+        None => sharable_device_impl.clone(),
+      };
+      match protocol_factory
+        .try_create(protocol_device_impl.clone(), device_protocol_config)
+        .await
+      {
+        Ok(protocol_impl) => {
+          if let Some(keepalive) = keepalive {
+            keepalive.start(sharable_device_impl);
+          }
+          let device = ButtplugDevice::new(&config_name, protocol_impl, protocol_device_impl);
+          device.set_intensity_limit(intensity_limit);
+          return Ok(Some(device));
+        }
+        Err(err) => {
+          warn!(
+            "Protocol {} failed to initialize device {}: {}",
+            config_name,
+            sharable_device_impl.name(),
+            err
+          );
+          last_error = Some(err);
+        }
+      }
+    }
+    // Nothing matched, so let go of the device before reporting the last
+    // failure.
+    if let Err(err) = sharable_device_impl.disconnect().await {
+      debug!(
+        "Error disconnecting device after failed initialization: {}",
+        err
+      );
+    }
+    Err(last_error.expect("Always have at least one candidate here"))
+  }
+
+  pub fn set_display_name(&mut self, name: &str) {
+    info!(
+      "Adding display name {} to device {} ({})",
+      name,
+      self.name(),
+      self.address()
+    );
+    self.display_name = Some(name.to_owned());
+  }
+
+  pub fn display_name(&self) -> Option<String> {
+    self.display_name.clone()
+  }
+
+  pub fn set_command_queue_options(&mut self, options: DeviceCommandQueueOptions) {
+    self.command_queue.set_options(options);
+  }
+
+  /// Number of commands waiting to be sent to the device.
+  pub fn command_queue_depth(&self) -> usize {
+    self.command_queue.depth()
+  }
+
+  pub fn name(&self) -> String {
+    // Instead of checking for raw messages at the protocol level, add the raw
+    // call here, since this is the only way to access devices in the library
+    // anyways.
+    //
+    // Having raw turned on means it'll work for read/write/sub/unsub on any
+    // endpoint so just use an arbitrary message here to check.
+    if self
+      .protocol
+      .supports_message(&ButtplugDeviceCommandMessageUnion::RawSubscribeCmd(
+        RawSubscribeCmd::new(1, Endpoint::Tx),
+      ))
+      .is_ok()
+    {
+      format!("{} (Raw Messages Allowed)", self.protocol.name())
+    } else {
+      self.protocol.name().to_owned()
+    }
+  }
+
+  pub fn disconnect(&self) -> ButtplugResultFuture {
+    self.device.disconnect()
+  }
+
+  /// See [DeviceImpl::set_traffic_diagnostics].
+  pub fn set_traffic_diagnostics(
+    &self,
+    sink: Option<(u32, broadcast::Sender<DeviceTrafficRecord>)>,
+  ) {
+    self.device.set_traffic_diagnostics(sink);
+  }
+
+  /// See [DeviceImpl::set_dry_run].
+  pub fn set_dry_run(&self, sink: Option<(u32, broadcast::Sender<DeviceTrafficRecord>)>) {
+    self.device.set_dry_run(sink);
+  }
+
+  pub fn message_attributes(&self) -> DeviceMessageAttributesMap {
+    (*self.shared_message_attributes()).clone()
+  }
+
+  /// Same as [message_attributes][Self::message_attributes], without copying
+  /// the map.
+  pub fn shared_message_attributes(&self) -> SharedDeviceMessageAttributesMap {
+    self
+      .message_attributes
+      .read()
+      .expect("Message attributes lock should never be poisoned")
+      .clone()
+  }
+
+  pub fn update_user_config(&self, config: &DeviceUserConfig) {
+    self.set_intensity_limit(*config.intensity_limit());
+    self
+      .device
+      .set_chunk_raw_writes(config.chunk_raw_writes().unwrap_or(false));
+    self.protocol.update_user_config(config);
+    *self
+      .message_attributes
+      .write()
+      .expect("Message attributes lock should never be poisoned") = interned_message_attributes(
+      &self.protocol_name,
+      self.protocol.as_ref(),
+      self.launch_translator.is_some(),
+    );
+  }
+
+  fn set_intensity_limit(&self, limit: Option<f64>) {
+    *self
+      .intensity_limit
+      .write()
+      .expect("Intensity limit lock should never be poisoned") =
+      limit.map(|limit| limit.clamp(0f64, 1f64));
+  }
+
+  /// SingleMotorVibrateCmd just means "run every vibrator at this speed", so
+  /// it's turned into a VibrateCmd covering every vibrator before it's queued,
+  /// and protocols only ever have to handle VibrateCmd.
+  fn upgrade_single_motor_vibrate_cmd(
+    &self,
+    message: &SingleMotorVibrateCmd,
+  ) -> Result<VibrateCmd, ButtplugError> {
+    let attributes = self.protocol.message_attributes();
+    let vibrate_attributes = attributes
+      .get(&ButtplugDeviceMessageType::VibrateCmd)
+      .ok_or(ButtplugDeviceError::MessageNotSupported(
+        ButtplugDeviceMessageType::SingleMotorVibrateCmd,
+      ))?;
+    let vibrator_count = vibrate_attributes.feature_count.ok_or_else(|| {
+      ButtplugDeviceError::ProtocolRequirementError(format!(
+        "{} needs to support VibrateCmd with a feature count to use SingleMotorVibrateCmd.",
+        self.protocol.name()
+      ))
+    })?;
+    let mut vibrate_cmd = VibrateCmd::new(
+      message.device_index(),
+      (0..vibrator_count)
+        .map(|index| VibrateSubcommand::new(index, message.speed()))
+        .collect(),
+    );
+    vibrate_cmd.set_id(message.id());
+    Ok(vibrate_cmd)
+  }
+
+  /// Queues the message to be sent to the device, after any commands sent
+  /// before it have finished.
+  pub fn parse_message(
+    &self,
+    message: ButtplugDeviceCommandMessageUnion,
+  ) -> ButtplugDeviceResultFuture {
+    let message = match message {
+      ButtplugDeviceCommandMessageUnion::SingleMotorVibrateCmd(msg) => {
+        match self.upgrade_single_motor_vibrate_cmd(&msg) {
+          Ok(vibrate_cmd) => vibrate_cmd.into(),
+          Err(err) => return Box::pin(future::ready(Err(err))),
+        }
+      }
+      message => message,
+    };
+    let intensity_limit = *self
+      .intensity_limit
+      .read()
+      .expect("Intensity limit lock should never be poisoned");
+    let message = match intensity_limit {
+      Some(limit) => apply_intensity_limit(message, limit),
+      None => message,
+    };
+    let protocol = self.protocol.clone();
+    let device = self.device.clone();
+    let launch_translator = self.launch_translator.clone();
+    // Queued commands run on the queue's own task, so carry the caller's span
+    // over to it.
+    let parent_span = Span::current();
+    self.command_queue.enqueue(message, move |message| {
+      // Translate when the command is sent rather than when it's queued, so
+      // the last position is right even if queued commands get cancelled.
+      let message = match (message, launch_translator) {
+        (ButtplugDeviceCommandMessageUnion::FleshlightLaunchFW12Cmd(msg), Some(translator)) => {
+          translator.translate(&msg).into()
+        }
+        (message, _) => message,
+      };
+      let span = message_span(|| {
+        info_span!(
+          parent: &parent_span,
+          "Device Protocol Command",
+          id = message.id(),
+          device_index = message.device_index(),
+          protocol = protocol.name()
+        )
+      });
+      let fut = span.in_scope(|| protocol.handle_command(device, message));
+      Box::pin(fut.instrument(span))
+    })
+  }
+
+  /// Cancels every command waiting to be sent, and the one being sent, without
+  /// stopping the device. Used when the device goes away, so nothing left over
+  /// is written to it.
+  pub fn cancel_commands(&self) {
+    let cancelled = self.command_queue.cancel_all();
+    if cancelled > 0 {
+      debug!(
+        "Cancelled {} commands for device {}.",
+        cancelled,
+        self.address()
+      );
+    }
+  }
+
+  /// Stops the device right away, instead of waiting behind whatever is in
+  /// the command queue, and cancels everything queued or being sent.
+  pub fn emergency_stop(&self) -> ButtplugDeviceResultFuture {
+    let cancelled = self.command_queue.cancel_all();
+    if cancelled > 0 {
+      debug!("Emergency stop cancelled {} queued commands.", cancelled);
+    }
+    // Protocols don't look at the device index, that's only for routing.
+    self
+      .protocol
+      .handle_command(self.device.clone(), StopDeviceCmd::new(0).into())
+  }
+
+  pub fn event_stream(&self) -> broadcast::Receiver<ButtplugDeviceEvent> {
+    self.device.event_stream()
+  }
+
+  /// Endpoint level access to the device, bypassing the protocol.
+  #[cfg(feature = "forwarded-device-manager")]
+  pub(crate) fn device_impl(&self) -> Arc<DeviceImpl> {
+    self.device.clone()
+  }
+
+  /// Sensor readings carried by a notification from the device, if the
+  /// protocol knows how to read any from that endpoint.
+  pub fn parse_sensor_notification(
+    &self,
+    device_index: u32,
+    endpoint: Endpoint,
+    data: &[u8],
+  ) -> Vec<SensorReading> {
+    self
+      .protocol
+      .parse_sensor_notification(device_index, endpoint, data)
+  }
+
+  // TODO Handle raw messages here.
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    server::comm_managers::test::{
+      check_test_recv_empty,
+      check_test_recv_value,
+      new_bluetoothle_test_device,
+      TestDevice,
+      TestDeviceInternal,
+    },
+    util::async_manager,
+  };
+
+  const TEST_WRITE_DELAY: Duration = Duration::from_millis(100);
+
+  fn assert_cancelled(result: Result<ButtplugServerMessage, ButtplugError>) {
+    assert!(matches!(
+      result,
+      Err(ButtplugError::ButtplugDeviceError(
+        ButtplugDeviceError::DeviceCommandCancelled
+      ))
+    ));
+  }
+
+  #[test]
+  pub fn test_confirmed_write_retries_then_fails() {
+    async_manager::block_on(async move {
+      let test_device = TestDeviceInternal::new("Confirmed Write Test", "confirmed-write-test");
+      test_device.add_endpoint(&Endpoint::Tx).await;
+      test_device.add_endpoint(&Endpoint::Rx).await;
+      let device = Arc::new(DeviceImpl::new(
+        "Confirmed Write Test",
+        "confirmed-write-test",
+        &[Endpoint::Tx, Endpoint::Rx],
+        Box::new(TestDevice::new(&test_device)),
+      ));
+      let confirmed_device = DeviceImpl::new_with_confirmed_writes(
+        device,
+        WriteConfirmation {
+          endpoint: Endpoint::Rx,
+          notify: false,
+        },
+      );
+      // The test device always reads back empty data, so the write can never
+      // be confirmed.
+      let result = confirmed_device
+        .write_value(DeviceWriteCmd::new(Endpoint::Tx, vec![0x00], false))
+        .await;
+      assert!(matches!(
+        result,
+        Err(ButtplugError::ButtplugDeviceError(
+          ButtplugDeviceError::DeviceWriteNotConfirmed(Endpoint::Tx, CONFIRMED_WRITE_ATTEMPTS)
+        ))
+      ));
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible");
+      for _ in 0..CONFIRMED_WRITE_ATTEMPTS {
+        check_test_recv_value(
+          &command_receiver,
+          DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0x00], true)),
+        );
+      }
+      assert!(check_test_recv_empty(&command_receiver));
+    });
+  }
+
+  #[test]
+  pub fn test_confirmed_write_notification_echo() {
+    async_manager::block_on(async move {
+      let test_device = TestDeviceInternal::new("Confirmed Write Test", "confirmed-write-test");
+      test_device.add_endpoint(&Endpoint::Tx).await;
+      test_device.add_endpoint(&Endpoint::Rx).await;
+      // Echo the stop command back on Rx, but not anything else.
+      test_device.add_write_response(Endpoint::Tx, &[0x00], Endpoint::Rx, &[0x00]);
+      test_device.add_write_response(Endpoint::Tx, &[0x01], Endpoint::Rx, &[0x02]);
+      let device = Arc::new(DeviceImpl::new(
+        "Confirmed Write Test",
+        "confirmed-write-test",
+        &[Endpoint::Tx, Endpoint::Rx],
+        Box::new(TestDevice::new(&test_device)),
+      ));
+      let confirmed_device = DeviceImpl::new_with_confirmed_writes(
+        device,
+        WriteConfirmation {
+          endpoint: Endpoint::Rx,
+          notify: true,
+        },
+      );
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible");
+      assert!(confirmed_device
+        .write_value(DeviceWriteCmd::new(Endpoint::Tx, vec![0x00], false))
+        .await
+        .is_ok());
+      // Confirmed on the first try, so only one write goes out.
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0x00], true)),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+      // An echo that doesn't match isn't a confirmation.
+      let result = confirmed_device
+        .write_value(DeviceWriteCmd::new(Endpoint::Tx, vec![0x01], false))
+        .await;
+      assert!(matches!(
+        result,
+        Err(ButtplugError::ButtplugDeviceError(
+          ButtplugDeviceError::DeviceWriteNotConfirmed(Endpoint::Tx, CONFIRMED_WRITE_ATTEMPTS)
+        ))
+      ));
+    });
+  }
+
+  #[test]
+  pub fn test_cancel_commands_drops_in_flight_writes() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("Massage Demo")
+        .await
+        .expect("Test, assuming infallible");
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible");
+      test_device.set_write_delay(Some(TEST_WRITE_DELAY));
+      let in_flight =
+        device.parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]).into());
+      let queued =
+        device.parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.1)]).into());
+      // Let the first command get as far as waiting on its write.
+      Delay::new(TEST_WRITE_DELAY / 10).await;
+      device.cancel_commands();
+      assert_cancelled(in_flight.await);
+      assert_cancelled(queued.await);
+      Delay::new(TEST_WRITE_DELAY * 2).await;
+      assert!(check_test_recv_empty(&command_receiver));
+    });
+  }
+
+  #[test]
+  pub fn test_stop_cancels_in_flight_motion_command() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("Massage Demo")
+        .await
+        .expect("Test, assuming infallible");
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible");
+      test_device.set_write_delay(Some(TEST_WRITE_DELAY));
+      let in_flight =
+        device.parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]).into());
+      Delay::new(TEST_WRITE_DELAY / 10).await;
+      let stop = device.parse_message(StopDeviceCmd::new(0).into());
+      assert_cancelled(in_flight.await);
+      assert!(stop.await.is_ok());
+      // Only the stop makes it to the device.
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+    });
+  }
+}
@@ -0,0 +1,58 @@
+//! Reports on devices that didn't match any protocol, for use in device
+//! support requests.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Characteristic found on a device during an inspection connection.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceInspectionCharacteristic {
+  pub uuid: Uuid,
+  /// Comm manager specific description of what the characteristic supports
+  /// (read, write, notify, etc).
+  pub properties: String,
+}
+
+/// Service found on a device during an inspection connection.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceInspectionService {
+  pub uuid: Uuid,
+  pub characteristics: Vec<DeviceInspectionCharacteristic>,
+}
+
+/// Everything a comm manager could find out about a device. Advertisement
+/// fields are filled in from discovery. `services` is only filled in if an
+/// inspection connection was allowed and succeeded.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeviceInspectionReport {
+  pub name: String,
+  pub address: String,
+  #[serde(rename = "advertised-services")]
+  pub advertised_services: Vec<Uuid>,
+  #[serde(rename = "manufacturer-data")]
+  pub manufacturer_data: HashMap<u16, Vec<u8>>,
+  #[serde(rename = "service-data")]
+  pub service_data: HashMap<Uuid, Vec<u8>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(default)]
+  pub rssi: Option<i16>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(default)]
+  pub services: Option<Vec<DeviceInspectionService>>,
+  /// Set if an inspection connection was allowed but failed.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(default)]
+  #[serde(rename = "inspection-error")]
+  pub inspection_error: Option<String>,
+}
+
+impl DeviceInspectionReport {
+  pub fn new(name: &str, address: &str) -> Self {
+    Self {
+      name: name.to_owned(),
+      address: address.to_owned(),
+      ..Default::default()
+    }
+  }
+}
@@ -0,0 +1,277 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Keepalive writes for devices whose protocol definition asks for them.
+//!
+//! Some devices drop their connection, or stop their motors, if they go too
+//! long without a write. Instead of every protocol for these devices running
+//! its own timer, a [KeepaliveDefinition] in the protocol definition sets up a
+//! [DeviceKeepalive] when the device is created, which watches writes to the
+//! keepalive endpoint and only sends a keepalive once the endpoint has been
+//! quiet for the whole interval.
+
+use super::{
+  configuration_manager::KeepaliveDefinition,
+  ButtplugDeviceEvent,
+  DeviceImpl,
+  DeviceImplInternal,
+  DeviceReadCmd,
+  DeviceSubscribeCmd,
+  DeviceUnsubscribeCmd,
+  DeviceWriteCmd,
+};
+use crate::{
+  core::{errors::ButtplugError, messages::RawReading, ButtplugResultFuture},
+  util::async_manager,
+};
+use futures::future::BoxFuture;
+use futures_timer::Delay;
+use std::{
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
+
+struct LastWrite {
+  time: Instant,
+  data: Option<Vec<u8>>,
+}
+
+/// Keeps a device alive according to its protocol's [KeepaliveDefinition].
+#[derive(Clone)]
+pub(super) struct DeviceKeepalive {
+  definition: KeepaliveDefinition,
+  last_write: Arc<Mutex<LastWrite>>,
+}
+
+impl DeviceKeepalive {
+  pub fn new(definition: KeepaliveDefinition) -> Self {
+    Self {
+      definition,
+      last_write: Arc::new(Mutex::new(LastWrite {
+        time: Instant::now(),
+        data: None,
+      })),
+    }
+  }
+
+  /// Wraps `device` so writes to the keepalive endpoint made through the
+  /// wrapper put off the next keepalive. Protocols should be handed the
+  /// wrapper.
+  pub fn wrap_device(&self, device: Arc<DeviceImpl>) -> Arc<DeviceImpl> {
+    Arc::new(DeviceImpl::new_wrapper(
+      &device,
+      Box::new(KeepaliveDeviceImpl {
+        device: device.clone(),
+        keepalive: self.clone(),
+      }),
+    ))
+  }
+
+  fn record_write(&self, msg: &DeviceWriteCmd) {
+    if msg.endpoint != self.definition.endpoint {
+      return;
+    }
+    let mut last_write = self
+      .last_write
+      .lock()
+      .expect("We never panic while holding this lock.");
+    last_write.time = Instant::now();
+    last_write.data = Some(msg.data.clone());
+  }
+
+  /// Starts sending keepalives to `device`. Stops once the device disconnects
+  /// or a keepalive write fails.
+  pub fn start(&self, device: Arc<DeviceImpl>) {
+    let keepalive = self.clone();
+    async_manager::spawn(async move { keepalive.run(device).await });
+  }
+
+  async fn run(self, device: Arc<DeviceImpl>) {
+    debug!("Starting keepalive for {}", device.name());
+    let interval = Duration::from_millis(self.definition.interval_ms.max(1).into());
+    while device.connected() {
+      let data = {
+        let mut last_write = self
+          .last_write
+          .lock()
+          .expect("We never panic while holding this lock.");
+        let wait = (last_write.time + interval).saturating_duration_since(Instant::now());
+        if wait.is_zero() {
+          last_write.time = Instant::now();
+          Ok(match &last_write.data {
+            Some(data) if self.definition.repeat_last_command => data.clone(),
+            _ => self.definition.packet.clone(),
+          })
+        } else {
+          Err(wait)
+        }
+      };
+      match data {
+        // Something was written since we last looked, check again once the
+        // endpoint could have been quiet for a whole interval.
+        Err(wait) => Delay::new(wait).await,
+        // Nothing to repeat yet.
+        Ok(data) if data.is_empty() => continue,
+        Ok(data) => {
+          if let Err(err) = device
+            .write_value(DeviceWriteCmd::new(
+              self.definition.endpoint,
+              data,
+              self.definition.write_with_response,
+            ))
+            .await
+          {
+            error!("Keepalive write to {} failed: {:?}", device.name(), err);
+            break;
+          }
+        }
+      }
+    }
+    info!(
+      "Keepalive for {} exiting, most likely due to device disconnection.",
+      device.name()
+    );
+  }
+}
+
+/// Device implementation wrapper that tells a [DeviceKeepalive] about writes
+/// going through it.
+struct KeepaliveDeviceImpl {
+  device: Arc<DeviceImpl>,
+  keepalive: DeviceKeepalive,
+}
+
+impl DeviceImplInternal for KeepaliveDeviceImpl {
+  fn connected(&self) -> bool {
+    self.device.connected()
+  }
+
+  fn disconnect(&self) -> ButtplugResultFuture {
+    self.device.disconnect()
+  }
+
+  fn event_stream(&self) -> broadcast::Receiver<ButtplugDeviceEvent> {
+    self.device.event_stream()
+  }
+
+  fn read_value(
+    &self,
+    msg: DeviceReadCmd,
+  ) -> BoxFuture<'static, Result<RawReading, ButtplugError>> {
+    self.device.read_value(msg)
+  }
+
+  fn write_value(&self, msg: DeviceWriteCmd) -> ButtplugResultFuture {
+    self.keepalive.record_write(&msg);
+    self.device.write_value(msg)
+  }
+
+  fn subscribe(&self, msg: DeviceSubscribeCmd) -> ButtplugResultFuture {
+    self.device.subscribe(msg)
+  }
+
+  fn unsubscribe(&self, msg: DeviceUnsubscribeCmd) -> ButtplugResultFuture {
+    self.device.unsubscribe(msg)
+  }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use crate::{
+    core::messages::{VibrateCmd, VibrateSubcommand},
+    device::{
+      configuration_manager::{DeviceConfigurationManager, KeepaliveDefinition},
+      DeviceImplCommand,
+      DeviceWriteCmd,
+      Endpoint,
+    },
+    server::comm_managers::test::{
+      check_test_recv_empty,
+      check_test_recv_value,
+      new_bluetoothle_test_device_with_cfg,
+    },
+    util::{async_manager, device_configuration::create_test_dcm},
+  };
+  use futures_timer::Delay;
+  use std::{sync::Arc, time::Duration};
+
+  const KEEPALIVE_INTERVAL_MS: u32 = 100;
+
+  fn keepalive_dcm(repeat_last_command: bool) -> Arc<DeviceConfigurationManager> {
+    let dcm = create_test_dcm(false);
+    dcm
+      .protocol_definitions()
+      .get_mut("leten")
+      .expect("Test, assuming infallible")
+      .keepalive = Some(KeepaliveDefinition {
+      interval_ms: KEEPALIVE_INTERVAL_MS,
+      endpoint: Endpoint::Tx,
+      repeat_last_command,
+      packet: vec![0xaa],
+      write_with_response: false,
+    });
+    Arc::new(dcm)
+  }
+
+  fn tx_write(data: Vec<u8>, write_with_response: bool) -> DeviceImplCommand {
+    DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, data, write_with_response))
+  }
+
+  async fn wait_for_keepalive() {
+    let interval = Duration::from_millis(KEEPALIVE_INTERVAL_MS.into());
+    Delay::new(interval + interval / 2).await;
+  }
+
+  #[test]
+  pub fn test_keepalive_repeats_last_command() {
+    async_manager::block_on(async move {
+      let (device, test_device) =
+        new_bluetoothle_test_device_with_cfg("ToyCod", Some(keepalive_dcm(true)), |_| {})
+          .await
+          .expect("Test, assuming infallible");
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible");
+      check_test_recv_value(&command_receiver, tx_write(vec![0x02, 0x00, 0x00], true));
+      // Writes made by the protocol count as keepalives themselves.
+      assert!(check_test_recv_empty(&command_receiver));
+      wait_for_keepalive().await;
+      check_test_recv_value(&command_receiver, tx_write(vec![0x02, 0x00, 0x00], false));
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(&command_receiver, tx_write(vec![0x02, 0x00, 10], true));
+      wait_for_keepalive().await;
+      check_test_recv_value(&command_receiver, tx_write(vec![0x02, 0x00, 10], false));
+    });
+  }
+
+  #[test]
+  pub fn test_keepalive_fixed_packet() {
+    async_manager::block_on(async move {
+      let (device, test_device) =
+        new_bluetoothle_test_device_with_cfg("ToyCod", Some(keepalive_dcm(false)), |_| {})
+          .await
+          .expect("Test, assuming infallible");
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible");
+      check_test_recv_value(&command_receiver, tx_write(vec![0x02, 0x00, 0x00], true));
+      wait_for_keepalive().await;
+      check_test_recv_value(&command_receiver, tx_write(vec![0xaa], false));
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(&command_receiver, tx_write(vec![0x02, 0x00, 10], true));
+      wait_for_keepalive().await;
+      check_test_recv_value(&command_receiver, tx_write(vec![0xaa], false));
+    });
+  }
+}
@@ -54,7 +54,7 @@ impl ButtplugProtocolCommandHandler for Ankni {
   ) -> ButtplugDeviceResultFuture {
     let manager = self.manager.clone();
     Box::pin(async move {
-      let result = manager.lock().await.update_vibration(&message, false)?;
+      let result = manager.lock().await.update_vibration(&message)?;
       if let Some(cmds) = result {
         if let Some(speed) = cmds[0] {
           device
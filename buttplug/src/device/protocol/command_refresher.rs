@@ -0,0 +1,84 @@
+use crate::{
+  core::errors::ButtplugError,
+  device::{DeviceImpl, DeviceWriteCmd, Endpoint},
+  util::async_manager,
+};
+use futures_timer::Delay;
+use std::{
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+use tokio::sync::RwLock;
+
+/// Keeps resending a device's current command, for devices that stop their
+/// motors if they don't hear from us every so often.
+#[derive(Clone)]
+pub struct CommandRefresher {
+  endpoint: Endpoint,
+  write_with_response: bool,
+  interval: Duration,
+  command: Arc<RwLock<Vec<u8>>>,
+  running: Arc<AtomicBool>,
+}
+
+impl CommandRefresher {
+  pub fn new(endpoint: Endpoint, write_with_response: bool, interval_ms: u64) -> Self {
+    Self {
+      endpoint,
+      write_with_response,
+      interval: Duration::from_millis(interval_ms),
+      command: Arc::new(RwLock::new(vec![])),
+      running: Arc::new(AtomicBool::new(false)),
+    }
+  }
+
+  /// Sends `command` right away, then keeps resending it every interval until
+  /// it's replaced by another update. The resend loop starts with the first
+  /// update, and stops once a write fails, i.e. when the device disconnects.
+  pub async fn update(
+    &self,
+    device: Arc<DeviceImpl>,
+    command: Vec<u8>,
+  ) -> Result<(), ButtplugError> {
+    *self.command.write().await = command.clone();
+    device
+      .write_value(DeviceWriteCmd::new(
+        self.endpoint,
+        command,
+        self.write_with_response,
+      ))
+      .await?;
+    if !self.running.swap(true, Ordering::SeqCst) {
+      let refresher = self.clone();
+      async_manager::spawn(async move { refresher.run(device).await });
+    }
+    Ok(())
+  }
+
+  async fn run(self, device: Arc<DeviceImpl>) {
+    debug!("Starting command refresher for {}", device.name());
+    loop {
+      Delay::new(self.interval).await;
+      let command = self.command.read().await.clone();
+      if device
+        .write_value(DeviceWriteCmd::new(
+          self.endpoint,
+          command,
+          self.write_with_response,
+        ))
+        .await
+        .is_err()
+      {
+        break;
+      }
+    }
+    self.running.store(false, Ordering::SeqCst);
+    info!(
+      "Command refresher for {} exiting, most likely due to device disconnection.",
+      device.name()
+    );
+  }
+}
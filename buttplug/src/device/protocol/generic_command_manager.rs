@@ -3,23 +3,103 @@ use crate::core::{
   messages::{
     ButtplugDeviceCommandMessageUnion,
     ButtplugDeviceMessageType,
+    DeviceMessageAttributes,
     DeviceMessageAttributesMap,
     LinearCmd,
+    OscillateCmd,
+    OscillateSubcommand,
     RotateCmd,
     RotationSubcommand,
+    VectorSubcommand,
     VibrateCmd,
     VibrateSubcommand,
   },
 };
 
+/// Time taken to move to a StopBehavior park position if the config doesn't
+/// give one, in milliseconds.
+const DEFAULT_PARK_DURATION: u32 = 500;
+
+/// How a feature's generic 0.0-1.0 speed is turned into a device step.
+#[derive(Clone, Debug, PartialEq)]
+enum StepConversion {
+  /// Spread evenly over 0 to StepCount.
+  Linear(u32),
+  /// Spread evenly over min to max step, with 0.0 still meaning 0, so any
+  /// nonzero speed is at least min.
+  Range(u32, u32),
+  /// Spread evenly over the entries of a lookup table, with 0.0 always
+  /// meaning 0. If the table doesn't start at 0, nonzero speeds are spread
+  /// over all of its entries.
+  Table(Vec<u32>),
+}
+
+impl StepConversion {
+  fn from_attributes(attr: &DeviceMessageAttributes) -> Vec<StepConversion> {
+    let step_counts = if let Some(step_counts) = &attr.step_count {
+      step_counts
+    } else {
+      return vec![];
+    };
+    step_counts
+      .iter()
+      .enumerate()
+      .map(|(index, step_count)| {
+        if let Some(table) = attr.step_map.as_ref().and_then(|maps| maps.get(index)) {
+          if !table.is_empty() {
+            return StepConversion::Table(table.clone());
+          }
+        }
+        if let Some((min, max)) = attr.step_range.as_ref().and_then(|ranges| ranges.get(index)) {
+          return StepConversion::Range(*min, *max);
+        }
+        StepConversion::Linear(*step_count)
+      })
+      .collect()
+  }
+
+  fn convert(&self, speed: f64) -> u32 {
+    // When calculating speeds, round up. This follows how we calculated
+    // things in buttplug-js and buttplug-csharp, so it's more for history
+    // than anything, but it's what users will expect.
+    match self {
+      StepConversion::Linear(step_count) => (speed * *step_count as f64).ceil() as u32,
+      StepConversion::Range(min, max) => {
+        if speed <= 0.0 {
+          0
+        } else {
+          let steps = max.saturating_sub(*min) + 1;
+          min + ((speed * steps as f64).ceil() as u32).clamp(1, steps) - 1
+        }
+      }
+      StepConversion::Table(table) => {
+        if speed <= 0.0 {
+          0
+        } else if table[0] == 0 {
+          let index = (speed * (table.len() - 1) as f64).ceil() as usize;
+          table[index.min(table.len() - 1)]
+        } else {
+          let index = ((speed * table.len() as f64).ceil() as usize).clamp(1, table.len());
+          table[index - 1]
+        }
+      }
+    }
+  }
+}
+
 pub struct GenericCommandManager {
   sent_vibration: bool,
   sent_rotation: bool,
   _sent_linear: bool,
   vibrations: Vec<u32>,
-  vibration_step_counts: Vec<u32>,
+  vibration_steps: Vec<StepConversion>,
+  vibration_match_all: bool,
+  vibration_always_send: bool,
   rotations: Vec<(u32, bool)>,
-  rotation_step_counts: Vec<u32>,
+  rotation_steps: Vec<StepConversion>,
+  sent_oscillation: bool,
+  oscillations: Vec<u32>,
+  oscillation_steps: Vec<StepConversion>,
   _linears: Vec<(u32, u32)>,
   _linear_step_counts: Vec<u32>,
   stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
@@ -28,9 +108,13 @@ pub struct GenericCommandManager {
 impl GenericCommandManager {
   pub fn new(attributes: &DeviceMessageAttributesMap) -> Self {
     let mut vibrations: Vec<u32> = vec![];
-    let mut vibration_step_counts: Vec<u32> = vec![];
+    let mut vibration_steps: Vec<StepConversion> = vec![];
+    let mut vibration_match_all = false;
+    let mut vibration_always_send = false;
     let mut rotations: Vec<(u32, bool)> = vec![];
-    let mut rotation_step_counts: Vec<u32> = vec![];
+    let mut rotation_steps: Vec<StepConversion> = vec![];
+    let mut oscillations: Vec<u32> = vec![];
+    let mut oscillation_steps: Vec<StepConversion> = vec![];
     let mut linears: Vec<(u32, u32)> = vec![];
     let mut linear_step_counts: Vec<u32> = vec![];
 
@@ -41,9 +125,9 @@ impl GenericCommandManager {
       if let Some(count) = attr.feature_count {
         vibrations = vec![0; count as usize];
       }
-      if let Some(step_counts) = &attr.step_count {
-        vibration_step_counts = step_counts.clone();
-      }
+      vibration_steps = StepConversion::from_attributes(attr);
+      vibration_match_all = attr.match_all.unwrap_or(false);
+      vibration_always_send = attr.always_send.unwrap_or(false);
 
       let mut subcommands = vec![];
       for i in 0..vibrations.len() {
@@ -55,9 +139,7 @@ impl GenericCommandManager {
       if let Some(count) = attr.feature_count {
         rotations = vec![(0, true); count as usize];
       }
-      if let Some(step_counts) = &attr.step_count {
-        rotation_step_counts = step_counts.clone();
-      }
+      rotation_steps = StepConversion::from_attributes(attr);
 
       // TODO Can we assume clockwise is false here? We might send extra
       // messages on Lovense since it'll require both a speed and change
@@ -69,6 +151,18 @@ impl GenericCommandManager {
       }
       stop_commands.push(RotateCmd::new(0, subcommands).into());
     }
+    if let Some(attr) = attributes.get(&ButtplugDeviceMessageType::OscillateCmd) {
+      if let Some(count) = attr.feature_count {
+        oscillations = vec![0; count as usize];
+      }
+      oscillation_steps = StepConversion::from_attributes(attr);
+
+      let mut subcommands = vec![];
+      for i in 0..oscillations.len() {
+        subcommands.push(OscillateSubcommand::new(i as u32, 0.0));
+      }
+      stop_commands.push(OscillateCmd::new(0, subcommands).into());
+    }
     if let Some(attr) = attributes.get(&ButtplugDeviceMessageType::LinearCmd) {
       if let Some(count) = attr.feature_count {
         linears = vec![(0, 0); count as usize];
@@ -77,6 +171,22 @@ impl GenericCommandManager {
         linear_step_counts = step_counts.clone();
       }
     }
+    // Parking runs after everything else has stopped, so nothing is still
+    // moving while linear features head home.
+    if let Some(behavior) = attributes
+      .get(&ButtplugDeviceMessageType::StopDeviceCmd)
+      .and_then(|attr| attr.stop_behavior.as_ref())
+    {
+      if let Some(position) = behavior.park_position {
+        if !linears.is_empty() {
+          let duration = behavior.park_duration.unwrap_or(DEFAULT_PARK_DURATION);
+          let vectors = (0..linears.len())
+            .map(|i| VectorSubcommand::new(i as u32, duration, position.clamp(0.0, 1.0)))
+            .collect();
+          stop_commands.push(LinearCmd::new(0, vectors).into());
+        }
+      }
+    }
 
     Self {
       sent_vibration: false,
@@ -85,17 +195,26 @@ impl GenericCommandManager {
       vibrations,
       rotations,
       _linears: linears,
-      vibration_step_counts,
-      rotation_step_counts,
+      vibration_steps,
+      vibration_match_all,
+      vibration_always_send,
+      rotation_steps,
+      sent_oscillation: false,
+      oscillations,
+      oscillation_steps,
       _linear_step_counts: linear_step_counts,
       stop_commands,
     }
   }
 
+  /// Converts a [VibrateCmd] into per-feature steps for the protocol to send.
+  ///
+  /// Unchanged features are returned as None, and None is returned if nothing
+  /// changed at all, unless the device configuration sets MatchAll (send every
+  /// feature whenever one changes) or AlwaysSend (never skip commands).
   pub fn update_vibration(
     &mut self,
     msg: &VibrateCmd,
-    match_all: bool,
   ) -> Result<Option<Vec<Option<u32>>>, ButtplugError> {
     // First, make sure this is a valid command, that contains at least one
     // subcommand.
@@ -114,6 +233,8 @@ impl GenericCommandManager {
     // If we've already sent commands before, we should check against our
     // old values. Otherwise, we should always send whatever command we're
     // going to send.
+    let match_all = self.vibration_match_all;
+    let always_send = self.vibration_always_send;
     let mut changed_value = false;
     let mut result: Vec<Option<u32>> = vec![None; self.vibrations.len()];
     // If we're in a match all situation, set up the array with all prior
@@ -138,15 +259,12 @@ impl GenericCommandManager {
         );
       }
 
-      // When calculating speeds, round up. This follows how we calculated
-      // things in buttplug-js and buttplug-csharp, so it's more for history
-      // than anything, but it's what users will expect.
-      let speed = (speed_command.speed() * self.vibration_step_counts[index] as f64).ceil() as u32;
+      let speed = self.vibration_steps[index].convert(speed_command.speed());
 
       // If we've already sent commands, we don't want to send them again,
       // because some of our communication busses are REALLY slow. Make sure
       // these values get None in our return vector.
-      if !self.sent_vibration || speed != self.vibrations[index] || match_all {
+      if !self.sent_vibration || speed != self.vibrations[index] || match_all || always_send {
         // For some hardware, we always have to send all vibration
         // values, otherwise if we update one motor but not the other,
         // we'll stop the other motor completely if we send 0 to it.
@@ -165,7 +283,7 @@ impl GenericCommandManager {
     self.sent_vibration = true;
 
     // Return the command vector for the protocol to turn into proprietary commands
-    if !changed_value {
+    if !changed_value && !always_send {
       Ok(None)
     } else {
       Ok(Some(result))
@@ -213,10 +331,7 @@ impl GenericCommandManager {
         );
       }
 
-      // When calculating speeds, round up. This follows how we calculated
-      // things in buttplug-js and buttplug-csharp, so it's more for history
-      // than anything, but it's what users will expect.
-      let speed = (rotate_command.speed() * self.rotation_step_counts[index] as f64).ceil() as u32;
+      let speed = self.rotation_steps[index].convert(rotate_command.speed());
       let clockwise = rotate_command.clockwise();
       // If we've already sent commands, we don't want to send them again,
       // because some of our communication busses are REALLY slow. Make sure
@@ -236,6 +351,56 @@ impl GenericCommandManager {
     Ok(result)
   }
 
+  /// Converts an [OscillateCmd] into per-feature steps for the protocol to
+  /// send. Features that haven't changed since the last command are returned
+  /// as None.
+  pub fn update_oscillation(
+    &mut self,
+    msg: &OscillateCmd,
+  ) -> Result<Vec<Option<u32>>, ButtplugError> {
+    if msg.speeds().is_empty() {
+      return Err(
+        ButtplugDeviceError::ProtocolRequirementError(
+          "OscillateCmd has 0 commands, will not do anything.".to_owned(),
+        )
+        .into(),
+      );
+    }
+
+    let mut result: Vec<Option<u32>> = vec![None; self.oscillations.len()];
+    for speed_command in msg.speeds() {
+      let index = speed_command.index() as usize;
+      if index >= self.oscillations.len() {
+        return Err(
+          ButtplugDeviceError::ProtocolRequirementError(format!(
+            "OscillateCmd has {} commands, device has {} oscillators.",
+            msg.speeds().len(),
+            self.oscillations.len()
+          ))
+          .into(),
+        );
+      }
+
+      let speed = self.oscillation_steps[index].convert(speed_command.speed());
+      if !self.sent_oscillation || speed != self.oscillations[index] {
+        self.oscillations[index] = speed;
+        result[index] = Some(speed);
+      }
+    }
+
+    self.sent_oscillation = true;
+
+    Ok(result)
+  }
+
+  /// Forgets the oscillation speeds sent so far, so the next
+  /// [update_oscillation][Self::update_oscillation] returns every feature it
+  /// is given. For protocols where other commands change the device's
+  /// oscillation behind the manager's back.
+  pub fn reset_oscillation(&mut self) {
+    self.sent_oscillation = false;
+  }
+
   pub fn _update_linear(
     &mut self,
     _msg: &LinearCmd,
@@ -258,6 +423,8 @@ impl GenericCommandManager {
     Ok(None)
   }
 
+  /// Commands that stop every feature, followed by anything the StopBehavior
+  /// in the StopDeviceCmd attributes adds.
   pub fn get_stop_commands(&self) -> Vec<ButtplugDeviceCommandMessageUnion> {
     self.stop_commands.clone()
   }
@@ -268,11 +435,17 @@ mod test {
 
   use super::GenericCommandManager;
   use crate::core::messages::{
+    ButtplugDeviceCommandMessageUnion,
     ButtplugDeviceMessageType,
     DeviceMessageAttributes,
     DeviceMessageAttributesMap,
+    LinearCmd,
+    OscillateCmd,
+    OscillateSubcommand,
     RotateCmd,
     RotationSubcommand,
+    StopBehavior,
+    VectorSubcommand,
     VibrateCmd,
     VibrateSubcommand,
   };
@@ -296,13 +469,13 @@ mod test {
     );
     assert_eq!(
       mgr
-        .update_vibration(&vibrate_msg, false)
+        .update_vibration(&vibrate_msg)
         .expect("Test, assuming infallible"),
       Some(vec![Some(10), Some(10)])
     );
     assert_eq!(
       mgr
-        .update_vibration(&vibrate_msg, false)
+        .update_vibration(&vibrate_msg)
         .expect("Test, assuming infallible"),
       None
     );
@@ -315,16 +488,73 @@ mod test {
     );
     assert_eq!(
       mgr
-        .update_vibration(&vibrate_msg_2, false)
+        .update_vibration(&vibrate_msg_2)
         .expect("Test, assuming infallible"),
       Some(vec![None, Some(15)])
     );
     let vibrate_msg_invalid = VibrateCmd::new(0, vec![VibrateSubcommand::new(2, 0.5)]);
-    assert!(mgr.update_vibration(&vibrate_msg_invalid, false).is_err());
+    assert!(mgr.update_vibration(&vibrate_msg_invalid).is_err());
 
     assert_eq!(mgr.get_vibration(), vec![Some(10), Some(15)]);
   }
 
+  #[test]
+  pub fn test_command_generator_vibration_match_all() {
+    let mut attributes_map = DeviceMessageAttributesMap::new();
+
+    let vibrate_attributes = DeviceMessageAttributes {
+      feature_count: Some(2),
+      step_count: Some(vec![20, 20]),
+      match_all: Some(true),
+      ..Default::default()
+    };
+    attributes_map.insert(ButtplugDeviceMessageType::VibrateCmd, vibrate_attributes);
+    let mut mgr = GenericCommandManager::new(&attributes_map);
+    let vibrate_msg = VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]);
+    assert_eq!(
+      mgr
+        .update_vibration(&vibrate_msg)
+        .expect("Test, assuming infallible"),
+      Some(vec![Some(10), Some(0)])
+    );
+    assert_eq!(
+      mgr
+        .update_vibration(&vibrate_msg)
+        .expect("Test, assuming infallible"),
+      None
+    );
+    let vibrate_msg_2 = VibrateCmd::new(0, vec![VibrateSubcommand::new(1, 0.75)]);
+    assert_eq!(
+      mgr
+        .update_vibration(&vibrate_msg_2)
+        .expect("Test, assuming infallible"),
+      Some(vec![Some(10), Some(15)])
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_vibration_always_send() {
+    let mut attributes_map = DeviceMessageAttributesMap::new();
+
+    let vibrate_attributes = DeviceMessageAttributes {
+      feature_count: Some(2),
+      step_count: Some(vec![20, 20]),
+      always_send: Some(true),
+      ..Default::default()
+    };
+    attributes_map.insert(ButtplugDeviceMessageType::VibrateCmd, vibrate_attributes);
+    let mut mgr = GenericCommandManager::new(&attributes_map);
+    let vibrate_msg = VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]);
+    for _ in 0..2 {
+      assert_eq!(
+        mgr
+          .update_vibration(&vibrate_msg)
+          .expect("Test, assuming infallible"),
+        Some(vec![Some(10), None])
+      );
+    }
+  }
+
   #[test]
   pub fn test_command_generator_rotation() {
     let mut attributes_map = DeviceMessageAttributesMap::new();
@@ -372,5 +602,209 @@ mod test {
     assert!(mgr.update_rotation(&rotate_msg_invalid).is_err());
   }
 
+  #[test]
+  pub fn test_command_generator_oscillation() {
+    let mut attributes_map = DeviceMessageAttributesMap::new();
+
+    let oscillate_attributes = DeviceMessageAttributes {
+      feature_count: Some(1),
+      step_count: Some(vec![20]),
+      ..Default::default()
+    };
+    attributes_map.insert(ButtplugDeviceMessageType::OscillateCmd, oscillate_attributes);
+    let mut mgr = GenericCommandManager::new(&attributes_map);
+    let oscillate_msg = OscillateCmd::new(0, vec![OscillateSubcommand::new(0, 0.5)]);
+    assert_eq!(
+      mgr
+        .update_oscillation(&oscillate_msg)
+        .expect("Test, assuming infallible"),
+      vec![Some(10)]
+    );
+    assert_eq!(
+      mgr
+        .update_oscillation(&oscillate_msg)
+        .expect("Test, assuming infallible"),
+      vec![None]
+    );
+    mgr.reset_oscillation();
+    assert_eq!(
+      mgr
+        .update_oscillation(&oscillate_msg)
+        .expect("Test, assuming infallible"),
+      vec![Some(10)]
+    );
+    assert_eq!(
+      mgr.get_stop_commands(),
+      vec![OscillateCmd::new(0, vec![OscillateSubcommand::new(0, 0.0)]).into()]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_vibration_step_range() {
+    let mut attributes_map = DeviceMessageAttributesMap::new();
+
+    let vibrate_attributes = DeviceMessageAttributes {
+      feature_count: Some(2),
+      step_count: Some(vec![20, 20]),
+      step_range: Some(vec![(4, 20)]),
+      ..Default::default()
+    };
+    attributes_map.insert(ButtplugDeviceMessageType::VibrateCmd, vibrate_attributes);
+    let mut mgr = GenericCommandManager::new(&attributes_map);
+    // Only the first feature has a range, the second is still linear.
+    let vibrate_msg = VibrateCmd::new(
+      0,
+      vec![
+        VibrateSubcommand::new(0, 0.5),
+        VibrateSubcommand::new(1, 0.5),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_vibration(&vibrate_msg)
+        .expect("Test, assuming infallible"),
+      Some(vec![Some(12), Some(10)])
+    );
+    let vibrate_msg_2 = VibrateCmd::new(
+      0,
+      vec![
+        VibrateSubcommand::new(0, 0.01),
+        VibrateSubcommand::new(1, 0.5),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_vibration(&vibrate_msg_2)
+        .expect("Test, assuming infallible"),
+      Some(vec![Some(4), None])
+    );
+    let vibrate_msg_3 = VibrateCmd::new(
+      0,
+      vec![
+        VibrateSubcommand::new(0, 0.0),
+        VibrateSubcommand::new(1, 0.5),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_vibration(&vibrate_msg_3)
+        .expect("Test, assuming infallible"),
+      Some(vec![Some(0), None])
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_vibration_step_range_bounds() {
+    let mut attributes_map = DeviceMessageAttributesMap::new();
+
+    let vibrate_attributes = DeviceMessageAttributes {
+      feature_count: Some(1),
+      step_count: Some(vec![20]),
+      step_range: Some(vec![(4, 20)]),
+      ..Default::default()
+    };
+    attributes_map.insert(ButtplugDeviceMessageType::VibrateCmd, vibrate_attributes);
+    let mut mgr = GenericCommandManager::new(&attributes_map);
+    // The smallest nonzero speed runs at the bottom of the range, not a step
+    // above it.
+    for (speed, step) in [(f64::MIN_POSITIVE, 4), (1.0, 20), (0.0, 0)] {
+      let vibrate_msg = VibrateCmd::new(0, vec![VibrateSubcommand::new(0, speed)]);
+      assert_eq!(
+        mgr
+          .update_vibration(&vibrate_msg)
+          .expect("Test, assuming infallible"),
+        Some(vec![Some(step)])
+      );
+    }
+  }
+
+  #[test]
+  pub fn test_command_generator_rotation_step_map() {
+    let mut attributes_map = DeviceMessageAttributesMap::new();
+
+    let rotate_attributes = DeviceMessageAttributes {
+      feature_count: Some(1),
+      step_count: Some(vec![20]),
+      step_map: Some(vec![vec![0, 8, 12, 20]]),
+      ..Default::default()
+    };
+    attributes_map.insert(ButtplugDeviceMessageType::RotateCmd, rotate_attributes);
+    let mut mgr = GenericCommandManager::new(&attributes_map);
+    for (speed, step) in [(0.0, 0), (0.1, 8), (0.5, 12), (1.0, 20)] {
+      let rotate_msg = RotateCmd::new(0, vec![RotationSubcommand::new(0, speed, true)]);
+      assert_eq!(
+        mgr
+          .update_rotation(&rotate_msg)
+          .expect("Test, assuming infallible"),
+        vec![Some((step, true))]
+      );
+    }
+  }
+
+  #[test]
+  pub fn test_command_generator_vibration_step_map_nonzero_start() {
+    let mut attributes_map = DeviceMessageAttributesMap::new();
+
+    let vibrate_attributes = DeviceMessageAttributes {
+      feature_count: Some(1),
+      step_count: Some(vec![20]),
+      step_map: Some(vec![vec![5, 10, 20]]),
+      ..Default::default()
+    };
+    attributes_map.insert(ButtplugDeviceMessageType::VibrateCmd, vibrate_attributes);
+    let mut mgr = GenericCommandManager::new(&attributes_map);
+    // Stopping has to turn the device off even though the table has no 0
+    // entry.
+    for (speed, step) in [(0.1, 5), (0.5, 10), (1.0, 20), (0.0, 0)] {
+      let vibrate_msg = VibrateCmd::new(0, vec![VibrateSubcommand::new(0, speed)]);
+      assert_eq!(
+        mgr
+          .update_vibration(&vibrate_msg)
+          .expect("Test, assuming infallible"),
+        Some(vec![Some(step)])
+      );
+    }
+  }
+
+  #[test]
+  pub fn test_command_generator_stop_behavior() {
+    let mut attributes_map = DeviceMessageAttributesMap::new();
+    attributes_map.insert(
+      ButtplugDeviceMessageType::LinearCmd,
+      DeviceMessageAttributes {
+        feature_count: Some(2),
+        step_count: Some(vec![100, 100]),
+        ..Default::default()
+      },
+    );
+    // Without a stop behavior, linear features are left where they are.
+    assert!(GenericCommandManager::new(&attributes_map)
+      .get_stop_commands()
+      .is_empty());
+
+    attributes_map.insert(
+      ButtplugDeviceMessageType::StopDeviceCmd,
+      DeviceMessageAttributes {
+        stop_behavior: Some(StopBehavior {
+          park_position: Some(0.0),
+          park_duration: Some(1000),
+        }),
+        ..Default::default()
+      },
+    );
+    let park_cmd: ButtplugDeviceCommandMessageUnion = LinearCmd::new(
+      0,
+      vec![
+        VectorSubcommand::new(0, 1000, 0.0),
+        VectorSubcommand::new(1, 1000, 0.0),
+      ],
+    )
+    .into();
+    assert_eq!(
+      GenericCommandManager::new(&attributes_map).get_stop_commands(),
+      vec![park_cmd]
+    );
+  }
+
   // TODO Write test for vibration stop generator
 }
@@ -21,7 +21,7 @@ impl ButtplugProtocolCommandHandler for JeJoue {
     // Store off result before the match, so we drop the lock ASAP.
     let manager = self.manager.clone();
     Box::pin(async move {
-      let result = manager.lock().await.update_vibration(&message, true)?;
+      let result = manager.lock().await.update_vibration(&message)?;
       if let Some(cmds) = result {
         // Default to both vibes
         let mut pattern: u8 = 1;
@@ -1,5 +1,5 @@
 use super::{
-  fleshlight_launch_helper::get_speed,
+  linear_motion::{device_position, LaunchCalibration, LinearMotion},
   ButtplugDeviceResultFuture,
   ButtplugProtocol,
   ButtplugProtocolCommandHandler,
@@ -68,6 +68,10 @@ impl ButtplugProtocol for KiirooV2 {
 }
 
 impl ButtplugProtocolCommandHandler for KiirooV2 {
+  fn fleshlight_launch_calibration(&self) -> Option<LaunchCalibration> {
+    None
+  }
+
   fn handle_linear_cmd(
     &self,
     device: Arc<DeviceImpl>,
@@ -76,12 +80,16 @@ impl ButtplugProtocolCommandHandler for KiirooV2 {
     let v = message.vectors()[0].clone();
     // In the protocol, we know max speed is 99, so convert here. We have to
     // use AtomicU8 because there's no AtomicF64 yet.
-    let previous_position = self.previous_position.load(SeqCst);
-    let distance = (previous_position as f64 - (v.position * 99f64)).abs() / 99f64;
+    let motion = LinearMotion::from_device_position(
+      self.previous_position.load(SeqCst) as u32,
+      99f64,
+      v.position,
+      v.duration,
+    );
     let fl_cmd = FleshlightLaunchFW12Cmd::new(
       message.device_index(),
-      (v.position * 99f64) as u8,
-      (get_speed(distance, v.duration) * 99f64) as u8,
+      device_position(motion.goal(), 99f64) as u8,
+      motion.device_speed(99f64) as u8,
     );
     self.handle_fleshlight_launch_fw12_cmd(device, fl_cmd)
   }
@@ -155,4 +163,26 @@ mod test {
       );
     });
   }
+
+  #[test]
+  pub fn test_kiiroov2_onyx_fast_stroke() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("Onyx2")
+        .await
+        .expect("Test, assuming infallible");
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible");
+      // Strokes faster than the Onyx can move go at its top speed, instead of
+      // overflowing the speed byte.
+      device
+        .parse_message(LinearCmd::new(0, vec![VectorSubcommand::new(0, 0, 1.0)]).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![99, 99], false)),
+      );
+    });
+  }
 }
@@ -1,5 +1,5 @@
 use super::{
-  fleshlight_launch_helper::get_speed,
+  linear_motion::{device_position, LaunchCalibration, LinearMotion},
   ButtplugDeviceResultFuture,
   ButtplugProtocol,
   ButtplugProtocolCommandHandler,
@@ -51,6 +51,10 @@ impl KiirooV21 {
 super::default_protocol_trait_declaration!(KiirooV21);
 
 impl ButtplugProtocolCommandHandler for KiirooV21 {
+  fn fleshlight_launch_calibration(&self) -> Option<LaunchCalibration> {
+    None
+  }
+
   fn handle_vibrate_cmd(
     &self,
     device: Arc<DeviceImpl>,
@@ -59,7 +63,7 @@ impl ButtplugProtocolCommandHandler for KiirooV21 {
     // Store off result before the match, so we drop the lock ASAP.
     let manager = self.manager.clone();
     Box::pin(async move {
-      let result = manager.lock().await.update_vibration(&message, false)?;
+      let result = manager.lock().await.update_vibration(&message)?;
       if let Some(cmds) = result {
         device
           .write_value(DeviceWriteCmd::new(
@@ -81,12 +85,16 @@ impl ButtplugProtocolCommandHandler for KiirooV21 {
     let v = message.vectors()[0].clone();
     // In the protocol, we know max speed is 99, so convert here. We have to
     // use AtomicU8 because there's no AtomicF64 yet.
-    let previous_position = self.previous_position.load(SeqCst);
-    let distance = (previous_position as f64 - (v.position * 99f64)).abs() / 99f64;
+    let motion = LinearMotion::from_device_position(
+      self.previous_position.load(SeqCst) as u32,
+      99f64,
+      v.position,
+      v.duration,
+    );
     let fl_cmd = FleshlightLaunchFW12Cmd::new(
       message.device_index(),
-      (v.position * 99f64) as u8,
-      (get_speed(distance, v.duration) * 99f64) as u8,
+      device_position(motion.goal(), 99f64) as u8,
+      motion.device_speed(99f64) as u8,
     );
     self.handle_fleshlight_launch_fw12_cmd(device, fl_cmd)
   }
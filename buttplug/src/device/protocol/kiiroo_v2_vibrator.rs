@@ -1,9 +1,17 @@
 use super::{ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolCommandHandler};
 use crate::{
-  core::messages::{self, ButtplugDeviceCommandMessageUnion, DeviceMessageAttributesMap},
+  core::messages::{
+    self,
+    ButtplugDeviceCommandMessageUnion,
+    DeviceMessageAttributesMap,
+    SensorReading,
+    SensorType,
+  },
   device::{
     protocol::{generic_command_manager::GenericCommandManager, ButtplugProtocolProperties},
     DeviceImpl,
+    DeviceSubscribeCmd,
+    DeviceUnsubscribeCmd,
     DeviceWriteCmd,
     Endpoint,
   },
@@ -21,7 +29,7 @@ impl ButtplugProtocolCommandHandler for KiirooV2Vibrator {
     // Store off result before the match, so we drop the lock ASAP.
     let manager = self.manager.clone();
     Box::pin(async move {
-      let result = manager.lock().await.update_vibration(&message, true)?;
+      let result = manager.lock().await.update_vibration(&message)?;
       if let Some(cmds) = result {
         device
           .write_value(DeviceWriteCmd::new(
@@ -38,12 +46,64 @@ impl ButtplugProtocolCommandHandler for KiirooV2Vibrator {
       Ok(messages::Ok::default().into())
     })
   }
+
+  // The Pearl's touch sensors are its only sensor, so there's just the one
+  // sensor index to handle.
+  fn handle_sensor_subscribe_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    _message: messages::SensorSubscribeCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let fut = device.subscribe(DeviceSubscribeCmd::new(Endpoint::RxTouch));
+    Box::pin(async move {
+      fut.await?;
+      Ok(messages::Ok::default().into())
+    })
+  }
+
+  fn handle_sensor_unsubscribe_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    _message: messages::SensorUnsubscribeCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let fut = device.unsubscribe(DeviceUnsubscribeCmd::new(Endpoint::RxTouch));
+    Box::pin(async move {
+      fut.await?;
+      Ok(messages::Ok::default().into())
+    })
+  }
+
+  // Touch notifications carry one pressure value per byte, one for each touch
+  // sensor along the shaft.
+  fn parse_sensor_notification(
+    &self,
+    device_index: u32,
+    endpoint: Endpoint,
+    data: &[u8],
+  ) -> Vec<SensorReading> {
+    if endpoint != Endpoint::RxTouch {
+      return vec![];
+    }
+    vec![SensorReading::new(
+      device_index,
+      0,
+      SensorType::Pressure,
+      data.iter().map(|value| *value as i32).collect(),
+    )]
+  }
 }
 
 #[cfg(all(test, feature = "server"))]
 mod test {
   use crate::{
-    core::messages::{StopDeviceCmd, VibrateCmd, VibrateSubcommand},
+    core::messages::{
+      SensorReading,
+      SensorSubscribeCmd,
+      SensorType,
+      StopDeviceCmd,
+      VibrateCmd,
+      VibrateSubcommand,
+    },
     device::{DeviceImplCommand, DeviceWriteCmd, Endpoint},
     server::comm_managers::test::{
       check_test_recv_empty,
@@ -204,4 +264,47 @@ mod test {
       );
     });
   }
+
+  #[test]
+  pub fn test_kiiroov2vibrator_pearl_touch_sensor() {
+    async_manager::block_on(async move {
+      let (device, _) = new_bluetoothle_test_device("Pearl2")
+        .await
+        .expect("Test, assuming infallible");
+      device
+        .parse_message(SensorSubscribeCmd::new(0, 0).into())
+        .await
+        .expect("Test, assuming infallible");
+      // The Pearl only has the one sensor.
+      assert!(device
+        .parse_message(SensorSubscribeCmd::new(0, 1).into())
+        .await
+        .is_err());
+      assert_eq!(
+        device.parse_sensor_notification(0, Endpoint::RxTouch, &[0, 128, 255]),
+        vec![SensorReading::new(
+          0,
+          0,
+          SensorType::Pressure,
+          vec![0, 128, 255]
+        )]
+      );
+      assert!(device
+        .parse_sensor_notification(0, Endpoint::RxAccel, &[0])
+        .is_empty());
+    });
+  }
+
+  #[test]
+  pub fn test_kiiroov2vibrator_no_sensor() {
+    async_manager::block_on(async move {
+      let (device, _) = new_bluetoothle_test_device("Titan")
+        .await
+        .expect("Test, assuming infallible");
+      assert!(device
+        .parse_message(SensorSubscribeCmd::new(0, 0).into())
+        .await
+        .is_err());
+    });
+  }
 }
@@ -43,7 +43,7 @@ impl ButtplugProtocolCommandHandler for LeloF1s {
     // Store off result before the match, so we drop the lock ASAP.
     let manager = self.manager.clone();
     Box::pin(async move {
-      let result = manager.lock().await.update_vibration(&message, true)?;
+      let result = manager.lock().await.update_vibration(&message)?;
       let mut cmd_vec = vec![0x1];
       if let Some(cmds) = result {
         info!("{:?}", cmds);
@@ -21,7 +21,7 @@ impl ButtplugProtocolCommandHandler for LiboElle {
     // Store off result before the match, so we drop the lock ASAP.
     let manager = self.manager.clone();
     Box::pin(async move {
-      let result = manager.lock().await.update_vibration(&message, false)?;
+      let result = manager.lock().await.update_vibration(&message)?;
       let mut fut_vec = vec![];
       if let Some(cmds) = result {
         for (index, cmd) in cmds.iter().enumerate() {
@@ -0,0 +1,446 @@
+use super::fleshlight_launch_helper::{get_duration, get_speed};
+use crate::core::messages::{
+  ButtplugDeviceMessage,
+  ButtplugMessage,
+  FleshlightLaunchFW12Cmd,
+  LinearCmd,
+  VectorSubcommand,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::HashMap,
+  f64::consts::PI,
+  sync::{
+    atomic::{AtomicU8, Ordering},
+    Mutex,
+  },
+};
+
+/// Milliseconds between the segments a move is split into when it's eased.
+pub const EASING_SEGMENT_INTERVAL: u32 = 50;
+
+/// How a device should move between positions over the course of a move. Set
+/// per device in user config, under `linear-easing`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LinearEasing {
+  /// Same speed the whole way.
+  #[default]
+  Linear,
+  /// Speeds up from the start and slows down into the goal, which is easier
+  /// on hardware that jerks when changing direction.
+  EaseInOut,
+}
+
+impl LinearEasing {
+  /// Fraction of the distance covered once `progress` (0.0-1.0) of the
+  /// duration has passed.
+  pub fn apply(&self, progress: f64) -> f64 {
+    let progress = progress.clamp(0f64, 1f64);
+    match self {
+      LinearEasing::Linear => progress,
+      LinearEasing::EaseInOut => (1f64 - (progress * PI).cos()) / 2f64,
+    }
+  }
+}
+
+/// Converts a position from 0.0-1.0 into a device's own range of 0-`max`.
+pub fn device_position(position: f64, max: f64) -> u32 {
+  (position.clamp(0f64, 1f64) * max) as u32
+}
+
+/// Point along a [LinearMotion].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinearStep {
+  /// Milliseconds since the start of the move.
+  pub time: u32,
+  /// Position, 0.0-1.0.
+  pub position: f64,
+}
+
+/// Part of a [LinearMotion], for devices that take a position and duration
+/// and move linearly between them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinearSegment {
+  /// Milliseconds since the start of the move that the segment should be sent.
+  pub start: u32,
+  /// Milliseconds the device should take to reach the position.
+  pub duration: u32,
+  /// Position, 0.0-1.0.
+  pub position: f64,
+}
+
+/// Move from one position to another, with positions between 0.0 and 1.0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinearMotion {
+  start: f64,
+  goal: f64,
+  /// Milliseconds the move should take.
+  duration: u32,
+}
+
+impl LinearMotion {
+  pub fn new(start: f64, goal: f64, duration: u32) -> Self {
+    Self {
+      start: start.clamp(0f64, 1f64),
+      goal: goal.clamp(0f64, 1f64),
+      duration,
+    }
+  }
+
+  /// Move starting from a position in a device's own range of 0-`max`, which
+  /// is usually how protocols remember where the device was last sent.
+  pub fn from_device_position(start: u32, max: f64, goal: f64, duration: u32) -> Self {
+    Self::new(start as f64 / max, goal, duration)
+  }
+
+  /// Move at a Launch style speed (0.0-1.0), for devices that take durations
+  /// but get sent FleshlightLaunchFW12Cmd.
+  pub fn from_launch_speed(start: f64, goal: f64, speed: f64) -> Self {
+    let distance = (goal - start).abs();
+    Self::new(start, goal, get_duration(distance, speed))
+  }
+
+  pub fn start(&self) -> f64 {
+    self.start
+  }
+
+  pub fn goal(&self) -> f64 {
+    self.goal
+  }
+
+  pub fn duration(&self) -> u32 {
+    self.duration
+  }
+
+  pub fn distance(&self) -> f64 {
+    (self.goal - self.start).abs()
+  }
+
+  /// Average speed of the move, in positions per second. A move with no
+  /// duration is treated as being as fast as the device can go, and returns
+  /// infinity.
+  pub fn velocity(&self) -> f64 {
+    if self.distance() == 0f64 {
+      0f64
+    } else {
+      self.distance() / (self.duration as f64 / 1000f64)
+    }
+  }
+
+  /// Launch style speed (0.0-1.0) that covers the distance in the duration.
+  /// May come out over 1.0 if the Launch couldn't move that fast.
+  pub fn launch_speed(&self) -> f64 {
+    get_speed(self.distance(), self.duration)
+  }
+
+  /// [launch_speed][LinearMotion::launch_speed] in a device's own range of
+  /// 0-`max`, capped at `max` for moves faster than the device can make.
+  pub fn device_speed(&self, max: f64) -> u32 {
+    (self.launch_speed().clamp(0f64, 1f64) * max) as u32
+  }
+
+  /// Where the device should be at each `interval` milliseconds into the move,
+  /// for devices that can only be sent positions. Always ends with the goal at
+  /// the full duration.
+  pub fn steps(&self, interval: u32, easing: LinearEasing) -> Vec<LinearStep> {
+    let mut steps = vec![];
+    if interval > 0 {
+      let mut time = interval;
+      while time < self.duration {
+        let progress = easing.apply(time as f64 / self.duration as f64);
+        steps.push(LinearStep {
+          time,
+          position: self.start + (self.goal - self.start) * progress,
+        });
+        time += interval;
+      }
+    }
+    steps.push(LinearStep {
+      time: self.duration,
+      position: self.goal,
+    });
+    steps
+  }
+
+  /// Splits the move into segments `interval` milliseconds apart that follow
+  /// `easing`, for devices that move linearly to whatever position they're
+  /// sent. A linear move is sent as is, in one segment.
+  pub fn segments(&self, interval: u32, easing: LinearEasing) -> Vec<LinearSegment> {
+    let interval = match easing {
+      LinearEasing::Linear => 0,
+      _ => interval,
+    };
+    let mut start = 0;
+    self
+      .steps(interval, easing)
+      .into_iter()
+      .map(|step| {
+        let segment = LinearSegment {
+          start,
+          duration: step.time - start,
+          position: step.position,
+        };
+        start = step.time;
+        segment
+      })
+      .collect()
+  }
+}
+
+/// Easing and last sent positions for a device's linear axes, for protocols
+/// that ease moves by splitting them into segments.
+#[derive(Debug, Default)]
+pub struct LinearAxes {
+  easing: Mutex<LinearEasing>,
+  positions: Mutex<HashMap<u32, f64>>,
+}
+
+impl LinearAxes {
+  pub fn new(easing: LinearEasing) -> Self {
+    Self {
+      easing: Mutex::new(easing),
+      positions: Mutex::new(HashMap::new()),
+    }
+  }
+
+  pub fn easing(&self) -> LinearEasing {
+    *self
+      .easing
+      .lock()
+      .expect("We never panic while holding this lock.")
+  }
+
+  pub fn set_easing(&self, easing: LinearEasing) {
+    *self
+      .easing
+      .lock()
+      .expect("We never panic while holding this lock.") = easing;
+  }
+
+  /// Segments moving axis `index` from wherever it was last sent to `goal`.
+  /// Axes that haven't been sent anywhere yet are assumed to start at 0.0.
+  pub fn segments(&self, index: u32, goal: f64, duration: u32) -> Vec<LinearSegment> {
+    let start = self
+      .positions
+      .lock()
+      .expect("We never panic while holding this lock.")
+      .get(&index)
+      .copied()
+      .unwrap_or(0f64);
+    LinearMotion::new(start, goal, duration).segments(EASING_SEGMENT_INTERVAL, self.easing())
+  }
+
+  /// Records a segment's position as sent, so if the rest of the move is
+  /// cancelled the next one starts from there.
+  pub fn set_position(&self, index: u32, position: f64) {
+    self
+      .positions
+      .lock()
+      .expect("We never panic while holding this lock.")
+      .insert(index, position);
+  }
+}
+
+/// Adjustments for hardware that doesn't move quite like a Launch, used when
+/// translating FleshlightLaunchFW12Cmd into LinearCmd.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LaunchCalibration {
+  /// Multiplier for how long the Launch would have taken to make the same move.
+  pub duration_scale: f64,
+  /// Shortest move the hardware can make, in milliseconds.
+  pub min_duration: u32,
+}
+
+impl Default for LaunchCalibration {
+  fn default() -> Self {
+    Self {
+      duration_scale: 1f64,
+      min_duration: 0,
+    }
+  }
+}
+
+/// Turns FleshlightLaunchFW12Cmd speed/position pairs into LinearCmd
+/// duration/position pairs. Launch speeds depend on how far the device has to
+/// travel, so this remembers the last position it sent.
+#[derive(Debug, Default)]
+pub struct LaunchTranslator {
+  calibration: LaunchCalibration,
+  previous_position: AtomicU8,
+}
+
+impl LaunchTranslator {
+  pub fn new(calibration: LaunchCalibration) -> Self {
+    Self {
+      calibration,
+      previous_position: AtomicU8::new(0),
+    }
+  }
+
+  pub fn calibration(&self) -> LaunchCalibration {
+    self.calibration
+  }
+
+  pub fn translate(&self, message: &FleshlightLaunchFW12Cmd) -> LinearCmd {
+    let position = message.position().min(99);
+    let previous_position = self.previous_position.swap(position, Ordering::SeqCst);
+    let motion = LinearMotion::from_launch_speed(
+      previous_position as f64 / 99f64,
+      position as f64 / 99f64,
+      message.speed().min(99) as f64 / 99f64,
+    );
+    let duration = ((motion.duration() as f64 * self.calibration.duration_scale) as u32)
+      .max(self.calibration.min_duration);
+    let mut linear_cmd = LinearCmd::new(
+      message.device_index(),
+      vec![VectorSubcommand::new(0, duration, motion.goal())],
+    );
+    linear_cmd.set_id(message.id());
+    linear_cmd
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_easing() {
+    for easing in [LinearEasing::Linear, LinearEasing::EaseInOut] {
+      assert_eq!(easing.apply(0f64), 0f64);
+      assert!((easing.apply(0.5) - 0.5).abs() < f64::EPSILON);
+      assert!((easing.apply(1f64) - 1f64).abs() < f64::EPSILON);
+    }
+    // Ease in/out starts slower and ends slower than linear.
+    assert!(LinearEasing::EaseInOut.apply(0.25) < LinearEasing::Linear.apply(0.25));
+    assert!(LinearEasing::EaseInOut.apply(0.75) > LinearEasing::Linear.apply(0.75));
+  }
+
+  #[test]
+  fn test_linear_motion_velocity() {
+    let motion = LinearMotion::new(0.25, 0.75, 500);
+    assert!((motion.distance() - 0.5).abs() < f64::EPSILON);
+    assert!((motion.velocity() - 1f64).abs() < f64::EPSILON);
+    assert_eq!(LinearMotion::new(0.5, 0.5, 500).velocity(), 0f64);
+    assert_eq!(LinearMotion::new(0f64, 1f64, 0).velocity(), f64::INFINITY);
+    assert_eq!(
+      LinearMotion::from_device_position(99, 99f64, 0f64, 100).start(),
+      1f64
+    );
+  }
+
+  #[test]
+  fn test_linear_motion_steps() {
+    let motion = LinearMotion::new(1f64, 0f64, 100);
+    let steps = motion.steps(25, LinearEasing::Linear);
+    assert_eq!(
+      steps.iter().map(|step| step.time).collect::<Vec<_>>(),
+      vec![25, 50, 75, 100]
+    );
+    assert!((steps[1].position - 0.5).abs() < f64::EPSILON);
+    assert_eq!(steps[3].position, 0f64);
+    // Moves shorter than the interval go straight to the goal.
+    assert_eq!(
+      LinearMotion::new(0f64, 1f64, 10).steps(25, LinearEasing::EaseInOut),
+      vec![LinearStep {
+        time: 10,
+        position: 1f64
+      }]
+    );
+  }
+
+  #[test]
+  fn test_linear_motion_segments() {
+    let motion = LinearMotion::new(0f64, 1f64, 100);
+    assert_eq!(
+      motion.segments(25, LinearEasing::Linear),
+      vec![LinearSegment {
+        start: 0,
+        duration: 100,
+        position: 1f64
+      }]
+    );
+    let segments = motion.segments(25, LinearEasing::EaseInOut);
+    assert_eq!(
+      segments
+        .iter()
+        .map(|segment| (segment.start, segment.duration))
+        .collect::<Vec<_>>(),
+      vec![(0, 25), (25, 25), (50, 25), (75, 25)]
+    );
+    assert!((segments[0].position - LinearEasing::EaseInOut.apply(0.25)).abs() < f64::EPSILON);
+    assert_eq!(segments[3].position, 1f64);
+  }
+
+  #[test]
+  fn test_linear_axes() {
+    let axes = LinearAxes::default();
+    assert_eq!(axes.segments(0, 1f64, 200).len(), 1);
+    axes.set_easing(LinearEasing::EaseInOut);
+    let segments = axes.segments(0, 1f64, 200);
+    assert_eq!(segments.len(), 4);
+    assert!(segments[0].position < 0.5);
+    // Moves start from the last position sent on that axis.
+    axes.set_position(0, segments[0].position);
+    let segments = axes.segments(0, segments[0].position, 200);
+    assert!(segments
+      .iter()
+      .all(|segment| (segment.position - segments[0].position).abs() < f64::EPSILON));
+    assert!(axes.segments(1, 1f64, 200)[0].position < 0.5);
+  }
+
+  #[test]
+  fn test_device_position() {
+    assert_eq!(device_position(0.5, 99f64), 49);
+    assert_eq!(device_position(1.5, 99f64), 99);
+    assert_eq!(device_position(-1f64, 99f64), 0);
+  }
+
+  #[test]
+  fn test_device_speed() {
+    assert_eq!(LinearMotion::new(0f64, 0.5, 500).device_speed(99f64), 19);
+    // Moves faster than the device can go are capped at its top speed.
+    assert_eq!(LinearMotion::new(0f64, 1f64, 0).device_speed(99f64), 99);
+    assert_eq!(LinearMotion::new(0.5, 0.5, 0).device_speed(99f64), 0);
+  }
+
+  #[test]
+  fn test_launch_translator() {
+    let translator = LaunchTranslator::default();
+    let first = translator.translate(&FleshlightLaunchFW12Cmd::new(0, 99, 50));
+    assert_eq!(first.vectors()[0].position, 1f64);
+    assert_eq!(
+      first.vectors()[0].duration,
+      get_duration(1f64, 50f64 / 99f64)
+    );
+    // Moving back to where we started takes the same time at the same speed.
+    let second = translator.translate(&FleshlightLaunchFW12Cmd::new(0, 0, 50));
+    assert_eq!(second.vectors()[0].position, 0f64);
+    assert_eq!(second.vectors()[0].duration, first.vectors()[0].duration);
+    // Not moving at all is instant, unless the hardware can't go that fast.
+    assert_eq!(
+      translator
+        .translate(&FleshlightLaunchFW12Cmd::new(0, 0, 50))
+        .vectors()[0]
+        .duration,
+      0
+    );
+    let translator = LaunchTranslator::new(LaunchCalibration {
+      duration_scale: 2f64,
+      min_duration: 100,
+    });
+    let scaled = translator.translate(&FleshlightLaunchFW12Cmd::new(0, 99, 50));
+    assert_eq!(
+      scaled.vectors()[0].duration,
+      first.vectors()[0].duration * 2
+    );
+    assert_eq!(
+      translator
+        .translate(&FleshlightLaunchFW12Cmd::new(0, 99, 50))
+        .vectors()[0]
+        .duration,
+      100
+    );
+  }
+}
@@ -1,7 +1,13 @@
-use super::{ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolCommandHandler};
+use super::{
+  linear_motion::{device_position, LinearMotion},
+  ButtplugDeviceResultFuture,
+  ButtplugProtocol,
+  ButtplugProtocolCommandHandler,
+};
 use crate::{
   core::errors::ButtplugDeviceError,
   device::{ButtplugDeviceEvent, DeviceSubscribeCmd},
+  util::stream::recv_skipping_lag,
 };
 use crate::{
   core::{
@@ -10,6 +16,7 @@ use crate::{
       self,
       ButtplugDeviceCommandMessageUnion,
       ButtplugDeviceMessage,
+      ButtplugDeviceMessageType,
       DeviceMessageAttributesMap,
     },
   },
@@ -24,7 +31,7 @@ use futures::FutureExt;
 use futures_timer::Delay;
 use std::{
   sync::{
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicU8, Ordering},
     Arc,
   },
   time::Duration,
@@ -39,6 +46,46 @@ use tokio::sync::Mutex;
 const LOVENSE_COMMAND_TIMEOUT_MS: u64 = 500;
 const LOVENSE_COMMAND_RETRY: u64 = 5;
 
+// Oscillating strokers (Solace, etc) take stroke speed in the same 0-20 range
+// as vibration, and stroke position as 0-100.
+const LOVENSE_STROKE_SPEED_MAX: f64 = 20f64;
+const LOVENSE_STROKE_POSITION_MAX: f64 = 100f64;
+
+/// Reply to a `DeviceType;` query, i.e. `P:37:0082059AD3BD;`. The model is
+/// what picks the device's configuration, and with it how many motors it has
+/// and what they do, so identification doesn't depend on advertised names.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LovenseDeviceType {
+  /// Model code, i.e. P for Edge or A/C for Nora.
+  pub model: String,
+  pub firmware_version: Option<u32>,
+  pub address: Option<String>,
+}
+
+impl LovenseDeviceType {
+  /// Parses a DeviceType reply. Returns None if the reply doesn't start with
+  /// a model code, i.e. if it's ERR or a reply to some other command.
+  pub fn parse(reply: &str) -> Option<Self> {
+    let reply = reply.trim().trim_end_matches(';');
+    let mut fields = reply.split(':').map(str::trim);
+    let model = fields.next()?;
+    if !model.starts_with(|c: char| c.is_ascii_alphabetic())
+      || !model.chars().all(|c| c.is_ascii_alphanumeric())
+      || model == "ERR"
+    {
+      return None;
+    }
+    Some(Self {
+      model: model.to_owned(),
+      firmware_version: fields.next().and_then(|version| version.parse().ok()),
+      address: fields
+        .next()
+        .filter(|address| !address.is_empty())
+        .map(str::to_owned),
+    })
+  }
+}
+
 #[derive(ButtplugProtocolProperties)]
 pub struct Lovense {
   name: String,
@@ -46,10 +93,16 @@ pub struct Lovense {
   manager: Arc<Mutex<GenericCommandManager>>,
   stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
   rotation_direction: Arc<AtomicBool>,
+  previous_position: Arc<AtomicU8>,
 }
 
 impl Lovense {
-  fn new(name: &str, attrs: DeviceMessageAttributesMap) -> Self {
+  fn new(name: &str, mut attrs: DeviceMessageAttributesMap) -> Self {
+    // Configurations can only add to the protocol defaults, which include a
+    // vibrator. Strokers don't have one, so drop it here.
+    if attrs.contains_key(&ButtplugDeviceMessageType::OscillateCmd) {
+      attrs.remove(&ButtplugDeviceMessageType::VibrateCmd);
+    }
     let manager = GenericCommandManager::new(&attrs);
     Self {
       name: name.to_owned(),
@@ -57,6 +110,7 @@ impl Lovense {
       stop_commands: manager.get_stop_commands(),
       manager: Arc::new(Mutex::new(manager)),
       rotation_direction: Arc::new(AtomicBool::new(false)),
+      previous_position: Arc::new(AtomicU8::new(0)),
     }
   }
 }
@@ -71,7 +125,6 @@ impl ButtplugProtocol for Lovense {
   > {
     Box::pin(async move {
       let mut event_receiver = device_impl.event_stream();
-      let identifier;
       let mut count = 0;
       device_impl
         .subscribe(DeviceSubscribeCmd::new(Endpoint::Rx))
@@ -86,9 +139,28 @@ impl ButtplugProtocol for Lovense {
             if let Ok(ButtplugDeviceEvent::Notification(_, _, n)) = event {
               let type_response = std::str::from_utf8(&n).map_err(|_| ButtplugError::from(ButtplugDeviceError::ProtocolSpecificError("lovense".to_owned(), "Lovense device init got back non-UTF8 string.".to_owned())))?.to_owned();
               info!("Lovense Device Type Response: {}", type_response);
-              identifier = type_response.split(':').collect::<Vec<&str>>()[0].to_owned();
-              let (name, attrs) = crate::device::protocol::get_protocol_features(device_impl, Some(identifier), config)?;
-              return Ok(Box::new(Self::new(&name, attrs)) as Box<dyn ButtplugProtocol>);
+              if let Some(device_type) = LovenseDeviceType::parse(&type_response) {
+                if !config.has_identifier(&device_type.model) {
+                  warn!(
+                    "Unknown Lovense model {} (firmware {:?}), using default features.",
+                    device_type.model, device_type.firmware_version
+                  );
+                }
+                let (name, attrs) = crate::device::protocol::get_protocol_features(device_impl, Some(device_type.model), config)?;
+                return Ok(Box::new(Self::new(&name, attrs)) as Box<dyn ButtplugProtocol>);
+              }
+              // Probably a late reply to something else, ask again.
+              warn!("Lovense device sent unexpected reply to DeviceType: {}", type_response);
+              count += 1;
+              if count > LOVENSE_COMMAND_RETRY {
+                return Err(
+                  ButtplugDeviceError::ProtocolSpecificError(
+                    "Lovense".to_owned(),
+                    format!("Lovense Device sent no valid DeviceType info. ({} retries)", LOVENSE_COMMAND_RETRY),
+                  )
+                  .into()
+                );
+              }
             } else {
               return Err(
                 ButtplugDeviceError::ProtocolSpecificError(
@@ -126,7 +198,7 @@ impl ButtplugProtocolCommandHandler for Lovense {
     let manager = self.manager.clone();
     Box::pin(async move {
       // Store off result before the match, so we drop the lock ASAP.
-      let result = manager.lock().await.update_vibration(&msg, false)?;
+      let result = manager.lock().await.update_vibration(&msg)?;
       // Lovense is the same situation as the Lovehoney Desire, where commands
       // are different if we're addressing all motors or seperate motors.
       // Difference here being that there's Lovense variants with different
@@ -190,6 +262,80 @@ impl ButtplugProtocolCommandHandler for Lovense {
     })
   }
 
+  fn handle_oscillate_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    msg: messages::OscillateCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let manager = self.manager.clone();
+    Box::pin(async move {
+      let result = manager.lock().await.update_oscillation(&msg)?;
+      // Strokers keep moving at their last speed until told otherwise, and the
+      // manager's cache can be stale if a LinearCmd changed the speed since,
+      // so a full stop is always sent. Stopping never touches the position,
+      // the device just halts wherever it is in the stroke.
+      let stopping = msg.speeds().iter().all(|cmd| cmd.speed() == 0f64);
+      let speed = if stopping { Some(0) } else { result[0] };
+      if let Some(speed) = speed {
+        let lovense_cmd = format!("Speed:{};", speed).as_bytes().to_vec();
+        device
+          .write_value(DeviceWriteCmd::new(Endpoint::Tx, lovense_cmd, false))
+          .await?;
+      }
+      Ok(messages::Ok::default().into())
+    })
+  }
+
+  fn handle_linear_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    msg: messages::LinearCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let manager = self.manager.clone();
+    let previous_position = self.previous_position.clone();
+    Box::pin(async move {
+      let vector = msg.vectors().first().ok_or_else(|| {
+        ButtplugError::from(ButtplugDeviceError::ProtocolRequirementError(
+          "LinearCmd has 0 commands, will not do anything.".to_owned(),
+        ))
+      })?;
+      // The device has no notion of duration, so work out the stroke speed
+      // needed to cover the distance in time, the same way we do for the
+      // Launch. Anything that moves at all gets at least the slowest speed.
+      let position = device_position(vector.position, LOVENSE_STROKE_POSITION_MAX) as u8;
+      let motion = LinearMotion::from_device_position(
+        previous_position.load(Ordering::SeqCst) as u32,
+        LOVENSE_STROKE_POSITION_MAX,
+        position as f64 / LOVENSE_STROKE_POSITION_MAX,
+        vector.duration,
+      );
+      let speed = if motion.distance() > 0f64 {
+        ((motion.launch_speed().min(1f64) * LOVENSE_STROKE_SPEED_MAX).ceil() as u32).max(1)
+      } else {
+        0
+      };
+      // Speed set here is shared with OscillateCmd, so make sure the next
+      // OscillateCmd is sent even if it matches what we last sent for it.
+      manager.lock().await.reset_oscillation();
+      device
+        .write_value(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          format!("Speed:{};", speed).as_bytes().to_vec(),
+          false,
+        ))
+        .await?;
+      device
+        .write_value(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          format!("Position:{};", position).as_bytes().to_vec(),
+          false,
+        ))
+        .await?;
+      previous_position.store(position, Ordering::SeqCst);
+      Ok(messages::Ok::default().into())
+    })
+  }
+
   fn handle_battery_level_cmd(
     &self,
     device: Arc<DeviceImpl>,
@@ -203,7 +349,7 @@ impl ButtplugProtocolCommandHandler for Lovense {
         false,
       ));
       write_fut.await?;
-      while let Ok(event) = device_notification_receiver.recv().await {
+      while let Some(event) = recv_skipping_lag(&mut device_notification_receiver).await {
         match event {
           ButtplugDeviceEvent::Notification(_, _, data) => {
             if let Ok(data_str) = std::str::from_utf8(&data) {
@@ -251,5 +397,150 @@ impl ButtplugProtocolCommandHandler for Lovense {
   }
 }
 
-// TODO Gonna need to add the ability to set subscribe data in tests before
-// writing Lovense tests. Oops.
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use super::LovenseDeviceType;
+  use crate::{
+    core::messages::{
+      ButtplugDeviceMessageType,
+      LinearCmd,
+      OscillateCmd,
+      OscillateSubcommand,
+      StopDeviceCmd,
+      VectorSubcommand,
+    },
+    device::{DeviceImplCommand, DeviceWriteCmd, Endpoint},
+    server::comm_managers::test::{
+      check_test_recv_empty,
+      check_test_recv_value,
+      new_bluetoothle_test_device_with_setup,
+    },
+    util::async_manager,
+  };
+
+  fn lovense_write(cmd: &str) -> DeviceImplCommand {
+    DeviceImplCommand::Write(DeviceWriteCmd::new(
+      Endpoint::Tx,
+      cmd.as_bytes().to_vec(),
+      false,
+    ))
+  }
+
+  #[test]
+  pub fn test_lovense_device_type_parse() {
+    assert_eq!(
+      LovenseDeviceType::parse("P:37:0082059AD3BD;"),
+      Some(LovenseDeviceType {
+        model: "P".to_owned(),
+        firmware_version: Some(37),
+        address: Some("0082059AD3BD".to_owned()),
+      })
+    );
+    assert_eq!(
+      LovenseDeviceType::parse("EB;\n"),
+      Some(LovenseDeviceType {
+        model: "EB".to_owned(),
+        firmware_version: None,
+        address: None,
+      })
+    );
+    assert_eq!(LovenseDeviceType::parse(";"), None);
+    // Battery level reply.
+    assert_eq!(LovenseDeviceType::parse("85;"), None);
+    assert_eq!(LovenseDeviceType::parse("ERR;"), None);
+  }
+
+  #[test]
+  pub fn test_lovense_device_type_features() {
+    async_manager::block_on(async move {
+      // Same advertised name, features come from the DeviceType reply.
+      for (reply, name, vibrators, rotators) in [
+        ("P:37:0082059AD3BD;", "Lovense Edge", 2, None),
+        ("A:11:0082059AD3BD;", "Lovense Nora", 1, Some(1)),
+      ] {
+        let (device, _) = new_bluetoothle_test_device_with_setup("LVS-Test", |d| {
+          d.add_write_response(Endpoint::Tx, b"DeviceType;", Endpoint::Rx, reply.as_bytes())
+        })
+        .await
+        .expect("Test, assuming infallible");
+        assert_eq!(device.name(), name);
+        let attrs = device.message_attributes();
+        assert_eq!(
+          attrs[&ButtplugDeviceMessageType::VibrateCmd].feature_count,
+          Some(vibrators)
+        );
+        assert_eq!(
+          attrs
+            .get(&ButtplugDeviceMessageType::RotateCmd)
+            .and_then(|attrs| attrs.feature_count),
+          rotators
+        );
+      }
+    });
+  }
+
+  #[test]
+  pub fn test_lovense_solace_oscillate_linear_stop() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device_with_setup("LVS-Solace", |d| {
+        d.add_write_response(
+          Endpoint::Tx,
+          b"DeviceType;",
+          Endpoint::Rx,
+          b"BA:11:0082059AD3BD;",
+        )
+      })
+      .await
+      .expect("Test, assuming infallible");
+      assert_eq!(device.name(), "Lovense Solace");
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible");
+      check_test_recv_value(&command_receiver, lovense_write("DeviceType;"));
+      assert!(check_test_recv_empty(&command_receiver));
+
+      let oscillate = OscillateCmd::new(0, vec![OscillateSubcommand::new(0, 0.5)]);
+      device
+        .parse_message(oscillate.clone().into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(&command_receiver, lovense_write("Speed:10;"));
+      // Same speed again, nothing to send.
+      device
+        .parse_message(oscillate.clone().into())
+        .await
+        .expect("Test, assuming infallible");
+      assert!(check_test_recv_empty(&command_receiver));
+
+      device
+        .parse_message(LinearCmd::new(0, vec![VectorSubcommand::new(0, 500, 0.5)]).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(&command_receiver, lovense_write("Speed:4;"));
+      check_test_recv_value(&command_receiver, lovense_write("Position:50;"));
+      assert!(check_test_recv_empty(&command_receiver));
+
+      // LinearCmd changed the speed on the device, so the same oscillation
+      // speed has to be sent again.
+      device
+        .parse_message(oscillate.into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(&command_receiver, lovense_write("Speed:10;"));
+
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(&command_receiver, lovense_write("Speed:0;"));
+      assert!(check_test_recv_empty(&command_receiver));
+      // Stopping always sends a stop, without moving the stroke position.
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(&command_receiver, lovense_write("Speed:0;"));
+      assert!(check_test_recv_empty(&command_receiver));
+    });
+  }
+}
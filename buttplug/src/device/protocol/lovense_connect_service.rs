@@ -58,7 +58,7 @@ impl ButtplugProtocolCommandHandler for LovenseConnectService {
     let manager = self.manager.clone();
     Box::pin(async move {
       // Store off result before the match, so we drop the lock ASAP.
-      let result = manager.lock().await.update_vibration(&msg, false)?;
+      let result = manager.lock().await.update_vibration(&msg)?;
       // Lovense is the same situation as the Lovehoney Desire, where commands
       // are different if we're addressing all motors or seperate motors.
       // Difference here being that there's Lovense variants with different
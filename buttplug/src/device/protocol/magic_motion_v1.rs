@@ -21,7 +21,7 @@ impl ButtplugProtocolCommandHandler for MagicMotionV1 {
     // Store off result before the match, so we drop the lock ASAP.
     let manager = self.manager.clone();
     Box::pin(async move {
-      let result = manager.lock().await.update_vibration(&message, false)?;
+      let result = manager.lock().await.update_vibration(&message)?;
       if let Some(cmds) = result {
         device
           .write_value(DeviceWriteCmd::new(
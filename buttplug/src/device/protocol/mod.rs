@@ -2,27 +2,42 @@
 pub mod aneros;
 pub mod ankni;
 pub mod cachito;
+pub mod command_refresher;
 pub mod fleshlight_launch_helper;
 pub mod fredorch;
 pub mod generic_command_manager;
 pub mod hgod;
 pub mod htk_bm;
 pub mod jejoue;
+#[cfg(feature = "kiiroo-protocols")]
 pub mod kiiroo_v2;
+#[cfg(feature = "kiiroo-protocols")]
 pub mod kiiroo_v21;
+#[cfg(feature = "kiiroo-protocols")]
 pub mod kiiroo_v21_initialized;
+#[cfg(feature = "kiiroo-protocols")]
 pub mod kiiroo_v2_vibrator;
 pub mod lelof1s;
+pub mod leten;
+#[cfg(feature = "libo-protocols")]
 pub mod libo_elle;
+#[cfg(feature = "libo-protocols")]
 pub mod libo_shark;
+#[cfg(feature = "libo-protocols")]
 pub mod libo_vibes;
+pub mod linear_motion;
 pub mod lovedistance;
 pub mod lovehoney_desire;
+#[cfg(feature = "lovense-protocols")]
 pub mod lovense;
+#[cfg(feature = "lovense-protocols")]
 pub mod lovense_connect_service;
 pub mod lovenuts;
+#[cfg(feature = "magic-motion-protocols")]
 pub mod magic_motion_v1;
+#[cfg(feature = "magic-motion-protocols")]
 pub mod magic_motion_v2;
+#[cfg(feature = "magic-motion-protocols")]
 pub mod magic_motion_v3;
 pub mod mannuo;
 pub mod maxpro;
@@ -36,16 +51,25 @@ pub mod prettylove;
 pub mod raw_protocol;
 pub mod realov;
 pub mod satisfyer;
+#[cfg(feature = "scripted-protocols")]
+pub mod scripted;
+#[cfg(feature = "svakom-protocols")]
 pub mod svakom;
+#[cfg(feature = "svakom-protocols")]
 pub mod svakom_alex;
+#[cfg(feature = "svakom-protocols")]
 pub mod svakom_iker;
+#[cfg(feature = "svakom-protocols")]
 pub mod svakom_sam;
 pub mod tcode_v03;
 pub mod thehandy;
 pub mod vibratissimo;
 pub mod vorze_sa;
+#[cfg(feature = "wevibe-protocols")]
 pub mod wevibe;
+#[cfg(feature = "wevibe-protocols")]
 pub mod wevibe8bit;
+#[cfg(feature = "xinput-manager")]
 pub mod xinput;
 pub mod youcups;
 pub mod youou;
@@ -63,8 +87,7 @@ use crate::{
       ButtplugMessage,
       DeviceMessageAttributesMap,
       RawReading,
-      VibrateCmd,
-      VibrateSubcommand,
+      SensorReading,
     },
   },
   device::{
@@ -73,10 +96,12 @@ use crate::{
     DeviceReadCmd,
     Endpoint,
   },
+  server::device_manager::DeviceUserConfig,
 };
 use dashmap::DashMap;
 use futures::future::{self, BoxFuture};
 use generic_command_manager::GenericCommandManager;
+use linear_motion::LaunchCalibration;
 use std::sync::Arc;
 
 pub type TryCreateProtocolFunc =
@@ -85,17 +110,57 @@ pub type TryCreateProtocolFunc =
     DeviceProtocolConfiguration,
   ) -> BoxFuture<'static, Result<Box<dyn ButtplugProtocol>, ButtplugError>>;
 
-pub fn add_to_protocol_map<T>(map: &DashMap<String, TryCreateProtocolFunc>, protocol_name: &str)
+/// Creates protocol instances for devices matched to a protocol name.
+///
+/// Protocols built into the library are registered through their
+/// [ButtplugProtocol::try_create] function. Protocols from other crates can
+/// implement this trait instead, i.e. to carry state shared between devices
+/// or loaded at runtime, and register it with
+/// [DeviceManager::add_protocol_factory][crate::server::device_manager::DeviceManager::add_protocol_factory].
+/// Functions and closures with the same signature as
+/// [ButtplugProtocol::try_create] implement it already.
+///
+/// Factories can build their message attributes from the device
+/// configuration with [get_protocol_features], or construct them in code.
+pub trait ButtplugProtocolFactory: Send + Sync {
+  fn try_create(
+    &self,
+    device_impl: Arc<DeviceImpl>,
+    config: DeviceProtocolConfiguration,
+  ) -> BoxFuture<'static, Result<Box<dyn ButtplugProtocol>, ButtplugError>>;
+}
+
+impl<F> ButtplugProtocolFactory for F
+where
+  F: Fn(
+      Arc<DeviceImpl>,
+      DeviceProtocolConfiguration,
+    ) -> BoxFuture<'static, Result<Box<dyn ButtplugProtocol>, ButtplugError>>
+    + Send
+    + Sync,
+{
+  fn try_create(
+    &self,
+    device_impl: Arc<DeviceImpl>,
+    config: DeviceProtocolConfiguration,
+  ) -> BoxFuture<'static, Result<Box<dyn ButtplugProtocol>, ButtplugError>> {
+    self(device_impl, config)
+  }
+}
+
+pub type ProtocolFactoryMap = DashMap<String, Arc<dyn ButtplugProtocolFactory>>;
+
+pub fn add_to_protocol_map<T>(map: &ProtocolFactoryMap, protocol_name: &str)
 where
   T: ButtplugProtocol,
 {
   map.insert(
     protocol_name.to_owned(),
-    T::try_create as TryCreateProtocolFunc,
+    Arc::new(T::try_create as TryCreateProtocolFunc),
   );
 }
 
-pub fn get_default_protocol_map() -> DashMap<String, TryCreateProtocolFunc> {
+pub fn get_default_protocol_map() -> ProtocolFactoryMap {
   let map = DashMap::new();
   add_to_protocol_map::<aneros::Aneros>(&map, "aneros");
   add_to_protocol_map::<ankni::Ankni>(&map, "ankni");
@@ -104,27 +169,40 @@ pub fn get_default_protocol_map() -> DashMap<String, TryCreateProtocolFunc> {
   add_to_protocol_map::<hgod::Hgod>(&map, "hgod");
   add_to_protocol_map::<htk_bm::HtkBm>(&map, "htk_bm");
   add_to_protocol_map::<jejoue::JeJoue>(&map, "jejoue");
+  #[cfg(feature = "kiiroo-protocols")]
   add_to_protocol_map::<kiiroo_v2::KiirooV2>(&map, "kiiroo-v2");
+  #[cfg(feature = "kiiroo-protocols")]
   add_to_protocol_map::<kiiroo_v2_vibrator::KiirooV2Vibrator>(&map, "kiiroo-v2-vibrator");
+  #[cfg(feature = "kiiroo-protocols")]
   add_to_protocol_map::<kiiroo_v21::KiirooV21>(&map, "kiiroo-v21");
+  #[cfg(feature = "kiiroo-protocols")]
   add_to_protocol_map::<kiiroo_v21_initialized::KiirooV21Initialized>(
     &map,
     "kiiroo-v21-initialized",
   );
   add_to_protocol_map::<lelof1s::LeloF1s>(&map, "lelo-f1s");
+  add_to_protocol_map::<leten::Leten>(&map, "leten");
+  #[cfg(feature = "libo-protocols")]
   add_to_protocol_map::<libo_elle::LiboElle>(&map, "libo-elle");
+  #[cfg(feature = "libo-protocols")]
   add_to_protocol_map::<libo_shark::LiboShark>(&map, "libo-shark");
+  #[cfg(feature = "libo-protocols")]
   add_to_protocol_map::<libo_vibes::LiboVibes>(&map, "libo-vibes");
   add_to_protocol_map::<lovehoney_desire::LovehoneyDesire>(&map, "lovehoney-desire");
   add_to_protocol_map::<lovedistance::LoveDistance>(&map, "lovedistance");
+  #[cfg(feature = "lovense-protocols")]
   add_to_protocol_map::<lovense::Lovense>(&map, "lovense");
+  #[cfg(feature = "lovense-protocols")]
   add_to_protocol_map::<lovense_connect_service::LovenseConnectService>(
     &map,
     "lovense-connect-service",
   );
   add_to_protocol_map::<lovenuts::LoveNuts>(&map, "lovenuts");
+  #[cfg(feature = "magic-motion-protocols")]
   add_to_protocol_map::<magic_motion_v1::MagicMotionV1>(&map, "magic-motion-1");
+  #[cfg(feature = "magic-motion-protocols")]
   add_to_protocol_map::<magic_motion_v2::MagicMotionV2>(&map, "magic-motion-2");
+  #[cfg(feature = "magic-motion-protocols")]
   add_to_protocol_map::<magic_motion_v3::MagicMotionV3>(&map, "magic-motion-3");
   add_to_protocol_map::<mannuo::ManNuo>(&map, "mannuo");
   add_to_protocol_map::<maxpro::Maxpro>(&map, "maxpro");
@@ -138,16 +216,23 @@ pub fn get_default_protocol_map() -> DashMap<String, TryCreateProtocolFunc> {
   add_to_protocol_map::<raw_protocol::RawProtocol>(&map, "raw");
   add_to_protocol_map::<realov::Realov>(&map, "realov");
   add_to_protocol_map::<satisfyer::Satisfyer>(&map, "satisfyer");
+  #[cfg(feature = "svakom-protocols")]
   add_to_protocol_map::<svakom::Svakom>(&map, "svakom");
+  #[cfg(feature = "svakom-protocols")]
   add_to_protocol_map::<svakom_alex::SvakomAlex>(&map, "svakom-alex");
+  #[cfg(feature = "svakom-protocols")]
   add_to_protocol_map::<svakom_iker::SvakomIker>(&map, "svakom-iker");
+  #[cfg(feature = "svakom-protocols")]
   add_to_protocol_map::<svakom_sam::SvakomSam>(&map, "svakom-sam");
   add_to_protocol_map::<tcode_v03::TCodeV03>(&map, "tcode-v03");
   add_to_protocol_map::<thehandy::TheHandy>(&map, "thehandy");
   add_to_protocol_map::<vibratissimo::Vibratissimo>(&map, "vibratissimo");
   add_to_protocol_map::<vorze_sa::VorzeSA>(&map, "vorze-sa");
+  #[cfg(feature = "wevibe-protocols")]
   add_to_protocol_map::<wevibe::WeVibe>(&map, "wevibe");
+  #[cfg(feature = "wevibe-protocols")]
   add_to_protocol_map::<wevibe8bit::WeVibe8Bit>(&map, "wevibe-8bit");
+  #[cfg(feature = "xinput-manager")]
   add_to_protocol_map::<xinput::XInput>(&map, "xinput");
   add_to_protocol_map::<youcups::Youcups>(&map, "youcups");
   add_to_protocol_map::<youou::Youou>(&map, "youou");
@@ -171,12 +256,25 @@ pub fn get_protocol_features(
 }
 
 pub trait ButtplugProtocol: ButtplugProtocolCommandHandler + Sync {
+  /// Creates the protocol for a device. Protocols only ever created through a
+  /// [ButtplugProtocolFactory] don't need to implement this.
   fn try_create(
     device_impl: Arc<DeviceImpl>,
-    config: DeviceProtocolConfiguration,
+    _config: DeviceProtocolConfiguration,
   ) -> BoxFuture<'static, Result<Box<dyn ButtplugProtocol>, ButtplugError>>
   where
-    Self: Sized;
+    Self: Sized,
+  {
+    Box::pin(future::ready(Err(
+      ButtplugDeviceError::ProtocolNotImplemented(device_impl.name().to_owned()).into(),
+    )))
+  }
+
+  /// Called when the user config for a connected device is added, changed or
+  /// removed. The config at creation time is available via
+  /// [DeviceProtocolConfiguration::user_config]. Most protocols don't use user
+  /// config, so this does nothing by default.
+  fn update_user_config(&self, _config: &DeviceUserConfig) {}
 }
 
 fn check_message_support(
@@ -190,6 +288,21 @@ fn check_message_support(
   }
 }
 
+// Sensor messages also have to address a sensor the device actually has.
+fn check_sensor_support(
+  message_type: &ButtplugDeviceMessageType,
+  sensor_index: u32,
+  message_attributes: &DeviceMessageAttributesMap,
+) -> Result<(), ButtplugError> {
+  check_message_support(message_type, message_attributes)?;
+  let sensor_count = message_attributes[message_type].feature_count.unwrap_or(0);
+  if sensor_index >= sensor_count {
+    Err(ButtplugDeviceError::DeviceFeatureIndexError(sensor_count, sensor_index).into())
+  } else {
+    Ok(())
+  }
+}
+
 pub trait ButtplugProtocolProperties {
   fn name(&self) -> &str;
   fn message_attributes(&self) -> DeviceMessageAttributesMap;
@@ -217,6 +330,10 @@ pub trait ButtplugProtocolProperties {
         &ButtplugDeviceMessageType::LinearCmd,
         &self.message_attributes(),
       ),
+      ButtplugDeviceCommandMessageUnion::OscillateCmd(_) => check_message_support(
+        &ButtplugDeviceMessageType::OscillateCmd,
+        &self.message_attributes(),
+      ),
       ButtplugDeviceCommandMessageUnion::RawReadCmd(_) => check_message_support(
         &ButtplugDeviceMessageType::RawReadCmd,
         &self.message_attributes(),
@@ -241,6 +358,16 @@ pub trait ButtplugProtocolProperties {
         &ButtplugDeviceMessageType::RSSILevelCmd,
         &self.message_attributes(),
       ),
+      ButtplugDeviceCommandMessageUnion::SensorSubscribeCmd(msg) => check_sensor_support(
+        &ButtplugDeviceMessageType::SensorSubscribeCmd,
+        msg.sensor_index(),
+        &self.message_attributes(),
+      ),
+      ButtplugDeviceCommandMessageUnion::SensorUnsubscribeCmd(msg) => check_sensor_support(
+        &ButtplugDeviceMessageType::SensorUnsubscribeCmd,
+        msg.sensor_index(),
+        &self.message_attributes(),
+      ),
       // We translate SingleMotorVibrateCmd into Vibrate, so this one is special.
       ButtplugDeviceCommandMessageUnion::SingleMotorVibrateCmd(_) => check_message_support(
         &ButtplugDeviceMessageType::VibrateCmd,
@@ -250,6 +377,10 @@ pub trait ButtplugProtocolProperties {
         &ButtplugDeviceMessageType::StopDeviceCmd,
         &self.message_attributes(),
       ),
+      ButtplugDeviceCommandMessageUnion::UploadPatternCmd(_) => check_message_support(
+        &ButtplugDeviceMessageType::UploadPatternCmd,
+        &self.message_attributes(),
+      ),
       ButtplugDeviceCommandMessageUnion::VibrateCmd(_) => check_message_support(
         &ButtplugDeviceMessageType::VibrateCmd,
         &self.message_attributes(),
@@ -287,8 +418,12 @@ pub trait ButtplugProtocolCommandHandler: Send + ButtplugProtocolProperties {
       ButtplugDeviceCommandMessageUnion::RawReadCmd(msg) => self.handle_raw_read_cmd(device, msg),
       ButtplugDeviceCommandMessageUnion::RawWriteCmd(msg) => self.handle_raw_write_cmd(device, msg),
       ButtplugDeviceCommandMessageUnion::RotateCmd(msg) => self.handle_rotate_cmd(device, msg),
+      ButtplugDeviceCommandMessageUnion::OscillateCmd(msg) => {
+        self.handle_oscillate_cmd(device, msg)
+      }
+      // ButtplugDevice upgrades these to VibrateCmd before they get here.
       ButtplugDeviceCommandMessageUnion::SingleMotorVibrateCmd(msg) => {
-        self.handle_single_motor_vibrate_cmd(device, msg)
+        self.command_unimplemented(print_type_of(&msg))
       }
       ButtplugDeviceCommandMessageUnion::StopDeviceCmd(msg) => {
         self.handle_stop_device_cmd(device, msg)
@@ -309,6 +444,15 @@ pub trait ButtplugProtocolCommandHandler: Send + ButtplugProtocolProperties {
       ButtplugDeviceCommandMessageUnion::RSSILevelCmd(msg) => {
         self.handle_rssi_level_cmd(device, msg)
       }
+      ButtplugDeviceCommandMessageUnion::SensorSubscribeCmd(msg) => {
+        self.handle_sensor_subscribe_cmd(device, msg)
+      }
+      ButtplugDeviceCommandMessageUnion::SensorUnsubscribeCmd(msg) => {
+        self.handle_sensor_unsubscribe_cmd(device, msg)
+      }
+      ButtplugDeviceCommandMessageUnion::UploadPatternCmd(msg) => {
+        self.handle_upload_pattern_cmd(device, msg)
+      }
     }
   }
 
@@ -318,6 +462,18 @@ pub trait ButtplugProtocolCommandHandler: Send + ButtplugProtocolProperties {
     message: messages::StopDeviceCmd,
   ) -> ButtplugDeviceResultFuture {
     let ok_return = messages::Ok::new(message.id());
+    // Devices configured with ConfirmWrites on StopDeviceCmd get their stop
+    // writes verified, and failures are returned so the device manager can
+    // escalate them instead of just logging.
+    let confirmation = self
+      .message_attributes()
+      .get(&ButtplugDeviceMessageType::StopDeviceCmd)
+      .and_then(|attrs| attrs.confirm_writes.clone());
+    let confirm_writes = confirmation.is_some();
+    let device = match confirmation {
+      Some(confirmation) => Arc::new(DeviceImpl::new_with_confirmed_writes(device, confirmation)),
+      None => device,
+    };
     let fut_vec: Vec<ButtplugDeviceResultFuture> = self
       .stop_commands()
       .iter()
@@ -325,57 +481,20 @@ pub trait ButtplugProtocolCommandHandler: Send + ButtplugProtocolProperties {
       .collect();
     Box::pin(async move {
       // TODO We should be able to run these concurrently, and should return any error we get.
+      let mut confirm_error = None;
       for fut in fut_vec {
         if let Err(e) = fut.await {
           error!("{:?}", e);
+          if confirm_writes && confirm_error.is_none() {
+            confirm_error = Some(e);
+          }
         }
       }
-      Ok(ok_return.into())
-    })
-  }
-
-  fn handle_single_motor_vibrate_cmd(
-    &self,
-    device: Arc<DeviceImpl>,
-    message: messages::SingleMotorVibrateCmd,
-  ) -> ButtplugDeviceResultFuture {
-    // Time for sadness! In order to handle conversion of
-    // SingleMotorVibrateCmd, we need to know how many vibrators a device
-    // has. We don't actually know that until we get to the protocol level,
-    // so we're stuck parsing this here. Since we can assume
-    // SingleMotorVibrateCmd will ALWAYS map to vibration, we can convert to
-    // VibrateCmd here and save ourselves having to handle it in every
-    // protocol, meaning spec v0 and v1 programs will still be forward
-    // compatible with vibrators.
-    let vibrator_count;
-    if let Some(attr) = self
-      .message_attributes()
-      .get(&ButtplugDeviceMessageType::VibrateCmd)
-    {
-      if let Some(count) = attr.feature_count {
-        vibrator_count = count as usize;
-      } else {
-        return ButtplugDeviceError::ProtocolRequirementError(format!(
-          "{} needs to support VibrateCmd with a feature count to use SingleMotorVibrateCmd.",
-          self.name()
-        ))
-        .into();
+      match confirm_error {
+        Some(e) => Err(e),
+        None => Ok(ok_return.into()),
       }
-    } else {
-      return ButtplugDeviceError::ProtocolRequirementError(format!(
-        "{} needs to support VibrateCmd to use SingleMotorVibrateCmd.",
-        self.name()
-      ))
-      .into();
-    }
-    let speed = message.speed();
-    let mut cmds = vec![];
-    for i in 0..vibrator_count {
-      cmds.push(VibrateSubcommand::new(i as u32, speed));
-    }
-    let mut vibrate_cmd = VibrateCmd::new(message.device_index(), cmds);
-    vibrate_cmd.set_id(message.id());
-    self.handle_command(device, vibrate_cmd.into())
+    })
   }
 
   fn handle_raw_write_cmd(
@@ -452,6 +571,15 @@ pub trait ButtplugProtocolCommandHandler: Send + ButtplugProtocolProperties {
     self.command_unimplemented(print_type_of(&message))
   }
 
+  /// Calibration for translating FleshlightLaunchFW12Cmd into LinearCmd, for
+  /// devices that support LinearCmd. Protocols that speak the Launch's own
+  /// speed/position commands should return None, so the message reaches
+  /// [handle_fleshlight_launch_fw12_cmd][Self::handle_fleshlight_launch_fw12_cmd]
+  /// untouched.
+  fn fleshlight_launch_calibration(&self) -> Option<LaunchCalibration> {
+    Some(LaunchCalibration::default())
+  }
+
   fn handle_fleshlight_launch_fw12_cmd(
     &self,
     _device: Arc<DeviceImpl>,
@@ -484,6 +612,14 @@ pub trait ButtplugProtocolCommandHandler: Send + ButtplugProtocolProperties {
     self.command_unimplemented(print_type_of(&message))
   }
 
+  fn handle_oscillate_cmd(
+    &self,
+    _device: Arc<DeviceImpl>,
+    message: messages::OscillateCmd,
+  ) -> ButtplugDeviceResultFuture {
+    self.command_unimplemented(print_type_of(&message))
+  }
+
   fn handle_battery_level_cmd(
     &self,
     device: Arc<DeviceImpl>,
@@ -515,6 +651,46 @@ pub trait ButtplugProtocolCommandHandler: Send + ButtplugProtocolProperties {
   ) -> ButtplugDeviceResultFuture {
     self.command_unimplemented(print_type_of(&message))
   }
+
+  fn handle_sensor_subscribe_cmd(
+    &self,
+    _device: Arc<DeviceImpl>,
+    message: messages::SensorSubscribeCmd,
+  ) -> ButtplugDeviceResultFuture {
+    self.command_unimplemented(print_type_of(&message))
+  }
+
+  fn handle_sensor_unsubscribe_cmd(
+    &self,
+    _device: Arc<DeviceImpl>,
+    message: messages::SensorUnsubscribeCmd,
+  ) -> ButtplugDeviceResultFuture {
+    self.command_unimplemented(print_type_of(&message))
+  }
+
+  /// Turns a notification from one of the device's endpoints into readings
+  /// for its sensors, for protocols that handle SensorSubscribeCmd. One
+  /// notification can carry readings for several sensors. The server only
+  /// relays readings for sensors a client has subscribed to.
+  fn parse_sensor_notification(
+    &self,
+    _device_index: u32,
+    _endpoint: Endpoint,
+    _data: &[u8],
+  ) -> Vec<SensorReading> {
+    vec![]
+  }
+
+  /// Protocols for devices that can play back patterns from their own
+  /// firmware should override this, and list UploadPatternCmd in their device
+  /// configuration.
+  fn handle_upload_pattern_cmd(
+    &self,
+    _device: Arc<DeviceImpl>,
+    message: messages::UploadPatternCmd,
+  ) -> ButtplugDeviceResultFuture {
+    self.command_unimplemented(print_type_of(&message))
+  }
 }
 
 #[macro_export]
@@ -552,14 +728,14 @@ macro_rules! default_protocol_trait_declaration {
   ( $protocol_name:ident ) => {
     impl ButtplugProtocol for $protocol_name {
       fn try_create(
-        device_impl: Arc<crate::device::DeviceImpl>,
-        config: crate::device::protocol::DeviceProtocolConfiguration,
+        device_impl: Arc<$crate::device::DeviceImpl>,
+        config: $crate::device::configuration_manager::DeviceProtocolConfiguration,
       ) -> futures::future::BoxFuture<
         'static,
-        Result<Box<dyn ButtplugProtocol>, crate::core::errors::ButtplugError>,
+        Result<Box<dyn ButtplugProtocol>, $crate::core::errors::ButtplugError>,
       > {
         let device = {
-          match crate::device::protocol::get_protocol_features(device_impl, None, config) {
+          match $crate::device::protocol::get_protocol_features(device_impl, None, config) {
             Ok((name, attrs)) => Ok(Box::new(Self::new(&name, attrs)) as Box<dyn ButtplugProtocol>),
             Err(e) => Err(e),
           }
@@ -573,9 +749,9 @@ macro_rules! default_protocol_trait_declaration {
 #[macro_export]
 macro_rules! default_protocol_declaration {
   ( $protocol_name:ident ) => {
-    crate::default_protocol_definition!($protocol_name);
+    $crate::default_protocol_definition!($protocol_name);
 
-    crate::default_protocol_trait_declaration!($protocol_name);
+    $crate::default_protocol_trait_declaration!($protocol_name);
   };
 }
 
@@ -1,4 +1,9 @@
-use super::{ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolCommandHandler};
+use super::{
+  command_refresher::CommandRefresher,
+  ButtplugDeviceResultFuture,
+  ButtplugProtocol,
+  ButtplugProtocolCommandHandler,
+};
 use crate::{
   core::messages::{self, ButtplugDeviceCommandMessageUnion, DeviceMessageAttributesMap},
   device::{
@@ -7,20 +12,13 @@ use crate::{
     DeviceWriteCmd,
     Endpoint,
   },
-  util::async_manager,
-};
-use futures_timer::Delay;
-use std::{
-  sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
-  },
-  time::Duration,
 };
-use tokio::sync::{Mutex, RwLock};
+use std::sync::Arc;
+use tokio::sync::Mutex;
 
 // Time between Mysteryvibe update commands, in milliseconds. This is basically
-// a best guess derived from watching packet timing a few years ago.
+// a best guess derived from watching packet timing a few years ago. Motors stop
+// if they go a couple of seconds without a command.
 //
 // Thelemic vibrator. Neat.
 //
@@ -32,8 +30,7 @@ pub struct MysteryVibe {
   message_attributes: DeviceMessageAttributesMap,
   manager: Arc<Mutex<GenericCommandManager>>,
   stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
-  current_command: Arc<RwLock<Vec<u8>>>,
-  updater_running: Arc<AtomicBool>,
+  refresher: CommandRefresher,
 }
 
 impl MysteryVibe {
@@ -45,8 +42,7 @@ impl MysteryVibe {
       message_attributes,
       stop_commands: manager.get_stop_commands(),
       manager: Arc::new(Mutex::new(manager)),
-      updater_running: Arc::new(AtomicBool::new(false)),
-      current_command: Arc::new(RwLock::new(vec![0u8, 0, 0, 0, 0, 0])),
+      refresher: CommandRefresher::new(Endpoint::TxVibrate, false, MYSTERYVIBE_COMMAND_DELAY_MS),
     }
   }
 }
@@ -70,25 +66,6 @@ impl ButtplugProtocol for MysteryVibe {
   }
 }
 
-async fn vibration_update_handler(device: Arc<DeviceImpl>, command_holder: Arc<RwLock<Vec<u8>>>) {
-  info!("Entering Mysteryvibe Control Loop");
-  let mut current_command = command_holder.read().await.clone();
-  while device
-    .write_value(DeviceWriteCmd::new(
-      Endpoint::TxVibrate,
-      current_command,
-      false,
-    ))
-    .await
-    .is_ok()
-  {
-    Delay::new(Duration::from_millis(MYSTERYVIBE_COMMAND_DELAY_MS)).await;
-    current_command = command_holder.read().await.clone();
-    info!("MV Command: {:?}", current_command);
-  }
-  info!("Mysteryvibe control loop exiting, most likely due to device disconnection.");
-}
-
 impl ButtplugProtocolCommandHandler for MysteryVibe {
   fn handle_vibrate_cmd(
     &self,
@@ -96,34 +73,107 @@ impl ButtplugProtocolCommandHandler for MysteryVibe {
     message: messages::VibrateCmd,
   ) -> ButtplugDeviceResultFuture {
     let manager = self.manager.clone();
-    let current_command = self.current_command.clone();
-    let update_running = self.updater_running.clone();
+    let refresher = self.refresher.clone();
     Box::pin(async move {
-      let result = manager.lock().await.update_vibration(&message, true)?;
-      info!("MV Result: {:?}", result);
-      if result.is_none() {
-        return Ok(messages::Ok::default().into());
-      }
-      let write_mutex = current_command.clone();
-      let mut command_writer = write_mutex.write().await;
-      let command: Vec<u8> = result
-        .expect("Already checked validity")
+      // Every motor's speed goes in each command, so send them all, not just
+      // the ones this command changed.
+      let speeds = {
+        let mut manager = manager.lock().await;
+        if manager.update_vibration(&message)?.is_none() {
+          return Ok(messages::Ok::default().into());
+        }
+        manager.get_vibration()
+      };
+      let command = speeds
         .into_iter()
-        .map(|x| x.expect("Validity ensured via GCM match_all") as u8)
+        .map(|speed| speed.unwrap_or(0) as u8)
         .collect();
-      *command_writer = command;
-      if !update_running.load(Ordering::SeqCst) {
-        async_manager::spawn(
-          async move { vibration_update_handler(device, current_command).await },
-        );
-        update_running.store(true, Ordering::SeqCst);
-      }
+      refresher.update(device, command).await?;
       Ok(messages::Ok::default().into())
     })
   }
 }
 
-// TODO Write some tests!
-//
-// At least, once I figure out how to do that with the weird timing on this
-// thing.
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use super::MYSTERYVIBE_COMMAND_DELAY_MS;
+  use crate::{
+    core::messages::{StopDeviceCmd, VibrateCmd, VibrateSubcommand},
+    device::{DeviceImplCommand, DeviceWriteCmd, Endpoint},
+    server::comm_managers::test::{check_test_recv_value, new_bluetoothle_test_device},
+    util::async_manager,
+  };
+  use futures_timer::Delay;
+  use std::time::Duration;
+
+  #[test]
+  pub fn test_mysteryvibe_protocol_six_motors() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("MV Crescendo")
+        .await
+        .expect("Test, assuming infallible");
+      let mode_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::TxMode)
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &mode_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::TxMode,
+          vec![0x43, 0x02, 0x00],
+          true,
+        )),
+      );
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::TxVibrate)
+        .expect("Test, assuming infallible");
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(3, 0.5)]).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::TxVibrate,
+          vec![0, 0, 0, 28, 0, 0],
+          false,
+        )),
+      );
+      // The command should be resent until something replaces it, otherwise
+      // the motors stall.
+      Delay::new(Duration::from_millis(MYSTERYVIBE_COMMAND_DELAY_MS + 50)).await;
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::TxVibrate,
+          vec![0, 0, 0, 28, 0, 0],
+          false,
+        )),
+      );
+      // Updating one motor keeps the others running.
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 1.0)]).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::TxVibrate,
+          vec![56, 0, 0, 28, 0, 0],
+          false,
+        )),
+      );
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::TxVibrate,
+          vec![0, 0, 0, 0, 0, 0],
+          false,
+        )),
+      );
+    });
+  }
+}
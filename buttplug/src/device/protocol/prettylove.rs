@@ -40,7 +40,7 @@ impl ButtplugProtocolCommandHandler for PrettyLove {
     // Store off result before the match, so we drop the lock ASAP.
     let manager = self.manager.clone();
     Box::pin(async move {
-      let result = manager.lock().await.update_vibration(&message, false)?;
+      let result = manager.lock().await.update_vibration(&message)?;
       if let Some(cmds) = result {
         if let Some(speed) = cmds[0] {
           device
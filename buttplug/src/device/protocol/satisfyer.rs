@@ -8,59 +8,29 @@ use crate::{
     DeviceWriteCmd,
     Endpoint,
   },
-  util::async_manager,
 };
-use std::{sync::Arc, time::Duration};
+use std::sync::Arc;
 use tokio::sync::Mutex;
 
+// Satisfyer toys will drop their connections if they don't get an update
+// within ~10 seconds. The keepalive in the device config resends the last
+// command every ~3s unless something is sent sooner.
 #[derive(ButtplugProtocolProperties)]
 pub struct Satisfyer {
   name: String,
   message_attributes: DeviceMessageAttributesMap,
   manager: Arc<Mutex<GenericCommandManager>>,
   stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
-  last_command: Arc<Mutex<Vec<u8>>>,
-}
-
-// Satisfyer toys will drop their connections if they don't get an update within ~10 seconds.
-// Therefore we try to send a command every ~3s unless something is sent/updated sooner.
-async fn send_satisfyer_updates(device: Arc<DeviceImpl>, data: Arc<Mutex<Vec<u8>>>) {
-  while device.connected() {
-    // Scope to make sure we drop the lock before sleeping.
-    {
-      let current_data = data.lock().await.clone();
-      if let Err(e) = device
-        .write_value(DeviceWriteCmd::new(
-          Endpoint::Tx,
-          current_data.clone().to_vec(),
-          false,
-        ))
-        .await
-      {
-        error!(
-          "Got an error from a satisfyer device, exiting control loop: {:?}",
-          e
-        );
-        break;
-      }
-    }
-    tokio::time::sleep(Duration::from_secs(3)).await;
-  }
 }
 
 impl Satisfyer {
-  fn new(
-    name: &str,
-    message_attributes: DeviceMessageAttributesMap,
-    last_command: Arc<Mutex<Vec<u8>>>,
-  ) -> Self {
+  fn new(name: &str, message_attributes: DeviceMessageAttributesMap) -> Self {
     let manager = GenericCommandManager::new(&message_attributes);
     Self {
       name: name.to_owned(),
       message_attributes,
       stop_commands: manager.get_stop_commands(),
       manager: Arc::new(Mutex::new(manager)),
-      last_command,
     }
   }
 }
@@ -81,7 +51,7 @@ impl ButtplugProtocol for Satisfyer {
         .read_value(DeviceReadCmd::new(Endpoint::RxBLEModel, 128, 500))
         .await?;
       let mut device_identifier =
-        String::from_utf8(result.data().to_vec()).unwrap_or_else(|_| device_impl.name.clone());
+        String::from_utf8(result.data().to_vec()).unwrap_or_else(|_| device_impl.name().to_owned());
       // Satisfyer devices send a null character at the end of their names. Pop that off, as it's
       // still a valid utf-8 character and screws up our string comparisons.
       device_identifier.pop();
@@ -92,18 +62,11 @@ impl ButtplugProtocol for Satisfyer {
       );
       info_fut.await?;
       let (name, attrs) = crate::device::protocol::get_protocol_features(
-        device_impl.clone(),
+        device_impl,
         Some(device_identifier),
         config,
       )?;
-      // Now that we've initialized and constructed the device, start the update cycle to make sure
-      // we don't drop the connection.
-      let last_command = Arc::new(Mutex::new(vec![0u8; 8]));
-      let device = Self::new(&name, attrs, last_command.clone());
-      async_manager::spawn(async move {
-        send_satisfyer_updates(device_impl, last_command).await;
-      });
-      Ok(Box::new(device) as Box<dyn ButtplugProtocol>)
+      Ok(Box::new(Self::new(&name, attrs)) as Box<dyn ButtplugProtocol>)
     })
   }
 }
@@ -116,9 +79,8 @@ impl ButtplugProtocolCommandHandler for Satisfyer {
   ) -> ButtplugDeviceResultFuture {
     // Store off result before the match, so we drop the lock ASAP.
     let manager = self.manager.clone();
-    let last_command = self.last_command.clone();
     Box::pin(async move {
-      let result = manager.lock().await.update_vibration(&message, true)?;
+      let result = manager.lock().await.update_vibration(&message)?;
       if let Some(cmds) = result {
         let data = if cmds.len() == 1 {
           vec![
@@ -143,7 +105,6 @@ impl ButtplugProtocolCommandHandler for Satisfyer {
             cmds[0].unwrap_or(0) as u8,
           ]
         };
-        *last_command.lock().await = data.clone();
         device
           .write_value(DeviceWriteCmd::new(Endpoint::Tx, data, false))
           .await?;
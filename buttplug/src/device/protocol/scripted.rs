@@ -0,0 +1,449 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Protocols defined by a script in the device configuration.
+//!
+//! Lets device tinkerers prototype support for a new device without
+//! rebuilding the library, by adding a `script` to its protocol definition.
+//! Scripts are written in [Rhai](https://rhai.rs), and can define:
+//!
+//! - `vibrate(speeds)`: Called for VibrateCmd, with an array of the step value
+//!   for every vibration feature, including ones the command didn't change.
+//! - `linear(index, position, duration)`: Called for each vector of a
+//!   LinearCmd, with the position as a float from 0.0 to 1.0 and the duration
+//!   in milliseconds.
+//!
+//! Each function returns the bytes to write to the script's endpoint, as an
+//! array of integers for a single write, an array of such arrays for several
+//! writes, or `()` to write nothing. For instance:
+//!
+//! ```text
+//! fn vibrate(speeds) {
+//!   [0x0f, 0x03, speeds[0]]
+//! }
+//! ```
+//!
+//! Message attributes still come from the protocol definition, and a device is
+//! only created if the script defines a function for each command its
+//! attributes list.
+
+use super::{
+  generic_command_manager::GenericCommandManager,
+  get_protocol_features,
+  ButtplugDeviceResultFuture,
+  ButtplugProtocol,
+  ButtplugProtocolCommandHandler,
+  ButtplugProtocolFactory,
+  ButtplugProtocolProperties,
+};
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::{
+      self,
+      ButtplugDeviceCommandMessageUnion,
+      ButtplugDeviceMessageType,
+      DeviceMessageAttributesMap,
+    },
+  },
+  device::{
+    configuration_manager::{DeviceProtocolConfiguration, ProtocolScript},
+    DeviceImpl,
+    DeviceWriteCmd,
+    Endpoint,
+  },
+};
+use futures::future::{self, BoxFuture};
+use rhai::{Array, Dynamic, Engine, Scope, AST};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Most operations a single script call can run before it's stopped, so a
+/// script stuck in a loop can't hang the device.
+pub const SCRIPT_MAX_OPERATIONS: u64 = 100_000;
+
+const VIBRATE_FUNCTION: &str = "vibrate";
+const LINEAR_FUNCTION: &str = "linear";
+
+/// A compiled protocol script, shared by all devices using the protocol.
+struct ProtocolScriptRunner {
+  protocol_name: String,
+  engine: Engine,
+  ast: AST,
+  endpoint: Endpoint,
+}
+
+impl ProtocolScriptRunner {
+  fn has_function(&self, function: &str) -> bool {
+    self.ast.iter_functions().any(|f| f.name == function)
+  }
+
+  fn error(&self, message: String) -> ButtplugError {
+    ButtplugDeviceError::ProtocolSpecificError(self.protocol_name.clone(), message).into()
+  }
+
+  /// Calls `function`, turning whatever it returns into device writes.
+  fn call(
+    &self,
+    function: &str,
+    args: impl rhai::FuncArgs,
+  ) -> Result<Vec<DeviceWriteCmd>, ButtplugError> {
+    let result: Dynamic = self
+      .engine
+      .call_fn(&mut Scope::new(), &self.ast, function, args)
+      .map_err(|err| self.error(format!("Script function {} failed: {}", function, err)))?;
+    let packets = if result.is_unit() {
+      vec![]
+    } else {
+      let array = result.try_cast::<Array>().ok_or_else(|| {
+        self.error(format!(
+          "Script function {} must return an array.",
+          function
+        ))
+      })?;
+      if array.iter().all(|value| value.is_array()) {
+        array
+          .into_iter()
+          .map(|packet| self.packet_bytes(function, packet.cast::<Array>()))
+          .collect::<Result<_, _>>()?
+      } else {
+        vec![self.packet_bytes(function, array)?]
+      }
+    };
+    Ok(
+      packets
+        .into_iter()
+        .map(|data| DeviceWriteCmd::new(self.endpoint, data, false))
+        .collect(),
+    )
+  }
+
+  fn packet_bytes(&self, function: &str, packet: Array) -> Result<Vec<u8>, ButtplugError> {
+    packet
+      .into_iter()
+      .map(|value| {
+        value
+          .as_int()
+          .ok()
+          .and_then(|byte| u8::try_from(byte).ok())
+          .ok_or_else(|| {
+            self.error(format!(
+              "Script function {} returned {}, which isn't a byte.",
+              function, value
+            ))
+          })
+      })
+      .collect()
+  }
+}
+
+/// Creates [ScriptedProtocol]s from a [ProtocolScript]. Registered for any
+/// protocol definition with a script when it's added to the
+/// [DeviceConfigurationManager][crate::device::configuration_manager::DeviceConfigurationManager].
+pub struct ScriptedProtocolFactory {
+  runner: Arc<ProtocolScriptRunner>,
+}
+
+impl ScriptedProtocolFactory {
+  /// Compiles `script`. Fails if it isn't valid Rhai.
+  pub fn new(protocol_name: &str, script: &ProtocolScript) -> Result<Self, ButtplugError> {
+    let mut engine = Engine::new();
+    engine.set_max_operations(SCRIPT_MAX_OPERATIONS);
+    let ast = engine.compile(&script.source).map_err(|err| {
+      ButtplugDeviceError::ProtocolSpecificError(
+        protocol_name.to_owned(),
+        format!("Script failed to compile: {}", err),
+      )
+    })?;
+    Ok(Self {
+      runner: Arc::new(ProtocolScriptRunner {
+        protocol_name: protocol_name.to_owned(),
+        engine,
+        ast,
+        endpoint: script.endpoint,
+      }),
+    })
+  }
+}
+
+impl ButtplugProtocolFactory for ScriptedProtocolFactory {
+  fn try_create(
+    &self,
+    device_impl: Arc<DeviceImpl>,
+    config: DeviceProtocolConfiguration,
+  ) -> BoxFuture<'static, Result<Box<dyn ButtplugProtocol>, ButtplugError>> {
+    let protocol = get_protocol_features(device_impl, None, config).and_then(|(name, attrs)| {
+      for (message_type, function) in [
+        (ButtplugDeviceMessageType::VibrateCmd, VIBRATE_FUNCTION),
+        (ButtplugDeviceMessageType::LinearCmd, LINEAR_FUNCTION),
+      ] {
+        if attrs.contains_key(&message_type) && !self.runner.has_function(function) {
+          return Err(self.runner.error(format!(
+            "Device supports {:?}, but the script has no {} function.",
+            message_type, function
+          )));
+        }
+      }
+      Ok(
+        Box::new(ScriptedProtocol::new(&name, attrs, self.runner.clone()))
+          as Box<dyn ButtplugProtocol>,
+      )
+    });
+    Box::pin(future::ready(protocol))
+  }
+}
+
+#[derive(ButtplugProtocolProperties)]
+pub struct ScriptedProtocol {
+  name: String,
+  message_attributes: DeviceMessageAttributesMap,
+  manager: Arc<Mutex<GenericCommandManager>>,
+  stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+  runner: Arc<ProtocolScriptRunner>,
+  // Last step sent to each vibration feature, as scripts get every feature.
+  speeds: Arc<Mutex<Vec<u32>>>,
+}
+
+impl ScriptedProtocol {
+  fn new(
+    name: &str,
+    message_attributes: DeviceMessageAttributesMap,
+    runner: Arc<ProtocolScriptRunner>,
+  ) -> Self {
+    let manager = GenericCommandManager::new(&message_attributes);
+    let vibrator_count = message_attributes
+      .get(&ButtplugDeviceMessageType::VibrateCmd)
+      .and_then(|attrs| attrs.feature_count)
+      .unwrap_or(0);
+    Self {
+      name: name.to_owned(),
+      message_attributes,
+      stop_commands: manager.get_stop_commands(),
+      manager: Arc::new(Mutex::new(manager)),
+      runner,
+      speeds: Arc::new(Mutex::new(vec![0; vibrator_count as usize])),
+    }
+  }
+}
+
+impl ButtplugProtocol for ScriptedProtocol {
+}
+
+fn write_all(device: Arc<DeviceImpl>, writes: Vec<DeviceWriteCmd>) -> ButtplugDeviceResultFuture {
+  Box::pin(async move {
+    for write in writes {
+      device.write_value(write).await?;
+    }
+    Ok(messages::Ok::default().into())
+  })
+}
+
+impl ButtplugProtocolCommandHandler for ScriptedProtocol {
+  fn handle_vibrate_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::VibrateCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let manager = self.manager.clone();
+    let speeds = self.speeds.clone();
+    let runner = self.runner.clone();
+    Box::pin(async move {
+      let result = manager.lock().await.update_vibration(&message)?;
+      let writes = match result {
+        Some(cmds) => {
+          let mut speeds = speeds.lock().await;
+          for (speed, cmd) in speeds.iter_mut().zip(cmds) {
+            if let Some(cmd) = cmd {
+              *speed = cmd;
+            }
+          }
+          let speeds: Array = speeds
+            .iter()
+            .map(|speed| Dynamic::from(*speed as i64))
+            .collect();
+          runner.call(VIBRATE_FUNCTION, (speeds,))?
+        }
+        None => vec![],
+      };
+      write_all(device, writes).await
+    })
+  }
+
+  fn handle_linear_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::LinearCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let mut writes = vec![];
+    for vector in message.vectors() {
+      match self.runner.call(
+        LINEAR_FUNCTION,
+        (
+          vector.index() as i64,
+          *vector.position(),
+          vector.duration() as i64,
+        ),
+      ) {
+        Ok(vector_writes) => writes.extend(vector_writes),
+        Err(err) => return Box::pin(future::ready(Err(err))),
+      }
+    }
+    write_all(device, writes)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::ScriptedProtocolFactory;
+  use crate::{
+    core::messages::{LinearCmd, StopDeviceCmd, VectorSubcommand, VibrateCmd, VibrateSubcommand},
+    device::{
+      configuration_manager::{
+        BluetoothLESpecifier,
+        DeviceConfigurationManager,
+        DeviceSpecifier,
+        ProtocolDefinition,
+        ProtocolScript,
+      },
+      ButtplugDevice,
+      DeviceImplCommand,
+      DeviceWriteCmd,
+      Endpoint,
+    },
+    server::comm_managers::test::{
+      check_test_recv_empty,
+      check_test_recv_value,
+      new_bluetoothle_test_device_with_cfg,
+      TestDeviceImplCreator,
+      TestDeviceInternal,
+    },
+    util::async_manager,
+  };
+  use std::sync::Arc;
+
+  fn scripted_dcm(script: &str) -> Arc<DeviceConfigurationManager> {
+    let dcm = DeviceConfigurationManager::default();
+    let definition: ProtocolDefinition = serde_json::from_value(serde_json::json!({
+      "btle": {
+        "names": ["Script Toy"],
+        "services": {
+          "0000ff00-0000-1000-8000-00805f9b34fb": {
+            "tx": "0000ff01-0000-1000-8000-00805f9b34fb"
+          }
+        }
+      },
+      "script": { "source": script },
+      "defaults": {
+        "name": { "en-us": "Script Toy" },
+        "messages": {
+          "VibrateCmd": { "FeatureCount": 2, "StepCount": [20, 20] },
+          "LinearCmd": { "FeatureCount": 1 }
+        }
+      }
+    }))
+    .expect("Test, assuming infallible");
+    dcm.add_protocol_definition("script-toy", definition);
+    Arc::new(dcm)
+  }
+
+  fn tx_write(data: Vec<u8>) -> DeviceImplCommand {
+    DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, data, false))
+  }
+
+  #[test]
+  pub fn test_scripted_protocol() {
+    async_manager::block_on(async move {
+      let dcm = scripted_dcm(
+        r#"
+          fn vibrate(speeds) { [0x0f, speeds[0], speeds[1]] }
+          fn linear(index, position, duration) {
+            [[0x10, index], [0x11, (position * 100.0).to_int(), duration / 10]]
+          }
+        "#,
+      );
+      let (device, test_device) =
+        new_bluetoothle_test_device_with_cfg("Script Toy", Some(dcm), |_| {})
+          .await
+          .expect("Test, assuming infallible");
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible");
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(1, 0.5)]).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(&command_receiver, tx_write(vec![0x0f, 0, 10]));
+      // Unchanged features keep their last value.
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 1.0)]).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(&command_receiver, tx_write(vec![0x0f, 20, 10]));
+      device
+        .parse_message(LinearCmd::new(0, vec![VectorSubcommand::new(0, 500, 0.25)]).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(&command_receiver, tx_write(vec![0x10, 0]));
+      check_test_recv_value(&command_receiver, tx_write(vec![0x11, 25, 50]));
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(&command_receiver, tx_write(vec![0x0f, 0, 0]));
+      assert!(check_test_recv_empty(&command_receiver));
+    });
+  }
+
+  #[test]
+  pub fn test_scripted_protocol_bad_output() {
+    async_manager::block_on(async move {
+      let dcm = scripted_dcm(
+        r#"
+          fn vibrate(speeds) { [0x0f, 256] }
+          fn linear(index, position, duration) { loop {} }
+        "#,
+      );
+      let (device, _) = new_bluetoothle_test_device_with_cfg("Script Toy", Some(dcm), |_| {})
+        .await
+        .expect("Test, assuming infallible");
+      assert!(device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]).into())
+        .await
+        .is_err());
+      // Runaway scripts are stopped instead of hanging the device.
+      assert!(device
+        .parse_message(LinearCmd::new(0, vec![VectorSubcommand::new(0, 500, 0.25)]).into())
+        .await
+        .is_err());
+    });
+  }
+
+  #[test]
+  pub fn test_scripted_protocol_missing_function() {
+    async_manager::block_on(async move {
+      let dcm = scripted_dcm("fn vibrate(speeds) { [0x0f] }");
+      let specifier =
+        DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device("Script Toy", &[]));
+      let mut creator = TestDeviceImplCreator::new(
+        specifier,
+        Arc::new(TestDeviceInternal::new("Script Toy", "scripted")),
+      );
+      assert!(ButtplugDevice::try_create_device(dcm, &mut creator, None)
+        .await
+        .is_err());
+    });
+  }
+
+  #[test]
+  pub fn test_scripted_protocol_compile_error() {
+    let script = ProtocolScript {
+      source: "fn vibrate(speeds) {".to_owned(),
+      endpoint: Endpoint::Tx,
+    };
+    assert!(ScriptedProtocolFactory::new("script-toy", &script).is_err());
+  }
+}
@@ -20,7 +20,7 @@ impl ButtplugProtocolCommandHandler for SvakomIker {
   ) -> ButtplugDeviceResultFuture {
     let manager = self.manager.clone();
     Box::pin(async move {
-      let result = manager.lock().await.update_vibration(&message, false)?;
+      let result = manager.lock().await.update_vibration(&message)?;
       let mut vibe_off = false;
       if let Some(cmds) = result {
         if let Some(speed) = cmds[0] {
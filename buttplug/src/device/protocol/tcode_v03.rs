@@ -1,37 +1,131 @@
 use super::{
   generic_command_manager::GenericCommandManager,
+  linear_motion::{device_position, LaunchCalibration, LinearAxes, LinearSegment},
   ButtplugDeviceResultFuture,
   ButtplugProtocol,
   ButtplugProtocolCommandHandler,
 };
 use crate::{
-  core::messages::{self, ButtplugDeviceCommandMessageUnion, DeviceMessageAttributesMap},
-  device::{protocol::ButtplugProtocolProperties, DeviceImpl, DeviceWriteCmd, Endpoint},
+  core::{
+    errors::ButtplugError,
+    messages::{self, ButtplugDeviceCommandMessageUnion, DeviceMessageAttributesMap},
+  },
+  device::{
+    configuration_manager::DeviceProtocolConfiguration,
+    protocol::ButtplugProtocolProperties,
+    DeviceImpl,
+    DeviceWriteCmd,
+    Endpoint,
+  },
+  server::device_manager::DeviceUserConfig,
 };
-use std::sync::Arc;
+use futures::future::{self, BoxFuture};
+use futures_timer::Delay;
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use tokio::sync::Mutex;
 
-super::default_protocol_declaration!(TCodeV03);
+// Calibrated against the OSR2, the most common TCode device. Its default
+// stroke is about 120mm to the Launch's 100mm, so the same speed takes longer
+// to cover the same share of it. Its servos update at 50Hz, so moves shorter
+// than two updates end up as jumps.
+const TCODE_LAUNCH_CALIBRATION: LaunchCalibration = LaunchCalibration {
+  duration_scale: 1.2,
+  min_duration: 40,
+};
+
+#[derive(ButtplugProtocolProperties)]
+pub struct TCodeV03 {
+  name: String,
+  message_attributes: DeviceMessageAttributesMap,
+  manager: Arc<Mutex<GenericCommandManager>>,
+  stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+  axes: Arc<LinearAxes>,
+}
+
+impl TCodeV03 {
+  fn new(
+    name: &str,
+    message_attributes: DeviceMessageAttributesMap,
+    user_config: &DeviceUserConfig,
+  ) -> Self {
+    let manager = GenericCommandManager::new(&message_attributes);
+
+    Self {
+      name: name.to_owned(),
+      message_attributes,
+      stop_commands: manager.get_stop_commands(),
+      manager: Arc::new(Mutex::new(manager)),
+      axes: Arc::new(LinearAxes::new(
+        user_config.linear_easing().unwrap_or_default(),
+      )),
+    }
+  }
+}
+
+impl ButtplugProtocol for TCodeV03 {
+  fn try_create(
+    device_impl: Arc<DeviceImpl>,
+    config: DeviceProtocolConfiguration,
+  ) -> BoxFuture<'static, Result<Box<dyn ButtplugProtocol>, ButtplugError>> {
+    let device =
+      super::get_protocol_features(device_impl, None, config.clone()).map(|(name, attrs)| {
+        Box::new(Self::new(&name, attrs, config.user_config())) as Box<dyn ButtplugProtocol>
+      });
+    Box::pin(future::ready(device))
+  }
+
+  fn update_user_config(&self, config: &DeviceUserConfig) {
+    self
+      .axes
+      .set_easing(config.linear_easing().unwrap_or_default());
+  }
+}
 
 impl ButtplugProtocolCommandHandler for TCodeV03 {
+  fn fleshlight_launch_calibration(&self) -> Option<LaunchCalibration> {
+    Some(TCODE_LAUNCH_CALIBRATION)
+  }
+
   fn handle_linear_cmd(
     &self,
     device: Arc<DeviceImpl>,
     msg: messages::LinearCmd,
   ) -> ButtplugDeviceResultFuture {
+    let axes = self.axes.clone();
     Box::pin(async move {
-      let mut fut_vec = vec![];
+      // TCode devices move linearly to each position they're sent, so eased
+      // moves are split into segments, each sent as it starts. They're grouped
+      // by start time so all axes move together.
+      let mut schedule: BTreeMap<u32, Vec<(u32, LinearSegment)>> = BTreeMap::new();
       for v in msg.vectors() {
-        let position = (v.position * 99f64) as u32;
-
-        let command = format!("L{}{:02}I{}\n", v.index, position, v.duration);
-        fut_vec.push(device.write_value(DeviceWriteCmd::new(
-          Endpoint::Tx,
-          command.as_bytes().to_vec(),
-          false,
-        )));
+        for segment in axes.segments(v.index, v.position, v.duration) {
+          schedule
+            .entry(segment.start)
+            .or_default()
+            .push((v.index, segment));
+        }
       }
-      for fut in fut_vec {
-        fut.await?;
+      let mut elapsed = 0;
+      for (start, segments) in schedule {
+        if start > elapsed {
+          Delay::new(Duration::from_millis((start - elapsed) as u64)).await;
+          elapsed = start;
+        }
+        let mut fut_vec = vec![];
+        for (index, segment) in segments {
+          let position = device_position(segment.position, 99f64);
+
+          let command = format!("L{}{:02}I{}\n", index, position, segment.duration);
+          fut_vec.push(device.write_value(DeviceWriteCmd::new(
+            Endpoint::Tx,
+            command.as_bytes().to_vec(),
+            false,
+          )));
+          axes.set_position(index, segment.position);
+        }
+        for fut in fut_vec {
+          fut.await?;
+        }
       }
       Ok(messages::Ok::default().into())
     })
@@ -45,7 +139,7 @@ impl ButtplugProtocolCommandHandler for TCodeV03 {
     let manager = self.manager.clone();
     Box::pin(async move {
       // Store off result before the match, so we drop the lock ASAP.
-      let result = manager.lock().await.update_vibration(&msg, false)?;
+      let result = manager.lock().await.update_vibration(&msg)?;
       let mut fut_vec = vec![];
       if let Some(cmds) = result {
         for (i, cmd) in cmds.iter().enumerate() {
@@ -1,5 +1,5 @@
 use super::{
-  fleshlight_launch_helper,
+  linear_motion::{LaunchCalibration, LinearAxes},
   ButtplugDeviceResultFuture,
   ButtplugProtocol,
   ButtplugProtocolCommandHandler,
@@ -10,25 +10,23 @@ use crate::{
     messages::{
       self,
       ButtplugDeviceCommandMessageUnion,
-      ButtplugDeviceMessage,
       DeviceMessageAttributesMap,
     },
   },
   device::{
+    configuration_manager::DeviceProtocolConfiguration,
     protocol::{generic_command_manager::GenericCommandManager, ButtplugProtocolProperties},
     DeviceImpl,
-    DeviceProtocolConfiguration,
     DeviceReadCmd,
     DeviceWriteCmd,
     Endpoint,
   },
+  server::device_manager::DeviceUserConfig,
 };
 use futures::future;
+use futures_timer::Delay;
 use prost::Message;
-use std::sync::{
-  atomic::{AtomicU8, Ordering},
-  Arc,
-};
+use std::{sync::Arc, time::Duration};
 
 mod protocomm {
   include!(concat!(env!("OUT_DIR"), "/protocomm.rs"));
@@ -38,19 +36,28 @@ mod handyplug {
   include!(concat!(env!("OUT_DIR"), "/handyplug.rs"));
 }
 
+// The Handy's stroke is about 110mm to the Launch's 100mm, so the same speed
+// takes a little longer to cover the same share of it. Its firmware doesn't
+// keep up with moves much shorter than 100ms.
+const HANDY_LAUNCH_CALIBRATION: LaunchCalibration = LaunchCalibration {
+  duration_scale: 1.1,
+  min_duration: 100,
+};
+
 #[derive(ButtplugProtocolProperties)]
 pub struct TheHandy {
   name: String,
   message_attributes: DeviceMessageAttributesMap,
   stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
-  // The generic command manager would normally handle this storage, but the only reason we're
-  // retaining tracking information is to build our fucking timing calculation for the fleshlight
-  // command backport. I am so mad right now.
-  previous_position: Arc<AtomicU8>,
+  axes: Arc<LinearAxes>,
 }
 
 impl TheHandy {
-  pub fn new(name: &str, message_attributes: DeviceMessageAttributesMap) -> Self
+  pub fn new(
+    name: &str,
+    message_attributes: DeviceMessageAttributesMap,
+    user_config: &DeviceUserConfig,
+  ) -> Self
   where
     Self: Sized,
   {
@@ -58,7 +65,9 @@ impl TheHandy {
       name: name.to_owned(),
       stop_commands: GenericCommandManager::new(&message_attributes).get_stop_commands(),
       message_attributes,
-      previous_position: Arc::new(AtomicU8::new(0)),
+      axes: Arc::new(LinearAxes::new(
+        user_config.linear_easing().unwrap_or_default(),
+      )),
     }
   }
 }
@@ -126,37 +135,65 @@ impl ButtplugProtocol for TheHandy {
       //
       // We have no device name updates here, so just return a device.
       let (name, attrs) =
-        crate::device::protocol::get_protocol_features(device_impl, None, config)?;
-      Ok(Box::new(Self::new(&name, attrs)) as Box<dyn ButtplugProtocol>)
+        crate::device::protocol::get_protocol_features(device_impl, None, config.clone())?;
+      Ok(Box::new(Self::new(&name, attrs, config.user_config())) as Box<dyn ButtplugProtocol>)
     })
   }
+
+  fn update_user_config(&self, config: &DeviceUserConfig) {
+    self
+      .axes
+      .set_easing(config.linear_easing().unwrap_or_default());
+  }
 }
 
-impl ButtplugProtocolCommandHandler for TheHandy {
-  fn handle_fleshlight_launch_fw12_cmd(
-    &self,
-    device: Arc<DeviceImpl>,
-    message: messages::FleshlightLaunchFW12Cmd,
-  ) -> ButtplugDeviceResultFuture {
-    // Oh good. ScriptPlayer hasn't updated to LinearCmd yet so now I have to
-    // work backward from fleshlight to my own Linear format that Handy uses.
+fn encode_linear_cmd(duration: u32, position: f64) -> Vec<u8> {
+  let linear = handyplug::LinearCmd {
+    // You know when message IDs are important? When you have a protocol that
+    // handles multiple asynchronous commands. You know what doesn't handle
+    // multiple asynchronous commands? The handyplug protocol.
     //
-    // Building this library was a mistake.
-    let goal_position = message.position() as f64 / 100f64;
-    let previous_position = self.previous_position.load(Ordering::SeqCst) as f64 / 100f64;
-    self
-      .previous_position
-      .store(message.position(), Ordering::SeqCst);
-    let distance = (goal_position - previous_position).abs();
-    let duration =
-      fleshlight_launch_helper::get_duration(distance, message.speed() as f64 / 99f64) as u32;
-    self.handle_linear_cmd(
-      device,
-      messages::LinearCmd::new(
-        message.device_index(),
-        vec![messages::VectorSubcommand::new(0, duration, goal_position)],
-      ),
-    )
+    // Do you know where you'd pack those? In the top level container, as
+    // they should then be separate from the message context, in order to
+    // allow multiple sorters. Do you know what doesn't need multiple
+    // sorters? The handyplug protocol.
+    //
+    // Please do not cargo cult protocols.
+    id: 2,
+    // You know when multiple device indicies are important? WHEN YOU HAVE
+    // MULTIPLE DEVICE CONNECTI... oh fuck it. I am so tired. I am going to
+    // bed.
+    device_index: 0,
+    // AND I'M BACK AND WELL RESTED. You know when multiple axes are
+    // important? When you have to support arbitrary devices with multiple
+    // axes. You know what device doesn't have multiple axes?
+    //
+    // Guess.
+    //
+    // I'll wait.
+    //
+    // The handy. It's the handy.
+    vectors: vec![handyplug::linear_cmd::Vector {
+      index: 0,
+      duration,
+      position,
+    }],
+  };
+  let linear_payload = handyplug::Payload {
+    messages: vec![handyplug::Message {
+      message: Some(handyplug::message::Message::LinearCmd(linear)),
+    }],
+  };
+  let mut linear_buf = vec![];
+  linear_payload
+    .encode(&mut linear_buf)
+    .expect("Infallible encode.");
+  linear_buf
+}
+
+impl ButtplugProtocolCommandHandler for TheHandy {
+  fn fleshlight_launch_calibration(&self) -> Option<LaunchCalibration> {
+    Some(HANDY_LAUNCH_CALIBRATION)
   }
 
   fn handle_linear_cmd(
@@ -176,51 +213,142 @@ impl ButtplugProtocolCommandHandler for TheHandy {
       )));
     }
 
-    let linear = handyplug::LinearCmd {
-      // You know when message IDs are important? When you have a protocol that
-      // handles multiple asynchronous commands. You know what doesn't handle
-      // multiple asynchronous commands? The handyplug protocol.
-      //
-      // Do you know where you'd pack those? In the top level container, as
-      // they should then be separate from the message context, in order to
-      // allow multiple sorters. Do you know what doesn't need multiple
-      // sorters? The handyplug protocol.
-      //
-      // Please do not cargo cult protocols.
-      id: 2,
-      // You know when multiple device indicies are important? WHEN YOU HAVE
-      // MULTIPLE DEVICE CONNECTI... oh fuck it. I am so tired. I am going to
-      // bed.
-      device_index: 0,
-      // AND I'M BACK AND WELL RESTED. You know when multiple axes are
-      // important? When you have to support arbitrary devices with multiple
-      // axes. You know what device doesn't have multiple axes?
-      //
-      // Guess.
-      //
-      // I'll wait.
-      //
-      // The handy. It's the handy.
-      vectors: vec![handyplug::linear_cmd::Vector {
-        index: 0,
-        duration: message.vectors()[0].duration(),
-        position: *message.vectors()[0].position(),
-      }],
-    };
-    let linear_payload = handyplug::Payload {
-      messages: vec![handyplug::Message {
-        message: Some(handyplug::message::Message::LinearCmd(linear)),
-      }],
-    };
-    let mut linear_buf = vec![];
-    linear_payload
-      .encode(&mut linear_buf)
-      .expect("Infallible encode.");
+    let axes = self.axes.clone();
+    let segments = axes.segments(
+      0,
+      *message.vectors()[0].position(),
+      message.vectors()[0].duration(),
+    );
     Box::pin(async move {
-      device
-        .write_value(DeviceWriteCmd::new(Endpoint::Tx, linear_buf, true))
-        .await?;
+      // The Handy moves linearly to each position it's sent, so eased moves
+      // are split into segments, each sent as it starts.
+      let mut elapsed = 0;
+      for segment in segments {
+        if segment.start > elapsed {
+          Delay::new(Duration::from_millis((segment.start - elapsed) as u64)).await;
+          elapsed = segment.start;
+        }
+        device
+          .write_value(DeviceWriteCmd::new(
+            Endpoint::Tx,
+            encode_linear_cmd(segment.duration, segment.position),
+            true,
+          ))
+          .await?;
+        axes.set_position(0, segment.position);
+      }
       Ok(messages::Ok::default().into())
     })
   }
 }
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use super::encode_linear_cmd;
+  use crate::{
+    core::messages::{FleshlightLaunchFW12Cmd, LinearCmd, VectorSubcommand},
+    device::{
+      protocol::{fleshlight_launch_helper::get_duration, linear_motion::LinearEasing},
+      DeviceImplCommand,
+      DeviceWriteCmd,
+      Endpoint,
+    },
+    server::{
+      comm_managers::test::{
+        check_test_recv_empty,
+        check_test_recv_value,
+        new_bluetoothle_test_device,
+      },
+      device_manager::DeviceUserConfig,
+    },
+    util::async_manager,
+  };
+
+  #[test]
+  pub fn test_thehandy_linear_easing() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("The Handy")
+        .await
+        .expect("Test, assuming infallible");
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible");
+      device
+        .parse_message(LinearCmd::new(0, vec![VectorSubcommand::new(0, 100, 1.0)]).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          encode_linear_cmd(100, 1.0),
+          true,
+        )),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+
+      // Eased moves start from the last position and arrive in segments.
+      let mut user_config = DeviceUserConfig::default();
+      user_config.set_linear_easing(Some(LinearEasing::EaseInOut));
+      device.update_user_config(&user_config);
+      device
+        .parse_message(LinearCmd::new(0, vec![VectorSubcommand::new(0, 100, 0.0)]).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          encode_linear_cmd(50, 1.0 - LinearEasing::EaseInOut.apply(0.5)),
+          true,
+        )),
+      );
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          encode_linear_cmd(50, 0.0),
+          true,
+        )),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+    });
+  }
+  #[test]
+  pub fn test_thehandy_fleshlight_launch_calibration() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("The Handy")
+        .await
+        .expect("Test, assuming infallible");
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible");
+      device
+        .parse_message(FleshlightLaunchFW12Cmd::new(0, 99, 50).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          encode_linear_cmd((get_duration(1.0, 50f64 / 99f64) as f64 * 1.1) as u32, 1.0),
+          true,
+        )),
+      );
+      // Moves too short for the Handy are stretched out to its minimum.
+      device
+        .parse_message(FleshlightLaunchFW12Cmd::new(0, 90, 99).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          encode_linear_cmd(100, 90f64 / 99f64),
+          true,
+        )),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+    });
+  }
+}
@@ -29,11 +29,16 @@ impl ButtplugProtocol for Vibratissimo {
     Result<Box<dyn ButtplugProtocol>, crate::core::errors::ButtplugError>,
   > {
     Box::pin(async move {
+      // Vibratissimo devices ignore commands until their auth characteristic
+      // has been read, so that has to happen before anything else.
+      device_impl
+        .read_value(DeviceReadCmd::new(Endpoint::Rx, 128, 500))
+        .await?;
       let result = device_impl
         .read_value(DeviceReadCmd::new(Endpoint::RxBLEModel, 128, 500))
         .await?;
       let ident =
-        String::from_utf8(result.data().to_vec()).unwrap_or_else(|_| device_impl.name.clone());
+        String::from_utf8(result.data().to_vec()).unwrap_or_else(|_| device_impl.name().to_owned());
       let (name, attrs) =
         crate::device::protocol::get_protocol_features(device_impl, Some(ident), config)?;
       Ok(Box::new(Self::new(&name, attrs)) as Box<dyn ButtplugProtocol>)
@@ -64,7 +69,7 @@ impl ButtplugProtocolCommandHandler for Vibratissimo {
     // Store off result before the match, so we drop the lock ASAP.
     let manager = self.manager.clone();
     Box::pin(async move {
-      let result = manager.lock().await.update_vibration(&message, true)?;
+      let result = manager.lock().await.update_vibration(&message)?;
       let mut fut_vec = vec![];
       if let Some(cmds) = result {
         let mut data: Vec<u8> = Vec::new();
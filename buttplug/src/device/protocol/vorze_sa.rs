@@ -1,19 +1,25 @@
 use super::{ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolCommandHandler};
 use crate::{
-  core::messages::{
-    self,
-    ButtplugDeviceCommandMessageUnion,
-    ButtplugDeviceMessage,
-    DeviceMessageAttributesMap,
+  core::{
+    errors::ButtplugError,
+    messages::{
+      self,
+      ButtplugDeviceCommandMessageUnion,
+      ButtplugDeviceMessage,
+      DeviceMessageAttributesMap,
+    },
   },
   device::{
+    configuration_manager::DeviceProtocolConfiguration,
     protocol::{generic_command_manager::GenericCommandManager, ButtplugProtocolProperties},
     DeviceImpl,
     DeviceWriteCmd,
     Endpoint,
   },
+  server::device_manager::DeviceUserConfig,
 };
-use std::sync::atomic::{AtomicU8, Ordering::SeqCst};
+use futures::future::{self, BoxFuture};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering::SeqCst};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
@@ -24,10 +30,15 @@ pub struct VorzeSA {
   manager: Arc<Mutex<GenericCommandManager>>,
   stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
   previous_position: Arc<AtomicU8>,
+  reverse_rotation: Arc<AtomicBool>,
 }
 
 impl VorzeSA {
-  fn new(name: &str, message_attributes: DeviceMessageAttributesMap) -> Self {
+  fn new(
+    name: &str,
+    message_attributes: DeviceMessageAttributesMap,
+    user_config: &DeviceUserConfig,
+  ) -> Self {
     let manager = GenericCommandManager::new(&message_attributes);
 
     Self {
@@ -36,11 +47,31 @@ impl VorzeSA {
       stop_commands: manager.get_stop_commands(),
       manager: Arc::new(Mutex::new(manager)),
       previous_position: Arc::new(AtomicU8::new(0)),
+      reverse_rotation: Arc::new(AtomicBool::new(
+        user_config.reverse_rotation().unwrap_or(false),
+      )),
     }
   }
 }
 
-super::default_protocol_trait_declaration!(VorzeSA);
+impl ButtplugProtocol for VorzeSA {
+  fn try_create(
+    device_impl: Arc<DeviceImpl>,
+    config: DeviceProtocolConfiguration,
+  ) -> BoxFuture<'static, Result<Box<dyn ButtplugProtocol>, ButtplugError>> {
+    let device =
+      super::get_protocol_features(device_impl, None, config.clone()).map(|(name, attrs)| {
+        Box::new(Self::new(&name, attrs, config.user_config())) as Box<dyn ButtplugProtocol>
+      });
+    Box::pin(future::ready(device))
+  }
+
+  fn update_user_config(&self, config: &DeviceUserConfig) {
+    self
+      .reverse_rotation
+      .store(config.reverse_rotation().unwrap_or(false), SeqCst);
+  }
+}
 
 #[repr(u8)]
 #[derive(PartialEq)]
@@ -96,7 +127,7 @@ impl ButtplugProtocolCommandHandler for VorzeSA {
       VorzeDevices::Bach
     };
     Box::pin(async move {
-      let result = manager.lock().await.update_vibration(&msg, false)?;
+      let result = manager.lock().await.update_vibration(&msg)?;
       let mut fut_vec = vec![];
       if let Some(cmds) = result {
         if let Some(speed) = cmds[0] {
@@ -126,10 +157,17 @@ impl ButtplugProtocolCommandHandler for VorzeSA {
     } else {
       VorzeDevices::Cyclone
     };
+    let reverse_rotation = self.reverse_rotation.load(SeqCst);
     Box::pin(async move {
       let result = manager.lock().await.update_rotation(&msg)?;
       let mut fut_vec = vec![];
       if let Some((speed, clockwise)) = result[0] {
+        // Don't flip the direction bit on stop commands.
+        let clockwise = if speed > 0 {
+          clockwise != reverse_rotation
+        } else {
+          clockwise
+        };
         let data: u8 = (clockwise as u8) << 7 | (speed as u8);
         fut_vec.push(device.write_value(DeviceWriteCmd::new(
           Endpoint::Tx,
@@ -203,10 +241,13 @@ mod test {
       VibrateSubcommand,
     },
     device::{DeviceImplCommand, DeviceWriteCmd, Endpoint},
-    server::comm_managers::test::{
-      check_test_recv_empty,
-      check_test_recv_value,
-      new_bluetoothle_test_device,
+    server::{
+      comm_managers::test::{
+        check_test_recv_empty,
+        check_test_recv_value,
+        new_bluetoothle_test_device,
+      },
+      device_manager::DeviceUserConfig,
     },
     util::async_manager,
   };
@@ -342,6 +383,63 @@ mod test {
     });
   }
 
+  #[test]
+  pub fn test_vorze_sa_reverse_rotation_user_config() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("CycSA")
+        .await
+        .expect("Test, assuming infallible");
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible");
+      let mut user_config = DeviceUserConfig::default();
+      user_config.set_reverse_rotation(Some(true));
+      device.update_user_config(&user_config);
+      device
+        .parse_message(RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.5, false)]).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          vec![0x01, 0x01, 178],
+          true,
+        )),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+
+      device
+        .parse_message(StopDeviceCmd::new(0).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          vec![0x01, 0x01, 0x0],
+          true,
+        )),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+
+      device.update_user_config(&DeviceUserConfig::default());
+      device
+        .parse_message(RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.5, false)]).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          vec![0x01, 0x01, 50],
+          true,
+        )),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+    });
+  }
+
   #[test]
   pub fn test_vorze_sa_linear_protocol() {
     async_manager::block_on(async move {
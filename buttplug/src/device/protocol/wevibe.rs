@@ -14,6 +14,26 @@ use std::time::Duration;
 
 super::default_protocol_definition!(WeVibe);
 
+/// Speed packet for WeVibe devices, with the internal motor's speed in the
+/// high nibble and the external motor's in the low one. Single motor devices
+/// get the same speed in both.
+fn speed_packet(speed_int: u8, speed_ext: u8) -> Vec<u8> {
+  if speed_int == 0 && speed_ext == 0 {
+    vec![0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+  } else {
+    vec![
+      0x0f,
+      0x03,
+      0x00,
+      speed_ext | (speed_int << 4),
+      0x00,
+      0x03,
+      0x00,
+      0x00,
+    ]
+  }
+}
+
 impl ButtplugProtocol for WeVibe {
   fn try_create(
     device_impl: Arc<crate::device::DeviceImpl>,
@@ -53,28 +73,26 @@ impl ButtplugProtocolCommandHandler for WeVibe {
     // Store off result before the match, so we drop the lock ASAP.
     let manager = self.manager.clone();
     Box::pin(async move {
-      let result = manager.lock().await.update_vibration(&message, true)?;
-      if let Some(cmds) = result {
-        let r_speed_int = cmds[0].unwrap_or(0) as u8;
-        let r_speed_ext = cmds.last().unwrap_or(&None).unwrap_or(0u32) as u8;
-        let data = if r_speed_int == 0 && r_speed_ext == 0 {
-          vec![0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
-        } else {
-          vec![
-            0x0f,
-            0x03,
-            0x00,
-            r_speed_ext | (r_speed_int << 4),
-            0x00,
-            0x03,
-            0x00,
-            0x00,
-          ]
-        };
-        device
-          .write_value(DeviceWriteCmd::new(Endpoint::Tx, data, true))
-          .await?;
-      }
+      // Both motors go in the same packet, so always send both current speeds,
+      // not just the ones this command changed.
+      let speeds = {
+        let mut manager = manager.lock().await;
+        if manager.update_vibration(&message)?.is_none() {
+          return Ok(messages::Ok::default().into());
+        }
+        manager.get_vibration()
+      };
+      let speed_int = speeds[0].unwrap_or(0) as u8;
+      let speed_ext = speeds
+        .get(1)
+        .map_or(speed_int, |speed| speed.unwrap_or(0) as u8);
+      device
+        .write_value(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          speed_packet(speed_int, speed_ext),
+          true,
+        ))
+        .await?;
       Ok(messages::Ok::default().into())
     })
   }
@@ -173,6 +191,71 @@ mod test {
     });
   }
 
+  #[test]
+  pub fn test_wevibe_protocol_independent_motors() {
+    async_manager::block_on(async move {
+      let (device, test_device) = new_bluetoothle_test_device("Sync")
+        .await
+        .expect("Test, assuming infallible");
+      let command_receiver = test_device
+        .get_endpoint_receiver(&Endpoint::Tx)
+        .expect("Test, assuming infallible");
+      // Init vibration pulse.
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          vec![0x0f, 0x03, 0x00, 0x99, 0x00, 0x03, 0x00, 0x00],
+          true,
+        )),
+      );
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          vec![0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+          true,
+        )),
+      );
+      // (motor index, speed, expected speed byte with the internal motor in
+      // the high nibble). Each command only sets one motor, the other keeps
+      // running at whatever it was last set to.
+      for (index, speed, expected) in [
+        (1, 1.0, 0x0f),
+        (0, 0.5, 0x8f),
+        (1, 0.2, 0x83),
+        (0, 0.0, 0x03),
+      ] {
+        device
+          .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(index, speed)]).into())
+          .await
+          .expect("Test, assuming infallible");
+        check_test_recv_value(
+          &command_receiver,
+          DeviceImplCommand::Write(DeviceWriteCmd::new(
+            Endpoint::Tx,
+            vec![0x0f, 0x03, 0x00, expected, 0x00, 0x03, 0x00, 0x00],
+            true,
+          )),
+        );
+      }
+      // Turning off the last running motor sends the off packet.
+      device
+        .parse_message(VibrateCmd::new(0, vec![VibrateSubcommand::new(1, 0.0)]).into())
+        .await
+        .expect("Test, assuming infallible");
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(
+          Endpoint::Tx,
+          vec![0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+          true,
+        )),
+      );
+      assert!(check_test_recv_empty(&command_receiver));
+    });
+  }
+
   #[test]
   pub fn test_wevibe_protocol_one_feature() {
     async_manager::block_on(async move {
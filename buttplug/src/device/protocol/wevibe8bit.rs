@@ -21,7 +21,7 @@ impl ButtplugProtocolCommandHandler for WeVibe8Bit {
     // Store off result before the match, so we drop the lock ASAP.
     let manager = self.manager.clone();
     Box::pin(async move {
-      let result = manager.lock().await.update_vibration(&message, true)?;
+      let result = manager.lock().await.update_vibration(&message)?;
       if let Some(cmds) = result {
         let r_speed_int = cmds[0].unwrap_or(0) as u8;
         let r_speed_ext = cmds.last().unwrap_or(&None).unwrap_or(0u32) as u8;
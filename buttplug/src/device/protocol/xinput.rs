@@ -1,20 +1,97 @@
 use super::{ButtplugDeviceResultFuture, ButtplugProtocol, ButtplugProtocolCommandHandler};
 use crate::{
   core::{
-    errors::ButtplugMessageError,
-    messages::{self, ButtplugDeviceCommandMessageUnion, DeviceMessageAttributesMap},
+    errors::{ButtplugError, ButtplugMessageError},
+    messages::{
+      self,
+      ButtplugDeviceCommandMessageUnion,
+      DeviceMessageAttributesMap,
+      SensorReading,
+      SensorType,
+    },
   },
   device::{
     protocol::{generic_command_manager::GenericCommandManager, ButtplugProtocolProperties},
     DeviceImpl,
+    DeviceSubscribeCmd,
+    DeviceUnsubscribeCmd,
     DeviceWriteCmd,
     Endpoint,
   },
 };
-use byteorder::{LittleEndian, WriteBytesExt};
-use std::sync::Arc;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::{
+  collections::HashSet,
+  io::Cursor,
+  sync::{Arc, Mutex},
+};
+
+// Sensor indexes, in the order the device config lists them.
+const BUTTON_SENSOR_INDEX: u32 = 0;
+const AXIS_SENSOR_INDEX: u32 = 1;
+
+#[derive(ButtplugProtocolProperties)]
+pub struct XInput {
+  name: String,
+  message_attributes: DeviceMessageAttributesMap,
+  manager: Arc<tokio::sync::Mutex<GenericCommandManager>>,
+  stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+  // Sensors clients are subscribed to. The gamepad's input is polled while
+  // there are any.
+  subscribed_sensors: Arc<Mutex<HashSet<u32>>>,
+}
+
+impl XInput {
+  pub fn new(name: &str, message_attributes: DeviceMessageAttributesMap) -> Self {
+    let manager = GenericCommandManager::new(&message_attributes);
 
-super::default_protocol_definition!(XInput);
+    Self {
+      name: name.to_owned(),
+      message_attributes,
+      stop_commands: manager.get_stop_commands(),
+      manager: Arc::new(tokio::sync::Mutex::new(manager)),
+      subscribed_sensors: Arc::new(Mutex::new(HashSet::new())),
+    }
+  }
+}
+
+// Unpacks gamepad input sent by the XInput device impl on its Rx endpoint, in
+// XINPUT_GAMEPAD layout: buttons (u16), left/right trigger (u8), then
+// left/right thumbstick x/y (i16), little endian.
+fn unpack_input_state(data: &[u8]) -> Option<(i32, Vec<i32>)> {
+  let mut cursor = Cursor::new(data);
+  let buttons = cursor.read_u16::<LittleEndian>().ok()?;
+  let mut axes = vec![];
+  for _ in 0..2 {
+    axes.push(cursor.read_u8().ok()? as i32);
+  }
+  for _ in 0..4 {
+    axes.push(cursor.read_i16::<LittleEndian>().ok()? as i32);
+  }
+  Some((buttons as i32, axes))
+}
+
+// Packs a pair of motor speeds as little endian u16s, which is what the XInput
+// device impl expects on both its rumble and trigger endpoints.
+fn pack_motor_speeds(first: Option<u32>, second: Option<u32>) -> Result<Vec<u8>, ButtplugError> {
+  let mut cmd = vec![];
+  for speed in [first, second] {
+    if cmd
+      .write_u16::<LittleEndian>(
+        speed.expect("GCM uses match_all, we'll always get all values") as u16,
+      )
+      .is_err()
+    {
+      return Err(
+        ButtplugMessageError::MessageConversionError(
+          "Cannot convert XInput value for processing".to_owned(),
+        )
+        .into(),
+      );
+    }
+  }
+  Ok(cmd)
+}
 
 impl ButtplugProtocol for XInput {
   fn try_create(
@@ -25,10 +102,19 @@ impl ButtplugProtocol for XInput {
     Result<Box<dyn ButtplugProtocol>, crate::core::errors::ButtplugError>,
   > {
     Box::pin(async move {
-      // This must match the identifier in the device config, otherwise we'll fail to load controllers.
+      // Trigger motors are only driveable if the device impl gives us an
+      // endpoint for them, and users can opt out of them via user config.
+      let use_triggers = device_impl.endpoints().contains(&Endpoint::TxVibrate)
+        && config.user_config().trigger_motors().unwrap_or(true);
+      // These must match the identifiers in the device config, otherwise we'll fail to load controllers.
+      let identifier = if use_triggers {
+        "XInput Gamepad With Triggers"
+      } else {
+        "XInput Gamepad"
+      };
       let (name, attrs) = crate::device::protocol::get_protocol_features(
         device_impl,
-        Some("XInput Gamepad".to_owned()),
+        Some(identifier.to_owned()),
         config,
       )?;
       Ok(Box::new(Self::new(&name, attrs)) as Box<dyn ButtplugProtocol>)
@@ -45,7 +131,7 @@ impl ButtplugProtocolCommandHandler for XInput {
     let manager = self.manager.clone();
     Box::pin(async move {
       // Store off result before the match, so we drop the lock ASAP.
-      let result = manager.lock().await.update_vibration(&msg, true);
+      let result = manager.lock().await.update_vibration(&msg);
       // My life for an async closure so I could just do this via and_then(). :(
       match result {
         Ok(cmds_option) => {
@@ -55,26 +141,20 @@ impl ButtplugProtocolCommandHandler for XInput {
             // back by the manager and just form our own packet. This means
             // we'll just use the manager's return for command validity
             // checking.
-            let mut cmd = vec![];
-            if cmd
-              .write_u16::<LittleEndian>(
-                cmds[1].expect("GCM uses match_all, we'll always get 2 values") as u16,
-              )
-              .is_err()
-              || cmd
-                .write_u16::<LittleEndian>(
-                  cmds[0].expect("GCM uses match_all, we'll always get 2 values") as u16,
-                )
-                .is_err()
-            {
-              return Err(
-                ButtplugMessageError::MessageConversionError(
-                  "Cannot convert XInput value for processing".to_owned(),
-                )
-                .into(),
-              );
+            fut_vec.push(device.write_value(DeviceWriteCmd::new(
+              Endpoint::Tx,
+              pack_motor_speeds(cmds[1], cmds[0])?,
+              false,
+            )));
+            // Features 2 and 3 are the left and right impulse triggers, if
+            // we picked the configuration that has them.
+            if cmds.len() == 4 {
+              fut_vec.push(device.write_value(DeviceWriteCmd::new(
+                Endpoint::TxVibrate,
+                pack_motor_speeds(cmds[2], cmds[3])?,
+                false,
+              )));
             }
-            fut_vec.push(device.write_value(DeviceWriteCmd::new(Endpoint::Tx, cmd, false)));
           }
 
           for fut in fut_vec {
@@ -86,4 +166,110 @@ impl ButtplugProtocolCommandHandler for XInput {
       }
     })
   }
+
+  // Both sensors come from the same endpoint, so it's only subscribed to for
+  // the first sensor, and unsubscribed from once the last one goes.
+  fn handle_sensor_subscribe_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::SensorSubscribeCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let first_sensor = {
+      let mut subscribed_sensors = self
+        .subscribed_sensors
+        .lock()
+        .expect("We never panic while holding this lock.");
+      subscribed_sensors.insert(message.sensor_index()) && subscribed_sensors.len() == 1
+    };
+    Box::pin(async move {
+      if first_sensor {
+        device
+          .subscribe(DeviceSubscribeCmd::new(Endpoint::Rx))
+          .await?;
+      }
+      Ok(messages::Ok::default().into())
+    })
+  }
+
+  fn handle_sensor_unsubscribe_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::SensorUnsubscribeCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let last_sensor = {
+      let mut subscribed_sensors = self
+        .subscribed_sensors
+        .lock()
+        .expect("We never panic while holding this lock.");
+      subscribed_sensors.remove(&message.sensor_index()) && subscribed_sensors.is_empty()
+    };
+    Box::pin(async move {
+      if last_sensor {
+        device
+          .unsubscribe(DeviceUnsubscribeCmd::new(Endpoint::Rx))
+          .await?;
+      }
+      Ok(messages::Ok::default().into())
+    })
+  }
+
+  // Every input notification carries the whole gamepad state, so it's split
+  // into a button reading and an axis reading, and the server passes on
+  // whichever ones have been subscribed to.
+  fn parse_sensor_notification(
+    &self,
+    device_index: u32,
+    endpoint: Endpoint,
+    data: &[u8],
+  ) -> Vec<SensorReading> {
+    if endpoint != Endpoint::Rx {
+      return vec![];
+    }
+    match unpack_input_state(data) {
+      Some((buttons, axes)) => vec![
+        SensorReading::new(
+          device_index,
+          BUTTON_SENSOR_INDEX,
+          SensorType::Button,
+          vec![buttons],
+        ),
+        SensorReading::new(device_index, AXIS_SENSOR_INDEX, SensorType::Axis, axes),
+      ],
+      None => {
+        warn!("XInput input state too short to unpack: {:?}", data);
+        vec![]
+      }
+    }
+  }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+  use super::XInput;
+  use crate::{
+    core::messages::{DeviceMessageAttributesMap, SensorReading, SensorType},
+    device::{protocol::ButtplugProtocolCommandHandler, Endpoint},
+  };
+
+  #[test]
+  pub fn test_xinput_input_state_notification() {
+    let protocol = XInput::new("XInput Gamepad", DeviceMessageAttributesMap::new());
+    // A held, right trigger pulled all the way, left stick pushed up and left.
+    let data = [
+      0x00, 0x10, 0x00, 0xff, 0x00, 0x80, 0xff, 0x7f, 0x00, 0x00, 0x00, 0x00,
+    ];
+    assert_eq!(
+      protocol.parse_sensor_notification(0, Endpoint::Rx, &data),
+      vec![
+        SensorReading::new(0, 0, SensorType::Button, vec![0x1000]),
+        SensorReading::new(0, 1, SensorType::Axis, vec![0, 255, -32768, 32767, 0, 0]),
+      ]
+    );
+    assert!(protocol
+      .parse_sensor_notification(0, Endpoint::Rx, &data[..11])
+      .is_empty());
+    assert!(protocol
+      .parse_sensor_notification(0, Endpoint::Tx, &data)
+      .is_empty());
+  }
 }
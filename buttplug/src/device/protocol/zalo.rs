@@ -21,7 +21,7 @@ impl ButtplugProtocolCommandHandler for Zalo {
     // Store off result before the match, so we drop the lock ASAP.
     let manager = self.manager.clone();
     Box::pin(async move {
-      let result = manager.lock().await.update_vibration(&message, true)?;
+      let result = manager.lock().await.update_vibration(&message)?;
       if let Some(cmds) = result {
         let speed0: u8 = cmds[0].unwrap_or(0) as u8;
         let speed1: u8 = if cmds.len() == 1 {
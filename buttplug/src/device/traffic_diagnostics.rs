@@ -0,0 +1,229 @@
+//! Mirrors raw device traffic to a side channel, for protocol development.
+//!
+//! While diagnostics are enabled for a device, every write sent to it, every
+//! read from it, and every notification it sends is logged with a hex dump
+//! and sent to the device manager's traffic stream as a
+//! [DeviceTrafficRecord]. This covers traffic from protocols and from helpers
+//! like keepalives and confirmed writes, so what's recorded is what went over
+//! the wire.
+//!
+//! Devices can also be put in dry run mode, where writes are reported the
+//! same way, to a separate stream, instead of being sent to the device.
+
+use super::{ButtplugDeviceEvent, DeviceImpl, DeviceWriteCmd, Endpoint};
+use crate::util::async_manager;
+use std::{
+  fmt,
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+    RwLock,
+  },
+  time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceTrafficDirection {
+  Write,
+  Read,
+  Notification,
+}
+
+impl fmt::Display for DeviceTrafficDirection {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let direction = match self {
+      DeviceTrafficDirection::Write => "write",
+      DeviceTrafficDirection::Read => "read",
+      DeviceTrafficDirection::Notification => "notification",
+    };
+    f.write_str(direction)
+  }
+}
+
+/// Data sent to or received from a device while diagnostics were enabled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceTrafficRecord {
+  pub timestamp: SystemTime,
+  pub device_index: u32,
+  pub address: String,
+  pub endpoint: Endpoint,
+  pub direction: DeviceTrafficDirection,
+  pub data: Vec<u8>,
+}
+
+impl DeviceTrafficRecord {
+  /// Data as space separated hex bytes, e.g. `0f 03 00`.
+  pub fn hex_dump(&self) -> String {
+    self
+      .data
+      .iter()
+      .map(|byte| format!("{:02x}", byte))
+      .collect::<Vec<_>>()
+      .join(" ")
+  }
+}
+
+impl fmt::Display for DeviceTrafficRecord {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let timestamp_ms = self
+      .timestamp
+      .duration_since(UNIX_EPOCH)
+      .map(|since_epoch| since_epoch.as_millis())
+      .unwrap_or(0);
+    write!(
+      f,
+      "[{}] Device {} ({}) {} {}: {}",
+      timestamp_ms,
+      self.device_index,
+      self.address,
+      self.direction,
+      self.endpoint,
+      self.hex_dump()
+    )
+  }
+}
+
+struct TrafficSink {
+  device_index: u32,
+  sender: broadcast::Sender<DeviceTrafficRecord>,
+}
+
+impl TrafficSink {
+  fn send(
+    &self,
+    address: &str,
+    endpoint: Endpoint,
+    direction: DeviceTrafficDirection,
+    data: &[u8],
+  ) {
+    let record = DeviceTrafficRecord {
+      timestamp: SystemTime::now(),
+      device_index: self.device_index,
+      address: address.to_owned(),
+      endpoint,
+      direction,
+      data: data.to_vec(),
+    };
+    info!("Device traffic: {}", record);
+    // Only fails if nobody is listening, in which case the log is enough.
+    let _ = self.sender.send(record);
+  }
+}
+
+/// Where a device's traffic is mirrored to, if anywhere. Shared between a
+/// device and any wrappers around it, so diagnostics can be toggled from
+/// whichever one is at hand.
+#[derive(Clone, Default)]
+pub(super) struct DeviceTrafficTap {
+  sink: Arc<RwLock<Option<TrafficSink>>>,
+  dry_run_sink: Arc<RwLock<Option<TrafficSink>>>,
+  // Bumped whenever the sink changes, so notification forwarding tasks for a
+  // previous sink know to stop.
+  generation: Arc<AtomicU32>,
+}
+
+impl DeviceTrafficTap {
+  pub fn record(
+    &self,
+    address: &str,
+    endpoint: Endpoint,
+    direction: DeviceTrafficDirection,
+    data: &[u8],
+  ) {
+    let sink = self
+      .sink
+      .read()
+      .expect("We never panic while holding this lock.");
+    if let Some(sink) = sink.as_ref() {
+      sink.send(address, endpoint, direction, data);
+    }
+  }
+
+  /// If the device is in dry run mode, reports `msg` instead of it being
+  /// written, and returns true.
+  pub fn record_dry_run_write(&self, address: &str, msg: &DeviceWriteCmd) -> bool {
+    let sink = self
+      .dry_run_sink
+      .read()
+      .expect("We never panic while holding this lock.");
+    match sink.as_ref() {
+      Some(sink) => {
+        sink.send(
+          address,
+          msg.endpoint,
+          DeviceTrafficDirection::Write,
+          &msg.data,
+        );
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Puts the device in dry run mode, reporting writes to `sender` under
+  /// `device_index`, or takes it out of dry run mode if `sink` is None.
+  pub fn set_dry_run_sink(&self, sink: Option<(u32, broadcast::Sender<DeviceTrafficRecord>)>) {
+    *self
+      .dry_run_sink
+      .write()
+      .expect("We never panic while holding this lock.") =
+      sink.map(|(device_index, sender)| TrafficSink {
+        device_index,
+        sender,
+      });
+  }
+
+  /// Starts mirroring traffic for `device` to `sender` under `device_index`,
+  /// or stops if `sink` is None.
+  pub fn set_sink(
+    &self,
+    device: &DeviceImpl,
+    sink: Option<(u32, broadcast::Sender<DeviceTrafficRecord>)>,
+  ) {
+    let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+    let enabled = sink.is_some();
+    *self
+      .sink
+      .write()
+      .expect("We never panic while holding this lock.") =
+      sink.map(|(device_index, sender)| TrafficSink {
+        device_index,
+        sender,
+      });
+    if !enabled {
+      return;
+    }
+    // Notifications don't pass through the device impl, so they're picked up
+    // from its event stream.
+    let mut event_receiver = device.event_stream();
+    let address = device.address().to_owned();
+    let tap = self.clone();
+    async_manager::spawn(async move {
+      loop {
+        let event = match event_receiver.recv().await {
+          Ok(event) => event,
+          Err(broadcast::error::RecvError::Lagged(_)) => continue,
+          Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if tap.generation.load(Ordering::SeqCst) != generation {
+          break;
+        }
+        match event {
+          ButtplugDeviceEvent::Notification(event_address, endpoint, data)
+            if event_address == address =>
+          {
+            tap.record(
+              &address,
+              endpoint,
+              DeviceTrafficDirection::Notification,
+              &data,
+            )
+          }
+          ButtplugDeviceEvent::Removed(event_address) if event_address == address => break,
+          _ => {}
+        }
+      }
+    });
+  }
+}
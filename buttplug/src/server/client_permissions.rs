@@ -0,0 +1,79 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Limits on what each client can do.
+//!
+//! Clients are told apart by the name they send in RequestServerInfo, the same
+//! name device claims are held under. Permissions for a client name are set
+//! with [ButtplugServerBuilder::client_permissions][super::ButtplugServerBuilder::client_permissions]
+//! or in the `client-permissions` section of the user device config, i.e.
+//!
+//! ```json
+//! "client-permissions": {
+//!   "Stats Overlay": { "read-only": true },
+//!   "Partner App": { "devices": ["a1:b2:c3:d4:e5:f6"] }
+//! }
+//! ```
+//!
+//! Clients without an entry get the server's default permissions, which allow
+//! everything unless set otherwise.
+//!
+//! Permissions are checked as the server parses each client message, before
+//! it's dispatched to the device manager. Devices a client can't use are left
+//! out of its device list and DeviceAdded events, commands for them fail as if
+//! they weren't connected, and StopAllDevices only stops the devices it can
+//! use.
+
+use crate::core::messages::ButtplugClientMessage;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientPermissions {
+  /// If true, the client can list devices and read their battery, RSSI and
+  /// sensor values, but can't command devices, scan, claim devices or use raw
+  /// endpoints.
+  #[serde(rename = "read-only", default)]
+  pub read_only: bool,
+  /// Addresses of the devices the client can use. None allows every device.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub devices: Option<BTreeSet<String>>,
+}
+
+impl ClientPermissions {
+  /// True if the client may send this kind of message at all. Device messages
+  /// also need [ClientPermissions::allows_device] for their device.
+  pub fn allows_message(&self, msg: &ButtplugClientMessage) -> bool {
+    !self.read_only
+      || matches!(
+        msg,
+        ButtplugClientMessage::Ping(_)
+          | ButtplugClientMessage::RequestLog(_)
+          | ButtplugClientMessage::RequestServerInfo(_)
+          | ButtplugClientMessage::RequestDeviceList(_)
+          | ButtplugClientMessage::RequestDeviceListChanges(_)
+          | ButtplugClientMessage::RequestServerTime(_)
+          | ButtplugClientMessage::BatteryLevelCmd(_)
+          | ButtplugClientMessage::RSSILevelCmd(_)
+          | ButtplugClientMessage::SensorSubscribeCmd(_)
+          | ButtplugClientMessage::SensorUnsubscribeCmd(_)
+      )
+  }
+
+  /// True if the client may see and use the device at this address.
+  pub fn allows_device(&self, address: &str) -> bool {
+    self
+      .devices
+      .as_ref()
+      .map_or(true, |devices| devices.contains(address))
+  }
+
+  /// True if the client is limited to some devices.
+  pub fn restricts_devices(&self) -> bool {
+    self.devices.is_some()
+  }
+}
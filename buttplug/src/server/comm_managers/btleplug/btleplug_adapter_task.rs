@@ -1,18 +1,59 @@
-use super::btleplug_device_impl::BtlePlugDeviceImplCreator;
-use crate::server::comm_managers::DeviceCommunicationEvent;
+use super::{
+  btleplug_connection_limiter::BtleplugConnectionLimiter,
+  btleplug_device_impl::BtlePlugDeviceImplCreator,
+};
+use crate::{core::errors::ButtplugDeviceError, server::comm_managers::DeviceCommunicationEvent};
 use btleplug::{
   api::{Central, CentralEvent, Manager as _, Peripheral, ScanFilter},
   platform::{Adapter, Manager, PeripheralId},
 };
-use futures::{future::FutureExt, StreamExt};
+use futures::{
+  future::{self, BoxFuture, FutureExt},
+  Stream,
+  StreamExt,
+};
 use futures_timer::Delay;
-use std::time::Duration;
-use tokio::sync::mpsc::{Receiver, Sender};
+use std::{
+  collections::HashMap,
+  pin::Pin,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+use tokio::sync::{
+  mpsc::{Receiver, Sender},
+  oneshot,
+};
+
+/// Hands the comm manager an adapter to scan with, in place of the first
+/// adapter btleplug finds. Called whenever the comm manager needs to look for
+/// an adapter, so platforms that hand out their BLE central through the app
+/// (i.e. mobile, after btleplug has been initialized by the FFI layer) can
+/// provide it. Returning None means there's no adapter available right now.
+pub type BtleplugAdapterProvider =
+  Arc<dyn Fn() -> BoxFuture<'static, Option<Adapter>> + Send + Sync>;
+
+// btleplug doesn't let us pick a scan mode, so background scans keep power
+// use down by only scanning for a short window every period.
+const BACKGROUND_SCAN_WINDOW: Duration = Duration::from_secs(5);
+const BACKGROUND_SCAN_PERIOD: Duration = Duration::from_secs(30);
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug)]
 pub enum BtleplugAdapterCommand {
-  StartScanning,
+  // Replies with whether scanning could be started.
+  StartScanning(oneshot::Sender<Result<(), ButtplugDeviceError>>),
   StopScanning,
+  // Both reply once done.
+  PauseIo(oneshot::Sender<()>),
+  ResumeIo(oneshot::Sender<()>),
+  // Replies with whether the device could be found.
+  ConnectDevice(String, oneshot::Sender<Result<(), ButtplugDeviceError>>),
+  SetBackgroundScanning(bool),
+}
+
+enum BtleplugAdapterTaskEvent {
+  Central(Option<CentralEvent>),
+  Command(Option<BtleplugAdapterCommand>),
+  BackgroundScanTimer,
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -20,21 +61,29 @@ struct PeripheralInfo {
   name: Option<String>,
   peripheral_id: PeripheralId,
   services: Vec<uuid::Uuid>,
+  manufacturer_data: HashMap<u16, Vec<u8>>,
 }
 
 pub struct BtleplugAdapterTask {
   event_sender: Sender<DeviceCommunicationEvent>,
   command_receiver: Receiver<BtleplugAdapterCommand>,
+  /// Shared by all devices this task finds, so they take turns connecting.
+  connection_limiter: BtleplugConnectionLimiter,
+  adapter_provider: Option<BtleplugAdapterProvider>,
 }
 
 impl BtleplugAdapterTask {
   pub fn new(
     event_sender: Sender<DeviceCommunicationEvent>,
     command_receiver: Receiver<BtleplugAdapterCommand>,
+    connection_limiter: BtleplugConnectionLimiter,
+    adapter_provider: Option<BtleplugAdapterProvider>,
   ) -> Self {
     Self {
       event_sender,
       command_receiver,
+      connection_limiter,
+      adapter_provider,
     }
   }
 
@@ -43,6 +92,7 @@ impl BtleplugAdapterTask {
     peripheral_id: &PeripheralId,
     adapter: &Adapter,
     tried_addresses: &mut Vec<PeripheralInfo>,
+    background: bool,
   ) {
     let peripheral = if let Ok(peripheral) = adapter.peripheral(peripheral_id).await {
       peripheral
@@ -72,9 +122,12 @@ impl BtleplugAdapterTask {
       name: properties.local_name.clone(),
       peripheral_id: peripheral_id.clone(),
       services: properties.services.clone(),
+      manufacturer_data: properties.manufacturer_data.clone(),
     };
 
-    if (!device_name.is_empty() || !properties.services.is_empty())
+    if (!device_name.is_empty()
+      || !properties.services.is_empty()
+      || !properties.manufacturer_data.is_empty())
       && !tried_addresses.contains(&peripheral_info)
     {
       let span = info_span!(
@@ -88,24 +141,34 @@ impl BtleplugAdapterTask {
         "Found new bluetooth device advertisement: {:?}",
         peripheral_info
       );
+      // Only keep the latest advertisement per device, as manufacturer data
+      // may change between advertisements.
+      tried_addresses.retain(|info| info.peripheral_id != *peripheral_id);
       tried_addresses.push(peripheral_info.clone());
       let device_creator = Box::new(BtlePlugDeviceImplCreator::new(
         &device_name,
         peripheral_id,
         &properties.services,
+        &properties.manufacturer_data,
         peripheral.clone(),
         adapter.clone(),
+        self.connection_limiter.clone(),
       ));
-      if self
-        .event_sender
-        .send(DeviceCommunicationEvent::DeviceFound {
+      let address = format!("{:?}", peripheral_id);
+      let event = if background {
+        DeviceCommunicationEvent::BackgroundDeviceFound {
           name: device_name,
-          address: format!("{:?}", peripheral_id),
+          address,
           creator: device_creator,
-        })
-        .await
-        .is_err()
-      {
+        }
+      } else {
+        DeviceCommunicationEvent::DeviceFound {
+          name: device_name,
+          address,
+          creator: device_creator,
+        }
+      };
+      if self.event_sender.send(event).await.is_err() {
         error!("Device manager receiver dropped, cannot send device found message.");
       }
     } else {
@@ -116,130 +179,373 @@ impl BtleplugAdapterTask {
     }
   }
 
-  pub async fn run(&mut self) {
+  async fn connect_peripheral(
+    &self,
+    address: &str,
+    adapter: &Option<Adapter>,
+  ) -> Result<(), ButtplugDeviceError> {
+    let adapter = adapter
+      .as_ref()
+      .ok_or(ButtplugDeviceError::NoBluetoothAdapter)?;
+    // Only peripherals the platform already knows about (from an earlier
+    // scan, or bonded devices) show up here, which covers reconnecting to
+    // remembered devices without scanning.
+    let peripherals = adapter.peripherals().await.map_err(|e| {
+      ButtplugDeviceError::DeviceConnectionError(format!("Cannot retreive peripherals: {:?}", e))
+    })?;
+    let peripheral = peripherals
+      .into_iter()
+      .find(|p| format!("{:?}", p.id()) == address)
+      .ok_or_else(|| {
+        ButtplugDeviceError::DeviceConnectionError(format!(
+          "No known bluetooth device with address {}",
+          address
+        ))
+      })?;
+    let properties = peripheral
+      .properties()
+      .await
+      .ok()
+      .flatten()
+      .ok_or_else(|| {
+        ButtplugDeviceError::DeviceConnectionError(format!(
+          "Cannot retreive peripheral properties for {}",
+          address
+        ))
+      })?;
+    let device_name = properties.local_name.clone().unwrap_or_default();
+    let device_creator = Box::new(BtlePlugDeviceImplCreator::new(
+      &device_name,
+      &peripheral.id(),
+      &properties.services,
+      &properties.manufacturer_data,
+      peripheral.clone(),
+      adapter.clone(),
+      self.connection_limiter.clone(),
+    ));
+    self
+      .event_sender
+      .send(DeviceCommunicationEvent::ConnectIdentifiedDevice {
+        name: device_name,
+        address: address.to_owned(),
+        creator: device_creator,
+      })
+      .await
+      .map_err(|_| {
+        ButtplugDeviceError::DeviceConnectionError(
+          "Device manager receiver dropped, cannot connect device.".to_owned(),
+        )
+      })
+  }
+
+  async fn find_adapter(adapter_provider: &Option<BtleplugAdapterProvider>) -> Option<Adapter> {
+    if let Some(provider) = adapter_provider {
+      let adapter = provider().await;
+      if adapter.is_none() {
+        warn!("No Bluetooth LE adapter provided, will ask again on next scanning request.");
+      }
+      return adapter;
+    }
     let manager = match Manager::new().await {
       Ok(mgr) => mgr,
       Err(e) => {
         error!("Error creating btleplug manager: {:?}", e);
-        return;
+        return None;
       }
     };
+    let adapter = match manager.adapters().await {
+      Ok(adapters) => adapters.into_iter().next(),
+      Err(e) => {
+        error!("Error retreiving BTLE adapters: {:?}", e);
+        return None;
+      }
+    };
+    if let Some(adapter) = adapter {
+      info!("Bluetooth LE adapter found.");
+      // Bluetooth dongle identification for Windows
+      #[cfg(target_os = "windows")]
+      {
+        use windows::Devices::Bluetooth::BluetoothAdapter;
+        let adapter_result = BluetoothAdapter::GetDefaultAsync()
+          .expect("If we're here, we got an adapter")
+          .await;
+        let adapter = adapter_result.expect("Considering infallible at this point");
+        let device_id = adapter
+          .DeviceId()
+          .expect("Considering infallible at this point")
+          .to_string();
+        info!("Windows Bluetooth Adapter ID: {:?}", device_id);
+        let device_manufacturer = if device_id.contains("VID_0A12") {
+          "Cambridge Silicon Radio (CSR)"
+        } else if device_id.contains("VID_0A5C") {
+          "Broadcom"
+        } else if device_id.contains("VID_8087") {
+          "Intel"
+        } else if device_id.contains("VID_0BDA") {
+          "RealTek"
+        } else if device_id.contains("VID_0B05") {
+          "Asus"
+        } else if device_id.contains("VID_13D3") {
+          "IMC"
+        } else {
+          "Unknown Manufacturer"
+        };
+        info!(
+          "Windows Bluetooth Adapter Manufacturer: {}",
+          device_manufacturer
+        );
+      }
+      Some(adapter)
+    } else {
+      warn!("Bluetooth LE adapter not found, will check again on next scanning request.");
+      None
+    }
+  }
 
-    // Start by assuming we'll find the adapter on the first try. If not, we'll print an error
-    // message then loop while trying to find it.
-    let mut adapter_found = true;
+  async fn start_scanning(
+    adapter_provider: &Option<BtleplugAdapterProvider>,
+    adapter: &mut Option<Adapter>,
+    events: &mut Option<Pin<Box<dyn Stream<Item = CentralEvent> + Send>>>,
+  ) -> Result<(), ButtplugDeviceError> {
+    // The adapter may have been plugged in or turned on since we last looked,
+    // so look again every time scanning is requested.
+    if adapter.is_none() {
+      *adapter = Self::find_adapter(adapter_provider).await;
+      *events = match adapter {
+        Some(adapter) => adapter.events().await.ok(),
+        None => None,
+      };
+    }
+    let scan_result = match adapter {
+      Some(adapter) => adapter.start_scan(ScanFilter::default()).await,
+      None => return Err(ButtplugDeviceError::NoBluetoothAdapter),
+    };
+    if let Err(err) = scan_result {
+      // Usually means the radio is turned off, or the adapter went away since
+      // we found it. Either way, look for it again next time.
+      error!("Start scanning request failed: {}", err);
+      *adapter = None;
+      *events = None;
+      return Err(ButtplugDeviceError::NoBluetoothAdapter);
+    }
+    Ok(())
+  }
+
+  pub async fn run(&mut self) {
+    let mut adapter = Self::find_adapter(&self.adapter_provider).await;
+    let mut events = match &adapter {
+      Some(adapter) => adapter.events().await.ok(),
+      None => None,
+    };
 
-    let adapter;
+    let mut tried_addresses = vec![];
+    let mut io_paused = false;
+    // Whether a user requested scan is running.
+    let mut user_scanning = false;
+    let mut background_scanning = false;
+    // Whether we're inside a background scan window, and when the current
+    // window or gap between windows ends.
+    let mut background_scan_active = false;
+    let mut background_scan_deadline: Option<Instant> = None;
 
     loop {
-      if !adapter_found {
-        Delay::new(Duration::from_secs(1)).await;
-      }
-      adapter = match manager.adapters().await {
-        Ok(adapters) => {
-          if let Some(adapter) = adapters.into_iter().next() {
-            info!("Bluetooth LE adapter found.");
-            // Bluetooth dongle identification for Windows
-            #[cfg(target_os = "windows")]
-            {
-              use windows::Devices::Bluetooth::BluetoothAdapter;
-              let adapter_result = BluetoothAdapter::GetDefaultAsync()
-                .expect("If we're here, we got an adapter")
+      // Wait on whichever comes first, but only act once the wait is over, as
+      // handling either may swap out the adapter the event stream belongs to.
+      let task_event = {
+        let event_fut = async {
+          match &mut events {
+            Some(events) => events.next().await,
+            None => future::pending().await,
+          }
+        }
+        .fuse();
+        let timer_fut = async {
+          match background_scan_deadline {
+            Some(deadline) => Delay::new(deadline.saturating_duration_since(Instant::now())).await,
+            None => future::pending().await,
+          }
+        }
+        .fuse();
+        pin_mut!(event_fut, timer_fut);
+        select! {
+          event = event_fut => BtleplugAdapterTaskEvent::Central(event),
+          command = self.command_receiver.recv().fuse() => BtleplugAdapterTaskEvent::Command(command),
+          _ = timer_fut => BtleplugAdapterTaskEvent::BackgroundScanTimer,
+        }
+      };
+
+      match task_event {
+        BtleplugAdapterTaskEvent::Central(Some(event)) => {
+          let current_adapter = adapter
+            .as_ref()
+            .expect("Only get events when we have an adapter");
+          match event {
+            CentralEvent::DeviceDiscovered(peripheral_id)
+            | CentralEvent::DeviceUpdated(peripheral_id) => {
+              self
+                .maybe_add_peripheral(
+                  &peripheral_id,
+                  current_adapter,
+                  &mut tried_addresses,
+                  !user_scanning && background_scanning,
+                )
                 .await;
-              let adapter = adapter_result.expect("Considering infallible at this point");
-              let device_id = adapter
-                .DeviceId()
-                .expect("Considering infallible at this point")
-                .to_string();
-              info!("Windows Bluetooth Adapter ID: {:?}", device_id);
-              let device_manufacturer = if device_id.contains("VID_0A12") {
-                "Cambridge Silicon Radio (CSR)"
-              } else if device_id.contains("VID_0A5C") {
-                "Broadcom"
-              } else if device_id.contains("VID_8087") {
-                "Intel"
-              } else if device_id.contains("VID_0BDA") {
-                "RealTek"
-              } else if device_id.contains("VID_0B05") {
-                "Asus"
-              } else if device_id.contains("VID_13D3") {
-                "IMC"
-              } else {
-                "Unknown Manufacturer"
-              };
-              info!(
-                "Windows Bluetooth Adapter Manufacturer: {}",
-                device_manufacturer
-              );
             }
-            adapter
-          } else {
-            if adapter_found {
-              adapter_found = false;
-              warn!("Bluetooth LE adapter not found, will not be using bluetooth scanning until found. Buttplug will continue polling for the adapter, but no more warning messages will be posted.");
+            CentralEvent::DeviceDisconnected(peripheral_id) => {
+              debug!("BTLEPlug Device disconnected: {:?}", peripheral_id);
+              tried_addresses.retain(|info| info.peripheral_id != peripheral_id);
+              self
+                .maybe_add_peripheral(
+                  &peripheral_id,
+                  current_adapter,
+                  &mut tried_addresses,
+                  !user_scanning && background_scanning,
+                )
+                .await;
+            }
+            event => {
+              trace!("Unhandled btleplug central event: {:?}", event)
             }
-            continue;
           }
         }
-        Err(e) => {
-          if adapter_found {
-            adapter_found = false;
-            error!("Error retreiving BTLE adapters: {:?}", e);
+        BtleplugAdapterTaskEvent::Central(None) => {
+          error!("Event stream closed, will look for adapter again on next scanning request.");
+          adapter = None;
+          events = None;
+        }
+        BtleplugAdapterTaskEvent::Command(Some(BtleplugAdapterCommand::StartScanning(
+          result_sender,
+        ))) => {
+          tried_addresses.clear();
+          let result = if io_paused {
+            Err(ButtplugDeviceError::NoBluetoothAdapter)
+          } else if background_scan_active {
+            // Already scanning, devices found from here on count as found by
+            // the user's scan.
+            Ok(())
+          } else {
+            Self::start_scanning(&self.adapter_provider, &mut adapter, &mut events).await
+          };
+          user_scanning = result.is_ok();
+          if result_sender.send(result).is_err() {
+            debug!("Start scanning result receiver dropped.");
           }
-          continue;
         }
-      };
-      break;
-    }
-
-    let mut events = adapter
-      .events()
-      .await
-      .expect("Should always be able to retreive stream.");
-
-    let mut tried_addresses = vec![];
-
-    loop {
-      let event_fut = events.next();
-
-      select! {
-        event = event_fut.fuse() => {
-            if let Some(event) = event {
-              match event {
-                CentralEvent::DeviceDiscovered(peripheral_id) | CentralEvent::DeviceUpdated(peripheral_id) => {
-                  self.maybe_add_peripheral(&peripheral_id, &adapter, &mut tried_addresses).await;
-                }
-                CentralEvent::DeviceDisconnected(peripheral_id) => {
-                  debug!("BTLEPlug Device disconnected: {:?}", peripheral_id);
-                  tried_addresses.retain(|info| info.peripheral_id != peripheral_id);
-                  self.maybe_add_peripheral(&peripheral_id, &adapter, &mut tried_addresses).await;
-                }
-                event => {
-                  trace!("Unhandled btleplug central event: {:?}", event)
-                }
+        BtleplugAdapterTaskEvent::Command(Some(BtleplugAdapterCommand::StopScanning)) => {
+          user_scanning = false;
+          // Leave the background scan running until its window ends.
+          if !background_scan_active {
+            if let Some(adapter) = &adapter {
+              if let Err(err) = adapter.stop_scan().await {
+                error!("Stop scanning request failed: {}", err);
               }
-            } else {
-              error!("Event stream closed. Exiting loop.");
-              return;
             }
-        },
-        command = self.command_receiver.recv().fuse() => {
-          if let Some(cmd) = command {
-            match cmd {
-              BtleplugAdapterCommand::StartScanning => {
-                tried_addresses.clear();
-                if let Err(err) = adapter.start_scan(ScanFilter::default()).await {
-                  error!("Start scanning request failed: {}", err);
+          }
+        }
+        BtleplugAdapterTaskEvent::Command(Some(BtleplugAdapterCommand::PauseIo(result_sender))) => {
+          // The platform may take the adapter away while we're paused, so let
+          // go of it, and find it again on resume. Devices keep their own
+          // handles, and are left to the platform.
+          if let Some(adapter) = &adapter {
+            if let Err(err) = adapter.stop_scan().await {
+              debug!("Stop scanning on IO pause failed: {}", err);
+            }
+          }
+          io_paused = true;
+          user_scanning = false;
+          background_scan_active = false;
+          background_scan_deadline = None;
+          adapter = None;
+          events = None;
+          let _ = result_sender.send(());
+        }
+        BtleplugAdapterTaskEvent::Command(Some(BtleplugAdapterCommand::ResumeIo(
+          result_sender,
+        ))) => {
+          io_paused = false;
+          if adapter.is_none() {
+            adapter = Self::find_adapter(&self.adapter_provider).await;
+            events = match &adapter {
+              Some(adapter) => adapter.events().await.ok(),
+              None => None,
+            };
+          }
+          if background_scanning {
+            background_scan_deadline = Some(Instant::now());
+          }
+          let _ = result_sender.send(());
+        }
+        BtleplugAdapterTaskEvent::Command(Some(BtleplugAdapterCommand::ConnectDevice(
+          address,
+          result_sender,
+        ))) => {
+          let result = if io_paused {
+            Err(ButtplugDeviceError::NoBluetoothAdapter)
+          } else {
+            self.connect_peripheral(&address, &adapter).await
+          };
+          if result_sender.send(result).is_err() {
+            debug!("Connect device result receiver dropped.");
+          }
+        }
+        BtleplugAdapterTaskEvent::Command(Some(BtleplugAdapterCommand::SetBackgroundScanning(
+          enabled,
+        ))) => {
+          background_scanning = enabled;
+          if enabled {
+            if !io_paused && background_scan_deadline.is_none() {
+              background_scan_deadline = Some(Instant::now());
+            }
+          } else {
+            background_scan_deadline = None;
+            if background_scan_active && !user_scanning {
+              if let Some(adapter) = &adapter {
+                if let Err(err) = adapter.stop_scan().await {
+                  error!("Stop background scanning request failed: {}", err);
                 }
               }
-              BtleplugAdapterCommand::StopScanning => {
+            }
+            background_scan_active = false;
+          }
+        }
+        BtleplugAdapterTaskEvent::BackgroundScanTimer => {
+          if background_scan_active {
+            background_scan_active = false;
+            if !user_scanning {
+              if let Some(adapter) = &adapter {
                 if let Err(err) = adapter.stop_scan().await {
-                  error!("Stop scanning request failed: {}", err);
+                  debug!("Stop background scanning request failed: {}", err);
                 }
               }
             }
+            background_scan_deadline =
+              Some(Instant::now() + BACKGROUND_SCAN_PERIOD - BACKGROUND_SCAN_WINDOW);
+          } else if user_scanning {
+            // Nothing to do while the user is scanning, check back next period.
+            background_scan_deadline = Some(Instant::now() + BACKGROUND_SCAN_PERIOD);
+          } else {
+            // Retry devices each window, as ones that were turned off since
+            // they were last seen may be back.
+            tried_addresses.clear();
+            match Self::start_scanning(&self.adapter_provider, &mut adapter, &mut events).await {
+              Ok(()) => {
+                background_scan_active = true;
+                background_scan_deadline = Some(Instant::now() + BACKGROUND_SCAN_WINDOW);
+              }
+              Err(err) => {
+                debug!(
+                  "Cannot start background scan, will try next period: {}",
+                  err
+                );
+                background_scan_deadline = Some(Instant::now() + BACKGROUND_SCAN_PERIOD);
+              }
+            }
           }
         }
+        BtleplugAdapterTaskEvent::Command(None) => {
+          debug!("Comm manager dropped, exiting btleplug adapter task.");
+          return;
+        }
       }
     }
   }
@@ -1,23 +1,72 @@
-use super::btleplug_adapter_task::{BtleplugAdapterCommand, BtleplugAdapterTask};
+use super::{
+  btleplug_adapter_task::{BtleplugAdapterCommand, BtleplugAdapterProvider, BtleplugAdapterTask},
+  btleplug_connection_limiter::{
+    BtleplugConnectionLimiter,
+    DEFAULT_CONNECTION_TIMEOUT,
+    DEFAULT_MAX_CONCURRENT_CONNECTIONS,
+  },
+};
 use crate::{
   core::{errors::ButtplugDeviceError, ButtplugResultFuture},
   server::comm_managers::{
+    CommManagerCapabilities,
     DeviceCommunicationEvent,
     DeviceCommunicationManager,
     DeviceCommunicationManagerBuilder,
   },
   util::async_manager,
 };
-use std::sync::{
-  atomic::{AtomicBool, Ordering},
-  Arc,
+use std::{
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  time::Duration,
 };
 
-use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::{
+  mpsc::{channel, Sender},
+  oneshot,
+};
 
-#[derive(Default)]
 pub struct BtlePlugCommunicationManagerBuilder {
   sender: Option<Sender<DeviceCommunicationEvent>>,
+  max_concurrent_connections: usize,
+  connection_timeout: Duration,
+  adapter_provider: Option<BtleplugAdapterProvider>,
+}
+
+impl Default for BtlePlugCommunicationManagerBuilder {
+  fn default() -> Self {
+    Self {
+      sender: None,
+      max_concurrent_connections: DEFAULT_MAX_CONCURRENT_CONNECTIONS,
+      connection_timeout: DEFAULT_CONNECTION_TIMEOUT,
+      adapter_provider: None,
+    }
+  }
+}
+
+impl BtlePlugCommunicationManagerBuilder {
+  /// Number of devices that can be connecting at once. Devices found while
+  /// this many are connecting wait for one of them to finish.
+  pub fn max_concurrent_connections(mut self, max: usize) -> Self {
+    self.max_concurrent_connections = max;
+    self
+  }
+
+  /// How long a single connection attempt can take before it fails.
+  pub fn connection_timeout(mut self, timeout: Duration) -> Self {
+    self.connection_timeout = timeout;
+    self
+  }
+
+  /// Scan with adapters from the given provider, instead of letting btleplug
+  /// find one. See [BtleplugAdapterProvider].
+  pub fn adapter_provider(mut self, provider: BtleplugAdapterProvider) -> Self {
+    self.adapter_provider = Some(provider);
+    self
+  }
 }
 
 impl DeviceCommunicationManagerBuilder for BtlePlugCommunicationManagerBuilder {
@@ -32,6 +81,8 @@ impl DeviceCommunicationManagerBuilder for BtlePlugCommunicationManagerBuilder {
         .sender
         .take()
         .expect("Device Manager will set this during initialization."),
+      BtleplugConnectionLimiter::new(self.max_concurrent_connections, self.connection_timeout),
+      self.adapter_provider.take(),
     ))
   }
 }
@@ -42,10 +93,15 @@ pub struct BtlePlugCommunicationManager {
 }
 
 impl BtlePlugCommunicationManager {
-  pub fn new(event_sender: Sender<DeviceCommunicationEvent>) -> Self {
+  fn new(
+    event_sender: Sender<DeviceCommunicationEvent>,
+    connection_limiter: BtleplugConnectionLimiter,
+    adapter_provider: Option<BtleplugAdapterProvider>,
+  ) -> Self {
     let (sender, receiver) = channel(256);
     async_manager::spawn(async move {
-      let mut task = BtleplugAdapterTask::new(event_sender, receiver);
+      let mut task =
+        BtleplugAdapterTask::new(event_sender, receiver, connection_limiter, adapter_provider);
       task.run().await;
     });
     Self {
@@ -66,21 +122,38 @@ impl DeviceCommunicationManager for BtlePlugCommunicationManager {
     // Set to true just to make sure we don't call ScanningFinished too early.
     scanning_status.store(true, Ordering::SeqCst);
     Box::pin(async move {
+      let (result_sender, result_receiver) = oneshot::channel();
       if adapter_event_sender
-        .send(BtleplugAdapterCommand::StartScanning)
+        .send(BtleplugAdapterCommand::StartScanning(result_sender))
         .await
         .is_err()
       {
         error!("Error starting scan, cannot send to btleplug event loop.");
         scanning_status.store(false, Ordering::SeqCst);
-        Err(
+        return Err(
           ButtplugDeviceError::DeviceConnectionError(
             "Cannot send start scanning request to event loop.".to_owned(),
           )
           .into(),
-        )
-      } else {
-        Ok(())
+        );
+      }
+      match result_receiver.await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(err)) => {
+          // Nothing is going to finish scanning for us if we never started.
+          scanning_status.store(false, Ordering::SeqCst);
+          Err(err.into())
+        }
+        Err(_) => {
+          error!("Error starting scan, btleplug event loop dropped the request.");
+          scanning_status.store(false, Ordering::SeqCst);
+          Err(
+            ButtplugDeviceError::DeviceConnectionError(
+              "Btleplug event loop dropped start scanning request.".to_owned(),
+            )
+            .into(),
+          )
+        }
       }
     })
   }
@@ -108,9 +181,116 @@ impl DeviceCommunicationManager for BtlePlugCommunicationManager {
     })
   }
 
+  fn capabilities(&self) -> CommManagerCapabilities {
+    CommManagerCapabilities {
+      hotplug: true,
+      targeted_connect: true,
+      background_scanning: true,
+      ..Default::default()
+    }
+  }
+
+  fn set_background_scanning(&self, enabled: bool) -> ButtplugResultFuture {
+    let adapter_event_sender = self.adapter_event_sender.clone();
+    Box::pin(async move {
+      if adapter_event_sender
+        .send(BtleplugAdapterCommand::SetBackgroundScanning(enabled))
+        .await
+        .is_err()
+      {
+        error!("Error setting background scanning, cannot send to btleplug event loop.");
+        return Err(
+          ButtplugDeviceError::DeviceConnectionError(
+            "Cannot send background scanning request to event loop.".to_owned(),
+          )
+          .into(),
+        );
+      }
+      Ok(())
+    })
+  }
+
+  fn connect_device(&self, address: &str) -> ButtplugResultFuture {
+    let adapter_event_sender = self.adapter_event_sender.clone();
+    let address = address.to_owned();
+    Box::pin(async move {
+      let (result_sender, result_receiver) = oneshot::channel();
+      if adapter_event_sender
+        .send(BtleplugAdapterCommand::ConnectDevice(
+          address,
+          result_sender,
+        ))
+        .await
+        .is_err()
+      {
+        error!("Error connecting device, cannot send to btleplug event loop.");
+        return Err(
+          ButtplugDeviceError::DeviceConnectionError(
+            "Cannot send connect device request to event loop.".to_owned(),
+          )
+          .into(),
+        );
+      }
+      match result_receiver.await {
+        Ok(result) => result.map_err(|err| err.into()),
+        Err(_) => Err(
+          ButtplugDeviceError::DeviceConnectionError(
+            "Btleplug event loop dropped connect device request.".to_owned(),
+          )
+          .into(),
+        ),
+      }
+    })
+  }
+
   fn scanning_status(&self) -> Arc<AtomicBool> {
     self.scanning_status.clone()
   }
+
+  fn pause_io(&self) -> ButtplugResultFuture {
+    let adapter_event_sender = self.adapter_event_sender.clone();
+    self.scanning_status.store(false, Ordering::SeqCst);
+    Box::pin(async move {
+      let (result_sender, result_receiver) = oneshot::channel();
+      if adapter_event_sender
+        .send(BtleplugAdapterCommand::PauseIo(result_sender))
+        .await
+        .is_err()
+        || result_receiver.await.is_err()
+      {
+        error!("Error pausing IO, cannot reach btleplug event loop.");
+        return Err(
+          ButtplugDeviceError::DeviceConnectionError(
+            "Cannot send pause IO request to event loop.".to_owned(),
+          )
+          .into(),
+        );
+      }
+      Ok(())
+    })
+  }
+
+  fn resume_io(&self) -> ButtplugResultFuture {
+    let adapter_event_sender = self.adapter_event_sender.clone();
+    Box::pin(async move {
+      let (result_sender, result_receiver) = oneshot::channel();
+      if adapter_event_sender
+        .send(BtleplugAdapterCommand::ResumeIo(result_sender))
+        .await
+        .is_err()
+        || result_receiver.await.is_err()
+      {
+        error!("Error resuming IO, cannot reach btleplug event loop.");
+        return Err(
+          ButtplugDeviceError::DeviceConnectionError(
+            "Cannot send resume IO request to event loop.".to_owned(),
+          )
+          .into(),
+        );
+      }
+      Ok(())
+    })
+  }
 }
 /*
 impl Drop for BtlePlugCommunicationManager {
@@ -0,0 +1,52 @@
+use crate::core::errors::{ButtplugDeviceConnectionError, ButtplugError};
+use futures::{Future, FutureExt};
+use futures_timer::Delay;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Semaphore;
+
+pub const DEFAULT_MAX_CONCURRENT_CONNECTIONS: usize = 2;
+pub const DEFAULT_CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Limits how many devices the btleplug comm manager connects to at once.
+/// Connecting to a lot of devices at the same time (i.e. when a scan finds a
+/// room full of toys) can destabilize some adapters, so attempts past the limit
+/// wait for earlier ones to finish.
+#[derive(Clone)]
+pub struct BtleplugConnectionLimiter {
+  semaphore: Arc<Semaphore>,
+  timeout: Duration,
+}
+
+impl BtleplugConnectionLimiter {
+  pub fn new(max_concurrent_connections: usize, timeout: Duration) -> Self {
+    Self {
+      // A limit of 0 would mean never connecting to anything.
+      semaphore: Arc::new(Semaphore::new(max_concurrent_connections.max(1))),
+      timeout,
+    }
+  }
+
+  /// Runs a connection attempt once a slot is free, failing it if it takes
+  /// longer than the timeout. Time spent waiting for a slot doesn't count
+  /// toward the timeout.
+  pub async fn run<T>(
+    &self,
+    attempt: impl Future<Output = Result<T, ButtplugError>>,
+  ) -> Result<T, ButtplugError> {
+    let _permit = self
+      .semaphore
+      .acquire()
+      .await
+      .expect("Semaphore is never closed");
+    select! {
+      result = attempt.fuse() => result,
+      _ = Delay::new(self.timeout).fuse() => Err(
+        ButtplugDeviceConnectionError::Timeout(format!(
+          "BTLEPlug connection attempt timed out after {}ms.",
+          self.timeout.as_millis()
+        ))
+        .into(),
+      ),
+    }
+  }
+}
@@ -1,6 +1,7 @@
+use super::btleplug_connection_limiter::BtleplugConnectionLimiter;
 use crate::{
   core::{
-    errors::{ButtplugDeviceError, ButtplugError},
+    errors::{ButtplugDeviceConnectionError, ButtplugDeviceError, ButtplugError},
     messages::RawReading,
     ButtplugResultFuture,
   },
@@ -10,6 +11,9 @@ use crate::{
     ButtplugDeviceImplCreator,
     DeviceImpl,
     DeviceImplInternal,
+    DeviceInspectionCharacteristic,
+    DeviceInspectionReport,
+    DeviceInspectionService,
     DeviceReadCmd,
     DeviceSubscribeCmd,
     DeviceUnsubscribeCmd,
@@ -29,6 +33,7 @@ use futures::{
   Stream,
   StreamExt,
 };
+use static_assertions::assert_impl_all;
 use std::{
   collections::HashMap,
   fmt::{self, Debug},
@@ -45,8 +50,10 @@ pub struct BtlePlugDeviceImplCreator<T: Peripheral + 'static> {
   name: String,
   address: PeripheralId,
   services: Vec<Uuid>,
+  manufacturer_data: HashMap<u16, Vec<u8>>,
   device: T,
   adapter: Adapter,
+  connection_limiter: BtleplugConnectionLimiter,
 }
 
 impl<T: Peripheral> BtlePlugDeviceImplCreator<T> {
@@ -54,15 +61,19 @@ impl<T: Peripheral> BtlePlugDeviceImplCreator<T> {
     name: &str,
     address: &PeripheralId,
     services: &[Uuid],
+    manufacturer_data: &HashMap<u16, Vec<u8>>,
     device: T,
     adapter: Adapter,
+    connection_limiter: BtleplugConnectionLimiter,
   ) -> Self {
     Self {
       name: name.to_owned(),
       address: address.to_owned(),
       services: services.to_vec(),
+      manufacturer_data: manufacturer_data.clone(),
       device,
       adapter,
+      connection_limiter,
     }
   }
 }
@@ -76,12 +87,63 @@ impl<T: Peripheral> Debug for BtlePlugDeviceImplCreator<T> {
 #[async_trait]
 impl<T: Peripheral> ButtplugDeviceImplCreator for BtlePlugDeviceImplCreator<T> {
   fn get_specifier(&self) -> DeviceSpecifier {
-    DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device(
+    DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_advertisement(
       &self.name,
       &self.services,
+      &self.manufacturer_data,
     ))
   }
 
+  async fn inspect(&mut self, connect: bool) -> Option<DeviceInspectionReport> {
+    let mut report = DeviceInspectionReport::new(&self.name, &format!("{:?}", self.address));
+    report.advertised_services = self.services.clone();
+    if let Ok(Some(properties)) = self.device.properties().await {
+      report.manufacturer_data = properties.manufacturer_data;
+      report.service_data = properties.service_data;
+      report.rssi = properties.rssi;
+    }
+    if !connect {
+      return Some(report);
+    }
+    let device = self.device.clone();
+    let result = self
+      .connection_limiter
+      .run(async move {
+        device.connect().await.map_err(connection_error)?;
+        device.discover_services().await.map_err(connection_error)
+      })
+      .await;
+    match result {
+      Ok(_) => {
+        report.services = Some(
+          self
+            .device
+            .services()
+            .iter()
+            .map(|service| DeviceInspectionService {
+              uuid: service.uuid,
+              characteristics: service
+                .characteristics
+                .iter()
+                .map(|chr| DeviceInspectionCharacteristic {
+                  uuid: chr.uuid,
+                  properties: format!("{:?}", chr.properties),
+                })
+                .collect(),
+            })
+            .collect(),
+        );
+      }
+      Err(err) => {
+        report.inspection_error = Some(err.to_string());
+      }
+    }
+    if let Err(err) = self.device.disconnect().await {
+      error!("BTLEPlug error disconnecting after inspection: {:?}", err);
+    }
+    Some(report)
+  }
+
   async fn try_create_device_impl(
     &mut self,
     protocol: ProtocolDefinition,
@@ -92,21 +154,24 @@ impl<T: Peripheral> ButtplugDeviceImplCreator for BtlePlugDeviceImplCreator<T> {
       .await
       .expect("If we crash here it's Bluez's fault. Use something else please.")
     {
-      if let Err(err) = self.device.connect().await {
-        let return_err = ButtplugDeviceError::DeviceSpecificError(
-          ButtplugDeviceSpecificError::BtleplugError(format!("{:?}", err)),
-        );
-        return Err(return_err.into());
-      }
-      if let Err(err) = self.device.discover_services().await {
-        error!("BTLEPlug error discovering characteristics: {:?}", err);
-        return Err(
-          ButtplugDeviceError::DeviceConnectionError(format!(
-            "BTLEPlug error discovering characteristics: {:?}",
-            err
-          ))
-          .into(),
-        );
+      let device = self.device.clone();
+      let result = self
+        .connection_limiter
+        .run(async move {
+          if let Err(err) = device.connect().await {
+            return Err(connection_error(err));
+          }
+          if let Err(err) = device.discover_services().await {
+            error!("BTLEPlug error discovering characteristics: {:?}", err);
+            return Err(connection_error(err));
+          }
+          Ok::<(), ButtplugError>(())
+        })
+        .await;
+      if let Err(err) = result {
+        // Don't leave a half finished connection around if we timed out.
+        let _ = self.device.disconnect().await;
+        return Err(err);
       }
     }
     // Map UUIDs to endpoints
@@ -141,6 +206,33 @@ impl<T: Peripheral> ButtplugDeviceImplCreator for BtlePlugDeviceImplCreator<T> {
         }
       }
     }
+    // Anything the config didn't name gets a generic endpoint, so raw
+    // commands can still reach it on devices we don't fully support.
+    let mut free_generics = (0..Endpoint::GENERIC_COUNT)
+      .filter_map(Endpoint::generic)
+      .filter(|endpoint| !endpoints.contains_key(endpoint))
+      .collect::<Vec<Endpoint>>()
+      .into_iter();
+    for service in self.device.services() {
+      for chr in service.characteristics {
+        if uuid_map.contains_key(&chr.uuid) {
+          continue;
+        }
+        if let Some(endpoint) = free_generics.next() {
+          debug!(
+            "Mapping unknown characteristic {} (service {}) to endpoint {}",
+            chr.uuid, service.uuid, endpoint
+          );
+          uuid_map.insert(chr.uuid, endpoint);
+          endpoints.insert(endpoint, chr);
+        } else {
+          warn!(
+            "Out of generic endpoints, characteristic {} (service {}) will not be accessible.",
+            chr.uuid, service.uuid
+          );
+        }
+      }
+    }
     let notification_stream = self
       .device
       .notifications()
@@ -169,6 +261,36 @@ impl<T: Peripheral> ButtplugDeviceImplCreator for BtlePlugDeviceImplCreator<T> {
   }
 }
 
+/// Sorts btleplug connection failures into [ButtplugDeviceConnectionError]s
+/// where possible, so the device manager knows whether to retry them.
+fn connection_error(err: btleplug::Error) -> ButtplugError {
+  match err {
+    btleplug::Error::DeviceNotFound => {
+      ButtplugDeviceConnectionError::NotFound("BTLEPlug device not found.".to_owned()).into()
+    }
+    btleplug::Error::PermissionDenied => {
+      ButtplugDeviceConnectionError::AuthFailed("BTLEPlug permission denied.".to_owned()).into()
+    }
+    btleplug::Error::NotSupported(msg) => ButtplugDeviceConnectionError::Unsupported(msg).into(),
+    btleplug::Error::TimedOut(duration) => ButtplugDeviceConnectionError::Timeout(format!(
+      "BTLEPlug timed out after {}ms.",
+      duration.as_millis()
+    ))
+    .into(),
+    err => ButtplugDeviceError::DeviceSpecificError(ButtplugDeviceSpecificError::BtleplugError(
+      format!("{:?}", err),
+    ))
+    .into(),
+  }
+}
+
+// btleplug doesn't tell us the MTU negotiated with the device, so assume the
+// minimum BLE allows. ATT write requests use 3 bytes of that for the opcode and
+// handle. Raw writes longer than this are only split up if the user opted in,
+// as a device that doesn't expect it will see each piece as its own packet.
+const BLE_DEFAULT_ATT_MTU: usize = 23;
+const ATT_WRITE_HEADER_SIZE: usize = 3;
+
 pub struct BtlePlugDeviceImpl<T: Peripheral + 'static> {
   device: T,
   event_stream: broadcast::Sender<ButtplugDeviceEvent>,
@@ -176,10 +298,9 @@ pub struct BtlePlugDeviceImpl<T: Peripheral + 'static> {
   endpoints: HashMap<Endpoint, Characteristic>,
 }
 
-unsafe impl<T: Peripheral + 'static> Send for BtlePlugDeviceImpl<T> {
-}
-unsafe impl<T: Peripheral + 'static> Sync for BtlePlugDeviceImpl<T> {
-}
+// btleplug peripherals are Send + Sync, so the device impl is too, without
+// having to promise it ourselves.
+assert_impl_all!(BtlePlugDeviceImpl<btleplug::platform::Peripheral>: Send, Sync);
 
 impl<T: Peripheral + 'static> BtlePlugDeviceImpl<T> {
   pub fn new(
@@ -302,19 +423,42 @@ impl<T: Peripheral + 'static> DeviceImplInternal for BtlePlugDeviceImpl<T> {
     } else {
       WriteType::WithoutResponse
     };
+    let max_write_size = BLE_DEFAULT_ATT_MTU - ATT_WRITE_HEADER_SIZE;
+    let chunks: Vec<Vec<u8>> = if msg.data.len() <= max_write_size || !msg.raw {
+      // Protocols know what their devices take, so their writes always go out
+      // as is.
+      vec![msg.data]
+    } else if msg.chunked {
+      msg
+        .data
+        .chunks(max_write_size)
+        .map(|chunk| chunk.to_vec())
+        .collect()
+    } else {
+      return Box::pin(future::ready(Err(
+        ButtplugDeviceError::DeviceWriteTooLong(
+          msg.endpoint,
+          msg.data.len() as u32,
+          max_write_size as u32,
+        )
+        .into(),
+      )));
+    };
     Box::pin(async move {
-      match device.write(&characteristic, &msg.data, write_type).await {
-        Ok(()) => Ok(()),
-        Err(err) => {
+      // Chunks go out in order, each waiting on the last, so with-response
+      // writes get acknowledged chunk by chunk.
+      for chunk in chunks {
+        if let Err(err) = device.write(&characteristic, &chunk, write_type).await {
           error!("BTLEPlug device write error: {:?}", err);
-          Err(
+          return Err(
             ButtplugDeviceError::DeviceSpecificError(ButtplugDeviceSpecificError::BtleplugError(
               format!("{:?}", err),
             ))
             .into(),
-          )
+          );
         }
       }
+      Ok(())
     })
   }
 
@@ -1,4 +1,6 @@
 pub mod btleplug_comm_manager;
+pub use btleplug_adapter_task::BtleplugAdapterProvider;
 pub use btleplug_comm_manager::BtlePlugCommunicationManagerBuilder;
 mod btleplug_adapter_task;
+mod btleplug_connection_limiter;
 pub mod btleplug_device_impl;
@@ -0,0 +1,328 @@
+use super::{
+  forwarded_device_impl::{ForwardedDeviceImplCreator, ForwardedDeviceRequest},
+  ForwardingLink,
+  ForwardingMessage,
+};
+use crate::{
+  core::ButtplugResultFuture,
+  device::ButtplugDeviceEvent,
+  server::comm_managers::{
+    CommManagerCapabilities,
+    DeviceCommunicationEvent,
+    DeviceCommunicationManager,
+    DeviceCommunicationManagerBuilder,
+  },
+  util::async_manager,
+};
+use futures::{future, FutureExt};
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+};
+use tokio::sync::{
+  broadcast,
+  mpsc::{self, Sender, UnboundedReceiver, UnboundedSender},
+  oneshot,
+};
+use tokio_util::sync::CancellationToken;
+
+pub struct ForwardedDeviceCommunicationManagerBuilder {
+  sender: Option<Sender<DeviceCommunicationEvent>>,
+  link_sender: UnboundedSender<ForwardingLink>,
+  link_receiver: UnboundedReceiver<ForwardingLink>,
+  listen_on_all_interfaces: bool,
+  server_port: Option<u16>,
+}
+
+impl Default for ForwardedDeviceCommunicationManagerBuilder {
+  fn default() -> Self {
+    let (link_sender, link_receiver) = mpsc::unbounded_channel();
+    Self {
+      sender: None,
+      link_sender,
+      link_receiver,
+      listen_on_all_interfaces: false,
+      server_port: None,
+    }
+  }
+}
+
+impl ForwardedDeviceCommunicationManagerBuilder {
+  /// Sender for links to forwarders. Devices shared over each link are added
+  /// as they're announced, and removed when the link closes. Links from the
+  /// websocket listener, if there is one, go through here too.
+  pub fn link_sender(&self) -> UnboundedSender<ForwardingLink> {
+    self.link_sender.clone()
+  }
+
+  pub fn listen_on_all_interfaces(mut self, should_listen: bool) -> Self {
+    self.listen_on_all_interfaces = should_listen;
+    self
+  }
+
+  /// Port to accept forwarding links on over websockets. Without one, links
+  /// can only be added through [link_sender][Self::link_sender].
+  #[cfg(feature = "websockets")]
+  pub fn server_port(mut self, port: u16) -> Self {
+    self.server_port = Some(port);
+    self
+  }
+}
+
+impl DeviceCommunicationManagerBuilder for ForwardedDeviceCommunicationManagerBuilder {
+  fn event_sender(mut self, sender: Sender<DeviceCommunicationEvent>) -> Self {
+    self.sender = Some(sender);
+    self
+  }
+
+  fn finish(mut self) -> Box<dyn DeviceCommunicationManager> {
+    Box::new(ForwardedDeviceCommunicationManager::new(
+      self
+        .sender
+        .take()
+        .expect("We'll always be able to take this"),
+      self.link_sender,
+      self.link_receiver,
+      self.server_port,
+      self.listen_on_all_interfaces,
+    ))
+  }
+}
+
+/// Runs one link, turning devices the forwarder announces into found devices
+/// and passing commands for them to the forwarder.
+async fn run_forwarding_link(
+  link: ForwardingLink,
+  device_event_sender: Sender<DeviceCommunicationEvent>,
+  token: CancellationToken,
+) {
+  let ForwardingLink {
+    sender: link_sender,
+    receiver: mut link_receiver,
+  } = link;
+  let (request_sender, mut request_receiver) = mpsc::unbounded_channel::<ForwardedDeviceRequest>();
+  let mut devices: HashMap<String, (Arc<AtomicBool>, broadcast::Sender<ButtplugDeviceEvent>)> =
+    HashMap::new();
+  let mut pending_replies: HashMap<u32, oneshot::Sender<Result<Vec<u8>, String>>> = HashMap::new();
+  let mut next_request_id = 0u32;
+  loop {
+    select! {
+      msg = link_receiver.recv().fuse() => match msg {
+        Some(ForwardingMessage::DeviceAdded(info)) => {
+          info!("Forwarder shared device {} ({}).", info.name, info.address);
+          let connected = Arc::new(AtomicBool::new(true));
+          let (event_sender, _) = broadcast::channel(256);
+          if let Some((old_connected, old_event_sender)) = devices.insert(
+            info.address.clone(),
+            (connected.clone(), event_sender.clone()),
+          ) {
+            old_connected.store(false, Ordering::SeqCst);
+            let _ = old_event_sender.send(ButtplugDeviceEvent::Removed(info.address.clone()));
+          }
+          let name = info.name.clone();
+          let address = info.address.clone();
+          let creator =
+            ForwardedDeviceImplCreator::new(info, connected, request_sender.clone(), event_sender);
+          if device_event_sender
+            .send(DeviceCommunicationEvent::DeviceFound {
+              name,
+              address,
+              creator: Box::new(creator),
+            })
+            .await
+            .is_err()
+          {
+            error!("Device manager disappeared, closing forwarding link.");
+            break;
+          }
+        }
+        Some(ForwardingMessage::DeviceRemoved { address }) => {
+          info!("Forwarder stopped sharing device {}.", address);
+          if let Some((connected, event_sender)) = devices.remove(&address) {
+            connected.store(false, Ordering::SeqCst);
+            // Only fails if the device never finished connecting.
+            let _ = event_sender.send(ButtplugDeviceEvent::Removed(address));
+          }
+        }
+        Some(ForwardingMessage::Notification { address, endpoint, data }) => {
+          if let Some((_, event_sender)) = devices.get(&address) {
+            let _ = event_sender.send(ButtplugDeviceEvent::Notification(address, endpoint, data));
+          }
+        }
+        Some(ForwardingMessage::Reply { id, result }) => {
+          if let Some(reply_sender) = pending_replies.remove(&id) {
+            // The command may have been given up on, in which case nobody
+            // needs the reply.
+            let _ = reply_sender.send(result);
+          } else {
+            warn!("Forwarder replied to unknown request {}, ignoring.", id);
+          }
+        }
+        Some(ForwardingMessage::Request { .. }) => {
+          warn!("Forwarding link sent a request, only forwarders can answer those. Ignoring.");
+        }
+        None => {
+          info!("Forwarding link closed.");
+          break;
+        }
+      },
+      request = request_receiver.recv().fuse() => {
+        let request = request.expect("We hold a sender, so this can't close.");
+        next_request_id = next_request_id.wrapping_add(1);
+        pending_replies.insert(next_request_id, request.reply_sender);
+        if link_sender
+          .send(ForwardingMessage::Request {
+            id: next_request_id,
+            address: request.address,
+            command: request.command,
+          })
+          .is_err()
+        {
+          info!("Forwarding link closed.");
+          break;
+        }
+      },
+      _ = token.cancelled().fuse() => {
+        break;
+      }
+    }
+  }
+  // Devices can't be reached without their link. Dropping the pending reply
+  // senders fails any commands still waiting.
+  for (address, (connected, event_sender)) in devices {
+    connected.store(false, Ordering::SeqCst);
+    let _ = event_sender.send(ButtplugDeviceEvent::Removed(address));
+  }
+}
+
+/// Accepts forwarding links over a websocket, passing them to `link_sender`.
+#[cfg(feature = "websockets")]
+async fn run_websocket_listener(
+  link_sender: UnboundedSender<ForwardingLink>,
+  port: u16,
+  listen_on_all_interfaces: bool,
+  token: CancellationToken,
+) {
+  use tokio::net::TcpListener;
+
+  let base_addr = if listen_on_all_interfaces {
+    "0.0.0.0"
+  } else {
+    "127.0.0.1"
+  };
+  let addr = format!("{}:{}", base_addr, port);
+  let listener = match TcpListener::bind(&addr).await {
+    Ok(listener) => listener,
+    Err(err) => {
+      error!("Cannot bind forwarded device listener to {}: {}", addr, err);
+      return;
+    }
+  };
+  debug!("Listening for forwarding links on: {}", addr);
+  loop {
+    select! {
+      listener_result = listener.accept().fuse() => {
+        let stream = match listener_result {
+          Ok((stream, _)) => stream,
+          Err(err) => {
+            error!("Cannot accept forwarding link on {}: {}", addr, err);
+            return;
+          }
+        };
+        let ws_stream = match async_tungstenite::tokio::accept_async(stream).await {
+          Ok(ws_stream) => ws_stream,
+          Err(err) => {
+            error!("Cannot accept forwarding link websocket: {}", err);
+            continue;
+          }
+        };
+        info!("Got forwarding link connection.");
+        if link_sender.send(ForwardingLink::from_websocket(ws_stream)).is_err() {
+          return;
+        }
+      },
+      _ = token.cancelled().fuse() => {
+        info!("Task token cancelled, stopping forwarding link listener.");
+        return;
+      }
+    }
+  }
+}
+
+pub struct ForwardedDeviceCommunicationManager {
+  cancellation_token: CancellationToken,
+}
+
+impl ForwardedDeviceCommunicationManager {
+  fn new(
+    sender: Sender<DeviceCommunicationEvent>,
+    link_sender: UnboundedSender<ForwardingLink>,
+    mut link_receiver: UnboundedReceiver<ForwardingLink>,
+    server_port: Option<u16>,
+    listen_on_all_interfaces: bool,
+  ) -> Self {
+    let cancellation_token = CancellationToken::new();
+    #[cfg(feature = "websockets")]
+    if let Some(port) = server_port {
+      async_manager::spawn(run_websocket_listener(
+        link_sender.clone(),
+        port,
+        listen_on_all_interfaces,
+        cancellation_token.child_token(),
+      ));
+    }
+    #[cfg(not(feature = "websockets"))]
+    let _ = (server_port, listen_on_all_interfaces);
+    let token = cancellation_token.child_token();
+    async_manager::spawn(async move {
+      // Holding a sender keeps the channel open even if everyone else drops
+      // theirs.
+      let _link_sender = link_sender;
+      loop {
+        select! {
+          link = link_receiver.recv().fuse() => {
+            let link = link.expect("We hold a sender, so this can't close.");
+            info!("New forwarding link.");
+            async_manager::spawn(run_forwarding_link(link, sender.clone(), token.child_token()));
+          },
+          _ = token.cancelled().fuse() => {
+            info!("Task token cancelled, assuming forwarded device comm manager shutdown.");
+            break;
+          }
+        }
+      }
+    });
+    Self { cancellation_token }
+  }
+}
+
+impl DeviceCommunicationManager for ForwardedDeviceCommunicationManager {
+  fn name(&self) -> &'static str {
+    "ForwardedDeviceCommunicationManager"
+  }
+
+  fn start_scanning(&self) -> ButtplugResultFuture {
+    // Devices show up whenever a forwarder shares them.
+    Box::pin(future::ready(Ok(())))
+  }
+
+  fn stop_scanning(&self) -> ButtplugResultFuture {
+    Box::pin(future::ready(Ok(())))
+  }
+
+  fn capabilities(&self) -> CommManagerCapabilities {
+    CommManagerCapabilities {
+      hotplug: true,
+      ..Default::default()
+    }
+  }
+}
+
+impl Drop for ForwardedDeviceCommunicationManager {
+  fn drop(&mut self) {
+    self.cancellation_token.cancel();
+  }
+}
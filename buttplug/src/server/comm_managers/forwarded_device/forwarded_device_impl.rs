@@ -0,0 +1,208 @@
+use super::{ForwardedDeviceCommand, ForwardedDeviceInfo};
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::RawReading,
+    ButtplugResultFuture,
+  },
+  device::{
+    configuration_manager::{DeviceSpecifier, ForwardedSpecifier, ProtocolDefinition},
+    ButtplugDeviceEvent,
+    ButtplugDeviceImplCreator,
+    DeviceImpl,
+    DeviceImplInternal,
+    DeviceReadCmd,
+    DeviceSubscribeCmd,
+    DeviceUnsubscribeCmd,
+    DeviceWriteCmd,
+  },
+};
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use std::{
+  fmt::{self, Debug},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+};
+use tokio::sync::{broadcast, mpsc::UnboundedSender, oneshot};
+
+/// Command for a shared device, on its way to the link task, which sends it
+/// to the forwarder and routes the reply back.
+pub(super) struct ForwardedDeviceRequest {
+  pub address: String,
+  pub command: ForwardedDeviceCommand,
+  pub reply_sender: oneshot::Sender<Result<Vec<u8>, String>>,
+}
+
+pub struct ForwardedDeviceImplCreator {
+  info: ForwardedDeviceInfo,
+  device_impl: Option<ForwardedDeviceImpl>,
+}
+
+impl ForwardedDeviceImplCreator {
+  pub(super) fn new(
+    info: ForwardedDeviceInfo,
+    connected: Arc<AtomicBool>,
+    request_sender: UnboundedSender<ForwardedDeviceRequest>,
+    event_sender: broadcast::Sender<ButtplugDeviceEvent>,
+  ) -> Self {
+    let device_impl = ForwardedDeviceImpl {
+      address: info.address.clone(),
+      connected,
+      request_sender,
+      event_sender,
+    };
+    Self {
+      info,
+      device_impl: Some(device_impl),
+    }
+  }
+}
+
+impl Debug for ForwardedDeviceImplCreator {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("ForwardedDeviceImplCreator")
+      .field("info", &self.info)
+      .finish()
+  }
+}
+
+#[async_trait]
+impl ButtplugDeviceImplCreator for ForwardedDeviceImplCreator {
+  fn get_specifier(&self) -> DeviceSpecifier {
+    DeviceSpecifier::Forwarded(ForwardedSpecifier::new(&self.info.protocol))
+  }
+
+  async fn try_create_device_impl(
+    &mut self,
+    _: ProtocolDefinition,
+  ) -> Result<DeviceImpl, ButtplugError> {
+    let device_impl = self.device_impl.take().ok_or_else(|| {
+      ButtplugDeviceError::DeviceConnectionError(format!(
+        "Forwarded device {} was already created.",
+        self.info.address
+      ))
+    })?;
+    Ok(DeviceImpl::new(
+      &self.info.name,
+      &self.info.address,
+      &self.info.endpoints,
+      Box::new(device_impl),
+    ))
+  }
+}
+
+/// Device shared by another server. Every command is sent over the link the
+/// device came from, and fails if the link has closed.
+pub struct ForwardedDeviceImpl {
+  address: String,
+  connected: Arc<AtomicBool>,
+  request_sender: UnboundedSender<ForwardedDeviceRequest>,
+  event_sender: broadcast::Sender<ButtplugDeviceEvent>,
+}
+
+impl ForwardedDeviceImpl {
+  fn send_command(
+    &self,
+    command: ForwardedDeviceCommand,
+  ) -> BoxFuture<'static, Result<Vec<u8>, ButtplugError>> {
+    let (reply_sender, reply_receiver) = oneshot::channel();
+    let sent = self
+      .request_sender
+      .send(ForwardedDeviceRequest {
+        address: self.address.clone(),
+        command,
+        reply_sender,
+      })
+      .is_ok();
+    let address = self.address.clone();
+    Box::pin(async move {
+      let link_closed = || {
+        ButtplugDeviceError::DeviceCommunicationError(format!(
+          "Link to forwarded device {} is closed.",
+          address
+        ))
+      };
+      if !sent {
+        return Err(link_closed().into());
+      }
+      match reply_receiver.await {
+        Ok(Ok(data)) => Ok(data),
+        Ok(Err(err)) => Err(
+          ButtplugDeviceError::DeviceCommunicationError(format!(
+            "Forwarded device {} returned an error: {}",
+            address, err
+          ))
+          .into(),
+        ),
+        Err(_) => Err(link_closed().into()),
+      }
+    })
+  }
+
+  fn send_command_without_reply(&self, command: ForwardedDeviceCommand) -> ButtplugResultFuture {
+    let reply = self.send_command(command);
+    Box::pin(async move { reply.await.map(|_| ()) })
+  }
+}
+
+impl DeviceImplInternal for ForwardedDeviceImpl {
+  fn connected(&self) -> bool {
+    self.connected.load(Ordering::SeqCst)
+  }
+
+  fn disconnect(&self) -> ButtplugResultFuture {
+    // The device stays connected to the forwarding server, we just stop using
+    // it.
+    self.connected.store(false, Ordering::SeqCst);
+    let event_sender = self.event_sender.clone();
+    let address = self.address.clone();
+    Box::pin(async move {
+      // Devices that fail to initialize are disconnected before anything
+      // subscribes to their events.
+      let _ = event_sender.send(ButtplugDeviceEvent::Removed(address));
+      Ok(())
+    })
+  }
+
+  fn event_stream(&self) -> broadcast::Receiver<ButtplugDeviceEvent> {
+    self.event_sender.subscribe()
+  }
+
+  fn read_value(
+    &self,
+    msg: DeviceReadCmd,
+  ) -> BoxFuture<'static, Result<RawReading, ButtplugError>> {
+    let endpoint = msg.endpoint;
+    let reply = self.send_command(ForwardedDeviceCommand::Read {
+      endpoint,
+      length: msg.length,
+      timeout_ms: msg.timeout_ms,
+    });
+    Box::pin(async move { Ok(RawReading::new(0, endpoint, reply.await?)) })
+  }
+
+  fn write_value(&self, msg: DeviceWriteCmd) -> ButtplugResultFuture {
+    self.send_command_without_reply(ForwardedDeviceCommand::Write {
+      endpoint: msg.endpoint,
+      data: msg.data,
+      write_with_response: msg.write_with_response,
+      raw: msg.raw,
+      chunked: msg.chunked,
+    })
+  }
+
+  fn subscribe(&self, msg: DeviceSubscribeCmd) -> ButtplugResultFuture {
+    self.send_command_without_reply(ForwardedDeviceCommand::Subscribe {
+      endpoint: msg.endpoint,
+    })
+  }
+
+  fn unsubscribe(&self, msg: DeviceUnsubscribeCmd) -> ButtplugResultFuture {
+    self.send_command_without_reply(ForwardedDeviceCommand::Unsubscribe {
+      endpoint: msg.endpoint,
+    })
+  }
+}
@@ -0,0 +1,196 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Devices shared by another server over a [ForwardingLink].
+//!
+//! The server the devices are connected to runs a
+//! [DeviceForwarder][crate::server::device_forwarder::DeviceForwarder], which
+//! decides which of its devices are shared. The server receiving them runs a
+//! [ForwardedDeviceCommunicationManager][forwarded_device_comm_manager::ForwardedDeviceCommunicationManager],
+//! which adds each shared device like any other found device. Devices are
+//! forwarded at the endpoint level, so the receiving server runs the protocol
+//! and the forwarder only passes reads, writes and notifications along.
+//!
+//! Links carry [ForwardingMessage]s. Both ends can be in the same process, see
+//! [ForwardingLink::pair], or on different machines, connected by a websocket
+//! when the `websockets` feature is enabled.
+
+pub mod forwarded_device_comm_manager;
+pub mod forwarded_device_impl;
+
+use crate::device::Endpoint;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// Identity of a shared device, as matched by the forwarding server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ForwardedDeviceInfo {
+  pub name: String,
+  pub address: String,
+  /// Protocol the forwarding server matched the device with. The receiving
+  /// server needs a protocol definition with the same name.
+  pub protocol: String,
+  pub endpoints: Vec<Endpoint>,
+}
+
+/// Endpoint level command the receiving server sends to a shared device.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ForwardedDeviceCommand {
+  Write {
+    endpoint: Endpoint,
+    data: Vec<u8>,
+    write_with_response: bool,
+    raw: bool,
+    chunked: bool,
+  },
+  Read {
+    endpoint: Endpoint,
+    length: u32,
+    timeout_ms: u32,
+  },
+  Subscribe {
+    endpoint: Endpoint,
+  },
+  Unsubscribe {
+    endpoint: Endpoint,
+  },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ForwardingMessage {
+  /// Sent by the forwarder when it starts sharing a device.
+  DeviceAdded(ForwardedDeviceInfo),
+  /// Sent by the forwarder when it stops sharing a device, either because the
+  /// device disconnected or because it was taken off the allow list.
+  DeviceRemoved { address: String },
+  /// Notification from a shared device.
+  Notification {
+    address: String,
+    endpoint: Endpoint,
+    data: Vec<u8>,
+  },
+  /// Sent by the receiving server to run a command on a shared device.
+  Request {
+    id: u32,
+    address: String,
+    command: ForwardedDeviceCommand,
+  },
+  /// Answer to the request with the same id. Holds the data read for reads,
+  /// and nothing for other commands.
+  Reply {
+    id: u32,
+    result: Result<Vec<u8>, String>,
+  },
+}
+
+/// One end of a link between a forwarder and the server receiving its
+/// devices. Dropping either end closes the link, which removes all of its
+/// devices from the receiving server.
+pub struct ForwardingLink {
+  pub(crate) sender: UnboundedSender<ForwardingMessage>,
+  pub(crate) receiver: UnboundedReceiver<ForwardingMessage>,
+}
+
+impl ForwardingLink {
+  /// Creates a link end that sends to `sender` and receives from `receiver`,
+  /// for carrying links over transports other than websockets.
+  pub fn new(
+    sender: UnboundedSender<ForwardingMessage>,
+    receiver: UnboundedReceiver<ForwardingMessage>,
+  ) -> Self {
+    Self { sender, receiver }
+  }
+
+  /// Creates both ends of a link within the process.
+  pub fn pair() -> (Self, Self) {
+    let (first_sender, first_receiver) = mpsc::unbounded_channel();
+    let (second_sender, second_receiver) = mpsc::unbounded_channel();
+    (
+      Self::new(first_sender, second_receiver),
+      Self::new(second_sender, first_receiver),
+    )
+  }
+
+  /// Carries the link over a websocket, one JSON encoded [ForwardingMessage]
+  /// per text frame.
+  #[cfg(feature = "websockets")]
+  pub fn from_websocket<S>(ws_stream: async_tungstenite::WebSocketStream<S>) -> Self
+  where
+    S: 'static + futures::AsyncRead + futures::AsyncWrite + Unpin + Send,
+  {
+    use crate::util::async_manager;
+    use async_tungstenite::tungstenite::Message;
+    use futures::{FutureExt, SinkExt, StreamExt};
+
+    let (outgoing_sender, mut outgoing_receiver) = mpsc::unbounded_channel();
+    let (incoming_sender, incoming_receiver) = mpsc::unbounded_channel();
+    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+    async_manager::spawn(async move {
+      loop {
+        select! {
+          msg = outgoing_receiver.recv().fuse() => {
+            let msg = match msg {
+              Some(msg) => msg,
+              None => {
+                debug!("Forwarding link dropped, closing websocket.");
+                let _ = ws_sender.close().await;
+                break;
+              }
+            };
+            let text = serde_json::to_string(&msg)
+              .expect("Forwarding messages are always serializable.");
+            if let Err(err) = ws_sender.send(Message::Text(text)).await {
+              error!("Cannot send to forwarding link websocket, closing link: {}", err);
+              break;
+            }
+          }
+          ws_msg = ws_receiver.next().fuse() => match ws_msg {
+            Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+              Ok(msg) => {
+                if incoming_sender.send(msg).is_err() {
+                  debug!("Forwarding link dropped, closing websocket.");
+                  let _ = ws_sender.close().await;
+                  break;
+                }
+              }
+              Err(err) => error!("Invalid forwarding message {}, ignoring: {}", text, err),
+            },
+            Some(Ok(Message::Close(_))) | None => {
+              info!("Forwarding link websocket closed.");
+              break;
+            }
+            Some(Ok(_)) => {}
+            Some(Err(err)) => {
+              error!("Error from forwarding link websocket, closing link: {}", err);
+              break;
+            }
+          }
+        }
+      }
+    });
+    Self::new(outgoing_sender, incoming_receiver)
+  }
+
+  /// Connects to a server listening for forwarding links, i.e. one with a
+  /// [ForwardedDeviceCommunicationManager][forwarded_device_comm_manager::ForwardedDeviceCommunicationManager]
+  /// that has a port set. Address should be the full URL of the server, i.e.
+  /// "ws://127.0.0.1:54818".
+  #[cfg(feature = "websockets")]
+  pub async fn connect_websocket(
+    address: &str,
+  ) -> Result<Self, crate::core::errors::ButtplugDeviceError> {
+    let (ws_stream, _) = async_tungstenite::tokio::connect_async(address)
+      .await
+      .map_err(|err| {
+        crate::core::errors::ButtplugDeviceError::DeviceConnectionError(format!(
+          "Cannot connect forwarding link to {}: {}",
+          address, err
+        ))
+      })?;
+    Ok(Self::from_websocket(ws_stream))
+  }
+}
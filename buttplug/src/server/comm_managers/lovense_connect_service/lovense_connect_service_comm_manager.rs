@@ -2,6 +2,7 @@ use super::lovense_connect_service_device_impl::LovenseServiceDeviceImplCreator;
 use crate::{
   core::ButtplugResultFuture,
   server::comm_managers::{
+    CommManagerCapabilities,
     DeviceCommunicationEvent,
     DeviceCommunicationManager,
     DeviceCommunicationManagerBuilder,
@@ -105,7 +106,7 @@ async fn lovense_local_service_check(
               "Error contacting Lovense Connect Local API endpoint. Status returned: {}",
               res.status()
             );
-            tokio::time::sleep(Duration::from_secs(1)).await;
+            Delay::new(Duration::from_secs(1)).await;
             continue;
           }
 
@@ -242,7 +243,7 @@ impl DeviceCommunicationManager for LovenseConnectServiceCommunicationManager {
             Ok(res) => {
               if res.status() != StatusCode::OK {
                 error!("Error contacting Lovense Connect Remote API endpoint. Status returned: {}", res.status());
-                tokio::time::sleep(Duration::from_secs(1)).await;
+                Delay::new(Duration::from_secs(1)).await;
                 continue;
               }
               let text = res.text().await.expect("Should always get json back from service, if we got a response.");
@@ -306,6 +307,13 @@ impl DeviceCommunicationManager for LovenseConnectServiceCommunicationManager {
     self.is_scanning.store(false, Ordering::SeqCst);
     Box::pin(future::ready(Ok(())))
   }
+
+  fn capabilities(&self) -> CommManagerCapabilities {
+    CommManagerCapabilities {
+      hotplug: true,
+      ..Default::default()
+    }
+  }
 }
 
 impl Drop for LovenseConnectServiceCommunicationManager {
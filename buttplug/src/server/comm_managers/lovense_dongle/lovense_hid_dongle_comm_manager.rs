@@ -9,6 +9,7 @@ use super::{
 use crate::{
   core::{errors::ButtplugDeviceError, ButtplugResultFuture},
   server::comm_managers::{
+    CommManagerCapabilities,
     DeviceCommunicationEvent,
     DeviceCommunicationManager,
     DeviceCommunicationManagerBuilder,
@@ -289,6 +290,14 @@ impl DeviceCommunicationManager for LovenseHIDDongleCommunicationManager {
     })
   }
 
+  fn capabilities(&self) -> CommManagerCapabilities {
+    CommManagerCapabilities {
+      hotplug: true,
+      exclusive_access: true,
+      ..Default::default()
+    }
+  }
+
   fn scanning_status(&self) -> Arc<AtomicBool> {
     self.is_scanning.clone()
   }
@@ -9,6 +9,7 @@ use super::{
 use crate::{
   core::ButtplugResultFuture,
   server::comm_managers::{
+    CommManagerCapabilities,
     DeviceCommunicationEvent,
     DeviceCommunicationManager,
     DeviceCommunicationManagerBuilder,
@@ -299,6 +300,14 @@ impl DeviceCommunicationManager for LovenseSerialDongleCommunicationManager {
     })
   }
 
+  fn capabilities(&self) -> CommManagerCapabilities {
+    CommManagerCapabilities {
+      hotplug: true,
+      exclusive_access: true,
+      ..Default::default()
+    }
+  }
+
   fn scanning_status(&self) -> Arc<AtomicBool> {
     self.is_scanning.clone()
   }
@@ -1,11 +1,15 @@
 #[cfg(feature = "btleplug-manager")]
 pub mod btleplug;
+#[cfg(feature = "forwarded-device-manager")]
+pub mod forwarded_device;
 #[cfg(feature = "lovense-connect-service-manager")]
 pub mod lovense_connect_service;
 #[cfg(feature = "lovense-dongle-manager")]
 pub mod lovense_dongle;
 #[cfg(feature = "serial-manager")]
 pub mod serialport;
+#[cfg(feature = "usb-manager")]
+pub mod usb;
 #[cfg(all(feature = "xinput-manager", target_os = "windows"))]
 pub mod xinput;
 
@@ -14,7 +18,11 @@ pub mod websocket_server;
 
 pub mod test;
 
-use crate::{core::ButtplugResultFuture, device::ButtplugDeviceImplCreator};
+use crate::{
+  core::{errors::ButtplugDeviceError, ButtplugResultFuture},
+  device::ButtplugDeviceImplCreator,
+};
+use futures::future;
 use serde::{Deserialize, Serialize};
 use std::sync::{atomic::AtomicBool, Arc};
 use thiserror::Error;
@@ -30,8 +38,64 @@ pub enum DeviceCommunicationEvent {
     creator: Box<dyn ButtplugDeviceImplCreator>,
   },
   DeviceManagerAdded(Arc<AtomicBool>),
+  DeviceManagerRemoved(Arc<AtomicBool>),
+  // Sent by the device manager once all comm managers have been told to start
+  // scanning.
   ScanningStarted,
+  // Sent by the device manager once the comm managers with these scanning
+  // statuses have been told to stop scanning. Lets the event loop finish the
+  // scan even if a comm manager stops without sending ScanningFinished.
+  ScanningStopped(Vec<Arc<AtomicBool>>),
+  // Sent by comm managers when they finish scanning.
   ScanningFinished,
+  // Sent by the device manager to connect a device that was found, but not
+  // connected, during an identify-only scan, and by comm managers to connect
+  // a device asked for by address.
+  ConnectIdentifiedDevice {
+    name: String,
+    address: String,
+    creator: Box<dyn ButtplugDeviceImplCreator>,
+  },
+  // Sent by comm managers for devices found by a background scan, rather
+  // than one a user started. Only connected if allow listed.
+  BackgroundDeviceFound {
+    name: String,
+    address: String,
+    creator: Box<dyn ButtplugDeviceImplCreator>,
+  },
+}
+
+/// What a comm manager's transport can do, so front-ends can show which
+/// transports are usable on the current machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommManagerCapabilities {
+  /// Devices show up as they're plugged in or come into range while
+  /// scanning, instead of only when scanning starts.
+  pub hotplug: bool,
+  /// Devices can be connected by address, without being found by a scan
+  /// first.
+  pub targeted_connect: bool,
+  /// Connected devices are held exclusively, so other applications can't use
+  /// them at the same time.
+  pub exclusive_access: bool,
+  /// Can keep a low power scan running between user requested scans, see
+  /// [DeviceCommunicationManager::set_background_scanning].
+  pub background_scanning: bool,
+  /// The transport works on this platform. Does not mean hardware for it,
+  /// like a Bluetooth adapter, is present.
+  pub available: bool,
+}
+
+impl Default for CommManagerCapabilities {
+  fn default() -> Self {
+    Self {
+      hotplug: false,
+      targeted_connect: false,
+      exclusive_access: false,
+      background_scanning: false,
+      available: true,
+    }
+  }
 }
 
 pub trait DeviceCommunicationManagerBuilder: Send {
@@ -46,6 +110,45 @@ pub trait DeviceCommunicationManager: Send + Sync {
   fn scanning_status(&self) -> Arc<AtomicBool> {
     Arc::new(AtomicBool::new(false))
   }
+  fn capabilities(&self) -> CommManagerCapabilities {
+    CommManagerCapabilities::default()
+  }
+  /// Finds the device at `address` without scanning, and sends it to the
+  /// device manager to connect. Only called on comm managers that report
+  /// [CommManagerCapabilities::targeted_connect].
+  fn connect_device(&self, address: &str) -> ButtplugResultFuture {
+    Box::pin(future::ready(Err(
+      ButtplugDeviceError::DeviceConnectionError(format!(
+        "{} cannot connect device {} by address.",
+        self.name(),
+        address
+      ))
+      .into(),
+    )))
+  }
+  /// Turns background scanning on or off. While on, the comm manager keeps
+  /// scanning at a low duty cycle, sending devices it finds as
+  /// [DeviceCommunicationEvent::BackgroundDeviceFound]. Only called on comm
+  /// managers that report [CommManagerCapabilities::background_scanning].
+  fn set_background_scanning(&self, _enabled: bool) -> ButtplugResultFuture {
+    Box::pin(future::ready(Err(
+      ButtplugDeviceError::DeviceConnectionError(format!(
+        "{} cannot scan in the background.",
+        self.name()
+      ))
+      .into(),
+    )))
+  }
+  /// Called when the host app is about to lose access to its hardware, i.e.
+  /// being backgrounded on mobile. Scanning will already have been stopped.
+  fn pause_io(&self) -> ButtplugResultFuture {
+    Box::pin(future::ready(Ok(())))
+  }
+  /// Called when the host app gets its hardware back after
+  /// [DeviceCommunicationManager::pause_io].
+  fn resume_io(&self) -> ButtplugResultFuture {
+    Box::pin(future::ready(Ok(())))
+  }
   // Events happen via channel senders passed to the comm manager.
 }
 
@@ -62,4 +165,7 @@ pub enum ButtplugDeviceSpecificError {
   #[cfg(feature = "serial-manager")]
   #[error("Serial error: {0}")]
   SerialError(String),
+  #[cfg(feature = "usb-manager")]
+  #[error("USB error: {0}")]
+  USBError(String),
 }
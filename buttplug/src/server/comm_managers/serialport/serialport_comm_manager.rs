@@ -2,6 +2,7 @@ use super::SerialPortDeviceImplCreator;
 use crate::{
   core::ButtplugResultFuture,
   server::comm_managers::{
+    CommManagerCapabilities,
     DeviceCommunicationEvent,
     DeviceCommunicationManager,
     DeviceCommunicationManagerBuilder,
@@ -99,4 +100,11 @@ impl DeviceCommunicationManager for SerialPortCommunicationManager {
   fn stop_scanning(&self) -> ButtplugResultFuture {
     Box::pin(future::ready(Ok(())))
   }
+
+  fn capabilities(&self) -> CommManagerCapabilities {
+    CommManagerCapabilities {
+      exclusive_access: true,
+      ..Default::default()
+    }
+  }
 }
@@ -1,6 +1,6 @@
 use crate::{
   core::{
-    errors::{ButtplugDeviceError, ButtplugError},
+    errors::{ButtplugDeviceConnectionError, ButtplugDeviceError, ButtplugError},
     messages::RawReading,
     ButtplugResultFuture,
   },
@@ -78,6 +78,23 @@ impl ButtplugDeviceImplCreator for SerialPortDeviceImplCreator {
   }
 }
 
+fn map_serial_open_error(e: serialport::Error) -> ButtplugError {
+  match e.kind() {
+    // serialport uses NoDevice for ports held open by something else, too.
+    serialport::ErrorKind::NoDevice => ButtplugDeviceConnectionError::Busy(e.to_string()).into(),
+    serialport::ErrorKind::Io(ErrorKind::NotFound) => {
+      ButtplugDeviceConnectionError::NotFound(e.to_string()).into()
+    }
+    serialport::ErrorKind::Io(ErrorKind::TimedOut) => {
+      ButtplugDeviceConnectionError::Timeout(e.to_string()).into()
+    }
+    _ => ButtplugDeviceError::DeviceSpecificError(ButtplugDeviceSpecificError::SerialError(
+      e.to_string(),
+    ))
+    .into(),
+  }
+}
+
 fn serial_write_thread(mut port: Box<dyn SerialPort>, receiver: mpsc::Receiver<Vec<u8>>) {
   let mut recv = receiver;
   // Instead of waiting on a token here, we'll expect that we'll break on our
@@ -175,11 +192,7 @@ impl SerialPortDeviceImpl {
       .recv()
       .await
       .expect("This will always be a Some value, we're just blocking for bringup")
-      .map_err(|e| {
-        ButtplugError::from(ButtplugDeviceError::DeviceSpecificError(
-          ButtplugDeviceSpecificError::SerialError(e.to_string()),
-        ))
-      })?;
+      .map_err(map_serial_open_error)?;
     debug!("Serial port received from thread.");
     let (writer_sender, writer_receiver) = mpsc::channel(256);
     let (reader_sender, reader_receiver) = mpsc::channel(256);
@@ -1,3 +1,5 @@
+#[cfg(feature = "server")]
+pub mod protocol_fuzz;
 mod test_device;
 #[cfg(feature = "server")]
 mod test_device_comm_manager;
@@ -16,6 +18,8 @@ pub use test_device::{
 #[cfg(feature = "server")]
 pub use test_device_comm_manager::{
   new_bluetoothle_test_device,
+  new_bluetoothle_test_device_with_cfg,
+  new_bluetoothle_test_device_with_setup,
   TestDeviceCommunicationManager,
   TestDeviceCommunicationManagerBuilder,
   TestDeviceCommunicationManagerHelper,
@@ -0,0 +1,446 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Property test harness for device protocols.
+//!
+//! [ProtocolFuzzer] creates each protocol against a test device, then sends it
+//! a seeded random sequence of valid VibrateCmd, LinearCmd and RotateCmd
+//! messages, weighted toward edge cases like zero speeds and duplicate
+//! subcommand indexes. A protocol fails if handling a command panics, never
+//! finishes, or writes to an endpoint its device configuration doesn't
+//! declare. Commands are free to return other errors.
+
+use super::test_device::{TestDeviceImplCreator, TestDeviceInternal};
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::{
+      ButtplugDeviceCommandMessageUnion,
+      ButtplugDeviceMessageType,
+      DeviceMessageAttributesMap,
+      LinearCmd,
+      RotateCmd,
+      RotationSubcommand,
+      VectorSubcommand,
+      VibrateCmd,
+      VibrateSubcommand,
+    },
+  },
+  device::{
+    configuration_manager::{BluetoothLESpecifier, DeviceConfigurationManager, DeviceSpecifier},
+    protocol::ButtplugProtocol,
+    ButtplugDeviceImplCreator,
+    DeviceImpl,
+  },
+  util::{device_configuration::create_test_dcm, stream::recv_now},
+};
+use futures::FutureExt;
+use futures_timer::Delay;
+use std::{
+  collections::HashMap,
+  fmt,
+  panic::{self, AssertUnwindSafe},
+  sync::Arc,
+  time::Duration,
+};
+
+/// Speeds and positions that protocols most often get wrong.
+const EDGE_VALUES: [f64; 4] = [0.0, 1.0, 0.0001, 0.9999];
+const FUZZED_MESSAGE_TYPES: [ButtplugDeviceMessageType; 3] = [
+  ButtplugDeviceMessageType::VibrateCmd,
+  ButtplugDeviceMessageType::LinearCmd,
+  ButtplugDeviceMessageType::RotateCmd,
+];
+
+/// Sets up a test device before its protocol is created, i.e. to add write
+/// responses for protocols that query the device during initialization.
+pub type TestDeviceSetupFn = fn(&TestDeviceInternal);
+
+type CreatedProtocol = (
+  Arc<DeviceImpl>,
+  Box<dyn ButtplugProtocol>,
+  Arc<TestDeviceInternal>,
+);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolFuzzOutcome {
+  /// Every command finished without panicking or writing to an undeclared
+  /// endpoint.
+  Passed { commands: u32 },
+  /// The protocol couldn't be tested, usually because it doesn't have a
+  /// Bluetooth LE configuration or waits for replies from the device during
+  /// initialization. See [ProtocolFuzzer::device_setup].
+  Skipped(String),
+  Failed {
+    command: ButtplugDeviceCommandMessageUnion,
+    reason: String,
+  },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtocolFuzzReport {
+  pub protocol: String,
+  /// Seed the command sequence was generated from, for reproducing failures.
+  pub seed: u64,
+  pub outcome: ProtocolFuzzOutcome,
+}
+
+impl ProtocolFuzzReport {
+  pub fn failed(&self) -> bool {
+    matches!(self.outcome, ProtocolFuzzOutcome::Failed { .. })
+  }
+}
+
+impl fmt::Display for ProtocolFuzzReport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.outcome {
+      ProtocolFuzzOutcome::Passed { commands } => {
+        write!(f, "{}: passed {} commands", self.protocol, commands)
+      }
+      ProtocolFuzzOutcome::Skipped(reason) => write!(f, "{}: skipped, {}", self.protocol, reason),
+      ProtocolFuzzOutcome::Failed { command, reason } => write!(
+        f,
+        "{}: failed with seed {} on {:?}, {}",
+        self.protocol, self.seed, command, reason
+      ),
+    }
+  }
+}
+
+/// Runs random command sequences through device protocols. See the [module
+/// documentation][self] for what is checked.
+pub struct ProtocolFuzzer {
+  device_config_mgr: Arc<DeviceConfigurationManager>,
+  seed: u64,
+  commands: u32,
+  command_timeout: Duration,
+  device_setup: HashMap<String, TestDeviceSetupFn>,
+}
+
+impl Default for ProtocolFuzzer {
+  fn default() -> Self {
+    Self {
+      device_config_mgr: Arc::new(create_test_dcm(false)),
+      seed: 0x5eed,
+      commands: 200,
+      command_timeout: Duration::from_secs(1),
+      device_setup: HashMap::new(),
+    }
+  }
+}
+
+impl ProtocolFuzzer {
+  /// Use a different device configuration, i.e. one with extra protocols
+  /// added. Defaults to the built in configuration.
+  pub fn device_configuration_manager(
+    mut self,
+    config_mgr: Arc<DeviceConfigurationManager>,
+  ) -> Self {
+    self.device_config_mgr = config_mgr;
+    self
+  }
+
+  pub fn seed(mut self, seed: u64) -> Self {
+    self.seed = seed;
+    self
+  }
+
+  /// Number of commands sent to each protocol. Defaults to 200.
+  pub fn commands(mut self, commands: u32) -> Self {
+    self.commands = commands;
+    self
+  }
+
+  /// How long a protocol gets to initialize or handle a single command before
+  /// it is considered hung. Defaults to 1 second.
+  pub fn command_timeout(mut self, timeout: Duration) -> Self {
+    self.command_timeout = timeout;
+    self
+  }
+
+  /// Runs `setup` on the test device for `protocol` before the protocol is
+  /// created.
+  pub fn device_setup(mut self, protocol: &str, setup: TestDeviceSetupFn) -> Self {
+    self.device_setup.insert(protocol.to_owned(), setup);
+    self
+  }
+
+  /// Fuzzes every protocol that has both a definition and an implementation
+  /// in the device configuration manager, sorted by protocol name.
+  pub async fn fuzz_all_protocols(&self) -> Vec<ProtocolFuzzReport> {
+    let mut protocols: Vec<String> = self
+      .device_config_mgr
+      .protocol_definitions()
+      .iter()
+      .map(|def| def.key().clone())
+      .filter(|protocol| self.device_config_mgr.has_protocol(protocol))
+      .collect();
+    protocols.sort();
+    let mut reports = vec![];
+    for protocol in protocols {
+      reports.push(self.fuzz_protocol(&protocol).await);
+    }
+    reports
+  }
+
+  pub async fn fuzz_protocol(&self, protocol: &str) -> ProtocolFuzzReport {
+    ProtocolFuzzReport {
+      protocol: protocol.to_owned(),
+      seed: self.seed,
+      outcome: self.run_protocol(protocol).await,
+    }
+  }
+
+  async fn run_protocol(&self, protocol_name: &str) -> ProtocolFuzzOutcome {
+    let (device_impl, protocol, test_device) = match self.create_protocol(protocol_name).await {
+      Ok(created) => created,
+      Err(reason) => return ProtocolFuzzOutcome::Skipped(reason),
+    };
+    let mut rng = FuzzRng::new(self.seed);
+    let attributes = protocol.message_attributes();
+    let message_types: Vec<ButtplugDeviceMessageType> = FUZZED_MESSAGE_TYPES
+      .iter()
+      .filter(|message_type| attributes.contains_key(message_type))
+      .copied()
+      .collect();
+    if message_types.is_empty() {
+      return ProtocolFuzzOutcome::Skipped(
+        "no VibrateCmd, LinearCmd or RotateCmd support".to_owned(),
+      );
+    }
+    for _ in 0..self.commands {
+      let message_type = message_types[rng.below(message_types.len() as u64) as usize];
+      let command = random_command(&mut rng, message_type, &attributes);
+      if let Err(reason) = self
+        .run_command(protocol.as_ref(), device_impl.clone(), command.clone())
+        .await
+      {
+        return ProtocolFuzzOutcome::Failed { command, reason };
+      }
+      // Keep the endpoint channels from filling up and blocking writes.
+      for endpoint in device_impl.endpoints() {
+        if let Some(receiver) = test_device.get_endpoint_receiver(&endpoint) {
+          let mut receiver = receiver
+            .lock()
+            .expect("We never panic while holding this lock.");
+          while let Some(Some(_)) = recv_now(&mut receiver) {}
+        }
+      }
+    }
+    ProtocolFuzzOutcome::Passed {
+      commands: self.commands,
+    }
+  }
+
+  async fn create_protocol(&self, protocol_name: &str) -> Result<CreatedProtocol, String> {
+    let definition = self
+      .device_config_mgr
+      .protocol_definitions()
+      .get(protocol_name)
+      .map(|def| def.value().clone())
+      .ok_or_else(|| "no protocol definition".to_owned())?;
+    let device_name = match &definition.btle {
+      Some(btle) => test_device_name(btle),
+      None => return Err("no Bluetooth LE configuration".to_owned()),
+    };
+    let config = self
+      .device_config_mgr
+      .get_protocol_config(protocol_name)
+      .ok_or_else(|| "no protocol configuration".to_owned())?;
+    let protocol_factory = self
+      .device_config_mgr
+      .get_protocol_creator(protocol_name)
+      .ok_or_else(|| "no protocol implementation".to_owned())?;
+    let test_device = Arc::new(TestDeviceInternal::new(
+      &device_name,
+      &format!("fuzz-{}", protocol_name),
+    ));
+    if let Some(setup) = self.device_setup.get(protocol_name) {
+      setup(&test_device);
+    }
+    let mut creator = TestDeviceImplCreator::new(
+      DeviceSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device(&device_name, &[])),
+      test_device.clone(),
+    );
+    let device_impl = Arc::new(
+      creator
+        .try_create_device_impl(definition)
+        .await
+        .map_err(|err| format!("device creation failed: {}", err))?,
+    );
+    select! {
+      protocol = protocol_factory.try_create(device_impl.clone(), config).fuse() => match protocol {
+        Ok(protocol) => Ok((device_impl, protocol, test_device)),
+        Err(err) => Err(format!("protocol initialization failed: {}", err)),
+      },
+      _ = Delay::new(self.command_timeout).fuse() => {
+        Err("protocol initialization timed out".to_owned())
+      }
+    }
+  }
+
+  async fn run_command(
+    &self,
+    protocol: &dyn ButtplugProtocol,
+    device_impl: Arc<DeviceImpl>,
+    command: ButtplugDeviceCommandMessageUnion,
+  ) -> Result<(), String> {
+    let fut = panic::catch_unwind(AssertUnwindSafe(|| {
+      protocol.handle_command(device_impl, command)
+    }))
+    .map_err(|_| "panicked while handling command".to_owned())?;
+    let mut fut = AssertUnwindSafe(fut).catch_unwind().fuse();
+    select! {
+      result = fut => match result {
+        Err(_) => Err("panicked while handling command".to_owned()),
+        Ok(Err(ButtplugError::ButtplugDeviceError(ButtplugDeviceError::InvalidEndpoint(
+          endpoint,
+        )))) => Err(format!("wrote to undeclared endpoint {}", endpoint)),
+        Ok(_) => Ok(()),
+      },
+      _ = Delay::new(self.command_timeout).fuse() => Err("command timed out".to_owned()),
+    }
+  }
+}
+
+/// Picks a name the protocol's Bluetooth LE specifier matches. Wildcard names
+/// get a suffix, so they look like a real advertised name.
+fn test_device_name(btle: &BluetoothLESpecifier) -> String {
+  let mut names: Vec<&String> = btle.names.iter().collect();
+  names.sort();
+  names
+    .iter()
+    .find(|name| !name.ends_with('*'))
+    .or_else(|| names.first())
+    .map_or_else(
+      || "Fuzz Test Device".to_owned(),
+      |name| name.replace('*', "Fuzz"),
+    )
+}
+
+fn random_command(
+  rng: &mut FuzzRng,
+  message_type: ButtplugDeviceMessageType,
+  attributes: &DeviceMessageAttributesMap,
+) -> ButtplugDeviceCommandMessageUnion {
+  let feature_count = attributes
+    .get(&message_type)
+    .and_then(|attrs| attrs.feature_count)
+    .unwrap_or(1)
+    .max(1);
+  // Allow one more subcommand than there are features, so some commands
+  // always carry duplicate indexes.
+  let subcommand_count = 1 + rng.below(u64::from(feature_count) + 1) as u32;
+  let indexes: Vec<u32> = (0..subcommand_count)
+    .map(|_| rng.below(u64::from(feature_count)) as u32)
+    .collect();
+  match message_type {
+    ButtplugDeviceMessageType::LinearCmd => LinearCmd::new(
+      0,
+      indexes
+        .into_iter()
+        .map(|index| VectorSubcommand::new(index, rng.duration(), rng.value()))
+        .collect(),
+    )
+    .into(),
+    ButtplugDeviceMessageType::RotateCmd => RotateCmd::new(
+      0,
+      indexes
+        .into_iter()
+        .map(|index| RotationSubcommand::new(index, rng.value(), rng.below(2) == 0))
+        .collect(),
+    )
+    .into(),
+    _ => VibrateCmd::new(
+      0,
+      indexes
+        .into_iter()
+        .map(|index| VibrateSubcommand::new(index, rng.value()))
+        .collect(),
+    )
+    .into(),
+  }
+}
+
+/// Small xorshift generator, so command sequences are reproducible from a
+/// seed without pulling in a random number crate.
+struct FuzzRng(u64);
+
+impl FuzzRng {
+  fn new(seed: u64) -> Self {
+    // Xorshift gets stuck at zero.
+    Self(seed.max(1))
+  }
+
+  fn next(&mut self) -> u64 {
+    self.0 ^= self.0 << 13;
+    self.0 ^= self.0 >> 7;
+    self.0 ^= self.0 << 17;
+    self.0
+  }
+
+  fn below(&mut self, bound: u64) -> u64 {
+    self.next() % bound
+  }
+
+  /// Speed or position in [0.0, 1.0], half the time an edge value.
+  fn value(&mut self) -> f64 {
+    if self.below(2) == 0 {
+      EDGE_VALUES[self.below(EDGE_VALUES.len() as u64) as usize]
+    } else {
+      self.below(10001) as f64 / 10000.0
+    }
+  }
+
+  /// Linear movement duration in milliseconds, sometimes zero.
+  fn duration(&mut self) -> u32 {
+    match self.below(4) {
+      0 => 0,
+      1 => 1,
+      _ => self.below(5000) as u32,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{device::Endpoint, util::async_manager};
+
+  #[test]
+  fn test_fuzz_all_protocols() {
+    async_manager::block_on(async {
+      let fuzzer = ProtocolFuzzer::default().device_setup("lovense", |device| {
+        device.add_write_response(
+          Endpoint::Tx,
+          b"DeviceType;",
+          Endpoint::Rx,
+          b"A:11:0082059AD3BD;",
+        )
+      });
+      let reports = fuzzer.fuzz_all_protocols().await;
+      assert!(reports
+        .iter()
+        .any(|report| matches!(report.outcome, ProtocolFuzzOutcome::Passed { .. })));
+      let failures: Vec<String> = reports
+        .iter()
+        .filter(|report| report.failed())
+        .map(|report| report.to_string())
+        .collect();
+      assert!(failures.is_empty(), "{:#?}", failures);
+    });
+  }
+
+  #[test]
+  fn test_fuzz_rng_is_reproducible() {
+    let mut first = FuzzRng::new(42);
+    let mut second = FuzzRng::new(42);
+    for _ in 0..100 {
+      assert_eq!(first.next(), second.next());
+    }
+  }
+}
@@ -1,6 +1,6 @@
 use crate::{
   core::{
-    errors::{ButtplugDeviceError, ButtplugError},
+    errors::{ButtplugDeviceConnectionError, ButtplugDeviceError, ButtplugError},
     messages::RawReading,
     ButtplugResultFuture,
   },
@@ -11,6 +11,7 @@ use crate::{
     DeviceImpl,
     DeviceImplCommand,
     DeviceImplInternal,
+    DeviceInspectionReport,
     DeviceReadCmd,
     DeviceSubscribeCmd,
     DeviceUnsubscribeCmd,
@@ -21,9 +22,12 @@ use crate::{
 use async_trait::async_trait;
 use dashmap::DashMap;
 use futures::future::{self, BoxFuture};
+use futures_timer::Delay;
 use std::{
+  collections::VecDeque,
   fmt::{self, Debug},
-  sync::Arc,
+  sync::{Arc, Mutex},
+  time::Duration,
 };
 use tokio::sync::{broadcast, mpsc};
 
@@ -64,6 +68,13 @@ impl ButtplugDeviceImplCreator for TestDeviceImplCreator {
     &mut self,
     protocol: ProtocolDefinition,
   ) -> Result<DeviceImpl, ButtplugError> {
+    if let Some(err) = self
+      .device_impl
+      .as_ref()
+      .and_then(|device| device.next_connection_failure())
+    {
+      return Err(err.into());
+    }
     let device = self
       .device_impl
       .take()
@@ -89,6 +100,16 @@ impl ButtplugDeviceImplCreator for TestDeviceImplCreator {
     );
     Ok(device_impl)
   }
+
+  async fn inspect(&mut self, connect: bool) -> Option<DeviceInspectionReport> {
+    let device = self.device_impl.as_ref()?;
+    let mut report = DeviceInspectionReport::new(&device.name(), &device.address());
+    // Test devices have no services until a protocol sets up their endpoints.
+    if connect {
+      report.services = Some(vec![]);
+    }
+    Some(report)
+  }
 }
 
 #[derive(Clone)]
@@ -110,11 +131,18 @@ impl TestDeviceEndpointChannel {
   }
 }
 
+// Maps a write (endpoint, data) to a notification (endpoint, data) the device
+// should send back, for protocols that need replies during init or commands.
+type TestDeviceWriteResponses = Arc<DashMap<(Endpoint, Vec<u8>), (Endpoint, Vec<u8>)>>;
+
 pub struct TestDeviceInternal {
   name: String,
   address: String,
   endpoint_channels: Arc<DashMap<Endpoint, TestDeviceEndpointChannel>>,
+  write_responses: TestDeviceWriteResponses,
   event_sender: broadcast::Sender<ButtplugDeviceEvent>,
+  connection_failures: Arc<Mutex<VecDeque<ButtplugDeviceConnectionError>>>,
+  write_delay: Arc<Mutex<Option<Duration>>>,
 }
 
 impl TestDeviceInternal {
@@ -124,7 +152,10 @@ impl TestDeviceInternal {
       name: name.to_owned(),
       address: address.to_owned(),
       endpoint_channels: Arc::new(DashMap::new()),
+      write_responses: Arc::new(DashMap::new()),
       event_sender,
+      connection_failures: Arc::new(Mutex::new(VecDeque::new())),
+      write_delay: Arc::new(Mutex::new(None)),
     }
   }
 
@@ -154,6 +185,41 @@ impl TestDeviceInternal {
       .map(|el| el.value().receiver.clone())
   }
 
+  /// Whenever `data` is written to `endpoint`, the device will send `response`
+  /// as a notification from `response_endpoint`.
+  pub fn add_write_response(
+    &self,
+    endpoint: Endpoint,
+    data: &[u8],
+    response_endpoint: Endpoint,
+    response: &[u8],
+  ) {
+    self.write_responses.insert(
+      (endpoint, data.to_vec()),
+      (response_endpoint, response.to_vec()),
+    );
+  }
+
+  /// Fails the next connection attempt with `error`. Each call fails one more
+  /// attempt.
+  pub fn add_connection_failure(&self, error: ButtplugDeviceConnectionError) {
+    self
+      .connection_failures
+      .lock()
+      .expect("Test")
+      .push_back(error);
+  }
+
+  /// Makes every write wait for `delay` before it reaches the endpoint
+  /// channel, like writes to a slow device would.
+  pub fn set_write_delay(&self, delay: Option<Duration>) {
+    *self.write_delay.lock().expect("Test") = delay;
+  }
+
+  fn next_connection_failure(&self) -> Option<ButtplugDeviceConnectionError> {
+    self.connection_failures.lock().expect("Test").pop_front()
+  }
+
   pub async fn add_endpoint(&self, endpoint: &Endpoint) {
     if !self.endpoint_channels.contains_key(endpoint) {
       let (sender, receiver) = mpsc::channel(256);
@@ -182,7 +248,9 @@ pub struct TestDevice {
   // for creation in ButtplugDevice, so initialization and cloning order
   // matters here.
   pub endpoint_channels: Arc<DashMap<Endpoint, TestDeviceEndpointChannel>>,
+  write_responses: TestDeviceWriteResponses,
   event_sender: broadcast::Sender<ButtplugDeviceEvent>,
+  write_delay: Arc<Mutex<Option<Duration>>>,
 }
 
 impl TestDevice {
@@ -191,7 +259,9 @@ impl TestDevice {
     Self {
       address: internal_device.address(),
       endpoint_channels: internal_device.endpoint_channels.clone(),
+      write_responses: internal_device.write_responses.clone(),
       event_sender: internal_device.sender(),
+      write_delay: internal_device.write_delay.clone(),
     }
   }
 }
@@ -209,9 +279,11 @@ impl DeviceImplInternal for TestDevice {
     let sender = self.event_sender.clone();
     let address = self.address.clone();
     Box::pin(async move {
-      sender
-        .send(ButtplugDeviceEvent::Removed(address))
-        .expect("Test");
+      // Devices that fail to initialize are disconnected before anything
+      // subscribes to their events.
+      if sender.send(ButtplugDeviceEvent::Removed(address)).is_err() {
+        debug!("No one listening for test device events, dropping removal.");
+      }
       Ok(())
     })
   }
@@ -225,12 +297,26 @@ impl DeviceImplInternal for TestDevice {
 
   fn write_value(&self, msg: DeviceWriteCmd) -> ButtplugResultFuture {
     let channels = self.endpoint_channels.clone();
+    let response = self
+      .write_responses
+      .get(&(msg.endpoint, msg.data.clone()))
+      .map(|el| el.value().clone());
+    let address = self.address.clone();
+    let event_sender = self.event_sender.clone();
+    let write_delay = *self.write_delay.lock().expect("Test");
     Box::pin(async move {
+      if let Some(delay) = write_delay {
+        Delay::new(delay).await;
+      }
       // Since we're only accessing a channel, we can use a read lock here.
       match channels.get(&msg.endpoint) {
         Some(device_channel) => {
           // We hold both ends, can unwrap.
           device_channel.sender.send(msg.into()).await.expect("Test");
+          if let Some((endpoint, data)) = response {
+            // Nothing may be listening for the response, which is fine.
+            let _ = event_sender.send(ButtplugDeviceEvent::Notification(address, endpoint, data));
+          }
           Ok(())
         }
         None => Err(ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into()),
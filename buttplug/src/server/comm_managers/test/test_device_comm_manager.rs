@@ -1,11 +1,15 @@
 use super::test_device::{TestDeviceImplCreator, TestDeviceInternal};
 use crate::{
-  core::{errors::ButtplugError, ButtplugResultFuture},
+  core::{
+    errors::{ButtplugDeviceConnectionError, ButtplugError},
+    ButtplugResultFuture,
+  },
   device::{
     configuration_manager::{BluetoothLESpecifier, DeviceConfigurationManager, DeviceSpecifier},
     ButtplugDevice,
   },
   server::comm_managers::{
+    CommManagerCapabilities,
     DeviceCommunicationEvent,
     DeviceCommunicationManager,
     DeviceCommunicationManagerBuilder,
@@ -42,15 +46,19 @@ fn new_uninitialized_ble_test_device(
   (device_impl_clone, device_impl_creator)
 }
 
-async fn new_bluetoothle_test_device_with_cfg(
+/// Like [new_bluetoothle_test_device_with_setup], but creates the device with
+/// `device_config_mgr` instead of the default device configuration, if given.
+pub async fn new_bluetoothle_test_device_with_cfg(
   name: &str,
   device_config_mgr: Option<Arc<DeviceConfigurationManager>>,
+  setup: impl FnOnce(&TestDeviceInternal),
 ) -> Result<(ButtplugDevice, Arc<TestDeviceInternal>), ButtplugError> {
   let config_mgr = device_config_mgr.unwrap_or_else(|| Arc::new(create_test_dcm(false)));
-  let (device_impl, device_impl_creator) = new_uninitialized_ble_test_device(name, None);
+  let (device_impl, mut device_impl_creator) = new_uninitialized_ble_test_device(name, None);
+  setup(&device_impl);
   let device_impl_clone = device_impl.clone();
   let device: ButtplugDevice =
-    ButtplugDevice::try_create_device(config_mgr, Box::new(device_impl_creator))
+    ButtplugDevice::try_create_device(config_mgr, &mut device_impl_creator, None)
       .await
       .expect("Empty option shouldn't be possible")
       .expect(&format!("No protocol found for device {}", name));
@@ -60,7 +68,18 @@ async fn new_bluetoothle_test_device_with_cfg(
 pub async fn new_bluetoothle_test_device(
   name: &str,
 ) -> Result<(ButtplugDevice, Arc<TestDeviceInternal>), ButtplugError> {
-  new_bluetoothle_test_device_with_cfg(name, None).await
+  new_bluetoothle_test_device_with_cfg(name, None, |_| {}).await
+}
+
+/// Like [new_bluetoothle_test_device], but lets the test set up the device
+/// (i.e. write responses for protocols that query the device during init)
+/// before the protocol is created.
+#[allow(dead_code)]
+pub async fn new_bluetoothle_test_device_with_setup(
+  name: &str,
+  setup: impl FnOnce(&TestDeviceInternal),
+) -> Result<(ButtplugDevice, Arc<TestDeviceInternal>), ButtplugError> {
+  new_bluetoothle_test_device_with_cfg(name, None, setup).await
 }
 
 pub struct TestDeviceCommunicationManagerHelper {
@@ -177,6 +196,85 @@ impl DeviceCommunicationManager for TestDeviceCommunicationManager {
   fn stop_scanning(&self) -> ButtplugResultFuture {
     Box::pin(future::ready(Ok(())))
   }
+
+  fn capabilities(&self) -> CommManagerCapabilities {
+    CommManagerCapabilities {
+      targeted_connect: true,
+      background_scanning: true,
+      ..Default::default()
+    }
+  }
+
+  fn set_background_scanning(&self, enabled: bool) -> ButtplugResultFuture {
+    let devices_vec = self.devices.clone();
+    let device_sender = self.device_sender.clone();
+    Box::pin(async move {
+      if !enabled {
+        return Ok(());
+      }
+      // Test devices are always in range, so a background scan finds all of
+      // them right away.
+      let mut devices = devices_vec.lock().await;
+      while let Some(d) = devices.pop() {
+        if device_sender
+          .send(DeviceCommunicationEvent::BackgroundDeviceFound {
+            name: d
+              .device()
+              .as_ref()
+              .map_or("Test device".to_owned(), |x| x.name()),
+            address: d
+              .device()
+              .as_ref()
+              .map_or("Test device address".to_owned(), |x| x.address()),
+            creator: Box::new(d),
+          })
+          .await
+          .is_err()
+        {
+          error!("Device channel no longer open.");
+        }
+      }
+      Ok(())
+    })
+  }
+
+  fn connect_device(&self, address: &str) -> ButtplugResultFuture {
+    let devices_vec = self.devices.clone();
+    let device_sender = self.device_sender.clone();
+    let address = address.to_owned();
+    Box::pin(async move {
+      let mut devices = devices_vec.lock().await;
+      let index = devices
+        .iter()
+        .position(|d| {
+          d.device()
+            .as_ref()
+            .map_or(false, |x| x.address() == address)
+        })
+        .ok_or_else(|| {
+          ButtplugDeviceConnectionError::NotFound(format!(
+            "No test device with address {}",
+            address
+          ))
+        })?;
+      let d = devices.remove(index);
+      if device_sender
+        .send(DeviceCommunicationEvent::ConnectIdentifiedDevice {
+          name: d
+            .device()
+            .as_ref()
+            .map_or("Test device".to_owned(), |x| x.name()),
+          address,
+          creator: Box::new(d),
+        })
+        .await
+        .is_err()
+      {
+        error!("Device channel no longer open.");
+      }
+      Ok(())
+    })
+  }
 }
 
 #[cfg(test)]
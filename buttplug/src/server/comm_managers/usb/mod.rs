@@ -0,0 +1,5 @@
+mod usb_comm_manager;
+mod usb_device_impl;
+
+pub use usb_comm_manager::{USBCommunicationManager, USBCommunicationManagerBuilder};
+pub use usb_device_impl::{USBDeviceImpl, USBDeviceImplCreator};
@@ -0,0 +1,120 @@
+use super::USBDeviceImplCreator;
+use crate::{
+  core::ButtplugResultFuture,
+  server::comm_managers::{
+    CommManagerCapabilities,
+    DeviceCommunicationEvent,
+    DeviceCommunicationManager,
+    DeviceCommunicationManagerBuilder,
+  },
+};
+use futures::future;
+use tokio::sync::mpsc::Sender;
+use tracing_futures::Instrument;
+
+#[derive(Default)]
+pub struct USBCommunicationManagerBuilder {
+  sender: Option<tokio::sync::mpsc::Sender<DeviceCommunicationEvent>>,
+}
+
+impl DeviceCommunicationManagerBuilder for USBCommunicationManagerBuilder {
+  fn event_sender(mut self, sender: Sender<DeviceCommunicationEvent>) -> Self {
+    self.sender = Some(sender);
+    self
+  }
+
+  fn finish(mut self) -> Box<dyn DeviceCommunicationManager> {
+    Box::new(USBCommunicationManager::new(
+      self
+        .sender
+        .take()
+        .expect("We'll always be able to take this"),
+    ))
+  }
+}
+
+pub struct USBCommunicationManager {
+  sender: Sender<DeviceCommunicationEvent>,
+}
+
+impl USBCommunicationManager {
+  fn new(sender: Sender<DeviceCommunicationEvent>) -> Self {
+    trace!("USB manager created.");
+    Self { sender }
+  }
+}
+
+impl DeviceCommunicationManager for USBCommunicationManager {
+  fn name(&self) -> &'static str {
+    "USBCommunicationManager"
+  }
+
+  fn start_scanning(&self) -> ButtplugResultFuture {
+    debug!("USB manager scanning for devices.");
+    // Enumeration only reads descriptors the OS already has cached, so it's
+    // quick enough to do here. Opening devices happens in the device impl.
+    let sender = self.sender.clone();
+    Box::pin(
+      async move {
+        match rusb::devices() {
+          Ok(devices) => {
+            debug!("Got {} USB devices back", devices.len());
+            for device in devices.iter() {
+              let descriptor = match device.device_descriptor() {
+                Ok(descriptor) => descriptor,
+                Err(e) => {
+                  debug!("Cannot read USB device descriptor, skipping: {}", e);
+                  continue;
+                }
+              };
+              let address = format!("usb-{}-{}", device.bus_number(), device.address());
+              trace!(
+                "Sending USB device {} for possible device connection.",
+                address
+              );
+              if sender
+                .send(DeviceCommunicationEvent::DeviceFound {
+                  name: format!(
+                    "USB Device {:04x}:{:04x}",
+                    descriptor.vendor_id(),
+                    descriptor.product_id()
+                  ),
+                  address: address.clone(),
+                  creator: Box::new(USBDeviceImplCreator::new(device, &descriptor, &address)),
+                })
+                .await
+                .is_err()
+              {
+                debug!("Device manager disappeared, exiting.");
+                break;
+              }
+            }
+          }
+          Err(e) => {
+            error!("Cannot enumerate USB devices: {}", e);
+          }
+        }
+        if sender
+          .send(DeviceCommunicationEvent::ScanningFinished)
+          .await
+          .is_err()
+        {
+          error!("Error sending scanning finished.");
+        }
+        Ok(())
+      }
+      .instrument(tracing::info_span!("USB Device Comm Manager Scanning.")),
+    )
+  }
+
+  fn stop_scanning(&self) -> ButtplugResultFuture {
+    Box::pin(future::ready(Ok(())))
+  }
+
+  fn capabilities(&self) -> CommManagerCapabilities {
+    CommManagerCapabilities {
+      exclusive_access: true,
+      ..Default::default()
+    }
+  }
+}
@@ -0,0 +1,391 @@
+use crate::{
+  core::{
+    errors::{ButtplugDeviceConnectionError, ButtplugDeviceError, ButtplugError},
+    messages::RawReading,
+    ButtplugResultFuture,
+  },
+  device::{
+    configuration_manager::{DeviceSpecifier, ProtocolDefinition, USBSpecifier},
+    ButtplugDeviceEvent,
+    ButtplugDeviceImplCreator,
+    DeviceImpl,
+    DeviceImplInternal,
+    DeviceReadCmd,
+    DeviceSubscribeCmd,
+    DeviceUnsubscribeCmd,
+    DeviceWriteCmd,
+    Endpoint,
+  },
+  server::comm_managers::ButtplugDeviceSpecificError,
+};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use futures::future::{self, BoxFuture};
+use rusb::{Device, DeviceDescriptor, DeviceHandle, GlobalContext, TransferType};
+use std::{
+  collections::HashMap,
+  fmt::{self, Debug},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  thread,
+  time::Duration,
+};
+use tokio::sync::{broadcast, oneshot};
+use tokio_util::sync::CancellationToken;
+
+// Timeout for writes, and for each read while subscribed, so subscription
+// threads get a chance to notice they've been cancelled.
+const USB_TRANSFER_TIMEOUT_MS: u64 = 100;
+// Big enough for a full high speed bulk packet.
+const USB_READ_BUFFER_SIZE: usize = 512;
+
+fn map_usb_error(e: impl ToString) -> ButtplugError {
+  ButtplugDeviceError::from(ButtplugDeviceSpecificError::USBError(e.to_string())).into()
+}
+
+// Like map_usb_error, but for opening and claiming the device, where the
+// device manager wants to know if it's worth trying again.
+fn map_usb_connection_error(e: rusb::Error) -> ButtplugError {
+  match e {
+    rusb::Error::NoDevice | rusb::Error::NotFound => {
+      ButtplugDeviceConnectionError::NotFound(e.to_string()).into()
+    }
+    rusb::Error::Access => ButtplugDeviceConnectionError::AuthFailed(e.to_string()).into(),
+    rusb::Error::Busy => ButtplugDeviceConnectionError::Busy(e.to_string()).into(),
+    rusb::Error::Timeout => ButtplugDeviceConnectionError::Timeout(e.to_string()).into(),
+    rusb::Error::NotSupported => ButtplugDeviceConnectionError::Unsupported(e.to_string()).into(),
+    e => map_usb_error(e),
+  }
+}
+
+// All rusb calls block, so run them on their own thread instead of stalling
+// the executor.
+async fn run_blocking<T, F>(f: F) -> T
+where
+  F: FnOnce() -> T + Send + 'static,
+  T: Send + 'static,
+{
+  let (sender, receiver) = oneshot::channel();
+  thread::Builder::new()
+    .name("USB Transfer Thread".to_string())
+    .spawn(move || {
+      if sender.send(f()).is_err() {
+        debug!("USB transfer finished after its device impl was dropped.");
+      }
+    })
+    .expect("Thread creation should always succeed.");
+  receiver.await.expect("Thread always sends before exiting.")
+}
+
+#[derive(Clone, Copy, Debug)]
+struct USBEndpoint {
+  address: u8,
+  transfer_type: TransferType,
+}
+
+fn usb_write(
+  handle: &DeviceHandle<GlobalContext>,
+  endpoint: USBEndpoint,
+  data: &[u8],
+) -> rusb::Result<usize> {
+  let timeout = Duration::from_millis(USB_TRANSFER_TIMEOUT_MS);
+  match endpoint.transfer_type {
+    TransferType::Interrupt => handle.write_interrupt(endpoint.address, data, timeout),
+    _ => handle.write_bulk(endpoint.address, data, timeout),
+  }
+}
+
+fn usb_read(
+  handle: &DeviceHandle<GlobalContext>,
+  endpoint: USBEndpoint,
+  buf: &mut [u8],
+  timeout: Duration,
+) -> rusb::Result<usize> {
+  match endpoint.transfer_type {
+    TransferType::Interrupt => handle.read_interrupt(endpoint.address, buf, timeout),
+    _ => handle.read_bulk(endpoint.address, buf, timeout),
+  }
+}
+
+pub struct USBDeviceImplCreator {
+  specifier: DeviceSpecifier,
+  device: Device<GlobalContext>,
+  name: String,
+  address: String,
+}
+
+impl USBDeviceImplCreator {
+  pub fn new(device: Device<GlobalContext>, descriptor: &DeviceDescriptor, address: &str) -> Self {
+    Self {
+      specifier: DeviceSpecifier::USB(USBSpecifier::new(
+        descriptor.vendor_id(),
+        descriptor.product_id(),
+      )),
+      device,
+      name: format!(
+        "USB Device {:04x}:{:04x}",
+        descriptor.vendor_id(),
+        descriptor.product_id()
+      ),
+      address: address.to_owned(),
+    }
+  }
+}
+
+impl Debug for USBDeviceImplCreator {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("USBDeviceImplCreator")
+      .field("specifier", &self.specifier)
+      .field("address", &self.address)
+      .finish()
+  }
+}
+
+#[async_trait]
+impl ButtplugDeviceImplCreator for USBDeviceImplCreator {
+  fn get_specifier(&self) -> DeviceSpecifier {
+    self.specifier.clone()
+  }
+
+  async fn try_create_device_impl(
+    &mut self,
+    protocol: ProtocolDefinition,
+  ) -> Result<DeviceImpl, ButtplugError> {
+    // If we've gotten this far, we can expect we have a USB definition.
+    let specifier = protocol
+      .usb
+      .expect("This will exist if we've made it here")
+      .into_iter()
+      .find(|usb| DeviceSpecifier::USB(usb.clone()) == self.specifier)
+      .expect("We had to match the device already to get here.");
+    let endpoints: Vec<Endpoint> = specifier.endpoints().keys().cloned().collect();
+    let device_impl_internal =
+      USBDeviceImpl::try_create(self.device.clone(), &self.address, specifier).await?;
+    let device_impl = DeviceImpl::new(
+      &self.name,
+      &self.address,
+      &endpoints,
+      Box::new(device_impl_internal),
+    );
+    Ok(device_impl)
+  }
+}
+
+pub struct USBDeviceImpl {
+  address: String,
+  handle: Arc<DeviceHandle<GlobalContext>>,
+  endpoints: HashMap<Endpoint, USBEndpoint>,
+  connected: Arc<AtomicBool>,
+  device_event_sender: broadcast::Sender<ButtplugDeviceEvent>,
+  subscriptions: DashMap<Endpoint, CancellationToken>,
+}
+
+impl USBDeviceImpl {
+  pub async fn try_create(
+    device: Device<GlobalContext>,
+    address: &str,
+    specifier: USBSpecifier,
+  ) -> Result<Self, ButtplugError> {
+    let (handle, endpoints) = run_blocking(move || {
+      let interface = specifier.interface();
+      let config = device.active_config_descriptor().map_err(map_usb_error)?;
+      // Look up how each configured endpoint transfers data, so we don't make
+      // users specify that in the device config too.
+      let mut endpoints = HashMap::new();
+      for (endpoint, usb_address) in specifier.endpoints() {
+        let transfer_type = config
+          .interfaces()
+          .filter(|iface| iface.number() == interface)
+          .flat_map(|iface| iface.descriptors())
+          .flat_map(|desc| desc.endpoint_descriptors())
+          .find(|desc| desc.address() == *usb_address)
+          .map(|desc| desc.transfer_type());
+        match transfer_type {
+          Some(transfer_type @ (TransferType::Bulk | TransferType::Interrupt)) => {
+            endpoints.insert(
+              *endpoint,
+              USBEndpoint {
+                address: *usb_address,
+                transfer_type,
+              },
+            );
+          }
+          Some(transfer_type) => {
+            return Err(
+              ButtplugDeviceConnectionError::Unsupported(format!(
+                "Endpoint {:#04x} uses {:?} transfers, only bulk and interrupt are supported",
+                usb_address, transfer_type
+              ))
+              .into(),
+            );
+          }
+          None => {
+            return Err(map_usb_error(format!(
+              "Interface {} has no endpoint {:#04x}",
+              interface, usb_address
+            )));
+          }
+        }
+      }
+      let mut handle = device.open().map_err(map_usb_connection_error)?;
+      // Not supported on every platform, in which case there's no kernel
+      // driver in the way anyways.
+      if let Err(e) = handle.set_auto_detach_kernel_driver(true) {
+        debug!("Cannot auto detach USB kernel driver: {}", e);
+      }
+      handle
+        .claim_interface(interface)
+        .map_err(map_usb_connection_error)?;
+      Ok((handle, endpoints))
+    })
+    .await?;
+    let (device_event_sender, _) = broadcast::channel(256);
+    Ok(Self {
+      address: address.to_owned(),
+      handle: Arc::new(handle),
+      endpoints,
+      connected: Arc::new(AtomicBool::new(true)),
+      device_event_sender,
+      subscriptions: DashMap::new(),
+    })
+  }
+
+  fn get_endpoint(&self, endpoint: Endpoint) -> Result<USBEndpoint, ButtplugError> {
+    self
+      .endpoints
+      .get(&endpoint)
+      .copied()
+      .ok_or_else(|| ButtplugDeviceError::InvalidEndpoint(endpoint).into())
+  }
+}
+
+impl DeviceImplInternal for USBDeviceImpl {
+  fn event_stream(&self) -> broadcast::Receiver<ButtplugDeviceEvent> {
+    self.device_event_sender.subscribe()
+  }
+
+  fn connected(&self) -> bool {
+    self.connected.load(Ordering::SeqCst)
+  }
+
+  fn disconnect(&self) -> ButtplugResultFuture {
+    self.connected.store(false, Ordering::SeqCst);
+    for subscription in self.subscriptions.iter() {
+      subscription.value().cancel();
+    }
+    self.subscriptions.clear();
+    Box::pin(future::ready(Ok(())))
+  }
+
+  fn read_value(
+    &self,
+    msg: DeviceReadCmd,
+  ) -> BoxFuture<'static, Result<RawReading, ButtplugError>> {
+    let endpoint = match self.get_endpoint(msg.endpoint) {
+      Ok(endpoint) => endpoint,
+      Err(e) => return Box::pin(future::ready(Err(e))),
+    };
+    let handle = self.handle.clone();
+    Box::pin(async move {
+      let data = run_blocking(move || {
+        let mut buf = vec![0u8; msg.length as usize];
+        let len = usb_read(
+          &handle,
+          endpoint,
+          &mut buf,
+          Duration::from_millis(msg.timeout_ms as u64),
+        )?;
+        buf.truncate(len);
+        Ok::<_, rusb::Error>(buf)
+      })
+      .await
+      .map_err(map_usb_error)?;
+      Ok(RawReading::new(0, msg.endpoint, data))
+    })
+  }
+
+  fn write_value(&self, msg: DeviceWriteCmd) -> ButtplugResultFuture {
+    let endpoint = match self.get_endpoint(msg.endpoint) {
+      Ok(endpoint) => endpoint,
+      Err(e) => return Box::pin(future::ready(Err(e))),
+    };
+    let handle = self.handle.clone();
+    Box::pin(async move {
+      run_blocking(move || usb_write(&handle, endpoint, &msg.data))
+        .await
+        .map_err(map_usb_error)?;
+      Ok(())
+    })
+  }
+
+  fn subscribe(&self, msg: DeviceSubscribeCmd) -> ButtplugResultFuture {
+    let endpoint = match self.get_endpoint(msg.endpoint) {
+      Ok(endpoint) => endpoint,
+      Err(e) => return Box::pin(future::ready(Err(e))),
+    };
+    if self.subscriptions.contains_key(&msg.endpoint) {
+      return Box::pin(future::ready(Ok(())));
+    }
+    let token = CancellationToken::new();
+    self.subscriptions.insert(msg.endpoint, token.clone());
+    let handle = self.handle.clone();
+    let address = self.address.clone();
+    let event_sender = self.device_event_sender.clone();
+    let connected = self.connected.clone();
+    thread::Builder::new()
+      .name("USB Reader Thread".to_string())
+      .spawn(move || {
+        let mut buf = [0u8; USB_READ_BUFFER_SIZE];
+        while !token.is_cancelled() {
+          match usb_read(
+            &handle,
+            endpoint,
+            &mut buf,
+            Duration::from_millis(USB_TRANSFER_TIMEOUT_MS),
+          ) {
+            Ok(len) => {
+              // Nothing may be listening yet, which is fine.
+              let _ = event_sender.send(ButtplugDeviceEvent::Notification(
+                address.clone(),
+                msg.endpoint,
+                buf[0..len].to_vec(),
+              ));
+            }
+            Err(rusb::Error::Timeout) => continue,
+            Err(rusb::Error::NoDevice) => {
+              info!("USB device {} unplugged, exiting read thread.", address);
+              connected.store(false, Ordering::SeqCst);
+              let _ = event_sender.send(ButtplugDeviceEvent::Removed(address.clone()));
+              break;
+            }
+            Err(e) => {
+              error!(
+                "Cannot read from USB device {}, exiting read thread: {}",
+                address, e
+              );
+              break;
+            }
+          }
+        }
+      })
+      .expect("Thread creation should always succeed.");
+    Box::pin(future::ready(Ok(())))
+  }
+
+  fn unsubscribe(&self, msg: DeviceUnsubscribeCmd) -> ButtplugResultFuture {
+    if let Some((_, token)) = self.subscriptions.remove(&msg.endpoint) {
+      token.cancel();
+    }
+    Box::pin(future::ready(Ok(())))
+  }
+}
+
+impl Drop for USBDeviceImpl {
+  fn drop(&mut self) {
+    for subscription in self.subscriptions.iter() {
+      subscription.value().cancel();
+    }
+  }
+}
@@ -2,6 +2,7 @@ use super::websocket_server_device_impl::WebsocketServerDeviceImplCreator;
 use crate::{
   core::ButtplugResultFuture,
   server::comm_managers::{
+    CommManagerCapabilities,
     DeviceCommunicationEvent,
     DeviceCommunicationManager,
     DeviceCommunicationManagerBuilder,
@@ -183,6 +184,13 @@ impl DeviceCommunicationManager for WebsocketServerDeviceCommunicationManager {
   fn stop_scanning(&self) -> ButtplugResultFuture {
     Box::pin(async move { Ok(()) })
   }
+
+  fn capabilities(&self) -> CommManagerCapabilities {
+    CommManagerCapabilities {
+      hotplug: true,
+      ..Default::default()
+    }
+  }
 }
 
 impl Drop for WebsocketServerDeviceCommunicationManager {
@@ -3,6 +3,7 @@ use crate::{
   core::ButtplugResultFuture,
   device::ButtplugDeviceEvent,
   server::comm_managers::{
+    CommManagerCapabilities,
     DeviceCommunicationEvent,
     DeviceCommunicationManager,
     DeviceCommunicationManagerBuilder,
@@ -238,4 +239,11 @@ impl DeviceCommunicationManager for XInputDeviceCommunicationManager {
       Ok(())
     })
   }
+
+  fn capabilities(&self) -> CommManagerCapabilities {
+    CommManagerCapabilities {
+      hotplug: true,
+      ..Default::default()
+    }
+  }
 }
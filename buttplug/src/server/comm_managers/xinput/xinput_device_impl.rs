@@ -22,17 +22,55 @@ use crate::{
     Endpoint,
   },
   server::comm_managers::ButtplugDeviceSpecificError,
+  util::async_manager,
 };
 use async_trait::async_trait;
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use futures::future::{self, BoxFuture};
-use rusty_xinput::{XInputHandle, XInputUsageError};
+use futures_timer::Delay;
+use rusty_xinput::{XInputHandle, XInputState, XInputUsageError};
 use std::{
   fmt::{self, Debug},
   io::Cursor,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  time::Duration,
 };
 use tokio::sync::broadcast;
 
+// Polling at 50hz is plenty for using triggers/sticks as control knobs.
+const INPUT_POLL_INTERVAL_MS: u64 = 20;
+
+// Packs gamepad input in XINPUT_GAMEPAD layout (buttons, left/right trigger,
+// left/right thumbstick x/y), little endian, 12 bytes total. This is what gets
+// sent out on the Rx endpoint.
+fn pack_input_state(state: &XInputState) -> Vec<u8> {
+  let gamepad = &state.raw.Gamepad;
+  let mut data = vec![];
+  data
+    .write_u16::<LittleEndian>(gamepad.wButtons)
+    .expect("Writing to a vec, infallible");
+  data.push(gamepad.bLeftTrigger);
+  data.push(gamepad.bRightTrigger);
+  for axis in [
+    gamepad.sThumbLX,
+    gamepad.sThumbLY,
+    gamepad.sThumbRX,
+    gamepad.sThumbRY,
+  ] {
+    data
+      .write_i16::<LittleEndian>(axis)
+      .expect("Writing to a vec, infallible");
+  }
+  data
+}
+
+fn map_xinput_error(e: XInputUsageError) -> ButtplugError {
+  ButtplugDeviceError::from(ButtplugDeviceSpecificError::XInputError(format!("{:?}", e))).into()
+}
+
 pub struct XInputDeviceImplCreator {
   index: XInputControllerIndex,
 }
@@ -65,10 +103,13 @@ impl ButtplugDeviceImplCreator for XInputDeviceImplCreator {
   ) -> Result<DeviceImpl, ButtplugError> {
     debug!("Emitting a new xbox device impl.");
     let device_impl_internal = XInputDeviceImpl::new(self.index);
+    // XInput only exposes the two main rumble motors, so we never offer
+    // TxVibrate for impulse triggers here. A backend that can drive trigger
+    // motors just needs to add that endpoint for the protocol to use them.
     let device_impl = DeviceImpl::new(
       &self.index.to_string(),
       &create_address(self.index),
-      &[Endpoint::Tx],
+      &[Endpoint::Tx, Endpoint::Rx],
       Box::new(device_impl_internal),
     );
     Ok(device_impl)
@@ -81,6 +122,7 @@ pub struct XInputDeviceImpl {
   index: XInputControllerIndex,
   event_sender: broadcast::Sender<ButtplugDeviceEvent>,
   connection_tracker: XInputConnectionTracker,
+  input_subscribed: Arc<AtomicBool>,
 }
 
 impl XInputDeviceImpl {
@@ -93,6 +135,7 @@ impl XInputDeviceImpl {
       index,
       event_sender: device_event_sender,
       connection_tracker,
+      input_subscribed: Arc::new(AtomicBool::new(false)),
     }
   }
 }
@@ -112,12 +155,27 @@ impl DeviceImplInternal for XInputDeviceImpl {
 
   fn read_value(
     &self,
-    _msg: DeviceReadCmd,
+    msg: DeviceReadCmd,
   ) -> BoxFuture<'static, Result<RawReading, ButtplugError>> {
-    panic!("We should never get here!");
+    if msg.endpoint != Endpoint::Rx {
+      return Box::pin(future::ready(Err(
+        ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into(),
+      )));
+    }
+    let handle = self.handle.clone();
+    let index = self.index;
+    Box::pin(async move {
+      let state = handle.get_state(index as u32).map_err(map_xinput_error)?;
+      Ok(RawReading::new(0, Endpoint::Rx, pack_input_state(&state)))
+    })
   }
 
   fn write_value(&self, msg: DeviceWriteCmd) -> ButtplugResultFuture {
+    if msg.endpoint != Endpoint::Tx {
+      return Box::pin(future::ready(Err(
+        ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into(),
+      )));
+    }
     let handle = self.handle.clone();
     let index = self.index;
     Box::pin(async move {
@@ -130,18 +188,60 @@ impl DeviceImplInternal for XInputDeviceImpl {
         .expect("Packed in protocol, infallible");
       handle
         .set_state(index as u32, left_motor_speed, right_motor_speed)
-        .map_err(|e: XInputUsageError| {
-          ButtplugDeviceError::from(ButtplugDeviceSpecificError::XInputError(format!("{:?}", e)))
-            .into()
-        })
+        .map_err(map_xinput_error)
     })
   }
 
-  fn subscribe(&self, _msg: DeviceSubscribeCmd) -> ButtplugResultFuture {
-    panic!("We should never get here!");
+  fn subscribe(&self, msg: DeviceSubscribeCmd) -> ButtplugResultFuture {
+    if msg.endpoint != Endpoint::Rx {
+      return Box::pin(future::ready(Err(
+        ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into(),
+      )));
+    }
+    if self.input_subscribed.swap(true, Ordering::SeqCst) {
+      return Box::pin(future::ready(Ok(())));
+    }
+    // XInput has no input events, so poll the gamepad while subscribed and
+    // emit a notification whenever its state changes.
+    let handle = self.handle.clone();
+    let index = self.index;
+    let event_sender = self.event_sender.clone();
+    let input_subscribed = self.input_subscribed.clone();
+    let connection_tracker = self.connection_tracker.clone();
+    async_manager::spawn(async move {
+      let mut last_packet = None;
+      while input_subscribed.load(Ordering::SeqCst) && connection_tracker.connected(index) {
+        match handle.get_state(index as u32) {
+          Ok(state) => {
+            if last_packet != Some(state.raw.dwPacketNumber) {
+              last_packet = Some(state.raw.dwPacketNumber);
+              // Nothing may be listening yet, which is fine.
+              let _ = event_sender.send(ButtplugDeviceEvent::Notification(
+                create_address(index),
+                Endpoint::Rx,
+                pack_input_state(&state),
+              ));
+            }
+          }
+          Err(e) => {
+            info!("XInput gamepad {} input polling stopped: {:?}", index, e);
+            input_subscribed.store(false, Ordering::SeqCst);
+            break;
+          }
+        }
+        Delay::new(Duration::from_millis(INPUT_POLL_INTERVAL_MS)).await;
+      }
+    });
+    Box::pin(future::ready(Ok(())))
   }
 
-  fn unsubscribe(&self, _msg: DeviceUnsubscribeCmd) -> ButtplugResultFuture {
-    panic!("We should never get here!");
+  fn unsubscribe(&self, msg: DeviceUnsubscribeCmd) -> ButtplugResultFuture {
+    if msg.endpoint != Endpoint::Rx {
+      return Box::pin(future::ready(Err(
+        ButtplugDeviceError::InvalidEndpoint(msg.endpoint).into(),
+      )));
+    }
+    self.input_subscribed.store(false, Ordering::SeqCst);
+    Box::pin(future::ready(Ok(())))
   }
 }
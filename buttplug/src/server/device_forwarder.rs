@@ -0,0 +1,360 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Shares devices connected to this server with another server.
+//!
+//! A [DeviceForwarder] sits on the server the devices are connected to, and
+//! shares them over a [ForwardingLink] with a server running a
+//! [ForwardedDeviceCommunicationManager][super::comm_managers::forwarded_device::forwarded_device_comm_manager::ForwardedDeviceCommunicationManager].
+//! Nothing is shared until it's allowed: only devices whose addresses are on
+//! the forwarder's allow list are announced, and requests for anything else
+//! are refused. Taking a device off the allow list removes it from the other
+//! server right away. While this server is emergency stopped, writes from the
+//! other server are refused too.
+
+use super::{
+  comm_managers::forwarded_device::{
+    ForwardedDeviceCommand,
+    ForwardedDeviceInfo,
+    ForwardingLink,
+    ForwardingMessage,
+  },
+  ButtplugServer,
+};
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::ButtplugServerMessage,
+  },
+  device::{
+    ButtplugDevice,
+    ButtplugDeviceEvent,
+    DeviceImpl,
+    DeviceReadCmd,
+    DeviceSubscribeCmd,
+    DeviceUnsubscribeCmd,
+    DeviceWriteCmd,
+    Endpoint,
+  },
+  util::async_manager,
+};
+use dashmap::{DashMap, DashSet};
+use futures::{FutureExt, StreamExt};
+use std::sync::{
+  atomic::{AtomicBool, Ordering},
+  Arc,
+  Mutex,
+};
+use tokio::sync::{broadcast, mpsc::UnboundedSender};
+use tokio_util::sync::CancellationToken;
+
+/// Device currently announced to the other server.
+struct SharedDevice {
+  device: Arc<DeviceImpl>,
+  endpoints: Vec<Endpoint>,
+  /// Stops forwarding the device's notifications.
+  token: CancellationToken,
+}
+
+struct ForwarderState {
+  link_sender: UnboundedSender<ForwardingMessage>,
+  /// The server's connected devices.
+  devices: Arc<DashMap<u32, Arc<ButtplugDevice>>>,
+  emergency_stopped: Arc<AtomicBool>,
+  allowed: DashSet<String>,
+  shared: DashMap<String, SharedDevice>,
+  /// Held while bringing `shared` in line with `allowed` and `devices`, so
+  /// devices aren't announced twice.
+  sync_lock: Mutex<()>,
+}
+
+impl ForwarderState {
+  /// Announces connected devices that are allowed but not shared yet, and
+  /// removes shared devices that have disconnected or aren't allowed anymore.
+  fn sync_shared_devices(&self) {
+    let _guard = self
+      .sync_lock
+      .lock()
+      .expect("We never panic while holding this lock.");
+    let mut connected = Vec::new();
+    for device in self.devices.iter() {
+      let address = device.value().address().to_owned();
+      if self.allowed.contains(&address) && !self.shared.contains_key(&address) {
+        self.share_device(device.value());
+      }
+      connected.push(address);
+    }
+    let stale: Vec<String> = self
+      .shared
+      .iter()
+      .map(|shared| shared.key().clone())
+      .filter(|address| !self.allowed.contains(address) || !connected.contains(address))
+      .collect();
+    for address in stale {
+      if let Some((_, shared)) = self.shared.remove(&address) {
+        info!("Stopped forwarding device {}.", address);
+        shared.token.cancel();
+        let _ = self
+          .link_sender
+          .send(ForwardingMessage::DeviceRemoved { address });
+      }
+    }
+  }
+
+  fn share_device(&self, device: &ButtplugDevice) {
+    let device_impl = device.device_impl();
+    let address = device.address().to_owned();
+    let info = ForwardedDeviceInfo {
+      name: device_impl.name().to_owned(),
+      address: address.clone(),
+      protocol: device.protocol_name().to_owned(),
+      endpoints: device_impl.endpoints(),
+    };
+    info!("Forwarding device {} ({}).", info.name, address);
+    if self
+      .link_sender
+      .send(ForwardingMessage::DeviceAdded(info))
+      .is_err()
+    {
+      // The link is gone, the forwarder task will stop on its own.
+      return;
+    }
+    let token = CancellationToken::new();
+    forward_notifications(
+      &address,
+      device_impl.event_stream(),
+      self.link_sender.clone(),
+      token.child_token(),
+    );
+    self.shared.insert(
+      address,
+      SharedDevice {
+        endpoints: device_impl.endpoints(),
+        device: device_impl,
+        token,
+      },
+    );
+  }
+
+  /// Runs a command from the other server, replying over the link once it's
+  /// done.
+  fn handle_request(&self, id: u32, address: String, command: ForwardedDeviceCommand) {
+    let shared = self
+      .shared
+      .get(&address)
+      .map(|shared| (shared.device.clone(), shared.endpoints.clone()));
+    let emergency_stopped = self.emergency_stopped.clone();
+    let link_sender = self.link_sender.clone();
+    async_manager::spawn(async move {
+      let result = match shared {
+        Some((device, endpoints)) => {
+          run_command(device, &endpoints, command, &emergency_stopped).await
+        }
+        None => Err(
+          ButtplugDeviceError::DevicePermissionError(format!(
+            "Device {} is not forwarded.",
+            address
+          ))
+          .into(),
+        ),
+      };
+      if let Err(err) = &result {
+        debug!("Forwarded command for device {} failed: {}", address, err);
+      }
+      let _ = link_sender.send(ForwardingMessage::Reply {
+        id,
+        result: result.map_err(|err| err.to_string()),
+      });
+    });
+  }
+}
+
+async fn run_command(
+  device: Arc<DeviceImpl>,
+  endpoints: &[Endpoint],
+  command: ForwardedDeviceCommand,
+  emergency_stopped: &AtomicBool,
+) -> Result<Vec<u8>, ButtplugError> {
+  let endpoint = match &command {
+    ForwardedDeviceCommand::Write { endpoint, .. }
+    | ForwardedDeviceCommand::Read { endpoint, .. }
+    | ForwardedDeviceCommand::Subscribe { endpoint }
+    | ForwardedDeviceCommand::Unsubscribe { endpoint } => *endpoint,
+  };
+  if !endpoints.contains(&endpoint) {
+    return Err(ButtplugDeviceError::InvalidEndpoint(endpoint).into());
+  }
+  match command {
+    ForwardedDeviceCommand::Write {
+      endpoint,
+      data,
+      write_with_response,
+      raw,
+      chunked,
+    } => {
+      if emergency_stopped.load(Ordering::SeqCst) {
+        return Err(ButtplugDeviceError::DevicesEmergencyStopped.into());
+      }
+      let mut write = DeviceWriteCmd::new(endpoint, data, write_with_response);
+      write.raw = raw;
+      write.chunked = chunked;
+      device.write_value(write).await.map(|_| vec![])
+    }
+    ForwardedDeviceCommand::Read {
+      endpoint,
+      length,
+      timeout_ms,
+    } => device
+      .read_value(DeviceReadCmd::new(endpoint, length, timeout_ms))
+      .await
+      .map(|reading| reading.data().clone()),
+    ForwardedDeviceCommand::Subscribe { endpoint } => device
+      .subscribe(DeviceSubscribeCmd::new(endpoint))
+      .await
+      .map(|_| vec![]),
+    ForwardedDeviceCommand::Unsubscribe { endpoint } => device
+      .unsubscribe(DeviceUnsubscribeCmd::new(endpoint))
+      .await
+      .map(|_| vec![]),
+  }
+}
+
+/// Passes notifications from a shared device to the other server until
+/// `token` is cancelled or the device goes away.
+fn forward_notifications(
+  address: &str,
+  mut event_receiver: broadcast::Receiver<ButtplugDeviceEvent>,
+  link_sender: UnboundedSender<ForwardingMessage>,
+  token: CancellationToken,
+) {
+  let address = address.to_owned();
+  async_manager::spawn(async move {
+    loop {
+      let event = select! {
+        event = event_receiver.recv().fuse() => event,
+        _ = token.cancelled().fuse() => break,
+      };
+      match event {
+        Ok(ButtplugDeviceEvent::Notification(event_address, endpoint, data))
+          if event_address == address =>
+        {
+          if link_sender
+            .send(ForwardingMessage::Notification {
+              address: address.clone(),
+              endpoint,
+              data,
+            })
+            .is_err()
+          {
+            break;
+          }
+        }
+        Ok(ButtplugDeviceEvent::Removed(event_address)) if event_address == address => break,
+        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+        Err(broadcast::error::RecvError::Closed) => break,
+      }
+    }
+  });
+}
+
+/// Shares allowed devices from a server over a [ForwardingLink]. See the
+/// [module documentation][self].
+///
+/// Forwarding stops when the forwarder is dropped or the link closes.
+pub struct DeviceForwarder {
+  state: Arc<ForwarderState>,
+  cancellation_token: CancellationToken,
+}
+
+impl DeviceForwarder {
+  /// Starts forwarding devices from `server` over `link`. Must be called from
+  /// within the library's runtime. The allow list starts out empty.
+  pub fn new(server: &ButtplugServer, link: ForwardingLink) -> Self {
+    let ForwardingLink {
+      sender: link_sender,
+      receiver: mut link_receiver,
+    } = link;
+    let state = Arc::new(ForwarderState {
+      link_sender,
+      devices: server.device_manager().device_map(),
+      emergency_stopped: server.device_manager().emergency_stop_flag(),
+      allowed: DashSet::new(),
+      shared: DashMap::new(),
+      sync_lock: Mutex::new(()),
+    });
+    let cancellation_token = CancellationToken::new();
+    let token = cancellation_token.child_token();
+    let task_state = state.clone();
+    let mut server_events = Box::pin(server.event_stream());
+    async_manager::spawn(async move {
+      loop {
+        select! {
+          msg = link_receiver.recv().fuse() => match msg {
+            Some(ForwardingMessage::Request { id, address, command }) => {
+              task_state.handle_request(id, address, command);
+            }
+            Some(msg) => {
+              warn!("Forwarder got {:?}, only requests are expected. Ignoring.", msg);
+            }
+            None => {
+              info!("Forwarding link closed, stopping forwarder.");
+              break;
+            }
+          },
+          event = server_events.next().fuse() => match event {
+            Some(ButtplugServerMessage::DeviceAdded(_))
+            | Some(ButtplugServerMessage::DeviceRemoved(_)) => task_state.sync_shared_devices(),
+            Some(_) => {}
+            None => break,
+          },
+          _ = token.cancelled().fuse() => break,
+        }
+      }
+      for shared in task_state.shared.iter() {
+        shared.value().token.cancel();
+      }
+    });
+    Self {
+      state,
+      cancellation_token,
+    }
+  }
+
+  /// Adds a device address to the allow list. If the device is connected, it's
+  /// shared right away, otherwise it's shared once it connects.
+  pub fn allow_device(&self, address: &str) {
+    self.state.allowed.insert(address.to_owned());
+    self.state.sync_shared_devices();
+  }
+
+  /// Takes a device address off the allow list, removing the device from the
+  /// other server if it was shared.
+  pub fn disallow_device(&self, address: &str) {
+    self.state.allowed.remove(address);
+    self.state.sync_shared_devices();
+  }
+
+  /// Addresses on the allow list, sorted.
+  pub fn allowed_devices(&self) -> Vec<String> {
+    let mut allowed: Vec<String> = self.state.allowed.iter().map(|x| x.key().clone()).collect();
+    allowed.sort();
+    allowed
+  }
+
+  /// Addresses of devices currently shared with the other server, sorted.
+  pub fn shared_devices(&self) -> Vec<String> {
+    let mut shared: Vec<String> = self.state.shared.iter().map(|x| x.key().clone()).collect();
+    shared.sort();
+    shared
+  }
+}
+
+impl Drop for DeviceForwarder {
+  fn drop(&mut self) {
+    self.cancellation_token.cancel();
+  }
+}
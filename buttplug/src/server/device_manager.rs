@@ -10,46 +10,181 @@
 
 use super::{
   comm_managers::{
+    CommManagerCapabilities,
     DeviceCommunicationEvent,
     DeviceCommunicationManager,
     DeviceCommunicationManagerBuilder,
   },
-  device_manager_event_loop::DeviceManagerEventLoop,
+  device_manager_event_loop::{DeviceManagerEventLoop, DeviceManagerEventLoopOptions},
+  device_watchdog::DeviceWatchdogs,
   ping_timer::PingTimer,
   ButtplugServerError,
 };
 use crate::{
   core::{
-    errors::{ButtplugDeviceError, ButtplugMessageError, ButtplugUnknownError},
+    errors::{ButtplugDeviceError, ButtplugError, ButtplugMessageError, ButtplugUnknownError},
     messages::{
       self,
       ButtplugClientMessage,
       ButtplugDeviceCommandMessageUnion,
       ButtplugDeviceManagerMessageUnion,
       ButtplugDeviceMessage,
+      ButtplugDeviceMessageType,
       ButtplugMessage,
       ButtplugServerMessage,
       DeviceList,
+      DeviceListChanges,
       DeviceMessageInfo,
+      DeviceRemovedReason,
+      StopBehavior,
     },
+    ButtplugResultFuture,
   },
   device::{
     configuration_manager::{DeviceConfigurationManager, ProtocolDefinition},
-    protocol::ButtplugProtocol,
+    protocol::{linear_motion::LinearEasing, ButtplugProtocol, ButtplugProtocolFactory},
     ButtplugDevice,
+    ButtplugDeviceImplCreator,
+    DeviceCommandQueueOptions,
+    DeviceInspectionReport,
+    DeviceTrafficRecord,
+    Endpoint,
+  },
+  server::{ButtplugServerResult, ButtplugServerResultFuture},
+  util::{
+    async_manager,
+    device_configuration::UserDeviceConfigFile,
+    message_tracing::message_span,
+    stream::convert_broadcast_receiver_to_stream,
   },
-  server::ButtplugServerResultFuture,
-  util::async_manager,
 };
-use dashmap::DashMap;
-use futures::future;
+use dashmap::{DashMap, DashSet};
+use futures::{future, FutureExt, Stream};
+use futures_timer::Delay;
 use getset::{Getters, Setters};
 use serde::{Deserialize, Serialize};
+use static_assertions::assert_impl_all;
 use std::{
+  collections::{HashSet, VecDeque},
   convert::TryFrom,
-  sync::{atomic::Ordering, Arc},
+  path::Path,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+    Mutex,
+  },
+  time::Duration,
 };
 use tokio::sync::{broadcast, mpsc};
+use tracing::{info_span, Instrument};
+
+/// How long an emergency stop waits on each device before giving up on it,
+/// in milliseconds.
+const EMERGENCY_STOP_TIMEOUT: u64 = 1000;
+
+/// How many device list changes are kept for RequestDeviceListChanges. Clients
+/// further behind than this are sent the whole list.
+const DEVICE_LIST_HISTORY_LENGTH: usize = 1024;
+
+fn device_message_info(index: u32, device: &ButtplugDevice) -> DeviceMessageInfo {
+  let mut info = DeviceMessageInfo::new(index, &device.name(), device.shared_message_attributes());
+  info.device_address = Some(device.address().to_owned());
+  info
+}
+
+// A stop that couldn't be confirmed means a device may still be running, so
+// this gets sent out as an Error event to everyone listening to the server,
+// not just returned to whoever asked for the stop.
+fn escalate_unconfirmed_stop(
+  output_sender: &broadcast::Sender<ButtplugServerMessage>,
+  device_index: u32,
+  result: &ButtplugServerResult,
+) {
+  if let Err(
+    original_error @ ButtplugError::ButtplugDeviceError(
+      err @ ButtplugDeviceError::DeviceWriteNotConfirmed(..),
+    ),
+  ) = result
+  {
+    error!(
+      "Stop for device {} was not confirmed: {}",
+      device_index, err
+    );
+    let mut error_msg = messages::Error::new(
+      messages::ErrorCode::ErrorDevice,
+      &format!("Device {} stop was not confirmed: {}", device_index, err),
+      Some(original_error.clone()),
+    );
+    error_msg.set_device_index(device_index);
+    if output_sender.receiver_count() > 0 && output_sender.send(error_msg.into()).is_err() {
+      debug!("Server not currently available, dropping unconfirmed stop error event.");
+    }
+  }
+}
+
+// If a comm manager couldn't scan because there's no Bluetooth adapter (or
+// it's turned off), let everyone listening to the server know, so GUIs can
+// tell users to turn Bluetooth on.
+fn report_missing_bluetooth_adapter(
+  output_sender: &broadcast::Sender<ButtplugServerMessage>,
+  comm_manager: &str,
+  error: &ButtplugError,
+) {
+  if !matches!(
+    error,
+    ButtplugError::ButtplugDeviceError(ButtplugDeviceError::NoBluetoothAdapter)
+  ) {
+    return;
+  }
+  let error_msg = messages::Error::new(
+    messages::ErrorCode::ErrorDevice,
+    &format!("{}: {}", comm_manager, error),
+    Some(error.clone()),
+  );
+  if output_sender.receiver_count() > 0 && output_sender.send(error_msg.into()).is_err() {
+    debug!("Server not currently available, dropping missing bluetooth adapter event.");
+  }
+}
+
+// Logs any comm managers that failed to start or stop scanning. If every comm
+// manager failed, the scanning request fails with all of their errors. If only
+// some failed, the request succeeds, and the failures are sent out as a
+// ScanningPartialFailure event.
+fn check_scanning_results(
+  action: &str,
+  results: Vec<(String, Result<(), ButtplugError>)>,
+  output_sender: &broadcast::Sender<ButtplugServerMessage>,
+) -> Result<(), ButtplugError> {
+  let total = results.len();
+  let failures: Vec<_> = results
+    .into_iter()
+    .filter_map(|(name, result)| {
+      result.err().map(|err| {
+        error!("Device manager {} failed to {} scanning: {}", name, action, err);
+        report_missing_bluetooth_adapter(output_sender, &name, &err);
+        messages::CommManagerScanningFailure::new(&name, &err.to_string())
+      })
+    })
+    .collect();
+  if failures.is_empty() {
+    Ok(())
+  } else if failures.len() == total {
+    let details = failures
+      .iter()
+      .map(|failure| format!("{}: {}", failure.comm_manager, failure.error))
+      .collect::<Vec<_>>()
+      .join(", ");
+    Err(ButtplugDeviceError::DeviceScanningFailed(details).into())
+  } else {
+    if output_sender
+      .send(messages::ScanningPartialFailure::new(failures).into())
+      .is_err()
+    {
+      debug!("Server not currently available, dropping scanning partial failure event.");
+    }
+    Ok(())
+  }
+}
 
 #[derive(Serialize, Deserialize, Debug, Getters, Setters, Default, Clone, PartialEq)]
 #[getset(get = "pub", set = "pub")]
@@ -64,6 +199,60 @@ pub struct DeviceUserConfig {
   #[serde(skip_serializing_if = "Option::is_none")]
   #[serde(default)]
   deny: Option<bool>,
+  /// Flip the direction of rotation commands, for devices that are held or
+  /// mounted the other way around.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(default)]
+  #[serde(rename = "reverse-rotation")]
+  reverse_rotation: Option<bool>,
+  /// Overrides the server's allow_raw_messages setting for this device, i.e.
+  /// to only allow raw access to a DIY device.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(default)]
+  #[serde(rename = "allow-raw-messages")]
+  allow_raw_messages: Option<bool>,
+  /// Scales every vibrate, rotate and oscillate speed sent to the device, from
+  /// 0.0 to 1.0, so 0.5 means the device never goes past half speed.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(default)]
+  #[serde(rename = "intensity-limit")]
+  intensity_limit: Option<f64>,
+  /// Replaces the protocol's StopBehavior for this device, i.e. to park a
+  /// stroker somewhere else. Takes effect the next time the device connects.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(default)]
+  #[serde(rename = "stop-behavior")]
+  stop_behavior: Option<StopBehavior>,
+  /// Stops the device if it's left running with no commands from the client
+  /// for this many milliseconds, in case the client crashed. Off if not set.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(default)]
+  #[serde(rename = "watchdog-timeout")]
+  watchdog_timeout: Option<u32>,
+  /// Set to false to leave a gamepad's impulse trigger motors out of its
+  /// vibrate features. Takes effect the next time the device connects.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(default)]
+  #[serde(rename = "trigger-motors")]
+  trigger_motors: Option<bool>,
+  /// Set to true to split raw writes that are too long to send in one go
+  /// across several writes, for devices that reassemble them (i.e. firmware
+  /// updates). Otherwise, raw writes that are too long are rejected.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(default)]
+  #[serde(rename = "chunk-raw-writes")]
+  chunk_raw_writes: Option<bool>,
+  /// How linear moves are eased, for strokers that would otherwise move at
+  /// the same speed the whole way. Only used by protocols that support it.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(default)]
+  #[serde(rename = "linear-easing")]
+  linear_easing: Option<LinearEasing>,
+  /// Fields this version of the library doesn't know about, kept so config
+  /// written by newer versions survives being loaded and saved again.
+  #[serde(flatten)]
+  #[getset(skip)]
+  other: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Debug)]
@@ -72,39 +261,244 @@ pub struct DeviceInfo {
   pub display_name: Option<String>,
 }
 
+/// Point in time view of a connected device, for use in [ServerStateSnapshot][super::ServerStateSnapshot].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceSnapshot {
+  pub index: u32,
+  pub name: String,
+  pub address: String,
+  /// Protocol the device was matched with.
+  pub protocol: String,
+  pub display_name: Option<String>,
+  /// Commands waiting to be sent to the device.
+  pub command_queue_depth: usize,
+}
+
+/// Point in time view of a device communication manager, for use in
+/// [ServerStateSnapshot][super::ServerStateSnapshot].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommManagerSnapshot {
+  pub name: String,
+  pub scanning: bool,
+  /// True if the comm manager is skipped when scanning starts, either on its
+  /// own or because all IO is paused.
+  pub paused: bool,
+  pub capabilities: CommManagerCapabilities,
+}
+
+/// Device that matched a protocol while the device manager was in
+/// identify-only mode. The device has not been connected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdentifiedDevice {
+  pub name: String,
+  pub address: String,
+  pub protocol: String,
+}
+
+/// Devices found during an identify-only scan, with what's needed to connect
+/// them later, keyed by address.
+pub(super) type IdentifiedDeviceMap =
+  DashMap<String, (IdentifiedDevice, Box<dyn ButtplugDeviceImplCreator>)>;
+
+/// Recent changes to the device list, so clients can be sent only what changed
+/// since the revision they last saw. Every device added or removed is a new
+/// revision.
+#[derive(Debug, Default)]
+pub(super) struct DeviceListHistory {
+  revision: u32,
+  /// Revision of each change, with the index and address of the device that
+  /// was added or removed, oldest first.
+  changes: VecDeque<(u32, u32, String)>,
+}
+
+impl DeviceListHistory {
+  pub(super) fn record(&mut self, device_index: u32, address: &str) {
+    self.revision += 1;
+    self
+      .changes
+      .push_back((self.revision, device_index, address.to_owned()));
+    if self.changes.len() > DEVICE_LIST_HISTORY_LENGTH {
+      self.changes.pop_front();
+    }
+  }
+
+  /// Indexes and addresses of devices added or removed since `revision`, or
+  /// None if changes going back that far aren't kept anymore.
+  fn changed_since(&self, revision: u32) -> Option<Vec<(u32, &str)>> {
+    // Revisions newer than ours come from a previous run of the server.
+    if revision == 0 || revision > self.revision {
+      return None;
+    }
+    let oldest_kept = self
+      .changes
+      .front()
+      .map_or(self.revision + 1, |(oldest, _, _)| *oldest);
+    if revision + 1 < oldest_kept {
+      return None;
+    }
+    let mut changed: Vec<(u32, &str)> = vec![];
+    for (_, device_index, address) in self
+      .changes
+      .iter()
+      .filter(|(change_revision, _, _)| *change_revision > revision)
+    {
+      if !changed.iter().any(|(index, _)| index == device_index) {
+        changed.push((*device_index, address));
+      }
+    }
+    Some(changed)
+  }
+}
+
+/// What the device manager does with found devices that don't match any
+/// protocol.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum UnmatchedDeviceReporting {
+  /// Ignore them.
+  #[default]
+  Off,
+  /// Record what they advertise, without connecting.
+  Advertisement,
+  /// Also connect to them briefly to list their services and characteristics.
+  /// Connecting to unknown hardware may have side effects, so this should only
+  /// be set when the user explicitly asks for it.
+  Inspect,
+}
+
+/// Devices found that didn't match any protocol, in a form users can attach to
+/// device support requests.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UnmatchedDeviceReport {
+  #[serde(rename = "buttplug-version")]
+  pub buttplug_version: String,
+  pub devices: Vec<DeviceInspectionReport>,
+}
+
+impl UnmatchedDeviceReport {
+  pub fn to_json(&self) -> String {
+    serde_json::to_string_pretty(self).expect("Report only contains serializable types")
+  }
+
+  pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+    std::fs::write(path, self.to_json())
+  }
+}
+
+/// How long new devices get to connect, and how often failed connections are
+/// retried.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DeviceConnectionOptions {
+  /// Milliseconds a device gets to connect and initialize, if set.
+  pub initialization_timeout: Option<u32>,
+  /// Extra attempts for connections that failed as busy or timed out.
+  pub retries: u32,
+}
+
 pub struct DeviceManager {
   // This uses a map to make sure we don't have 2 comm managers of the same type
   // register. Also means we can do lockless access since it's a Dashmap.
   comm_managers: Arc<DashMap<String, Box<dyn DeviceCommunicationManager>>>,
+  /// Names of comm managers that StartScanning should skip.
+  paused_comm_managers: Arc<DashSet<String>>,
+  /// Set between [DeviceManager::pause_all_io] and
+  /// [DeviceManager::resume_all_io], while StartScanning skips every comm
+  /// manager.
+  io_paused: Arc<AtomicBool>,
   devices: Arc<DashMap<u32, Arc<ButtplugDevice>>>,
   device_user_config: Arc<DashMap<String, DeviceUserConfig>>,
   device_event_sender: mpsc::Sender<DeviceCommunicationEvent>,
   config: Arc<DeviceConfigurationManager>,
+  /// Device index/endpoint pairs that have been subscribed to via
+  /// RawSubscribeCmd. Notifications on any other endpoint are only used
+  /// internally by protocols, and are not forwarded to clients.
+  raw_subscriptions: Arc<DashSet<(u32, Endpoint)>>,
+  /// Device index/sensor index pairs that have been subscribed to via
+  /// SensorSubscribeCmd.
+  sensor_subscriptions: Arc<DashSet<(u32, u32)>>,
+  /// Used for events that come from device command handling, like stop
+  /// escalation, rather than from the device event loop.
+  output_sender: broadcast::Sender<ButtplugServerMessage>,
+  /// Server wide raw message setting, which device user config can override.
+  allow_raw_messages: bool,
+  identify_only: Arc<AtomicBool>,
+  /// Whether comm managers that support it keep scanning in the background.
+  background_scanning: Arc<AtomicBool>,
+  identified_device_sender: broadcast::Sender<IdentifiedDevice>,
+  identified_devices: Arc<IdentifiedDeviceMap>,
+  unmatched_device_reporting: Arc<Mutex<UnmatchedDeviceReporting>>,
+  /// Reports for unmatched devices, keyed by address.
+  unmatched_devices: Arc<DashMap<String, DeviceInspectionReport>>,
+  /// Why we asked devices to disconnect, keyed by address, so the event loop
+  /// can say why when the device is removed.
+  disconnect_reasons: Arc<DashMap<String, DeviceRemovedReason>>,
+  /// Set by [DeviceManager::emergency_stop]. While set, every device command
+  /// other than a stop is refused.
+  emergency_stopped: Arc<AtomicBool>,
+  watchdogs: DeviceWatchdogs,
+  device_traffic_sender: broadcast::Sender<DeviceTrafficRecord>,
+  dry_run: Arc<AtomicBool>,
+  dry_run_write_sender: broadcast::Sender<DeviceTrafficRecord>,
+  /// Held by the event loop while it adds or removes devices, so the device
+  /// map and its revision always match.
+  device_list_history: Arc<Mutex<DeviceListHistory>>,
 }
 
-unsafe impl Send for DeviceManager {
-}
-
-unsafe impl Sync for DeviceManager {
-}
+// The device manager is shared with the server's tasks. Everything in it has
+// to be thread safe on its own, rather than being declared so.
+assert_impl_all!(DeviceManager: Send, Sync);
 
 impl DeviceManager {
-  pub fn new(
+  pub(crate) fn new(
     output_sender: broadcast::Sender<ButtplugServerMessage>,
     ping_timer: Arc<PingTimer>,
     allow_raw_messages: bool,
+    raw_reading_batch_window: Option<u32>,
+    stop_devices_on_ping_timeout: bool,
+    command_queue_options: DeviceCommandQueueOptions,
+    connection_options: DeviceConnectionOptions,
   ) -> Self {
     let config = Arc::new(DeviceConfigurationManager::new(allow_raw_messages));
     let devices = Arc::new(DashMap::new());
     let (device_event_sender, device_event_receiver) = mpsc::channel(256);
     let device_user_config = Arc::new(DashMap::new());
+    let raw_subscriptions = Arc::new(DashSet::new());
+    let sensor_subscriptions = Arc::new(DashSet::new());
+    let identify_only = Arc::new(AtomicBool::new(false));
+    let (identified_device_sender, _) = broadcast::channel(256);
+    let identified_devices = Arc::new(DashMap::new());
+    let unmatched_device_reporting = Arc::new(Mutex::new(UnmatchedDeviceReporting::default()));
+    let unmatched_devices = Arc::new(DashMap::new());
+    let disconnect_reasons = Arc::new(DashMap::new());
+    let dry_run = Arc::new(AtomicBool::new(false));
+    let (dry_run_write_sender, _) = broadcast::channel(256);
+    let device_list_history = Arc::new(Mutex::new(DeviceListHistory::default()));
+    let watchdogs = DeviceWatchdogs::default();
     let mut event_loop = DeviceManagerEventLoop::new(
       config.clone(),
-      output_sender,
+      output_sender.clone(),
       devices.clone(),
       device_user_config.clone(),
       ping_timer,
       device_event_receiver,
+      DeviceManagerEventLoopOptions {
+        raw_subscriptions: raw_subscriptions.clone(),
+        sensor_subscriptions: sensor_subscriptions.clone(),
+        raw_reading_batch_window,
+        stop_devices_on_ping_timeout,
+        identify_only: identify_only.clone(),
+        identified_device_sender: identified_device_sender.clone(),
+        identified_devices: identified_devices.clone(),
+        command_queue_options,
+        initialization_timeout: connection_options.initialization_timeout,
+        connection_retries: connection_options.retries,
+        unmatched_device_reporting: unmatched_device_reporting.clone(),
+        unmatched_devices: unmatched_devices.clone(),
+        disconnect_reasons: disconnect_reasons.clone(),
+        dry_run: dry_run.clone(),
+        dry_run_write_sender: dry_run_write_sender.clone(),
+        device_list_history: device_list_history.clone(),
+        watchdogs: watchdogs.clone(),
+      },
     );
     async_manager::spawn(async move {
       event_loop.run().await;
@@ -114,37 +508,67 @@ impl DeviceManager {
       devices,
       device_user_config,
       comm_managers: Arc::new(DashMap::new()),
+      paused_comm_managers: Arc::new(DashSet::new()),
+      io_paused: Arc::new(AtomicBool::new(false)),
       config,
+      raw_subscriptions,
+      sensor_subscriptions,
+      output_sender,
+      allow_raw_messages,
+      identify_only,
+      background_scanning: Arc::new(AtomicBool::new(false)),
+      identified_device_sender,
+      identified_devices,
+      unmatched_device_reporting,
+      unmatched_devices,
+      disconnect_reasons,
+      emergency_stopped: Arc::new(AtomicBool::new(false)),
+      watchdogs,
+      device_traffic_sender: broadcast::channel(256).0,
+      dry_run,
+      dry_run_write_sender,
+      device_list_history,
     }
   }
 
   fn start_scanning(&self) -> ButtplugServerResultFuture {
-    if self.comm_managers.is_empty() {
+    if self.io_paused.load(Ordering::SeqCst)
+      || self
+        .comm_managers
+        .iter()
+        .all(|mgr| self.paused_comm_managers.contains(mgr.key()))
+    {
       ButtplugUnknownError::NoDeviceCommManagers.into()
     } else {
       let mgrs = self.comm_managers.clone();
+      let paused_mgrs = self.paused_comm_managers.clone();
       let sender = self.device_event_sender.clone();
+      let output_sender = self.output_sender.clone();
+      let identified_devices = self.identified_devices.clone();
       Box::pin(async move {
         for mgr in mgrs.iter() {
           if mgr.value().scanning_status().load(Ordering::SeqCst) {
             return Err(ButtplugDeviceError::DeviceScanningAlreadyStarted.into());
           }
         }
+        // Cleared before any manager starts, since managers can report devices
+        // before the event loop hears that scanning started.
+        identified_devices.clear();
         let fut_vec: Vec<_> = mgrs
           .iter()
-          .map(|guard| guard.value().start_scanning())
+          .filter(|guard| !paused_mgrs.contains(guard.key()))
+          .map(|guard| {
+            let name = guard.key().clone();
+            let fut = guard.value().start_scanning();
+            async move { (name, fut.await) }
+          })
           .collect();
-        // TODO If start_scanning fails anywhere, this will ignore it. We should maybe at least log?
-        future::join_all(fut_vec).await;
-        debug!("All managers started, sending ScanningStarted (and invoking ScanningFinished hack) signal to event loop.");
-        // HACK: In case everything somehow exited between the time all of our
-        // futures resolved and when we updated the event loop, act like we're a
-        // device comm manager and send a ScanningFinished message. This will
-        // cause the finish check to run just in case, so we don't get stuck.
-        //
-        // Ideally, this should be some sort of state machine, but for now, we
-        // can deal with this.
-        //
+        let results = future::join_all(fut_vec).await;
+        // If nothing started, there's no scan for the event loop to finish.
+        check_scanning_results("start", results, &output_sender)?;
+        // Managers that finished before the event loop sees this are picked up
+        // when it checks their scanning statuses.
+        debug!("All managers started, sending ScanningStarted signal to event loop.");
         // At this point, it doesn't really matter what we return, only way that
         // event loop could shut down is if the whole system is shutting down.
         // So complain if our sends error out, but don't worry about returning
@@ -153,10 +577,6 @@ impl DeviceManager {
           .send(DeviceCommunicationEvent::ScanningStarted)
           .await
           .is_err()
-          || sender
-            .send(DeviceCommunicationEvent::ScanningFinished)
-            .await
-            .is_err()
         {
           debug!("Device manager event loop shut down, cannot send ScanningStarted");
         }
@@ -170,7 +590,13 @@ impl DeviceManager {
       ButtplugUnknownError::NoDeviceCommManagers.into()
     } else {
       let mgrs = self.comm_managers.clone();
+      let sender = self.device_event_sender.clone();
+      let output_sender = self.output_sender.clone();
       Box::pin(async move {
+        let statuses: Vec<_> = mgrs
+          .iter()
+          .map(|guard| guard.value().scanning_status())
+          .collect();
         let mut scanning_stopped = true;
         for mgr in mgrs.iter() {
           if mgr.value().scanning_status().load(Ordering::SeqCst) {
@@ -179,48 +605,174 @@ impl DeviceManager {
             break;
           }
         }
+        let mut results = vec![];
+        if !scanning_stopped {
+          let fut_vec: Vec<_> = mgrs
+            .iter()
+            .map(|guard| {
+              let name = guard.key().clone();
+              let fut = guard.value().stop_scanning();
+              async move { (name, fut.await) }
+            })
+            .collect();
+          results = future::join_all(fut_vec).await;
+        }
+        // Even if every manager had already stopped, one may have done so
+        // without reporting it, so the event loop still needs to check.
+        if sender
+          .send(DeviceCommunicationEvent::ScanningStopped(statuses))
+          .await
+          .is_err()
+        {
+          debug!("Device manager event loop shut down, cannot send ScanningStopped");
+        }
         if scanning_stopped {
-          return Err(ButtplugDeviceError::DeviceScanningAlreadyStopped.into());
+          Err(ButtplugDeviceError::DeviceScanningAlreadyStopped.into())
+        } else {
+          check_scanning_results("stop", results, &output_sender)?;
+          Ok(messages::Ok::default().into())
         }
-
-        let fut_vec: Vec<_> = mgrs
-          .iter()
-          .map(|guard| guard.value().stop_scanning())
-          .collect();
-        // TODO If stop_scanning fails anywhere, this will ignore it. We should maybe at least log?
-        future::join_all(fut_vec).await;
-        Ok(messages::Ok::default().into())
       })
     }
   }
 
-  fn stop_all_devices(&self) -> ButtplugServerResultFuture {
+  /// Stops every device, or only the devices with the given indexes if there
+  /// are any.
+  fn stop_all_devices(&self, device_indexes: Option<&[u32]>) -> ButtplugServerResultFuture {
     let device_map = self.devices.clone();
+    let output_sender = self.output_sender.clone();
+    let watchdogs = self.watchdogs.clone();
+    let device_indexes: Option<HashSet<u32>> =
+      device_indexes.map(|indexes| indexes.iter().copied().collect());
     // TODO This could use some error reporting.
     Box::pin(async move {
       let fut_vec: Vec<_> = device_map
         .iter()
+        .filter(|dev| {
+          device_indexes
+            .as_ref()
+            .map_or(true, |indexes| indexes.contains(dev.key()))
+        })
         .map(|dev| {
-          let device = dev.value();
-          device.parse_message(messages::StopDeviceCmd::new(1).into())
+          let device_index = *dev.key();
+          let fut = dev
+            .value()
+            .parse_message(messages::StopDeviceCmd::new(device_index).into());
+          watchdogs.device_stopped(device_index);
+          async move { (device_index, fut.await) }
         })
         .collect();
-      future::join_all(fut_vec).await;
+      for (device_index, result) in future::join_all(fut_vec).await {
+        escalate_unconfirmed_stop(&output_sender, device_index, &result);
+      }
       Ok(messages::Ok::default().into())
     })
   }
 
+  /// Raw messages are allowed for a device if its user config says so, or
+  /// failing that, if the server allows them.
+  fn raw_messages_allowed(&self, address: &str) -> bool {
+    self
+      .device_user_config
+      .get(address)
+      .and_then(|config| *config.value().allow_raw_messages())
+      .unwrap_or(self.allow_raw_messages)
+  }
+
   fn parse_device_message(
     &self,
     device_msg: ButtplugDeviceCommandMessageUnion,
   ) -> ButtplugServerResultFuture {
-    match self.devices.get(&device_msg.device_index()) {
+    let device_index = device_msg.device_index();
+    if self.emergency_stopped()
+      && !matches!(
+        device_msg,
+        ButtplugDeviceCommandMessageUnion::StopDeviceCmd(_)
+      )
+    {
+      return ButtplugDeviceError::DevicesEmergencyStopped.into();
+    }
+    match self.devices.get(&device_index) {
       Some(device) => {
+        // Raw message attributes are only advertised at connection time, so
+        // also check here in case user config has since taken access away.
+        let raw_message_type = match &device_msg {
+          ButtplugDeviceCommandMessageUnion::RawReadCmd(_) => {
+            Some(ButtplugDeviceMessageType::RawReadCmd)
+          }
+          ButtplugDeviceCommandMessageUnion::RawWriteCmd(_) => {
+            Some(ButtplugDeviceMessageType::RawWriteCmd)
+          }
+          ButtplugDeviceCommandMessageUnion::RawSubscribeCmd(_) => {
+            Some(ButtplugDeviceMessageType::RawSubscribeCmd)
+          }
+          ButtplugDeviceCommandMessageUnion::RawUnsubscribeCmd(_) => {
+            Some(ButtplugDeviceMessageType::RawUnsubscribeCmd)
+          }
+          _ => None,
+        };
+        if let Some(message_type) = raw_message_type {
+          if !self.raw_messages_allowed(device.address()) {
+            return ButtplugDeviceError::MessageNotSupported(message_type).into();
+          }
+        }
+        // Keep track of raw and sensor subscriptions, so the event loop knows
+        // which notifications to relay to clients.
+        let subscription = match &device_msg {
+          ButtplugDeviceCommandMessageUnion::RawSubscribeCmd(msg) => Some((true, msg.endpoint())),
+          ButtplugDeviceCommandMessageUnion::RawUnsubscribeCmd(msg) => {
+            Some((false, msg.endpoint()))
+          }
+          _ => None,
+        };
+        let sensor_subscription = match &device_msg {
+          ButtplugDeviceCommandMessageUnion::SensorSubscribeCmd(msg) => {
+            Some((true, msg.sensor_index()))
+          }
+          ButtplugDeviceCommandMessageUnion::SensorUnsubscribeCmd(msg) => {
+            Some((false, msg.sensor_index()))
+          }
+          _ => None,
+        };
+        let is_stop = matches!(
+          device_msg,
+          ButtplugDeviceCommandMessageUnion::StopDeviceCmd(_)
+        );
+        let watchdog_timeout = self
+          .device_user_config
+          .get(device.address())
+          .and_then(|config| *config.value().watchdog_timeout());
+        self
+          .watchdogs
+          .command_sent(device_index, device.value(), &device_msg, watchdog_timeout);
+        let raw_subscriptions = self.raw_subscriptions.clone();
+        let sensor_subscriptions = self.sensor_subscriptions.clone();
+        let output_sender = self.output_sender.clone();
         let fut = device.parse_message(device_msg);
         // Create a future to run the message through the device, then handle adding the id to the result.
-        Box::pin(async move { fut.await })
+        Box::pin(async move {
+          let result = fut.await;
+          if is_stop {
+            escalate_unconfirmed_stop(&output_sender, device_index, &result);
+          }
+          if let (Ok(_), Some((subscribe, endpoint))) = (&result, subscription) {
+            if subscribe {
+              raw_subscriptions.insert((device_index, endpoint));
+            } else {
+              raw_subscriptions.remove(&(device_index, endpoint));
+            }
+          }
+          if let (Ok(_), Some((subscribe, sensor_index))) = (&result, sensor_subscription) {
+            if subscribe {
+              sensor_subscriptions.insert((device_index, sensor_index));
+            } else {
+              sensor_subscriptions.remove(&(device_index, sensor_index));
+            }
+          }
+          result
+        })
       }
-      None => ButtplugDeviceError::DeviceNotAvailable(device_msg.device_index()).into(),
+      None => ButtplugDeviceError::DeviceNotAvailable(device_index).into(),
     }
   }
 
@@ -233,16 +785,18 @@ impl DeviceManager {
         let devices = self
           .devices
           .iter()
-          .map(|device| {
-            let dev = device.value();
-            DeviceMessageInfo::new(*device.key(), &dev.name(), dev.message_attributes())
-          })
+          .map(|device| device_message_info(*device.key(), device.value()))
           .collect();
         let mut device_list = DeviceList::new(devices);
         device_list.set_id(msg.id());
         Box::pin(future::ready(Ok(device_list.into())))
       }
-      ButtplugDeviceManagerMessageUnion::StopAllDevices(_) => self.stop_all_devices(),
+      ButtplugDeviceManagerMessageUnion::RequestDeviceListChanges(msg) => {
+        let mut changes = self.device_list_changes(msg.revision());
+        changes.set_id(msg.id());
+        Box::pin(future::ready(Ok(changes.into())))
+      }
+      ButtplugDeviceManagerMessageUnion::StopAllDevices(_) => self.stop_all_devices(None),
       ButtplugDeviceManagerMessageUnion::StartScanning(_) => self.start_scanning(),
       ButtplugDeviceManagerMessageUnion::StopScanning(_) => self.stop_scanning(),
     }
@@ -252,7 +806,17 @@ impl DeviceManager {
     // If this is a device command message, just route it directly to the
     // device.
     match ButtplugDeviceCommandMessageUnion::try_from(msg.clone()) {
-      Ok(device_msg) => self.parse_device_message(device_msg),
+      Ok(device_msg) => {
+        let span = message_span(|| {
+          info_span!(
+            "Device Manager Message",
+            id = device_msg.id(),
+            device_index = device_msg.device_index()
+          )
+        });
+        let fut = span.in_scope(|| self.parse_device_message(device_msg));
+        Box::pin(fut.instrument(span))
+      }
       Err(_) => match ButtplugDeviceManagerMessageUnion::try_from(msg.clone()) {
         Ok(manager_msg) => self.parse_device_manager_message(manager_msg),
         Err(_) => ButtplugMessageError::UnexpectedMessageType(format!("{:?}", msg)).into(),
@@ -260,6 +824,125 @@ impl DeviceManager {
     }
   }
 
+  /// Device list changes since `revision`, as sent in reply to
+  /// RequestDeviceListChanges.
+  pub fn device_list_changes(&self, revision: u32) -> DeviceListChanges {
+    self.permitted_device_list_changes(revision, |_| true)
+  }
+
+  /// Device list changes since `revision`, leaving out devices whose address
+  /// `permitted` returns false for.
+  pub(crate) fn permitted_device_list_changes(
+    &self,
+    revision: u32,
+    permitted: impl Fn(&str) -> bool,
+  ) -> DeviceListChanges {
+    let history = self
+      .device_list_history
+      .lock()
+      .expect("We never panic while holding this lock.");
+    let device_info = |index: u32| {
+      self
+        .devices
+        .get(&index)
+        .map(|device| device_message_info(index, device.value()))
+    };
+    match history.changed_since(revision) {
+      Some(changed) => {
+        let mut devices = vec![];
+        let mut removed_devices = vec![];
+        for (index, _) in changed
+          .into_iter()
+          .filter(|(_, address)| permitted(address))
+        {
+          match device_info(index) {
+            Some(info) => devices.push(info),
+            None => removed_devices.push(index),
+          }
+        }
+        DeviceListChanges::new(history.revision, false, devices, removed_devices)
+      }
+      None => {
+        let mut devices: Vec<DeviceMessageInfo> = self
+          .devices
+          .iter()
+          .filter(|device| permitted(device.value().address()))
+          .map(|device| device_message_info(*device.key(), device.value()))
+          .collect();
+        devices.sort_by_key(|info| info.device_index);
+        DeviceListChanges::new(history.revision, true, devices, vec![])
+      }
+    }
+  }
+
+  /// Stops only the devices with the given indexes, i.e. the devices one app
+  /// or client is controlling, leaving the rest running. Indexes of devices
+  /// that aren't connected are skipped.
+  pub fn stop_devices(&self, device_indexes: &[u32]) -> ButtplugServerResultFuture {
+    self.stop_all_devices(Some(device_indexes))
+  }
+
+  /// Stops every device as fast as possible, skipping past anything waiting
+  /// in device command queues, then refuses every device command other than
+  /// a stop until [DeviceManager::resume_after_emergency_stop] is called.
+  ///
+  /// Devices are stopped concurrently, and each gets a short time to confirm
+  /// the stop. An error means at least one device may still be running.
+  pub fn emergency_stop(&self) -> ButtplugResultFuture {
+    warn!("Emergency stop, device commands are refused until resumed.");
+    self.emergency_stopped.store(true, Ordering::SeqCst);
+    let output_sender = self.output_sender.clone();
+    let fut_vec: Vec<_> = self
+      .devices
+      .iter()
+      .map(|dev| {
+        let device_index = *dev.key();
+        let fut = dev.value().emergency_stop();
+        self.watchdogs.device_stopped(device_index);
+        async move {
+          let result = futures::select! {
+            result = fut.fuse() => Some(result),
+            _ = Delay::new(Duration::from_millis(EMERGENCY_STOP_TIMEOUT)).fuse() => None,
+          };
+          (device_index, result)
+        }
+      })
+      .collect();
+    Box::pin(async move {
+      let mut failures = vec![];
+      for (device_index, result) in future::join_all(fut_vec).await {
+        match result {
+          Some(result) => {
+            escalate_unconfirmed_stop(&output_sender, device_index, &result);
+            if let Err(err) = result {
+              error!("Emergency stop failed for device {}: {}", device_index, err);
+              failures.push(format!("device {}: {}", device_index, err));
+            }
+          }
+          None => {
+            error!("Emergency stop timed out for device {}", device_index);
+            failures.push(format!("device {}: timed out", device_index));
+          }
+        }
+      }
+      if failures.is_empty() {
+        Ok(())
+      } else {
+        Err(ButtplugDeviceError::EmergencyStopFailed(failures.join(", ")).into())
+      }
+    })
+  }
+
+  /// Lets device commands through again after [DeviceManager::emergency_stop].
+  pub fn resume_after_emergency_stop(&self) {
+    info!("Resuming device commands after emergency stop.");
+    self.emergency_stopped.store(false, Ordering::SeqCst);
+  }
+
+  pub fn emergency_stopped(&self) -> bool {
+    self.emergency_stopped.load(Ordering::SeqCst)
+  }
+
   pub fn add_comm_manager<T>(&self, builder: T) -> Result<(), ButtplugServerError>
   where
     T: DeviceCommunicationManagerBuilder,
@@ -274,17 +957,153 @@ impl DeviceManager {
     }
     let status = mgr.scanning_status();
     let sender = self.device_event_sender.clone();
+    let background_fut = (self.background_scanning.load(Ordering::SeqCst)
+      && mgr.capabilities().background_scanning)
+      .then(|| mgr.set_background_scanning(true));
     // TODO This could run out of order and possibly cause weird scanning finished bugs?
     async_manager::spawn(async move {
       sender
         .send(DeviceCommunicationEvent::DeviceManagerAdded(status))
         .await
         .expect("We should always have an event loop for this to go to.");
+      if let Some(fut) = background_fut {
+        if let Err(e) = fut.await {
+          error!(
+            "Error starting background scanning on added comm manager: {:?}",
+            e
+          );
+        }
+      }
     });
     self.comm_managers.insert(mgr.name().to_owned(), mgr);
     Ok(())
   }
 
+  /// Removes a comm manager, stopping it first if it's scanning. Devices it
+  /// already connected stay connected.
+  pub fn remove_comm_manager(&self, name: &str) -> Result<(), ButtplugServerError> {
+    let (_, mgr) = self
+      .comm_managers
+      .remove(name)
+      .ok_or_else(|| ButtplugServerError::DeviceManagerTypeNotFound(name.to_owned()))?;
+    self.paused_comm_managers.remove(name);
+    let status = mgr.scanning_status();
+    let sender = self.device_event_sender.clone();
+    async_manager::spawn(async move {
+      if status.load(Ordering::SeqCst) {
+        if let Err(e) = mgr.stop_scanning().await {
+          error!("Error stopping scanning on removed comm manager: {:?}", e);
+        }
+      }
+      // The event loop reruns the scanning finished check when it drops the
+      // manager, in case this was the last manager still scanning.
+      if sender
+        .send(DeviceCommunicationEvent::DeviceManagerRemoved(status))
+        .await
+        .is_err()
+      {
+        debug!("Device manager event loop shut down, cannot send DeviceManagerRemoved");
+      }
+    });
+    Ok(())
+  }
+
+  /// Keeps a comm manager from scanning until
+  /// [DeviceManager::resume_comm_manager] is called, stopping it if it's
+  /// currently scanning. Devices it already connected stay connected.
+  pub fn pause_comm_manager(&self, name: &str) -> Result<(), ButtplugServerError> {
+    let mgr = self
+      .comm_managers
+      .get(name)
+      .ok_or_else(|| ButtplugServerError::DeviceManagerTypeNotFound(name.to_owned()))?;
+    let status = mgr.value().scanning_status();
+    if self.paused_comm_managers.insert(name.to_owned()) && status.load(Ordering::SeqCst) {
+      let stop_fut = mgr.value().stop_scanning();
+      let sender = self.device_event_sender.clone();
+      async_manager::spawn(async move {
+        if let Err(e) = stop_fut.await {
+          error!("Error stopping scanning on paused comm manager: {:?}", e);
+        }
+        if sender
+          .send(DeviceCommunicationEvent::ScanningStopped(vec![status]))
+          .await
+          .is_err()
+        {
+          debug!("Device manager event loop shut down, cannot send ScanningStopped");
+        }
+      });
+    }
+    Ok(())
+  }
+
+  /// Lets a comm manager paused with [DeviceManager::pause_comm_manager] scan
+  /// again, starting with the next StartScanning.
+  pub fn resume_comm_manager(&self, name: &str) -> Result<(), ButtplugServerError> {
+    if !self.comm_managers.contains_key(name) {
+      return Err(ButtplugServerError::DeviceManagerTypeNotFound(
+        name.to_owned(),
+      ));
+    }
+    self.paused_comm_managers.remove(name);
+    Ok(())
+  }
+
+  /// Stops scanning and tells every comm manager to let go of its hardware,
+  /// for when the host app is about to be backgrounded. Scanning can't start
+  /// again until [DeviceManager::resume_all_io] is called. Devices stay
+  /// connected, though platforms may drop them anyway while backgrounded.
+  pub fn pause_all_io(&self) -> ButtplugResultFuture {
+    self.io_paused.store(true, Ordering::SeqCst);
+    let mgrs = self.comm_managers.clone();
+    let sender = self.device_event_sender.clone();
+    Box::pin(async move {
+      let (statuses, stop_futs): (Vec<_>, Vec<_>) = mgrs
+        .iter()
+        .filter(|guard| guard.value().scanning_status().load(Ordering::SeqCst))
+        .map(|guard| {
+          (
+            guard.value().scanning_status(),
+            guard.value().stop_scanning(),
+          )
+        })
+        .unzip();
+      if !statuses.is_empty() {
+        for result in future::join_all(stop_futs).await {
+          if let Err(e) = result {
+            error!("Error stopping scanning while pausing IO: {:?}", e);
+          }
+        }
+        if sender
+          .send(DeviceCommunicationEvent::ScanningStopped(statuses))
+          .await
+          .is_err()
+        {
+          debug!("Device manager event loop shut down, cannot send ScanningStopped");
+        }
+      }
+      let pause_futs: Vec<_> = mgrs.iter().map(|guard| guard.value().pause_io()).collect();
+      future::join_all(pause_futs)
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+      Ok(())
+    })
+  }
+
+  /// Undoes [DeviceManager::pause_all_io], letting comm managers get their
+  /// hardware back. Scanning isn't restarted, that's up to the client.
+  pub fn resume_all_io(&self) -> ButtplugResultFuture {
+    let mgrs = self.comm_managers.clone();
+    let io_paused = self.io_paused.clone();
+    Box::pin(async move {
+      let resume_futs: Vec<_> = mgrs.iter().map(|guard| guard.value().resume_io()).collect();
+      let results = future::join_all(resume_futs).await;
+      io_paused.store(false, Ordering::SeqCst);
+      results.into_iter().collect::<Result<Vec<_>, _>>()?;
+      Ok(())
+    })
+  }
+
   pub fn add_protocol<T>(&self, protocol_name: &str) -> Result<(), ButtplugServerError>
   where
     T: ButtplugProtocol,
@@ -299,6 +1118,24 @@ impl DeviceManager {
     }
   }
 
+  /// Registers a protocol implemented outside the library. Devices matching
+  /// the protocol definition added for `protocol_name` (see
+  /// [DeviceManager::add_protocol_definition]) are created with `factory`.
+  pub fn add_protocol_factory(
+    &self,
+    protocol_name: &str,
+    factory: Arc<dyn ButtplugProtocolFactory>,
+  ) -> Result<(), ButtplugServerError> {
+    if !self.config.has_protocol(protocol_name) {
+      self.config.add_protocol_factory(protocol_name, factory);
+      Ok(())
+    } else {
+      Err(ButtplugServerError::ProtocolAlreadyAdded(
+        protocol_name.to_owned(),
+      ))
+    }
+  }
+
   pub fn remove_protocol(&self, protocol_name: &str) -> Result<(), ButtplugServerError> {
     if self.config.has_protocol(protocol_name) {
       self.config.remove_protocol(protocol_name);
@@ -322,17 +1159,219 @@ impl DeviceManager {
     self.config.remove_protocol_definition(name);
   }
 
-  pub fn add_device_user_config(&self, address: &str, config: DeviceUserConfig) {
+  /// Current user config for every device address, i.e. for editing and
+  /// saving back to the user's config file.
+  pub fn user_config_file(&self) -> UserDeviceConfigFile {
+    let mut file = UserDeviceConfigFile::default();
+    for config in self.device_user_config.iter() {
+      *file.device_config_mut(config.key()) = config.value().clone();
+    }
+    file
+  }
+
+  /// Replaces the user config for every device address with the config in
+  /// `file`. Connected devices pick up the changes straight away.
+  pub fn set_user_config_file(&self, file: &UserDeviceConfigFile) {
+    let removed: Vec<String> = self
+      .device_user_config
+      .iter()
+      .map(|config| config.key().clone())
+      .filter(|address| file.device_config(address).is_none())
+      .collect();
+    for address in removed {
+      self.remove_device_user_config(&address);
+    }
+    for (address, config) in file.device_configs() {
+      self.add_device_user_config(address, config.clone());
+    }
+  }
+
+  pub(crate) fn add_device_user_config(&self, address: &str, config: DeviceUserConfig) {
     info!(
       "Adding device user config for address {} with values {:?}.",
       address, config
     );
+    self.update_connected_device_user_config(address, &config);
     self.device_user_config.insert(address.to_owned(), config);
   }
 
-  pub fn remove_device_user_config(&self, address: &str) {
+  fn remove_device_user_config(&self, address: &str) {
     info!("Removing device user config for address {}.", address);
     self.device_user_config.remove(address);
+    self.update_connected_device_user_config(address, &DeviceUserConfig::default());
+  }
+
+  /// Lets protocols of any connected device at the address pick up user config
+  /// changes without reconnecting.
+  fn update_connected_device_user_config(&self, address: &str, config: &DeviceUserConfig) {
+    self
+      .devices
+      .iter()
+      .filter(|device| device.value().address() == address)
+      .for_each(|device| device.value().update_user_config(config));
+  }
+
+  /// While set, scanning only reports devices that match a protocol via
+  /// [identified_device_stream][DeviceManager::identified_device_stream],
+  /// instead of connecting to them. Lets setup UIs show what would connect so
+  /// users can allow/deny devices before anything is activated. Identified
+  /// devices can be listed with
+  /// [identified_devices][DeviceManager::identified_devices] and connected
+  /// with [connect_identified_device][DeviceManager::connect_identified_device].
+  pub fn set_identify_only(&self, identify_only: bool) {
+    self.identify_only.store(identify_only, Ordering::SeqCst);
+  }
+
+  pub fn identify_only(&self) -> bool {
+    self.identify_only.load(Ordering::SeqCst)
+  }
+
+  pub fn identified_device_stream(&self) -> impl Stream<Item = IdentifiedDevice> {
+    convert_broadcast_receiver_to_stream(self.identified_device_sender.subscribe())
+  }
+
+  /// Every device reported during the current identify-only scan, sorted by
+  /// address, i.e. for building a device picker. Cleared when a new scan
+  /// starts, and devices are removed once picked with
+  /// [connect_identified_device][DeviceManager::connect_identified_device].
+  pub fn identified_devices(&self) -> Vec<IdentifiedDevice> {
+    let mut devices: Vec<_> = self
+      .identified_devices
+      .iter()
+      .map(|entry| entry.value().0.clone())
+      .collect();
+    devices.sort_by(|a, b| a.address.cmp(&b.address));
+    devices
+  }
+
+  /// Connects a device found during an identify-only scan, as if it had been
+  /// found with identify-only mode off, except that allow and deny lists
+  /// aren't checked, since picking the device counts as allowing it. The
+  /// device is added like any other once connected, i.e. with a DeviceAdded
+  /// event.
+  pub fn connect_identified_device(&self, address: &str) -> ButtplugResultFuture {
+    let sender = self.device_event_sender.clone();
+    let candidate = self.identified_devices.remove(address);
+    let address = address.to_owned();
+    Box::pin(async move {
+      let (_, (device, creator)) = candidate.ok_or_else(|| {
+        ButtplugDeviceError::DeviceConnectionError(format!(
+          "No identified device with address {}",
+          address
+        ))
+      })?;
+      if sender
+        .send(DeviceCommunicationEvent::ConnectIdentifiedDevice {
+          name: device.name,
+          address,
+          creator,
+        })
+        .await
+        .is_err()
+      {
+        debug!("Device manager event loop shut down, cannot connect identified device.");
+      }
+      Ok(())
+    })
+  }
+
+  /// Turns background scanning on or off for every comm manager that supports
+  /// it. While on, those comm managers keep a low power scan running between
+  /// scans started by the client, and allow listed devices are connected as
+  /// soon as they're found. Comm managers added later pick up the setting.
+  pub fn set_background_scanning(&self, enabled: bool) -> ButtplugResultFuture {
+    self.background_scanning.store(enabled, Ordering::SeqCst);
+    let fut_vec: Vec<_> = self
+      .comm_managers
+      .iter()
+      .filter(|guard| guard.value().capabilities().background_scanning)
+      .map(|guard| guard.value().set_background_scanning(enabled))
+      .collect();
+    Box::pin(async move {
+      future::join_all(fut_vec)
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+      Ok(())
+    })
+  }
+
+  pub fn background_scanning(&self) -> bool {
+    self.background_scanning.load(Ordering::SeqCst)
+  }
+
+  /// Connects the device at `address` without scanning, i.e. a device
+  /// remembered from an earlier session. Comm managers that can connect
+  /// devices by address are tried in turn, until one finds the device. As
+  /// with [connect_identified_device][DeviceManager::connect_identified_device],
+  /// allow and deny lists aren't checked, and the device is added with a
+  /// DeviceAdded event once connected.
+  pub fn connect_device(&self, address: &str) -> ButtplugResultFuture {
+    let fut_vec: Vec<_> = if self.io_paused.load(Ordering::SeqCst) {
+      vec![]
+    } else {
+      self
+        .comm_managers
+        .iter()
+        .filter(|guard| {
+          !self.paused_comm_managers.contains(guard.key())
+            && guard.value().capabilities().targeted_connect
+        })
+        .map(|guard| (guard.key().clone(), guard.value().connect_device(address)))
+        .collect()
+    };
+    let address = address.to_owned();
+    Box::pin(async move {
+      for (name, fut) in fut_vec {
+        match fut.await {
+          Ok(()) => return Ok(()),
+          Err(err) => debug!("{} cannot connect device {}: {}", name, address, err),
+        }
+      }
+      Err(
+        ButtplugDeviceError::DeviceConnectionError(format!(
+          "No comm manager could connect device {}",
+          address
+        ))
+        .into(),
+      )
+    })
+  }
+
+  /// Sets whether found devices that match no protocol are recorded for
+  /// [unmatched_device_report][DeviceManager::unmatched_device_report]. Each
+  /// address is only recorded once, until
+  /// [clear_unmatched_devices][DeviceManager::clear_unmatched_devices] is
+  /// called.
+  pub fn set_unmatched_device_reporting(&self, reporting: UnmatchedDeviceReporting) {
+    *self
+      .unmatched_device_reporting
+      .lock()
+      .expect("Lock only held for copies") = reporting;
+  }
+
+  pub fn unmatched_device_reporting(&self) -> UnmatchedDeviceReporting {
+    *self
+      .unmatched_device_reporting
+      .lock()
+      .expect("Lock only held for copies")
+  }
+
+  pub fn unmatched_device_report(&self) -> UnmatchedDeviceReport {
+    let mut devices: Vec<DeviceInspectionReport> = self
+      .unmatched_devices
+      .iter()
+      .map(|report| report.value().clone())
+      .collect();
+    devices.sort_by(|a, b| a.address.cmp(&b.address));
+    UnmatchedDeviceReport {
+      buttplug_version: env!("CARGO_PKG_VERSION").to_owned(),
+      devices,
+    }
+  }
+
+  pub fn clear_unmatched_devices(&self) {
+    self.unmatched_devices.clear();
   }
 
   pub fn device_info(&self, index: u32) -> Result<DeviceInfo, ButtplugDeviceError> {
@@ -345,6 +1384,122 @@ impl DeviceManager {
       Err(ButtplugDeviceError::DeviceNotAvailable(index))
     }
   }
+
+  /// Disconnects a device. Its DeviceRemoved event will give
+  /// [DeviceRemovedReason::UserRequested] as the reason.
+  pub fn disconnect_device(&self, index: u32) -> ButtplugResultFuture {
+    let device = if let Some(device) = self.devices.get(&index) {
+      device.value().clone()
+    } else {
+      return ButtplugDeviceError::DeviceNotAvailable(index).into();
+    };
+    self.disconnect_reasons.insert(
+      device.address().to_owned(),
+      DeviceRemovedReason::UserRequested,
+    );
+    device.disconnect()
+  }
+
+  /// Turns traffic diagnostics on or off for a device. While on, every write,
+  /// read and notification for the device is logged with a hex dump and sent
+  /// to [device_traffic_stream][DeviceManager::device_traffic_stream].
+  /// Diagnostics are off for newly connected devices, including ones that
+  /// reconnect after being removed.
+  pub fn set_device_traffic_diagnostics(
+    &self,
+    index: u32,
+    enabled: bool,
+  ) -> Result<(), ButtplugDeviceError> {
+    let device = self
+      .devices
+      .get(&index)
+      .ok_or(ButtplugDeviceError::DeviceNotAvailable(index))?;
+    device
+      .value()
+      .set_traffic_diagnostics(enabled.then(|| (index, self.device_traffic_sender.clone())));
+    Ok(())
+  }
+
+  /// Traffic for all devices with diagnostics turned on. See
+  /// [set_device_traffic_diagnostics][DeviceManager::set_device_traffic_diagnostics].
+  pub fn device_traffic_stream(&self) -> impl Stream<Item = DeviceTrafficRecord> {
+    convert_broadcast_receiver_to_stream(self.device_traffic_sender.subscribe())
+  }
+
+  /// While set, device commands are validated and translated by protocols as
+  /// usual, but the resulting writes are sent to
+  /// [dry_run_write_stream][DeviceManager::dry_run_write_stream] instead of
+  /// the device. Applies to connected devices and any that connect later.
+  /// Device initialization isn't affected, so devices still need to be
+  /// connected, e.g. through test devices.
+  pub fn set_dry_run(&self, dry_run: bool) {
+    self.dry_run.store(dry_run, Ordering::SeqCst);
+    for device in self.devices.iter() {
+      device
+        .value()
+        .set_dry_run(dry_run.then(|| (*device.key(), self.dry_run_write_sender.clone())));
+    }
+  }
+
+  pub fn dry_run(&self) -> bool {
+    self.dry_run.load(Ordering::SeqCst)
+  }
+
+  /// Writes skipped while in dry run mode. See
+  /// [set_dry_run][DeviceManager::set_dry_run].
+  pub fn dry_run_write_stream(&self) -> impl Stream<Item = DeviceTrafficRecord> {
+    convert_broadcast_receiver_to_stream(self.dry_run_write_sender.subscribe())
+  }
+
+  /// Connected devices by index, for sharing them with a
+  /// [DeviceForwarder][super::device_forwarder::DeviceForwarder].
+  #[cfg(feature = "forwarded-device-manager")]
+  pub(crate) fn device_map(&self) -> Arc<DashMap<u32, Arc<ButtplugDevice>>> {
+    self.devices.clone()
+  }
+
+  /// Set while devices are emergency stopped, so forwarded devices can be
+  /// held to it as well.
+  #[cfg(feature = "forwarded-device-manager")]
+  pub(crate) fn emergency_stop_flag(&self) -> Arc<AtomicBool> {
+    self.emergency_stopped.clone()
+  }
+
+  pub fn device_snapshots(&self) -> Vec<DeviceSnapshot> {
+    let mut devices: Vec<DeviceSnapshot> = self
+      .devices
+      .iter()
+      .map(|device| {
+        let dev = device.value();
+        DeviceSnapshot {
+          index: *device.key(),
+          name: dev.name(),
+          address: dev.address().to_owned(),
+          protocol: dev.protocol_name().to_owned(),
+          display_name: dev.display_name(),
+          command_queue_depth: dev.command_queue_depth(),
+        }
+      })
+      .collect();
+    devices.sort_by_key(|device| device.index);
+    devices
+  }
+
+  pub fn comm_manager_snapshots(&self) -> Vec<CommManagerSnapshot> {
+    let mut mgrs: Vec<CommManagerSnapshot> = self
+      .comm_managers
+      .iter()
+      .map(|mgr| CommManagerSnapshot {
+        name: mgr.key().clone(),
+        scanning: mgr.value().scanning_status().load(Ordering::SeqCst),
+        paused: self.io_paused.load(Ordering::SeqCst)
+          || self.paused_comm_managers.contains(mgr.key()),
+        capabilities: mgr.value().capabilities(),
+      })
+      .collect();
+    mgrs.sort_by(|a, b| a.name.cmp(&b.name));
+    mgrs
+  }
 }
 
 impl Drop for DeviceManager {
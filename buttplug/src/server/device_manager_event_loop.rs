@@ -1,40 +1,120 @@
 use super::{
   comm_managers::DeviceCommunicationEvent,
-  device_manager::DeviceUserConfig,
+  device_manager::{
+    DeviceListHistory,
+    DeviceUserConfig,
+    IdentifiedDevice,
+    IdentifiedDeviceMap,
+    UnmatchedDeviceReporting,
+  },
+  device_watchdog::DeviceWatchdogs,
   ping_timer::PingTimer,
 };
 use crate::{
-  core::messages::{
-    ButtplugServerMessage,
-    DeviceAdded,
-    DeviceRemoved,
-    ScanningFinished,
-    StopDeviceCmd,
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::{
+      ButtplugMessage,
+      ButtplugServerMessage,
+      DeviceAdded,
+      DeviceInitializationFailed,
+      DeviceRemoved,
+      DeviceRemovedReason,
+      RawReading,
+      ScanningFinished,
+      ScanningStarted,
+      StopDeviceCmd,
+      BUTTPLUG_SERVER_EVENT_ID,
+    },
   },
   device::{
     configuration_manager::DeviceConfigurationManager,
     ButtplugDevice,
     ButtplugDeviceEvent,
     ButtplugDeviceImplCreator,
+    DeviceCommandQueueOptions,
+    DeviceInspectionReport,
+    DeviceTrafficRecord,
+    Endpoint,
   },
-  util::async_manager,
+  util::{async_manager, stream::recv_skipping_lag},
 };
 use dashmap::{DashMap, DashSet};
 use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
-use std::sync::{
-  atomic::{AtomicBool, Ordering},
-  Arc,
+use futures_timer::Delay;
+use std::{
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+    Mutex,
+  },
+  time::Duration,
 };
 use tokio::sync::{broadcast, mpsc};
 use tracing;
 use tracing_futures::Instrument;
 
+/// Milliseconds to wait before retrying a busy or timed out device connection.
+const DEVICE_CONNECTION_RETRY_DELAY_MS: u64 = 1000;
+
+/// Raw notifications collected per device index/endpoint pair during a
+/// batching window.
+type RawReadingBatches = Arc<DashMap<(u32, Endpoint), Vec<Vec<u8>>>>;
+
+/// State shared between the [DeviceManager][super::DeviceManager] and its
+/// event loop that isn't needed for basic device management.
+pub struct DeviceManagerEventLoopOptions {
+  pub raw_subscriptions: Arc<DashSet<(u32, Endpoint)>>,
+  pub sensor_subscriptions: Arc<DashSet<(u32, u32)>>,
+  pub raw_reading_batch_window: Option<u32>,
+  pub stop_devices_on_ping_timeout: bool,
+  pub identify_only: Arc<AtomicBool>,
+  pub identified_device_sender: broadcast::Sender<IdentifiedDevice>,
+  pub identified_devices: Arc<IdentifiedDeviceMap>,
+  pub command_queue_options: DeviceCommandQueueOptions,
+  pub initialization_timeout: Option<u32>,
+  pub connection_retries: u32,
+  pub unmatched_device_reporting: Arc<Mutex<UnmatchedDeviceReporting>>,
+  pub unmatched_devices: Arc<DashMap<String, DeviceInspectionReport>>,
+  pub disconnect_reasons: Arc<DashMap<String, DeviceRemovedReason>>,
+  pub dry_run: Arc<AtomicBool>,
+  pub dry_run_write_sender: broadcast::Sender<DeviceTrafficRecord>,
+  pub device_list_history: Arc<Mutex<DeviceListHistory>>,
+  pub watchdogs: DeviceWatchdogs,
+}
+
+/// Scanning state of a single comm manager, as tracked by the event loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CommManagerScanningState {
+  /// Not taking part in a scan, either because none is running or because the
+  /// comm manager has finished.
+  Idle,
+  /// Started by StartScanning, and hasn't finished yet.
+  Scanning,
+  /// Told to stop by StopScanning, but hasn't finished yet.
+  Stopping,
+}
+
+struct CommManagerScanningStatus {
+  /// Set by the comm manager while it's scanning.
+  status: Arc<AtomicBool>,
+  state: CommManagerScanningState,
+}
+
+impl CommManagerScanningStatus {
+  fn is_scanning(&self) -> bool {
+    self.status.load(Ordering::SeqCst)
+  }
+}
+
 pub struct DeviceManagerEventLoop {
   device_config_manager: Arc<DeviceConfigurationManager>,
   device_index_generator: u32,
   device_map: Arc<DashMap<u32, Arc<ButtplugDevice>>>,
   device_user_config: Arc<DashMap<String, DeviceUserConfig>>,
   ping_timer: Arc<PingTimer>,
+  /// If false, the ping timeout policy leaves devices running.
+  stop_devices_on_ping_timeout: bool,
   /// Maps device addresses to indexes, so they can be reused on reconnect.
   device_index_map: Arc<DashMap<String, u32>>,
   /// Broadcaster that relays device events in the form of Buttplug Messages to
@@ -50,10 +130,61 @@ pub struct DeviceManagerEventLoop {
   /// True if StartScanning has been called but no ScanningFinished has been
   /// emitted yet.
   scanning_in_progress: bool,
-  /// Holds the status of comm manager scanning states (scanning/not scanning).
-  comm_manager_scanning_statuses: Vec<Arc<AtomicBool>>,
+  /// Scanning state of each comm manager. ScanningFinished is emitted once all
+  /// of them are idle.
+  comm_manager_scanning_statuses: Vec<CommManagerScanningStatus>,
   /// Devices currently trying to connect.
   connecting_devices: Arc<DashSet<String>>,
+  /// Device index/endpoint pairs that clients have subscribed to. Shared with
+  /// the device manager, which updates it on RawSubscribe/RawUnsubscribe.
+  raw_subscriptions: Arc<DashSet<(u32, Endpoint)>>,
+  /// Device index/sensor index pairs that clients have subscribed to. Shared
+  /// with the device manager, which updates it on
+  /// SensorSubscribe/SensorUnsubscribe.
+  sensor_subscriptions: Arc<DashSet<(u32, u32)>>,
+  /// If set, the number of milliseconds to collect subscribed data for before
+  /// emitting it. Each notification is still sent as its own RawReading.
+  raw_reading_batch_window: Option<u32>,
+  /// Next sequence number to use for each subscribed endpoint.
+  raw_reading_sequences: Arc<DashMap<(u32, Endpoint), u32>>,
+  /// Notifications collected during a batching window that haven't been
+  /// emitted yet, in the order they arrived.
+  raw_reading_batches: RawReadingBatches,
+  /// If true, found devices are matched against protocols and reported, but
+  /// never connected.
+  identify_only: Arc<AtomicBool>,
+  /// Relays devices matched while identify_only is set.
+  identified_device_sender: broadcast::Sender<IdentifiedDevice>,
+  /// Devices reported during the current identify-only scan, keyed by
+  /// address. Shared with the device manager, which lists them and hands
+  /// their creators back when one is picked to connect.
+  identified_devices: Arc<IdentifiedDeviceMap>,
+  /// Command queue settings for newly connected devices.
+  command_queue_options: DeviceCommandQueueOptions,
+  /// Milliseconds a device gets to connect and initialize before it's given
+  /// up on, if set.
+  initialization_timeout: Option<u32>,
+  /// How many more times to try connecting a device after a busy or timed out
+  /// connection attempt.
+  connection_retries: u32,
+  /// Whether devices that match no protocol are recorded, and how.
+  unmatched_device_reporting: Arc<Mutex<UnmatchedDeviceReporting>>,
+  /// Reports for unmatched devices, keyed by address. Shared with the device
+  /// manager, which hands them out.
+  unmatched_devices: Arc<DashMap<String, DeviceInspectionReport>>,
+  /// Why we asked devices to disconnect, keyed by address. Devices removed
+  /// without an entry here went away on their own.
+  disconnect_reasons: Arc<DashMap<String, DeviceRemovedReason>>,
+  /// If true, newly connected devices report writes to
+  /// dry_run_write_sender instead of sending them.
+  dry_run: Arc<AtomicBool>,
+  dry_run_write_sender: broadcast::Sender<DeviceTrafficRecord>,
+  /// Revisions of the device list. Locked while changing `device_map`, so
+  /// anyone holding the lock sees a map that matches the latest revision.
+  device_list_history: Arc<Mutex<DeviceListHistory>>,
+  /// Shared with the device manager. Devices this loop stops or removes are
+  /// recorded, so their watchdogs don't stop them again.
+  watchdogs: DeviceWatchdogs,
 }
 
 impl DeviceManagerEventLoop {
@@ -64,6 +195,7 @@ impl DeviceManagerEventLoop {
     device_user_config: Arc<DashMap<String, DeviceUserConfig>>,
     ping_timer: Arc<PingTimer>,
     device_comm_receiver: mpsc::Receiver<DeviceCommunicationEvent>,
+    options: DeviceManagerEventLoopOptions,
   ) -> Self {
     let (device_event_sender, device_event_receiver) = mpsc::channel(256);
     Self {
@@ -72,6 +204,7 @@ impl DeviceManagerEventLoop {
       device_map,
       device_user_config,
       ping_timer,
+      stop_devices_on_ping_timeout: options.stop_devices_on_ping_timeout,
       device_comm_receiver,
       device_index_generator: 0,
       device_index_map: Arc::new(DashMap::new()),
@@ -80,24 +213,190 @@ impl DeviceManagerEventLoop {
       scanning_in_progress: false,
       comm_manager_scanning_statuses: vec![],
       connecting_devices: Arc::new(DashSet::new()),
+      raw_subscriptions: options.raw_subscriptions,
+      sensor_subscriptions: options.sensor_subscriptions,
+      raw_reading_batch_window: options.raw_reading_batch_window,
+      raw_reading_sequences: Arc::new(DashMap::new()),
+      raw_reading_batches: Arc::new(DashMap::new()),
+      identify_only: options.identify_only,
+      identified_device_sender: options.identified_device_sender,
+      identified_devices: options.identified_devices,
+      command_queue_options: options.command_queue_options,
+      initialization_timeout: options.initialization_timeout,
+      connection_retries: options.connection_retries,
+      unmatched_device_reporting: options.unmatched_device_reporting,
+      unmatched_devices: options.unmatched_devices,
+      disconnect_reasons: options.disconnect_reasons,
+      dry_run: options.dry_run,
+      dry_run_write_sender: options.dry_run_write_sender,
+      device_list_history: options.device_list_history,
+      watchdogs: options.watchdogs,
+    }
+  }
+
+  fn send_raw_reading(
+    server_sender: &broadcast::Sender<ButtplugServerMessage>,
+    raw_reading_sequences: &DashMap<(u32, Endpoint), u32>,
+    device_index: u32,
+    endpoint: Endpoint,
+    data: Vec<u8>,
+  ) {
+    let sequence = {
+      let mut entry = raw_reading_sequences
+        .entry((device_index, endpoint))
+        .or_insert(0);
+      let sequence = *entry.value();
+      *entry.value_mut() = sequence.wrapping_add(1);
+      sequence
+    };
+    let mut reading = RawReading::new(device_index, endpoint, data);
+    reading.set_id(BUTTPLUG_SERVER_EVENT_ID);
+    reading.set_sequence(Some(sequence));
+    if server_sender.send(reading.into()).is_err() {
+      debug!("Server not currently available, dropping RawReading event.");
     }
   }
 
+  fn relay_sensor_readings(&self, device_index: u32, endpoint: Endpoint, data: &[u8]) {
+    let readings = match self.device_map.get(&device_index) {
+      Some(device) => device
+        .value()
+        .parse_sensor_notification(device_index, endpoint, data),
+      None => return,
+    };
+    for reading in readings {
+      if self
+        .sensor_subscriptions
+        .contains(&(device_index, reading.sensor_index()))
+        && self.server_sender.send(reading.into()).is_err()
+      {
+        debug!("Server not currently available, dropping SensorReading event.");
+      }
+    }
+  }
+
+  fn handle_notification(&self, address: String, endpoint: Endpoint, data: Vec<u8>) {
+    let device_index = if let Some(index) = self.device_index_map.get(&address) {
+      *index.value()
+    } else {
+      debug!("Notification from unknown device {}, ignoring.", address);
+      return;
+    };
+    self.relay_sensor_readings(device_index, endpoint, &data);
+    // Protocols may subscribe to endpoints for their own use (battery
+    // readings, etc), so only relay data that a client asked for.
+    let key = (device_index, endpoint);
+    if !self.raw_subscriptions.contains(&key) {
+      return;
+    }
+    let window = if let Some(window) = self.raw_reading_batch_window {
+      window
+    } else {
+      Self::send_raw_reading(
+        &self.server_sender,
+        &self.raw_reading_sequences,
+        device_index,
+        endpoint,
+        data,
+      );
+      return;
+    };
+    // If there's already a batch waiting, its flush task will pick up this
+    // notification too. Otherwise, start a new batch and schedule its flush.
+    // Notifications are kept separate so clients can still tell where one
+    // packet ends and the next begins.
+    if let Some(mut batch) = self.raw_reading_batches.get_mut(&key) {
+      batch.value_mut().push(data);
+      return;
+    }
+    self.raw_reading_batches.insert(key, vec![data]);
+    let server_sender = self.server_sender.clone();
+    let raw_reading_sequences = self.raw_reading_sequences.clone();
+    let raw_reading_batches = self.raw_reading_batches.clone();
+    async_manager::spawn(async move {
+      Delay::new(Duration::from_millis(window.into())).await;
+      if let Some((_, batch)) = raw_reading_batches.remove(&key) {
+        for data in batch {
+          Self::send_raw_reading(
+            &server_sender,
+            &raw_reading_sequences,
+            device_index,
+            endpoint,
+            data,
+          );
+        }
+      }
+    });
+  }
+
   fn try_create_new_device(
     &mut self,
+    device_name: String,
     device_address: String,
-    device_creator: Box<dyn ButtplugDeviceImplCreator>,
+    mut device_creator: Box<dyn ButtplugDeviceImplCreator>,
   ) {
     let device_event_sender_clone = self.device_event_sender.clone();
-    let create_device_future =
-      ButtplugDevice::try_create_device(self.device_config_manager.clone(), device_creator);
     let device_user_config = self.device_user_config.clone();
+    let device_config_manager = self.device_config_manager.clone();
+    // Only used to report failures, creation does its own lookup.
+    let protocols: Vec<_> = self
+      .device_config_manager
+      .find_all_protocol_definitions(&device_creator.get_specifier())
+      .into_iter()
+      .map(|(_, protocol, _)| protocol)
+      .filter(|protocol| self.device_config_manager.has_protocol(protocol))
+      .collect();
+    let user_config = device_user_config
+      .get(&device_address)
+      .map(|config| config.value().clone());
     let connecting_devices = self.connecting_devices.clone();
+    let command_queue_options = self.command_queue_options;
+    let initialization_timeout = self.initialization_timeout;
+    let connection_retries = self.connection_retries;
+    let server_sender = self.server_sender.clone();
+    let retry_address = device_address.clone();
     async_manager::spawn(async move {
-      match create_device_future.await {
+      let create_device_future = async move {
+        let mut retries_left = connection_retries;
+        loop {
+          let result = ButtplugDevice::try_create_device(
+            device_config_manager.clone(),
+            device_creator.as_mut(),
+            user_config.clone(),
+          )
+          .await;
+          match result {
+            Err(ButtplugError::ButtplugDeviceError(ButtplugDeviceError::DeviceConnectionFailed(
+              err,
+            ))) if err.is_retryable() && retries_left > 0 => {
+              retries_left -= 1;
+              info!(
+                "Connecting to {} failed ({}), retrying, {} retries left.",
+                retry_address, err, retries_left
+              );
+              Delay::new(Duration::from_millis(DEVICE_CONNECTION_RETRY_DELAY_MS)).await;
+            }
+            result => return result,
+          }
+        }
+      }
+      .boxed();
+      // On timeout, creation is dropped wherever it got to, and the device
+      // won't be added.
+      let result = match initialization_timeout {
+        Some(timeout_ms) => futures::select! {
+          result = create_device_future.fuse() => result,
+          _ = Delay::new(Duration::from_millis(timeout_ms.into())).fuse() => {
+            Err(ButtplugError::from(ButtplugDeviceError::DeviceInitializationTimeout(timeout_ms)))
+          }
+        },
+        None => create_device_future.await,
+      };
+      match result {
         Ok(option_dev) => match option_dev {
           Some(mut device) => {
             // The device was created, now we need to customize it before handing it to the system.
+            device.set_command_queue_options(command_queue_options);
             if let Some(device_config) = device_user_config.get(device.address()) {
               if let Some(device_name) = device_config.display_name() {
                 info!("Display name found for {} ({}), setting to {}", device.name(), device.address(), device_name);
@@ -114,124 +413,343 @@ impl DeviceManagerEventLoop {
           }
           None => debug!("Device could not be matched to a protocol."),
         },
-        Err(e) => error!("Device errored while trying to connect: {}", e),
+        Err(e) => {
+          error!("Device errored while trying to connect: {}", e);
+          let failed = DeviceInitializationFailed::new(
+            &device_name,
+            &device_address,
+            &protocols,
+            &e.to_string(),
+          );
+          if server_sender.send(failed.into()).is_err() {
+            debug!("Server not currently available, dropping device initialization failure event.");
+          }
+        }
       }
       connecting_devices.remove(&device_address);
     }.instrument(tracing::Span::current()));
   }
 
+  /// Reports a found device if it matches a protocol, without connecting to
+  /// it. Each address is only reported once per scan.
+  fn identify_device(
+    &self,
+    name: String,
+    address: String,
+    creator: Box<dyn ButtplugDeviceImplCreator>,
+  ) {
+    if self.identified_devices.contains_key(&address) {
+      return;
+    }
+    match self
+      .device_config_manager
+      .find_protocol_definitions(&creator.get_specifier())
+    {
+      Some((_, protocol, _)) if self.device_config_manager.has_protocol(&protocol) => {
+        info!(
+          "Identified device {} ({}) as protocol {}, not connecting.",
+          name, address, protocol
+        );
+        let device = IdentifiedDevice {
+          name,
+          address: address.clone(),
+          protocol,
+        };
+        self
+          .identified_devices
+          .insert(address, (device.clone(), creator));
+        if self.identified_device_sender.send(device).is_err() {
+          debug!("No one listening for identified devices, dropping event.");
+        }
+      }
+      _ => debug!("Device {} could not be matched to a protocol.", address),
+    }
+  }
+
+  /// Records what the comm manager can find out about a device that matched
+  /// no protocol. Each address is only recorded once.
+  fn report_unmatched_device(
+    &self,
+    address: String,
+    mut creator: Box<dyn ButtplugDeviceImplCreator>,
+    connect: bool,
+  ) {
+    if self.unmatched_devices.contains_key(&address) || self.connecting_devices.contains(&address) {
+      return;
+    }
+    // Inspection may connect to the device, so treat it like any other
+    // connection attempt while it runs.
+    self.connecting_devices.insert(address.clone());
+    let connecting_devices = self.connecting_devices.clone();
+    let unmatched_devices = self.unmatched_devices.clone();
+    async_manager::spawn(
+      async move {
+        if let Some(report) = creator.inspect(connect).await {
+          info!("Recorded unmatched device {} for device report.", address);
+          unmatched_devices.insert(address.clone(), report);
+        }
+        connecting_devices.remove(&address);
+      }
+      .instrument(tracing::Span::current()),
+    );
+  }
+
+  /// Connects a found device, unless it's already connected or connecting,
+  /// or ruled out by identify-only mode, unmatched device reporting or the
+  /// user's allow and deny lists.
+  fn handle_device_found(
+    &mut self,
+    name: String,
+    address: String,
+    creator: Box<dyn ButtplugDeviceImplCreator>,
+  ) {
+    let span = info_span!(
+      "device creation",
+      name = tracing::field::display(&name),
+      address = tracing::field::display(address.clone())
+    );
+    let _enter = span.enter();
+
+    // Check to make sure the device isn't already connected. If it is, drop it.
+    if self
+      .device_map
+      .iter()
+      .any(|entry| entry.value().address() == address)
+    {
+      debug!(
+        "Device {} already connected, ignoring new device event.",
+        address
+      );
+      return;
+    }
+
+    // In identify-only mode, report the device regardless of allow/deny
+    // lists, as the point is to let the user decide what to allow.
+    if self.identify_only.load(Ordering::SeqCst) {
+      self.identify_device(name, address, creator);
+      return;
+    }
+
+    let reporting = *self
+      .unmatched_device_reporting
+      .lock()
+      .expect("Lock only held for copies");
+    if reporting != UnmatchedDeviceReporting::Off {
+      let matched = matches!(
+        self.device_config_manager.find_protocol_definitions(&creator.get_specifier()),
+        Some((_, protocol, _)) if self.device_config_manager.has_protocol(&protocol)
+      );
+      if !matched {
+        self.report_unmatched_device(
+          address,
+          creator,
+          reporting == UnmatchedDeviceReporting::Inspect,
+        );
+        return;
+      }
+    }
+
+    // Some device managers (like bluetooth) can send multiple DeviceFound events for the same
+    // device, due to how things like advertisements work. We'll filter this at the
+    // DeviceManager level to make sure that even if a badly coded DCM throws multiple found
+    // events, we only listen to the first one.
+    if self.connecting_devices.contains(&address) {
+      info!(
+        "Device {} currently trying to connect, ignoring new device event.",
+        address
+      );
+      return;
+    }
+
+    self.connecting_devices.insert(address.clone());
+
+    // Make sure the device isn't on the deny list
+    if let Some(config) = self.device_user_config.get(&address) {
+      info!(
+        "Device {} has a user configuration entry, checking.",
+        address
+      );
+      if let Some(true) = config.deny() {
+        info!("Denied device address {} found, ignoring.", address);
+        return;
+      }
+    } else {
+      info!("Device {} has no user configuration entry.", address);
+    }
+
+    let mut is_allowed = true;
+    {
+      // Make sure allow list isn't active, or that the device is in the allow list if it is.
+      let mut allow_list = self
+        .device_user_config
+        .iter()
+        .filter(|x| *x.value().allow() == Some(true))
+        .peekable();
+      if allow_list.peek().is_some() {
+        if !allow_list.any(|x| *x.key() == address) {
+          info!(
+            "Allow list active and device address {} not found, ignoring.",
+            address
+          );
+          is_allowed = false;
+        } else {
+          info!("Allow list active and device address {} found.", address);
+        }
+      }
+    }
+    if !is_allowed {
+      return;
+    }
+
+    self.try_create_new_device(name, address, creator);
+  }
+
+  /// Moves comm managers that are no longer scanning to idle.
+  fn update_comm_manager_scanning_states(&mut self) {
+    for mgr in self.comm_manager_scanning_statuses.iter_mut() {
+      if mgr.state != CommManagerScanningState::Idle && !mgr.is_scanning() {
+        mgr.state = CommManagerScanningState::Idle;
+      }
+    }
+  }
+
+  /// Emits ScanningFinished if a scan is running and every comm manager is
+  /// idle. Only the first call after ScanningStarted can emit it.
+  fn check_scanning_finished(&mut self) {
+    if !self.scanning_in_progress {
+      debug!("No scan in progress, not emitting ScanningFinished.");
+      return;
+    }
+    if self
+      .comm_manager_scanning_statuses
+      .iter()
+      .any(|mgr| mgr.state != CommManagerScanningState::Idle)
+    {
+      debug!("At least one manager still scanning, continuing event loop.");
+      return;
+    }
+    debug!("All managers finished, emitting ScanningFinished");
+    self.scanning_in_progress = false;
+    if self
+      .server_sender
+      .send(ScanningFinished::default().into())
+      .is_err()
+    {
+      info!("Server disappeared, exiting loop.");
+    }
+  }
+
   async fn handle_device_communication(&mut self, event: DeviceCommunicationEvent) {
     match event {
       DeviceCommunicationEvent::ScanningStarted => {
         self.scanning_in_progress = true;
-      }
-      DeviceCommunicationEvent::ScanningFinished => {
-        debug!(
-          "System signaled that scanning was finished, check to see if all managers are finished."
-        );
-        if !self.scanning_in_progress {
-          debug!("Manager finished before scanning was fully started, continuing event loop.");
-          return;
-        }
-        if self
-          .comm_manager_scanning_statuses
-          .iter()
-          .any(|x| x.load(Ordering::SeqCst))
-        {
-          debug!("At least one manager still scanning, continuing event loop.");
-          return;
-        }
-        debug!("All managers finished, emitting ScanningFinished");
-        self.scanning_in_progress = false;
+        // Sent before checking whether scanning already finished, so clients
+        // always see ScanningStarted before the matching ScanningFinished.
         if self
           .server_sender
-          .send(ScanningFinished::default().into())
+          .send(ScanningStarted::default().into())
           .is_err()
         {
-          info!("Server disappeared, exiting loop.");
+          debug!("Server not currently available, dropping ScanningStarted event.");
+        }
+        // Comm managers that have already finished, or weren't started
+        // because they're paused, go straight to idle.
+        for mgr in self.comm_manager_scanning_statuses.iter_mut() {
+          mgr.state = if mgr.is_scanning() {
+            CommManagerScanningState::Scanning
+          } else {
+            CommManagerScanningState::Idle
+          };
         }
+        self.check_scanning_finished();
       }
-      DeviceCommunicationEvent::DeviceFound {
+      DeviceCommunicationEvent::ScanningStopped(statuses) => {
+        for mgr in self.comm_manager_scanning_statuses.iter_mut() {
+          if mgr.state == CommManagerScanningState::Scanning
+            && statuses.iter().any(|x| Arc::ptr_eq(x, &mgr.status))
+          {
+            mgr.state = CommManagerScanningState::Stopping;
+          }
+        }
+        self.update_comm_manager_scanning_states();
+        self.check_scanning_finished();
+      }
+      DeviceCommunicationEvent::ScanningFinished => {
+        debug!(
+          "System signaled that scanning was finished, check to see if all managers are finished."
+        );
+        self.update_comm_manager_scanning_states();
+        self.check_scanning_finished();
+      }
+      // Picking a device to connect counts as allowing it, so allow and deny
+      // lists aren't checked here.
+      DeviceCommunicationEvent::ConnectIdentifiedDevice {
         name,
         address,
         creator,
       } => {
-        let span = info_span!(
-          "device creation",
-          name = tracing::field::display(name),
-          address = tracing::field::display(address.clone())
-        );
-        let _enter = span.enter();
-
-        // Check to make sure the device isn't already connected. If it is, drop it.
         if self
           .device_map
           .iter()
           .any(|entry| entry.value().address() == address)
+          || self.connecting_devices.contains(&address)
         {
           debug!(
-            "Device {} already connected, ignoring new device event.",
+            "Identified device {} already connected or connecting, ignoring.",
             address
           );
           return;
         }
-
-        // Some device managers (like bluetooth) can send multiple DeviceFound events for the same
-        // device, due to how things like advertisements work. We'll filter this at the
-        // DeviceManager level to make sure that even if a badly coded DCM throws multiple found
-        // events, we only listen to the first one.
-        if self.connecting_devices.contains(&address) {
-          info!(
-            "Device {} currently trying to connect, ignoring new device event.",
-            address
-          );
-          return;
-        }
-
+        info!("Connecting identified device {}.", address);
         self.connecting_devices.insert(address.clone());
-
-        // Make sure the device isn't on the deny list
-        if let Some(config) = self.device_user_config.get(&address) {
-          info!(
-            "Device {} has a user configuration entry, checking.",
+        self.try_create_new_device(name, address, creator);
+      }
+      DeviceCommunicationEvent::DeviceFound {
+        name,
+        address,
+        creator,
+      } => {
+        self.handle_device_found(name, address, creator);
+      }
+      DeviceCommunicationEvent::BackgroundDeviceFound {
+        name,
+        address,
+        creator,
+      } => {
+        // Background scans run without anyone asking for new devices, so
+        // only connect devices the user has already allowed.
+        let known = self
+          .device_user_config
+          .get(&address)
+          .map_or(false, |config| {
+            *config.value().allow() == Some(true) && *config.value().deny() != Some(true)
+          });
+        if !known {
+          trace!(
+            "Device {} found by background scan is not allow listed, ignoring.",
             address
           );
-          if let Some(true) = config.deny() {
-            info!("Denied device address {} found, ignoring.", address);
-            return;
-          }
-        } else {
-          info!("Device {} has no user configuration entry.", address);
-        }
-
-        let mut is_allowed = true;
-        {
-          // Make sure allow list isn't active, or that the device is in the allow list if it is.
-          let mut allow_list = self
-            .device_user_config
-            .iter()
-            .filter(|x| *x.value().allow() == Some(true))
-            .peekable();
-          if allow_list.peek().is_some() {
-            if !allow_list.any(|x| *x.key() == address) {
-              info!(
-                "Allow list active and device address {} not found, ignoring.",
-                address
-              );
-              is_allowed = false;
-            } else {
-              info!("Allow list active and device address {} found.", address);
-            }
-          }
-        }
-        if !is_allowed {
           return;
         }
-
-        self.try_create_new_device(address, creator);
+        self.handle_device_found(name, address, creator);
       }
       DeviceCommunicationEvent::DeviceManagerAdded(status) => {
-        self.comm_manager_scanning_statuses.push(status);
+        // Adding a comm manager is reported asynchronously, so it may already
+        // have been started by a scan we've been told about.
+        let state = if self.scanning_in_progress && status.load(Ordering::SeqCst) {
+          CommManagerScanningState::Scanning
+        } else {
+          CommManagerScanningState::Idle
+        };
+        self
+          .comm_manager_scanning_statuses
+          .push(CommManagerScanningStatus { status, state });
+      }
+      DeviceCommunicationEvent::DeviceManagerRemoved(status) => {
+        self
+          .comm_manager_scanning_statuses
+          .retain(|x| !Arc::ptr_eq(&x.status, &status));
+        self.check_scanning_finished();
       }
     }
   }
@@ -266,8 +784,10 @@ impl DeviceManagerEventLoop {
         // message goes out, so timing matters here.
         if let Some((_, old_device)) = self.device_map.remove(&device_index) {
           info!("Device map contains key {}.", device_index);
+          self.watchdogs.device_removed(device_index);
           // After removing the device from the array, manually disconnect it to
           // make sure the event is thrown.
+          old_device.cancel_commands();
           if let Err(err) = old_device.disconnect().await {
             // If we throw an error during the disconnect, we can't really do
             // anything with it, but should at least log it.
@@ -280,8 +800,11 @@ impl DeviceManagerEventLoop {
         // Create event loop for forwarding device events into our selector.
         let mut event_listener = device.event_stream();
         let event_sender = self.device_event_sender.clone();
+        // Notification heavy devices (i.e. sensor streaming) can outrun us, so
+        // keep forwarding after a lag, otherwise we'd miss the device going
+        // away too.
         async_manager::spawn(async move {
-          while let Ok(event) = event_listener.recv().await {
+          while let Some(event) = recv_skipping_lag(&mut event_listener).await {
             event_sender
               .send(event)
               .await
@@ -290,9 +813,23 @@ impl DeviceManagerEventLoop {
         });
 
         info!("Assigning index {} to {}", device_index, device.name());
-        let device_added_message =
-          DeviceAdded::new(device_index, &device.name(), &device.message_attributes());
-        self.device_map.insert(device_index, device);
+        let mut device_added_message = DeviceAdded::new(
+          device_index,
+          &device.name(),
+          device.shared_message_attributes(),
+        );
+        device_added_message.set_device_address(Some(device.address().to_owned()));
+        if self.dry_run.load(Ordering::SeqCst) {
+          device.set_dry_run(Some((device_index, self.dry_run_write_sender.clone())));
+        }
+        {
+          let mut history = self
+            .device_list_history
+            .lock()
+            .expect("We never panic while holding this lock.");
+          history.record(device_index, device.address());
+          self.device_map.insert(device_index, device);
+        }
         // After that, we can send out to the server's event listeners to let
         // them know a device has been added.
         if self
@@ -309,38 +846,85 @@ impl DeviceManagerEventLoop {
           .get(&address)
           .expect("Index must exist to get here.")
           .value();
+        let (_, device) = {
+          let mut history = self
+            .device_list_history
+            .lock()
+            .expect("We never panic while holding this lock.");
+          history.record(device_index, &address);
+          self
+            .device_map
+            .remove(&device_index)
+            .expect("Remove will always work.")
+        };
+        // Whatever was still queued for the device has nowhere to go.
+        device.cancel_commands();
+        self.watchdogs.device_removed(device_index);
+        // Subscriptions don't survive disconnection, so reset them along with
+        // their sequence numbers.
         self
-          .device_map
-          .remove(&device_index)
-          .expect("Remove will always work.");
+          .raw_subscriptions
+          .retain(|(index, _)| *index != device_index);
+        self
+          .sensor_subscriptions
+          .retain(|(index, _)| *index != device_index);
+        self
+          .raw_reading_sequences
+          .retain(|(index, _), _| *index != device_index);
+        self
+          .raw_reading_batches
+          .retain(|(index, _), _| *index != device_index);
+        let reason = self
+          .disconnect_reasons
+          .remove(&address)
+          .map(|(_, reason)| reason)
+          .unwrap_or(DeviceRemovedReason::ConnectionLost);
         if self
           .server_sender
-          .send(DeviceRemoved::new(device_index).into())
+          .send(DeviceRemoved::new_with_reason(device_index, reason).into())
           .is_err()
         {
           debug!("Server not currently available, dropping Device Removed event.");
         }
       }
-      ButtplugDeviceEvent::Notification(_address, _endpoint, _data) => {
-        // TODO At some point here we need to fill this in for RawSubscribe and
-        // other sensor subscriptions.
+      ButtplugDeviceEvent::Notification(address, endpoint, data) => {
+        self.handle_notification(address, endpoint, data);
       }
     }
   }
 
   async fn handle_ping_timeout(&self) {
+    if !self.stop_devices_on_ping_timeout {
+      error!("Pinged out, ping timeout policy leaves devices running");
+      return;
+    }
     error!("Pinged out, stopping devices");
     let mut fut_vec = FuturesUnordered::new();
     self.device_map.iter().for_each(|dev| {
-      let device = dev.value();
-      fut_vec.push(device.parse_message(StopDeviceCmd::new(1).into()))
+      let device = dev.value().clone();
+      // Device index doesn't matter here, since we're sending the message
+      // directly to the device itself.
+      let stop_fut = device.parse_message(StopDeviceCmd::new(1).into());
+      self.watchdogs.device_stopped(*dev.key());
+      fut_vec.push(async move { (device, stop_fut.await) });
     });
+    let disconnect_reasons = self.disconnect_reasons.clone();
     async_manager::spawn(async move {
-      while let Some(val) = fut_vec.next().await {
-        // Device index doesn't matter here, since we're sending the
-        // message directly to the device itself.
-        if let Err(e) = val {
-          error!("Error stopping device on ping timeout: {}", e);
+      while let Some((device, result)) = fut_vec.next().await {
+        // Nothing is controlling the device anymore, so if it won't stop,
+        // disconnect it rather than leave it running.
+        if let Err(e) = result {
+          error!(
+            "Error stopping device on ping timeout, disconnecting: {}",
+            e
+          );
+          disconnect_reasons.insert(
+            device.address().to_owned(),
+            DeviceRemovedReason::PingTimeout,
+          );
+          if let Err(e) = device.disconnect().await {
+            error!("Error disconnecting device on ping timeout: {}", e);
+          }
         }
       }
     });
@@ -369,5 +953,19 @@ impl DeviceManagerEventLoop {
         },
       }
     }
+    // The comm managers are gone, so nothing will tell us about the devices
+    // they connected anymore.
+    for device in self.device_map.iter() {
+      if self
+        .server_sender
+        .send(
+          DeviceRemoved::new_with_reason(*device.key(), DeviceRemovedReason::CommManagerShutdown)
+            .into(),
+        )
+        .is_err()
+      {
+        debug!("Server not currently available, dropping Device Removed event.");
+      }
+    }
   }
 }
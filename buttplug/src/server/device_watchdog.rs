@@ -0,0 +1,192 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Stops devices that are left running when their client goes quiet, for
+//! connectors that don't use ping to notice a crashed client.
+
+use crate::{
+  core::messages::{self, ButtplugDeviceCommandMessageUnion, ButtplugDeviceMessageType},
+  device::ButtplugDevice,
+  util::async_manager,
+};
+use dashmap::DashMap;
+use futures_timer::Delay;
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+#[derive(Default)]
+struct DeviceWatchdogState {
+  /// Bumped on every command, so a pending stop can tell that a newer command
+  /// has arrived since it was armed.
+  generation: u32,
+  /// Features last sent a nonzero speed.
+  running: HashSet<(ButtplugDeviceMessageType, u32)>,
+}
+
+impl DeviceWatchdogState {
+  fn set_running(&mut self, message_type: ButtplugDeviceMessageType, index: u32, speed: f64) {
+    if speed > 0.0 {
+      self.running.insert((message_type, index));
+    } else {
+      self.running.remove(&(message_type, index));
+    }
+  }
+
+  /// Updates which features are running with what `message` sets them to.
+  fn update(&mut self, message: &ButtplugDeviceCommandMessageUnion, vibrator_count: u32) {
+    self.generation = self.generation.wrapping_add(1);
+    match message {
+      ButtplugDeviceCommandMessageUnion::StopDeviceCmd(_) => self.running.clear(),
+      ButtplugDeviceCommandMessageUnion::VibrateCmd(msg) => {
+        for subcommand in msg.speeds() {
+          self.set_running(
+            ButtplugDeviceMessageType::VibrateCmd,
+            subcommand.index(),
+            subcommand.speed(),
+          );
+        }
+      }
+      // Sets every vibrator to the same speed.
+      ButtplugDeviceCommandMessageUnion::SingleMotorVibrateCmd(msg) => {
+        for index in 0..vibrator_count {
+          self.set_running(ButtplugDeviceMessageType::VibrateCmd, index, msg.speed());
+        }
+      }
+      ButtplugDeviceCommandMessageUnion::RotateCmd(msg) => {
+        for subcommand in &msg.rotations {
+          self.set_running(
+            ButtplugDeviceMessageType::RotateCmd,
+            subcommand.index(),
+            subcommand.speed(),
+          );
+        }
+      }
+      ButtplugDeviceCommandMessageUnion::VorzeA10CycloneCmd(msg) => {
+        self.set_running(ButtplugDeviceMessageType::RotateCmd, 0, msg.speed() as f64)
+      }
+      ButtplugDeviceCommandMessageUnion::OscillateCmd(msg) => {
+        for subcommand in msg.speeds() {
+          self.set_running(
+            ButtplugDeviceMessageType::OscillateCmd,
+            subcommand.index(),
+            subcommand.speed(),
+          );
+        }
+      }
+      // Linear moves finish on their own, and nothing else starts an actuator.
+      _ => {}
+    }
+  }
+}
+
+/// Per device watchdogs. Every device command rearms the device's watchdog,
+/// and if no command arrives before it runs out while any vibrate, rotate or
+/// oscillate feature is running, the device is stopped. Watchdogs are only
+/// armed for devices with a watchdog timeout in their user config.
+///
+/// Clones share state, so the device manager's event loop can record the
+/// stops and removals it handles.
+#[derive(Default, Clone)]
+pub(super) struct DeviceWatchdogs {
+  states: Arc<DashMap<u32, DeviceWatchdogState>>,
+}
+
+impl DeviceWatchdogs {
+  /// Records a command being sent to a device, and arms the device's watchdog
+  /// if the device is still running afterwards.
+  pub fn command_sent(
+    &self,
+    device_index: u32,
+    device: &Arc<ButtplugDevice>,
+    message: &ButtplugDeviceCommandMessageUnion,
+    timeout_ms: Option<u32>,
+  ) {
+    let vibrator_count = device
+      .shared_message_attributes()
+      .get(&ButtplugDeviceMessageType::VibrateCmd)
+      .and_then(|attrs| attrs.feature_count)
+      .unwrap_or(0);
+    let (generation, running) = {
+      let mut state = self.states.entry(device_index).or_default();
+      state.update(message, vibrator_count);
+      (state.generation, !state.running.is_empty())
+    };
+    let timeout_ms = match timeout_ms {
+      Some(timeout_ms) if running => timeout_ms,
+      _ => return,
+    };
+    let states = self.states.clone();
+    let device = device.clone();
+    async_manager::spawn(async move {
+      Delay::new(Duration::from_millis(timeout_ms.into())).await;
+      let stop: ButtplugDeviceCommandMessageUnion =
+        messages::StopDeviceCmd::new(device_index).into();
+      {
+        let mut state = match states.get_mut(&device_index) {
+          Some(state) if state.generation == generation => state,
+          _ => return,
+        };
+        state.update(&stop, 0);
+      }
+      warn!(
+        "No commands for device {} in {}ms while it was running, stopping it.",
+        device_index, timeout_ms
+      );
+      if let Err(err) = device.parse_message(stop).await {
+        error!("Watchdog stop for device {} failed: {}", device_index, err);
+      }
+    });
+  }
+  /// Records a device being stopped some other way than a StopDeviceCmd sent
+  /// through [command_sent][DeviceWatchdogs::command_sent], so an armed
+  /// watchdog doesn't stop it again.
+  pub fn device_stopped(&self, device_index: u32) {
+    if let Some(mut state) = self.states.get_mut(&device_index) {
+      state.update(&messages::StopDeviceCmd::new(device_index).into(), 0);
+    }
+  }
+
+  /// Forgets a device that's been removed. Any armed watchdog for it won't
+  /// fire, and if it reconnects it starts out stopped.
+  pub fn device_removed(&self, device_index: u32) {
+    self.states.remove(&device_index);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::core::messages::{VibrateCmd, VibrateSubcommand};
+
+  #[test]
+  fn test_device_stopped_clears_running() {
+    let watchdogs = DeviceWatchdogs::default();
+    watchdogs.states.entry(0).or_default().update(
+      &VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]).into(),
+      1,
+    );
+    let generation = watchdogs
+      .states
+      .get(&0)
+      .expect("Test, assuming infallible")
+      .generation;
+    watchdogs.device_stopped(0);
+    let state = watchdogs.states.get(&0).expect("Test, assuming infallible");
+    assert!(state.running.is_empty());
+    // Any watchdog armed before the stop won't fire.
+    assert_ne!(state.generation, generation);
+  }
+
+  #[test]
+  fn test_device_removed_drops_state() {
+    let watchdogs = DeviceWatchdogs::default();
+    watchdogs.states.entry(0).or_default();
+    watchdogs.states.entry(1).or_default();
+    watchdogs.device_removed(0);
+    assert!(!watchdogs.states.contains_key(&0));
+    assert!(watchdogs.states.contains_key(&1));
+  }
+}
@@ -0,0 +1,212 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Limits on how many messages the client can have waiting on replies.
+//!
+//! Every client message is handled by its own future until it's replied to,
+//! so a client sending commands faster than its devices can take them would
+//! otherwise grow the server's memory without bound. Once the client has
+//! [ClientInFlightOptions::max_in_flight] messages waiting, the overload
+//! policy decides what happens to the next one.
+//!
+//! StopDeviceCmd, StopAllDevices and Ping are always let through, so an
+//! overloaded client can still stop its devices and keep its connection.
+
+use crate::{
+  core::{
+    errors::ButtplugMessageError,
+    messages::{
+      ButtplugClientMessage,
+      ButtplugDeviceCommandMessageUnion,
+      ButtplugDeviceMessage,
+      ButtplugMessage,
+    },
+  },
+  device::is_motion_command,
+};
+use std::{
+  collections::HashMap,
+  mem::{self, Discriminant},
+  sync::{Arc, Mutex},
+};
+use tokio_util::sync::CancellationToken;
+
+/// What to do with a message from a client that already has too many waiting
+/// on replies.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum ClientOverloadPolicy {
+  /// Reject the new message.
+  Reject,
+  /// If the new message is a motion command, i.e. VibrateCmd or LinearCmd,
+  /// cancel the oldest waiting command of the same type for the same device,
+  /// since the new one replaces whatever it would have done. The cancelled
+  /// command is replied to with an error, and isn't sent to the device if it
+  /// hadn't been yet. Anything else is rejected.
+  #[default]
+  CoalesceNewest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientInFlightOptions {
+  /// Number of messages the client can have waiting on replies before the
+  /// overload policy is applied.
+  pub max_in_flight: usize,
+  pub overload_policy: ClientOverloadPolicy,
+}
+
+impl Default for ClientInFlightOptions {
+  fn default() -> Self {
+    Self {
+      max_in_flight: 128,
+      overload_policy: ClientOverloadPolicy::default(),
+    }
+  }
+}
+
+/// Commands that replace each other, being of the same type and for the same
+/// device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CoalesceKey {
+  device_index: u32,
+  message_type: Discriminant<ButtplugDeviceCommandMessageUnion>,
+}
+
+struct InFlightMessage {
+  /// Order messages arrived in, so the oldest can be coalesced first.
+  sequence: u64,
+  coalesce_key: Option<CoalesceKey>,
+  /// Cancelled when a newer command replaces this one.
+  token: CancellationToken,
+}
+
+#[derive(Default)]
+struct InFlightMessagesState {
+  messages: HashMap<u32, InFlightMessage>,
+  next_sequence: u64,
+}
+
+/// Client messages that haven't been replied to yet, by id.
+pub(super) struct InFlightMessages {
+  options: ClientInFlightOptions,
+  state: Mutex<InFlightMessagesState>,
+}
+
+impl InFlightMessages {
+  pub fn new(options: ClientInFlightOptions) -> Self {
+    Self {
+      options,
+      state: Mutex::new(InFlightMessagesState::default()),
+    }
+  }
+
+  /// Marks `msg` as waiting on a reply, applying the overload policy if the
+  /// client already has too many messages waiting. Fails if the message's id
+  /// is already in use, or if the message is rejected.
+  pub fn insert(
+    self: &Arc<Self>,
+    msg: &ButtplugClientMessage,
+  ) -> Result<InFlightMessageId, ButtplugMessageError> {
+    let id = msg.id();
+    let mut state = self
+      .state
+      .lock()
+      .expect("We never panic while holding this lock.");
+    if state.messages.contains_key(&id) {
+      return Err(ButtplugMessageError::MessageIdInUse(id));
+    }
+    let coalesce_key = ButtplugDeviceCommandMessageUnion::try_from(msg.clone())
+      .ok()
+      .filter(is_motion_command)
+      .map(|device_msg| CoalesceKey {
+        device_index: device_msg.device_index(),
+        message_type: mem::discriminant(&device_msg),
+      });
+    let always_allowed = matches!(
+      msg,
+      ButtplugClientMessage::StopDeviceCmd(_)
+        | ButtplugClientMessage::StopAllDevices(_)
+        | ButtplugClientMessage::Ping(_)
+    );
+    // Replaced messages stay in the map until they're replied to, so only
+    // count the ones still waiting on their own reply.
+    let waiting_count = if always_allowed || state.messages.len() < self.options.max_in_flight {
+      0
+    } else {
+      state
+        .messages
+        .values()
+        .filter(|waiting| !waiting.token.is_cancelled())
+        .count()
+    };
+    if !always_allowed && waiting_count >= self.options.max_in_flight {
+      let replaced = match (self.options.overload_policy, coalesce_key) {
+        (ClientOverloadPolicy::CoalesceNewest, Some(key)) => state
+          .messages
+          .iter()
+          .filter(|(_, waiting)| waiting.coalesce_key == Some(key) && !waiting.token.is_cancelled())
+          .min_by_key(|(_, waiting)| waiting.sequence)
+          .map(|(replaced_id, waiting)| (*replaced_id, waiting.token.clone())),
+        _ => None,
+      };
+      match replaced {
+        Some((replaced_id, token)) => {
+          debug!(
+            "Too many messages in flight, message {} replaces message {}.",
+            id, replaced_id
+          );
+          token.cancel();
+        }
+        None => {
+          return Err(ButtplugMessageError::ServerOverloaded(waiting_count as u32));
+        }
+      }
+    }
+    let sequence = state.next_sequence;
+    state.next_sequence += 1;
+    let token = CancellationToken::new();
+    state.messages.insert(
+      id,
+      InFlightMessage {
+        sequence,
+        coalesce_key,
+        token: token.clone(),
+      },
+    );
+    Ok(InFlightMessageId {
+      id,
+      token,
+      in_flight: self.clone(),
+    })
+  }
+}
+
+/// Marks a client message id as in use until the reply to the message is
+/// sent, or the reply future is dropped.
+pub(super) struct InFlightMessageId {
+  id: u32,
+  token: CancellationToken,
+  in_flight: Arc<InFlightMessages>,
+}
+
+impl InFlightMessageId {
+  /// Cancelled if a newer command replaces this message.
+  pub fn replaced_token(&self) -> CancellationToken {
+    self.token.clone()
+  }
+}
+
+impl Drop for InFlightMessageId {
+  fn drop(&mut self) {
+    self
+      .in_flight
+      .state
+      .lock()
+      .expect("We never panic while holding this lock.")
+      .messages
+      .remove(&self.id);
+  }
+}
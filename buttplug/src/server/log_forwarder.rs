@@ -0,0 +1,148 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Forwards tracing events to connected clients as Log messages.
+//!
+//! Add a [ButtplugLogForwarder] to the application's tracing subscriber as a
+//! layer, and hand a clone of it to every server that should forward logs via
+//! [ButtplugServerBuilder::log_forwarder][super::ButtplugServerBuilder::log_forwarder].
+//! Nothing is forwarded until a client sends RequestLog with a level other than
+//! Off, and each server only forwards events at or above the level its own
+//! client asked for.
+//!
+//! Forwarded events are sent from whatever thread emitted them. If a client
+//! running in the same process logs every message it receives, requesting
+//! Trace level logs will feed those logs back to it.
+
+use crate::core::messages::{ButtplugServerMessage, Log, LogLevel};
+use std::{
+  cell::Cell,
+  fmt::{self, Write},
+  sync::{Arc, Mutex, RwLock, Weak},
+};
+use tokio::sync::broadcast;
+use tracing::{
+  field::{Field, Visit},
+  Event,
+  Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+thread_local! {
+  /// Set while an event is being forwarded, so events emitted while sending
+  /// don't get forwarded in turn.
+  static FORWARDING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Where a single server wants its logs sent.
+#[derive(Debug)]
+pub(crate) struct LogForwarderTarget {
+  level: RwLock<LogLevel>,
+  sender: broadcast::Sender<ButtplugServerMessage>,
+}
+
+impl LogForwarderTarget {
+  pub fn set_level(&self, level: LogLevel) {
+    *self
+      .level
+      .write()
+      .expect("We never panic while holding this lock.") = level;
+  }
+
+  fn forward(&self, level: &LogLevel, message: &str) {
+    if *level
+      > *self
+        .level
+        .read()
+        .expect("We never panic while holding this lock.")
+    {
+      return;
+    }
+    if self.sender.receiver_count() > 0 {
+      // If the send fails, the server is going away, and the target will be
+      // dropped along with it.
+      let _ = self.sender.send(Log::new(level.clone(), message).into());
+    }
+  }
+}
+
+/// Tracing layer that forwards events to the clients of any servers it has
+/// been added to. See the [module documentation][self] for setup.
+#[derive(Debug, Clone, Default)]
+pub struct ButtplugLogForwarder {
+  targets: Arc<Mutex<Vec<Weak<LogForwarderTarget>>>>,
+}
+
+impl ButtplugLogForwarder {
+  /// Registers a server's event sender. Logs are forwarded to it until the
+  /// returned target is dropped.
+  pub(crate) fn add_target(
+    &self,
+    sender: broadcast::Sender<ButtplugServerMessage>,
+  ) -> Arc<LogForwarderTarget> {
+    let target = Arc::new(LogForwarderTarget {
+      level: RwLock::new(LogLevel::Off),
+      sender,
+    });
+    self
+      .targets
+      .lock()
+      .expect("We never panic while holding this lock.")
+      .push(Arc::downgrade(&target));
+    target
+  }
+}
+
+impl<S: Subscriber> Layer<S> for ButtplugLogForwarder {
+  fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+    if FORWARDING.with(|forwarding| forwarding.replace(true)) {
+      return;
+    }
+    let targets: Vec<Arc<LogForwarderTarget>> = {
+      let mut targets = self
+        .targets
+        .lock()
+        .expect("We never panic while holding this lock.");
+      targets.retain(|target| target.strong_count() > 0);
+      targets
+        .iter()
+        .filter_map(|target| target.upgrade())
+        .collect()
+    };
+    if !targets.is_empty() {
+      let level = LogLevel::from(*event.metadata().level());
+      let mut visitor = LogMessageVisitor::default();
+      event.record(&mut visitor);
+      let message = format!("{}: {}", event.metadata().target(), visitor.message);
+      for target in targets {
+        target.forward(&level, &message);
+      }
+    }
+    FORWARDING.with(|forwarding| forwarding.set(false));
+  }
+}
+
+/// Builds a log line out of an event's message and fields, in the same
+/// `message key=value` shape the fmt subscriber uses.
+#[derive(Default)]
+struct LogMessageVisitor {
+  message: String,
+}
+
+impl Visit for LogMessageVisitor {
+  fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+    if !self.message.is_empty() {
+      self.message.push(' ');
+    }
+    // Writing to a String can't fail.
+    let _ = if field.name() == "message" {
+      write!(self.message, "{:?}", value)
+    } else {
+      write!(self.message, "{}={:?}", field.name(), value)
+    };
+  }
+}
@@ -0,0 +1,206 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Per device command metrics, for figuring out why a device seems laggy.
+//!
+//! Every device command that goes through
+//! [ButtplugServer::parse_message][super::ButtplugServer::parse_message] is
+//! timed from the moment the server receives it until the device finishes with
+//! it, which includes time spent waiting in the device's command queue.
+
+use dashmap::DashMap;
+use std::{
+  collections::VecDeque,
+  time::{Duration, Instant},
+};
+
+/// Upper bounds, in milliseconds, of the command round trip time histogram
+/// buckets. Commands slower than the last bound land in an extra overflow
+/// bucket.
+pub const LATENCY_BUCKET_BOUNDS_MS: [u64; 11] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 5000];
+
+/// Commands per second is averaged over this window.
+const COMMAND_RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Histogram of command round trip times.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+  /// Command counts per bucket. Entry `i` counts commands that took at most
+  /// `LATENCY_BUCKET_BOUNDS_MS[i]` milliseconds (and more than the previous
+  /// bound), and the last entry counts commands slower than every bound.
+  pub buckets: [u64; LATENCY_BUCKET_BOUNDS_MS.len() + 1],
+  /// Sum of all recorded round trip times, in milliseconds.
+  pub total_ms: u64,
+  /// Slowest recorded round trip time, in milliseconds.
+  pub max_ms: u64,
+}
+
+impl Default for LatencyHistogram {
+  fn default() -> Self {
+    Self {
+      buckets: [0; LATENCY_BUCKET_BOUNDS_MS.len() + 1],
+      total_ms: 0,
+      max_ms: 0,
+    }
+  }
+}
+
+impl LatencyHistogram {
+  fn record(&mut self, elapsed_ms: u64) {
+    let bucket = LATENCY_BUCKET_BOUNDS_MS
+      .iter()
+      .position(|bound| elapsed_ms <= *bound)
+      .unwrap_or(LATENCY_BUCKET_BOUNDS_MS.len());
+    self.buckets[bucket] += 1;
+    self.total_ms += elapsed_ms;
+    self.max_ms = self.max_ms.max(elapsed_ms);
+  }
+
+  /// Number of commands recorded.
+  pub fn count(&self) -> u64 {
+    self.buckets.iter().sum()
+  }
+
+  /// Average round trip time in milliseconds, or None if nothing has been
+  /// recorded.
+  pub fn mean_ms(&self) -> Option<f64> {
+    match self.count() {
+      0 => None,
+      count => Some(self.total_ms as f64 / count as f64),
+    }
+  }
+}
+
+/// Command metrics for a single device, retrieved via
+/// [ButtplugServer::metrics][super::ButtplugServer::metrics].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceMetrics {
+  pub device_index: u32,
+  /// Commands the device has finished, including ones that failed.
+  pub command_count: u64,
+  /// Commands that returned an error.
+  pub error_count: u64,
+  /// Commands finished per second, averaged over the last 10 seconds.
+  pub commands_per_second: f64,
+  pub latency: LatencyHistogram,
+}
+
+#[derive(Default)]
+struct DeviceMetricsState {
+  command_count: u64,
+  error_count: u64,
+  latency: LatencyHistogram,
+  /// Finish times of commands within the command rate window.
+  recent_commands: VecDeque<Instant>,
+}
+
+impl DeviceMetricsState {
+  fn trim_recent_commands(&mut self, now: Instant) {
+    while let Some(oldest) = self.recent_commands.front() {
+      if now.duration_since(*oldest) <= COMMAND_RATE_WINDOW {
+        break;
+      }
+      self.recent_commands.pop_front();
+    }
+  }
+}
+
+/// Collects command metrics for every device the server has sent commands to.
+/// Device indexes are reused when a device reconnects, so metrics carry over
+/// across reconnects.
+#[derive(Default)]
+pub(crate) struct MetricsRecorder {
+  devices: DashMap<u32, DeviceMetricsState>,
+}
+
+impl MetricsRecorder {
+  pub fn record(&self, device_index: u32, elapsed: Duration, succeeded: bool) {
+    let now = Instant::now();
+    let mut state = self.devices.entry(device_index).or_default();
+    state.command_count += 1;
+    if !succeeded {
+      state.error_count += 1;
+    }
+    state.latency.record(elapsed.as_millis() as u64);
+    state.recent_commands.push_back(now);
+    state.trim_recent_commands(now);
+  }
+
+  pub fn snapshot(&self) -> Vec<DeviceMetrics> {
+    let now = Instant::now();
+    let mut metrics: Vec<DeviceMetrics> = self
+      .devices
+      .iter_mut()
+      .map(|mut entry| {
+        let device_index = *entry.key();
+        let state = entry.value_mut();
+        state.trim_recent_commands(now);
+        DeviceMetrics {
+          device_index,
+          command_count: state.command_count,
+          error_count: state.error_count,
+          commands_per_second: state.recent_commands.len() as f64
+            / COMMAND_RATE_WINDOW.as_secs_f64(),
+          latency: state.latency.clone(),
+        }
+      })
+      .collect();
+    metrics.sort_by_key(|device| device.device_index);
+    metrics
+  }
+
+  pub fn log_metrics(&self) {
+    for device in self.snapshot() {
+      info!(
+        "Device {} metrics: {} commands, {} errors, {:.1} commands/sec, mean latency {:.1}ms, max latency {}ms",
+        device.device_index,
+        device.command_count,
+        device.error_count,
+        device.commands_per_second,
+        device.latency.mean_ms().unwrap_or(0.0),
+        device.latency.max_ms
+      );
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_latency_histogram_buckets() {
+    let mut histogram = LatencyHistogram::default();
+    histogram.record(0);
+    histogram.record(1);
+    histogram.record(7);
+    histogram.record(5000);
+    histogram.record(60000);
+    assert_eq!(histogram.buckets[0], 2);
+    assert_eq!(histogram.buckets[3], 1);
+    assert_eq!(histogram.buckets[LATENCY_BUCKET_BOUNDS_MS.len() - 1], 1);
+    assert_eq!(histogram.buckets[LATENCY_BUCKET_BOUNDS_MS.len()], 1);
+    assert_eq!(histogram.count(), 5);
+    assert_eq!(histogram.max_ms, 60000);
+    assert_eq!(histogram.mean_ms(), Some(65008.0 / 5.0));
+  }
+
+  #[test]
+  fn test_metrics_recorder_counts() {
+    let recorder = MetricsRecorder::default();
+    recorder.record(1, Duration::from_millis(3), true);
+    recorder.record(1, Duration::from_millis(30), false);
+    recorder.record(0, Duration::from_millis(3), true);
+    let metrics = recorder.snapshot();
+    assert_eq!(metrics.len(), 2);
+    assert_eq!(metrics[0].device_index, 0);
+    assert_eq!(metrics[1].command_count, 2);
+    assert_eq!(metrics[1].error_count, 1);
+    assert_eq!(metrics[1].latency.count(), 2);
+    assert!(metrics[1].commands_per_second > 0.0);
+  }
+}
@@ -7,11 +7,23 @@
 
 //! Handles client sessions, as well as discovery and communication with hardware.
 
+pub mod client_permissions;
 pub mod comm_managers;
+#[cfg(feature = "forwarded-device-manager")]
+pub mod device_forwarder;
 pub mod device_manager;
 mod device_manager_event_loop;
+mod device_watchdog;
+pub mod in_flight_messages;
+pub mod log_forwarder;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 mod ping_timer;
 pub mod remote_server;
+#[cfg(feature = "serialize-json")]
+pub mod runner;
+#[cfg(feature = "serialize-json")]
+pub mod session_recording;
 
 pub use remote_server::ButtplugRemoteServer;
 
@@ -23,28 +35,62 @@ use crate::{
       ButtplugClientMessage,
       ButtplugDeviceCommandMessageUnion,
       ButtplugDeviceManagerMessageUnion,
+      ButtplugDeviceMessage,
       ButtplugMessage,
+      ButtplugMessageSpecVersion,
       ButtplugServerMessage,
+      PingTimeout,
+      PingTimeoutPolicy,
       StopAllDevices,
       StopScanning,
       BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+      BUTTPLUG_SERVER_EVENT_ID,
     },
+    ButtplugResultFuture,
   },
+  device::DeviceCommandQueueOptions,
   util::{
     async_manager,
-    device_configuration::{load_protocol_config_from_json, DEVICE_CONFIGURATION_JSON},
+    device_configuration::{
+      load_protocol_config_from_file,
+      load_protocol_config_from_json,
+      DEVICE_CONFIGURATION_JSON,
+    },
     stream::convert_broadcast_receiver_to_stream,
+    time::unix_time_millis,
   },
 };
-use device_manager::DeviceManager;
+use client_permissions::ClientPermissions;
+use comm_managers::DeviceCommunicationManagerBuilder;
+use dashmap::DashMap;
+use device_manager::{CommManagerSnapshot, DeviceConnectionOptions, DeviceManager, DeviceSnapshot};
 use futures::{
   future::{self, BoxFuture},
+  FutureExt,
   Stream,
+  StreamExt,
 };
+use futures_timer::Delay;
+use in_flight_messages::{ClientInFlightOptions, InFlightMessages};
+use log_forwarder::{ButtplugLogForwarder, LogForwarderTarget};
+#[cfg(feature = "metrics")]
+use metrics::{DeviceMetrics, MetricsRecorder};
 use ping_timer::PingTimer;
-use std::sync::{
-  atomic::{AtomicBool, Ordering},
-  Arc,
+#[cfg(feature = "serialize-json")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "serialize-json")]
+use session_recording::SessionRecorder;
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  fmt,
+  path::PathBuf,
+  sync::{
+    atomic::{AtomicBool, AtomicU32, Ordering},
+    Arc,
+    Mutex,
+    RwLock,
+  },
+  time::Duration,
 };
 use thiserror::Error;
 use tokio::sync::broadcast;
@@ -53,14 +99,68 @@ use tracing_futures::Instrument;
 pub type ButtplugServerResult = Result<ButtplugServerMessage, ButtplugError>;
 pub type ButtplugServerResultFuture = BoxFuture<'static, ButtplugServerResult>;
 
+/// Number of errors kept around for [ServerStateSnapshot::last_errors].
+const MAX_SNAPSHOT_ERRORS: usize = 10;
+
+/// Default for [ButtplugServerBuilder::device_initialization_timeout], in
+/// milliseconds.
+const DEFAULT_DEVICE_INITIALIZATION_TIMEOUT: u32 = 30000;
+
+/// Default for [ButtplugServerBuilder::device_connection_retries].
+const DEFAULT_DEVICE_CONNECTION_RETRIES: u32 = 2;
+
 #[derive(Error, Debug)]
 pub enum ButtplugServerError {
   #[error("DeviceManager of type {0} has already been added.")]
   DeviceManagerTypeAlreadyAdded(String),
+  #[error("DeviceManager of type {0} has not been added.")]
+  DeviceManagerTypeNotFound(String),
   #[error("Buttplug Protocol of type {0} has already been added to the system.")]
   ProtocolAlreadyAdded(String),
   #[error("Buttplug Protocol of type {0} does not exist in the system and cannot be removed.")]
   ProtocolDoesNotExist(String),
+  #[error("Session recording error: {0}")]
+  SessionRecordingError(String),
+}
+
+/// Point in time view of everything a host UI would want to show about a
+/// server, retrieved via [ButtplugServer::state_snapshot]. Serializable so
+/// front-ends running out of process can be sent the whole thing.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct ServerStateSnapshot {
+  pub server_name: String,
+  /// True if a client has completed the handshake and is still connected.
+  pub connected: bool,
+  /// Name of the connected client, if there is one.
+  pub client_name: Option<String>,
+  /// Message spec version the connected client asked for, if there is one.
+  pub client_spec_version: Option<ButtplugMessageSpecVersion>,
+  /// Maximum time between client pings in milliseconds, 0 if pings aren't
+  /// required.
+  pub max_ping_time: u32,
+  /// True if the last client was disconnected for missing a ping.
+  pub pinged_out: bool,
+  /// True if any comm manager is currently scanning.
+  pub scanning: bool,
+  pub devices: Vec<DeviceSnapshot>,
+  pub comm_managers: Vec<CommManagerSnapshot>,
+  /// Most recent errors returned to the client, oldest first.
+  pub last_errors: Vec<messages::Error>,
+}
+
+/// Creates a comm manager and adds it to a device manager. Stored as a
+/// factory, rather than a comm manager builder, so the server builder can be
+/// finished more than once.
+type CommManagerFactoryFn = dyn Fn(&DeviceManager) -> Result<(), ButtplugServerError> + Send + Sync;
+
+#[derive(Clone)]
+struct CommManagerFactory(Arc<CommManagerFactoryFn>);
+
+impl fmt::Debug for CommManagerFactory {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("CommManagerFactory")
+  }
 }
 
 #[derive(Debug, Clone)]
@@ -69,7 +169,27 @@ pub struct ButtplugServerBuilder {
   pub max_ping_time: Option<u32>,
   pub allow_raw_messages: bool,
   pub device_configuration_json: Option<String>,
+  pub device_configuration_file: Option<PathBuf>,
   pub user_device_configuration_json: Option<String>,
+  pub raw_reading_batch_window: Option<u32>,
+  pub stop_devices_on_disconnect: bool,
+  pub disconnect_stop_grace_period: u32,
+  pub ping_timeout_policy: PingTimeoutPolicy,
+  pub ping_timeout_grace_period: u32,
+  pub device_command_queue_options: DeviceCommandQueueOptions,
+  pub device_initialization_timeout: Option<u32>,
+  pub device_connection_retries: u32,
+  pub background_scanning: bool,
+  pub dry_run: bool,
+  #[cfg(feature = "metrics")]
+  pub metrics_log_interval: Option<u32>,
+  pub log_forwarder: Option<ButtplugLogForwarder>,
+  #[cfg(feature = "serialize-json")]
+  pub session_recorder: Option<SessionRecorder>,
+  pub client_permissions: HashMap<String, ClientPermissions>,
+  pub default_client_permissions: ClientPermissions,
+  pub client_in_flight_options: ClientInFlightOptions,
+  comm_managers: Vec<CommManagerFactory>,
 }
 
 impl Default for ButtplugServerBuilder {
@@ -79,7 +199,27 @@ impl Default for ButtplugServerBuilder {
       max_ping_time: None,
       allow_raw_messages: false,
       device_configuration_json: Some(DEVICE_CONFIGURATION_JSON.to_owned()),
+      device_configuration_file: None,
       user_device_configuration_json: None,
+      raw_reading_batch_window: None,
+      stop_devices_on_disconnect: true,
+      disconnect_stop_grace_period: 0,
+      ping_timeout_policy: PingTimeoutPolicy::default(),
+      ping_timeout_grace_period: 0,
+      device_command_queue_options: DeviceCommandQueueOptions::default(),
+      device_initialization_timeout: Some(DEFAULT_DEVICE_INITIALIZATION_TIMEOUT),
+      device_connection_retries: DEFAULT_DEVICE_CONNECTION_RETRIES,
+      background_scanning: false,
+      dry_run: false,
+      #[cfg(feature = "metrics")]
+      metrics_log_interval: None,
+      log_forwarder: None,
+      #[cfg(feature = "serialize-json")]
+      session_recorder: None,
+      client_permissions: HashMap::new(),
+      default_client_permissions: ClientPermissions::default(),
+      client_in_flight_options: ClientInFlightOptions::default(),
+      comm_managers: vec![],
     }
   }
 }
@@ -105,11 +245,170 @@ impl ButtplugServerBuilder {
     self
   }
 
+  /// Path to a main device config file, i.e. an updated config downloaded
+  /// separately from the library, which is merged over the device
+  /// configuration JSON when the server is built.
+  pub fn device_configuration_file(&mut self, path: Option<PathBuf>) -> &mut Self {
+    self.device_configuration_file = path;
+    self
+  }
+
   pub fn user_device_configuration_json(&mut self, config_json: Option<String>) -> &mut Self {
     self.user_device_configuration_json = config_json;
     self
   }
 
+  /// If set, data from subscribed endpoints is collected for this many
+  /// milliseconds before being sent out, instead of being emitted as soon as
+  /// it arrives. Each notification is still sent as its own RawReading, with
+  /// its own sequence number.
+  pub fn raw_reading_batch_window(&mut self, window_ms: u32) -> &mut Self {
+    self.raw_reading_batch_window = Some(window_ms);
+    self
+  }
+
+  /// If true (the default), StopAllDevices is run when the client
+  /// disconnects, so devices don't keep running with nothing controlling them.
+  pub fn stop_devices_on_disconnect(&mut self, stop: bool) -> &mut Self {
+    self.stop_devices_on_disconnect = stop;
+    self
+  }
+
+  /// Wait this many milliseconds after a client disconnects before stopping
+  /// devices. If a client finishes a new handshake within the grace period,
+  /// devices are left running, so quick reconnects don't interrupt whatever
+  /// was happening.
+  pub fn disconnect_stop_grace_period(&mut self, grace_period_ms: u32) -> &mut Self {
+    self.disconnect_stop_grace_period = grace_period_ms;
+    self
+  }
+
+  /// What to do when the client misses its ping deadline. Defaults to
+  /// [PingTimeoutPolicy::StopDevicesAndDisconnect].
+  pub fn ping_timeout_policy(&mut self, policy: PingTimeoutPolicy) -> &mut Self {
+    self.ping_timeout_policy = policy;
+    self
+  }
+
+  /// Extra milliseconds to wait after a missed ping deadline before applying
+  /// the ping timeout policy. A ping received during the grace period cancels
+  /// the timeout.
+  pub fn ping_timeout_grace_period(&mut self, grace_period_ms: u32) -> &mut Self {
+    self.ping_timeout_grace_period = grace_period_ms;
+    self
+  }
+
+  /// How many commands each device can have waiting to be sent, and what to
+  /// do when more arrive. Defaults to [DeviceCommandQueueOptions::default].
+  pub fn device_command_queue_options(&mut self, options: DeviceCommandQueueOptions) -> &mut Self {
+    self.device_command_queue_options = options;
+    self
+  }
+
+  /// How many milliseconds a device gets to connect and initialize before
+  /// it's given up on and a DeviceInitializationFailed event is sent. Defaults
+  /// to 30 seconds. None waits forever.
+  pub fn device_initialization_timeout(&mut self, timeout_ms: Option<u32>) -> &mut Self {
+    self.device_initialization_timeout = timeout_ms;
+    self
+  }
+
+  /// How many more times to try connecting a device when a comm manager
+  /// reports it as busy or timed out. Retries count against
+  /// [ButtplugServerBuilder::device_initialization_timeout]. Defaults to 2.
+  pub fn device_connection_retries(&mut self, retries: u32) -> &mut Self {
+    self.device_connection_retries = retries;
+    self
+  }
+
+  /// If true, comm managers that support it (currently bluetooth) keep a low
+  /// power scan running, and connect allow listed devices as they show up, so
+  /// users don't need to start a scan every time they turn a device on.
+  /// Defaults to false. See [DeviceManager::set_background_scanning].
+  pub fn background_scanning(&mut self, enabled: bool) -> &mut Self {
+    self.background_scanning = enabled;
+    self
+  }
+
+  /// If true, device commands are processed as usual, but writes are reported
+  /// instead of being sent to devices, so apps can check what bytes would be
+  /// sent without hardware attached. Defaults to false. See
+  /// [DeviceManager::set_dry_run].
+  pub fn dry_run(&mut self, dry_run: bool) -> &mut Self {
+    self.dry_run = dry_run;
+    self
+  }
+
+  /// If set, device command metrics (see [ButtplugServer::metrics]) are logged
+  /// every this many milliseconds.
+  #[cfg(feature = "metrics")]
+  pub fn metrics_log_interval(&mut self, interval_ms: u32) -> &mut Self {
+    self.metrics_log_interval = Some(interval_ms);
+    self
+  }
+
+  /// Lets clients request the server's logs with RequestLog. The forwarder
+  /// also needs to be added to the application's tracing subscriber, see
+  /// [log_forwarder] for details.
+  pub fn log_forwarder(&mut self, forwarder: ButtplugLogForwarder) -> &mut Self {
+    self.log_forwarder = Some(forwarder);
+    self
+  }
+
+  /// Records every message the server receives, so the session can be
+  /// replayed later, see [session_recording] for details.
+  #[cfg(feature = "serialize-json")]
+  pub fn session_recorder(&mut self, recorder: SessionRecorder) -> &mut Self {
+    self.session_recorder = Some(recorder);
+    self
+  }
+
+  /// Limits what the client with this name can do, see [client_permissions]
+  /// for details. Overrides permissions for the same client in the user
+  /// device config.
+  pub fn client_permissions(
+    &mut self,
+    client_name: &str,
+    permissions: ClientPermissions,
+  ) -> &mut Self {
+    self
+      .client_permissions
+      .insert(client_name.to_owned(), permissions);
+    self
+  }
+
+  /// Permissions for clients that don't have their own. Defaults to allowing
+  /// everything.
+  pub fn default_client_permissions(&mut self, permissions: ClientPermissions) -> &mut Self {
+    self.default_client_permissions = permissions;
+    self
+  }
+
+  /// How many messages the client can have waiting on replies, and what to do
+  /// when more arrive. Defaults to [ClientInFlightOptions::default]. See
+  /// [in_flight_messages] for details.
+  pub fn client_in_flight_options(&mut self, options: ClientInFlightOptions) -> &mut Self {
+    self.client_in_flight_options = options;
+    self
+  }
+
+  /// Adds a comm manager to the server when it is built. Takes a function that
+  /// creates the comm manager builder, e.g.
+  /// `BtlePlugCommunicationManagerBuilder::default`, as a new comm manager is
+  /// needed each time [ButtplugServerBuilder::finish] is called.
+  pub fn comm_manager<T, F>(&mut self, builder_fn: F) -> &mut Self
+  where
+    T: DeviceCommunicationManagerBuilder,
+    F: Fn() -> T + Send + Sync + 'static,
+  {
+    self
+      .comm_managers
+      .push(CommManagerFactory(Arc::new(move |device_manager| {
+        device_manager.add_comm_manager(builder_fn())
+      })));
+    self
+  }
+
   pub fn finish(&self) -> Result<ButtplugServer, ButtplugError> {
     // If the user config string exists, parse it.
     let user_config = if let Some(user_device_config) = &self.user_device_configuration_json {
@@ -119,8 +418,26 @@ impl ButtplugServerBuilder {
     };
 
     // If the device config string exists, parse it.
-    let device_config = if let Some(main_device_config) = &self.device_configuration_json {
-      let mut main_config = load_protocol_config_from_json(main_device_config)?;
+    let mut main_config = if let Some(main_device_config) = &self.device_configuration_json {
+      Some(load_protocol_config_from_json(main_device_config)?)
+    } else {
+      None
+    };
+
+    // If there's an external device config file, merge it over the device
+    // config string.
+    if let Some(path) = &self.device_configuration_file {
+      let external_config = load_protocol_config_from_file(path)?;
+      main_config = match main_config {
+        Some(mut main_config) => {
+          main_config.merge_main_config(external_config);
+          Some(main_config)
+        }
+        None => Some(external_config),
+      };
+    }
+
+    let device_config = if let Some(mut main_config) = main_config {
       if let Some(user_config) = user_config {
         main_config.merge(user_config);
       }
@@ -135,28 +452,78 @@ impl ButtplugServerBuilder {
     let output_sender_clone = send.clone();
     let connected = Arc::new(AtomicBool::new(false));
     let ping_time = self.max_ping_time.unwrap_or(0);
-    let ping_timer = Arc::new(PingTimer::new(ping_time));
-    let ping_timeout_notifier = ping_timer.ping_timeout_waiter();
+    let ping_timeout_policy = self.ping_timeout_policy;
+    let ping_timer = Arc::new(PingTimer::new(
+      ping_time,
+      self.ping_timeout_grace_period,
+      ping_timeout_policy.disconnects_client(),
+    ));
+    let ping_timer_clone = ping_timer.clone();
     let connected_clone = connected.clone();
     async_manager::spawn(
       async move {
-        // This will only exit if we've pinged out.
-        ping_timeout_notifier.await;
-        error!("Ping out signal received, stopping server");
-        connected_clone.store(false, Ordering::SeqCst);
-        // TODO Should the event sender return a result instead of an error message?
-        if output_sender_clone
-          .send(messages::Error::from(ButtplugError::from(ButtplugPingError::PingedOut)).into())
-          .is_err()
-        {
-          error!("Server disappeared, cannot update about ping out.");
-        };
+        // If the policy disconnects the client, this only runs once, as the
+        // ping timer exits after timing out. Otherwise, keep listening, as the
+        // timer rearms on the next ping.
+        loop {
+          ping_timer_clone.ping_timeout_waiter().await;
+          error!(
+            "Ping out signal received, applying policy {:?}",
+            ping_timeout_policy
+          );
+          if ping_timeout_policy.disconnects_client() {
+            connected_clone.store(false, Ordering::SeqCst);
+            // TODO Should the event sender return a result instead of an error message?
+            if output_sender_clone
+              .send(messages::Error::from(ButtplugError::from(ButtplugPingError::PingedOut)).into())
+              .is_err()
+            {
+              error!("Server disappeared, cannot update about ping out.");
+            };
+          }
+          if output_sender_clone
+            .send(PingTimeout::new(ping_timeout_policy).into())
+            .is_err()
+          {
+            error!("Server disappeared, cannot update about ping timeout policy.");
+          }
+          if ping_timeout_policy.disconnects_client() {
+            break;
+          }
+        }
       }
       .instrument(tracing::info_span!("Buttplug Server Ping Timeout Task")),
     );
-    let device_manager =
-      DeviceManager::new(send.clone(), ping_timer.clone(), self.allow_raw_messages);
+    let device_manager = DeviceManager::new(
+      send.clone(),
+      ping_timer.clone(),
+      self.allow_raw_messages,
+      self.raw_reading_batch_window,
+      ping_timeout_policy.stops_devices(),
+      self.device_command_queue_options,
+      DeviceConnectionOptions {
+        initialization_timeout: self.device_initialization_timeout,
+        retries: self.device_connection_retries,
+      },
+    );
 
+    for factory in &self.comm_managers {
+      (factory.0)(&device_manager)
+        .map_err(|e| ButtplugDeviceError::DeviceCommunicationError(e.to_string()))?;
+    }
+
+    if self.background_scanning {
+      let background_fut = device_manager.set_background_scanning(true);
+      async_manager::spawn(async move {
+        if let Err(e) = background_fut.await {
+          error!("Error starting background scanning: {:?}", e);
+        }
+      });
+    }
+
+    device_manager.set_dry_run(self.dry_run);
+
+    let client_permissions = DashMap::new();
     if let Some(devices) = device_config {
       for (name, def) in devices.protocols {
         device_manager.add_protocol_definition(&name, def);
@@ -164,8 +531,39 @@ impl ButtplugServerBuilder {
       for (address, user_config) in devices.user_config {
         device_manager.add_device_user_config(&address, user_config);
       }
+      for (client_name, permissions) in devices.client_permissions {
+        client_permissions.insert(client_name, permissions);
+      }
+    }
+    for (client_name, permissions) in &self.client_permissions {
+      client_permissions.insert(client_name.clone(), permissions.clone());
     }
 
+    #[cfg(feature = "metrics")]
+    let metrics = Arc::new(MetricsRecorder::default());
+    #[cfg(feature = "metrics")]
+    if let Some(interval) = self.metrics_log_interval {
+      // Only hold a weak reference, so the task stops once the server is gone.
+      let metrics = Arc::downgrade(&metrics);
+      async_manager::spawn(
+        async move {
+          loop {
+            Delay::new(Duration::from_millis(interval.into())).await;
+            match metrics.upgrade() {
+              Some(metrics) => metrics.log_metrics(),
+              None => break,
+            }
+          }
+        }
+        .instrument(tracing::info_span!("Buttplug Server Metrics Log Task")),
+      );
+    }
+
+    let log_target = self
+      .log_forwarder
+      .as_ref()
+      .map(|forwarder| forwarder.add_target(send.clone()));
+
     let server = ButtplugServer {
       server_name: self.name.clone(),
       max_ping_time: ping_time,
@@ -173,6 +571,21 @@ impl ButtplugServerBuilder {
       ping_timer,
       connected,
       output_sender: send,
+      client_name: Arc::new(RwLock::new(None)),
+      client_spec_version: Arc::new(RwLock::new(None)),
+      last_errors: Arc::new(Mutex::new(VecDeque::new())),
+      stop_devices_on_disconnect: self.stop_devices_on_disconnect,
+      disconnect_stop_grace_period: self.disconnect_stop_grace_period,
+      connection_generation: Arc::new(AtomicU32::new(0)),
+      device_claims: Arc::new(DashMap::new()),
+      in_flight_messages: Arc::new(InFlightMessages::new(self.client_in_flight_options)),
+      client_permissions: Arc::new(client_permissions),
+      default_client_permissions: self.default_client_permissions.clone(),
+      log_target,
+      #[cfg(feature = "metrics")]
+      metrics,
+      #[cfg(feature = "serialize-json")]
+      session_recorder: self.session_recorder.clone(),
     };
 
     // Add the device config
@@ -192,6 +605,42 @@ pub struct ButtplugServer {
   ping_timer: Arc<PingTimer>,
   connected: Arc<AtomicBool>,
   output_sender: broadcast::Sender<ButtplugServerMessage>,
+  client_name: Arc<RwLock<Option<String>>>,
+  /// Spec version the connected client asked for in its handshake.
+  client_spec_version: Arc<RwLock<Option<ButtplugMessageSpecVersion>>>,
+  last_errors: Arc<Mutex<VecDeque<messages::Error>>>,
+  stop_devices_on_disconnect: bool,
+  disconnect_stop_grace_period: u32,
+  /// Bumped on every successful handshake, so a delayed stop from an earlier
+  /// disconnect can tell that a client has connected since.
+  connection_generation: Arc<AtomicU32>,
+  /// Device index to name of the client holding a claim on it.
+  device_claims: Arc<DashMap<u32, String>>,
+  /// Client messages that haven't been replied to yet.
+  in_flight_messages: Arc<InFlightMessages>,
+  /// Client name to the permissions set for it.
+  client_permissions: Arc<DashMap<String, ClientPermissions>>,
+  default_client_permissions: ClientPermissions,
+  /// Set if the server was built with a log forwarder.
+  log_target: Option<Arc<LogForwarderTarget>>,
+  #[cfg(feature = "metrics")]
+  metrics: Arc<MetricsRecorder>,
+  #[cfg(feature = "serialize-json")]
+  session_recorder: Option<SessionRecorder>,
+}
+
+/// Events added in spec v3, which older clients don't know how to parse.
+fn is_spec_v3_event(msg: &ButtplugServerMessage) -> bool {
+  matches!(
+    msg,
+    ButtplugServerMessage::ScanningStarted(_)
+      | ButtplugServerMessage::DeviceClaimed(_)
+      | ButtplugServerMessage::DeviceReleased(_)
+      | ButtplugServerMessage::PingTimeout(_)
+      | ButtplugServerMessage::ScanningPartialFailure(_)
+      | ButtplugServerMessage::DeviceInitializationFailed(_)
+      | ButtplugServerMessage::SensorReading(_)
+  )
 }
 
 impl Default for ButtplugServer {
@@ -204,10 +653,57 @@ impl Default for ButtplugServer {
 }
 
 impl ButtplugServer {
+  /// Events for the connected client. Events from spec versions newer than
+  /// the client's, like ScanningStarted, are left out, as are any sent before
+  /// the client has said which spec version it speaks.
+  ///
+  /// Devices the client isn't permitted to use are left out too, see
+  /// [client_permissions].
   pub fn event_stream(&self) -> impl Stream<Item = ButtplugServerMessage> {
+    let client_spec_version = self.client_spec_version.clone();
+    let client_name = self.client_name.clone();
+    let client_permissions = self.client_permissions.clone();
+    let default_client_permissions = self.default_client_permissions.clone();
+    // Indexes of devices the client wasn't told about, so it isn't told about
+    // their removal either.
+    let mut hidden_devices = HashSet::new();
     // Unlike the client API, we can expect anyone using the server to pin this
     // themselves.
     convert_broadcast_receiver_to_stream(self.output_sender.subscribe())
+      .filter(move |msg| {
+        let supported = !is_spec_v3_event(msg)
+          || client_spec_version
+            .read()
+            .expect("We never panic while holding this lock.")
+            .is_some_and(|version| version >= ButtplugMessageSpecVersion::Version3);
+        future::ready(supported)
+      })
+      .filter(move |msg| {
+        let permitted = match msg {
+          ButtplugServerMessage::DeviceAdded(device_added) => {
+            let permissions = permissions_for_client(
+              &client_name,
+              &client_permissions,
+              &default_client_permissions,
+            );
+            let permitted = match (permissions, device_added.device_address()) {
+              (Some(permissions), Some(address)) => permissions.allows_device(address),
+              _ => true,
+            };
+            if permitted {
+              hidden_devices.remove(&device_added.device_index());
+            } else {
+              hidden_devices.insert(device_added.device_index());
+            }
+            permitted
+          }
+          ButtplugServerMessage::DeviceRemoved(device_removed) => {
+            !hidden_devices.remove(&device_removed.device_index())
+          }
+          _ => true,
+        };
+        future::ready(permitted)
+      })
   }
 
   pub fn device_manager(&self) -> &DeviceManager {
@@ -218,29 +714,158 @@ impl ButtplugServer {
     self.connected.load(Ordering::SeqCst)
   }
 
+  /// Gathers current server state in one call, so simple host applications
+  /// can poll it instead of tracking the event stream.
+  pub fn state_snapshot(&self) -> ServerStateSnapshot {
+    let comm_managers = self.device_manager.comm_manager_snapshots();
+    ServerStateSnapshot {
+      server_name: self.server_name.clone(),
+      connected: self.connected(),
+      client_name: self
+        .client_name
+        .read()
+        .expect("We never panic while holding this lock.")
+        .clone(),
+      client_spec_version: *self
+        .client_spec_version
+        .read()
+        .expect("We never panic while holding this lock."),
+      max_ping_time: self.ping_timer.max_ping_time(),
+      pinged_out: self.ping_timer.pinged_out(),
+      scanning: comm_managers.iter().any(|mgr| mgr.scanning),
+      devices: self.device_manager.device_snapshots(),
+      comm_managers,
+      last_errors: self
+        .last_errors
+        .lock()
+        .expect("We never panic while holding this lock.")
+        .iter()
+        .cloned()
+        .collect(),
+    }
+  }
+
+  /// Command round trip times, rates and error counts for every device that
+  /// has been sent a command, sorted by device index.
+  #[cfg(feature = "metrics")]
+  pub fn metrics(&self) -> Vec<DeviceMetrics> {
+    self.metrics.snapshot()
+  }
+
+  /// Stops only the devices claimed by the given client, leaving devices
+  /// other clients are using running.
+  pub fn stop_claimed_devices(&self, client_name: &str) -> ButtplugServerResultFuture {
+    let claimed: Vec<u32> = self
+      .device_claims
+      .iter()
+      .filter(|claim| claim.value() == client_name)
+      .map(|claim| *claim.key())
+      .collect();
+    self.device_manager.stop_devices(&claimed)
+  }
+
+  /// Stops every device as fast as possible, then refuses every device
+  /// command other than a stop until
+  /// [resume_after_emergency_stop][ButtplugServer::resume_after_emergency_stop]
+  /// is called. See [DeviceManager::emergency_stop].
+  pub fn emergency_stop(&self) -> ButtplugResultFuture {
+    self.device_manager.emergency_stop()
+  }
+
+  /// Lets device commands through again after an emergency stop.
+  pub fn resume_after_emergency_stop(&self) {
+    self.device_manager.resume_after_emergency_stop()
+  }
+
+  pub fn emergency_stopped(&self) -> bool {
+    self.device_manager.emergency_stopped()
+  }
+
+  /// Changes the permissions for the client with this name. If it's connected,
+  /// they apply from its next message.
+  pub fn set_client_permissions(&self, client_name: &str, permissions: ClientPermissions) {
+    self
+      .client_permissions
+      .insert(client_name.to_owned(), permissions);
+  }
+
+  /// Removes the permissions set for the client with this name, so it gets
+  /// the default permissions.
+  pub fn remove_client_permissions(&self, client_name: &str) {
+    self.client_permissions.remove(client_name);
+  }
+
   pub fn disconnect(&self) -> BoxFuture<Result<(), messages::Error>> {
     debug!("Buttplug Server {} disconnect requested", self.server_name);
     let ping_timer = self.ping_timer.clone();
     let stop_scanning_fut =
-      self.parse_message(ButtplugClientMessage::StopScanning(StopScanning::default()));
-    let stop_fut = self.parse_message(ButtplugClientMessage::StopAllDevices(
+      self.handle_message(ButtplugClientMessage::StopScanning(StopScanning::default()));
+    let stop_fut = self.handle_message(ButtplugClientMessage::StopAllDevices(
       StopAllDevices::default(),
     ));
     let connected = self.connected.clone();
+    let client_name = self.client_name.clone();
+    let client_spec_version = self.client_spec_version.clone();
+    let stop_devices_on_disconnect = self.stop_devices_on_disconnect;
+    let grace_period = self.disconnect_stop_grace_period;
+    let connection_generation = self.connection_generation.clone();
+    let disconnect_generation = connection_generation.load(Ordering::SeqCst);
+    let device_claims = self.device_claims.clone();
+    let log_target = self.log_target.clone();
     Box::pin(async move {
       connected.store(false, Ordering::SeqCst);
+      // The next client has to ask for logs itself.
+      if let Some(log_target) = log_target {
+        log_target.set_level(messages::LogLevel::Off);
+      }
+      let disconnected_client = client_name
+        .write()
+        .expect("We never panic while holding this lock.")
+        .take();
+      *client_spec_version
+        .write()
+        .expect("We never panic while holding this lock.") = None;
+      // Claims don't outlive the client that made them, otherwise a client
+      // that crashed would lock other clients out of its devices.
+      if let Some(disconnected_client) = disconnected_client {
+        release_client_claims(&device_claims, &disconnected_client);
+      }
       ping_timer.stop_ping_timer().await;
       // Ignore returns here, we just want to stop.
       info!("Server disconnected, stopping device scanning if it was started...");
       let _ = stop_scanning_fut.await;
-      info!("Server disconnected, stopping all devices...");
-      let _ = stop_fut.await;
+      if !stop_devices_on_disconnect {
+        info!("Server disconnected, leaving devices running.");
+      } else if grace_period == 0 {
+        info!("Server disconnected, stopping all devices...");
+        let _ = stop_fut.await;
+      } else {
+        info!(
+          "Server disconnected, stopping all devices in {}ms if no client reconnects...",
+          grace_period
+        );
+        async_manager::spawn(async move {
+          Delay::new(Duration::from_millis(grace_period.into())).await;
+          if connected.load(Ordering::SeqCst)
+            || connection_generation.load(Ordering::SeqCst) != disconnect_generation
+          {
+            info!("Client reconnected during disconnect grace period, leaving devices running.");
+            return;
+          }
+          info!("Disconnect grace period expired, stopping all devices...");
+          let _ = stop_fut.await;
+        });
+      }
       Ok(())
     })
   }
 
   // This is the only method that returns ButtplugServerResult, as it handles
   // the packing of the message ID.
+  //
+  // Client message ids are how clients match our replies to their requests, so
+  // id 0 (used for events) and ids still waiting on a reply are rejected here.
+  // So are messages past the client's in-flight limit, see [in_flight_messages].
   pub fn parse_message(
     &self,
     msg: ButtplugClientMessage,
@@ -250,7 +875,86 @@ impl ButtplugServer {
       self.server_name,
       msg
     );
+    #[cfg(feature = "serialize-json")]
+    if let Some(recorder) = &self.session_recorder {
+      recorder.record(&msg);
+    }
     let id = msg.id();
+    let in_flight_id = if id == BUTTPLUG_SERVER_EVENT_ID {
+      Err(ButtplugMessageError::ReservedMessageId)
+    } else {
+      self.in_flight_messages.insert(&msg)
+    };
+    let in_flight_id = match in_flight_id {
+      Ok(in_flight_id) => in_flight_id,
+      Err(err) => {
+        warn!("Rejecting client message: {}", err);
+        let mut error = messages::Error::from(ButtplugError::from(err));
+        error.set_id(id);
+        return Box::pin(future::ready(Err(error)));
+      }
+    };
+    let device_index = ButtplugDeviceCommandMessageUnion::try_from(msg.clone())
+      .ok()
+      .map(|device_msg| device_msg.device_index());
+    let permissions = self.connected_client_permissions();
+    if let Some(permissions) = &permissions {
+      if let Err(err) = self.check_client_permissions(permissions, &msg) {
+        warn!("Rejecting client message: {}", err);
+        let mut error = messages::Error::from(err);
+        error.set_id(id);
+        return Box::pin(future::ready(Err(error)));
+      }
+    }
+    let out_fut = match permissions.filter(ClientPermissions::restricts_devices) {
+      Some(permissions) if matches!(msg, ButtplugClientMessage::StopAllDevices(_)) => {
+        self.stop_permitted_devices(id, &permissions)
+      }
+      Some(permissions) if matches!(msg, ButtplugClientMessage::RequestDeviceListChanges(_)) => {
+        self.permitted_device_list_changes(msg, &permissions)
+      }
+      Some(permissions) if matches!(msg, ButtplugClientMessage::RequestDeviceList(_)) => {
+        let list_fut = self.handle_message(msg);
+        Box::pin(async move {
+          list_fut
+            .await
+            .map(|reply| permitted_device_list(reply, &permissions))
+        })
+      }
+      _ => self.handle_message(msg),
+    };
+    let replaced_token = in_flight_id.replaced_token();
+    Box::pin(async move {
+      let _in_flight_id = in_flight_id;
+      // If the reply is already here, send it, even if the message has been
+      // replaced since.
+      select_biased! {
+        result = out_fut.fuse() => result,
+        // Dropping the command's future keeps it from being sent, if it's
+        // still waiting in the device's command queue.
+        _ = replaced_token.cancelled().fuse() => {
+          let mut error =
+            messages::Error::from(ButtplugError::from(ButtplugDeviceError::DeviceCommandCancelled));
+          error.set_id(id);
+          if let Some(device_index) = device_index {
+            error.set_device_index(device_index);
+          }
+          Err(error)
+        }
+      }
+    })
+  }
+
+  /// Replies to a message without checking its id, so the server can send
+  /// itself messages while a client is connected.
+  fn handle_message(
+    &self,
+    msg: ButtplugClientMessage,
+  ) -> BoxFuture<'static, Result<ButtplugServerMessage, messages::Error>> {
+    let id = msg.id();
+    let device_index = ButtplugDeviceCommandMessageUnion::try_from(msg.clone())
+      .ok()
+      .map(|device_msg| device_msg.device_index());
     if !self.connected() {
       // Check for ping timeout first! There's no way we should've pinged out if
       // we haven't received RequestServerInfo first, but we do want to know if
@@ -277,23 +981,47 @@ impl ButtplugServer {
     // return Result<ButtplugServerMessage, ButtplugError>, and we'll handle
     // tagging the result with the message id in the future we put out as the
     // return value from this method.
-    let out_fut = if ButtplugDeviceManagerMessageUnion::try_from(msg.clone()).is_ok()
-      || ButtplugDeviceCommandMessageUnion::try_from(msg.clone()).is_ok()
-    {
-      self.device_manager.parse_message(msg.clone())
-    } else {
-      match msg {
-        ButtplugClientMessage::RequestServerInfo(rsi_msg) => self.perform_handshake(rsi_msg),
-        ButtplugClientMessage::Ping(p) => self.handle_ping(p),
-        _ => ButtplugMessageError::UnexpectedMessageType(format!("{:?}", msg)).into(),
+    //
+    // Device manager and device spans are created while building the future,
+    // so enter the message span here to make it their parent.
+    let span = info_span!("Buttplug Server Message", id = id, device_index);
+    let out_fut = span.in_scope(|| {
+      if ButtplugDeviceManagerMessageUnion::try_from(msg.clone()).is_ok() {
+        self.device_manager.parse_message(msg.clone())
+      } else if let Some(device_index) = device_index {
+        match self.check_device_claim(device_index) {
+          Ok(()) => self.device_manager.parse_message(msg.clone()),
+          Err(err) => err.into(),
+        }
+      } else {
+        match msg {
+          ButtplugClientMessage::RequestServerInfo(rsi_msg) => self.perform_handshake(rsi_msg),
+          ButtplugClientMessage::Ping(p) => self.handle_ping(p),
+          ButtplugClientMessage::RequestServerTime(_) => Box::pin(future::ready(Ok(
+            messages::ServerTime::new(unix_time_millis()).into(),
+          ))),
+          ButtplugClientMessage::ClaimDevice(claim_msg) => self.claim_device(claim_msg),
+          ButtplugClientMessage::ReleaseDevice(release_msg) => self.release_device(release_msg),
+          ButtplugClientMessage::RequestLog(log_msg) => self.request_log(log_msg),
+          _ => ButtplugMessageError::UnexpectedMessageType(format!("{:?}", msg)).into(),
+        }
       }
-    };
+    });
     // Simple way to set the ID on the way out. Just rewrap
     // the returned future to make sure it happens.
+    let last_errors = self.last_errors.clone();
+    #[cfg(feature = "metrics")]
+    let metrics = self.metrics.clone();
     Box::pin(
       async move {
-        out_fut
-          .await
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+        let result = out_fut.await;
+        #[cfg(feature = "metrics")]
+        if let Some(device_index) = device_index {
+          metrics.record(device_index, start.elapsed(), result.is_ok());
+        }
+        result
           .map(|mut ok_msg| {
             ok_msg.set_id(id);
             ok_msg
@@ -301,10 +1029,20 @@ impl ButtplugServer {
           .map_err(|err| {
             let mut error = messages::Error::from(err);
             error.set_id(id);
+            if let Some(device_index) = device_index {
+              error.set_device_index(device_index);
+            }
+            let mut last_errors = last_errors
+              .lock()
+              .expect("We never panic while holding this lock.");
+            if last_errors.len() == MAX_SNAPSHOT_ERRORS {
+              last_errors.pop_front();
+            }
+            last_errors.push_back(error.clone());
             error
           })
       }
-      .instrument(info_span!("Buttplug Server Message", id = id)),
+      .instrument(span),
     )
   }
 
@@ -332,8 +1070,20 @@ impl ButtplugServer {
       self.max_ping_time,
     );
     let connected = self.connected.clone();
+    let client_name = self.client_name.clone();
+    let msg_client_name = msg.client_name().to_owned();
+    let client_spec_version = self.client_spec_version.clone();
+    let msg_spec_version = msg.message_version();
+    let connection_generation = self.connection_generation.clone();
     Box::pin(async move {
       ping_timer.start_ping_timer().await;
+      *client_name
+        .write()
+        .expect("We never panic while holding this lock.") = Some(msg_client_name);
+      *client_spec_version
+        .write()
+        .expect("We never panic while holding this lock.") = Some(msg_spec_version);
+      connection_generation.fetch_add(1, Ordering::SeqCst);
       connected.store(true, Ordering::SeqCst);
       debug!("Server handshake check successful.");
       Result::Ok(out_msg.into())
@@ -350,6 +1100,254 @@ impl ButtplugServer {
       Result::Ok(messages::Ok::new(msg.id()).into())
     })
   }
+
+  fn request_log(&self, msg: messages::RequestLog) -> ButtplugServerResultFuture {
+    let log_target = match &self.log_target {
+      Some(log_target) => log_target,
+      None => {
+        return ButtplugMessageError::UnhandledMessage(
+          "Server was not built with a log forwarder, RequestLog is not available.".to_owned(),
+        )
+        .into()
+      }
+    };
+    info!("Client requested logs at level {:?}", msg.log_level());
+    log_target.set_level(msg.log_level());
+    Box::pin(future::ready(Result::Ok(
+      messages::Ok::new(msg.id()).into(),
+    )))
+  }
+
+  fn connected_client_name(&self) -> Option<String> {
+    self
+      .client_name
+      .read()
+      .expect("We never panic while holding this lock.")
+      .clone()
+  }
+
+  fn connected_client_permissions(&self) -> Option<ClientPermissions> {
+    permissions_for_client(
+      &self.client_name,
+      &self.client_permissions,
+      &self.default_client_permissions,
+    )
+  }
+
+  /// Checks a message against the connected client's permissions, before
+  /// it's dispatched.
+  fn check_client_permissions(
+    &self,
+    permissions: &ClientPermissions,
+    msg: &ButtplugClientMessage,
+  ) -> Result<(), ButtplugError> {
+    if !permissions.allows_message(msg) {
+      return Err(
+        ButtplugMessageError::MessageNotPermitted(
+          self.connected_client_name().unwrap_or_default(),
+          format!("{:?}", msg),
+        )
+        .into(),
+      );
+    }
+    if !permissions.restricts_devices() {
+      return Ok(());
+    }
+    let device_index = match msg {
+      ButtplugClientMessage::ClaimDevice(claim_msg) => Some(claim_msg.device_index()),
+      ButtplugClientMessage::ReleaseDevice(release_msg) => Some(release_msg.device_index()),
+      _ => ButtplugDeviceCommandMessageUnion::try_from(msg.clone())
+        .ok()
+        .map(|device_msg| device_msg.device_index()),
+    };
+    if let Some(device_index) = device_index {
+      if let Ok(info) = self.device_manager.device_info(device_index) {
+        if !permissions.allows_device(&info.address) {
+          // Answer as if the device wasn't there, as the client was never
+          // told about it.
+          return Err(ButtplugDeviceError::DeviceNotAvailable(device_index).into());
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// StopAllDevices from a client limited to some devices only stops those.
+  fn stop_permitted_devices(
+    &self,
+    id: u32,
+    permissions: &ClientPermissions,
+  ) -> BoxFuture<'static, Result<ButtplugServerMessage, messages::Error>> {
+    let permitted: Vec<u32> = self
+      .device_manager
+      .device_snapshots()
+      .into_iter()
+      .filter(|device| permissions.allows_device(&device.address))
+      .map(|device| device.index)
+      .collect();
+    let stop_fut = self.device_manager.stop_devices(&permitted);
+    Box::pin(async move {
+      stop_fut
+        .await
+        .map(|mut ok_msg| {
+          ok_msg.set_id(id);
+          ok_msg
+        })
+        .map_err(|err| {
+          let mut error = messages::Error::from(err);
+          error.set_id(id);
+          error
+        })
+    })
+  }
+
+  /// RequestDeviceListChanges from a client limited to some devices only
+  /// mentions those.
+  fn permitted_device_list_changes(
+    &self,
+    msg: ButtplugClientMessage,
+    permissions: &ClientPermissions,
+  ) -> BoxFuture<'static, Result<ButtplugServerMessage, messages::Error>> {
+    let id = msg.id();
+    let revision = match msg {
+      ButtplugClientMessage::RequestDeviceListChanges(changes_msg) => changes_msg.revision(),
+      msg => return self.handle_message(msg),
+    };
+    let mut changes = self
+      .device_manager
+      .permitted_device_list_changes(revision, |address| permissions.allows_device(address));
+    changes.set_id(id);
+    Box::pin(future::ready(Ok(changes.into())))
+  }
+
+  /// Device commands are only allowed from the client holding the device's
+  /// claim, or from anyone if the device isn't claimed.
+  fn check_device_claim(&self, device_index: u32) -> Result<(), ButtplugDeviceError> {
+    let client_name = self.connected_client_name();
+    match self.device_claims.get(&device_index) {
+      Some(owner) if Some(owner.value()) != client_name.as_ref() => Err(
+        ButtplugDeviceError::DeviceClaimedByOtherClient(device_index, owner.value().clone()),
+      ),
+      _ => Ok(()),
+    }
+  }
+
+  fn claim_device(&self, msg: messages::ClaimDevice) -> ButtplugServerResultFuture {
+    let device_index = msg.device_index();
+    if let Err(err) = self.device_manager.device_info(device_index) {
+      return err.into();
+    }
+    let client_name = match self.connected_client_name() {
+      Some(name) => name,
+      None => return ButtplugHandshakeError::RequestServerInfoExpected.into(),
+    };
+    if let Err(err) = self.check_device_claim(device_index) {
+      return err.into();
+    }
+    if self
+      .device_claims
+      .insert(device_index, client_name.clone())
+      .is_none()
+    {
+      info!("Client {} claimed device {}", client_name, device_index);
+      self.send_server_event(messages::DeviceClaimed::new(device_index, &client_name).into());
+    }
+    Box::pin(future::ready(Result::Ok(
+      messages::Ok::new(msg.id()).into(),
+    )))
+  }
+
+  fn release_device(&self, msg: messages::ReleaseDevice) -> ButtplugServerResultFuture {
+    let device_index = msg.device_index();
+    let client_name = self.connected_client_name();
+    match self.device_claims.get(&device_index) {
+      None => {
+        return ButtplugDeviceError::DeviceNotClaimed(device_index).into();
+      }
+      Some(owner) if Some(owner.value()) != client_name.as_ref() => {
+        return ButtplugDeviceError::DeviceClaimedByOtherClient(
+          device_index,
+          owner.value().clone(),
+        )
+        .into();
+      }
+      _ => {}
+    }
+    if let Some((_, owner)) = self.device_claims.remove(&device_index) {
+      info!("Client {} released device {}", owner, device_index);
+      self.send_server_event(messages::DeviceReleased::new(device_index, &owner).into());
+    }
+    Box::pin(future::ready(Result::Ok(
+      messages::Ok::new(msg.id()).into(),
+    )))
+  }
+
+  fn send_server_event(&self, msg: ButtplugServerMessage) {
+    if self.output_sender.receiver_count() > 0 && self.output_sender.send(msg).is_err() {
+      debug!("Server event stream not currently available, dropping event.");
+    }
+  }
+}
+
+/// Permissions for the connected client, None if no client is connected.
+fn permissions_for_client(
+  client_name: &RwLock<Option<String>>,
+  client_permissions: &DashMap<String, ClientPermissions>,
+  default_permissions: &ClientPermissions,
+) -> Option<ClientPermissions> {
+  client_name
+    .read()
+    .expect("We never panic while holding this lock.")
+    .as_ref()
+    .map(|client_name| {
+      client_permissions.get(client_name).map_or_else(
+        || default_permissions.clone(),
+        |permissions| permissions.value().clone(),
+      )
+    })
+}
+
+/// Leaves devices the client can't use out of a DeviceList reply.
+fn permitted_device_list(
+  reply: ButtplugServerMessage,
+  permissions: &ClientPermissions,
+) -> ButtplugServerMessage {
+  match reply {
+    ButtplugServerMessage::DeviceList(device_list) => {
+      let devices = device_list
+        .devices()
+        .iter()
+        .filter(|device| {
+          device
+            .device_address
+            .as_deref()
+            .map_or(false, |address| permissions.allows_device(address))
+        })
+        .cloned()
+        .collect();
+      let mut permitted = messages::DeviceList::new(devices);
+      permitted.set_id(device_list.id());
+      permitted.into()
+    }
+    reply => reply,
+  }
+}
+
+/// Drops claims held by a disconnected client. No DeviceReleased is sent, as
+/// there's no client connected to receive it.
+fn release_client_claims(device_claims: &DashMap<u32, String>, client_name: &str) {
+  let released: Vec<u32> = device_claims
+    .iter()
+    .filter(|claim| claim.value() == client_name)
+    .map(|claim| *claim.key())
+    .collect();
+  for device_index in released {
+    device_claims.remove(&device_index);
+    info!(
+      "Releasing claim on device {} held by disconnected client {}",
+      device_index, client_name
+    );
+  }
 }
 
 #[cfg(test)]
@@ -360,6 +1358,33 @@ mod test {
     util::async_manager,
   };
 
+  #[test]
+  fn test_server_state_snapshot() {
+    async_manager::block_on(async {
+      let server = ButtplugServer::default();
+      let snapshot = server.state_snapshot();
+      assert!(!snapshot.connected);
+      assert!(snapshot.client_name.is_none());
+      let msg =
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION);
+      assert!(server.parse_message(msg.into()).await.is_ok());
+      // No comm managers have been added, so this will fail.
+      assert!(server
+        .parse_message(messages::StartScanning::default().into())
+        .await
+        .is_err());
+      let snapshot = server.state_snapshot();
+      assert!(snapshot.connected);
+      assert_eq!(snapshot.client_name, Some("Test Client".to_owned()));
+      assert!(!snapshot.scanning);
+      assert!(snapshot.devices.is_empty());
+      assert!(snapshot.comm_managers.is_empty());
+      assert_eq!(snapshot.last_errors.len(), 1);
+      assert!(server.disconnect().await.is_ok());
+      assert!(server.state_snapshot().client_name.is_none());
+    });
+  }
+
   #[test]
   fn test_server_reuse() {
     async_manager::block_on(async {
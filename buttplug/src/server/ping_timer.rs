@@ -19,22 +19,43 @@ pub enum PingMessage {
 
 async fn ping_timer(
   max_ping_time: u32,
+  grace_period: u32,
+  disconnect_on_timeout: bool,
   mut ping_msg_receiver: mpsc::Receiver<PingMessage>,
   notifier: Arc<Notify>,
   pinged_out_status: Arc<AtomicBool>,
 ) {
   let mut started = false;
   let mut pinged = false;
+  // True once a deadline has been missed, while we wait out the grace period.
+  let mut in_grace_period = false;
+  // True if we've timed out but are still running, waiting for the client to
+  // ping again before rearming.
+  let mut timed_out = false;
   loop {
+    let wait_time = if in_grace_period {
+      grace_period
+    } else {
+      max_ping_time
+    };
     select! {
-      _ = Delay::new(Duration::from_millis(max_ping_time.into())).fuse() => {
-        if started {
+      _ = Delay::new(Duration::from_millis(wait_time.into())).fuse() => {
+        if started && !timed_out {
           if !pinged {
+            if !in_grace_period && grace_period > 0 {
+              warn!("Ping deadline missed, waiting {}ms grace period", grace_period);
+              in_grace_period = true;
+              continue;
+            }
             notifier.notify_waiters();
-            pinged_out_status.store(true, Ordering::SeqCst);
-            return;
+            if disconnect_on_timeout {
+              pinged_out_status.store(true, Ordering::SeqCst);
+              return;
+            }
+            timed_out = true;
           }
           pinged = false;
+          in_grace_period = false;
         }
       }
       msg = ping_msg_receiver.recv().fuse() => {
@@ -42,9 +63,18 @@ async fn ping_timer(
           return;
         }
         match msg.expect("Already checked") {
-          PingMessage::StartTimer => started = true,
-          PingMessage::StopTimer => started = false,
-          PingMessage::Ping => pinged = true,
+          PingMessage::StartTimer => {
+            started = true;
+            timed_out = false;
+          }
+          PingMessage::StopTimer => {
+            started = false;
+            in_grace_period = false;
+          }
+          PingMessage::Ping => {
+            pinged = true;
+            timed_out = false;
+          }
           PingMessage::End => break,
         }
       }
@@ -73,13 +103,19 @@ impl Drop for PingTimer {
 }
 
 impl PingTimer {
-  pub fn new(max_ping_time: u32) -> Self {
+  /// Creates a timer that notifies waiters if no ping arrives within
+  /// `max_ping_time` milliseconds, plus `grace_period` milliseconds once the
+  /// first deadline is missed. If `disconnect_on_timeout` is false, the timer
+  /// keeps running after a timeout and rearms on the next ping.
+  pub fn new(max_ping_time: u32, grace_period: u32, disconnect_on_timeout: bool) -> Self {
     let ping_timeout_notifier = Arc::new(Notify::new());
     let (sender, receiver) = mpsc::channel(256);
     let pinged_out = Arc::new(AtomicBool::new(false));
     if max_ping_time > 0 {
       let fut = ping_timer(
         max_ping_time,
+        grace_period,
+        disconnect_on_timeout,
         receiver,
         ping_timeout_notifier.clone(),
         pinged_out.clone(),
@@ -1,4 +1,4 @@
-use super::{ButtplugServer, ButtplugServerBuilder, DeviceManager};
+use super::{ButtplugServer, ButtplugServerBuilder, DeviceManager, ServerStateSnapshot};
 use crate::{
   connector::ButtplugConnector,
   core::{
@@ -24,6 +24,9 @@ pub enum ButtplugRemoteServerEvent {
   Connected(String),
   DeviceAdded(u32, String),
   DeviceRemoved(u32),
+  // Scanning couldn't use Bluetooth, because there's no adapter or it's
+  // turned off.
+  NoBluetoothAdapter,
   Disconnected,
 }
 
@@ -118,6 +121,12 @@ async fn run_server<ConnectorType>(
                  error!("Cannot send event to owner, dropping and assuming local server thread has exited.");
                }
               },
+              ButtplugServerMessage::Error(err)
+                if err.error_details.as_ref().map(|details| details.error_class) == Some(messages::ErrorClass::NoBluetoothAdapter)
+                  && remote_event_sender.send(ButtplugRemoteServerEvent::NoBluetoothAdapter).is_err() =>
+              {
+                error!("Cannot send event to owner, dropping and assuming local server thread has exited.");
+              },
               _ => {}
             }
           }
@@ -129,9 +138,18 @@ async fn run_server<ConnectorType>(
       },
     };
   }
+  let client_was_connected = server.connected();
   if let Err(err) = server.disconnect().await {
     error!("Error disconnecting server: {:?}", err);
   }
+  if client_was_connected
+    && remote_event_sender.receiver_count() > 0
+    && remote_event_sender
+      .send(ButtplugRemoteServerEvent::Disconnected)
+      .is_err()
+  {
+    error!("Cannot send event to owner, dropping and assuming local server thread has exited.");
+  }
   info!("Exiting remote server loop");
 }
 
@@ -195,6 +213,10 @@ impl ButtplugRemoteServer {
   pub fn device_manager(&self) -> &DeviceManager {
     self.server.device_manager()
   }
+
+  pub fn state_snapshot(&self) -> ServerStateSnapshot {
+    self.server.state_snapshot()
+  }
 }
 
 impl Drop for ButtplugRemoteServer {
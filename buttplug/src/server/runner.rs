@@ -0,0 +1,393 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Runs a server as a standalone engine process, like Intiface Desktop does.
+//!
+//! [ButtplugServerRunner] sets up a server with the platform's comm managers
+//! and device configuration files, then accepts client connections one after
+//! another until it's told to stop. Front-ends control the runner with
+//! [RunnerCommand]s and are kept up to date with [RunnerEvent]s, either by
+//! calling [ButtplugServerRunner::command] and
+//! [ButtplugServerRunner::event_stream] directly, or as newline delimited JSON
+//! over stdio or a socket via [ButtplugServerRunner::run_control_channel].
+
+use super::{
+  remote_server::{ButtplugRemoteServerEvent, ButtplugServerConnectorError},
+  ButtplugRemoteServer,
+  ButtplugServerBuilder,
+  ServerStateSnapshot,
+};
+use crate::{
+  connector::ButtplugConnector,
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    messages::{
+      ButtplugClientMessage,
+      ButtplugServerMessage,
+      StartScanning,
+      StopAllDevices,
+      StopScanning,
+    },
+  },
+  util::{async_manager, stream::convert_broadcast_receiver_to_stream},
+};
+use futures::{FutureExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+  fs,
+  io,
+  path::PathBuf,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+};
+use tokio::{
+  io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt},
+  sync::{broadcast, Notify},
+};
+
+/// What the runner sets up. Comm managers that weren't compiled into the
+/// library are skipped, whatever their setting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ButtplugServerRunnerOptions {
+  pub server_name: String,
+  pub websocket_port: u16,
+  pub websocket_listen_on_all_interfaces: bool,
+  /// Token clients must send to connect over the websocket. None lets any
+  /// client connect.
+  pub websocket_auth_token: Option<String>,
+  /// Maximum time between client pings in milliseconds, 0 to not require
+  /// pings.
+  pub max_ping_time: u32,
+  pub allow_raw_messages: bool,
+  /// Main device config file, merged over the config built into the
+  /// library.
+  pub device_configuration_file: Option<PathBuf>,
+  pub user_device_configuration_file: Option<PathBuf>,
+  pub use_bluetooth_le: bool,
+  pub use_serial_port: bool,
+  pub use_usb: bool,
+  pub use_lovense_dongle: bool,
+  pub use_lovense_connect: bool,
+  pub use_xinput: bool,
+  pub use_device_websocket_server: bool,
+}
+
+impl Default for ButtplugServerRunnerOptions {
+  fn default() -> Self {
+    Self {
+      server_name: "Buttplug Server".to_owned(),
+      websocket_port: 12345,
+      websocket_listen_on_all_interfaces: false,
+      websocket_auth_token: None,
+      max_ping_time: 0,
+      allow_raw_messages: false,
+      device_configuration_file: None,
+      user_device_configuration_file: None,
+      use_bluetooth_le: true,
+      use_serial_port: false,
+      use_usb: false,
+      use_lovense_dongle: false,
+      use_lovense_connect: false,
+      use_xinput: true,
+      use_device_websocket_server: false,
+    }
+  }
+}
+
+/// Commands front-ends can send to a [ButtplugServerRunner].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RunnerCommand {
+  StartScanning,
+  StopScanning,
+  StopAllDevices,
+  /// Replied to with [RunnerEvent::Status].
+  RequestStatus,
+  /// Disconnects the client, if there is one, and stops accepting new ones.
+  Stop,
+}
+
+/// Updates sent to front-ends, and replies to [RunnerCommand]s.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RunnerEvent {
+  /// The runner is accepting client connections.
+  ServerStarted,
+  ClientConnected(String),
+  ClientDisconnected,
+  DeviceAdded {
+    index: u32,
+    name: String,
+  },
+  DeviceRemoved(u32),
+  /// Scanning couldn't use Bluetooth, because there's no adapter or it's
+  /// turned off.
+  NoBluetoothAdapter,
+  Status(ServerStateSnapshot),
+  /// A command failed, or a control channel line wasn't a valid command.
+  Error(String),
+  ServerStopped,
+}
+
+impl From<ButtplugRemoteServerEvent> for RunnerEvent {
+  fn from(event: ButtplugRemoteServerEvent) -> Self {
+    match event {
+      ButtplugRemoteServerEvent::Connected(name) => RunnerEvent::ClientConnected(name),
+      ButtplugRemoteServerEvent::DeviceAdded(index, name) => {
+        RunnerEvent::DeviceAdded { index, name }
+      }
+      ButtplugRemoteServerEvent::DeviceRemoved(index) => RunnerEvent::DeviceRemoved(index),
+      ButtplugRemoteServerEvent::NoBluetoothAdapter => RunnerEvent::NoBluetoothAdapter,
+      ButtplugRemoteServerEvent::Disconnected => RunnerEvent::ClientDisconnected,
+    }
+  }
+}
+
+fn add_comm_managers(builder: &mut ButtplugServerBuilder, options: &ButtplugServerRunnerOptions) {
+  #[cfg(feature = "btleplug-manager")]
+  if options.use_bluetooth_le {
+    use super::comm_managers::btleplug::BtlePlugCommunicationManagerBuilder;
+    builder.comm_manager(BtlePlugCommunicationManagerBuilder::default);
+  }
+  #[cfg(feature = "serial-manager")]
+  if options.use_serial_port {
+    use super::comm_managers::serialport::SerialPortCommunicationManagerBuilder;
+    builder.comm_manager(SerialPortCommunicationManagerBuilder::default);
+  }
+  #[cfg(feature = "usb-manager")]
+  if options.use_usb {
+    use super::comm_managers::usb::USBCommunicationManagerBuilder;
+    builder.comm_manager(USBCommunicationManagerBuilder::default);
+  }
+  #[cfg(feature = "lovense-dongle-manager")]
+  if options.use_lovense_dongle {
+    use super::comm_managers::lovense_dongle::{
+      LovenseHIDDongleCommunicationManagerBuilder,
+      LovenseSerialDongleCommunicationManagerBuilder,
+    };
+    builder.comm_manager(LovenseHIDDongleCommunicationManagerBuilder::default);
+    builder.comm_manager(LovenseSerialDongleCommunicationManagerBuilder::default);
+  }
+  #[cfg(feature = "lovense-connect-service-manager")]
+  if options.use_lovense_connect {
+    use super::comm_managers::lovense_connect_service::LovenseConnectServiceCommunicationManagerBuilder;
+    builder.comm_manager(LovenseConnectServiceCommunicationManagerBuilder::default);
+  }
+  #[cfg(all(feature = "xinput-manager", target_os = "windows"))]
+  if options.use_xinput {
+    use super::comm_managers::xinput::XInputDeviceCommunicationManagerBuilder;
+    builder.comm_manager(XInputDeviceCommunicationManagerBuilder::default);
+  }
+  #[cfg(feature = "websocket-server-manager")]
+  if options.use_device_websocket_server {
+    use super::comm_managers::websocket_server::websocket_server_comm_manager::WebsocketServerDeviceCommunicationManagerBuilder;
+    let listen_on_all_interfaces = options.websocket_listen_on_all_interfaces;
+    builder.comm_manager(move || {
+      WebsocketServerDeviceCommunicationManagerBuilder::default()
+        .listen_on_all_interfaces(listen_on_all_interfaces)
+    });
+  }
+  // Without any comm managers compiled in, the builder and options go unused.
+  let _ = (builder, options);
+}
+
+/// Runs a server for front-ends, see the [module documentation][self].
+pub struct ButtplugServerRunner {
+  options: ButtplugServerRunnerOptions,
+  server: Arc<ButtplugRemoteServer>,
+  event_sender: broadcast::Sender<RunnerEvent>,
+  stop_requested: AtomicBool,
+  stop_notifier: Notify,
+}
+
+impl ButtplugServerRunner {
+  /// Builds the server, loading the device configuration files in the
+  /// options.
+  pub fn new(options: ButtplugServerRunnerOptions) -> Result<Self, ButtplugError> {
+    let mut builder = ButtplugServerBuilder::default();
+    builder
+      .name(&options.server_name)
+      .allow_raw_messages(options.allow_raw_messages)
+      .device_configuration_file(options.device_configuration_file.clone());
+    if options.max_ping_time > 0 {
+      builder.max_ping_time(options.max_ping_time);
+    }
+    if let Some(path) = &options.user_device_configuration_file {
+      let user_config = fs::read_to_string(path).map_err(|err| {
+        ButtplugDeviceError::DeviceConfigurationFileError(format!(
+          "Cannot read user device configuration file {}: {}",
+          path.display(),
+          err
+        ))
+      })?;
+      builder.user_device_configuration_json(Some(user_config));
+    }
+    add_comm_managers(&mut builder, &options);
+    let server = Arc::new(ButtplugRemoteServer::new(builder.finish()?));
+
+    let (event_sender, _) = broadcast::channel(256);
+    let remote_events = server.event_stream();
+    let event_sender_clone = event_sender.clone();
+    async_manager::spawn(async move {
+      pin_mut!(remote_events);
+      while let Some(event) = remote_events.next().await {
+        // Nobody listening is fine, front-ends can check in with RequestStatus.
+        let _ = event_sender_clone.send(RunnerEvent::from(event));
+      }
+    });
+
+    Ok(Self {
+      options,
+      server,
+      event_sender,
+      stop_requested: AtomicBool::new(false),
+      stop_notifier: Notify::new(),
+    })
+  }
+
+  pub fn options(&self) -> &ButtplugServerRunnerOptions {
+    &self.options
+  }
+
+  pub fn server(&self) -> &ButtplugRemoteServer {
+    &self.server
+  }
+
+  pub fn event_stream(&self) -> impl Stream<Item = RunnerEvent> {
+    convert_broadcast_receiver_to_stream(self.event_sender.subscribe())
+  }
+
+  fn send_event(&self, event: RunnerEvent) {
+    let _ = self.event_sender.send(event);
+  }
+
+  /// Runs a command, returning the reply to send to the front-end, if there
+  /// is one.
+  pub async fn command(&self, command: RunnerCommand) -> Option<RunnerEvent> {
+    let device_manager = self.server.device_manager();
+    let result = match command {
+      RunnerCommand::StartScanning => device_manager
+        .parse_message(StartScanning::default().into())
+        .await
+        .map(|_| ()),
+      RunnerCommand::StopScanning => device_manager
+        .parse_message(StopScanning::default().into())
+        .await
+        .map(|_| ()),
+      RunnerCommand::StopAllDevices => device_manager
+        .parse_message(StopAllDevices::default().into())
+        .await
+        .map(|_| ()),
+      RunnerCommand::RequestStatus => {
+        return Some(RunnerEvent::Status(self.server.state_snapshot()));
+      }
+      RunnerCommand::Stop => {
+        self.stop_requested.store(true, Ordering::SeqCst);
+        self.stop_notifier.notify_one();
+        self.server.disconnect().await
+      }
+    };
+    result.err().map(|err| RunnerEvent::Error(err.to_string()))
+  }
+
+  /// Accepts clients through connectors made by `connector_factory`, one
+  /// client at a time, until [RunnerCommand::Stop] is sent. Returns early if
+  /// a connector can't be brought up.
+  pub async fn run_with_connector<ConnectorType, F>(
+    &self,
+    connector_factory: F,
+  ) -> Result<(), ButtplugServerConnectorError>
+  where
+    ConnectorType: ButtplugConnector<ButtplugServerMessage, ButtplugClientMessage> + 'static,
+    F: Fn() -> ConnectorType,
+  {
+    self.send_event(RunnerEvent::ServerStarted);
+    let mut result = Ok(());
+    while !self.stop_requested.load(Ordering::SeqCst) {
+      select! {
+        session = self.server.start(connector_factory()).fuse() => {
+          if let Err(err) = session {
+            self.send_event(RunnerEvent::Error(err.to_string()));
+            result = Err(err);
+            break;
+          }
+        }
+        _ = self.stop_notifier.notified().fuse() => break,
+      }
+    }
+    self.send_event(RunnerEvent::ServerStopped);
+    result
+  }
+
+  /// Accepts clients over a websocket, using the port, interfaces and auth
+  /// token in the options. See [ButtplugServerRunner::run_with_connector].
+  #[cfg(feature = "websockets")]
+  pub async fn run(&self) -> Result<(), ButtplugServerConnectorError> {
+    use crate::{
+      connector::{
+        ButtplugRemoteServerConnector,
+        ButtplugWebsocketAuth,
+        ButtplugWebsocketServerTransport,
+        ButtplugWebsocketServerTransportBuilder,
+      },
+      core::messages::serializer::ButtplugServerJSONSerializer,
+    };
+    let port = self.options.websocket_port;
+    let listen_on_all_interfaces = self.options.websocket_listen_on_all_interfaces;
+    let mut auth = ButtplugWebsocketAuth::default();
+    if let Some(token) = &self.options.websocket_auth_token {
+      auth.shared_secret(token);
+    }
+    self
+      .run_with_connector(|| {
+        ButtplugRemoteServerConnector::<
+          ButtplugWebsocketServerTransport,
+          ButtplugServerJSONSerializer,
+        >::new(
+          ButtplugWebsocketServerTransportBuilder::default()
+            .port(port)
+            .listen_on_all_interfaces(listen_on_all_interfaces)
+            .auth(auth.clone())
+            .finish(),
+        )
+      })
+      .await
+  }
+
+  /// Reads JSON [RunnerCommand]s from `reader`, one per line, and writes
+  /// replies and [RunnerEvent]s to `writer` as JSON, one per line. Returns
+  /// once the reader is closed.
+  pub async fn run_control_channel<R, W>(&self, reader: R, mut writer: W) -> io::Result<()>
+  where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+  {
+    let mut lines = reader.lines();
+    let events = self.event_stream();
+    pin_mut!(events);
+    loop {
+      let output = select! {
+        line = lines.next_line().fuse() => match line? {
+          None => break,
+          Some(line) if line.trim().is_empty() => continue,
+          Some(line) => match serde_json::from_str::<RunnerCommand>(&line) {
+            Ok(command) => self.command(command).await,
+            Err(err) => Some(RunnerEvent::Error(format!("Invalid command {}: {}", line, err))),
+          },
+        },
+        event = events.next().fuse() => event,
+      };
+      if let Some(output) = output {
+        let mut json = serde_json::to_string(&output).expect("Runner events always serialize.");
+        json.push('\n');
+        writer.write_all(json.as_bytes()).await?;
+        writer.flush().await?;
+      }
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,173 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Records the messages a client sends to a server, and replays them later.
+//!
+//! Hand a [SessionRecorder] to
+//! [ButtplugServerBuilder::session_recorder][super::ButtplugServerBuilder::session_recorder]
+//! and every message passed to
+//! [ButtplugServer::parse_message][super::ButtplugServer::parse_message] is
+//! written to the recording file, one JSON [RecordedMessage] per line, with the
+//! time it arrived. Recordings from user bug reports can then be loaded with
+//! [load_session] and fed to a server with test devices via [replay_session],
+//! either at the original speed or faster.
+
+use super::{ButtplugServer, ButtplugServerError};
+use crate::core::messages::{self, ButtplugClientMessage, ButtplugServerMessage};
+use futures_timer::Delay;
+use serde::{Deserialize, Serialize};
+use std::{
+  fmt,
+  fs::{self, File},
+  io::{LineWriter, Write},
+  path::Path,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+/// A message received by a server while it was being recorded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedMessage {
+  /// Milliseconds between the recorder being created and the message
+  /// arriving.
+  pub time_ms: u64,
+  pub message: ButtplugClientMessage,
+}
+
+/// Writes messages received by a server to a recording file. Clones write to
+/// the same file, so a recorder shared by several servers interleaves their
+/// messages.
+#[derive(Clone)]
+pub struct SessionRecorder {
+  start: Instant,
+  writer: Arc<Mutex<LineWriter<File>>>,
+}
+
+impl fmt::Debug for SessionRecorder {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("SessionRecorder")
+      .field("start", &self.start)
+      .finish()
+  }
+}
+
+impl SessionRecorder {
+  /// Creates the recording file at `path`, replacing it if it already exists.
+  pub fn create(path: impl AsRef<Path>) -> Result<Self, ButtplugServerError> {
+    let path = path.as_ref();
+    let file = File::create(path).map_err(|err| {
+      ButtplugServerError::SessionRecordingError(format!(
+        "Cannot create session recording file {}: {}",
+        path.display(),
+        err
+      ))
+    })?;
+    Ok(Self {
+      start: Instant::now(),
+      writer: Arc::new(Mutex::new(LineWriter::new(file))),
+    })
+  }
+
+  pub(super) fn record(&self, message: &ButtplugClientMessage) {
+    let recorded = RecordedMessage {
+      time_ms: self.start.elapsed().as_millis() as u64,
+      message: message.clone(),
+    };
+    let line = match serde_json::to_string(&recorded) {
+      Ok(line) => line,
+      Err(err) => {
+        error!("Cannot serialize message for session recording: {}", err);
+        return;
+      }
+    };
+    // Lines are flushed as they're written, so recordings survive crashes.
+    if let Err(err) = writeln!(
+      self
+        .writer
+        .lock()
+        .expect("We never panic while holding this lock."),
+      "{}",
+      line
+    ) {
+      error!("Cannot write to session recording: {}", err);
+    }
+  }
+}
+
+/// Parses a recording written by a [SessionRecorder]. Blank lines are skipped.
+pub fn parse_session(recording: &str) -> Result<Vec<RecordedMessage>, ButtplugServerError> {
+  recording
+    .lines()
+    .enumerate()
+    .filter(|(_, line)| !line.trim().is_empty())
+    .map(|(line_number, line)| {
+      serde_json::from_str(line).map_err(|err| {
+        ButtplugServerError::SessionRecordingError(format!(
+          "Invalid recorded message on line {}: {}",
+          line_number + 1,
+          err
+        ))
+      })
+    })
+    .collect()
+}
+
+/// Loads a recording file written by a [SessionRecorder].
+pub fn load_session(path: impl AsRef<Path>) -> Result<Vec<RecordedMessage>, ButtplugServerError> {
+  let path = path.as_ref();
+  let recording = fs::read_to_string(path).map_err(|err| {
+    ButtplugServerError::SessionRecordingError(format!(
+      "Cannot read session recording file {}: {}",
+      path.display(),
+      err
+    ))
+  })?;
+  parse_session(&recording)
+}
+
+/// Outcome of sending a recorded message to a server during a replay.
+#[derive(Debug, Clone)]
+pub struct ReplayedMessage {
+  pub recorded: RecordedMessage,
+  pub result: Result<ButtplugServerMessage, messages::Error>,
+  /// Time between the message being sent and the server replying.
+  pub round_trip: Duration,
+}
+
+/// Sends recorded messages to `server`, spaced out the way they originally
+/// arrived. `speed` scales the gaps between messages, e.g. 2.0 replays twice
+/// as fast as the original session, and anything 0 or below sends messages
+/// back to back.
+///
+/// Each message waits for the reply to the previous one before being sent, so
+/// a message that took longer than the gap after it delays the rest of the
+/// replay.
+pub async fn replay_session(
+  server: &ButtplugServer,
+  session: &[RecordedMessage],
+  speed: f64,
+) -> Vec<ReplayedMessage> {
+  let start = Instant::now();
+  let mut replayed = Vec::with_capacity(session.len());
+  for recorded in session {
+    if speed > 0.0 {
+      let send_time = Duration::from_secs_f64(recorded.time_ms as f64 / 1000.0 / speed);
+      let elapsed = start.elapsed();
+      if send_time > elapsed {
+        Delay::new(send_time - elapsed).await;
+      }
+    }
+    let sent = Instant::now();
+    let result = server.parse_message(recorded.message.clone()).await;
+    replayed.push(ReplayedMessage {
+      recorded: recorded.clone(),
+      result,
+      round_trip: sent.elapsed(),
+    });
+  }
+  replayed
+}
@@ -0,0 +1,39 @@
+use futures::{
+  future::{Future, RemoteHandle},
+  task::{FutureObj, Spawn, SpawnError, SpawnExt},
+};
+
+#[derive(Default)]
+pub struct AsyncStdAsyncManager {}
+
+impl Spawn for AsyncStdAsyncManager {
+  fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+    // Dropping the JoinHandle detaches the task, same as tokio.
+    async_std::task::spawn(future);
+    Ok(())
+  }
+}
+
+pub fn spawn<Fut>(future: Fut)
+where
+  Fut: Future<Output = ()> + Send + 'static,
+{
+  AsyncStdAsyncManager::default()
+    .spawn(future)
+    .expect("Infallible, only returns result to match trait")
+}
+
+pub fn spawn_with_handle<Fut>(future: Fut) -> Result<RemoteHandle<Fut::Output>, SpawnError>
+where
+  Fut: Future + Send + 'static,
+  Fut::Output: Send,
+{
+  AsyncStdAsyncManager::default().spawn_with_handle(future)
+}
+
+pub fn block_on<F>(f: F) -> <F as Future>::Output
+where
+  F: Future,
+{
+  async_std::task::block_on(f)
+}
@@ -1,3 +1,19 @@
+//! Runtime abstraction for the library.
+//!
+//! Everything in Buttplug that needs an executor goes through the functions
+//! exported here, so the runtime can be picked with a feature flag
+//! (`tokio-runtime`, `async-std-runtime`, `wasm-bindgen-runtime`), and
+//! embedders don't have to carry a runtime they don't otherwise use. Each
+//! backend exports an [Spawn](futures::task::Spawn) implementation as
+//! `AsyncManager`, along with `spawn`, `spawn_with_handle` and `block_on`.
+//!
+//! Timers and channels don't need a backend of their own: [sleep] is built on
+//! `futures-timer`, and the `tokio::sync` channels used throughout the library
+//! don't depend on a tokio runtime, so both work on any executor.
+
+use futures_timer::Delay;
+use std::time::Duration;
+
 cfg_if::cfg_if! {
   if #[cfg(feature = "dummy-runtime")] {
     mod dummy;
@@ -8,8 +24,16 @@ cfg_if::cfg_if! {
   } else if #[cfg(feature = "tokio-runtime")] {
     mod tokio;
     pub use self::tokio::{TokioAsyncManager as AsyncManager, spawn, spawn_with_handle, block_on};
+  } else if #[cfg(feature = "async-std-runtime")] {
+    mod async_std;
+    pub use self::async_std::{AsyncStdAsyncManager as AsyncManager, spawn, spawn_with_handle, block_on};
   }
   else {
-    std::compile_error!("Please choose a runtime feature: tokio-runtime, wasm-bindgen-runtime, dummy-runtime");
+    std::compile_error!("Please choose a runtime feature: tokio-runtime, async-std-runtime, wasm-bindgen-runtime, dummy-runtime");
   }
 }
+
+/// Waits for `duration`, without tying the caller to any particular runtime.
+pub fn sleep(duration: Duration) -> Delay {
+  Delay::new(duration)
+}
@@ -0,0 +1,181 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2021 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Downloads device configuration updates, so new devices can be supported
+//! between library releases. Only built with the `device-config-updater`
+//! feature.
+
+use super::device_configuration::{
+  get_internal_config_version,
+  load_protocol_config_from_json,
+  ProtocolConfiguration,
+};
+use crate::{
+  core::errors::{ButtplugDeviceError, ButtplugError},
+  server::device_manager::DeviceManager,
+};
+use sha2::{Digest, Sha256};
+use std::sync::{
+  atomic::{AtomicU32, Ordering},
+  Arc,
+};
+
+/// Result of checking for a device configuration update.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceConfigUpdate {
+  /// A newer configuration was downloaded and applied.
+  Updated(u32),
+  /// The downloaded configuration was no newer than the one already in use,
+  /// so nothing changed.
+  UpToDate(u32),
+}
+
+fn config_error(msg: String) -> ButtplugError {
+  ButtplugDeviceError::DeviceConfigurationFileError(msg).into()
+}
+
+async fn download(url: &str) -> Result<Vec<u8>, ButtplugError> {
+  let download_error =
+    |err: reqwest::Error| config_error(format!("Cannot download {}: {}", url, err));
+  let res = reqwest::get(url)
+    .await
+    .and_then(|res| res.error_for_status())
+    .map_err(download_error)?;
+  Ok(res.bytes().await.map_err(download_error)?.to_vec())
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+  Sha256::digest(data)
+    .iter()
+    .map(|byte| format!("{:02x}", byte))
+    .collect()
+}
+
+/// Checks that `data` has the SHA-256 hash given in `expected`, which can be
+/// the output of `sha256sum`, i.e. the hex hash followed by a file name.
+fn verify_hash(data: &[u8], expected: &str) -> Result<(), ButtplugError> {
+  let expected = expected
+    .split_whitespace()
+    .next()
+    .unwrap_or_default()
+    .to_ascii_lowercase();
+  let actual = sha256_hex(data);
+  if actual == expected {
+    Ok(())
+  } else {
+    Err(config_error(format!(
+      "Device configuration hash mismatch, expected {}, got {}.",
+      expected, actual
+    )))
+  }
+}
+
+/// Fetches the device configuration JSON from a URL, verifies its SHA-256
+/// hash, and adds its protocol definitions to a server's device manager if
+/// it's newer than the configuration already in use.
+///
+/// The hash is downloaded from the config URL with `.sha256` appended, unless
+/// another URL is set with [DeviceConfigUpdater::hash_url] or a known hash is
+/// set with [DeviceConfigUpdater::expected_hash]. Only protocol definitions are
+/// applied, any user config in the downloaded file is ignored.
+#[derive(Debug)]
+pub struct DeviceConfigUpdater {
+  config_url: String,
+  hash_url: String,
+  expected_hash: Option<String>,
+  /// Version of the newest configuration applied so far, starting at the
+  /// version built into the library.
+  current_version: Arc<AtomicU32>,
+}
+
+impl DeviceConfigUpdater {
+  pub fn new(config_url: &str) -> Self {
+    Self {
+      config_url: config_url.to_owned(),
+      hash_url: format!("{}.sha256", config_url),
+      expected_hash: None,
+      current_version: Arc::new(AtomicU32::new(get_internal_config_version())),
+    }
+  }
+
+  /// URL of the file holding the SHA-256 hash of the configuration.
+  pub fn hash_url(mut self, hash_url: &str) -> Self {
+    self.hash_url = hash_url.to_owned();
+    self
+  }
+
+  /// Hash the configuration must have, instead of downloading one.
+  pub fn expected_hash(mut self, hash: &str) -> Self {
+    self.expected_hash = Some(hash.to_owned());
+    self
+  }
+
+  /// Version of the newest configuration applied by this updater, or the
+  /// version built into the library if none has been.
+  pub fn current_version(&self) -> u32 {
+    self.current_version.load(Ordering::SeqCst)
+  }
+
+  /// Downloads and verifies the configuration, without applying it.
+  pub async fn fetch(&self) -> Result<ProtocolConfiguration, ButtplugError> {
+    let config = download(&self.config_url).await?;
+    let expected_hash = match &self.expected_hash {
+      Some(hash) => hash.clone(),
+      None => String::from_utf8_lossy(&download(&self.hash_url).await?).into_owned(),
+    };
+    verify_hash(&config, &expected_hash)?;
+    let config_str = String::from_utf8(config).map_err(|err| {
+      config_error(format!(
+        "Device configuration from {} is not UTF-8: {}",
+        self.config_url, err
+      ))
+    })?;
+    load_protocol_config_from_json(&config_str)
+  }
+
+  /// Downloads the configuration, and applies it to `device_manager` if it's
+  /// newer than the configuration already in use. Devices that are already
+  /// connected keep the configuration they connected with.
+  pub async fn update(
+    &self,
+    device_manager: &DeviceManager,
+  ) -> Result<DeviceConfigUpdate, ButtplugError> {
+    let config = self.fetch().await?;
+    let current_version = self.current_version();
+    if config.version <= current_version {
+      debug!(
+        "Downloaded device configuration version {} is not newer than current version {}.",
+        config.version, current_version
+      );
+      return Ok(DeviceConfigUpdate::UpToDate(current_version));
+    }
+    info!(
+      "Updating device configuration from version {} to {}.",
+      current_version, config.version
+    );
+    for (name, definition) in config.protocols {
+      device_manager.add_protocol_definition(&name, definition);
+    }
+    self.current_version.store(config.version, Ordering::SeqCst);
+    Ok(DeviceConfigUpdate::Updated(config.version))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::verify_hash;
+
+  #[test]
+  fn test_verify_hash() {
+    let hash = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    assert!(verify_hash(b"abc", hash).is_ok());
+    // sha256sum output, in any case.
+    assert!(verify_hash(b"abc", &format!("{}  config.json\n", hash.to_uppercase())).is_ok());
+    assert!(verify_hash(b"abd", hash).is_err());
+    assert!(verify_hash(b"abc", "").is_err());
+  }
+}
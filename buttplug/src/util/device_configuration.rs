@@ -2,10 +2,14 @@ use super::json::JSONValidator;
 use crate::{
   core::errors::{ButtplugDeviceError, ButtplugError},
   device::configuration_manager::{DeviceConfigurationManager, ProtocolDefinition},
-  server::device_manager::DeviceUserConfig,
+  server::{client_permissions::ClientPermissions, device_manager::DeviceUserConfig},
 };
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{
+  collections::{BTreeMap, HashMap},
+  fs,
+  path::Path,
+};
 
 pub static DEVICE_CONFIGURATION_JSON: &str =
   include_str!("../../buttplug-device-config/buttplug-device-config.json");
@@ -15,18 +19,31 @@ static DEVICE_CONFIGURATION_JSON_SCHEMA: &str =
 #[derive(Deserialize, Serialize, Debug)]
 pub struct ProtocolConfiguration {
   pub version: u32,
+  /// Version of the config schema the file was written for. Files without
+  /// one are assumed to use the schema built into the library.
+  #[serde(
+    rename = "schema-version",
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub schema_version: Option<u32>,
   #[serde(default)]
   pub protocols: HashMap<String, ProtocolDefinition>,
   #[serde(rename = "user-config", default)]
   pub user_config: HashMap<String, DeviceUserConfig>,
+  /// Permissions keyed by client name, see [client_permissions][crate::server::client_permissions].
+  #[serde(rename = "client-permissions", default)]
+  pub client_permissions: HashMap<String, ClientPermissions>,
 }
 
 impl Default for ProtocolConfiguration {
   fn default() -> Self {
     Self {
       version: get_internal_config_version(),
+      schema_version: None,
       protocols: HashMap::new(),
       user_config: HashMap::new(),
+      client_permissions: HashMap::new(),
     }
   }
 }
@@ -43,6 +60,18 @@ impl ProtocolConfiguration {
     }
     // Just copy the user config wholesale.
     self.user_config = other.user_config;
+    self.client_permissions = other.client_permissions;
+  }
+
+  /// Merges a main device config from outside the library, i.e. a newer
+  /// config file downloaded on its own, over this one. Protocols in `other`
+  /// replace ours outright, while protocols only we know about are kept.
+  pub fn merge_main_config(&mut self, other: ProtocolConfiguration) {
+    self.version = other.version;
+    self.schema_version = other.schema_version;
+    self.protocols.extend(other.protocols);
+    self.user_config.extend(other.user_config);
+    self.client_permissions.extend(other.client_permissions);
   }
 
   pub fn to_json(&self) -> String {
@@ -51,15 +80,127 @@ impl ProtocolConfiguration {
   }
 }
 
+/// User device configuration file, with per-device user config keyed by
+/// device address. Can be loaded from JSON, edited, and saved again. Anything
+/// in the file besides the user config (protocols, fields added by newer
+/// versions of the library, etc) is kept as is, so nothing is lost when saving.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct UserDeviceConfigFile {
+  version: u32,
+  #[serde(rename = "user-config", default)]
+  user_config: BTreeMap<String, DeviceUserConfig>,
+  #[serde(
+    rename = "client-permissions",
+    default,
+    skip_serializing_if = "BTreeMap::is_empty"
+  )]
+  client_permissions: BTreeMap<String, ClientPermissions>,
+  #[serde(flatten)]
+  other: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Default for UserDeviceConfigFile {
+  fn default() -> Self {
+    Self {
+      version: get_internal_config_version(),
+      user_config: BTreeMap::new(),
+      client_permissions: BTreeMap::new(),
+      other: serde_json::Map::new(),
+    }
+  }
+}
+
+impl UserDeviceConfigFile {
+  pub fn from_json(config_str: &str) -> Result<Self, ButtplugError> {
+    serde_json::from_str(config_str)
+      .map_err(|err| ButtplugDeviceError::DeviceConfigurationFileError(format!("{}", err)).into())
+  }
+
+  pub fn to_json(&self) -> String {
+    serde_json::to_string_pretty(self)
+      .expect("All types below this are Serialize, so this should be infallible.")
+  }
+
+  pub fn version(&self) -> u32 {
+    self.version
+  }
+
+  pub fn device_config(&self, address: &str) -> Option<&DeviceUserConfig> {
+    self.user_config.get(address)
+  }
+
+  /// User config for the device at the address, added with nothing set if
+  /// there wasn't any yet.
+  pub fn device_config_mut(&mut self, address: &str) -> &mut DeviceUserConfig {
+    self.user_config.entry(address.to_owned()).or_default()
+  }
+
+  pub fn remove_device_config(&mut self, address: &str) -> Option<DeviceUserConfig> {
+    self.user_config.remove(address)
+  }
+
+  pub fn device_configs(&self) -> impl Iterator<Item = (&String, &DeviceUserConfig)> {
+    self.user_config.iter()
+  }
+
+  pub fn client_permissions(&self, client_name: &str) -> Option<&ClientPermissions> {
+    self.client_permissions.get(client_name)
+  }
+
+  pub fn set_client_permissions(&mut self, client_name: &str, permissions: ClientPermissions) {
+    self
+      .client_permissions
+      .insert(client_name.to_owned(), permissions);
+  }
+
+  pub fn remove_client_permissions(&mut self, client_name: &str) -> Option<ClientPermissions> {
+    self.client_permissions.remove(client_name)
+  }
+}
+
 pub fn get_internal_config_version() -> u32 {
   let config: ProtocolConfiguration = serde_json::from_str(DEVICE_CONFIGURATION_JSON)
     .expect("If this fails, the whole library goes with it.");
   config.version
 }
 
+pub fn get_internal_config_schema_version() -> u32 {
+  let schema: serde_json::Value = serde_json::from_str(DEVICE_CONFIGURATION_JSON_SCHEMA)
+    .expect("If this fails, the whole library goes with it.");
+  schema["version"]
+    .as_u64()
+    .expect("Schema version is required, and always a number.") as u32
+}
+
+fn check_config_schema_version(config_str: &str) -> Result<(), ButtplugError> {
+  #[derive(Deserialize)]
+  struct SchemaVersion {
+    #[serde(rename = "schema-version")]
+    schema_version: Option<u32>,
+  }
+
+  // Files for newer schemas will usually fail validation too, with a far less
+  // helpful error, so check this first.
+  if let Ok(SchemaVersion {
+    schema_version: Some(schema_version),
+  }) = serde_json::from_str(config_str)
+  {
+    let internal_schema_version = get_internal_config_schema_version();
+    if schema_version > internal_schema_version {
+      return Err(ButtplugDeviceError::DeviceConfigurationFileError(format!(
+        "Device configuration file is for config schema version {}, but this version of Buttplug only supports up to version {}. Please update Buttplug to use this file.",
+        schema_version,
+        internal_schema_version
+      )).into());
+    }
+  }
+  Ok(())
+}
+
 pub fn load_protocol_config_from_json(
   config_str: &str,
 ) -> Result<ProtocolConfiguration, ButtplugError> {
+  check_config_schema_version(config_str)?;
   let config_validator = JSONValidator::new(DEVICE_CONFIGURATION_JSON_SCHEMA);
   match config_validator.validate(config_str) {
     Ok(_) => match serde_json::from_str(config_str) {
@@ -83,6 +224,30 @@ pub fn load_protocol_config_from_json(
   }
 }
 
+pub fn load_protocol_config_from_file(
+  path: impl AsRef<Path>,
+) -> Result<ProtocolConfiguration, ButtplugError> {
+  let path = path.as_ref();
+  let config_str = fs::read_to_string(path).map_err(|err| {
+    ButtplugDeviceError::DeviceConfigurationFileError(format!(
+      "Cannot read device configuration file {}: {}",
+      path.display(),
+      err
+    ))
+  })?;
+  load_protocol_config_from_json(&config_str)
+}
+
+/// Loads the device config built into the library, with the main device config
+/// file at `path` merged over it.
+pub fn load_protocol_config_with_external_file(
+  path: impl AsRef<Path>,
+) -> Result<ProtocolConfiguration, ButtplugError> {
+  let mut config = load_protocol_config_from_json(DEVICE_CONFIGURATION_JSON)?;
+  config.merge_main_config(load_protocol_config_from_file(path)?);
+  Ok(config)
+}
+
 pub fn create_test_dcm(allow_raw_messages: bool) -> DeviceConfigurationManager {
   let devices = load_protocol_config_from_json(DEVICE_CONFIGURATION_JSON)
     .expect("If this fails, the whole library goes with it.");
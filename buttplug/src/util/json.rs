@@ -44,17 +44,28 @@ impl JSONValidator {
   ///
   /// - `json_str`: JSON string to validate.
   pub fn validate(&self, json_str: &str) -> Result<(), ButtplugSerializerError> {
-    let schema = self
-      .scope
-      .resolve(&self.id)
-      .expect("id generated on creation.");
     let check_value = serde_json::from_str(json_str).map_err(|err| {
       ButtplugSerializerError::JsonSerializerError(format!(
         "Message: {} - Error: {:?}",
         json_str, err
       ))
     })?;
-    let state = schema.validate(&check_value);
+    self.validate_value(&check_value)
+  }
+
+  /// Validates an already parsed json value, based on the schema the validator
+  /// was created with. Saves parsing the same string twice when the caller
+  /// needs the value anyways.
+  ///
+  /// # Parameters
+  ///
+  /// - `value`: JSON value to validate.
+  pub fn validate_value(&self, value: &Value) -> Result<(), ButtplugSerializerError> {
+    let schema = self
+      .scope
+      .resolve(&self.id)
+      .expect("id generated on creation.");
+    let state = schema.validate(value);
     if state.is_valid() {
       Ok(())
     } else {
@@ -63,7 +74,7 @@ impl JSONValidator {
       // back.
       Err(ButtplugSerializerError::JsonValidatorError(format!(
         "Message: {} - Error: {:?}",
-        json_str, state
+        value, state
       )))
     }
   }
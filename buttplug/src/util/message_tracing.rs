@@ -0,0 +1,31 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Spans for following a message through the library.
+//!
+//! With the `message-tracing` feature enabled, a message gets a span in the
+//! client (open until the server's response arrives), the device manager, the
+//! device protocol and the device implementation, alongside the span the server
+//! already creates for each message. Spans carry the message id and device index
+//! where there is one, so subscribers can reconstruct per command latency. With
+//! the feature disabled, no spans are built.
+
+use tracing::Span;
+
+/// Returns the span built by `make_span` if message tracing is enabled, or a
+/// disabled span otherwise.
+#[cfg(feature = "message-tracing")]
+pub fn message_span(make_span: impl FnOnce() -> Span) -> Span {
+  make_span()
+}
+
+/// Returns the span built by `make_span` if message tracing is enabled, or a
+/// disabled span otherwise.
+#[cfg(not(feature = "message-tracing"))]
+pub fn message_span(_make_span: impl FnOnce() -> Span) -> Span {
+  Span::none()
+}
@@ -9,8 +9,15 @@
 //! the library.
 
 pub mod async_manager;
+#[cfg(feature = "device-config-updater")]
+pub mod device_config_updater;
+#[cfg(feature = "server")]
 pub mod device_configuration;
 pub mod future;
 pub mod json;
 pub mod logging;
+pub mod message_tracing;
 pub mod stream;
+#[cfg(feature = "server")]
+pub mod testing;
+pub mod time;
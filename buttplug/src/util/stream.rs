@@ -2,15 +2,33 @@ use async_stream::stream;
 use futures::{FutureExt, Stream};
 use tokio::sync::{broadcast, mpsc};
 
+/// Receives the next value from a broadcast channel, or None once the channel
+/// is closed. If the receiver fell behind and values were dropped, logs how
+/// many and carries on from the oldest value still buffered, rather than
+/// treating it like the channel closing.
+pub async fn recv_skipping_lag<T>(receiver: &mut broadcast::Receiver<T>) -> Option<T>
+where
+  T: Clone,
+{
+  loop {
+    match receiver.recv().await {
+      Ok(val) => return Some(val),
+      Err(broadcast::error::RecvError::Lagged(count)) => {
+        warn!("Broadcast receiver fell behind, skipped {} values.", count);
+      }
+      Err(broadcast::error::RecvError::Closed) => return None,
+    }
+  }
+}
+
 pub fn convert_broadcast_receiver_to_stream<T>(
-  receiver: broadcast::Receiver<T>,
+  mut receiver: broadcast::Receiver<T>,
 ) -> impl Stream<Item = T>
 where
   T: Unpin + Clone,
 {
   stream! {
-    pin_mut!(receiver);
-    while let Ok(val) = receiver.recv().await {
+    while let Some(val) = recv_skipping_lag(&mut receiver).await {
       yield val;
     }
   }
@@ -23,3 +41,39 @@ pub fn recv_now<T>(receiver: &mut mpsc::Receiver<T>) -> Option<Option<T>> {
 pub fn iffy_is_empty_check<T>(receiver: &mut mpsc::Receiver<T>) -> bool {
   recv_now(receiver).is_none()
 }
+
+#[cfg(test)]
+mod test {
+  use super::{convert_broadcast_receiver_to_stream, recv_skipping_lag};
+  use crate::util::async_manager;
+  use futures::StreamExt;
+  use tokio::sync::broadcast;
+
+  #[test]
+  fn test_recv_skipping_lag() {
+    async_manager::block_on(async {
+      let (sender, mut receiver) = broadcast::channel(2);
+      for i in 0..5 {
+        sender.send(i).expect("Test, assuming infallible");
+      }
+      // The oldest values were pushed out, but the receiver keeps going.
+      assert_eq!(recv_skipping_lag(&mut receiver).await, Some(3));
+      assert_eq!(recv_skipping_lag(&mut receiver).await, Some(4));
+      drop(sender);
+      assert_eq!(recv_skipping_lag(&mut receiver).await, None);
+    });
+  }
+
+  #[test]
+  fn test_broadcast_stream_survives_lag() {
+    async_manager::block_on(async {
+      let (sender, receiver) = broadcast::channel(2);
+      let stream = convert_broadcast_receiver_to_stream(receiver);
+      for i in 0..5 {
+        sender.send(i).expect("Test, assuming infallible");
+      }
+      drop(sender);
+      assert_eq!(stream.collect::<Vec<i32>>().await, vec![3, 4]);
+    });
+  }
+}
@@ -0,0 +1,46 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Fake devices for writing tests against, without hardware.
+//!
+//! These are the same utilities the library's own tests use, exported so
+//! applications and out of tree protocols can write integration tests the
+//! same way. There are two ways in:
+//!
+//! - [new_bluetoothle_test_device] creates a device using whichever protocol
+//!   matches the device name, for testing a protocol directly.
+//! - [TestDeviceCommunicationManagerBuilder] is a comm manager that emits test
+//!   devices while scanning, for testing against a full server. Add devices
+//!   through its [helper][TestDeviceCommunicationManagerBuilder::helper].
+//!
+//! Either way, the returned [TestDeviceInternal] gives access to what was
+//! written to each endpoint, which [check_test_recv_value] and
+//! [check_test_recv_empty] check against. [ProtocolFuzzer] runs random command
+//! sequences through protocols.
+//!
+//! Things exported here won't change without a major version bump, unlike the
+//! internal `server::comm_managers::test` module they live in.
+
+pub use crate::server::comm_managers::test::{
+  check_test_recv_empty,
+  check_test_recv_value,
+  new_bluetoothle_test_device,
+  new_bluetoothle_test_device_with_setup,
+  protocol_fuzz::{
+    ProtocolFuzzOutcome,
+    ProtocolFuzzReport,
+    ProtocolFuzzer,
+    TestDeviceSetupFn,
+  },
+  TestDevice,
+  TestDeviceCommunicationManager,
+  TestDeviceCommunicationManagerBuilder,
+  TestDeviceCommunicationManagerHelper,
+  TestDeviceEndpointChannel,
+  TestDeviceImplCreator,
+  TestDeviceInternal,
+};
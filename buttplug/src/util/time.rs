@@ -0,0 +1,28 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2020 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Wall clock helpers, used for estimating clock offsets between clients and
+//! servers.
+
+#[cfg(not(all(target_arch = "wasm32", feature = "wasm-bindgen-runtime")))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Milliseconds since the unix epoch, according to the local clock.
+#[cfg(not(all(target_arch = "wasm32", feature = "wasm-bindgen-runtime")))]
+pub fn unix_time_millis() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_millis() as u64)
+    .unwrap_or(0)
+}
+
+/// Milliseconds since the unix epoch, according to the local clock.
+/// SystemTime panics in browsers, so ask javascript instead.
+#[cfg(all(target_arch = "wasm32", feature = "wasm-bindgen-runtime"))]
+pub fn unix_time_millis() -> u64 {
+  js_sys::Date::now() as u64
+}
@@ -2,7 +2,13 @@ mod util;
 extern crate buttplug;
 
 use buttplug::{
-  client::{ButtplugClient, ButtplugClientError, ButtplugClientEvent, VibrateCommand},
+  client::{
+    blocking::ButtplugBlockingClient,
+    ButtplugClient,
+    ButtplugClientError,
+    ButtplugClientEvent,
+    VibrateCommand,
+  },
   connector::{
     ButtplugConnector,
     ButtplugConnectorError,
@@ -10,8 +16,16 @@ use buttplug::{
     ButtplugInProcessClientConnector,
   },
   core::{
-    errors::{ButtplugDeviceError, ButtplugError},
-    messages::{ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage},
+    errors::{ButtplugDeviceError, ButtplugError, ButtplugHandshakeError},
+    messages::{
+      self,
+      ButtplugClientMessage,
+      ButtplugCurrentSpecClientMessage,
+      ButtplugCurrentSpecServerMessage,
+      ButtplugMessage,
+      ButtplugMessageSpecVersion,
+      BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+    },
   },
   device::{DeviceImplCommand, DeviceWriteCmd, Endpoint},
   server::comm_managers::test::{check_test_recv_value, TestDeviceCommunicationManagerBuilder},
@@ -20,8 +34,8 @@ use buttplug::{
 };
 use futures::{future::BoxFuture, StreamExt};
 use futures_timer::Delay;
-use std::time::Duration;
-use tokio::sync::mpsc::Sender;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::mpsc::{self, Sender};
 use util::DelayDeviceCommunicationManagerBuilder;
 
 #[derive(Default)]
@@ -99,6 +113,57 @@ fn test_connect_init() {
   });
 }
 
+#[cfg(feature = "server")]
+#[test]
+fn test_client_handshake_falls_back_to_v2() {
+  async_manager::block_on(async {
+    let helper = Arc::new(util::ChannelClientTestHelper::new());
+    let helper_clone = helper.clone();
+    let connect =
+      async_manager::spawn_with_handle(async move { helper_clone.connect_without_reply().await })
+        .expect("Test, assuming infallible.");
+    // Reject the handshake the way a server that only knows spec v2 would.
+    match helper.get_next_client_message().await {
+      ButtplugClientMessage::RequestServerInfo(rsi) => {
+        assert_eq!(rsi.message_version(), BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION);
+        let mut error = messages::Error::from(ButtplugError::from(
+          ButtplugHandshakeError::MessageSpecVersionMismatch(
+            ButtplugMessageSpecVersion::Version2,
+            rsi.message_version(),
+          ),
+        ));
+        error.set_id(rsi.id());
+        helper.send_client_incoming(error.into()).await;
+      }
+      msg => panic!("Expected RequestServerInfo, got {:?}", msg),
+    }
+    match helper.get_next_client_message().await {
+      ButtplugClientMessage::RequestServerInfo(rsi) => {
+        assert_eq!(rsi.message_version(), ButtplugMessageSpecVersion::Version2);
+        let mut server_info =
+          messages::ServerInfo::new("test server", ButtplugMessageSpecVersion::Version2, 0);
+        server_info.set_id(rsi.id());
+        helper.send_client_incoming(server_info.into()).await;
+      }
+      msg => panic!("Expected RequestServerInfo, got {:?}", msg),
+    }
+    match helper.get_next_client_message().await {
+      ButtplugClientMessage::RequestDeviceList(rdl) => {
+        let mut device_list = messages::DeviceList::new(vec![]);
+        device_list.set_id(rdl.id());
+        helper.send_client_incoming(device_list.into()).await;
+      }
+      msg => panic!("Expected RequestDeviceList, got {:?}", msg),
+    }
+    connect.await.expect("Test, assuming infallible.");
+    assert!(helper.client().connected());
+    assert_eq!(
+      helper.client().server_name(),
+      Some("test server".to_owned())
+    );
+  });
+}
+
 #[cfg(feature = "server")]
 #[test]
 fn test_client_connected_status() {
@@ -236,6 +301,82 @@ fn test_client_ping() {
   });
 }
 
+#[cfg(feature = "server")]
+#[test]
+fn test_client_sync_server_time() {
+  async_manager::block_on(async {
+    let client = ButtplugClient::new("Test Client");
+    client
+      .connect(ButtplugInProcessClientConnector::default())
+      .await
+      .expect("Test, assuming infallible.");
+    assert!(client.estimated_server_offset().is_none());
+    let offset = client
+      .sync_server_time()
+      .await
+      .expect("Test, assuming infallible.");
+    // Same machine, same clock, so the only offset is rounding.
+    assert!(offset.abs() <= 1, "Offset too large: {}", offset);
+    assert_eq!(client.estimated_server_offset(), Some(offset));
+    assert!(client.estimated_server_time().is_some());
+  });
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_client_request_timeout() {
+  async_manager::block_on(async {
+    let helper = util::ChannelClientTestHelper::new_with_client(
+      ButtplugClient::new("Test Client").request_timeout(Some(Duration::from_millis(100))),
+    );
+    helper.simulate_successful_connect().await;
+    // The helper never answers, so the ping should time out.
+    let ping =
+      async_manager::spawn_with_handle(helper.client().ping()).expect("Test, assuming infallible.");
+    assert!(matches!(
+      helper.get_next_client_message().await,
+      ButtplugClientMessage::Ping(..)
+    ));
+    assert!(matches!(
+      ping.await,
+      Err(ButtplugClientError::RequestTimeout)
+    ));
+    // A late reply for the timed out request shouldn't break anything.
+    let mut ok = messages::Ok::default();
+    ok.set_id(3);
+    helper.send_client_incoming(ok.into()).await;
+    assert!(helper.client().connected());
+  });
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_client_max_pending_requests() {
+  async_manager::block_on(async {
+    let helper = util::ChannelClientTestHelper::new_with_client(
+      ButtplugClient::new("Test Client")
+        .request_timeout(None)
+        .max_pending_requests(1),
+    );
+    helper.simulate_successful_connect().await;
+    let first_ping =
+      async_manager::spawn_with_handle(helper.client().ping()).expect("Test, assuming infallible.");
+    helper.get_next_client_message().await;
+    // Registering a second request should evict the first.
+    let second_ping =
+      async_manager::spawn_with_handle(helper.client().ping()).expect("Test, assuming infallible.");
+    helper.get_next_client_message().await;
+    assert!(matches!(
+      first_ping.await,
+      Err(ButtplugClientError::RequestTimeout)
+    ));
+    let mut ok = messages::Ok::default();
+    ok.set_id(4);
+    helper.send_client_incoming(ok.into()).await;
+    assert!(second_ping.await.is_ok());
+  });
+}
+
 // Tests both the stop all devices functionality, as well as both ends of the
 // command range for is_in_command_range message validation.
 #[cfg(feature = "server")]
@@ -299,6 +440,157 @@ fn test_stop_all_devices_and_device_command_range() {
   });
 }
 
+#[cfg(feature = "server")]
+#[test]
+fn test_client_device_lookups() {
+  async_manager::block_on(async {
+    let connector = ButtplugInProcessClientConnector::default();
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    connector
+      .server_ref()
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper
+      .add_ble_device_with_address("Massage Demo", "test-address")
+      .await;
+    let client = ButtplugClient::new("Test Client");
+    let mut event_stream = client.event_stream();
+    client
+      .connect(connector)
+      .await
+      .expect("Test, assuming infallible.");
+    assert!(client.start_scanning().await.is_ok());
+    while let Some(event) = event_stream.next().await {
+      if let ButtplugClientEvent::DeviceAdded(dev) = event {
+        assert_eq!(dev.address(), Some("test-address"));
+        break;
+      }
+    }
+    let dev = client
+      .device_by_address("test-address")
+      .expect("Test, assuming infallible.");
+    assert_eq!(
+      client
+        .device_by_name(&dev.name)
+        .expect("Test, assuming infallible.")
+        .index(),
+      dev.index()
+    );
+    assert!(client.device_by_address("other-address").is_none());
+    assert!(client.device_by_name("Not A Device").is_none());
+  });
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_blocking_client() {
+  let client = ButtplugBlockingClient::new("Test Client");
+  let builder = TestDeviceCommunicationManagerBuilder::default();
+  let helper = builder.helper();
+  let test_device = client.block_on(async move { helper.add_ble_device("Massage Demo").await });
+  client
+    .connect_with(move || {
+      let connector = ButtplugInProcessClientConnector::default();
+      connector
+        .server_ref()
+        .device_manager()
+        .add_comm_manager(builder)
+        .expect("Test, assuming infallible.");
+      connector
+    })
+    .expect("Test, assuming infallible.");
+  assert!(client.connected());
+  client.start_scanning().expect("Test, assuming infallible.");
+  let device = loop {
+    if let Some(device) = client.devices().pop() {
+      break device;
+    }
+    std::thread::sleep(Duration::from_millis(10));
+  };
+  client
+    .vibrate(&device, VibrateCommand::Speed(0.5))
+    .expect("Test, assuming infallible.");
+  let command_receiver = test_device
+    .get_endpoint_receiver(&Endpoint::Tx)
+    .expect("Test, assuming infallible.");
+  check_test_recv_value(
+    &command_receiver,
+    DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+  );
+  check_test_recv_value(
+    &command_receiver,
+    DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 64], false)),
+  );
+  client.disconnect().expect("Test, assuming infallible.");
+  assert!(!client.connected());
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_client_event_callbacks() {
+  async_manager::block_on(async {
+    let connector = ButtplugInProcessClientConnector::default();
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    connector
+      .server_ref()
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper.add_ble_device("Massage Demo").await;
+    let client = ButtplugClient::new("Test Client");
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let _handle = client.add_event_callback(move |event| {
+      let _ = sender.send(event);
+    });
+    let (deregistered_sender, mut deregistered_receiver) = mpsc::unbounded_channel();
+    let deregistered_handle = client.add_event_callback(move |event| {
+      let _ = deregistered_sender.send(event);
+    });
+    client
+      .connect(connector)
+      .await
+      .expect("Test, assuming infallible.");
+    assert!(client.start_scanning().await.is_ok());
+    while let Some(event) = receiver.recv().await {
+      if matches!(event, ButtplugClientEvent::DeviceAdded(_)) {
+        break;
+      }
+    }
+    deregistered_handle.deregister();
+    assert!(client.disconnect().await.is_ok());
+    // Devices are removed before the disconnect event is sent, and callbacks
+    // get events in the order they're sent.
+    let mut events = vec![];
+    while let Some(event) = receiver.recv().await {
+      let disconnected = matches!(event, ButtplugClientEvent::ServerDisconnect);
+      if !matches!(event, ButtplugClientEvent::ScanningFinished) {
+        events.push(event);
+      }
+      if disconnected {
+        break;
+      }
+    }
+    assert!(matches!(
+      events.as_slice(),
+      [
+        ButtplugClientEvent::DeviceRemoved(_),
+        ButtplugClientEvent::ServerDisconnect
+      ]
+    ));
+    // The deregistered callback, and its sender, are dropped without seeing
+    // anything after deregistration.
+    while let Some(event) = deregistered_receiver.recv().await {
+      assert!(!matches!(
+        event,
+        ButtplugClientEvent::DeviceRemoved(_) | ButtplugClientEvent::ServerDisconnect
+      ));
+    }
+  });
+}
+
 // TODO Test calling connect twice
 // TODO Test calling disconnect twice w/o connection
 // TODO Test invalid return on RequestServerInfo
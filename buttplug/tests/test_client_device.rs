@@ -5,15 +5,24 @@ use buttplug::{
     ButtplugClientDeviceEvent,
     ButtplugClientError,
     ButtplugClientEvent,
+    DeviceDisconnectReason,
+    LinearCommand,
     VibrateCommand,
+    TIME_SYNC_SAMPLES,
   },
   connector::ButtplugInProcessClientConnector,
   core::{
     errors::{ButtplugDeviceError, ButtplugError, ButtplugMessageError},
-    messages::{self, ButtplugClientMessage},
+    messages::{
+      self,
+      ButtplugClientMessage,
+      ButtplugDeviceMessageType,
+      ButtplugMessage,
+      DeviceMessageAttributes,
+    },
   },
   server::comm_managers::test::TestDeviceCommunicationManagerBuilder,
-  util::async_manager,
+  util::{async_manager, time::unix_time_millis},
 };
 use futures::StreamExt;
 use futures_timer::Delay;
@@ -59,8 +68,9 @@ fn test_client_device_connected_status() {
       .await
       .expect("Test, assuming infallible.");
     while let Some(msg) = device_event_stream.next().await {
-      if let ButtplugClientDeviceEvent::DeviceRemoved = msg {
+      if let ButtplugClientDeviceEvent::DeviceRemoved(reason) = msg {
         assert!(!test_device.connected());
+        assert_eq!(reason, DeviceDisconnectReason::ConnectionLost);
         break;
       }
     }
@@ -119,7 +129,8 @@ fn test_client_device_client_disconnected_status() {
       }
     }
     while let Some(msg) = device_event_stream.next().await {
-      if let ButtplugClientDeviceEvent::DeviceRemoved = msg {
+      if let ButtplugClientDeviceEvent::DeviceRemoved(reason) = msg {
+        assert_eq!(reason, DeviceDisconnectReason::ClientDisconnected);
         break;
       }
     }
@@ -333,3 +344,157 @@ fn test_client_repeated_deviceremoved_message() {
 // TODO Test DeviceList being sent followed by repeat DeviceAdded
 // TODO Test DeviceList being sent multiple times
 // TODO Test sending device return for device that doesn't exist (in client)
+
+#[cfg(feature = "server")]
+#[test]
+fn test_client_device_reading_stream_unsupported() {
+  async_manager::block_on(async {
+    let client = ButtplugClient::new("Test Client");
+    let mut event_stream = client.event_stream();
+    let connector = ButtplugInProcessClientConnector::default();
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    connector
+      .server_ref()
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let _ = helper.add_ble_device("Massage Demo").await;
+    client
+      .connect(connector)
+      .await
+      .expect("Test, assuming infallible.");
+    client
+      .start_scanning()
+      .await
+      .expect("Test, assuming infallible.");
+    let mut client_device = None;
+    while let Some(msg) = event_stream.next().await {
+      if let ButtplugClientEvent::DeviceAdded(da) = msg {
+        client_device = Some(da);
+        break;
+      }
+    }
+    let test_device = client_device.expect("Test, assuming infallible.");
+    // Massage Demo doesn't support battery readings, so the stream should
+    // yield the error once and then end.
+    let mut battery_stream = test_device.battery_stream(Duration::from_millis(10));
+    assert!(matches!(
+      battery_stream
+        .next()
+        .await
+        .expect("Test, assuming infallible."),
+      Err(ButtplugClientError::ButtplugError(
+        ButtplugError::ButtplugDeviceError(ButtplugDeviceError::MessageNotSupported(..))
+      ))
+    ));
+    assert!(battery_stream.next().await.is_none());
+  });
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_client_device_linear_sent_at() {
+  async_manager::block_on(async move {
+    let helper = Arc::new(util::ChannelClientTestHelper::new());
+    helper.simulate_successful_connect().await;
+    let mut event_stream = helper.client().event_stream();
+    let mut attributes = HashMap::new();
+    attributes.insert(
+      ButtplugDeviceMessageType::LinearCmd,
+      DeviceMessageAttributes {
+        feature_count: Some(1),
+        ..Default::default()
+      },
+    );
+    helper
+      .send_client_incoming(messages::DeviceAdded::new(1, "Test Device", &attributes).into())
+      .await;
+    let device = match event_stream
+      .next()
+      .await
+      .expect("Test, assuming infallible.")
+    {
+      ButtplugClientEvent::DeviceAdded(device) => device,
+      event => panic!("Expected DeviceAdded, got {:?}", event),
+    };
+
+    // Commands aren't timestamped until the client knows the server's clock.
+    let _unsynced =
+      async_manager::spawn_with_handle(device.linear(LinearCommand::Linear(500, 0.5)))
+        .expect("Test, assuming infallible.");
+    match helper.get_next_client_message().await {
+      ButtplugClientMessage::LinearCmd(msg) => assert_eq!(msg.sent_at(), None),
+      msg => panic!("Expected LinearCmd, got {:?}", msg),
+    }
+
+    let helper_clone = helper.clone();
+    async_manager::spawn(async move {
+      for _ in 0..TIME_SYNC_SAMPLES {
+        let request = helper_clone.get_next_client_message().await;
+        assert!(matches!(
+          request,
+          ButtplugClientMessage::RequestServerTime(..)
+        ));
+        let mut reply = messages::ServerTime::new(unix_time_millis());
+        reply.set_id(request.id());
+        helper_clone.send_client_incoming(reply.into()).await;
+      }
+    });
+    helper
+      .client()
+      .sync_server_time()
+      .await
+      .expect("Test, assuming infallible.");
+    let _synced =
+      async_manager::spawn_with_handle(device.linear(LinearCommand::Linear(500, 0.5)))
+        .expect("Test, assuming infallible.");
+    match helper.get_next_client_message().await {
+      ButtplugClientMessage::LinearCmd(msg) => {
+        let sent_at = msg.sent_at().expect("Test, assuming infallible.");
+        assert!(sent_at.abs_diff(unix_time_millis()) <= 100);
+      }
+      msg => panic!("Expected LinearCmd, got {:?}", msg),
+    }
+  });
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn test_client_device_linear_not_timestamped_for_older_servers() {
+  async_manager::block_on(async move {
+    let helper = Arc::new(util::ChannelClientTestHelper::new());
+    helper
+      .simulate_successful_connect_with_version(messages::ButtplugMessageSpecVersion::Version2)
+      .await;
+    let mut event_stream = helper.client().event_stream();
+    let mut attributes = HashMap::new();
+    attributes.insert(
+      ButtplugDeviceMessageType::LinearCmd,
+      DeviceMessageAttributes {
+        feature_count: Some(1),
+        ..Default::default()
+      },
+    );
+    helper
+      .send_client_incoming(messages::DeviceAdded::new(1, "Test Device", &attributes).into())
+      .await;
+    let device = match event_stream
+      .next()
+      .await
+      .expect("Test, assuming infallible.")
+    {
+      ButtplugClientEvent::DeviceAdded(device) => device,
+      event => panic!("Expected DeviceAdded, got {:?}", event),
+    };
+    // v2 servers can't tell the time, so the client never syncs with them.
+    assert!(helper.client().sync_server_time().await.is_err());
+    let _unsynced =
+      async_manager::spawn_with_handle(device.linear(LinearCommand::Linear(500, 0.5)))
+        .expect("Test, assuming infallible.");
+    match helper.get_next_client_message().await {
+      ButtplugClientMessage::LinearCmd(msg) => assert_eq!(msg.sent_at(), None),
+      msg => panic!("Expected LinearCmd, got {:?}", msg),
+    }
+  });
+}
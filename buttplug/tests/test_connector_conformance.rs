@@ -0,0 +1,313 @@
+// Conformance suite for connectors and transports. Every connector/transport
+// pairing runs the same handshake, enumeration, command and disconnect flows
+// against a server with a test device. To validate a new transport, write a
+// function that connects a client to a server over it, and add a
+// connector_conformance_tests! line for it at the bottom of this file.
+//
+// Like the websocket connector tests, connecting retries for a bit, as the
+// server side of remote transports may not be listening yet.
+
+#![cfg(all(feature = "client", feature = "server"))]
+
+use buttplug::{
+  client::{ButtplugClient, ButtplugClientEvent, VibrateCommand},
+  connector::ButtplugInProcessClientConnector,
+  device::{DeviceImplCommand, DeviceWriteCmd, Endpoint},
+  server::ButtplugServer,
+  util::{
+    async_manager,
+    testing::{
+      check_test_recv_empty,
+      check_test_recv_value,
+      TestDeviceCommunicationManagerBuilder,
+      TestDeviceInternal,
+    },
+  },
+};
+use futures::{future::BoxFuture, StreamExt};
+use futures_timer::Delay;
+use std::{any::Any, sync::Arc, time::Duration};
+
+/// Whatever has to stay alive for a connection to stay up, i.e. a remote
+/// server.
+type ConnectionGuard = Box<dyn Any + Send>;
+
+/// Connects `client` to `server` over a single connector/transport pairing.
+/// Panics if the connection can't be made.
+type ConnectFn = for<'a> fn(ButtplugServer, &'a ButtplugClient) -> BoxFuture<'a, ConnectionGuard>;
+
+const CONNECT_ATTEMPTS: u8 = 20;
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+macro_rules! connector_conformance_tests {
+  ($name:ident, $connect:expr) => {
+    mod $name {
+      use super::*;
+
+      #[test]
+      fn test_conformance_handshake() {
+        async_manager::block_on(check_handshake($connect));
+      }
+
+      #[test]
+      fn test_conformance_device_enumeration() {
+        async_manager::block_on(check_device_enumeration($connect));
+      }
+
+      #[test]
+      fn test_conformance_device_command() {
+        async_manager::block_on(check_device_command($connect));
+      }
+
+      #[test]
+      fn test_conformance_disconnect() {
+        async_manager::block_on(check_disconnect($connect));
+      }
+    }
+  };
+}
+
+async fn connect_with_test_device(
+  connect: ConnectFn,
+) -> (ButtplugClient, Arc<TestDeviceInternal>, ConnectionGuard) {
+  let server = ButtplugServer::default();
+  let builder = TestDeviceCommunicationManagerBuilder::default();
+  let helper = builder.helper();
+  server
+    .device_manager()
+    .add_comm_manager(builder)
+    .expect("Test, assuming infallible.");
+  let device = helper.add_ble_device("Massage Demo").await;
+  let client = ButtplugClient::new("Conformance Client");
+  let guard = connect(server, &client).await;
+  (client, device, guard)
+}
+
+async fn check_handshake(connect: ConnectFn) {
+  let (client, _, _guard) = connect_with_test_device(connect).await;
+  assert!(client.connected());
+  assert_eq!(client.server_name(), Some("Buttplug Server".to_owned()));
+}
+
+async fn check_device_enumeration(connect: ConnectFn) {
+  let (client, _, _guard) = connect_with_test_device(connect).await;
+  let mut event_stream = client.event_stream();
+  client
+    .start_scanning()
+    .await
+    .expect("Test, assuming infallible.");
+  let mut added = false;
+  let mut scanning_finished = false;
+  while !(added && scanning_finished) {
+    match event_stream
+      .next()
+      .await
+      .expect("Test, assuming infallible.")
+    {
+      ButtplugClientEvent::DeviceAdded(device) => {
+        assert_eq!(device.name, "Aneros Vivi");
+        added = true;
+      }
+      ButtplugClientEvent::ScanningFinished => scanning_finished = true,
+      _ => {}
+    }
+  }
+  assert_eq!(client.devices().len(), 1);
+}
+
+async fn check_device_command(connect: ConnectFn) {
+  let (client, test_device, _guard) = connect_with_test_device(connect).await;
+  let mut event_stream = client.event_stream();
+  client
+    .start_scanning()
+    .await
+    .expect("Test, assuming infallible.");
+  let device = loop {
+    if let ButtplugClientEvent::DeviceAdded(device) = event_stream
+      .next()
+      .await
+      .expect("Test, assuming infallible.")
+    {
+      break device;
+    }
+  };
+  device
+    .vibrate(VibrateCommand::Speed(0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  let command_receiver = test_device
+    .get_endpoint_receiver(&Endpoint::Tx)
+    .expect("Test, assuming infallible.");
+  // The Vivi has two vibrators, both set by VibrateCommand::Speed.
+  for command in [0xF1, 0xF2] {
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![command, 64], false)),
+    );
+  }
+  device.stop().await.expect("Test, assuming infallible.");
+  for command in [0xF1, 0xF2] {
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![command, 0], false)),
+    );
+  }
+  assert!(check_test_recv_empty(&command_receiver));
+}
+
+async fn check_disconnect(connect: ConnectFn) {
+  let (client, _, _guard) = connect_with_test_device(connect).await;
+  let mut event_stream = client.event_stream();
+  client
+    .disconnect()
+    .await
+    .expect("Test, assuming infallible.");
+  assert!(!client.connected());
+  while let Some(event) = event_stream.next().await {
+    if let ButtplugClientEvent::ServerDisconnect = event {
+      return;
+    }
+  }
+  panic!("Client event stream ended without a ServerDisconnect event.");
+}
+
+fn connect_in_process(
+  server: ButtplugServer,
+  client: &ButtplugClient,
+) -> BoxFuture<'_, ConnectionGuard> {
+  Box::pin(async move {
+    client
+      .connect(ButtplugInProcessClientConnector::new(Some(server)))
+      .await
+      .expect("Test, assuming infallible.");
+    Box::new(()) as ConnectionGuard
+  })
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod remote {
+  use super::*;
+  use buttplug::{
+    connector::{ButtplugConnector, ButtplugRemoteClientConnector, ButtplugRemoteServerConnector},
+    core::messages::{
+      serializer::{ButtplugClientJSONSerializer, ButtplugServerJSONSerializer},
+      ButtplugClientMessage,
+      ButtplugCurrentSpecClientMessage,
+      ButtplugCurrentSpecServerMessage,
+      ButtplugServerMessage,
+    },
+    server::ButtplugRemoteServer,
+  };
+  use std::sync::atomic::{AtomicU16, Ordering};
+
+  /// Gives each test its own pipe name or port, as tests run in parallel.
+  static NEXT_ADDRESS: AtomicU16 = AtomicU16::new(12360);
+
+  pub fn next_address() -> u16 {
+    NEXT_ADDRESS.fetch_add(1, Ordering::SeqCst)
+  }
+
+  /// Starts `server` listening on `server_connector`, then connects `client`
+  /// with connectors from `client_connector` until one succeeds.
+  pub async fn connect_remote<ServerConnector, ClientConnector>(
+    server: ButtplugServer,
+    client: &ButtplugClient,
+    server_connector: ServerConnector,
+    client_connector: impl Fn() -> ClientConnector,
+  ) -> ConnectionGuard
+  where
+    ServerConnector: ButtplugConnector<ButtplugServerMessage, ButtplugClientMessage> + 'static,
+    ClientConnector: ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServerMessage>
+      + 'static,
+  {
+    let remote_server = ButtplugRemoteServer::new(server);
+    let start_fut = remote_server.start(server_connector);
+    // Failures show up as the client being unable to connect.
+    async_manager::spawn(async move {
+      let _ = start_fut.await;
+    });
+    for _ in 0..CONNECT_ATTEMPTS {
+      if client.connect(client_connector()).await.is_ok() {
+        return Box::new(remote_server);
+      }
+      Delay::new(CONNECT_RETRY_DELAY).await;
+    }
+    panic!("Client could not connect to remote server.");
+  }
+
+  pub fn connect_pipe(
+    server: ButtplugServer,
+    client: &ButtplugClient,
+  ) -> BoxFuture<'_, ConnectionGuard> {
+    use buttplug::connector::{
+      ButtplugPipeClientTransport,
+      ButtplugPipeClientTransportBuilder,
+      ButtplugPipeServerTransportBuilder,
+    };
+    let name = format!(
+      "buttplug-conformance-{}-{}",
+      std::process::id(),
+      next_address()
+    );
+    #[cfg(target_os = "windows")]
+    let address = format!("\\\\.\\pipe\\{}", name);
+    #[cfg(not(target_os = "windows"))]
+    let address = std::env::temp_dir()
+      .join(name)
+      .to_string_lossy()
+      .into_owned();
+    Box::pin(async move {
+      connect_remote(
+        server,
+        client,
+        ButtplugRemoteServerConnector::<_, ButtplugServerJSONSerializer>::new(
+          ButtplugPipeServerTransportBuilder::new(&address).finish(),
+        ),
+        || {
+          ButtplugRemoteClientConnector::<ButtplugPipeClientTransport, ButtplugClientJSONSerializer>::new(
+            ButtplugPipeClientTransportBuilder::new(&address).finish(),
+          )
+        },
+      )
+      .await
+    })
+  }
+
+  #[cfg(feature = "websockets")]
+  pub fn connect_websocket(
+    server: ButtplugServer,
+    client: &ButtplugClient,
+  ) -> BoxFuture<'_, ConnectionGuard> {
+    use buttplug::connector::{
+      ButtplugWebsocketClientTransport,
+      ButtplugWebsocketServerTransportBuilder,
+    };
+    let port = next_address();
+    Box::pin(async move {
+      connect_remote(
+        server,
+        client,
+        ButtplugRemoteServerConnector::<_, ButtplugServerJSONSerializer>::new(
+          ButtplugWebsocketServerTransportBuilder::default()
+            .port(port)
+            .finish(),
+        ),
+        || {
+          ButtplugRemoteClientConnector::<_, ButtplugClientJSONSerializer>::new(
+            ButtplugWebsocketClientTransport::new_insecure_connector(&format!(
+              "ws://127.0.0.1:{}",
+              port
+            )),
+          )
+        },
+      )
+      .await
+    })
+  }
+}
+
+connector_conformance_tests!(in_process, connect_in_process);
+#[cfg(not(target_arch = "wasm32"))]
+connector_conformance_tests!(pipe, remote::connect_pipe);
+#[cfg(feature = "websockets")]
+connector_conformance_tests!(websocket, remote::connect_websocket);
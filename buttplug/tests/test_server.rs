@@ -2,22 +2,38 @@ mod util;
 
 use buttplug::{
   core::{
-    errors::{ButtplugDeviceError, ButtplugError, ButtplugHandshakeError},
+    errors::{ButtplugDeviceError, ButtplugError, ButtplugHandshakeError, ButtplugMessageError},
     messages::{
       self,
+      ButtplugMessage,
       ButtplugMessageSpecVersion,
       ButtplugServerMessage,
       BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
     },
   },
   device::{DeviceImplCommand, DeviceWriteCmd, Endpoint},
-  server::comm_managers::test::{check_test_recv_value, TestDeviceCommunicationManagerBuilder},
-  server::{ButtplugServer, ButtplugServerBuilder},
+  server::comm_managers::test::{
+    check_test_recv_empty,
+    check_test_recv_value,
+    TestDeviceCommunicationManagerBuilder,
+  },
+  server::{
+    log_forwarder::ButtplugLogForwarder,
+    session_recording::{load_session, replay_session, SessionRecorder},
+    ButtplugServer,
+    ButtplugServerBuilder,
+    ServerStateSnapshot,
+  },
   util::async_manager,
 };
-use futures::{pin_mut, Stream, StreamExt};
+use futures::{pin_mut, FutureExt, Stream, StreamExt};
 use futures_timer::Delay;
-use std::time::Duration;
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+use tokio::sync::mpsc::Receiver;
+use tracing_subscriber::layer::SubscriberExt;
 
 async fn setup_test_server(
   msg_union: messages::ButtplugClientMessage,
@@ -32,7 +48,7 @@ async fn setup_test_server(
   {
     ButtplugServerMessage::ServerInfo(s) => assert_eq!(
       s,
-      messages::ServerInfo::new("Buttplug Server", ButtplugMessageSpecVersion::Version2, 0)
+      messages::ServerInfo::new("Buttplug Server", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION, 0)
     ),
     _ => panic!("Should've received ok"),
   }
@@ -74,20 +90,74 @@ fn test_server_version_lt() {
   });
 }
 
-// TODO Now that we're moving to a spec version enum, this test is invalid
-// because we can't just pass a u8 in. This should be rebuilt using the
-// JSON parser, and it should fail to deserialize the message.
 #[test]
-#[ignore]
-fn test_server_version_gt() {
-  let server = ButtplugServer::default();
+fn test_server_version_current() {
+  let msg =
+    messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into();
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    match server
+      .parse_message(msg)
+      .await
+      .expect("Test, assuming infallible.")
+    {
+      ButtplugServerMessage::ServerInfo(s) => {
+        assert_eq!(s.message_version(), BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+      }
+      msg => panic!("Should've received ServerInfo, got {:?}", msg),
+    }
+    assert!(server.connected());
+  });
+}
+
+#[test]
+fn test_server_rejects_reserved_message_id() {
   let msg =
     messages::RequestServerInfo::new("Test Client", ButtplugMessageSpecVersion::Version2).into();
   async_manager::block_on(async {
-    assert!(
-      server.parse_message(msg).await.is_err(),
-      "Client having higher version than server should fail"
+    let (server, _recv) = setup_test_server(msg).await;
+    let mut msg = messages::RequestServerTime::default();
+    msg.set_id(0);
+    let err = server
+      .parse_message(msg.into())
+      .await
+      .expect_err("Id 0 is reserved for events.");
+    assert!(matches!(
+      err.original_error(),
+      ButtplugError::ButtplugMessageError(ButtplugMessageError::ReservedMessageId)
+    ));
+    assert_eq!(
+      err.error_details.map(|details| details.error_class),
+      Some(messages::ErrorClass::ReservedMessageId)
+    );
+  });
+}
+
+#[test]
+fn test_server_rejects_in_flight_message_id() {
+  let msg =
+    messages::RequestServerInfo::new("Test Client", ButtplugMessageSpecVersion::Version2).into();
+  async_manager::block_on(async {
+    let (server, _recv) = setup_test_server(msg).await;
+    let mut msg = messages::RequestServerTime::default();
+    msg.set_id(5);
+    let first = server.parse_message(msg.clone().into());
+    let err = server
+      .parse_message(msg.clone().into())
+      .await
+      .expect_err("Id 5 is still waiting on a reply.");
+    assert!(matches!(
+      err.original_error(),
+      ButtplugError::ButtplugMessageError(ButtplugMessageError::MessageIdInUse(5))
+    ));
+    assert_eq!(err.id(), 5);
+    assert_eq!(
+      err.error_details.map(|details| details.error_class),
+      Some(messages::ErrorClass::MessageIdInUse)
     );
+    assert!(first.await.is_ok());
+    // Once replied to, the id can be used again.
+    assert!(server.parse_message(msg.into()).await.is_ok());
   });
 }
 
@@ -125,6 +195,88 @@ fn test_ping_timeout() {
     } else {
       panic!("Didn't get an error message back");
     }
+    // Followed by which policy the server applied.
+    let msg = recv.next().await.expect("Test, assuming infallible.");
+    if let ButtplugServerMessage::PingTimeout(timeout) = msg {
+      assert_eq!(
+        timeout.policy(),
+        messages::PingTimeoutPolicy::StopDevicesAndDisconnect
+      );
+    } else {
+      panic!("Didn't get a ping timeout message back: {:?}", msg);
+    }
+  });
+}
+
+#[test]
+fn test_ping_timeout_stop_devices_policy() {
+  async_manager::block_on(async {
+    let server = ButtplugServerBuilder::default()
+      .max_ping_time(100)
+      .ping_timeout_policy(messages::PingTimeoutPolicy::StopDevices)
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let msg =
+      messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION);
+    assert!(server.parse_message(msg.into()).await.is_ok());
+    Delay::new(Duration::from_millis(300)).await;
+    // The client should only be told which policy fired, not disconnected.
+    let msg = recv.next().await.expect("Test, assuming infallible.");
+    if let ButtplugServerMessage::PingTimeout(timeout) = msg {
+      assert_eq!(timeout.policy(), messages::PingTimeoutPolicy::StopDevices);
+    } else {
+      panic!("Didn't get a ping timeout message back: {:?}", msg);
+    }
+    assert!(server.connected());
+    assert!(server
+      .parse_message(messages::Ping::default().into())
+      .await
+      .is_ok());
+  });
+}
+
+#[test]
+fn test_ping_timeout_not_sent_to_older_clients() {
+  async_manager::block_on(async {
+    let server = ButtplugServerBuilder::default()
+      .max_ping_time(100)
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let msg = messages::RequestServerInfo::new("Test Client", ButtplugMessageSpecVersion::Version2);
+    assert!(server.parse_message(msg.into()).await.is_ok());
+    Delay::new(Duration::from_millis(300)).await;
+    // v2 clients only get the ping error.
+    let msg = recv.next().await.expect("Test, assuming infallible.");
+    assert!(matches!(msg, ButtplugServerMessage::Error(_)));
+    assert!(recv.next().now_or_never().is_none());
+  });
+}
+
+#[test]
+fn test_ping_timeout_grace_period() {
+  async_manager::block_on(async {
+    let server = ButtplugServerBuilder::default()
+      .max_ping_time(100)
+      .ping_timeout_grace_period(300)
+      .finish()
+      .expect("Test, assuming infallible.");
+    let msg =
+      messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION);
+    assert!(server.parse_message(msg.into()).await.is_ok());
+    // Past the ping deadline, but still inside the grace period.
+    Delay::new(Duration::from_millis(200)).await;
+    assert!(server
+      .parse_message(messages::Ping::default().into())
+      .await
+      .is_ok());
+    assert!(server.connected());
+    // Without any more pings, the grace period runs out too.
+    Delay::new(Duration::from_millis(800)).await;
+    assert!(!server.connected());
   });
 }
 
@@ -158,7 +310,9 @@ fn test_device_stop_on_ping_timeout() {
     // Check that we got an event back about a new device.
     let mut device_index = 100;
     while let Some(msg) = recv.next().await {
-      if let ButtplugServerMessage::ScanningFinished(_) = msg {
+      if let ButtplugServerMessage::ScanningStarted(_)
+      | ButtplugServerMessage::ScanningFinished(_) = msg
+      {
         continue;
       } else if let ButtplugServerMessage::DeviceAdded(da) = msg {
         assert_eq!(da.device_name(), "Aneros Vivi");
@@ -202,6 +356,96 @@ fn test_device_stop_on_ping_timeout() {
   });
 }
 
+async fn setup_disconnect_grace_period_test(
+  server: &ButtplugServer,
+) -> Arc<Mutex<Receiver<DeviceImplCommand>>> {
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  let builder = TestDeviceCommunicationManagerBuilder::default();
+  let helper = builder.helper();
+  server
+    .device_manager()
+    .add_comm_manager(builder)
+    .expect("Test, assuming infallible.");
+  let device = helper.add_ble_device("Massage Demo").await;
+  let msg = messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION);
+  assert!(server.parse_message(msg.into()).await.is_ok());
+  assert!(server
+    .parse_message(messages::StartScanning::default().into())
+    .await
+    .is_ok());
+  let mut device_index = 100;
+  while let Some(msg) = recv.next().await {
+    if let ButtplugServerMessage::DeviceAdded(da) = msg {
+      device_index = da.device_index();
+      break;
+    }
+  }
+  server
+    .parse_message(
+      messages::VibrateCmd::new(device_index, vec![messages::VibrateSubcommand::new(0, 0.5)])
+        .into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  let command_receiver = device
+    .get_endpoint_receiver(&Endpoint::Tx)
+    .expect("Test, assuming infallible.");
+  check_test_recv_value(
+    &command_receiver,
+    DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+  );
+  command_receiver
+}
+
+#[test]
+fn test_device_stop_after_disconnect_grace_period() {
+  async_manager::block_on(async {
+    let server = ButtplugServerBuilder::default()
+      .disconnect_stop_grace_period(100)
+      .finish()
+      .expect("Test, assuming infallible.");
+    let command_receiver = setup_disconnect_grace_period_test(&server).await;
+    assert!(server.disconnect().await.is_ok());
+    assert!(check_test_recv_empty(&command_receiver));
+    Delay::new(Duration::from_millis(300)).await;
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+    );
+  });
+}
+
+#[test]
+fn test_no_device_stop_on_reconnect_in_grace_period() {
+  async_manager::block_on(async {
+    let server = ButtplugServerBuilder::default()
+      .disconnect_stop_grace_period(100)
+      .finish()
+      .expect("Test, assuming infallible.");
+    let command_receiver = setup_disconnect_grace_period_test(&server).await;
+    assert!(server.disconnect().await.is_ok());
+    let msg =
+      messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION);
+    assert!(server.parse_message(msg.into()).await.is_ok());
+    Delay::new(Duration::from_millis(300)).await;
+    assert!(check_test_recv_empty(&command_receiver));
+  });
+}
+
+#[test]
+fn test_no_device_stop_on_disconnect_when_disabled() {
+  async_manager::block_on(async {
+    let server = ButtplugServerBuilder::default()
+      .stop_devices_on_disconnect(false)
+      .finish()
+      .expect("Test, assuming infallible.");
+    let command_receiver = setup_disconnect_grace_period_test(&server).await;
+    assert!(server.disconnect().await.is_ok());
+    assert!(check_test_recv_empty(&command_receiver));
+  });
+}
+
 #[test]
 fn test_repeated_handshake() {
   let msg = messages::RequestServerInfo::new("Test Client", ButtplugMessageSpecVersion::Version2);
@@ -261,7 +505,9 @@ fn test_device_index_generation() {
     // Check that we got an event back about a new device.
     let mut index = 0u32;
     while let Some(msg) = recv.next().await {
-      if let ButtplugServerMessage::ScanningFinished(_) = msg {
+      if let ButtplugServerMessage::ScanningStarted(_)
+      | ButtplugServerMessage::ScanningFinished(_) = msg
+      {
         continue;
       } else if let ButtplugServerMessage::DeviceAdded(da) = msg {
         assert_eq!(da.device_name(), "Aneros Vivi");
@@ -284,6 +530,139 @@ fn test_device_index_generation() {
   });
 }
 
+#[test]
+fn test_server_state_snapshot() {
+  async_manager::block_on(async {
+    let server = ButtplugServerBuilder::default()
+      .max_ping_time(1000)
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper.add_ble_device("Massage Demo").await;
+    let snapshot = server.state_snapshot();
+    assert!(!snapshot.connected);
+    assert_eq!(snapshot.client_spec_version, None);
+    assert_eq!(snapshot.max_ping_time, 1000);
+    assert!(!snapshot.pinged_out);
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    let snapshot = server.state_snapshot();
+    assert!(snapshot.connected);
+    assert_eq!(snapshot.client_name, Some("Test Client".to_owned()));
+    assert_eq!(
+      snapshot.client_spec_version,
+      Some(BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+    );
+    assert_eq!(snapshot.devices.len(), 1);
+    assert_eq!(snapshot.devices[0].protocol, "aneros");
+    assert_eq!(snapshot.comm_managers.len(), 1);
+    // Front-ends running out of process get the snapshot as JSON.
+    let json = serde_json::to_string(&snapshot).expect("Test, assuming infallible.");
+    let parsed: ServerStateSnapshot =
+      serde_json::from_str(&json).expect("Test, assuming infallible.");
+    assert_eq!(parsed.devices, snapshot.devices);
+    assert_eq!(parsed.comm_managers, snapshot.comm_managers);
+  });
+}
+
+#[test]
+fn test_session_recording_and_replay() {
+  async_manager::block_on(async {
+    let path = std::env::temp_dir().join(format!("buttplug-session-{}.jsonl", std::process::id()));
+    let server = ButtplugServerBuilder::default()
+      .session_recorder(SessionRecorder::create(&path).expect("Test, assuming infallible."))
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let mut device_index = 100;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        device_index = da.device_index();
+        break;
+      }
+    }
+    // Leave a gap before the command, so the replay doesn't send it before
+    // the device has been found again.
+    Delay::new(Duration::from_millis(100)).await;
+    assert!(server
+      .parse_message(
+        messages::VibrateCmd::new(device_index, vec![messages::VibrateSubcommand::new(0, 0.5)])
+          .into(),
+      )
+      .await
+      .is_ok());
+
+    let session = load_session(&path).expect("Test, assuming infallible.");
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(session.len(), 3);
+    assert!(matches!(
+      session[2].message,
+      messages::ButtplugClientMessage::VibrateCmd(_)
+    ));
+    assert!(session[2].time_ms >= 100);
+
+    // Replay against a new server with the same test device.
+    let server = ButtplugServer::default();
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Massage Demo").await;
+    let replayed = replay_session(&server, &session, 1.0).await;
+    assert_eq!(replayed.len(), 3);
+    assert!(replayed.iter().all(|msg| msg.result.is_ok()));
+    let command_receiver = device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+  });
+}
+
 #[test]
 fn test_server_scanning_finished() {
   async_manager::block_on(async {
@@ -315,6 +694,9 @@ fn test_server_scanning_finished() {
     let mut finish_received = false;
     // We should get 3 messages: 2 DeviceAdded, 1 ScanningFinished.
     while let Some(msg) = recv.next().await {
+      if matches!(msg, ButtplugServerMessage::ScanningStarted(_)) {
+        continue;
+      }
       if matches!(msg, ButtplugServerMessage::ScanningFinished(_)) {
         finish_received = true;
         break;
@@ -337,19 +719,484 @@ fn test_server_scanning_finished() {
   });
 }
 
+// Waits for in flight events, then counts the ScanningFinished events received.
+async fn count_scanning_finished(
+  recv: &mut (impl Stream<Item = ButtplugServerMessage> + Unpin),
+) -> usize {
+  Delay::new(Duration::from_millis(100)).await;
+  let mut count = 0;
+  while let Some(Some(msg)) = recv.next().now_or_never() {
+    if matches!(msg, ButtplugServerMessage::ScanningFinished(_)) {
+      count += 1;
+    }
+  }
+  count
+}
+
 #[test]
-fn test_server_builder_null_device_config() {
+fn test_server_scanning_started_not_sent_to_older_clients() {
   async_manager::block_on(async {
-    let mut builder = ButtplugServerBuilder::default();
-    let _ = builder
-      .device_configuration_json(None)
-      .finish()
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
       .expect("Test, assuming infallible.");
+    helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", ButtplugMessageSpecVersion::Version2)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    // ScanningStarted is a spec v3 message, so a v2 client never sees it, even
+    // though the scan finishes.
+    while let Some(msg) = recv.next().await {
+      assert!(!matches!(msg, ButtplugServerMessage::ScanningStarted(_)));
+      if matches!(msg, ButtplugServerMessage::ScanningFinished(_)) {
+        break;
+      }
+    }
   });
 }
 
 #[test]
-fn test_server_builder_device_config_invalid_json() {
+fn test_server_scanning_started_not_sent_before_handshake() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper.add_ble_device("Massage Demo").await;
+    // Without a handshake, the server doesn't know whether the client could
+    // parse ScanningStarted.
+    assert!(server
+      .device_manager()
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      assert!(!matches!(msg, ButtplugServerMessage::ScanningStarted(_)));
+      if matches!(msg, ButtplugServerMessage::ScanningFinished(_)) {
+        break;
+      }
+    }
+  });
+}
+
+#[test]
+fn test_server_scanning_finished_waits_for_all_managers() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    server
+      .device_manager()
+      .add_comm_manager(util::DelayDeviceCommunicationManagerBuilder::default())
+      .expect("Test, assuming infallible.");
+    helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    // The test manager finishes right away, but the delay manager is still
+    // scanning.
+    assert_eq!(count_scanning_finished(&mut recv).await, 0);
+    assert!(server
+      .parse_message(messages::StopScanning::default().into())
+      .await
+      .is_ok());
+    assert_eq!(count_scanning_finished(&mut recv).await, 1);
+  });
+}
+
+#[test]
+fn test_server_scanning_finished_without_manager_report() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    server
+      .device_manager()
+      .add_comm_manager(util::DelayDeviceCommunicationManagerBuilder::default().silent_stop())
+      .expect("Test, assuming infallible.");
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    assert_eq!(count_scanning_finished(&mut recv).await, 0);
+    assert!(server
+      .parse_message(messages::StopScanning::default().into())
+      .await
+      .is_ok());
+    assert_eq!(count_scanning_finished(&mut recv).await, 1);
+    // Stopping again errors, and doesn't finish the scan a second time.
+    assert!(server
+      .parse_message(messages::StopScanning::default().into())
+      .await
+      .is_err());
+    assert_eq!(count_scanning_finished(&mut recv).await, 0);
+  });
+}
+
+#[test]
+fn test_server_scanning_fails_when_all_managers_fail() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    server
+      .device_manager()
+      .add_comm_manager(util::DelayDeviceCommunicationManagerBuilder::default().fail_start())
+      .expect("Test, assuming infallible.");
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    let err = server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .expect_err("Test, assuming infallible.");
+    assert!(matches!(
+      err.original_error(),
+      ButtplugError::ButtplugDeviceError(ButtplugDeviceError::DeviceScanningFailed(_))
+    ));
+  });
+}
+
+#[test]
+fn test_server_scanning_partial_failure() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    server
+      .device_manager()
+      .add_comm_manager(util::DelayDeviceCommunicationManagerBuilder::default().fail_start())
+      .expect("Test, assuming infallible.");
+    helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::ScanningPartialFailure(failure) = msg {
+        assert_eq!(failure.failures().len(), 1);
+        assert_eq!(
+          failure.failures()[0].comm_manager,
+          "DelayDeviceCommunicationManager"
+        );
+        break;
+      }
+    }
+  });
+}
+
+#[test]
+fn test_server_scanning_partial_failure_not_sent_to_older_clients() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    server
+      .device_manager()
+      .add_comm_manager(util::DelayDeviceCommunicationManagerBuilder::default().fail_start())
+      .expect("Test, assuming infallible.");
+    helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", ButtplugMessageSpecVersion::Version2)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    Delay::new(Duration::from_millis(100)).await;
+    while let Some(Some(msg)) = recv.next().now_or_never() {
+      assert!(!matches!(
+        msg,
+        ButtplugServerMessage::ScanningPartialFailure(_)
+      ));
+    }
+  });
+}
+
+#[test]
+fn test_server_scanning_no_bluetooth_adapter() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    server
+      .device_manager()
+      .add_comm_manager(
+        util::DelayDeviceCommunicationManagerBuilder::default().no_bluetooth_adapter(),
+      )
+      .expect("Test, assuming infallible.");
+    helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::Error(err) = msg {
+        assert_eq!(
+          err.error_details.as_ref().map(|details| details.error_class),
+          Some(messages::ErrorClass::NoBluetoothAdapter)
+        );
+        assert!(matches!(
+          err.original_error(),
+          ButtplugError::ButtplugDeviceError(ButtplugDeviceError::NoBluetoothAdapter)
+        ));
+        break;
+      }
+    }
+  });
+}
+
+#[test]
+fn test_server_builder_comm_managers() {
+  async_manager::block_on(async {
+    let mut builder = ButtplugServerBuilder::default();
+    builder.comm_manager(TestDeviceCommunicationManagerBuilder::default);
+    // Each finished server gets its own comm manager.
+    for _ in 0..2 {
+      let server = builder.finish().expect("Test, assuming infallible.");
+      let comm_managers = server.state_snapshot().comm_managers;
+      assert_eq!(comm_managers.len(), 1);
+      assert_eq!(comm_managers[0].name, "TestDeviceCommunicationManager");
+      assert!(comm_managers[0].capabilities.available);
+      assert!(!comm_managers[0].capabilities.exclusive_access);
+    }
+    // Adding the same comm manager type twice fails when building.
+    builder.comm_manager(TestDeviceCommunicationManagerBuilder::default);
+    assert!(builder.finish().is_err());
+  });
+}
+
+#[test]
+fn test_server_builder_background_scanning() {
+  async_manager::block_on(async {
+    let server = ButtplugServerBuilder::default()
+      .finish()
+      .expect("Test, assuming infallible.");
+    assert!(!server.device_manager().background_scanning());
+    let server = ButtplugServerBuilder::default()
+      .background_scanning(true)
+      .finish()
+      .expect("Test, assuming infallible.");
+    assert!(server.device_manager().background_scanning());
+  });
+}
+
+#[test]
+fn test_remove_comm_manager() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    server
+      .device_manager()
+      .add_comm_manager(TestDeviceCommunicationManagerBuilder::default())
+      .expect("Test, assuming infallible.");
+    let msg =
+      messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION);
+    assert!(server.parse_message(msg.into()).await.is_ok());
+    server
+      .device_manager()
+      .remove_comm_manager("TestDeviceCommunicationManager")
+      .expect("Test, assuming infallible.");
+    assert!(server.state_snapshot().comm_managers.is_empty());
+    assert!(server
+      .device_manager()
+      .remove_comm_manager("TestDeviceCommunicationManager")
+      .is_err());
+    // With nothing left to scan with, scanning fails.
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_err());
+  });
+}
+
+#[test]
+fn test_pause_and_resume_comm_manager() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let _ = helper.add_ble_device("Massage Demo").await;
+    let msg =
+      messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION);
+    assert!(server.parse_message(msg.into()).await.is_ok());
+
+    server
+      .device_manager()
+      .pause_comm_manager("TestDeviceCommunicationManager")
+      .expect("Test, assuming infallible.");
+    assert!(server.state_snapshot().comm_managers[0].paused);
+    // The only comm manager is paused, so there's nothing to scan with.
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_err());
+
+    server
+      .device_manager()
+      .resume_comm_manager("TestDeviceCommunicationManager")
+      .expect("Test, assuming infallible.");
+    assert!(!server.state_snapshot().comm_managers[0].paused);
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        assert_eq!(da.device_name(), "Aneros Vivi");
+        break;
+      }
+    }
+    assert!(server
+      .device_manager()
+      .pause_comm_manager("NotACommManager")
+      .is_err());
+  });
+}
+
+#[test]
+fn test_pause_and_resume_all_io() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let _ = helper.add_ble_device("Massage Demo").await;
+    let msg =
+      messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION);
+    assert!(server.parse_message(msg.into()).await.is_ok());
+
+    server
+      .device_manager()
+      .pause_all_io()
+      .await
+      .expect("Test, assuming infallible.");
+    assert!(server.state_snapshot().comm_managers[0].paused);
+    // Nothing scans while IO is paused.
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_err());
+
+    server
+      .device_manager()
+      .resume_all_io()
+      .await
+      .expect("Test, assuming infallible.");
+    assert!(!server.state_snapshot().comm_managers[0].paused);
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        assert_eq!(da.device_name(), "Aneros Vivi");
+        break;
+      }
+    }
+  });
+}
+
+#[test]
+fn test_server_builder_null_device_config() {
+  async_manager::block_on(async {
+    let mut builder = ButtplugServerBuilder::default();
+    let _ = builder
+      .device_configuration_json(None)
+      .finish()
+      .expect("Test, assuming infallible.");
+  });
+}
+
+#[test]
+fn test_server_builder_device_config_invalid_json() {
   async_manager::block_on(async {
     let mut builder = ButtplugServerBuilder::default();
     assert!(builder
@@ -499,6 +1346,63 @@ fn test_server_builder_user_device_config_old_config_version() {
   });
 }
 
+#[test]
+fn test_server_request_log() {
+  async_manager::block_on(async {
+    let forwarder = ButtplugLogForwarder::default();
+    let server = ButtplugServerBuilder::default()
+      .log_forwarder(forwarder.clone())
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    let subscriber = || tracing_subscriber::registry().with(forwarder.clone());
+    // Nothing is forwarded until the client asks for logs.
+    tracing::subscriber::with_default(subscriber(), || tracing::error!("Not requested"));
+    assert!(recv.next().now_or_never().is_none());
+    let reply = server
+      .parse_message(messages::RequestLog::new(messages::LogLevel::Warn).into())
+      .await;
+    assert!(matches!(reply, Ok(ButtplugServerMessage::Ok(_))));
+    tracing::subscriber::with_default(subscriber(), || {
+      tracing::debug!("Too verbose");
+      tracing::warn!(device_index = 1, "Forwarded");
+    });
+    match recv.next().now_or_never() {
+      Some(Some(ButtplugServerMessage::Log(log))) => {
+        assert_eq!(log.log_level(), messages::LogLevel::Warn);
+        assert!(log.log_message().contains("Forwarded device_index=1"));
+      }
+      msg => panic!("Expected Log message, got {:?}", msg),
+    }
+    assert!(recv.next().now_or_never().is_none());
+    // Disconnecting resets the level.
+    assert!(server.disconnect().await.is_ok());
+    tracing::subscriber::with_default(subscriber(), || tracing::error!("After disconnect"));
+    assert!(recv.next().now_or_never().is_none());
+  });
+}
+
+#[test]
+fn test_server_request_log_without_forwarder() {
+  async_manager::block_on(async {
+    let msg =
+      messages::RequestServerInfo::new("Test Client", ButtplugMessageSpecVersion::Version2).into();
+    let (server, _recv) = setup_test_server(msg).await;
+    let reply = server
+      .parse_message(messages::RequestLog::new(messages::LogLevel::Warn).into())
+      .await;
+    assert!(reply.is_err());
+  });
+}
+
 // TODO Test sending system message (Id 0)
 // TODO Test sending system message (Ok but Id > 0)
 // TODO Test repeated handshake
@@ -1,21 +1,72 @@
 mod util;
 use buttplug::{
   core::{
-    errors::{ButtplugDeviceError, ButtplugError},
+    errors::{
+      ButtplugDeviceConnectionError,
+      ButtplugDeviceError,
+      ButtplugError,
+      ButtplugMessageError,
+    },
     messages::{
       self,
+      ButtplugClientMessage,
+      ButtplugDeviceCommandMessageUnion,
+      ButtplugDeviceMessage,
       ButtplugDeviceMessageType,
+      ButtplugMessage,
+      ButtplugMessageSpecVersion,
       ButtplugServerMessage,
+      DeviceMessageAttributes,
+      DeviceMessageAttributesMap,
+      DeviceRemovedReason,
       BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
     },
   },
-  device::Endpoint,
-  server::comm_managers::test::TestDeviceCommunicationManagerBuilder,
-  server::{ButtplugServer, ButtplugServerBuilder},
-  util::async_manager,
+  device::{
+    configuration_manager::{DeviceProtocolConfiguration, ProtocolDefinition},
+    protocol::{
+      fleshlight_launch_helper::{get_duration, get_speed},
+      generic_command_manager::GenericCommandManager,
+      vorze_sa::get_piston_speed,
+      ButtplugProtocol,
+      ButtplugProtocolCommandHandler,
+      ButtplugProtocolFactory,
+      ButtplugProtocolProperties,
+    },
+    ButtplugDeviceEvent,
+    ButtplugDeviceResultFuture,
+    DeviceImpl,
+    DeviceImplCommand,
+    DeviceTrafficDirection,
+    DeviceWriteCmd,
+    Endpoint,
+  },
+  server::{
+    client_permissions::ClientPermissions,
+    device_manager::{UnmatchedDeviceReport, UnmatchedDeviceReporting},
+    in_flight_messages::{ClientInFlightOptions, ClientOverloadPolicy},
+    ButtplugServer,
+    ButtplugServerBuilder,
+  },
+  util::{
+    async_manager,
+    device_configuration::UserDeviceConfigFile,
+    testing::{
+      check_test_recv_empty,
+      check_test_recv_value,
+      new_bluetoothle_test_device,
+      TestDeviceCommunicationManagerBuilder,
+    },
+  },
 };
-use futures::{pin_mut, StreamExt};
-use std::matches;
+use futures::{
+  future::{self, BoxFuture},
+  pin_mut,
+  FutureExt,
+  StreamExt,
+};
+use futures_timer::Delay;
+use std::{collections::BTreeSet, matches, sync::Arc, time::Duration};
 
 // Test devices that have protocols that support movements not all devices do.
 // For instance, the Onyx+ is part of a protocol that supports vibration, but
@@ -64,6 +115,200 @@ fn test_capabilities_exposure() {
   });
 }
 
+// Protocols can be tested without a server, through the public testing API.
+#[test]
+fn test_protocol_against_test_device() {
+  async_manager::block_on(async {
+    let (device, test_device) = new_bluetoothle_test_device("Massage Demo")
+      .await
+      .expect("Test, assuming infallible.");
+    assert_eq!(device.name(), "Aneros Vivi");
+    let command_receiver = test_device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    device
+      .parse_message(
+        messages::VibrateCmd::new(0, vec![messages::VibrateSubcommand::new(0, 0.5)]).into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+    assert!(check_test_recv_empty(&command_receiver));
+  });
+}
+
+// Budget Svakom, Ankni and Leten devices advertise names that don't say much,
+// so make sure they're all picked up by a protocol when scanned for.
+#[test]
+fn test_budget_brand_devices_found() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let mut leten = None;
+    for name in ["Ella NEO", "Sam Neo", "Alex NEO", "Iker", "DSJM", "ToyCod"] {
+      let device = helper.add_ble_device(name).await;
+      if name == "ToyCod" {
+        leten = Some(device);
+      }
+    }
+    let leten = leten.expect("Test, assuming infallible.");
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .expect("Test, assuming infallible.");
+    let mut found = BTreeSet::new();
+    let mut leten_index = None;
+    while found.len() < 6 {
+      let msg = recv.next().await.expect("Test, assuming infallible.");
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        if da.device_name() == "Leten Device" {
+          leten_index = Some(da.device_index());
+        }
+        found.insert(da.device_name().clone());
+      }
+    }
+    assert_eq!(
+      found,
+      BTreeSet::from(
+        [
+          "Svakom Ella Neo",
+          "Svakom Sam Neo",
+          "Svakom Alex Neo",
+          "Svakom Iker",
+          "Ankni Candy",
+          "Leten Device",
+        ]
+        .map(str::to_owned)
+      )
+    );
+    // Commands reach the Leten device through the server, after the manual
+    // mode handshake sent during initialization.
+    let command_receiver = leten
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(
+        Endpoint::Tx,
+        vec![0x02, 0x00, 0x00],
+        true,
+      )),
+    );
+    server
+      .parse_message(
+        messages::VibrateCmd::new(
+          leten_index.expect("Test, assuming infallible."),
+          vec![messages::VibrateSubcommand::new(0, 1.0)],
+        )
+        .into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(
+        Endpoint::Tx,
+        vec![0x02, 0x00, 20],
+        true,
+      )),
+    );
+    assert!(check_test_recv_empty(&command_receiver));
+  });
+}
+
+// SingleMotorVibrateCmd should run every vibrator on a device, not just the
+// first one.
+#[test]
+fn test_single_motor_vibrate_cmd_multiple_motors() {
+  async_manager::block_on(async {
+    let (device, test_device) = new_bluetoothle_test_device("Massage Demo")
+      .await
+      .expect("Test, assuming infallible.");
+    let command_receiver = test_device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    device
+      .parse_message(messages::SingleMotorVibrateCmd::new(0, 0.5).into())
+      .await
+      .expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 64], false)),
+    );
+    assert!(check_test_recv_empty(&command_receiver));
+  });
+}
+
+#[test]
+fn test_single_motor_vibrate_cmd_without_vibrators() {
+  async_manager::block_on(async {
+    let (device, _) = new_bluetoothle_test_device("VorzePiston")
+      .await
+      .expect("Test, assuming infallible.");
+    let reply = device
+      .parse_message(messages::SingleMotorVibrateCmd::new(0, 0.5).into())
+      .await;
+    assert!(matches!(
+      reply,
+      Err(ButtplugError::ButtplugDeviceError(
+        ButtplugDeviceError::MessageNotSupported(ButtplugDeviceMessageType::SingleMotorVibrateCmd)
+      ))
+    ));
+  });
+}
+
+// Legacy clients only know FleshlightLaunchFW12Cmd, which should still drive
+// linear devices that aren't Launches.
+#[test]
+fn test_fleshlight_launch_fw12_cmd_translation() {
+  async_manager::block_on(async {
+    let (device, test_device) = new_bluetoothle_test_device("VorzePiston")
+      .await
+      .expect("Test, assuming infallible.");
+    assert!(device
+      .message_attributes()
+      .contains_key(&ButtplugDeviceMessageType::FleshlightLaunchFW12Cmd));
+    let command_receiver = test_device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    device
+      .parse_message(messages::FleshlightLaunchFW12Cmd::new(0, 99, 50).into())
+      .await
+      .expect("Test, assuming infallible.");
+    let duration = get_duration(1f64, 50f64 / 99f64);
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(
+        Endpoint::Tx,
+        vec![3, 200, get_piston_speed(200f64, duration as f64)],
+        true,
+      )),
+    );
+    assert!(check_test_recv_empty(&command_receiver));
+  });
+}
+
 #[test]
 fn test_server_raw_message() {
   async_manager::block_on(async {
@@ -92,7 +337,9 @@ fn test_server_raw_message() {
       .await
       .is_ok());
     while let Some(msg) = recv.next().await {
-      if let ButtplugServerMessage::ScanningFinished(_) = msg {
+      if let ButtplugServerMessage::ScanningStarted(_)
+      | ButtplugServerMessage::ScanningFinished(_) = msg
+      {
         continue;
       } else if let ButtplugServerMessage::DeviceAdded(da) = msg {
         assert_eq!(da.device_name(), "Aneros Vivi (Raw Messages Allowed)");
@@ -119,6 +366,139 @@ fn test_server_raw_message() {
   });
 }
 
+#[test]
+fn test_server_raw_message_per_device_user_config() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Massage Demo").await;
+    let mut user_config = UserDeviceConfigFile::default();
+    user_config
+      .device_config_mut(&device.address())
+      .set_allow_raw_messages(Some(true));
+    server.device_manager().set_user_config_file(&user_config);
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let mut device_index = 100;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        assert!(da
+          .device_messages()
+          .contains_key(&ButtplugDeviceMessageType::RawWriteCmd));
+        device_index = da.device_index();
+        break;
+      }
+    }
+    assert!(server
+      .parse_message(
+        messages::RawWriteCmd::new(device_index, Endpoint::Tx, vec![0x0], false).into()
+      )
+      .await
+      .is_ok());
+    // Taking access away applies to the connected device right away.
+    user_config.remove_device_config(&device.address());
+    server.device_manager().set_user_config_file(&user_config);
+    let err = server
+      .parse_message(
+        messages::RawWriteCmd::new(device_index, Endpoint::Tx, vec![0x0], false).into(),
+      )
+      .await
+      .expect_err("Test, assuming infallible.");
+    assert!(matches!(
+      err.original_error(),
+      ButtplugError::ButtplugDeviceError(ButtplugDeviceError::MessageNotSupported(
+        ButtplugDeviceMessageType::RawWriteCmd
+      ))
+    ));
+  });
+}
+
+#[test]
+fn test_server_raw_write_chunking_user_config() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Massage Demo").await;
+    let mut user_config = UserDeviceConfigFile::default();
+    let device_config = user_config.device_config_mut(&device.address());
+    device_config.set_allow_raw_messages(Some(true));
+    device_config.set_chunk_raw_writes(Some(true));
+    server.device_manager().set_user_config_file(&user_config);
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let mut device_index = 100;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        device_index = da.device_index();
+        break;
+      }
+    }
+    let command_receiver = device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    let data = vec![0x0; 64];
+    // Raw writes are marked as chunkable for the device impl once the user
+    // opts in.
+    assert!(server
+      .parse_message(
+        messages::RawWriteCmd::new(device_index, Endpoint::Tx, data.clone(), true).into()
+      )
+      .await
+      .is_ok());
+    let mut expected = DeviceWriteCmd::new(Endpoint::Tx, data.clone(), true);
+    expected.raw = true;
+    expected.chunked = true;
+    check_test_recv_value(&command_receiver, DeviceImplCommand::Write(expected));
+    // Turning it back off applies to the connected device right away, and
+    // raw writes go back to being sent whole, or rejected if too long.
+    user_config
+      .device_config_mut(&device.address())
+      .set_chunk_raw_writes(None);
+    server.device_manager().set_user_config_file(&user_config);
+    assert!(server
+      .parse_message(
+        messages::RawWriteCmd::new(device_index, Endpoint::Tx, data.clone(), true).into()
+      )
+      .await
+      .is_ok());
+    let mut expected = DeviceWriteCmd::new(Endpoint::Tx, data, true);
+    expected.raw = true;
+    check_test_recv_value(&command_receiver, DeviceImplCommand::Write(expected));
+  });
+}
+
 #[test]
 fn test_server_no_raw_message() {
   async_manager::block_on(async {
@@ -144,7 +524,9 @@ fn test_server_no_raw_message() {
       .await
       .is_ok());
     while let Some(msg) = recv.next().await {
-      if let ButtplugServerMessage::ScanningFinished(_) = msg {
+      if let ButtplugServerMessage::ScanningStarted(_)
+      | ButtplugServerMessage::ScanningFinished(_) = msg
+      {
         continue;
       } else if let ButtplugServerMessage::DeviceAdded(da) = msg {
         assert_eq!(da.device_name(), "Aneros Vivi");
@@ -196,7 +578,9 @@ fn test_reject_on_no_raw_message() {
       .await
       .is_ok());
     while let Some(msg) = recv.next().await {
-      if let ButtplugServerMessage::ScanningFinished(_) = msg {
+      if let ButtplugServerMessage::ScanningStarted(_)
+      | ButtplugServerMessage::ScanningFinished(_) = msg
+      {
         continue;
       } else if let ButtplugServerMessage::DeviceAdded(da) = msg {
         assert_eq!(da.device_name(), "Aneros Vivi");
@@ -249,9 +633,8 @@ fn test_reject_on_no_raw_message() {
   });
 }
 
-#[cfg(target = "windows")]
 #[test]
-fn test_repeated_address_additions() {
+fn test_reject_unsupported_pattern_upload() {
   async_manager::block_on(async {
     let server = ButtplugServer::default();
     let recv = server.event_stream();
@@ -262,12 +645,7 @@ fn test_repeated_address_additions() {
       .device_manager()
       .add_comm_manager(builder)
       .expect("Test, assuming infallible.");
-    helper
-      .add_ble_device_with_address("Massage Demo", "SameAddress")
-      .await;
-    helper
-      .add_ble_device_with_address("Massage Demo", "SameAddress")
-      .await;
+    helper.add_ble_device("Massage Demo").await;
     assert!(server
       .parse_message(
         messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
@@ -279,38 +657,2578 @@ fn test_repeated_address_additions() {
       .parse_message(messages::StartScanning::default().into())
       .await
       .is_ok());
-    let mut device_index = None;
-    let mut device_removed_called = true;
     while let Some(msg) = recv.next().await {
-      match msg {
-        ButtplugServerMessage::ScanningFinished(_) => continue,
-        ButtplugServerMessage::DeviceAdded(da) => {
-          assert_eq!(da.device_name(), "Aneros Vivi");
-          if device_index.is_none() {
-            device_index = Some(da.device_index());
-          } else {
-            assert!(device_removed_called);
-            assert_eq!(
-              da.device_index(),
-              device_index.expect("Test, assuming infallible.")
-            );
-            return;
-          }
-        }
-        ButtplugServerMessage::DeviceRemoved(dr) => {
-          assert_eq!(
-            dr.device_index(),
-            device_index.expect("Test, assuming infallible.")
-          );
-          device_removed_called = true;
-        }
-        _ => {
-          panic!(
-            "Returned message was not a DeviceAdded message or timed out: {:?}",
-            msg
-          );
-        }
+      if let ButtplugServerMessage::ScanningStarted(_)
+      | ButtplugServerMessage::ScanningFinished(_) = msg
+      {
+        continue;
+      } else if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        assert!(!da
+          .device_messages()
+          .contains_key(&ButtplugDeviceMessageType::UploadPatternCmd));
+        let should_be_err = server
+          .parse_message(messages::UploadPatternCmd::new(da.device_index(), vec![0x0]).into())
+          .await;
+        assert!(should_be_err.is_err());
+        assert!(matches!(
+          should_be_err.unwrap_err().original_error(),
+          ButtplugError::ButtplugDeviceError(ButtplugDeviceError::MessageNotSupported(_))
+        ));
+        return;
+      } else {
+        panic!(
+          "Returned message was not a DeviceAdded message or timed out: {:?}",
+          msg
+        );
       }
     }
   });
 }
+
+#[test]
+fn test_identify_only_scan() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let identified = server.device_manager().identified_device_stream();
+    pin_mut!(identified);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper.add_ble_device("Massage Demo").await;
+    server.device_manager().set_identify_only(true);
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let device = identified
+      .next()
+      .await
+      .expect("Test, assuming infallible.");
+    assert_eq!(device.name, "Massage Demo");
+    assert_eq!(device.protocol, "aneros");
+    assert!(server.device_manager().device_snapshots().is_empty());
+  });
+}
+
+#[test]
+fn test_connect_identified_device() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let identified = server.device_manager().identified_device_stream();
+    pin_mut!(identified);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper.add_ble_device("Massage Demo").await;
+    server.device_manager().set_identify_only(true);
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let device = identified.next().await.expect("Test, assuming infallible.");
+    assert_eq!(
+      server.device_manager().identified_devices(),
+      vec![device.clone()]
+    );
+    assert!(server
+      .device_manager()
+      .connect_identified_device("not-a-real-address")
+      .await
+      .is_err());
+    server
+      .device_manager()
+      .connect_identified_device(&device.address)
+      .await
+      .expect("Test, assuming infallible.");
+    assert!(server.device_manager().identified_devices().is_empty());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        assert_eq!(da.device_name(), "Aneros Vivi");
+        break;
+      }
+    }
+  });
+}
+
+#[test]
+fn test_connect_device_by_address() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper
+      .add_ble_device_with_address("Massage Demo", "remembered-address")
+      .await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .device_manager()
+      .connect_device("not-a-real-address")
+      .await
+      .is_err());
+    // No scanning needed, the device should connect from its address alone.
+    server
+      .device_manager()
+      .connect_device("remembered-address")
+      .await
+      .expect("Test, assuming infallible.");
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        assert_eq!(da.device_name(), "Aneros Vivi");
+        break;
+      }
+    }
+  });
+}
+
+#[test]
+fn test_background_scanning_connects_allowed_devices() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper
+      .add_ble_device_with_address("Massage Demo", "allowed-address")
+      .await;
+    // Found first, but not allow listed, so it should be skipped.
+    helper
+      .add_ble_device_with_address("Massage Demo", "unknown-address")
+      .await;
+    let mut user_config = UserDeviceConfigFile::default();
+    user_config
+      .device_config_mut("allowed-address")
+      .set_allow(Some(true));
+    server.device_manager().set_user_config_file(&user_config);
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    server
+      .device_manager()
+      .set_background_scanning(true)
+      .await
+      .expect("Test, assuming infallible.");
+    assert!(server.device_manager().background_scanning());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    let devices = server.device_manager().device_snapshots();
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0].address, "allowed-address");
+  });
+}
+
+#[test]
+fn test_protocol_fallback_on_failed_initialization() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let btle = r#"
+      "btle": {
+        "names": ["Massage Fallback"],
+        "services": {
+          "0000ff00-0000-1000-8000-00805f9b34fb": {
+            "tx": "0000ff01-0000-1000-8000-00805f9b34fb"
+          }
+        }
+      }"#;
+    // Without any attributes for the device, aneros fails to initialize, so
+    // creation should fall back to cachito, which also matches the name.
+    let failing: ProtocolDefinition =
+      serde_json::from_str(&format!("{{{}}}", btle)).expect("Test, assuming infallible.");
+    let working: ProtocolDefinition = serde_json::from_str(&format!(
+      r#"{{{},
+        "defaults": {{
+          "name": {{ "en-us": "Fallback Vibrator" }},
+          "messages": {{ "VibrateCmd": {{ "FeatureCount": 1, "StepCount": [100] }} }}
+        }}
+      }}"#,
+      btle
+    ))
+    .expect("Test, assuming infallible.");
+    server
+      .device_manager()
+      .add_protocol_definition("aneros", failing);
+    server
+      .device_manager()
+      .add_protocol_definition("cachito", working);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper.add_ble_device("Massage Fallback").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        assert_eq!(da.device_name(), "Fallback Vibrator");
+        break;
+      }
+    }
+  });
+}
+
+#[test]
+fn test_device_initialization_failed_event() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    // Without any attributes for the device, aneros fails to initialize.
+    let failing: ProtocolDefinition = serde_json::from_str(
+      r#"{
+        "btle": {
+          "names": ["Broken Massager"],
+          "services": {
+            "0000ff00-0000-1000-8000-00805f9b34fb": {
+              "tx": "0000ff01-0000-1000-8000-00805f9b34fb"
+            }
+          }
+        }
+      }"#,
+    )
+    .expect("Test, assuming infallible.");
+    server
+      .device_manager()
+      .add_protocol_definition("aneros", failing);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper
+      .add_ble_device_with_address("Broken Massager", "broken-massager")
+      .await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      match msg {
+        ButtplugServerMessage::DeviceInitializationFailed(failed) => {
+          assert_eq!(failed.device_name(), "Broken Massager");
+          assert_eq!(failed.device_address(), "broken-massager");
+          assert_eq!(failed.protocols(), &vec!["aneros".to_owned()]);
+          break;
+        }
+        ButtplugServerMessage::DeviceAdded(_) => panic!("Device should not have been added."),
+        _ => {}
+      }
+    }
+  });
+}
+
+#[test]
+fn test_device_initialization_failed_not_sent_to_older_clients() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    // Without any attributes for the device, aneros fails to initialize.
+    let failing: ProtocolDefinition = serde_json::from_str(
+      r#"{
+        "btle": {
+          "names": ["Broken Massager"],
+          "services": {
+            "0000ff00-0000-1000-8000-00805f9b34fb": {
+              "tx": "0000ff01-0000-1000-8000-00805f9b34fb"
+            }
+          }
+        }
+      }"#,
+    )
+    .expect("Test, assuming infallible.");
+    server
+      .device_manager()
+      .add_protocol_definition("aneros", failing);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper
+      .add_ble_device_with_address("Broken Massager", "broken-massager")
+      .await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", ButtplugMessageSpecVersion::Version2)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    Delay::new(Duration::from_millis(100)).await;
+    while let Some(Some(msg)) = recv.next().now_or_never() {
+      assert!(!matches!(
+        msg,
+        ButtplugServerMessage::DeviceInitializationFailed(_)
+      ));
+    }
+  });
+}
+
+#[test]
+fn test_device_connection_retried_when_busy() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Massage Demo").await;
+    device.add_connection_failure(ButtplugDeviceConnectionError::Busy("Test".to_owned()));
+    device.add_connection_failure(ButtplugDeviceConnectionError::Timeout("Test".to_owned()));
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      match msg {
+        ButtplugServerMessage::DeviceAdded(da) => {
+          assert_eq!(da.device_name(), "Aneros Vivi");
+          break;
+        }
+        ButtplugServerMessage::DeviceInitializationFailed(_) => {
+          panic!("Device should have connected on retry.")
+        }
+        _ => {}
+      }
+    }
+  });
+}
+
+#[test]
+fn test_device_connection_not_retried_when_not_found() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Massage Demo").await;
+    device.add_connection_failure(ButtplugDeviceConnectionError::NotFound("Test".to_owned()));
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      match msg {
+        ButtplugServerMessage::DeviceInitializationFailed(failed) => {
+          assert_eq!(failed.device_name(), "Massage Demo");
+          break;
+        }
+        ButtplugServerMessage::DeviceAdded(_) => panic!("Device should not have been added."),
+        _ => {}
+      }
+    }
+  });
+}
+
+#[test]
+fn test_device_connection_gives_up_after_retries() {
+  async_manager::block_on(async {
+    let server = ButtplugServerBuilder::default()
+      .device_connection_retries(1)
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Massage Demo").await;
+    device.add_connection_failure(ButtplugDeviceConnectionError::Busy("Test".to_owned()));
+    device.add_connection_failure(ButtplugDeviceConnectionError::Busy("Test".to_owned()));
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      match msg {
+        ButtplugServerMessage::DeviceInitializationFailed(failed) => {
+          assert_eq!(failed.device_name(), "Massage Demo");
+          break;
+        }
+        ButtplugServerMessage::DeviceAdded(_) => panic!("Device should not have been added."),
+        _ => {}
+      }
+    }
+  });
+}
+
+#[test]
+fn test_unmatched_device_report() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper.add_ble_device("Massage Demo").await;
+    let unmatched = helper.add_ble_device("Not A Real Toy").await;
+    server
+      .device_manager()
+      .set_unmatched_device_reporting(UnmatchedDeviceReporting::Inspect);
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    // Matched devices still connect as usual.
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        assert_eq!(da.device_name(), "Aneros Vivi");
+        break;
+      }
+    }
+    let mut report = server.device_manager().unmatched_device_report();
+    for _ in 0..10 {
+      if !report.devices.is_empty() {
+        break;
+      }
+      Delay::new(Duration::from_millis(10)).await;
+      report = server.device_manager().unmatched_device_report();
+    }
+    assert_eq!(report.devices.len(), 1);
+    assert_eq!(report.devices[0].name, "Not A Real Toy");
+    assert_eq!(report.devices[0].address, unmatched.address());
+    // Inspection was allowed, so services were listed.
+    assert_eq!(report.devices[0].services, Some(vec![]));
+    let parsed: UnmatchedDeviceReport =
+      serde_json::from_str(&report.to_json()).expect("Test, assuming infallible.");
+    assert_eq!(parsed, report);
+    server.device_manager().clear_unmatched_devices();
+    assert!(server
+      .device_manager()
+      .unmatched_device_report()
+      .devices
+      .is_empty());
+  });
+}
+
+#[test]
+fn test_unmatched_device_reporting_off_by_default() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper.add_ble_device("Not A Real Toy").await;
+    assert_eq!(
+      server.device_manager().unmatched_device_reporting(),
+      UnmatchedDeviceReporting::Off
+    );
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if matches!(msg, ButtplugServerMessage::ScanningFinished(_)) {
+        break;
+      }
+    }
+    assert!(server
+      .device_manager()
+      .unmatched_device_report()
+      .devices
+      .is_empty());
+  });
+}
+
+#[test]
+fn test_device_claim_and_release() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let mut device_index = 100;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        device_index = da.device_index();
+        break;
+      }
+    }
+    let err = server
+      .parse_message(messages::ClaimDevice::new(device_index + 1).into())
+      .await
+      .expect_err("Test, assuming infallible.");
+    assert!(matches!(
+      err.original_error(),
+      ButtplugError::ButtplugDeviceError(ButtplugDeviceError::DeviceNotAvailable(_))
+    ));
+    assert!(server
+      .parse_message(messages::ClaimDevice::new(device_index).into())
+      .await
+      .is_ok());
+    // Claiming a device we already hold shouldn't fail.
+    assert!(server
+      .parse_message(messages::ClaimDevice::new(device_index).into())
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(
+        messages::VibrateCmd::new(device_index, vec![messages::VibrateSubcommand::new(0, 0.5)])
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::ReleaseDevice::new(device_index).into())
+      .await
+      .is_ok());
+    let err = server
+      .parse_message(messages::ReleaseDevice::new(device_index).into())
+      .await
+      .expect_err("Test, assuming infallible.");
+    assert!(matches!(
+      err.original_error(),
+      ButtplugError::ButtplugDeviceError(ButtplugDeviceError::DeviceNotClaimed(_))
+    ));
+    let mut events = vec![];
+    while events.len() < 2 {
+      match recv.next().await.expect("Test, assuming infallible.") {
+        ButtplugServerMessage::DeviceClaimed(msg) => events.push(msg.client_name().to_owned()),
+        ButtplugServerMessage::DeviceReleased(msg) => events.push(msg.client_name().to_owned()),
+        _ => {}
+      }
+    }
+    assert_eq!(events, vec!["Test Client", "Test Client"]);
+  });
+}
+
+#[test]
+fn test_device_claim_released_on_disconnect() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let mut device_index = 100;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        device_index = da.device_index();
+        break;
+      }
+    }
+    assert!(server
+      .parse_message(messages::ClaimDevice::new(device_index).into())
+      .await
+      .is_ok());
+    assert!(server.disconnect().await.is_ok());
+    // Nobody is connected to be told about the release, but the next client
+    // can claim the device.
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Other Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::ClaimDevice::new(device_index).into())
+      .await
+      .is_ok());
+  });
+}
+
+#[test]
+fn test_stop_claimed_devices() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let claimed_device = helper
+      .add_ble_device_with_address("Massage Demo", "claimed")
+      .await;
+    let other_device = helper
+      .add_ble_device_with_address("Massage Demo", "other")
+      .await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let mut claimed_index = None;
+    let mut other_index = None;
+    while claimed_index.is_none() || other_index.is_none() {
+      if let ButtplugServerMessage::DeviceAdded(da) =
+        recv.next().await.expect("Test, assuming infallible.")
+      {
+        match da.device_address().as_deref() {
+          Some("claimed") => claimed_index = Some(da.device_index()),
+          Some("other") => other_index = Some(da.device_index()),
+          _ => panic!("Unexpected device added."),
+        }
+      }
+    }
+    let claimed_index = claimed_index.expect("Test, assuming infallible.");
+    let other_index = other_index.expect("Test, assuming infallible.");
+    assert!(server
+      .parse_message(messages::ClaimDevice::new(claimed_index).into())
+      .await
+      .is_ok());
+    for device_index in [claimed_index, other_index] {
+      assert!(server
+        .parse_message(
+          messages::VibrateCmd::new(
+            device_index,
+            vec![
+              messages::VibrateSubcommand::new(0, 0.5),
+              messages::VibrateSubcommand::new(1, 0.5),
+            ]
+          )
+          .into()
+        )
+        .await
+        .is_ok());
+    }
+    let claimed_receiver = claimed_device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    let other_receiver = other_device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    for receiver in [&claimed_receiver, &other_receiver] {
+      check_test_recv_value(
+        receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+      );
+      check_test_recv_value(
+        receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 64], false)),
+      );
+    }
+
+    assert!(server.stop_claimed_devices("Test Client").await.is_ok());
+    check_test_recv_value(
+      &claimed_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+    );
+    check_test_recv_value(
+      &claimed_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 0], false)),
+    );
+    assert!(check_test_recv_empty(&other_receiver));
+
+    // Devices that aren't connected are skipped.
+    assert!(server
+      .device_manager()
+      .stop_devices(&[other_index, other_index + 100])
+      .await
+      .is_ok());
+    check_test_recv_value(
+      &other_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+    );
+  });
+}
+
+#[cfg(target = "windows")]
+#[test]
+fn test_repeated_address_additions() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper
+      .add_ble_device_with_address("Massage Demo", "SameAddress")
+      .await;
+    helper
+      .add_ble_device_with_address("Massage Demo", "SameAddress")
+      .await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let mut device_index = None;
+    let mut device_removed_called = true;
+    while let Some(msg) = recv.next().await {
+      match msg {
+        ButtplugServerMessage::ScanningFinished(_) => continue,
+        ButtplugServerMessage::DeviceAdded(da) => {
+          assert_eq!(da.device_name(), "Aneros Vivi");
+          if device_index.is_none() {
+            device_index = Some(da.device_index());
+          } else {
+            assert!(device_removed_called);
+            assert_eq!(
+              da.device_index(),
+              device_index.expect("Test, assuming infallible.")
+            );
+            return;
+          }
+        }
+        ButtplugServerMessage::DeviceRemoved(dr) => {
+          assert_eq!(
+            dr.device_index(),
+            device_index.expect("Test, assuming infallible.")
+          );
+          device_removed_called = true;
+        }
+        _ => {
+          panic!(
+            "Returned message was not a DeviceAdded message or timed out: {:?}",
+            msg
+          );
+        }
+      }
+    }
+  });
+}
+
+#[test]
+fn test_device_removed_reasons() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper
+      .add_ble_device_with_address("Massage Demo", "RequestedAddress")
+      .await;
+    let lost_device = helper
+      .add_ble_device_with_address("Massage Demo", "LostAddress")
+      .await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let mut requested_index = None;
+    let mut lost_index = None;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        let info = server
+          .device_manager()
+          .device_info(da.device_index())
+          .expect("Test, assuming infallible.");
+        if info.address == "RequestedAddress" {
+          requested_index = Some(da.device_index());
+        } else {
+          lost_index = Some(da.device_index());
+        }
+        if requested_index.is_some() && lost_index.is_some() {
+          break;
+        }
+      }
+    }
+    let requested_index = requested_index.expect("Test, assuming infallible.");
+    let lost_index = lost_index.expect("Test, assuming infallible.");
+
+    server
+      .device_manager()
+      .disconnect_device(requested_index)
+      .await
+      .expect("Test, assuming infallible.");
+    lost_device
+      .disconnect()
+      .await
+      .expect("Test, assuming infallible.");
+    let mut removed = vec![];
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceRemoved(dr) = msg {
+        removed.push((dr.device_index(), dr.reason()));
+        if removed.len() == 2 {
+          break;
+        }
+      }
+    }
+    assert!(removed.contains(&(requested_index, Some(DeviceRemovedReason::UserRequested))));
+    assert!(removed.contains(&(lost_index, Some(DeviceRemovedReason::ConnectionLost))));
+  });
+}
+
+#[test]
+fn test_no_writes_after_device_removed() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let mut device_index = None;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        device_index = Some(da.device_index());
+        break;
+      }
+    }
+    let device_index = device_index.expect("Test, assuming infallible.");
+    let command_receiver = device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    let write_delay = Duration::from_millis(100);
+    device.set_write_delay(Some(write_delay));
+    let vibrate = server.parse_message(
+      messages::VibrateCmd::new(device_index, vec![messages::VibrateSubcommand::new(0, 0.5)])
+        .into(),
+    );
+    let queued = server.parse_message(
+      messages::VibrateCmd::new(device_index, vec![messages::VibrateSubcommand::new(0, 0.1)])
+        .into(),
+    );
+    let remove = async {
+      // Let the first command get as far as waiting on its write.
+      Delay::new(write_delay / 10).await;
+      device
+        .disconnect()
+        .await
+        .expect("Test, assuming infallible.");
+    };
+    let (vibrate_result, queued_result, _) = futures::join!(vibrate, queued, remove);
+    assert!(vibrate_result.is_err());
+    assert!(queued_result.is_err());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceRemoved(_) = msg {
+        break;
+      }
+    }
+    Delay::new(write_delay * 2).await;
+    assert!(check_test_recv_empty(&command_receiver));
+  });
+}
+
+#[test]
+fn test_raw_subscription_sequence() {
+  async_manager::block_on(async {
+    let server = ButtplugServerBuilder::default()
+      .allow_raw_messages(true)
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    // Notifications on endpoints that haven't been subscribed to aren't
+    // relayed to the client. Use an endpoint we never subscribe to, as device
+    // events are handled asynchronously and could otherwise arrive after the
+    // subscription below.
+    device.send_event(ButtplugDeviceEvent::Notification(
+      device.address(),
+      Endpoint::Rx,
+      vec![0],
+    ));
+    assert!(server
+      .parse_message(messages::RawSubscribeCmd::new(0, Endpoint::Tx).into())
+      .await
+      .is_ok());
+    for data in [vec![1], vec![2]] {
+      device.send_event(ButtplugDeviceEvent::Notification(
+        device.address(),
+        Endpoint::Tx,
+        data,
+      ));
+    }
+    for (sequence, data) in [(0, vec![1]), (1, vec![2])] {
+      loop {
+        let msg = recv.next().await.expect("Test, assuming infallible.");
+        if let ButtplugServerMessage::RawReading(reading) = msg {
+          assert_eq!(reading.sequence(), Some(sequence));
+          assert_eq!(*reading.data(), data);
+          break;
+        }
+      }
+    }
+  });
+}
+
+#[test]
+fn test_raw_subscription_batch_keeps_notifications_separate() {
+  async_manager::block_on(async {
+    let server = ButtplugServerBuilder::default()
+      .allow_raw_messages(true)
+      .raw_reading_batch_window(50)
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    assert!(server
+      .parse_message(messages::RawSubscribeCmd::new(0, Endpoint::Tx).into())
+      .await
+      .is_ok());
+    // Both notifications land in the same batch, but should still come out as
+    // separate readings rather than one concatenated payload.
+    for data in [vec![1, 2], vec![3]] {
+      device.send_event(ButtplugDeviceEvent::Notification(
+        device.address(),
+        Endpoint::Tx,
+        data,
+      ));
+    }
+    for (sequence, data) in [(0, vec![1, 2]), (1, vec![3])] {
+      loop {
+        let msg = recv.next().await.expect("Test, assuming infallible.");
+        if let ButtplugServerMessage::RawReading(reading) = msg {
+          assert_eq!(reading.sequence(), Some(sequence));
+          assert_eq!(*reading.data(), data);
+          break;
+        }
+      }
+    }
+  });
+}
+
+#[test]
+fn test_pearl_sensor_readings_drive_onyx() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let pearl = helper.add_ble_device("Pearl2").await;
+    let onyx = helper.add_ble_device("Onyx2").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let mut pearl_index = None;
+    let mut onyx_index = None;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        if da.device_name() == "Kiiroo Pearl 2" {
+          pearl_index = Some(da.device_index());
+        } else {
+          onyx_index = Some(da.device_index());
+        }
+        if pearl_index.is_some() && onyx_index.is_some() {
+          break;
+        }
+      }
+    }
+    let pearl_index = pearl_index.expect("Test, assuming infallible.");
+    let onyx_index = onyx_index.expect("Test, assuming infallible.");
+    assert!(server
+      .parse_message(messages::SensorSubscribeCmd::new(pearl_index, 0).into())
+      .await
+      .is_ok());
+    pearl.send_event(ButtplugDeviceEvent::Notification(
+      pearl.address(),
+      Endpoint::RxTouch,
+      vec![0, 255, 128],
+    ));
+    let reading = loop {
+      let msg = recv.next().await.expect("Test, assuming infallible.");
+      if let ButtplugServerMessage::SensorReading(reading) = msg {
+        break reading;
+      }
+    };
+    assert_eq!(reading.device_index(), pearl_index);
+    assert_eq!(*reading.data(), vec![0, 255, 128]);
+    // Squeezing the Pearl harder strokes the Onyx further.
+    let pressure = *reading.data().iter().max().expect("Test, assuming infallible.");
+    let position = pressure as f64 / 255f64;
+    assert!(server
+      .parse_message(
+        messages::LinearCmd::new(
+          onyx_index,
+          vec![messages::VectorSubcommand::new(0, 500, position)]
+        )
+        .into()
+      )
+      .await
+      .is_ok());
+    let command_receiver = onyx
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(
+        Endpoint::Tx,
+        vec![99, (get_speed(1f64, 500) * 99f64) as u8],
+        false,
+      )),
+    );
+  });
+}
+
+#[test]
+fn test_device_user_config_reaches_protocol() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let mut user_config = UserDeviceConfigFile::default();
+    user_config
+      .device_config_mut("ReversedCyclone")
+      .set_reverse_rotation(Some(true));
+    server.device_manager().set_user_config_file(&user_config);
+    let device = helper
+      .add_ble_device_with_address("CycSA", "ReversedCyclone")
+      .await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    let command_receiver = device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    let rotate =
+      || messages::RotateCmd::new(0, vec![messages::RotationSubcommand::new(0, 0.5, false)]).into();
+
+    // Config set before connection is passed in at protocol creation.
+    assert!(server.parse_message(rotate()).await.is_ok());
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(
+        Endpoint::Tx,
+        vec![0x01, 0x01, 178],
+        true,
+      )),
+    );
+
+    // Removing the config at runtime reaches the connected protocol.
+    assert!(server
+      .parse_message(messages::StopDeviceCmd::new(0).into())
+      .await
+      .is_ok());
+    server
+      .device_manager()
+      .set_user_config_file(&UserDeviceConfigFile::default());
+    assert!(server.parse_message(rotate()).await.is_ok());
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(
+        Endpoint::Tx,
+        vec![0x01, 0x01, 0x0],
+        true,
+      )),
+    );
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(
+        Endpoint::Tx,
+        vec![0x01, 0x01, 50],
+        true,
+      )),
+    );
+  });
+}
+
+#[test]
+fn test_device_user_config_intensity_limit() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let mut user_config = UserDeviceConfigFile::default();
+    user_config
+      .device_config_mut("LimitedVivi")
+      .set_intensity_limit(Some(0.5));
+    server.device_manager().set_user_config_file(&user_config);
+    let device = helper
+      .add_ble_device_with_address("Massage Demo", "LimitedVivi")
+      .await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    let command_receiver = device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    assert!(server
+      .parse_message(messages::SingleMotorVibrateCmd::new(0, 1.0).into())
+      .await
+      .is_ok());
+    for motor in [0xF1, 0xF2] {
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![motor, 64], false)),
+      );
+    }
+
+    // Reading the config back, it should match what was set.
+    let user_config = server.device_manager().user_config_file();
+    assert_eq!(
+      *user_config
+        .device_config("LimitedVivi")
+        .expect("Test, assuming infallible.")
+        .intensity_limit(),
+      Some(0.5)
+    );
+  });
+}
+
+#[test]
+fn test_device_user_config_stop_behavior() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let mut user_config = UserDeviceConfigFile::default();
+    user_config
+      .device_config_mut("ParkedPiston")
+      .set_stop_behavior(Some(messages::StopBehavior {
+        park_position: Some(0.0),
+        park_duration: Some(500),
+      }));
+    server.device_manager().set_user_config_file(&user_config);
+    let device = helper
+      .add_ble_device_with_address("VorzePiston", "ParkedPiston")
+      .await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    let command_receiver = device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    assert!(server
+      .parse_message(
+        messages::LinearCmd::new(0, vec![messages::VectorSubcommand::new(0, 50, 0.5)]).into()
+      )
+      .await
+      .is_ok());
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(
+        Endpoint::Tx,
+        vec![0x03, 100, 100],
+        true,
+      )),
+    );
+    // Stopping moves the piston back to the bottom instead of leaving it
+    // halfway.
+    assert!(server
+      .parse_message(messages::StopDeviceCmd::new(0).into())
+      .await
+      .is_ok());
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(
+        Endpoint::Tx,
+        vec![0x03, 0, get_piston_speed(100.0, 500.0)],
+        true,
+      )),
+    );
+    assert!(check_test_recv_empty(&command_receiver));
+  });
+}
+
+#[test]
+fn test_emergency_stop() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    let command_receiver = device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    let vibrate = || messages::SingleMotorVibrateCmd::new(0, 0.5).into();
+    assert!(server.parse_message(vibrate()).await.is_ok());
+    for motor in [0xF1, 0xF2] {
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![motor, 64], false)),
+      );
+    }
+
+    server
+      .emergency_stop()
+      .await
+      .expect("Test, assuming infallible.");
+    assert!(server.emergency_stopped());
+    for motor in [0xF1, 0xF2] {
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![motor, 0], false)),
+      );
+    }
+    // Everything but stops is refused until we resume.
+    let err = server
+      .parse_message(vibrate())
+      .await
+      .expect_err("Test, assuming infallible.");
+    assert!(matches!(
+      err.original_error(),
+      ButtplugError::ButtplugDeviceError(ButtplugDeviceError::DevicesEmergencyStopped)
+    ));
+    assert!(server
+      .parse_message(messages::StopDeviceCmd::new(0).into())
+      .await
+      .is_ok());
+    assert!(check_test_recv_empty(&command_receiver));
+
+    server.resume_after_emergency_stop();
+    assert!(server.parse_message(vibrate()).await.is_ok());
+    for motor in [0xF1, 0xF2] {
+      check_test_recv_value(
+        &command_receiver,
+        DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![motor, 64], false)),
+      );
+    }
+  });
+}
+
+#[test]
+fn test_device_watchdog_stops_running_device() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let mut user_config = UserDeviceConfigFile::default();
+    user_config
+      .device_config_mut("WatchedVivi")
+      .set_watchdog_timeout(Some(100));
+    server.device_manager().set_user_config_file(&user_config);
+    let device = helper
+      .add_ble_device_with_address("Massage Demo", "WatchedVivi")
+      .await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    let command_receiver = device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    let check_speed = |step: u8| {
+      for motor in [0xF1, 0xF2] {
+        check_test_recv_value(
+          &command_receiver,
+          DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![motor, step], false)),
+        );
+      }
+    };
+    assert!(server
+      .parse_message(messages::SingleMotorVibrateCmd::new(0, 0.5).into())
+      .await
+      .is_ok());
+    check_speed(64);
+    // Nothing else arrives, so the watchdog stops the device.
+    Delay::new(Duration::from_millis(250)).await;
+    check_speed(0);
+
+    // A stopped device is left alone.
+    assert!(server
+      .parse_message(messages::SingleMotorVibrateCmd::new(0, 0.5).into())
+      .await
+      .is_ok());
+    check_speed(64);
+    assert!(server
+      .parse_message(messages::SingleMotorVibrateCmd::new(0, 0.0).into())
+      .await
+      .is_ok());
+    check_speed(0);
+    Delay::new(Duration::from_millis(250)).await;
+    assert!(check_test_recv_empty(&command_receiver));
+  });
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_device_command_metrics() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper.add_ble_device("Massage Demo").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let mut device_index = None;
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        device_index = Some(da.device_index());
+        break;
+      }
+    }
+    let device_index = device_index.expect("Test, assuming infallible.");
+    assert!(server.metrics().is_empty());
+    assert!(server
+      .parse_message(
+        messages::VibrateCmd::new(device_index, vec![messages::VibrateSubcommand::new(0, 0.5)])
+          .into()
+      )
+      .await
+      .is_ok());
+    // Aneros devices can't rotate, so this counts as an error.
+    assert!(server
+      .parse_message(
+        messages::RotateCmd::new(
+          device_index,
+          vec![messages::RotationSubcommand::new(0, 0.5, true)]
+        )
+        .into()
+      )
+      .await
+      .is_err());
+    let metrics = server.metrics();
+    assert_eq!(metrics.len(), 1);
+    assert_eq!(metrics[0].device_index, device_index);
+    assert_eq!(metrics[0].command_count, 2);
+    assert_eq!(metrics[0].error_count, 1);
+    assert_eq!(metrics[0].latency.count(), 2);
+  });
+}
+
+// A protocol implemented outside the library, the way a plugin crate would.
+struct PluginProtocol {
+  message_attributes: DeviceMessageAttributesMap,
+  stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+}
+
+impl ButtplugProtocol for PluginProtocol {
+}
+
+impl ButtplugProtocolProperties for PluginProtocol {
+  fn name(&self) -> &str {
+    "Plugin Vibrator"
+  }
+
+  fn message_attributes(&self) -> DeviceMessageAttributesMap {
+    self.message_attributes.clone()
+  }
+
+  fn stop_commands(&self) -> Vec<ButtplugDeviceCommandMessageUnion> {
+    self.stop_commands.clone()
+  }
+}
+
+impl ButtplugProtocolCommandHandler for PluginProtocol {
+  fn handle_vibrate_cmd(
+    &self,
+    device: Arc<DeviceImpl>,
+    message: messages::VibrateCmd,
+  ) -> ButtplugDeviceResultFuture {
+    let speed = (message.speeds()[0].speed() * 10.0) as u8;
+    let write = device.write_value(DeviceWriteCmd::new(Endpoint::Tx, vec![0xAB, speed], false));
+    Box::pin(async move {
+      write.await?;
+      Ok(messages::Ok::default().into())
+    })
+  }
+}
+
+// Builds message attributes in code, from its own state, instead of reading
+// them from the device configuration.
+struct PluginProtocolFactory {
+  steps: u32,
+}
+
+impl ButtplugProtocolFactory for PluginProtocolFactory {
+  fn try_create(
+    &self,
+    _device_impl: Arc<DeviceImpl>,
+    _config: DeviceProtocolConfiguration,
+  ) -> BoxFuture<'static, Result<Box<dyn ButtplugProtocol>, ButtplugError>> {
+    let mut message_attributes = DeviceMessageAttributesMap::new();
+    message_attributes.insert(
+      ButtplugDeviceMessageType::VibrateCmd,
+      DeviceMessageAttributes::with_step_count(vec![self.steps]),
+    );
+    message_attributes.insert(
+      ButtplugDeviceMessageType::StopDeviceCmd,
+      DeviceMessageAttributes::default(),
+    );
+    let stop_commands = GenericCommandManager::new(&message_attributes).get_stop_commands();
+    Box::pin(future::ready(Ok(Box::new(PluginProtocol {
+      message_attributes,
+      stop_commands,
+    }) as Box<dyn ButtplugProtocol>)))
+  }
+}
+
+#[test]
+fn test_external_protocol_factory() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let definition: ProtocolDefinition = serde_json::from_str(
+      r#"{
+        "btle": {
+          "names": ["Plugin Toy"],
+          "services": {
+            "0000ff00-0000-1000-8000-00805f9b34fb": {
+              "tx": "0000ff01-0000-1000-8000-00805f9b34fb"
+            }
+          }
+        }
+      }"#,
+    )
+    .expect("Test, assuming infallible.");
+    server
+      .device_manager()
+      .add_protocol_definition("plugin-toy", definition);
+    server
+      .device_manager()
+      .add_protocol_factory("plugin-toy", Arc::new(PluginProtocolFactory { steps: 10 }))
+      .expect("Test, assuming infallible.");
+    // Names can only be registered once.
+    assert!(server
+      .device_manager()
+      .add_protocol_factory("plugin-toy", Arc::new(PluginProtocolFactory { steps: 10 }))
+      .is_err());
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let test_device = helper.add_ble_device("Plugin Toy").await;
+    assert!(server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into()
+      )
+      .await
+      .is_ok());
+    assert!(server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .is_ok());
+    let device_index = loop {
+      if let Some(ButtplugServerMessage::DeviceAdded(da)) = recv.next().await {
+        assert_eq!(da.device_name(), "Plugin Vibrator");
+        assert_eq!(
+          da.device_messages()[&ButtplugDeviceMessageType::VibrateCmd].step_count,
+          Some(vec![10])
+        );
+        break da.device_index();
+      }
+    };
+    server
+      .parse_message(
+        messages::VibrateCmd::new(device_index, vec![messages::VibrateSubcommand::new(0, 0.5)])
+          .into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    let command_receiver = test_device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xAB, 5], false)),
+    );
+  });
+}
+
+#[test]
+fn test_device_traffic_diagnostics() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let traffic = server.device_manager().device_traffic_stream();
+    pin_mut!(traffic);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Massage Demo").await;
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .expect("Test, assuming infallible.");
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    assert!(server
+      .device_manager()
+      .set_device_traffic_diagnostics(1, true)
+      .is_err());
+    server
+      .device_manager()
+      .set_device_traffic_diagnostics(0, true)
+      .expect("Test, assuming infallible.");
+    let vibrate =
+      |speed| messages::VibrateCmd::new(0, vec![messages::VibrateSubcommand::new(0, speed)]).into();
+    server
+      .parse_message(vibrate(0.5))
+      .await
+      .expect("Test, assuming infallible.");
+    let record = traffic.next().await.expect("Test, assuming infallible.");
+    assert_eq!(record.device_index, 0);
+    assert_eq!(record.address, device.address());
+    assert_eq!(record.endpoint, Endpoint::Tx);
+    assert_eq!(record.direction, DeviceTrafficDirection::Write);
+    assert_eq!(record.hex_dump(), "f1 40");
+    device.send_event(ButtplugDeviceEvent::Notification(
+      device.address(),
+      Endpoint::Rx,
+      vec![0xde, 0xad],
+    ));
+    let record = traffic.next().await.expect("Test, assuming infallible.");
+    assert_eq!(record.endpoint, Endpoint::Rx);
+    assert_eq!(record.direction, DeviceTrafficDirection::Notification);
+    assert_eq!(record.data, vec![0xde, 0xad]);
+    // Nothing is mirrored once diagnostics are turned off.
+    server
+      .device_manager()
+      .set_device_traffic_diagnostics(0, false)
+      .expect("Test, assuming infallible.");
+    server
+      .parse_message(vibrate(1.0))
+      .await
+      .expect("Test, assuming infallible.");
+    assert!(traffic.next().now_or_never().is_none());
+  });
+}
+
+#[test]
+fn test_dry_run_skips_device_writes() {
+  async_manager::block_on(async {
+    let server = ButtplugServerBuilder::default()
+      .dry_run(true)
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let writes = server.device_manager().dry_run_write_stream();
+    pin_mut!(writes);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Massage Demo").await;
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .expect("Test, assuming infallible.");
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    let command_receiver = device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    let vibrate =
+      |speed| messages::VibrateCmd::new(0, vec![messages::VibrateSubcommand::new(0, speed)]).into();
+    server
+      .parse_message(vibrate(0.5))
+      .await
+      .expect("Test, assuming infallible.");
+    let write = writes.next().await.expect("Test, assuming infallible.");
+    assert_eq!(write.device_index, 0);
+    assert_eq!(write.endpoint, Endpoint::Tx);
+    assert_eq!(write.data, vec![0xF1, 64]);
+    assert!(check_test_recv_empty(&command_receiver));
+    // Commands are still validated as usual.
+    assert!(server
+      .parse_message(
+        messages::VibrateCmd::new(0, vec![messages::VibrateSubcommand::new(5, 0.5)]).into()
+      )
+      .await
+      .is_err());
+    // Writes reach the device again once dry run mode is off.
+    server.device_manager().set_dry_run(false);
+    server
+      .parse_message(vibrate(1.0))
+      .await
+      .expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 127], false)),
+    );
+    assert!(writes.next().now_or_never().is_none());
+  });
+}
+
+#[cfg(feature = "forwarded-device-manager")]
+#[test]
+fn test_device_forwarding() {
+  use buttplug::server::{
+    comm_managers::forwarded_device::{
+      forwarded_device_comm_manager::ForwardedDeviceCommunicationManagerBuilder,
+      ForwardingLink,
+    },
+    device_forwarder::DeviceForwarder,
+  };
+
+  async_manager::block_on(async {
+    let handshake = || {
+      messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into()
+    };
+    // Server the device is connected to.
+    let local_server = ButtplugServer::default();
+    let local_recv = local_server.event_stream();
+    pin_mut!(local_recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    local_server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper
+      .add_ble_device_with_address("Massage Demo", "forwarded-device")
+      .await;
+    local_server
+      .parse_message(handshake())
+      .await
+      .expect("Test, assuming infallible.");
+    local_server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .expect("Test, assuming infallible.");
+    while let Some(msg) = local_recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        break;
+      }
+    }
+    // Server the device is shared with.
+    let remote_server = ButtplugServer::default();
+    let remote_recv = remote_server.event_stream();
+    pin_mut!(remote_recv);
+    let builder = ForwardedDeviceCommunicationManagerBuilder::default();
+    let link_sender = builder.link_sender();
+    remote_server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    remote_server
+      .parse_message(handshake())
+      .await
+      .expect("Test, assuming infallible.");
+    let (local_link, remote_link) = ForwardingLink::pair();
+    if link_sender.send(remote_link).is_err() {
+      panic!("Forwarded device comm manager should be running.");
+    }
+    let forwarder = DeviceForwarder::new(&local_server, local_link);
+    // Nothing is shared until it's allowed.
+    Delay::new(Duration::from_millis(100)).await;
+    assert!(forwarder.shared_devices().is_empty());
+    assert!(remote_recv.next().now_or_never().is_none());
+
+    forwarder.allow_device("forwarded-device");
+    let remote_index = loop {
+      match remote_recv.next().await {
+        Some(ButtplugServerMessage::DeviceAdded(added)) => {
+          assert_eq!(added.device_address(), &Some("forwarded-device".to_owned()));
+          break added.device_index();
+        }
+        Some(_) => continue,
+        None => panic!("Should get a DeviceAdded message."),
+      }
+    };
+    assert_eq!(
+      forwarder.shared_devices(),
+      vec!["forwarded-device".to_owned()]
+    );
+    let command_receiver = device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    let vibrate = |speed| {
+      messages::VibrateCmd::new(
+        remote_index,
+        vec![messages::VibrateSubcommand::new(0, speed)],
+      )
+      .into()
+    };
+    remote_server
+      .parse_message(vibrate(0.5))
+      .await
+      .expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+
+    // The forwarding server's emergency stop holds for forwarded commands too.
+    local_server
+      .emergency_stop()
+      .await
+      .expect("Test, assuming infallible.");
+    assert!(remote_server.parse_message(vibrate(1.0)).await.is_err());
+    local_server.resume_after_emergency_stop();
+
+    // Taking the device off the allow list removes it from the other server.
+    forwarder.disallow_device("forwarded-device");
+    loop {
+      match remote_recv.next().await {
+        Some(ButtplugServerMessage::DeviceRemoved(removed)) => {
+          assert_eq!(removed.device_index(), remote_index);
+          break;
+        }
+        Some(_) => continue,
+        None => panic!("Should get a DeviceRemoved message."),
+      }
+    }
+    assert!(forwarder.shared_devices().is_empty());
+  });
+}
+
+#[test]
+fn test_read_only_client_permissions() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let device = helper.add_ble_device("Massage Demo").await;
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .expect("Test, assuming infallible.");
+    let device_index = loop {
+      if let ButtplugServerMessage::DeviceAdded(da) =
+        recv.next().await.expect("Test, assuming infallible.")
+      {
+        break da.device_index();
+      }
+    };
+
+    // Permissions apply to a connected client from its next message.
+    server.set_client_permissions(
+      "Test Client",
+      ClientPermissions {
+        read_only: true,
+        ..Default::default()
+      },
+    );
+    let err = server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .expect_err("Read-only clients can't scan.");
+    assert!(matches!(
+      err.original_error(),
+      ButtplugError::ButtplugMessageError(ButtplugMessageError::MessageNotPermitted(..))
+    ));
+    assert_eq!(
+      err.error_details.map(|details| details.error_class),
+      Some(messages::ErrorClass::MessageNotPermitted)
+    );
+    let err = server
+      .parse_message(
+        messages::VibrateCmd::new(device_index, vec![messages::VibrateSubcommand::new(0, 0.5)])
+          .into(),
+      )
+      .await
+      .expect_err("Read-only clients can't command devices.");
+    assert!(matches!(
+      err.original_error(),
+      ButtplugError::ButtplugMessageError(ButtplugMessageError::MessageNotPermitted(..))
+    ));
+    let command_receiver = device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    assert!(check_test_recv_empty(&command_receiver));
+    // Listing devices is still fine.
+    assert!(matches!(
+      server
+        .parse_message(messages::RequestDeviceList::default().into())
+        .await,
+      Ok(ButtplugServerMessage::DeviceList(list)) if list.devices().len() == 1
+    ));
+
+    server.remove_client_permissions("Test Client");
+    assert!(server
+      .parse_message(
+        messages::VibrateCmd::new(device_index, vec![messages::VibrateSubcommand::new(0, 0.5)])
+          .into()
+      )
+      .await
+      .is_ok());
+    check_test_recv_value(
+      &command_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+  });
+}
+
+#[test]
+fn test_client_device_permissions() {
+  async_manager::block_on(async {
+    let server = ButtplugServerBuilder::default()
+      .client_permissions(
+        "Test Client",
+        ClientPermissions {
+          devices: Some(BTreeSet::from(["permitted".to_owned()])),
+          ..Default::default()
+        },
+      )
+      .finish()
+      .expect("Test, assuming infallible.");
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    let permitted_device = helper
+      .add_ble_device_with_address("Massage Demo", "permitted")
+      .await;
+    let other_device = helper
+      .add_ble_device_with_address("Massage Demo", "other")
+      .await;
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .expect("Test, assuming infallible.");
+    let permitted_index = loop {
+      if let ButtplugServerMessage::DeviceAdded(da) =
+        recv.next().await.expect("Test, assuming infallible.")
+      {
+        assert_eq!(da.device_address(), &Some("permitted".to_owned()));
+        break da.device_index();
+      }
+    };
+    // Wait for the other device to connect, the client is never told about it.
+    while server.device_manager().device_snapshots().len() < 2 {
+      Delay::new(Duration::from_millis(10)).await;
+    }
+    let other_index = server
+      .device_manager()
+      .device_snapshots()
+      .into_iter()
+      .map(|snapshot| snapshot.index)
+      .find(|index| *index != permitted_index)
+      .expect("Test, assuming infallible.");
+    match server
+      .parse_message(messages::RequestDeviceList::default().into())
+      .await
+    {
+      Ok(ButtplugServerMessage::DeviceList(list)) => {
+        assert_eq!(list.devices().len(), 1);
+        assert_eq!(list.devices()[0].device_index, permitted_index);
+      }
+      msg => panic!("Should get a device list, got {:?}", msg),
+    }
+
+    let vibrate = |device_index| {
+      messages::VibrateCmd::new(
+        device_index,
+        vec![
+          messages::VibrateSubcommand::new(0, 0.5),
+          messages::VibrateSubcommand::new(1, 0.5),
+        ],
+      )
+      .into()
+    };
+    let err = server
+      .parse_message(vibrate(other_index))
+      .await
+      .expect_err("Client can't use the other device.");
+    assert!(matches!(
+      err.original_error(),
+      ButtplugError::ButtplugDeviceError(ButtplugDeviceError::DeviceNotAvailable(index))
+        if index == other_index
+    ));
+    server
+      .parse_message(vibrate(permitted_index))
+      .await
+      .expect("Test, assuming infallible.");
+    let permitted_receiver = permitted_device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    let other_receiver = other_device
+      .get_endpoint_receiver(&Endpoint::Tx)
+      .expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &permitted_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+    check_test_recv_value(
+      &permitted_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 64], false)),
+    );
+
+    // StopAllDevices only stops what the client can use.
+    server
+      .parse_message(messages::StopAllDevices::default().into())
+      .await
+      .expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &permitted_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+    );
+    check_test_recv_value(
+      &permitted_receiver,
+      DeviceImplCommand::Write(DeviceWriteCmd::new(Endpoint::Tx, vec![0xF2, 0], false)),
+    );
+    assert!(check_test_recv_empty(&other_receiver));
+  });
+}
+
+async fn device_list_changes(
+  server: &ButtplugServer,
+  revision: u32,
+) -> messages::DeviceListChanges {
+  match server
+    .parse_message(messages::RequestDeviceListChanges::new(revision).into())
+    .await
+  {
+    Ok(ButtplugServerMessage::DeviceListChanges(changes)) => changes,
+    msg => panic!("Should get device list changes, got {:?}", msg),
+  }
+}
+
+#[test]
+fn test_device_list_changes() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper
+      .add_ble_device_with_address("Massage Demo", "first")
+      .await;
+    helper
+      .add_ble_device_with_address("Massage Demo", "second")
+      .await;
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .expect("Test, assuming infallible.");
+    let mut second_index = None;
+    let mut added = 0;
+    while added < 2 {
+      if let ButtplugServerMessage::DeviceAdded(da) =
+        recv.next().await.expect("Test, assuming infallible.")
+      {
+        if da.device_address().as_deref() == Some("second") {
+          second_index = Some(da.device_index());
+        }
+        added += 1;
+      }
+    }
+    let second_index = second_index.expect("Test, assuming infallible.");
+    // Revision 0 gets the whole list.
+    let changes = device_list_changes(&server, 0).await;
+    assert!(changes.full());
+    assert_eq!(changes.devices().len(), 2);
+    assert!(changes.removed_devices().is_empty());
+    let revision = changes.revision();
+    let changes = device_list_changes(&server, revision).await;
+    assert!(!changes.full());
+    assert!(changes.devices().is_empty());
+    assert_eq!(changes.revision(), revision);
+
+    server
+      .device_manager()
+      .disconnect_device(second_index)
+      .await
+      .expect("Test, assuming infallible.");
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceRemoved(_) = msg {
+        break;
+      }
+    }
+    let changes = device_list_changes(&server, revision).await;
+    assert!(!changes.full());
+    assert!(changes.devices().is_empty());
+    assert_eq!(changes.removed_devices(), &vec![second_index]);
+    assert!(changes.revision() > revision);
+    assert_eq!(
+      server
+        .device_manager()
+        .device_list_changes(changes.revision()),
+      messages::DeviceListChanges::new(changes.revision(), false, vec![], vec![])
+    );
+
+    // Revisions the server hasn't reached yet are from an earlier run, so the
+    // whole list is sent again.
+    let changes = device_list_changes(&server, changes.revision() + 100).await;
+    assert!(changes.full());
+    assert_eq!(changes.devices().len(), 1);
+    assert_eq!(
+      changes.devices()[0].device_address,
+      Some("first".to_owned())
+    );
+  });
+}
+
+#[test]
+fn test_device_attribute_maps_shared() {
+  async_manager::block_on(async {
+    let server = ButtplugServer::default();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    let builder = TestDeviceCommunicationManagerBuilder::default();
+    let helper = builder.helper();
+    server
+      .device_manager()
+      .add_comm_manager(builder)
+      .expect("Test, assuming infallible.");
+    helper
+      .add_ble_device_with_address("Massage Demo", "first")
+      .await;
+    helper
+      .add_ble_device_with_address("Massage Demo", "second")
+      .await;
+    server
+      .parse_message(
+        messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    server
+      .parse_message(messages::StartScanning::default().into())
+      .await
+      .expect("Test, assuming infallible.");
+    let mut added = vec![];
+    while added.len() < 2 {
+      if let ButtplugServerMessage::DeviceAdded(da) =
+        recv.next().await.expect("Test, assuming infallible.")
+      {
+        added.push(da);
+      }
+    }
+    // Devices of the same model share one attribute map, as do the messages
+    // listing them.
+    assert!(added[0]
+      .shared_device_messages()
+      .ptr_eq(added[1].shared_device_messages()));
+    let changes = device_list_changes(&server, 0).await;
+    assert_eq!(changes.devices().len(), 2);
+    for info in changes.devices() {
+      assert!(info
+        .device_messages
+        .ptr_eq(added[0].shared_device_messages()));
+    }
+  });
+}
+
+// Connects a server with the given in-flight options to a vibrator, returning
+// the vibrator's device index. Writes to the vibrator are slowed down, so
+// commands stay in flight for a while.
+async fn in_flight_limited_server(options: ClientInFlightOptions) -> (ButtplugServer, u32) {
+  let server = ButtplugServerBuilder::default()
+    .client_in_flight_options(options)
+    .finish()
+    .expect("Test, assuming infallible.");
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  let builder = TestDeviceCommunicationManagerBuilder::default();
+  let helper = builder.helper();
+  server
+    .device_manager()
+    .add_comm_manager(builder)
+    .expect("Test, assuming infallible.");
+  helper
+    .add_ble_device("Massage Demo")
+    .await
+    .set_write_delay(Some(Duration::from_millis(100)));
+  server
+    .parse_message(
+      messages::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  server
+    .parse_message(messages::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  loop {
+    if let ButtplugServerMessage::DeviceAdded(da) =
+      recv.next().await.expect("Test, assuming infallible.")
+    {
+      return (server, da.device_index());
+    }
+  }
+}
+
+fn with_id(msg: impl Into<ButtplugClientMessage>, id: u32) -> ButtplugClientMessage {
+  let mut msg = msg.into();
+  msg.set_id(id);
+  msg
+}
+
+fn vibrate(device_index: u32, speed: f64) -> messages::VibrateCmd {
+  messages::VibrateCmd::new(
+    device_index,
+    vec![messages::VibrateSubcommand::new(0, speed)],
+  )
+}
+
+#[test]
+fn test_client_in_flight_limit_coalesces_motion_commands() {
+  async_manager::block_on(async {
+    let (server, index) = in_flight_limited_server(ClientInFlightOptions {
+      max_in_flight: 2,
+      overload_policy: ClientOverloadPolicy::CoalesceNewest,
+    })
+    .await;
+    // Nothing is replied to until the reply futures are polled, so these all
+    // stay in flight.
+    let oldest = server.parse_message(with_id(vibrate(index, 0.1), 10));
+    let older = server.parse_message(with_id(vibrate(index, 0.2), 11));
+    let newest = server.parse_message(with_id(vibrate(index, 0.3), 12));
+    let overloaded = server
+      .parse_message(with_id(messages::RequestServerTime::default(), 13))
+      .await
+      .expect_err("Client is over its in-flight limit.");
+    assert!(matches!(
+      overloaded.original_error(),
+      ButtplugError::ButtplugMessageError(ButtplugMessageError::ServerOverloaded(2))
+    ));
+    assert_eq!(overloaded.id(), 13);
+    assert_eq!(
+      overloaded.error_details.map(|details| details.error_class),
+      Some(messages::ErrorClass::ServerOverloaded)
+    );
+    // Stops always get through.
+    let stop = server.parse_message(with_id(messages::StopDeviceCmd::new(index), 14));
+
+    let replaced = oldest.await.expect_err("Replaced by a newer command.");
+    assert!(matches!(
+      replaced.original_error(),
+      ButtplugError::ButtplugDeviceError(ButtplugDeviceError::DeviceCommandCancelled)
+    ));
+    assert_eq!(replaced.id(), 10);
+    for fut in [older, newest] {
+      // Either sent, or cancelled by the stop.
+      let _ = fut.await;
+    }
+    assert!(stop.await.is_ok());
+    // With everything replied to, there's room again.
+    assert!(server
+      .parse_message(with_id(messages::RequestServerTime::default(), 15))
+      .await
+      .is_ok());
+  });
+}
+
+#[test]
+fn test_client_in_flight_limit_rejects() {
+  async_manager::block_on(async {
+    let (server, index) = in_flight_limited_server(ClientInFlightOptions {
+      max_in_flight: 1,
+      overload_policy: ClientOverloadPolicy::Reject,
+    })
+    .await;
+    let first = server.parse_message(with_id(vibrate(index, 0.1), 10));
+    let err = server
+      .parse_message(with_id(vibrate(index, 0.2), 11))
+      .await
+      .expect_err("Client is over its in-flight limit.");
+    assert!(matches!(
+      err.original_error(),
+      ButtplugError::ButtplugMessageError(ButtplugMessageError::ServerOverloaded(1))
+    ));
+    assert!(server
+      .parse_message(with_id(messages::StopAllDevices::default(), 12))
+      .await
+      .is_ok());
+    // Either sent, or cancelled by the stop.
+    let _ = first.await;
+  });
+}
@@ -0,0 +1,138 @@
+#![cfg(all(feature = "server", feature = "serialize-json"))]
+
+use buttplug::{
+  connector::{
+    transport::{ButtplugConnectorTransport, ButtplugTransportIncomingMessage},
+    ButtplugConnectorError,
+    ButtplugRemoteServerConnector,
+  },
+  core::messages::serializer::{ButtplugSerializedMessage, ButtplugServerJSONSerializer},
+  server::runner::{ButtplugServerRunner, ButtplugServerRunnerOptions, RunnerCommand, RunnerEvent},
+  util::async_manager,
+};
+use futures::{
+  future::{self, BoxFuture},
+  pin_mut,
+  StreamExt,
+};
+use tokio::{
+  io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+  sync::mpsc::{Receiver, Sender},
+};
+
+fn test_runner() -> ButtplugServerRunner {
+  ButtplugServerRunner::new(ButtplugServerRunnerOptions {
+    server_name: "Test Runner".to_owned(),
+    use_bluetooth_le: false,
+    use_xinput: false,
+    ..Default::default()
+  })
+  .expect("Test, assuming infallible.")
+}
+
+struct FailingTransport;
+
+impl ButtplugConnectorTransport for FailingTransport {
+  fn connect(
+    &self,
+    _: Receiver<ButtplugSerializedMessage>,
+    _: Sender<ButtplugTransportIncomingMessage>,
+  ) -> BoxFuture<'static, Result<(), ButtplugConnectorError>> {
+    Box::pin(future::ready(Err(
+      ButtplugConnectorError::ConnectorGenericError("Cannot listen".to_owned()),
+    )))
+  }
+
+  fn disconnect(self) -> BoxFuture<'static, Result<(), ButtplugConnectorError>> {
+    Box::pin(future::ready(Ok(())))
+  }
+}
+
+#[test]
+fn test_runner_control_channel() {
+  async_manager::block_on(async {
+    let runner = test_runner();
+    let (control, runner_side) = tokio::io::duplex(4096);
+    let (runner_reader, runner_writer) = tokio::io::split(runner_side);
+    let (control_reader, mut control_writer) = tokio::io::split(control);
+    let mut replies = BufReader::new(control_reader).lines();
+    let channel = runner.run_control_channel(BufReader::new(runner_reader), runner_writer);
+    let front_end = async {
+      control_writer
+        .write_all(b"\"RequestStatus\"\n")
+        .await
+        .expect("Test, assuming infallible.");
+      let reply = replies
+        .next_line()
+        .await
+        .expect("Test, assuming infallible.")
+        .expect("Test, assuming infallible.");
+      match serde_json::from_str(&reply).expect("Test, assuming infallible.") {
+        RunnerEvent::Status(status) => {
+          assert_eq!(status.server_name, "Test Runner");
+          assert!(!status.connected);
+        }
+        event => panic!("Expected status, got {:?}", event),
+      }
+      control_writer
+        .write_all(b"\"Reticulate\"\n")
+        .await
+        .expect("Test, assuming infallible.");
+      let reply = replies
+        .next_line()
+        .await
+        .expect("Test, assuming infallible.")
+        .expect("Test, assuming infallible.");
+      assert!(matches!(
+        serde_json::from_str(&reply).expect("Test, assuming infallible."),
+        RunnerEvent::Error(_)
+      ));
+      // Closing the control channel ends it.
+      control_writer
+        .shutdown()
+        .await
+        .expect("Test, assuming infallible.");
+    };
+    let (result, _) = futures::join!(channel, front_end);
+    assert!(result.is_ok());
+  });
+}
+
+#[test]
+fn test_runner_stops_on_connector_failure() {
+  async_manager::block_on(async {
+    let runner = test_runner();
+    let events = runner.event_stream();
+    pin_mut!(events);
+    assert!(runner
+      .run_with_connector(|| {
+        ButtplugRemoteServerConnector::<_, ButtplugServerJSONSerializer>::new(FailingTransport)
+      })
+      .await
+      .is_err());
+    assert!(matches!(
+      events.next().await,
+      Some(RunnerEvent::ServerStarted)
+    ));
+    assert!(matches!(events.next().await, Some(RunnerEvent::Error(_))));
+    assert!(matches!(
+      events.next().await,
+      Some(RunnerEvent::ServerStopped)
+    ));
+  });
+}
+
+#[test]
+fn test_runner_stop_command() {
+  async_manager::block_on(async {
+    let runner = test_runner();
+    assert!(runner.command(RunnerCommand::Stop).await.is_none());
+    // Once stopped, the runner doesn't accept any more clients.
+    assert!(runner
+      .run_with_connector(|| {
+        ButtplugRemoteServerConnector::<_, ButtplugServerJSONSerializer>::new(FailingTransport)
+      })
+      .await
+      .is_ok());
+  });
+}
@@ -0,0 +1,193 @@
+// Replays client sessions from old spec version clients against the server,
+// checking that everything the server sends back is converted down to the
+// client's spec version byte for byte, so changes to message conversions that
+// would break those clients show up here.
+//
+// Sessions live in tests/fixtures/spec_compat, one per file, as lines of:
+//
+// - `# ...`: Comment.
+// - `device <name>`: Adds a test device with the given bluetooth name, which
+//   will be found when the client starts scanning. Must come before any
+//   messages.
+// - `> <json>`: Message array sent by the client.
+// - `< <json>`: Reply the server should send, in order, one per message in the
+//   last client message array.
+// - `! <json>`: Event the server should send. Events on consecutive lines may
+//   arrive in any order.
+//
+// All JSON is compared exactly as written, so fixture lines must match the
+// server's serializer output, key order and all.
+
+#![cfg(all(feature = "server", feature = "serialize-json"))]
+
+use buttplug::{
+  core::messages::{
+    serializer::{
+      ButtplugMessageSerializer,
+      ButtplugSerializedMessage,
+      ButtplugServerJSONSerializer,
+    },
+    ButtplugServerMessage,
+  },
+  server::{comm_managers::test::TestDeviceCommunicationManagerBuilder, ButtplugServer},
+  util::async_manager,
+};
+use futures::{pin_mut, select, FutureExt, Stream, StreamExt};
+use futures_timer::Delay;
+use std::time::Duration;
+
+/// How long to wait for each expected event before failing.
+const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+enum FixtureLine<'a> {
+  Device(&'a str),
+  Client(&'a str),
+  Reply(&'a str),
+  Event(&'a str),
+}
+
+fn parse_fixture(fixture: &str) -> Vec<(usize, FixtureLine<'_>)> {
+  fixture
+    .lines()
+    .enumerate()
+    .map(|(index, line)| (index + 1, line.trim()))
+    .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+    .map(|(line_number, line)| {
+      let parsed = if let Some(name) = line.strip_prefix("device ") {
+        FixtureLine::Device(name)
+      } else if let Some(json) = line.strip_prefix("> ") {
+        FixtureLine::Client(json)
+      } else if let Some(json) = line.strip_prefix("< ") {
+        FixtureLine::Reply(json)
+      } else if let Some(json) = line.strip_prefix("! ") {
+        FixtureLine::Event(json)
+      } else {
+        panic!("Line {}: unknown fixture line: {}", line_number, line);
+      };
+      (line_number, parsed)
+    })
+    .collect()
+}
+
+fn serialize(serializer: &ButtplugServerJSONSerializer, msg: ButtplugServerMessage) -> String {
+  match serializer.serialize(vec![msg]) {
+    ButtplugSerializedMessage::Text(json) => json,
+    ButtplugSerializedMessage::Binary(_) => unreachable!("JSON serializer only outputs text."),
+  }
+}
+
+async fn next_event(
+  serializer: &ButtplugServerJSONSerializer,
+  events: &mut (impl Stream<Item = ButtplugServerMessage> + Unpin),
+  line_number: usize,
+) -> String {
+  select! {
+    event = events.next().fuse() => {
+      serialize(serializer, event.expect("Server event stream should stay open."))
+    }
+    _ = Delay::new(EVENT_TIMEOUT).fuse() => {
+      panic!("Line {}: timed out waiting for server event.", line_number)
+    }
+  }
+}
+
+async fn replay_fixture(fixture: &str) {
+  let server = ButtplugServer::default();
+  let events = server.event_stream();
+  pin_mut!(events);
+  let serializer = ButtplugServerJSONSerializer::default();
+  let builder = TestDeviceCommunicationManagerBuilder::default();
+  let helper = builder.helper();
+  server
+    .device_manager()
+    .add_comm_manager(builder)
+    .expect("Test, assuming infallible.");
+
+  let lines = parse_fixture(fixture);
+  let mut replies = vec![].into_iter();
+  let mut index = 0;
+  while index < lines.len() {
+    let (line_number, line) = &lines[index];
+    match line {
+      FixtureLine::Device(name) => {
+        helper.add_ble_device(name).await;
+      }
+      FixtureLine::Client(json) => {
+        assert_eq!(
+          replies.len(),
+          0,
+          "Line {}: server replied to the last message array more than expected.",
+          line_number
+        );
+        let msgs = serializer
+          .deserialize_str(json)
+          .unwrap_or_else(|err| panic!("Line {}: {:?}", line_number, err));
+        let mut msg_replies = vec![];
+        for msg in msgs {
+          let reply = server
+            .parse_message(msg)
+            .await
+            .unwrap_or_else(|err| err.into());
+          msg_replies.push(serialize(&serializer, reply));
+        }
+        replies = msg_replies.into_iter();
+      }
+      FixtureLine::Reply(json) => {
+        let reply = replies
+          .next()
+          .unwrap_or_else(|| panic!("Line {}: server sent no more replies.", line_number));
+        assert_eq!(&reply, json, "Line {}: reply mismatch.", line_number);
+      }
+      FixtureLine::Event(_) => {
+        // Gather the whole run of events, as their order isn't fixed.
+        let mut expected = vec![];
+        while let Some((line_number, FixtureLine::Event(json))) = lines.get(index) {
+          expected.push((*line_number, *json));
+          index += 1;
+        }
+        let mut received = vec![];
+        for (line_number, _) in &expected {
+          received.push(next_event(&serializer, &mut events, *line_number).await);
+        }
+        for (line_number, json) in expected {
+          let position = received
+            .iter()
+            .position(|event| event == json)
+            .unwrap_or_else(|| {
+              panic!(
+                "Line {}: expected event {}, got {:?}",
+                line_number, json, received
+              )
+            });
+          received.remove(position);
+        }
+        continue;
+      }
+    }
+    index += 1;
+  }
+  assert_eq!(
+    replies.len(),
+    0,
+    "Server replied to the last message array more than expected."
+  );
+}
+
+macro_rules! spec_compat_fixture_tests {
+  ($($name:ident => $file:literal),* $(,)?) => {
+    $(
+      #[test]
+      fn $name() {
+        async_manager::block_on(replay_fixture(include_str!(concat!(
+          "fixtures/spec_compat/",
+          $file
+        ))));
+      }
+    )*
+  };
+}
+
+spec_compat_fixture_tests!(
+  test_spec_v0_legacy_intiface_session => "v0_legacy_intiface.txt",
+  test_spec_v1_scriptplayer_session => "v1_scriptplayer.txt",
+);
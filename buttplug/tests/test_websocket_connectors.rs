@@ -3,10 +3,14 @@
 #[cfg(all(feature = "websockets", target = "windows"))]
 mod websocket_connector_tests {
   use buttplug::{
-    client::ButtplugClient,
+    client::{ButtplugClient, ButtplugClientError},
     connector::{
+      transport::ButtplugConnectorTransportSpecificError,
+      ButtplugConnectorError,
       ButtplugRemoteClientConnector,
       ButtplugRemoteServerConnector,
+      ButtplugWebsocketAuth,
+      ButtplugWebsocketAuthRejection,
       ButtplugWebsocketClientTransport,
       ButtplugWebsocketServerTransport,
       ButtplugWebsocketServerTransportBuilder,
@@ -63,6 +67,130 @@ mod websocket_connector_tests {
     });
   }
 
+  #[test]
+  fn test_client_ws_client_server_ws_server_compressed() {
+    async_manager::block_on(async move {
+      let test_server = ButtplugRemoteServer::default();
+      let server = Arc::new(test_server);
+      let server_clone = server.clone();
+      async_manager::spawn(async move {
+        let connector = ButtplugRemoteServerConnector::<
+          ButtplugWebsocketServerTransport,
+          ButtplugServerJSONSerializer,
+        >::new(
+          ButtplugWebsocketServerTransportBuilder::default()
+            .port(12350)
+            .compression(true)
+            .finish(),
+        );
+        server_clone
+          .start(connector)
+          .await
+          .expect("Test, assuming infallible.");
+      });
+      let mut connected = false;
+      for _ in 0..10u8 {
+        let connector = ButtplugRemoteClientConnector::<
+          ButtplugWebsocketClientTransport,
+          ButtplugClientJSONSerializer,
+        >::new(
+          ButtplugWebsocketClientTransport::new_insecure_connector("ws://127.0.0.1:12350")
+            .compression(true),
+        );
+
+        let client = ButtplugClient::new("Test Client");
+        if client.connect(connector).await.is_ok() {
+          connected = true;
+          break;
+        }
+        Delay::new(Duration::from_secs(1)).await;
+      }
+      assert!(connected);
+      server
+        .disconnect()
+        .await
+        .expect("Test, assuming infallible.");
+    });
+  }
+
+  #[test]
+  fn test_client_ws_client_server_ws_server_auth() {
+    async_manager::block_on(async move {
+      let test_server = ButtplugRemoteServer::default();
+      let server = Arc::new(test_server);
+      let server_clone = server.clone();
+      async_manager::spawn(async move {
+        let mut auth = ButtplugWebsocketAuth::default();
+        auth.shared_secret("secret");
+        let connector = ButtplugRemoteServerConnector::<
+          ButtplugWebsocketServerTransport,
+          ButtplugServerJSONSerializer,
+        >::new(
+          ButtplugWebsocketServerTransportBuilder::default()
+            .port(12351)
+            .auth(auth)
+            .finish(),
+        );
+        server_clone
+          .start(connector)
+          .await
+          .expect("Test, assuming infallible.");
+      });
+      let connect = |token: Option<&str>| {
+        let mut transport =
+          ButtplugWebsocketClientTransport::new_insecure_connector("ws://127.0.0.1:12351");
+        if let Some(token) = token {
+          transport = transport.auth_token(token);
+        }
+        let connector = ButtplugRemoteClientConnector::<
+          ButtplugWebsocketClientTransport,
+          ButtplugClientJSONSerializer,
+        >::new(transport);
+        async move {
+          match ButtplugClient::new("Test Client").connect(connector).await {
+            Err(ButtplugClientError::ButtplugConnectorError(
+              ButtplugConnectorError::TransportSpecificError(
+                ButtplugConnectorTransportSpecificError::AuthenticationRejected(rejection),
+              ),
+            )) => Some(rejection),
+            _ => None,
+          }
+        }
+      };
+      let mut rejection = None;
+      for _ in 0..10u8 {
+        rejection = connect(None).await;
+        if rejection.is_some() {
+          break;
+        }
+        Delay::new(Duration::from_secs(1)).await;
+      }
+      assert_eq!(
+        rejection,
+        Some(ButtplugWebsocketAuthRejection::MissingToken)
+      );
+      assert_eq!(
+        connect(Some("wrong")).await,
+        Some(ButtplugWebsocketAuthRejection::InvalidToken)
+      );
+      let connector = ButtplugRemoteClientConnector::<
+        ButtplugWebsocketClientTransport,
+        ButtplugClientJSONSerializer,
+      >::new(
+        ButtplugWebsocketClientTransport::new_insecure_connector("ws://127.0.0.1:12351")
+          .auth_token("secret"),
+      );
+      ButtplugClient::new("Test Client")
+        .connect(connector)
+        .await
+        .expect("Test, assuming infallible.");
+      server
+        .disconnect()
+        .await
+        .expect("Test, assuming infallible.");
+    });
+  }
+
   #[test]
   fn test_client_ws_server_server_ws_client_insecure() {
     async_manager::block_on(async move {
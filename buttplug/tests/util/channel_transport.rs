@@ -33,6 +33,7 @@ use futures::{
 use std::sync::Arc;
 use tokio::sync::{
   mpsc::{channel, Receiver, Sender},
+  oneshot,
   Mutex,
   Notify,
 };
@@ -117,7 +118,11 @@ pub struct ChannelClientTestHelper {
 
 impl ChannelClientTestHelper {
   pub fn new() -> Self {
-    let client = Arc::new(ButtplugClient::new("test client"));
+    Self::new_with_client(ButtplugClient::new("test client"))
+  }
+
+  pub fn new_with_client(client: ButtplugClient) -> Self {
+    let client = Arc::new(client);
     let (incoming_sender, incoming_receiver) = channel(256);
     let (outgoing_sender, outgoing_receiver) = channel(256);
     let connector = Arc::new(Mutex::new(Some(ButtplugRemoteClientConnector::<
@@ -161,6 +166,17 @@ impl ChannelClientTestHelper {
   }
 
   pub async fn simulate_successful_connect(&self) {
+    self
+      .simulate_successful_connect_with_version(messages::BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+      .await;
+  }
+
+  /// Same as [Self::simulate_successful_connect], but the server claims to
+  /// speak `version` instead of the current spec.
+  pub async fn simulate_successful_connect_with_version(
+    &self,
+    version: messages::ButtplugMessageSpecVersion,
+  ) {
     let client_clone = self.client.clone();
     let connector = self
       .connector
@@ -168,13 +184,14 @@ impl ChannelClientTestHelper {
       .await
       .take()
       .expect("Test, assuming infallible");
-    let finish_notifier = Arc::new(Notify::new());
-    let finish_notifier_clone = finish_notifier.clone();
+    // Use a oneshot instead of a Notify, as connect may finish before we start
+    // waiting on it.
+    let (finish_sender, finish_receiver) = oneshot::channel();
     async_manager::spawn(async move {
       if let Err(e) = client_clone.connect(connector).await {
         assert!(false, "Error connecting to client: {:?}", e);
       }
-      finish_notifier_clone.notify_waiters();
+      let _ = finish_sender.send(());
     });
     // Wait for RequestServerInfo message
     assert!(matches!(
@@ -184,12 +201,7 @@ impl ChannelClientTestHelper {
     // Just assume we get an RSI message
     self
       .send_client_incoming(
-        messages::ServerInfo::new(
-          "test server",
-          messages::BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
-          0,
-        )
-        .into(),
+        messages::ServerInfo::new("test server", version, 0).into(),
       )
       .await;
     // Wait for RequestDeviceList message.
@@ -200,7 +212,7 @@ impl ChannelClientTestHelper {
     let mut dl = messages::DeviceList::new(vec![]);
     dl.set_id(2);
     self.send_client_incoming(dl.into()).await;
-    finish_notifier.notified().await;
+    finish_receiver.await.expect("Test, assuming infallible");
   }
 
   pub async fn get_next_client_message(&self) -> ButtplugClientMessage {
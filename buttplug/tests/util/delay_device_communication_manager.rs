@@ -1,5 +1,8 @@
 use buttplug::{
-  core::ButtplugResultFuture,
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    ButtplugResultFuture,
+  },
   server::comm_managers::{
     DeviceCommunicationEvent,
     DeviceCommunicationManager,
@@ -15,6 +18,36 @@ use tokio::sync::mpsc::Sender;
 #[derive(Default)]
 pub struct DelayDeviceCommunicationManagerBuilder {
   sender: Option<tokio::sync::mpsc::Sender<DeviceCommunicationEvent>>,
+  silent_stop: bool,
+  start_error: Option<ButtplugDeviceError>,
+}
+
+impl DelayDeviceCommunicationManagerBuilder {
+  /// Don't send ScanningFinished when stopping, like comm managers that only
+  /// report scanning through their scanning status.
+  #[allow(dead_code)]
+  pub fn silent_stop(mut self) -> Self {
+    self.silent_stop = true;
+    self
+  }
+
+  /// Fail every start_scanning call, like a comm manager whose adapter went
+  /// missing.
+  #[allow(dead_code)]
+  pub fn fail_start(mut self) -> Self {
+    self.start_error = Some(ButtplugDeviceError::DeviceCommunicationError(
+      "Delay manager failed to start".to_owned(),
+    ));
+    self
+  }
+
+  /// Fail every start_scanning call like a bluetooth comm manager with no
+  /// adapter.
+  #[allow(dead_code)]
+  pub fn no_bluetooth_adapter(mut self) -> Self {
+    self.start_error = Some(ButtplugDeviceError::NoBluetoothAdapter);
+    self
+  }
 }
 
 impl DeviceCommunicationManagerBuilder for DelayDeviceCommunicationManagerBuilder {
@@ -26,6 +59,8 @@ impl DeviceCommunicationManagerBuilder for DelayDeviceCommunicationManagerBuilde
   fn finish(mut self) -> Box<dyn DeviceCommunicationManager> {
     Box::new(DelayDeviceCommunicationManager::new(
       self.sender.take().expect("Test, assuming infallible"),
+      self.silent_stop,
+      self.start_error.take(),
     ))
   }
 }
@@ -33,13 +68,21 @@ impl DeviceCommunicationManagerBuilder for DelayDeviceCommunicationManagerBuilde
 pub struct DelayDeviceCommunicationManager {
   sender: Sender<DeviceCommunicationEvent>,
   is_scanning: Arc<AtomicBool>,
+  silent_stop: bool,
+  start_error: Option<ButtplugDeviceError>,
 }
 
 impl DelayDeviceCommunicationManager {
-  fn new(sender: Sender<DeviceCommunicationEvent>) -> Self {
+  fn new(
+    sender: Sender<DeviceCommunicationEvent>,
+    silent_stop: bool,
+    start_error: Option<ButtplugDeviceError>,
+  ) -> Self {
     Self {
       sender,
       is_scanning: Arc::new(AtomicBool::new(false)),
+      silent_stop,
+      start_error,
     }
   }
 }
@@ -51,7 +94,11 @@ impl DeviceCommunicationManager for DelayDeviceCommunicationManager {
 
   fn start_scanning(&self) -> ButtplugResultFuture {
     let is_scanning = self.is_scanning.clone();
+    let start_error = self.start_error.clone();
     Box::pin(async move {
+      if let Some(err) = start_error {
+        return Err(ButtplugError::from(err));
+      }
       is_scanning.store(true, Ordering::SeqCst);
       Ok(())
     })
@@ -60,12 +107,15 @@ impl DeviceCommunicationManager for DelayDeviceCommunicationManager {
   fn stop_scanning(&self) -> ButtplugResultFuture {
     let is_scanning = self.is_scanning.clone();
     let sender = self.sender.clone();
+    let silent_stop = self.silent_stop;
     Box::pin(async move {
       is_scanning.store(false, Ordering::SeqCst);
-      sender
-        .send(DeviceCommunicationEvent::ScanningFinished)
-        .await
-        .expect("Test, assuming infallible");
+      if !silent_stop {
+        sender
+          .send(DeviceCommunicationEvent::ScanningFinished)
+          .await
+          .expect("Test, assuming infallible");
+      }
       Ok(())
     })
   }
@@ -230,7 +230,7 @@ fn impl_from_specific_buttplug_message_derive_macro(ast: &syn::DeriveInput) -> T
     let gen = quote! {
         #(impl From<#idents> for #name {
             fn from(msg: #idents) -> #name {
-                #name::#idents(msg)
+                #name::#idents(msg.into())
             }
         })*
     };